@@ -60,6 +60,12 @@ let
       "--media-store-path"
       cfg.mediaStorePath
     ]
+    ++ [
+      "--media-cache-path"
+      cfg.mediaCachePath
+      "--media-cache-max-size"
+      (toString cfg.mediaCacheMaxSize)
+    ]
     ++ lib.optionals (cfg.displayName != null) [
       "--display-name"
       cfg.displayName
@@ -253,6 +259,18 @@ in
       description = "Path to the content-addressable media store directory.";
     };
 
+    mediaCachePath = mkOption {
+      type = types.str;
+      default = "/var/cache/matrix-embed/media";
+      description = "Path to the cache of recently downloaded media.";
+    };
+
+    mediaCacheMaxSize = mkOption {
+      type = types.ints.unsigned;
+      default = 1024 * 1024 * 1024;
+      description = "Max total size in bytes of the media cache, or 0 to disable it.";
+    };
+
     displayName = mkOption {
       type = types.nullOr types.str;
       default = null;
@@ -407,6 +425,8 @@ in
         DynamicUser = true;
         StateDirectory = "matrix-embed";
         StateDirectoryMode = "0700";
+        CacheDirectory = "matrix-embed";
+        CacheDirectoryMode = "0700";
 
         NetworkNamespacePath = netnsPath;
         BindReadOnlyPaths = [ "${netnsResolvConf}:/etc/resolv.conf" ];
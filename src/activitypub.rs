@@ -6,6 +6,10 @@
 //
 // I may clean this up more later.
 
+// Without the `extractors` feature, the detector is kept but never fetches
+// anything.
+#![cfg_attr(not(feature = "extractors"), allow(dead_code))]
+
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
@@ -13,10 +17,17 @@ use reqwest::header::{ACCEPT, CONTENT_TYPE};
 use scraper::Html;
 use serde::Deserialize;
 use tokio::sync::RwLock;
-use tracing::{debug, warn};
+use tracing::debug;
+#[cfg(feature = "extractors")]
+use tracing::warn;
 use url::Url;
 
-use crate::metadata::Metadata;
+#[cfg(feature = "extractors")]
+use crate::decompress;
+use crate::dump;
+use crate::fixtures;
+use crate::metadata::{Emote, GalleryImage, Metadata, Rendition};
+use crate::timestamp;
 
 /// How long to cache per-host ActivityPub detection results.
 const DETECTION_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
@@ -27,6 +38,9 @@ const DETECTION_TIMEOUT: Duration = Duration::from_secs(5);
 /// Timeout for fetching ActivityPub post data.
 const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Largest ActivityPub object we're willing to parse.
+const MAX_OBJECT_SIZE: u64 = 1024 * 1024;
+
 /// ActivityPub content type used in Accept headers.
 const AP_CONTENT_TYPE: &str = "application/activity+json";
 
@@ -88,6 +102,12 @@ struct ActivityPubObject {
     /// but can also be an inline Actor object or an array.
     #[serde(rename = "attributedTo")]
     attributed_to: Option<serde_json::Value>,
+
+    published: Option<String>,
+
+    /// Mentions, hashtags and custom emotes used in the post; an object or
+    /// an array of them.
+    tag: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -99,8 +119,7 @@ struct ActivityPubAttachment {
     /// either.
     url: Option<serde_json::Value>,
 
-    /// Alt-text (unused for now, but deserialized so serde doesn't choke).
-    #[allow(dead_code)]
+    /// Alt-text.
     name: Option<String>,
 }
 
@@ -114,6 +133,9 @@ struct ActivityPubActor {
     /// Local username without the leading `@` (e.g. "arurumo").
     #[serde(rename = "preferredUsername")]
     preferred_username: Option<String>,
+
+    /// Custom emotes used in the display name, among other tags.
+    tag: Option<serde_json::Value>,
 }
 
 // ---------------------------------------------------------------------------
@@ -127,6 +149,15 @@ impl ActivityPubDetector {
         }
     }
 
+    /// Drop expired detections from the cache, returning how many were
+    /// dropped.
+    pub async fn prune_expired(&self) -> usize {
+        let mut cache = self.cache.write().await;
+        let before = cache.len();
+        cache.retain(|_, cached| cached.checked_at.elapsed() < DETECTION_CACHE_TTL);
+        before - cache.len()
+    }
+
     /// Check whether `host` advertises ActivityPub support, returning a cached
     /// answer when available.
     pub async fn supports_activitypub(&self, client: &reqwest::Client, host: &str) -> bool {
@@ -209,6 +240,7 @@ impl ActivityPubDetector {
     /// the URL resolves to a post-like object (`Note`, `Article`, …).
     /// Returns `None` on any failure, letting the caller fall back to normal
     /// HTML scraping.
+    #[cfg(feature = "extractors")]
     pub async fn fetch_metadata(&self, client: &reqwest::Client, url: &Url) -> Option<Metadata> {
         let host = url.host_str()?;
 
@@ -231,6 +263,7 @@ impl ActivityPubDetector {
                 return None;
             }
         };
+        dump::record_response(&response);
 
         if !response.status().is_success() {
             debug!(
@@ -246,7 +279,8 @@ impl ActivityPubDetector {
             .headers()
             .get(CONTENT_TYPE)
             .and_then(|v| v.to_str().ok())
-            .unwrap_or("");
+            .unwrap_or("")
+            .to_string();
 
         if !ct.contains("application/activity+json")
             && !ct.contains("application/ld+json")
@@ -259,7 +293,17 @@ impl ActivityPubDetector {
             return None;
         }
 
-        let obj: ActivityPubObject = match response.json().await {
+        let final_url = response.url().clone();
+        let body = match decompress::read_body(response, MAX_OBJECT_SIZE, 0).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to read ActivityPub object for {}: {}", url, e);
+                return None;
+            }
+        };
+        dump::record_body(&body);
+        fixtures::record(url.as_str(), final_url.as_str(), &ct, &body);
+        let obj: ActivityPubObject = match serde_json::from_slice(&body) {
             Ok(o) => o,
             Err(e) => {
                 warn!("Failed to parse ActivityPub JSON for {}: {}", url, e);
@@ -272,27 +316,30 @@ impl ActivityPubDetector {
         // ap_object_to_metadata, so returning None is fine.
         let note = extract_note(&obj)?;
 
-        let author_title = self.resolve_author(client, note).await;
+        let author = self.resolve_author(client, note).await;
+        with_author(&obj, author)
+    }
 
-        ap_object_to_metadata(&obj, author_title.as_deref())
+    /// Without the `extractors` feature, nothing is fetched over
+    /// ActivityPub and every page is scraped.
+    #[cfg(not(feature = "extractors"))]
+    pub async fn fetch_metadata(&self, _client: &reqwest::Client, _url: &Url) -> Option<Metadata> {
+        None
     }
 
     /// Try to build an author title string like
-    /// `"DisplayName (@username@host)"` from the note's `attributedTo` field.
+    /// `"DisplayName (@username@host)"` from the note's `attributedTo` field,
+    /// along with the custom emotes in the display name.
     async fn resolve_author(
         &self,
         client: &reqwest::Client,
         note: &ActivityPubObject,
-    ) -> Option<String> {
+    ) -> Option<(String, Vec<Emote>)> {
         let attributed_to = note.attributed_to.as_ref()?;
         let actor_url = extract_attributed_to_url(attributed_to)?;
 
         let actor = self.fetch_actor(client, &actor_url).await;
-        let host = Url::parse(&actor_url)
-            .ok()
-            .and_then(|u| u.host_str().map(|h| h.to_string()));
-
-        format_author_title(&actor, host.as_deref())
+        author_of(&actor, &actor_url)
     }
 
     /// Fetch an ActivityPub Actor object by URL.
@@ -314,8 +361,26 @@ impl ActivityPubDetector {
                 return ActivityPubActor::default();
             }
         };
+        dump::record_response(&response);
 
-        match response.json::<ActivityPubActor>().await {
+        let final_url = response.url().clone();
+        let ct = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(AP_CONTENT_TYPE)
+            .to_string();
+        let body = match response.bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                debug!("Failed to read actor from {}: {}", actor_url, e);
+                return ActivityPubActor::default();
+            }
+        };
+        dump::record_body(&body);
+        fixtures::record(actor_url, final_url.as_str(), &ct, &body);
+
+        match serde_json::from_slice::<ActivityPubActor>(&body) {
             Ok(actor) => {
                 debug!(
                     "Resolved actor {}: name={:?}, preferredUsername={:?}",
@@ -335,6 +400,59 @@ impl ActivityPubDetector {
 // Conversion helpers
 // ---------------------------------------------------------------------------
 
+/// Build [`Metadata`] from an already fetched ActivityPub object, looking up
+/// its author's Actor object with `actor`. This is [`ActivityPubDetector::fetch_metadata`]
+/// without the network, for replaying recorded responses.
+pub fn metadata_from_json(
+    object: &[u8],
+    actor: impl Fn(&str) -> Option<Vec<u8>>,
+) -> Option<Metadata> {
+    let obj: ActivityPubObject = serde_json::from_slice(object).ok()?;
+    let note = extract_note(&obj)?;
+    let author = note
+        .attributed_to
+        .as_ref()
+        .and_then(extract_attributed_to_url)
+        .and_then(|actor_url| {
+            let actor_json = actor(&actor_url)?;
+            let actor = serde_json::from_slice(&actor_json).ok()?;
+            author_of(&actor, &actor_url)
+        });
+    with_author(&obj, author)
+}
+
+/// The author title of `actor`, fetched from `actor_url`, and the custom
+/// emotes in its display name.
+fn author_of(actor: &ActivityPubActor, actor_url: &str) -> Option<(String, Vec<Emote>)> {
+    let host = Url::parse(actor_url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()));
+
+    let title = format_author_title(actor, host.as_deref())?;
+    Some((title, extract_emotes(actor.tag.as_ref())))
+}
+
+/// [`ap_object_to_metadata`], with the emotes of the author's display name
+/// added to those of the post.
+fn with_author(obj: &ActivityPubObject, author: Option<(String, Vec<Emote>)>) -> Option<Metadata> {
+    let (author_title, author_emotes) = match author {
+        Some((title, emotes)) => (Some(title), emotes),
+        None => (None, Vec::new()),
+    };
+
+    let mut metadata = ap_object_to_metadata(obj, author_title.as_deref())?;
+    for emote in author_emotes {
+        if !metadata
+            .emotes
+            .iter()
+            .any(|e| e.shortcode == emote.shortcode)
+        {
+            metadata.emotes.push(emote);
+        }
+    }
+    Some(metadata)
+}
+
 /// Unwrap an [`ActivityPubObject`] to reach the inner post-like object,
 /// handling both bare Notes and wrapping Activities (`Create`, `Announce`).
 fn extract_note(obj: &ActivityPubObject) -> Option<&ActivityPubObject> {
@@ -354,6 +472,44 @@ fn extract_note(obj: &ActivityPubObject) -> Option<&ActivityPubObject> {
     }
 }
 
+/// Collect the custom emotes (`Emoji` tags) from a `tag` value, which can be
+/// a single tag or an array of them. Emotes without a usable image are
+/// skipped.
+fn extract_emotes(tag: Option<&serde_json::Value>) -> Vec<Emote> {
+    let tags = match tag {
+        Some(serde_json::Value::Array(tags)) => tags.as_slice(),
+        Some(tag) => std::slice::from_ref(tag),
+        None => &[],
+    };
+    tags.iter()
+        .filter(|tag| tag.get("type").and_then(|t| t.as_str()) == Some("Emoji"))
+        .filter_map(|tag| {
+            let name = tag.get("name")?.as_str()?;
+            let shortcode = name.trim().trim_matches(':');
+            if shortcode.is_empty()
+                || !shortcode
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                return None;
+            }
+            let icon = tag.get("icon")?;
+            let url = icon
+                .get("url")
+                .and_then(|u| u.as_str())
+                .or_else(|| icon.as_str())?;
+            let url = Url::parse(url)
+                .ok()
+                .filter(|u| matches!(u.scheme(), "http" | "https"))?;
+            Some(Emote {
+                shortcode: shortcode.to_string(),
+                url,
+                mxc_uri: None,
+            })
+        })
+        .collect()
+}
+
 /// Extract an actor URL from an `attributedTo` value, which can be a plain
 /// URL string, an object with an `id` field, or an array of either.
 fn extract_attributed_to_url(value: &serde_json::Value) -> Option<String> {
@@ -401,6 +557,7 @@ fn ap_object_to_metadata(obj: &ActivityPubObject, author_title: Option<&str>) ->
     let cw = note.summary.as_deref().filter(|s| !s.is_empty());
     let content = note.content.as_deref().map(strip_html);
     let content = content.as_deref().filter(|s| !s.is_empty());
+    let content_html = note.content.as_deref().filter(|_| content.is_some());
 
     // When we have author information use it as the title (matching the
     // format OG tags normally provide, e.g. "あるるも (@arurumo@misskey.io)").
@@ -408,6 +565,7 @@ fn ap_object_to_metadata(obj: &ActivityPubObject, author_title: Option<&str>) ->
     // When author resolution failed, fall back to the CW as the title.
     let title;
     let description;
+    let description_html;
 
     if let Some(author) = author_title {
         title = Some(author.to_string());
@@ -417,13 +575,24 @@ fn ap_object_to_metadata(obj: &ActivityPubObject, author_title: Option<&str>) ->
             (None, Some(text)) => Some(text.to_string()),
             (None, None) => None,
         };
+        description_html = match (cw, content_html) {
+            (Some(cw), Some(html)) => {
+                Some(format!("<p>{}</p>{}", html_escape::encode_text(cw), html))
+            }
+            (None, Some(html)) => Some(html.to_string()),
+            (_, None) => None,
+        };
     } else {
         title = cw.map(|s| s.to_string());
         description = content.map(|s| s.to_string());
+        description_html = content_html.map(|s| s.to_string());
     }
 
     let mut image_url: Option<Url> = None;
+    let mut image_alt: Option<String> = None;
+    let mut gallery = Vec::new();
     let mut video_url: Option<Url> = None;
+    let mut video_renditions = Vec::new();
     let mut audio_url: Option<Url> = None;
 
     if let Some(attachments) = &note.attachment {
@@ -437,10 +606,24 @@ fn ap_object_to_metadata(obj: &ActivityPubObject, author_title: Option<&str>) ->
                 continue;
             };
 
+            let alt = att
+                .name
+                .as_deref()
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string);
             if media_type.starts_with("image/") && image_url.is_none() {
                 image_url = Some(parsed);
+                image_alt = alt;
+            } else if media_type.starts_with("image/") {
+                gallery.push(GalleryImage { url: parsed, alt });
             } else if media_type.starts_with("video/") && video_url.is_none() {
-                video_url = Some(parsed);
+                video_renditions = attachment_renditions(att);
+                video_url = Some(
+                    video_renditions
+                        .first()
+                        .map_or(parsed, |rendition| rendition.url.clone()),
+                );
             } else if media_type.starts_with("audio/") && audio_url.is_none() {
                 audio_url = Some(parsed);
             }
@@ -450,10 +633,29 @@ fn ap_object_to_metadata(obj: &ActivityPubObject, author_title: Option<&str>) ->
     let metadata = Metadata {
         card: None,
         title,
+        site_name: None,
+        locale: None,
+        alternate_locales: Vec::new(),
         description,
+        description_html,
         image_url,
+        image_alt,
+        original_image_url: None,
+        gallery,
+        og_images: Vec::new(),
         video_url,
+        alternate_video_url: None,
+        video_duration: None,
+        video_renditions,
         audio_url,
+        player_url: None,
+        text: None,
+        summary: None,
+        published: note.published.as_deref().and_then(timestamp::parse),
+        url_warning: None,
+        canonical_url: None,
+        content_url: None,
+        emotes: extract_emotes(note.tag.as_ref()),
     };
 
     if metadata.is_empty() {
@@ -484,6 +686,34 @@ fn extract_attachment_url(att: &ActivityPubAttachment) -> Option<String> {
     }
 }
 
+/// Every encoding a video attachment offers, when its `url` is an array of
+/// Link objects with their own `mediaType`, `height` and `size` (as PeerTube
+/// publishes them).
+fn attachment_renditions(att: &ActivityPubAttachment) -> Vec<Rendition> {
+    let Some(serde_json::Value::Array(links)) = &att.url else {
+        return vec![];
+    };
+    links
+        .iter()
+        .filter_map(|link| {
+            let link = link.as_object()?;
+            let media_type = link.get("mediaType").and_then(|v| v.as_str());
+            if media_type.is_some_and(|t| !t.starts_with("video/")) {
+                return None;
+            }
+            let url = Url::parse(link.get("href")?.as_str()?).ok()?;
+            Some(Rendition {
+                url,
+                height: link
+                    .get("height")
+                    .and_then(|v| v.as_u64())
+                    .and_then(|h| u32::try_from(h).ok()),
+                size: link.get("size").and_then(|v| v.as_u64()),
+            })
+        })
+        .collect()
+}
+
 /// Convert HTML to plain text, roughly preserving line breaks from `<br>` and
 /// `</p>` tags.
 fn strip_html(html: &str) -> String {
@@ -676,6 +906,8 @@ mod tests {
             }]),
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, None).unwrap();
@@ -711,8 +943,12 @@ mod tests {
                 }]),
                 object: None,
                 attributed_to: None,
+                published: None,
+                tag: None,
             })),
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, None).unwrap();
@@ -734,6 +970,8 @@ mod tests {
             attachment: None,
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, None).unwrap();
@@ -751,6 +989,8 @@ mod tests {
             attachment: None,
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, None).unwrap();
@@ -767,6 +1007,8 @@ mod tests {
             attachment: None,
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         assert!(ap_object_to_metadata(&obj, None).is_none());
@@ -782,6 +1024,8 @@ mod tests {
             attachment: None,
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         assert!(ap_object_to_metadata(&obj, None).is_none());
@@ -797,6 +1041,8 @@ mod tests {
             attachment: None,
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         assert!(ap_object_to_metadata(&obj, None).is_none());
@@ -841,6 +1087,8 @@ mod tests {
             ]),
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, None).unwrap();
@@ -857,6 +1105,58 @@ mod tests {
             meta.audio_url,
             Some(Url::parse("https://cdn.example.com/a.mp3").unwrap())
         );
+        // Further images make up the gallery.
+        assert_eq!(
+            meta.gallery,
+            vec![GalleryImage {
+                url: Url::parse("https://cdn.example.com/b.png").unwrap(),
+                alt: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_video_attachment_renditions() {
+        let obj = ActivityPubObject {
+            object_type: Some("Note".into()),
+            summary: None,
+            content: Some("<p>Video</p>".into()),
+            sensitive: None,
+            attachment: Some(vec![ActivityPubAttachment {
+                media_type: Some("video/mp4".into()),
+                url: Some(serde_json::json!([
+                    {"type": "Link", "mediaType": "text/html", "href": "https://video.example.com/w/1"},
+                    {"type": "Link", "mediaType": "video/mp4", "href": "https://video.example.com/1-1080.mp4", "height": 1080, "size": 90000000},
+                    {"type": "Link", "mediaType": "video/mp4", "href": "https://video.example.com/1-480.mp4", "height": 480}
+                ])),
+                name: None,
+            }]),
+            object: None,
+            attributed_to: None,
+            published: None,
+            tag: None,
+        };
+
+        let meta = ap_object_to_metadata(&obj, None).unwrap();
+        assert_eq!(
+            meta.video_url,
+            Some(Url::parse("https://video.example.com/1-1080.mp4").unwrap())
+        );
+        assert_eq!(
+            meta.video_renditions,
+            vec![
+                Rendition {
+                    url: Url::parse("https://video.example.com/1-1080.mp4").unwrap(),
+                    height: Some(1080),
+                    size: Some(90000000),
+                },
+                Rendition {
+                    url: Url::parse("https://video.example.com/1-480.mp4").unwrap(),
+                    height: Some(480),
+                    size: None,
+                },
+            ]
+        );
     }
 
     #[test]
@@ -882,6 +1182,8 @@ mod tests {
             ]),
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, None).unwrap();
@@ -901,6 +1203,8 @@ mod tests {
             attachment: None,
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, None).unwrap();
@@ -924,6 +1228,8 @@ mod tests {
             }]),
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, None).unwrap();
@@ -974,6 +1280,10 @@ mod tests {
             meta.image_url,
             Some(Url::parse("https://files.mastodon.social/media/original/abc123.webp").unwrap())
         );
+        assert_eq!(
+            meta.published.unwrap().to_rfc3339(),
+            "2025-01-15T12:00:00+00:00"
+        );
     }
 
     #[test]
@@ -1014,6 +1324,7 @@ mod tests {
         let actor = ActivityPubActor {
             name: Some("あるるも".into()),
             preferred_username: Some("arurumo".into()),
+            tag: None,
         };
         assert_eq!(
             format_author_title(&actor, Some("misskey.io")),
@@ -1026,6 +1337,7 @@ mod tests {
         let actor = ActivityPubActor {
             name: Some("Alice".into()),
             preferred_username: Some("alice".into()),
+            tag: None,
         };
         assert_eq!(
             format_author_title(&actor, None),
@@ -1038,6 +1350,7 @@ mod tests {
         let actor = ActivityPubActor {
             name: Some("Alice".into()),
             preferred_username: None,
+            tag: None,
         };
         assert_eq!(
             format_author_title(&actor, Some("example.com")),
@@ -1050,6 +1363,7 @@ mod tests {
         let actor = ActivityPubActor {
             name: None,
             preferred_username: Some("alice".into()),
+            tag: None,
         };
         assert_eq!(
             format_author_title(&actor, Some("example.com")),
@@ -1062,6 +1376,7 @@ mod tests {
         let actor = ActivityPubActor {
             name: None,
             preferred_username: Some("alice".into()),
+            tag: None,
         };
         assert_eq!(format_author_title(&actor, None), Some("@alice".into()));
     }
@@ -1071,6 +1386,7 @@ mod tests {
         let actor = ActivityPubActor {
             name: None,
             preferred_username: None,
+            tag: None,
         };
         assert_eq!(format_author_title(&actor, Some("example.com")), None);
     }
@@ -1080,6 +1396,7 @@ mod tests {
         let actor = ActivityPubActor {
             name: Some("".into()),
             preferred_username: Some("alice".into()),
+            tag: None,
         };
         assert_eq!(
             format_author_title(&actor, Some("example.com")),
@@ -1146,6 +1463,8 @@ mod tests {
             }]),
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, Some("Alice (@alice@example.com)")).unwrap();
@@ -1156,6 +1475,10 @@ mod tests {
             meta.description.as_deref(),
             Some("CW: spoilers\nThe actual post")
         );
+        assert_eq!(
+            meta.description_html.as_deref(),
+            Some("<p>CW: spoilers</p><p>The actual post</p>")
+        );
         assert_eq!(
             meta.image_url,
             Some(Url::parse("https://files.example.com/photo.jpg").unwrap())
@@ -1172,6 +1495,8 @@ mod tests {
             attachment: None,
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, Some("Bob (@bob@example.com)")).unwrap();
@@ -1189,6 +1514,8 @@ mod tests {
             attachment: None,
             object: None,
             attributed_to: None,
+            published: None,
+            tag: None,
         };
 
         let meta = ap_object_to_metadata(&obj, Some("Carol (@carol@example.com)")).unwrap();
@@ -1276,4 +1603,56 @@ mod tests {
             Some(Url::parse("https://media.misskeycdn.com/abc.webp").unwrap())
         );
     }
+
+    #[test]
+    fn test_note_with_emotes() {
+        let json = serde_json::json!({
+            "type": "Note",
+            "content": "<p>hi :blobcat: :blobcat:</p>",
+            "tag": [
+                {
+                    "type": "Mention",
+                    "href": "https://example.com/users/bob",
+                    "name": "@bob"
+                },
+                {
+                    "type": "Emoji",
+                    "name": ":blobcat:",
+                    "icon": {
+                        "type": "Image",
+                        "mediaType": "image/png",
+                        "url": "https://example.com/emoji/blobcat.png"
+                    }
+                },
+                {
+                    "type": "Emoji",
+                    "name": ":bad name:",
+                    "icon": { "url": "https://example.com/emoji/bad.png" }
+                },
+                {
+                    "type": "Emoji",
+                    "name": ":noicon:"
+                }
+            ]
+        });
+
+        let obj: ActivityPubObject = serde_json::from_value(json).unwrap();
+        let meta = ap_object_to_metadata(&obj, None).unwrap();
+        assert_eq!(
+            meta.emotes,
+            vec![Emote {
+                shortcode: "blobcat".into(),
+                url: Url::parse("https://example.com/emoji/blobcat.png").unwrap(),
+                mxc_uri: None,
+            }]
+        );
+
+        // A lone tag needn't be in an array.
+        let tag = serde_json::json!({
+            "type": "Emoji",
+            "name": "ablobwave",
+            "icon": { "url": "https://example.com/emoji/ablobwave.gif" }
+        });
+        assert_eq!(extract_emotes(Some(&tag))[0].shortcode, "ablobwave");
+    }
 }
@@ -0,0 +1,260 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use ical::IcalParser;
+use ical::property::Property;
+use tracing::debug;
+
+use crate::metadata::Metadata;
+
+/// A parsed `DTSTART`/`DTEND` value.
+#[derive(Debug, Clone, PartialEq)]
+enum EventTime {
+    /// An all-day date (`VALUE=DATE`).
+    Date(NaiveDate),
+    /// A point in time, already converted to the display timezone.
+    DateTime(DateTime<Tz>),
+}
+
+/// Returns `true` if the MIME type looks like an iCalendar file. Servers often
+/// send `.ics` exports with a generic type, so the URL path is consulted too.
+pub fn is_calendar(mime_type: &str, path: &str) -> bool {
+    match mime_type {
+        "text/calendar" => true,
+        "" | "text/plain" | "application/octet-stream" => {
+            path.to_ascii_lowercase().ends_with(".ics")
+        }
+        _ => false,
+    }
+}
+
+/// Parse an iCalendar document and summarize its first event as [`Metadata`].
+///
+/// Start and end times are rendered in `tz`. Returns `None` if the document
+/// can't be parsed or doesn't contain any events.
+pub fn parse_ics(content: &str, tz: Tz) -> Option<Metadata> {
+    let calendar = match IcalParser::new(content.as_bytes()).next()? {
+        Ok(calendar) => calendar,
+        Err(e) => {
+            debug!("Failed to parse iCalendar data: {}", e);
+            return None;
+        }
+    };
+
+    let event = calendar.events.first()?;
+    let find = |name: &str| event.properties.iter().find(|p| p.name == name);
+    let text = |name: &str| {
+        find(name)
+            .and_then(|p| p.value.as_deref())
+            .map(unescape_text)
+            .filter(|s| !s.is_empty())
+    };
+
+    let title = text("SUMMARY");
+    let start = find("DTSTART").and_then(|p| parse_event_time(p, tz));
+    let end = find("DTEND").and_then(|p| parse_event_time(p, tz));
+
+    let mut lines = Vec::new();
+    if let Some(start) = &start {
+        lines.push(format!("When: {}", format_range(start, end.as_ref())));
+    }
+    if let Some(location) = text("LOCATION") {
+        lines.push(format!("Where: {}", location));
+    }
+    if let Some(description) = text("DESCRIPTION") {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(description);
+    }
+
+    let metadata = Metadata {
+        title,
+        description: if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        },
+        ..Default::default()
+    };
+
+    if metadata.is_empty() {
+        return None;
+    }
+
+    Some(metadata)
+}
+
+/// Parse a date or date-time property, honouring `VALUE=DATE`, a trailing `Z`
+/// (UTC) and `TZID=` parameters. Floating times are interpreted in `tz`.
+fn parse_event_time(prop: &Property, tz: Tz) -> Option<EventTime> {
+    let value = prop.value.as_deref()?.trim();
+    let param = |key: &str| {
+        prop.params
+            .as_ref()?
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .and_then(|(_, v)| v.first())
+            .map(|s| s.as_str())
+    };
+
+    if param("VALUE") == Some("DATE") || value.len() == 8 {
+        return NaiveDate::parse_from_str(value, "%Y%m%d")
+            .ok()
+            .map(EventTime::Date);
+    }
+
+    if let Some(utc) = value.strip_suffix('Z') {
+        let naive = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+        return Some(EventTime::DateTime(
+            Utc.from_utc_datetime(&naive).with_timezone(&tz),
+        ));
+    }
+
+    let naive = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+    let source_tz = param("TZID")
+        .and_then(|id| id.trim_matches('"').parse::<Tz>().ok())
+        .unwrap_or(tz);
+    let local = source_tz.from_local_datetime(&naive).earliest()?;
+    Some(EventTime::DateTime(local.with_timezone(&tz)))
+}
+
+/// Render an event's start (and optional end) as a human-readable string.
+fn format_range(start: &EventTime, end: Option<&EventTime>) -> String {
+    match (start, end) {
+        (EventTime::Date(start), Some(EventTime::Date(end))) => {
+            // DTEND is exclusive for all-day events.
+            let last = end.pred_opt().unwrap_or(*end);
+            if last <= *start {
+                format_date(start)
+            } else {
+                format!("{} – {}", format_date(start), format_date(&last))
+            }
+        }
+        (EventTime::Date(start), _) => format_date(start),
+        (EventTime::DateTime(start), Some(EventTime::DateTime(end))) => {
+            if start.date_naive() == end.date_naive() {
+                format!(
+                    "{} – {}",
+                    start.format("%a %b %-d %Y, %H:%M"),
+                    end.format("%H:%M %Z")
+                )
+            } else {
+                format!(
+                    "{} – {}",
+                    start.format("%a %b %-d %Y, %H:%M"),
+                    end.format("%a %b %-d %Y, %H:%M %Z")
+                )
+            }
+        }
+        (EventTime::DateTime(start), _) => start.format("%a %b %-d %Y, %H:%M %Z").to_string(),
+    }
+}
+
+fn format_date(date: &NaiveDate) -> String {
+    date.format("%a %b %-d %Y").to_string()
+}
+
+/// Undo iCalendar TEXT escaping (RFC 5545 §3.3.11).
+fn unescape_text(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEETUP_ICS: &str = "BEGIN:VCALENDAR\r\n\
+VERSION:2.0\r\n\
+PRODID:-//Example//EN\r\n\
+BEGIN:VEVENT\r\n\
+UID:1234@example.com\r\n\
+SUMMARY:Rust Meetup\\, March edition\r\n\
+DTSTART:20250315T180000Z\r\n\
+DTEND:20250315T200000Z\r\n\
+LOCATION:Community Hall\\, Room 2\r\n\
+DESCRIPTION:Talks and pizza.\\nBring a laptop!\r\n\
+END:VEVENT\r\n\
+END:VCALENDAR\r\n";
+
+    #[test]
+    fn test_is_calendar() {
+        assert!(is_calendar("text/calendar", "/event"));
+        assert!(is_calendar("application/octet-stream", "/export/Event.ICS"));
+        assert!(!is_calendar("text/html", "/page"));
+        assert!(!is_calendar("text/html", "/calendar.ics"));
+    }
+
+    #[test]
+    fn test_parse_ics_utc() {
+        let meta = parse_ics(MEETUP_ICS, chrono_tz::UTC).unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Rust Meetup, March edition"));
+        assert_eq!(
+            meta.description.as_deref(),
+            Some(
+                "When: Sat Mar 15 2025, 18:00 – 20:00 UTC\n\
+                 Where: Community Hall, Room 2\n\
+                 \n\
+                 Talks and pizza.\nBring a laptop!"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_ics_converts_timezone() {
+        let meta = parse_ics(MEETUP_ICS, chrono_tz::Asia::Tokyo).unwrap();
+        let desc = meta.description.unwrap();
+        assert!(
+            desc.starts_with("When: Sun Mar 16 2025, 03:00 – 05:00 JST"),
+            "got: {}",
+            desc
+        );
+    }
+
+    #[test]
+    fn test_parse_ics_tzid_and_all_day() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+BEGIN:VEVENT\r\n\
+SUMMARY:Conference\r\n\
+DTSTART;TZID=Europe/Berlin:20250601T090000\r\n\
+END:VEVENT\r\n\
+END:VCALENDAR\r\n";
+        let meta = parse_ics(ics, chrono_tz::UTC).unwrap();
+        assert_eq!(
+            meta.description.as_deref(),
+            Some("When: Sun Jun 1 2025, 07:00 UTC")
+        );
+
+        let ics = "BEGIN:VCALENDAR\r\n\
+BEGIN:VEVENT\r\n\
+SUMMARY:Festival\r\n\
+DTSTART;VALUE=DATE:20250704\r\n\
+DTEND;VALUE=DATE:20250707\r\n\
+END:VEVENT\r\n\
+END:VCALENDAR\r\n";
+        let meta = parse_ics(ics, chrono_tz::UTC).unwrap();
+        assert_eq!(
+            meta.description.as_deref(),
+            Some("When: Fri Jul 4 2025 – Sun Jul 6 2025")
+        );
+    }
+
+    #[test]
+    fn test_parse_ics_no_events() {
+        let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nEND:VCALENDAR\r\n";
+        assert!(parse_ics(ics, chrono_tz::UTC).is_none());
+        assert!(parse_ics("not a calendar", chrono_tz::UTC).is_none());
+    }
+}
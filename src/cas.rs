@@ -10,7 +10,8 @@ pub struct MediaStore {
     root: PathBuf,
 }
 
-fn hex_encode(bytes: &[u8]) -> String {
+/// Lowercase hex encoding of `bytes`.
+pub fn hex_encode(bytes: &[u8]) -> String {
     let mut s = String::with_capacity(bytes.len() * 2);
     for b in bytes {
         let _ = write!(s, "{:02x}", b);
@@ -18,6 +19,11 @@ fn hex_encode(bytes: &[u8]) -> String {
     s
 }
 
+/// SHA-256 hex hash of `data`, as used to address stored objects.
+pub fn content_hash(data: &[u8]) -> String {
+    hex_encode(Sha256::digest(data).as_slice())
+}
+
 impl MediaStore {
     pub async fn open(root: &Path) -> Result<Self> {
         fs::create_dir_all(root)
@@ -35,7 +41,7 @@ impl MediaStore {
     /// Store data and return its SHA-256 hex hash. Writes are atomic (write to
     /// temp then rename) and idempotent.
     pub async fn store(&self, data: &[u8]) -> Result<String> {
-        let hash = hex_encode(Sha256::digest(data).as_slice());
+        let hash = content_hash(data);
         let dest = self.path_for(&hash);
 
         if dest.exists() {
@@ -80,6 +86,7 @@ mod tests {
         let data = b"hello world";
         let hash = store.store(data).await.unwrap();
         assert_eq!(hash.len(), 64);
+        assert_eq!(hash, content_hash(data));
 
         let loaded = store.load(&hash).await.unwrap();
         assert_eq!(loaded, data);
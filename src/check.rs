@@ -0,0 +1,400 @@
+use anyhow::Context;
+use regex::Regex;
+use url::Url;
+
+use crate::config::{Config, UrlRewrite};
+use crate::db::UrlRewriteRow;
+
+/// A rewrite rule, and the name it's reported by.
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    regex: Regex,
+    /// The replacement of each of the rule's targets.
+    replacements: Vec<String>,
+}
+
+/// What `check-config` or `doctor` found: lines to print, and the problems
+/// that make it fail.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub lines: Vec<String>,
+    pub problems: Vec<String>,
+}
+
+/// Check the loaded `config` along with the rewrite rules managed with admin
+/// commands, and run each of `samples` through the rewrite rules.
+pub fn check(config: &Config, admin_rules: &[UrlRewriteRow], samples: &[String]) -> Report {
+    let mut report = Report::default();
+
+    // Rules added with admin commands are tried before the configured ones.
+    let mut url_rules = Vec::new();
+    for row in admin_rules {
+        match Regex::new(&row.pattern).with_context(|| format!("Invalid regex: {}", row.pattern)) {
+            Ok(regex) => url_rules.push(Rule {
+                name: format!("admin rewrite #{}", row.id),
+                regex,
+                replacements: vec![row.replacement.clone()],
+            }),
+            Err(e) => report
+                .problems
+                .push(format!("admin rewrite #{}: {:#}", row.id, e)),
+        }
+    }
+    url_rules.extend(named_rules("rewrite", &config.url_rewrites));
+    let media_rules = named_rules("media rewrite", &config.media_url_rewrites);
+
+    report.lines.push(format!(
+        "Configuration loaded: {} URL rewrite rule(s) ({} from admin commands), {} media URL rewrite rule(s), {} ignored URL pattern(s)",
+        url_rules.len(),
+        admin_rules.len(),
+        media_rules.len(),
+        config.ignored_url_patterns.len()
+    ));
+
+    for rules in [&url_rules, &media_rules] {
+        for rule in rules {
+            for replacement in &rule.replacements {
+                if let Some(problem) = replacement_problem(&rule.regex, replacement) {
+                    report.problems.push(format!("{}: {}", rule.name, problem));
+                }
+            }
+        }
+        for (earlier, later) in duplicate_rules(rules) {
+            report.problems.push(format!(
+                "{} never applies: {} has the same pattern `{}`",
+                rules[later].name,
+                rules[earlier].name,
+                rules[later].regex.as_str()
+            ));
+        }
+    }
+
+    let mut matched = vec![0; url_rules.len()];
+    let mut applied = vec![0; url_rules.len()];
+    for sample in samples {
+        let url = match Url::parse(sample) {
+            Ok(url) => url,
+            Err(e) => {
+                report
+                    .problems
+                    .push(format!("Sample URL `{}` is invalid: {}", sample, e));
+                continue;
+            }
+        };
+        report.lines.push(url.to_string());
+        if config.is_url_ignored(&url) {
+            report.lines.push("  ignored".to_string());
+            continue;
+        }
+
+        let rewrite = simulate(&url_rules, &url);
+        for &i in &rewrite.matched {
+            matched[i] += 1;
+        }
+        for &i in &rewrite.invalid {
+            report.problems.push(format!(
+                "{} rewrites {} to an invalid URL",
+                url_rules[i].name, url
+            ));
+        }
+        match rewrite.applied {
+            Some((i, ref new_urls)) => {
+                applied[i] += 1;
+                report.lines.push(format!(
+                    "  -> {} ({})",
+                    join_urls(new_urls),
+                    url_rules[i].name
+                ));
+                let others: Vec<&str> = rewrite
+                    .matched
+                    .iter()
+                    .filter(|&&j| j != i)
+                    .map(|&j| url_rules[j].name.as_str())
+                    .collect();
+                if !others.is_empty() {
+                    report
+                        .lines
+                        .push(format!("  also matches {}", others.join(", ")));
+                }
+            }
+            None => report.lines.push("  unchanged".to_string()),
+        }
+        if let Some((i, media_urls)) = simulate(&media_rules, &url).applied {
+            report.lines.push(format!(
+                "  as media -> {} ({})",
+                join_urls(&media_urls),
+                media_rules[i].name
+            ));
+        }
+    }
+
+    for (i, rule) in url_rules.iter().enumerate() {
+        if matched[i] > 0 && applied[i] == 0 {
+            report.problems.push(format!(
+                "{} (`{}`) never applies to the sample URLs it matches: earlier rules rewrite them first",
+                rule.name,
+                rule.regex.as_str()
+            ));
+        }
+    }
+
+    report
+}
+
+/// Name `rules` `<kind> 1`, `<kind> 2` and so on, in order.
+fn named_rules(kind: &str, rules: &[UrlRewrite]) -> Vec<Rule> {
+    rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| Rule {
+            name: format!("{} {}", kind, i + 1),
+            regex: rule.regex.clone(),
+            replacements: rule.targets.iter().map(|t| t.replacement.clone()).collect(),
+        })
+        .collect()
+}
+
+/// The URLs a rule with several targets can make, as one alternative.
+fn join_urls(urls: &[Url]) -> String {
+    urls.iter()
+        .map(Url::as_str)
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+/// How a URL fares against a list of rewrite rules.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Simulation {
+    /// Every rule that changes the URL, in order.
+    matched: Vec<usize>,
+    /// Rules with a target that changes the URL into something that isn't a
+    /// URL, and so is skipped.
+    invalid: Vec<usize>,
+    /// The rule that's used, which is the first that changes the URL into a
+    /// valid one, and the URLs its targets make.
+    applied: Option<(usize, Vec<Url>)>,
+}
+
+/// Run `url` through `rules` the way [`Config::rewrite_url`] does, noting
+/// every rule that would change it.
+fn simulate(rules: &[Rule], url: &Url) -> Simulation {
+    let mut simulation = Simulation::default();
+    for (i, rule) in rules.iter().enumerate() {
+        let mut changed = false;
+        let mut invalid = false;
+        let mut new_urls = Vec::new();
+        for replacement in &rule.replacements {
+            let new_url = rule.regex.replace(url.as_str(), replacement.as_str());
+            if new_url == url.as_str() {
+                continue;
+            }
+            changed = true;
+            match Url::parse(&new_url) {
+                Ok(new_url) => new_urls.push(new_url),
+                Err(_) => invalid = true,
+            }
+        }
+        if changed {
+            simulation.matched.push(i);
+        }
+        if invalid {
+            simulation.invalid.push(i);
+        }
+        if !new_urls.is_empty() {
+            simulation.applied.get_or_insert((i, new_urls));
+        }
+    }
+    simulation
+}
+
+/// Pairs of rules where the later one has the same pattern as an earlier
+/// one, and so can never apply.
+fn duplicate_rules(rules: &[Rule]) -> Vec<(usize, usize)> {
+    let mut duplicates = Vec::new();
+    for (later, rule) in rules.iter().enumerate() {
+        if let Some(earlier) = rules[..later]
+            .iter()
+            .position(|r| r.regex.as_str() == rule.regex.as_str())
+        {
+            duplicates.push((earlier, later));
+        }
+    }
+    duplicates
+}
+
+/// Check that the groups `replacement` refers to, like `$1` or
+/// `${name}`, exist in `regex`. A reference to a missing group is silently
+/// replaced with nothing, which is rarely what was meant.
+fn replacement_problem(regex: &Regex, replacement: &str) -> Option<String> {
+    let mut rest = replacement;
+    while let Some(dollar) = rest.find('$') {
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+        let (name, braced) = match rest.strip_prefix('{') {
+            Some(inner) => match inner.find('}') {
+                Some(end) => {
+                    rest = &inner[end + 1..];
+                    (&inner[..end], true)
+                }
+                None => continue,
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let name = &rest[..end];
+                rest = &rest[end..];
+                (name, false)
+            }
+        };
+        if name.is_empty() {
+            continue;
+        }
+        if let Ok(index) = name.parse::<usize>() {
+            if index >= regex.captures_len() {
+                return Some(format!(
+                    "replacement refers to group ${}, but the pattern only has {}",
+                    index,
+                    regex.captures_len() - 1
+                ));
+            }
+        } else if !regex.capture_names().flatten().any(|n| n == name) {
+            let digits = name.len() - name.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            return Some(if digits > 0 && !braced {
+                format!(
+                    "replacement refers to a group named `{}`; write `${{{}}}{}` to follow group {} with text",
+                    name,
+                    &name[..digits],
+                    &name[digits..],
+                    &name[..digits]
+                )
+            } else {
+                format!(
+                    "replacement refers to a group named `{}` that doesn't exist",
+                    name
+                )
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, pattern: &str, replacements: &[&str]) -> Rule {
+        Rule {
+            name: name.to_string(),
+            regex: Regex::new(pattern).unwrap(),
+            replacements: replacements.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_replacement_problem() {
+        let re = Regex::new(r"^https://(?<host>[^/]+)/(.*)$").unwrap();
+        assert_eq!(replacement_problem(&re, "https://$host/$2"), None);
+        assert_eq!(replacement_problem(&re, "https://${host}/${2}?a=$$1"), None);
+        assert_eq!(
+            replacement_problem(&re, "https://$host/$3"),
+            Some("replacement refers to group $3, but the pattern only has 2".to_string())
+        );
+        assert_eq!(
+            replacement_problem(&re, "https://example.com/$2x"),
+            Some(
+                "replacement refers to a group named `2x`; write `${2}x` to follow group 2 with text"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            replacement_problem(&re, "https://${domain}/"),
+            Some("replacement refers to a group named `domain` that doesn't exist".to_string())
+        );
+    }
+
+    #[test]
+    fn test_simulate() {
+        let rules = [
+            rule("rewrite 1", r"^https://x\.com/", &["not a url"]),
+            rule(
+                "rewrite 2",
+                r"^https://x\.com/",
+                &["https://vxtwitter.com/", "https://fixvx.com/"],
+            ),
+            rule(
+                "rewrite 3",
+                r"^https://(x|twitter)\.com/",
+                &["https://fx.com/"],
+            ),
+        ];
+        let url = Url::parse("https://x.com/a/status/1").unwrap();
+        assert_eq!(
+            simulate(&rules, &url),
+            Simulation {
+                matched: vec![0, 1, 2],
+                invalid: vec![0],
+                applied: Some((
+                    1,
+                    vec![
+                        Url::parse("https://vxtwitter.com/a/status/1").unwrap(),
+                        Url::parse("https://fixvx.com/a/status/1").unwrap(),
+                    ]
+                )),
+            }
+        );
+        assert_eq!(
+            simulate(&rules, &Url::parse("https://example.com/").unwrap()),
+            Simulation::default()
+        );
+        assert_eq!(duplicate_rules(&rules), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_check() {
+        let config = Config {
+            url_rewrites: vec![
+                UrlRewrite::new(
+                    Regex::new(r"^https://(www\.)?x\.com/").unwrap(),
+                    "https://vxtwitter.com/",
+                ),
+                UrlRewrite::new(
+                    Regex::new(r"^https://x\.com/").unwrap(),
+                    "https://fxtwitter.com/",
+                ),
+            ],
+            ..Default::default()
+        };
+        let admin_rules = [UrlRewriteRow {
+            id: 7,
+            pattern: "^https://old\\.example/".to_string(),
+            replacement: "https://new.example/".to_string(),
+        }];
+        let samples = [
+            "https://x.com/a/status/1".to_string(),
+            "https://old.example/page".to_string(),
+            "https://matrix.to/#/#room:example.org".to_string(),
+            "not a url".to_string(),
+        ];
+        let report = check(&config, &admin_rules, &samples);
+        assert_eq!(
+            report.lines[1..],
+            [
+                "https://x.com/a/status/1",
+                "  -> https://vxtwitter.com/a/status/1 (rewrite 1)",
+                "  also matches rewrite 2",
+                "https://old.example/page",
+                "  -> https://new.example/page (admin rewrite #7)",
+                "https://matrix.to/#/#room:example.org",
+                "  ignored",
+            ]
+        );
+        assert_eq!(report.problems.len(), 2);
+        assert!(report.problems[0].starts_with("Sample URL `not a url` is invalid"));
+        assert!(report.problems[1].starts_with("rewrite 2 (`^https://x\\.com/`) never applies"));
+    }
+}
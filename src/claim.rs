@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedUserId, UserId};
+use tracing::{debug, warn};
+
+/// Event type a bot sends to announce that it will embed the links in a
+/// message, so other bots in the room that understand it hold back. Bots
+/// that don't are unaffected, and ignore it like any unknown event.
+pub const CLAIM_EVENT_TYPE: &str = "io.github.jchv.matrix_embed.claim";
+
+/// How long to wait after claiming a message for competing claims to
+/// arrive, before going by the earliest one.
+pub const SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+/// How long claims are remembered. Messages are claimed within seconds of
+/// arriving; this only has to cover edits shortly afterwards.
+const MAX_CLAIM_AGE: Duration = Duration::from_secs(15 * 60);
+
+static CLAIMS: LazyLock<Claims> = LazyLock::new(Claims::default);
+
+/// Claims seen in all rooms, recorded as they arrive in the sync.
+pub fn claims() -> &'static Claims {
+    &CLAIMS
+}
+
+/// One bot's claim on a message. Claims order by when they were sent, then
+/// by sender, so every bot sees the same one first.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Claim {
+    /// The claim's `origin_server_ts`, in milliseconds since the Unix epoch.
+    ts: u64,
+    sender: OwnedUserId,
+}
+
+struct Entry {
+    claims: Vec<Claim>,
+    seen_at: Instant,
+}
+
+/// Claims on messages, by the message's event ID.
+#[derive(Default)]
+pub struct Claims {
+    entries: Mutex<HashMap<OwnedEventId, Entry>>,
+}
+
+impl Claims {
+    /// Note that `sender` claimed `event_id` at `ts`, in milliseconds since
+    /// the Unix epoch. Only a bot's first claim on a message counts.
+    pub fn record(&self, event_id: &EventId, sender: &UserId, ts: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.seen_at.elapsed() < MAX_CLAIM_AGE);
+        let entry = entries.entry(event_id.to_owned()).or_insert_with(|| Entry {
+            claims: Vec::new(),
+            seen_at: Instant::now(),
+        });
+        if !entry.claims.iter().any(|claim| *claim.sender == *sender) {
+            entry.claims.push(Claim {
+                ts,
+                sender: sender.to_owned(),
+            });
+        }
+    }
+
+    fn has_claimed(&self, event_id: &EventId, sender: &UserId) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(event_id)
+            .is_some_and(|entry| entry.claims.iter().any(|claim| *claim.sender == *sender))
+    }
+
+    /// The bot whose claim on `event_id` came first, if any did.
+    fn winner(&self, event_id: &EventId) -> Option<OwnedUserId> {
+        let entries = self.entries.lock().unwrap();
+        let claim = entries.get(event_id)?.claims.iter().min()?;
+        Some(claim.sender.clone())
+    }
+
+    /// Decide whether `own_user_id` embeds the links in `event_id`. After a
+    /// random delay of up to `max_delay`, so bots don't all claim at once,
+    /// it's claimed with `send_claim` unless another bot got there first;
+    /// then the earliest claim that arrives within `settle` wins.
+    ///
+    /// If the claim can't be sent, the links are embedded anyway: a second
+    /// embed is better than none.
+    pub async fn contend(
+        &self,
+        event_id: &EventId,
+        own_user_id: &UserId,
+        max_delay: Duration,
+        settle: Duration,
+        send_claim: impl Future<Output = Result<()>>,
+    ) -> bool {
+        // Already decided, like when the message is edited.
+        if self.has_claimed(event_id, own_user_id) {
+            return self.won(event_id, own_user_id);
+        }
+
+        tokio::time::sleep(max_delay.mul_f64(rand::random::<f64>())).await;
+        if !self.won(event_id, own_user_id) {
+            return false;
+        }
+
+        let sent_at = now_millis();
+        if let Err(e) = send_claim.await {
+            warn!("Failed to claim {}: {:?}", event_id, e);
+            return true;
+        }
+        tokio::time::sleep(settle).await;
+        // Our own claim is normally back from the homeserver by now. If it
+        // isn't, go by when it was sent.
+        if !self.has_claimed(event_id, own_user_id) {
+            self.record(event_id, own_user_id, sent_at);
+        }
+        self.won(event_id, own_user_id)
+    }
+
+    /// Whether no bot other than `user_id` has the first claim on
+    /// `event_id`.
+    fn won(&self, event_id: &EventId, user_id: &UserId) -> bool {
+        match self.winner(event_id) {
+            Some(winner) if *winner != *user_id => {
+                debug!("{} was claimed by {}", event_id, winner);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{event_id, user_id};
+
+    async fn unexpected_claim() -> Result<()> {
+        panic!("claimed a message that was already decided");
+    }
+
+    #[test]
+    fn test_winner() {
+        let claims = Claims::default();
+        let event_id = event_id!("$message");
+        assert_eq!(claims.winner(event_id), None);
+
+        claims.record(event_id, user_id!("@b:example.com"), 100);
+        claims.record(event_id, user_id!("@a:example.com"), 100);
+        // Ties go to the lowest user ID.
+        assert_eq!(claims.winner(event_id).unwrap().as_str(), "@a:example.com");
+
+        // Only a bot's first claim counts.
+        claims.record(event_id, user_id!("@b:example.com"), 10);
+        assert_eq!(claims.winner(event_id).unwrap().as_str(), "@a:example.com");
+
+        claims.record(event_id, user_id!("@c:example.com"), 50);
+        assert_eq!(claims.winner(event_id).unwrap().as_str(), "@c:example.com");
+    }
+
+    #[tokio::test]
+    async fn test_contend() {
+        let event_id = event_id!("$message");
+        let (a, b) = (user_id!("@a:example.com"), user_id!("@b:example.com"));
+
+        // Another bot claimed it first, so there's nothing to send.
+        let claims = Claims::default();
+        claims.record(event_id, b, 1);
+        let won = claims
+            .contend(
+                event_id,
+                a,
+                Duration::ZERO,
+                Duration::ZERO,
+                unexpected_claim(),
+            )
+            .await;
+        assert!(!won);
+
+        // Another bot's claim that was sent earlier but arrives while we
+        // wait still wins, and the other bot agrees.
+        let claims = Claims::default();
+        let won = claims
+            .contend(event_id, a, Duration::ZERO, Duration::ZERO, async {
+                claims.record(event_id, a, 200);
+                claims.record(event_id, b, 100);
+                anyhow::Ok(())
+            })
+            .await;
+        assert!(!won);
+        assert!(
+            claims
+                .contend(
+                    event_id,
+                    b,
+                    Duration::ZERO,
+                    Duration::ZERO,
+                    unexpected_claim()
+                )
+                .await
+        );
+
+        // Our own claim counts from when it was sent until it comes back.
+        let claims = Claims::default();
+        let won = claims
+            .contend(event_id, a, Duration::ZERO, Duration::ZERO, async {
+                anyhow::Ok(())
+            })
+            .await;
+        assert!(won);
+        // Edits are decided the same way, without claiming again.
+        assert!(
+            claims
+                .contend(
+                    event_id,
+                    a,
+                    Duration::ZERO,
+                    Duration::ZERO,
+                    unexpected_claim()
+                )
+                .await
+        );
+
+        // Without a claim, embed anyway.
+        let claims = Claims::default();
+        let won = claims
+            .contend(event_id, a, Duration::ZERO, Duration::ZERO, async {
+                anyhow::bail!("forbidden")
+            })
+            .await;
+        assert!(won);
+    }
+}
@@ -1,19 +1,45 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::activitypub::ActivityPubDetector;
 use crate::cas::MediaStore;
-use crate::config::Config;
+use crate::config::{
+    CaptionLayout, Config, EmbedMode, LinkPolicy, TimeStyle, UrlRewrite, VideoFormat,
+};
 use crate::db::{CannedResponse, Database};
+use crate::describe;
+use crate::extract::extract_urls;
+use crate::http::{self, Fetch};
+use crate::jobs::JobRegistry;
 use crate::key_sharing;
+use crate::locale;
 use crate::metadata::Metadata;
+use crate::processing::format_duration;
+use crate::profile;
+use crate::quiet::QuietHours;
 use anyhow::{Context, Result, bail};
 use matrix_sdk::Client;
 use matrix_sdk::encryption::CrossSigningResetAuthType;
 use matrix_sdk::ruma::api::client::uiaa;
-use matrix_sdk::ruma::{OwnedDeviceId, RoomId};
+use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
+use matrix_sdk::ruma::{EventId, OwnedDeviceId, RoomId, UserId};
+use regex::Regex;
 use tracing::{error, info, warn};
 use url::Url;
 
+/// Number of embeds `purge` removes when no count is given.
+const DEFAULT_PURGE_COUNT: usize = 10;
+
+/// Upper bound for a single `purge`, to keep it from hitting rate limits for
+/// too long.
+const MAX_PURGE_COUNT: usize = 100;
+
+/// Number of domains `domains` lists when no count is given.
+const DEFAULT_DOMAINS_COUNT: usize = 20;
+
+/// Upper bound for `domains`, to keep the response a readable size.
+const MAX_DOMAINS_COUNT: usize = 100;
+
 pub enum CommandResult {
     NotACommand,
     Response(String),
@@ -23,6 +49,8 @@ pub enum CommandResult {
         key_count: usize,
     },
     CannedResponse(CannedResponse),
+    /// Embed this link again with freshly fetched data.
+    Refresh(Url),
 }
 
 pub async fn handle_command(
@@ -35,6 +63,7 @@ pub async fn handle_command(
     http_client: &reqwest::Client,
     media_store: &MediaStore,
     ap_detector: &ActivityPubDetector,
+    jobs: &JobRegistry,
 ) -> CommandResult {
     let trimmed = body.trim();
     let prefix = &config.command_prefix;
@@ -54,11 +83,15 @@ pub async fn handle_command(
                     http_client,
                     media_store,
                     ap_detector,
+                    jobs,
                     prefix,
                 )
                 .await
             }
             Some("export-keys") => handle_export_keys(room_id, client, database, prefix).await,
+            Some("version") => CommandResult::Response(describe::about(config)),
+            Some("help") => handle_help(room_id, config, client, database, prefix).await,
+            Some("refresh") => handle_refresh(&args[2..], config, prefix),
             Some(other) => CommandResult::Response(format!(
                 "Unknown command `{}`. {}",
                 other,
@@ -85,11 +118,57 @@ fn usage_root(prefix: &str) -> String {
     format!(
         "Usage: `{prefix} <subcommand>`\n\n\
 Available subcommands:\n\
+- `help` — Show what the bot does and its settings in this room\n\
+- `version` — Show the bot's version and what it can embed\n\
+- `refresh <url>` — Embed a link again with freshly fetched data, editing the bot's last embed of it in this room\n\
 - `export-keys` — Export room keys for this room (Element-compatible format)\n\
 - `admin` — Admin commands (trusted users only)"
     )
 }
 
+async fn handle_help(
+    room_id: &str,
+    config: &Config,
+    client: &Client,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let room = RoomId::parse(room_id)
+        .ok()
+        .and_then(|room_id| client.get_room(&room_id));
+    let direct = match room {
+        Some(room) => room.is_direct().await.unwrap_or_else(|e| {
+            warn!(
+                "Failed to check whether {} is a direct chat: {:?}",
+                room_id, e
+            );
+            false
+        }),
+        None => false,
+    };
+    let settings = match describe::room_settings(config, database, room_id, direct).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to look up settings for {}: {:?}", room_id, e);
+            format!("Failed to look up the settings in this room: {}\n", e)
+        }
+    };
+    CommandResult::Response(format!(
+        "{}\n{}\n{}",
+        describe::about(config),
+        settings,
+        usage_root(prefix)
+    ))
+}
+
+fn handle_refresh(args: &[&str], config: &Config, prefix: &str) -> CommandResult {
+    let text = TextMessageEventContent::plain(args.join(" "));
+    match extract_urls(&text, config, true).into_iter().next() {
+        Some(url) => CommandResult::Refresh(url),
+        None => CommandResult::Response(format!("Usage: `{} refresh <url>`", prefix)),
+    }
+}
+
 fn usage_admin(prefix: &str) -> String {
     format!(
         "Usage: `{prefix} admin <subcommand>`\n\n\
@@ -101,6 +180,52 @@ Available subcommands:\n\
 - `enable-key-sharing` — Enable automatic room key distribution in this room\n\
 - `disable-key-sharing` — Disable automatic room key distribution in this room\n\
 - `list-key-sharing` — List all rooms with key sharing enabled\n\
+- `enable-summaries` — Enable LLM-generated article summaries in this room\n\
+- `disable-summaries` — Disable LLM-generated article summaries in this room\n\
+- `enable-data-saver` — Reencode videos in this room to smaller files\n\
+- `disable-data-saver` — Stop reencoding videos in this room\n\
+- `enable-bare-links` — Also embed `www.` links without a scheme in this room\n\
+- `disable-bare-links` — Stop embedding `www.` links without a scheme in this room\n\
+- `enable-caption-links` — Also embed links in media captions and stickers in this room\n\
+- `disable-caption-links` — Stop embedding links in media captions and stickers in this room\n\
+- `enable-summary-thumbnails` — Post the image of text-only summary cards as a small thumbnail in this room\n\
+- `disable-summary-thumbnails` — Keep summary cards text-only in this room\n\
+- `enable-video-posters` — Also post the poster image of embedded videos in this room\n\
+- `disable-video-posters` — Post only the video of embeds in this room\n\
+- `enable-publish-info` — Describe the bot in a state event in this room, and give it a topic if it has none\n\
+- `disable-publish-info` — Stop describing the bot in this room\n\
+- `set-embed-mode <always|encrypted-only|never>` — Choose whether links in this room are embedded, or only if it's encrypted\n\
+- `clear-embed-mode` — Use the default embed mode in this room\n\
+- `set-caption-layout <on-media|media-first|text-first>` — Post embed text as the caption of the media, or as its own message before or after it\n\
+- `clear-caption-layout` — Use the default caption layout in this room\n\
+- `set-link-policy <first|all>` — Embed only the first link of a message with several, or each of them\n\
+- `clear-link-policy` — Use the default link policy in this room\n\
+- `set-video-format <mp4|webm>` — Convert videos in this room to this container\n\
+- `clear-video-format` — Use the default video format in this room\n\
+- `set-language <tag>` — Ask for pages linked in this room in this language (e.g. `de` or `pt-BR`), and embed their translation when they link one\n\
+- `clear-language` — Use the default language in this room\n\
+- `set-timezone <timezone>` — Write times in this room in this IANA timezone (e.g. `Europe/Berlin`)\n\
+- `set-time-style <absolute|relative>` — Write times in this room as dates or as \"3 hours ago\"\n\
+- `clear-time-format` — Use the default timezone and time style in this room\n\
+- `set-embed-power-level <level>` — Only embed links from users with at least this power level in this room\n\
+- `clear-embed-power-level` — Use the default embed power level requirement in this room\n\
+- `set-quiet-hours <HH:MM-HH:MM> [digest]` — Queue links in this room during these hours every day, in its timezone, and post them when they end (as one digest message with `digest`)\n\
+- `clear-quiet-hours` — Embed links in this room as they're posted at any time\n\
+- `enable-digest [minutes]` — Collect links in this room and post them together in one digest message every so many minutes, instead of embedding each\n\
+- `disable-digest` — Embed links in this room one by one again\n\
+- `queue` — List the embeds in progress\n\
+- `cancel <id>` — Stop the embed with this ID from `queue`\n\
+- `domains [count]` — Show how embeds of each domain turned out, failing domains first (default {DEFAULT_DOMAINS_COUNT}, at most {MAX_DOMAINS_COUNT})\n\
+- `purge [count]` — Redact the bot's last embeds in this room (default {DEFAULT_PURGE_COUNT}, at most {MAX_PURGE_COUNT})\n\
+- `set-room-name <name>` — Set the bot's display name in this room\n\
+- `set-room-avatar <mxc_or_image_url>` — Set the bot's avatar in this room\n\
+- `clear-room-profile` — Use the bot's default display name and avatar in this room\n\
+- `add-rewrite <regex> <replacement>` — Add a URL rewrite rule, applied before the configured ones\n\
+- `remove-rewrite <n>` — Remove the nth rule shown by `list-rewrites`\n\
+- `list-rewrites` — List URL rewrite rules added with `add-rewrite`\n\
+- `trust <user_id>` — Let this user use admin commands\n\
+- `untrust <user_id>` — Stop letting a user added with `trust` use admin commands\n\
+- `list-trusted` — List the users who can use admin commands\n\
 - `add-command [--global] <name> [media_url] [text...]` — Add/update a custom command\n\
 - `remove-command [--global] <name>` — Remove a custom command\n\
 - `list-commands [--global]` — List custom commands for this room (or globally)\n\
@@ -130,9 +255,10 @@ async fn handle_admin(
     http_client: &reqwest::Client,
     media_store: &MediaStore,
     ap_detector: &ActivityPubDetector,
+    jobs: &JobRegistry,
     prefix: &str,
 ) -> CommandResult {
-    if !config.trusted_users.iter().any(|u| u == sender) {
+    if !config.is_trusted(sender) {
         warn!("Untrusted user {} attempted to use admin command", sender);
         return CommandResult::Response(
             "Permission denied. This command is restricted to trusted users.".to_string(),
@@ -152,6 +278,119 @@ async fn handle_admin(
             handle_disable_key_sharing(room_id, &args[1..], database).await
         }
         Some("list-key-sharing") => handle_list_key_sharing(database).await,
+        Some("enable-summaries") => {
+            handle_enable_summaries(room_id, &args[1..], config, database).await
+        }
+        Some("disable-summaries") => handle_disable_summaries(room_id, &args[1..], database).await,
+        Some("enable-data-saver") => handle_enable_data_saver(room_id, &args[1..], database).await,
+        Some("disable-data-saver") => {
+            handle_disable_data_saver(room_id, &args[1..], database).await
+        }
+        Some("enable-bare-links") => handle_enable_bare_links(room_id, &args[1..], database).await,
+        Some("disable-bare-links") => {
+            handle_disable_bare_links(room_id, &args[1..], config, database).await
+        }
+        Some("enable-caption-links") => {
+            handle_enable_caption_links(room_id, &args[1..], database).await
+        }
+        Some("disable-caption-links") => {
+            handle_disable_caption_links(room_id, &args[1..], config, database).await
+        }
+        Some("enable-summary-thumbnails") => {
+            handle_enable_summary_thumbnails(room_id, &args[1..], database).await
+        }
+        Some("disable-summary-thumbnails") => {
+            handle_disable_summary_thumbnails(room_id, &args[1..], config, database).await
+        }
+        Some("enable-video-posters") => {
+            handle_enable_video_posters(room_id, &args[1..], database).await
+        }
+        Some("disable-video-posters") => {
+            handle_disable_video_posters(room_id, &args[1..], config, database).await
+        }
+        Some("enable-publish-info") => {
+            handle_enable_publish_info(room_id, &args[1..], config, client, database).await
+        }
+        Some("disable-publish-info") => {
+            handle_disable_publish_info(room_id, &args[1..], config, database).await
+        }
+        Some("set-embed-mode") => {
+            handle_set_embed_mode(room_id, &args[1..], database, prefix).await
+        }
+        Some("clear-embed-mode") => {
+            handle_clear_embed_mode(room_id, &args[1..], config, database).await
+        }
+        Some("set-caption-layout") => {
+            handle_set_caption_layout(room_id, &args[1..], database, prefix).await
+        }
+        Some("clear-caption-layout") => {
+            handle_clear_caption_layout(room_id, &args[1..], config, database).await
+        }
+        Some("set-link-policy") => {
+            handle_set_link_policy(room_id, &args[1..], database, prefix).await
+        }
+        Some("clear-link-policy") => {
+            handle_clear_link_policy(room_id, &args[1..], config, database).await
+        }
+        Some("set-video-format") => {
+            handle_set_video_format(room_id, &args[1..], database, prefix).await
+        }
+        Some("clear-video-format") => {
+            handle_clear_video_format(room_id, &args[1..], config, database).await
+        }
+        Some("set-language") => handle_set_language(room_id, &args[1..], database, prefix).await,
+        Some("clear-language") => {
+            handle_clear_language(room_id, &args[1..], config, database).await
+        }
+        Some("set-timezone") => handle_set_timezone(room_id, &args[1..], database, prefix).await,
+        Some("set-time-style") => {
+            handle_set_time_style(room_id, &args[1..], database, prefix).await
+        }
+        Some("clear-time-format") => {
+            handle_clear_time_format(room_id, &args[1..], config, database).await
+        }
+        Some("set-embed-power-level") => {
+            handle_set_embed_power_level(room_id, &args[1..], database, prefix).await
+        }
+        Some("clear-embed-power-level") => {
+            handle_clear_embed_power_level(room_id, &args[1..], config, database).await
+        }
+        Some("set-quiet-hours") => {
+            handle_set_quiet_hours(room_id, &args[1..], config, database, prefix).await
+        }
+        Some("clear-quiet-hours") => handle_clear_quiet_hours(room_id, &args[1..], database).await,
+        Some("enable-digest") => {
+            handle_enable_digest(room_id, &args[1..], config, database, prefix).await
+        }
+        Some("disable-digest") => handle_disable_digest(room_id, &args[1..], database).await,
+        Some("queue") => handle_queue(jobs),
+        Some("cancel") => handle_cancel(&args[1..], jobs, prefix),
+        Some("domains") => handle_domains(&args[1..], database, prefix).await,
+        Some("purge") => handle_purge(room_id, &args[1..], client, database, prefix).await,
+        Some("set-room-name") => {
+            handle_set_room_name(room_id, &args[1..], config, client, database, prefix).await
+        }
+        Some("set-room-avatar") => {
+            handle_set_room_avatar(
+                room_id,
+                &args[1..],
+                config,
+                client,
+                database,
+                http_client,
+                prefix,
+            )
+            .await
+        }
+        Some("clear-room-profile") => {
+            handle_clear_room_profile(room_id, config, client, database).await
+        }
+        Some("add-rewrite") => handle_add_rewrite(&args[1..], config, database, prefix).await,
+        Some("remove-rewrite") => handle_remove_rewrite(&args[1..], config, database, prefix).await,
+        Some("list-rewrites") => handle_list_rewrites(config, database).await,
+        Some("trust") => handle_trust(&args[1..], sender, config, database, prefix).await,
+        Some("untrust") => handle_untrust(&args[1..], sender, config, database, prefix).await,
+        Some("list-trusted") => handle_list_trusted(config, database).await,
         Some("add-command") => {
             handle_add_command(
                 room_id,
@@ -477,551 +716,3500 @@ async fn handle_disable_key_sharing(
     }
 }
 
-async fn handle_list_key_sharing(database: &Arc<Database>) -> CommandResult {
-    info!("Admin request to list key-sharing rooms");
+async fn handle_enable_summaries(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    match database.list_key_sharing_rooms().await {
-        Ok(rooms) if rooms.is_empty() => {
-            CommandResult::Response("No rooms have key sharing enabled.".to_string())
-        }
-        Ok(rooms) => {
-            let mut lines = vec![format!(
-                "**Rooms with key sharing enabled ({}):**\n",
-                rooms.len()
-            )];
-            for room_id in &rooms {
-                lines.push(format!("- `{}`", room_id));
+    info!("Admin request to enable summaries for room {}", room_id);
+
+    match database.enable_summaries(room_id).await {
+        Ok(()) => {
+            let mut response =
+                format!("Article summaries have been **enabled** for `{}`.", room_id);
+            if config.summary_api_url.is_none() {
+                response.push_str(
+                    "\n\nNote: no summary endpoint is configured, so no summaries will be posted.",
+                );
             }
-            CommandResult::Response(lines.join("\n"))
+            CommandResult::Response(response)
         }
         Err(e) => {
-            error!("Failed to list key-sharing rooms: {:?}", e);
-            CommandResult::Response(format!("Failed to list key-sharing rooms: {}", e))
+            error!("Failed to enable summaries for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to enable summaries: {}", e))
         }
     }
 }
 
-async fn fetch_and_store_media(
-    url_str: &str,
-    http_client: &reqwest::Client,
-    config: &Config,
-    media_store: &MediaStore,
-    ap_detector: &ActivityPubDetector,
-) -> Result<(String, String, String)> {
-    let url = Url::parse(url_str).context("Invalid URL")?;
-    let meta = Metadata::fetch_from_url(http_client, &url, ap_detector).await?;
-    let media_url = meta
-        .video_url
-        .or(meta.audio_url)
-        .or(meta.image_url)
-        .unwrap_or_else(|| url.clone());
-
-    let response = http_client
-        .get(media_url.clone())
-        .timeout(config.download_timeout)
-        .send()
-        .await
-        .context("Failed to download media")?
-        .error_for_status()
-        .context("Media download returned error status")?;
-
-    let mime_type = response
-        .headers()
-        .get(reqwest::header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("application/octet-stream")
-        .split(';')
-        .next()
-        .unwrap_or("application/octet-stream")
-        .trim()
-        .to_string();
+async fn handle_disable_summaries(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    let filename = media_url
-        .path_segments()
-        .and_then(|mut s| s.next_back())
-        .filter(|s| !s.is_empty())
-        .unwrap_or("media")
-        .to_string();
+    info!("Admin request to disable summaries for room {}", room_id);
 
-    let data = response.bytes().await.context("Failed to read media")?;
-    let hash = media_store.store(&data).await?;
-    Ok((hash, filename, mime_type))
+    match database.disable_summaries(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Article summaries have been **disabled** for `{}`.",
+            room_id
+        )),
+        Err(e) => {
+            error!("Failed to disable summaries for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to disable summaries: {}", e))
+        }
+    }
 }
 
-async fn handle_add_command(
-    room_id: &str,
+async fn handle_enable_data_saver(
+    mut room_id: &str,
     args: &[&str],
     database: &Arc<Database>,
-    http_client: &reqwest::Client,
-    config: &Config,
-    media_store: &MediaStore,
-    ap_detector: &ActivityPubDetector,
-    prefix: &str,
 ) -> CommandResult {
-    let (room_id, args) = parse_global_flag(room_id, args);
-    let scope = if room_id.is_empty() { " globally" } else { "" };
-
-    let Some(name) = args.first().copied() else {
-        return CommandResult::Response(format!(
-            "Usage: `{prefix} admin add-command [--global] <name> [media_url] [text...]`"
-        ));
-    };
-
-    if !name.starts_with('!') {
-        return CommandResult::Response("Command name must start with `!`.".to_string());
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
     }
 
-    let mut rest = &args[1..];
-    let mut media_info = None;
+    info!("Admin request to enable data saver for room {}", room_id);
 
-    if let Some(first) = rest.first() {
-        if first.starts_with("http://") || first.starts_with("https://") {
-            match fetch_and_store_media(first, http_client, config, media_store, ap_detector).await
-            {
-                Ok(info) => media_info = Some(info),
-                Err(e) => {
-                    return CommandResult::Response(format!("Failed to fetch media: {}", e));
-                }
-            }
-            rest = &rest[1..];
+    match database.enable_data_saver(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Data saver has been **enabled** for `{}`. Videos will be reencoded to smaller files.",
+            room_id
+        )),
+        Err(e) => {
+            error!("Failed to enable data saver for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to enable data saver: {}", e))
         }
     }
+}
 
-    let text = if rest.is_empty() {
-        None
-    } else {
-        Some(rest.join(" "))
-    };
-
-    if text.is_none() && media_info.is_none() {
-        return CommandResult::Response(format!(
-            "Must provide at least text or a media URL.\n\n\
-             Usage: `{prefix} admin add-command [--global] <name> [media_url] [text...]`"
-        ));
+async fn handle_disable_data_saver(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
     }
 
-    let (cas_hash, filename, mime_type) = match &media_info {
-        Some((h, f, m)) => (Some(h.as_str()), Some(f.as_str()), Some(m.as_str())),
-        None => (None, None, None),
-    };
-
-    let response_id = match database
-        .create_canned_response(text.as_deref(), cas_hash, filename, mime_type)
-        .await
-    {
-        Ok(id) => id,
-        Err(e) => {
-            error!("Failed to create canned response: {:?}", e);
-            return CommandResult::Response(format!("Failed to create response: {}", e));
-        }
-    };
+    info!("Admin request to disable data saver for room {}", room_id);
 
-    match database
-        .add_custom_command(room_id, name, response_id)
-        .await
-    {
+    match database.disable_data_saver(room_id).await {
         Ok(()) => CommandResult::Response(format!(
-            "Custom command `{}` has been set{}{}.",
-            name,
-            scope,
-            if media_info.is_some() {
-                " (with media)"
-            } else {
-                ""
-            }
+            "Data saver has been **disabled** for `{}`.",
+            room_id
         )),
         Err(e) => {
-            error!("Failed to add custom command: {:?}", e);
-            CommandResult::Response(format!("Failed to add command: {}", e))
+            error!("Failed to disable data saver for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to disable data saver: {}", e))
         }
     }
 }
 
-async fn handle_remove_command(
-    room_id: &str,
+async fn handle_enable_bare_links(
+    mut room_id: &str,
     args: &[&str],
     database: &Arc<Database>,
-    prefix: &str,
 ) -> CommandResult {
-    let (room_id, args) = parse_global_flag(room_id, args);
-    let scope = if room_id.is_empty() {
-        "globally"
-    } else {
-        "in this room"
-    };
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    let Some(name) = args.first().copied() else {
-        return CommandResult::Response(format!(
-            "Usage: `{prefix} admin remove-command [--global] <name>`"
-        ));
-    };
+    info!("Admin request to enable bare links for room {}", room_id);
 
-    match database.remove_custom_command(room_id, name).await {
-        Ok(true) => CommandResult::Response(format!("Custom command `{}` has been removed.", name)),
-        Ok(false) => {
-            CommandResult::Response(format!("No custom command `{}` found {}.", name, scope))
-        }
+    match database.enable_bare_links(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Links starting with `www.` will now be embedded in `{}`.",
+            room_id
+        )),
         Err(e) => {
-            error!("Failed to remove custom command: {:?}", e);
-            CommandResult::Response(format!("Failed to remove command: {}", e))
+            error!("Failed to enable bare links for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to enable bare links: {}", e))
         }
     }
 }
 
-async fn handle_list_commands(
-    room_id: &str,
+async fn handle_disable_bare_links(
+    mut room_id: &str,
     args: &[&str],
+    config: &Config,
     database: &Arc<Database>,
 ) -> CommandResult {
-    let (room_id, _args) = parse_global_flag(room_id, args);
-    let scope = if room_id.is_empty() {
-        "globally"
-    } else {
-        "for this room"
-    };
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    match database.list_custom_commands(room_id).await {
-        Ok(cmds) if cmds.is_empty() => {
-            CommandResult::Response(format!("No custom commands configured {}.", scope))
-        }
-        Ok(cmds) => {
-            let mut lines = vec![format!("**Custom commands {} ({}):**\n", scope, cmds.len())];
-            for cmd in &cmds {
-                let has_media = cmd.response.media_cas_hash.is_some();
-                let text_preview = cmd.response.text_markdown.as_deref().unwrap_or("(no text)");
-                let truncated = if text_preview.len() > 50 {
-                    format!("{}…", &text_preview[..50])
-                } else {
-                    text_preview.to_string()
-                };
-                lines.push(format!(
-                    "- `{}` — {}{}",
-                    cmd.command_name,
-                    truncated,
-                    if has_media { " 📎" } else { "" }
-                ));
-            }
-            CommandResult::Response(lines.join("\n"))
-        }
+    info!("Admin request to disable bare links for room {}", room_id);
+
+    match database.disable_bare_links(room_id).await {
+        Ok(()) if config.bare_www_links => CommandResult::Response(format!(
+            "Bare links have been **disabled** for `{}`, but they're still enabled globally.",
+            room_id
+        )),
+        Ok(()) => CommandResult::Response(format!(
+            "Bare links have been **disabled** for `{}`.",
+            room_id
+        )),
         Err(e) => {
-            error!("Failed to list custom commands: {:?}", e);
-            CommandResult::Response(format!("Failed to list commands: {}", e))
+            error!("Failed to disable bare links for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to disable bare links: {}", e))
         }
     }
 }
 
-async fn handle_add_autoresponder(
-    room_id: &str,
+async fn handle_enable_caption_links(
+    mut room_id: &str,
     args: &[&str],
     database: &Arc<Database>,
-    http_client: &reqwest::Client,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!("Admin request to enable caption links for room {}", room_id);
+
+    match database.enable_caption_links(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Links in media captions and stickers will now be embedded in `{}`.",
+            room_id
+        )),
+        Err(e) => {
+            error!("Failed to enable caption links for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to enable caption links: {}", e))
+        }
+    }
+}
+
+async fn handle_disable_caption_links(
+    mut room_id: &str,
+    args: &[&str],
     config: &Config,
-    media_store: &MediaStore,
-    ap_detector: &ActivityPubDetector,
-    prefix: &str,
+    database: &Arc<Database>,
 ) -> CommandResult {
-    let (room_id, args) = parse_global_flag(room_id, args);
-    let scope = if room_id.is_empty() { " globally" } else { "" };
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    let Some(pattern) = args.first().copied() else {
-        return CommandResult::Response(format!(
-            "Usage: `{prefix} admin add-autoresponder [--global] <pattern> [probability] [media_url] [text...]`"
-        ));
-    };
+    info!(
+        "Admin request to disable caption links for room {}",
+        room_id
+    );
 
-    if regex::Regex::new(pattern).is_err() {
-        return CommandResult::Response(format!("`{}` is not a valid regex pattern.", pattern));
+    match database.disable_caption_links(room_id).await {
+        Ok(()) if config.caption_links => CommandResult::Response(format!(
+            "Caption links have been **disabled** for `{}`, but they're still enabled globally.",
+            room_id
+        )),
+        Ok(()) => CommandResult::Response(format!(
+            "Caption links have been **disabled** for `{}`.",
+            room_id
+        )),
+        Err(e) => {
+            error!("Failed to disable caption links for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to disable caption links: {}", e))
+        }
     }
+}
 
-    let mut rest = &args[1..];
-    let mut probability = 1.0;
+async fn handle_enable_summary_thumbnails(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    if let Some(first) = rest.first() {
-        if let Ok(p) = first.parse::<f64>() {
-            if (0.0..=1.0).contains(&p) {
-                probability = p;
-                rest = &rest[1..];
-            }
+    info!(
+        "Admin request to enable summary thumbnails for room {}",
+        room_id
+    );
+
+    match database.enable_summary_thumbnails(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Summary cards in `{}` will now have a small thumbnail of their image.",
+            room_id
+        )),
+        Err(e) => {
+            error!(
+                "Failed to enable summary thumbnails for {}: {:?}",
+                room_id, e
+            );
+            CommandResult::Response(format!("Failed to enable summary thumbnails: {}", e))
         }
     }
+}
 
-    let mut media_info = None;
-    if let Some(first) = rest.first() {
-        if first.starts_with("http://") || first.starts_with("https://") {
-            match fetch_and_store_media(first, http_client, config, media_store, ap_detector).await
-            {
-                Ok(info) => media_info = Some(info),
-                Err(e) => {
-                    return CommandResult::Response(format!("Failed to fetch media: {}", e));
-                }
-            }
-            rest = &rest[1..];
+async fn handle_disable_summary_thumbnails(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!(
+        "Admin request to disable summary thumbnails for room {}",
+        room_id
+    );
+
+    match database.disable_summary_thumbnails(room_id).await {
+        Ok(()) if config.summary_thumbnails => CommandResult::Response(format!(
+            "Summary thumbnails have been **disabled** for `{}`, but they're still enabled globally.",
+            room_id
+        )),
+        Ok(()) => CommandResult::Response(format!(
+            "Summary thumbnails have been **disabled** for `{}`.",
+            room_id
+        )),
+        Err(e) => {
+            error!(
+                "Failed to disable summary thumbnails for {}: {:?}",
+                room_id, e
+            );
+            CommandResult::Response(format!("Failed to disable summary thumbnails: {}", e))
         }
     }
+}
 
-    let text = if rest.is_empty() {
-        None
-    } else {
-        Some(rest.join(" "))
-    };
+async fn handle_enable_publish_info(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    client: &Client,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    if text.is_none() && media_info.is_none() {
+    info!("Admin request to enable bot info for room {}", room_id);
+
+    if let Err(e) = database.enable_publish_info(room_id).await {
+        error!("Failed to enable bot info for {}: {:?}", room_id, e);
+        return CommandResult::Response(format!("Failed to enable bot info: {}", e));
+    }
+    // Rooms the bot is already in are described right away, the rest when
+    // it joins them.
+    if let Some(room) = RoomId::parse(room_id)
+        .ok()
+        .and_then(|room_id| client.get_room(&room_id))
+        && let Err(e) = describe::publish(&room, config).await
+    {
+        warn!("Failed to describe the bot in {}: {:?}", room_id, e);
         return CommandResult::Response(format!(
-            "Must provide at least text or a media URL.\n\n\
-             Usage: `{prefix} admin add-autoresponder [--global] <pattern> [probability] [media_url] [text...]`"
+            "Bot info has been **enabled** for `{}`, but describing the bot there failed: {}",
+            room_id, e
         ));
     }
+    CommandResult::Response(format!(
+        "The bot will now describe itself in `{}`, as far as its power level allows.",
+        room_id
+    ))
+}
 
-    let (cas_hash, filename, mime_type) = match &media_info {
-        Some((h, f, m)) => (Some(h.as_str()), Some(f.as_str()), Some(m.as_str())),
-        None => (None, None, None),
-    };
+async fn handle_disable_publish_info(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    let response_id = match database
-        .create_canned_response(text.as_deref(), cas_hash, filename, mime_type)
-        .await
-    {
-        Ok(id) => id,
-        Err(e) => {
-            error!("Failed to create canned response: {:?}", e);
-            return CommandResult::Response(format!("Failed to create response: {}", e));
-        }
-    };
+    info!("Admin request to disable bot info for room {}", room_id);
 
-    match database
-        .add_autoresponder(room_id, pattern, probability, response_id)
-        .await
-    {
-        Ok(()) => {
-            let prob_str = if probability < 1.0 {
-                format!(" ({}% chance)", (probability * 100.0) as u32)
-            } else {
-                String::new()
-            };
+    match database.disable_publish_info(room_id).await {
+        Ok(()) if config.publish_info_rooms.iter().any(|room| room == room_id) => {
             CommandResult::Response(format!(
-                "Autoresponder for `{}`{} has been set{}{}.",
-                pattern,
-                prob_str,
-                scope,
-                if media_info.is_some() {
-                    " (with media)"
-                } else {
-                    ""
-                }
+                "Bot info has been **disabled** for `{}`, but it's still configured to be published there.",
+                room_id
             ))
         }
+        Ok(()) => CommandResult::Response(format!(
+            "Bot info has been **disabled** for `{}`. What was already published stays until it's changed.",
+            room_id
+        )),
         Err(e) => {
-            error!("Failed to add autoresponder: {:?}", e);
-            CommandResult::Response(format!("Failed to add autoresponder: {}", e))
+            error!("Failed to disable bot info for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to disable bot info: {}", e))
         }
     }
 }
 
-async fn handle_remove_autoresponder(
-    room_id: &str,
+async fn handle_enable_video_posters(
+    mut room_id: &str,
     args: &[&str],
     database: &Arc<Database>,
-    prefix: &str,
 ) -> CommandResult {
-    let (room_id, args) = parse_global_flag(room_id, args);
-    let scope = if room_id.is_empty() {
-        "globally"
-    } else {
-        "in this room"
-    };
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    let Some(pattern) = args.first().copied() else {
-        return CommandResult::Response(format!(
-            "Usage: `{prefix} admin remove-autoresponder [--global] <pattern>`"
-        ));
-    };
+    info!("Admin request to enable video posters for room {}", room_id);
 
-    match database.remove_autoresponder(room_id, pattern).await {
-        Ok(true) => {
-            CommandResult::Response(format!("Autoresponder for `{}` has been removed.", pattern))
-        }
-        Ok(false) => CommandResult::Response(format!(
-            "No autoresponder for `{}` found {}.",
-            pattern, scope
+    match database.enable_video_posters(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Embedded videos in `{}` will now be followed by their poster image.",
+            room_id
         )),
         Err(e) => {
-            error!("Failed to remove autoresponder: {:?}", e);
-            CommandResult::Response(format!("Failed to remove autoresponder: {}", e))
+            error!("Failed to enable video posters for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to enable video posters: {}", e))
         }
     }
 }
 
-async fn handle_list_autoresponders(
-    room_id: &str,
+async fn handle_disable_video_posters(
+    mut room_id: &str,
     args: &[&str],
+    config: &Config,
     database: &Arc<Database>,
 ) -> CommandResult {
-    let (room_id, _args) = parse_global_flag(room_id, args);
-    let scope = if room_id.is_empty() {
-        "globally"
-    } else {
-        "for this room"
-    };
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    match database.list_autoresponders(room_id).await {
-        Ok(autos) if autos.is_empty() => {
-            CommandResult::Response(format!("No autoresponders configured {}.", scope))
-        }
-        Ok(autos) => {
-            let mut lines = vec![format!("**Autoresponders {} ({}):**\n", scope, autos.len())];
-            for auto in &autos {
-                let has_media = auto.response.media_cas_hash.is_some();
-                let text_preview = auto
-                    .response
-                    .text_markdown
-                    .as_deref()
-                    .unwrap_or("(no text)");
-                let truncated = if text_preview.len() > 50 {
-                    format!("{}…", &text_preview[..50])
-                } else {
-                    text_preview.to_string()
-                };
-                let prob_str = if auto.probability < 1.0 {
-                    format!(" ({}%)", (auto.probability * 100.0) as u32)
-                } else {
-                    String::new()
-                };
-                lines.push(format!(
-                    "- `{}`{} — {}{}",
-                    auto.pattern,
-                    prob_str,
-                    truncated,
-                    if has_media { " 📎" } else { "" }
-                ));
-            }
-            CommandResult::Response(lines.join("\n"))
+    info!(
+        "Admin request to disable video posters for room {}",
+        room_id
+    );
+
+    match database.disable_video_posters(room_id).await {
+        Ok(()) if config.video_posters => CommandResult::Response(format!(
+            "Video posters have been **disabled** for `{}`, but they're still enabled globally.",
+            room_id
+        )),
+        Ok(()) => CommandResult::Response(format!(
+            "Video posters have been **disabled** for `{}`.",
+            room_id
+        )),
+        Err(e) => {
+            error!("Failed to disable video posters for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to disable video posters: {}", e))
         }
+    }
+}
+
+async fn handle_set_embed_mode(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(mode) = args.first().and_then(|s| EmbedMode::from_name(s)) else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-embed-mode <always|encrypted-only|never> [room_id]`"
+        ));
+    };
+    if let Some(room_id_arg) = args.get(1).copied() {
+        room_id = room_id_arg;
+    }
+
+    info!(
+        "Admin request to set embed mode for room {} to {}",
+        room_id,
+        mode.name()
+    );
+
+    match database.set_embed_mode(room_id, mode).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Embed mode for `{}` set to **{}**.",
+            room_id,
+            mode.name()
+        )),
         Err(e) => {
-            error!("Failed to list autoresponders: {:?}", e);
-            CommandResult::Response(format!("Failed to list autoresponders: {}", e))
+            error!("Failed to set embed mode for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set embed mode: {}", e))
         }
     }
 }
 
-/// Check autoresponders for a room. Returns the first matching canned response
-/// (after rolling against the configured probability), or `None`.
-pub async fn check_autoresponders(
-    body: &str,
-    room_id: &str,
-    database: &Database,
-) -> Option<CannedResponse> {
-    let autoresponders = match database.get_autoresponders(room_id).await {
-        Ok(a) => a,
+async fn handle_clear_embed_mode(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!("Admin request to clear embed mode for room {}", room_id);
+
+    match database.clear_embed_mode(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Embed mode override removed for `{}`; using the default ({}).",
+            room_id,
+            config.embed_mode.name()
+        )),
         Err(e) => {
-            error!("Failed to get autoresponders: {:?}", e);
-            return None;
+            error!("Failed to clear embed mode for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to clear embed mode: {}", e))
         }
+    }
+}
+
+async fn handle_set_caption_layout(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(layout) = args.first().and_then(|s| CaptionLayout::from_name(s)) else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-caption-layout <on-media|media-first|text-first> [room_id]`"
+        ));
     };
+    if let Some(room_id_arg) = args.get(1).copied() {
+        room_id = room_id_arg;
+    }
 
-    for auto in autoresponders {
-        let re = match regex::Regex::new(&auto.pattern) {
-            Ok(re) => re,
-            Err(e) => {
-                warn!("Invalid autoresponder pattern '{}': {}", auto.pattern, e);
-                continue;
-            }
-        };
-        if re.is_match(body) {
-            if auto.probability < 1.0 {
-                use rand::Rng;
-                if rand::thread_rng().r#gen::<f64>() >= auto.probability {
-                    continue;
-                }
-            }
-            return Some(auto.response);
+    info!(
+        "Admin request to set caption layout for room {} to {}",
+        room_id,
+        layout.name()
+    );
+
+    match database.set_caption_layout(room_id, layout).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Caption layout for `{}` set to **{}**.",
+            room_id,
+            layout.name()
+        )),
+        Err(e) => {
+            error!("Failed to set caption layout for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set caption layout: {}", e))
         }
     }
-
-    None
 }
 
-pub(crate) async fn remove_device(
-    client: &Client,
+async fn handle_clear_caption_layout(
+    mut room_id: &str,
+    args: &[&str],
     config: &Config,
-    device_id: &OwnedDeviceId,
-) -> Result<()> {
-    let devices = [device_id.clone()];
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
 
-    match client.delete_devices(&devices, None).await {
-        Ok(_) => Ok(()),
+    info!("Admin request to clear caption layout for room {}", room_id);
+
+    match database.clear_caption_layout(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Caption layout override removed for `{}`; using the default ({}).",
+            room_id,
+            config.caption_layout.name()
+        )),
         Err(e) => {
-            // If the server requires interactive auth, handle the UIAA flow.
-            if let Some(info) = e.as_uiaa_response() {
-                let password = config
-                    .password
-                    .as_deref()
-                    .context("Server requires interactive auth to delete devices, but no password is configured")?;
+            error!("Failed to clear caption layout for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to clear caption layout: {}", e))
+        }
+    }
+}
 
-                let mut auth = uiaa::Password::new(
-                    uiaa::UserIdentifier::Matrix(uiaa::MatrixUserIdentifier::new(
-                        config.username.clone(),
-                    )),
-                    password.to_owned(),
-                );
-                auth.session = info.session.clone();
+async fn handle_set_link_policy(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(policy) = args.first().and_then(|s| LinkPolicy::from_name(s)) else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-link-policy <first|all> [room_id]`"
+        ));
+    };
+    if let Some(room_id_arg) = args.get(1).copied() {
+        room_id = room_id_arg;
+    }
 
-                client
-                    .delete_devices(&devices, Some(uiaa::AuthData::Password(auth)))
-                    .await
-                    .context("Failed to delete device with password auth")?;
+    info!(
+        "Admin request to set link policy for room {} to {}",
+        room_id,
+        policy.name()
+    );
 
-                return Ok(());
-            }
+    match database.set_link_policy(room_id, policy).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Link policy for `{}` set to **{}**.",
+            room_id,
+            policy.name()
+        )),
+        Err(e) => {
+            error!("Failed to set link policy for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set link policy: {}", e))
+        }
+    }
+}
 
-            bail!("Failed to delete device: {}", e);
+async fn handle_clear_link_policy(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!("Admin request to clear link policy for room {}", room_id);
+
+    match database.clear_link_policy(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Link policy override removed for `{}`; using the default ({}).",
+            room_id,
+            config.link_policy.name()
+        )),
+        Err(e) => {
+            error!("Failed to clear link policy for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to clear link policy: {}", e))
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+async fn handle_set_video_format(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(format) = args.first().and_then(|s| VideoFormat::from_name(s)) else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-video-format <mp4|webm> [room_id]`"
+        ));
+    };
+    if let Some(room_id_arg) = args.get(1).copied() {
+        room_id = room_id_arg;
+    }
 
-    fn test_config(trusted: Vec<&str>) -> Config {
-        Config {
-            trusted_users: trusted.into_iter().map(String::from).collect(),
-            ..Config::default()
+    info!(
+        "Admin request to set video format for room {} to {}",
+        room_id,
+        format.name()
+    );
+
+    match database.set_video_format(room_id, format).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Videos in `{}` will be converted to **{}**.",
+            room_id,
+            format.name()
+        )),
+        Err(e) => {
+            error!("Failed to set video format for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set video format: {}", e))
         }
     }
+}
 
-    async fn test_database() -> Arc<Database> {
-        Arc::new(Database::open_in_memory().await.unwrap())
+async fn handle_clear_video_format(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
     }
 
-    async fn run_cmd(
-        body: &str,
-        sender: &str,
-        room_id: &str,
-        config: &Config,
-        client: &Client,
-        database: &Arc<Database>,
-    ) -> CommandResult {
-        let dir = tempfile::TempDir::new().unwrap();
-        let media_store = crate::cas::MediaStore::open(dir.path()).await.unwrap();
-        let http_client = reqwest::Client::new();
-        let ap_detector = crate::activitypub::ActivityPubDetector::new();
-        handle_command(
-            body,
-            sender,
+    info!("Admin request to clear video format for room {}", room_id);
+
+    match database.clear_video_format(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Video format override removed for `{}`; videos are converted to {}.",
             room_id,
-            config,
-            client,
-            database,
-            &http_client,
-            &media_store,
-            &ap_detector,
+            config.video_format.name()
+        )),
+        Err(e) => {
+            error!("Failed to clear video format for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to clear video format: {}", e))
+        }
+    }
+}
+
+async fn handle_set_language(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(language) = args.first().and_then(|s| locale::normalize(s)) else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-language <tag> [room_id]` \
+             (a language tag such as `de` or `pt-BR`)"
+        ));
+    };
+    if let Some(room_id_arg) = args.get(1).copied() {
+        room_id = room_id_arg;
+    }
+
+    info!(
+        "Admin request to set language for room {} to {}",
+        room_id, language
+    );
+
+    match database.set_room_language(room_id, &language).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Pages linked in `{}` will be asked for in **{}**.",
+            room_id, language
+        )),
+        Err(e) => {
+            error!("Failed to set language for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set language: {}", e))
+        }
+    }
+}
+
+async fn handle_clear_language(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!("Admin request to clear language for room {}", room_id);
+
+    match database.clear_room_language(room_id).await {
+        Ok(()) => CommandResult::Response(match &config.language {
+            Some(language) => format!(
+                "Language override removed for `{}`; pages are asked for in {}.",
+                room_id, language
+            ),
+            None => format!(
+                "Language override removed for `{}`; pages are asked for in no particular language.",
+                room_id
+            ),
+        }),
+        Err(e) => {
+            error!("Failed to clear language for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to clear language: {}", e))
+        }
+    }
+}
+
+async fn handle_set_timezone(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(timezone) = args.first().and_then(|s| s.parse::<chrono_tz::Tz>().ok()) else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-timezone <timezone> [room_id]` \
+             (an IANA timezone such as `Europe/Berlin`)"
+        ));
+    };
+    if let Some(room_id_arg) = args.get(1).copied() {
+        room_id = room_id_arg;
+    }
+
+    info!(
+        "Admin request to set timezone for room {} to {}",
+        room_id,
+        timezone.name()
+    );
+
+    match database.set_room_timezone(room_id, timezone.name()).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Times in `{}` will be shown in **{}**.",
+            room_id,
+            timezone.name()
+        )),
+        Err(e) => {
+            error!("Failed to set timezone for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set timezone: {}", e))
+        }
+    }
+}
+
+async fn handle_set_time_style(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(style) = args.first().and_then(|s| TimeStyle::from_name(s)) else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-time-style <absolute|relative> [room_id]`"
+        ));
+    };
+    if let Some(room_id_arg) = args.get(1).copied() {
+        room_id = room_id_arg;
+    }
+
+    info!(
+        "Admin request to set time style for room {} to {}",
+        room_id,
+        style.name()
+    );
+
+    match database.set_room_time_style(room_id, style).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Times in `{}` will be shown as **{}**.",
+            room_id,
+            style.name()
+        )),
+        Err(e) => {
+            error!("Failed to set time style for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set time style: {}", e))
+        }
+    }
+}
+
+async fn handle_clear_time_format(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!("Admin request to clear time format for room {}", room_id);
+
+    match database.clear_room_time_format(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Time format overrides removed for `{}`; times are shown as {} in {}.",
+            room_id,
+            config.time_style.name(),
+            config.timezone.name()
+        )),
+        Err(e) => {
+            error!("Failed to clear time format for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to clear time format: {}", e))
+        }
+    }
+}
+
+async fn handle_set_embed_power_level(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(level) = args.first().and_then(|s| s.parse::<i64>().ok()) else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-embed-power-level <level> [room_id]`"
+        ));
+    };
+    if let Some(room_id_arg) = args.get(1).copied() {
+        room_id = room_id_arg;
+    }
+
+    info!(
+        "Admin request to set embed power level for room {} to {}",
+        room_id, level
+    );
+
+    match database.set_embed_min_power_level(room_id, level).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Links in `{}` will only be embedded for users with power level **{}** or higher.",
+            room_id, level
+        )),
+        Err(e) => {
+            error!("Failed to set embed power level for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set embed power level: {}", e))
+        }
+    }
+}
+
+async fn handle_clear_embed_power_level(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!(
+        "Admin request to clear embed power level for room {}",
+        room_id
+    );
+
+    match database.clear_embed_min_power_level(room_id).await {
+        Ok(()) => {
+            let default = match config.embed_min_power_level {
+                Some(level) => format!("power level {} or higher", level),
+                None => "everyone".to_string(),
+            };
+            CommandResult::Response(format!(
+                "Embed power level override removed for `{}`; links are embedded for {}.",
+                room_id, default
+            ))
+        }
+        Err(e) => {
+            error!("Failed to clear embed power level for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to clear embed power level: {}", e))
+        }
+    }
+}
+
+async fn handle_set_quiet_hours(
+    mut room_id: &str,
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let mut rest = args.get(1..).unwrap_or_default();
+    let digest = rest.first() == Some(&"digest");
+    if digest {
+        rest = &rest[1..];
+    }
+    let Some(hours) = args
+        .first()
+        .and_then(|window| QuietHours::parse(window, digest))
+    else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-quiet-hours <HH:MM-HH:MM> [digest] [room_id]` \
+             (e.g. `22:00-07:00`, which may run past midnight)"
+        ));
+    };
+    if let Some(room_id_arg) = rest.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!(
+        "Admin request to set quiet hours for room {} to {}",
+        room_id, hours
+    );
+
+    let timezone = match database.get_room_time_format(room_id).await {
+        Ok((Some(timezone), _)) => timezone,
+        Ok((None, _)) => config.timezone.name().to_string(),
+        Err(e) => {
+            error!("Failed to look up timezone for {}: {:?}", room_id, e);
+            return CommandResult::Response(format!("Failed to set quiet hours: {}", e));
+        }
+    };
+    let how = if hours.digest {
+        "as one digest message"
+    } else {
+        "as embeds"
+    };
+    match database.set_quiet_hours(room_id, &hours).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Links in `{}` will be queued from {} to {} ({}) every day, and posted {} when quiet hours end.",
+            room_id,
+            hours.start.format("%H:%M"),
+            hours.end.format("%H:%M"),
+            timezone,
+            how
+        )),
+        Err(e) => {
+            error!("Failed to set quiet hours for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to set quiet hours: {}", e))
+        }
+    }
+}
+
+async fn handle_clear_quiet_hours(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!("Admin request to clear quiet hours for room {}", room_id);
+
+    match database.clear_quiet_hours(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Quiet hours removed for `{}`; links are embedded as they're posted, and any that were queued are posted shortly.",
+            room_id
+        )),
+        Err(e) => {
+            error!("Failed to clear quiet hours for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to clear quiet hours: {}", e))
+        }
+    }
+}
+
+async fn handle_enable_digest(
+    mut room_id: &str,
+    mut args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let mut interval = None;
+    if let Some(arg) = args.first()
+        && !arg.starts_with('!')
+    {
+        match arg.parse::<u64>() {
+            Ok(minutes) if minutes > 0 => interval = Some(Duration::from_secs(minutes * 60)),
+            _ => {
+                return CommandResult::Response(format!(
+                    "Usage: `{prefix} admin enable-digest [minutes] [room_id]`"
+                ));
+            }
+        }
+        args = &args[1..];
+    }
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!("Admin request to enable digest for room {}", room_id);
+
+    let minutes = interval.unwrap_or(config.digest_interval).as_secs() / 60;
+    match database.enable_digest(room_id, interval).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Links in `{}` will be collected and posted together every {} minute(s) instead of being embedded one by one.",
+            room_id, minutes
+        )),
+        Err(e) => {
+            error!("Failed to enable digest for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to enable digest: {}", e))
+        }
+    }
+}
+
+async fn handle_disable_digest(
+    mut room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+) -> CommandResult {
+    if let Some(room_id_arg) = args.first().copied() {
+        room_id = room_id_arg;
+    }
+
+    info!("Admin request to disable digest for room {}", room_id);
+
+    match database.disable_digest(room_id).await {
+        Ok(()) => CommandResult::Response(format!(
+            "Digest has been **disabled** for `{}`; links collected so far are posted shortly.",
+            room_id
+        )),
+        Err(e) => {
+            error!("Failed to disable digest for {}: {:?}", room_id, e);
+            CommandResult::Response(format!("Failed to disable digest: {}", e))
+        }
+    }
+}
+
+fn handle_queue(jobs: &JobRegistry) -> CommandResult {
+    let running = jobs.list();
+    if running.is_empty() {
+        return CommandResult::Response("No embeds in progress.".to_string());
+    }
+
+    let mut response = format!("**{} embed(s) in progress:**\n", running.len());
+    for job in running {
+        response.push_str(&format!(
+            "\n- `{}` — {} in `{}`: {} stage, {}",
+            job.id,
+            job.url,
+            job.room_id,
+            job.stage.label(),
+            format_duration(job.elapsed.as_secs())
+        ));
+    }
+    CommandResult::Response(response)
+}
+
+fn handle_cancel(args: &[&str], jobs: &JobRegistry, prefix: &str) -> CommandResult {
+    let Some(id) = args.first().and_then(|arg| arg.parse::<u64>().ok()) else {
+        return CommandResult::Response(format!("Usage: `{prefix} admin cancel <id>`"));
+    };
+
+    info!("Admin request to cancel embed {}", id);
+
+    if jobs.cancel(id) {
+        CommandResult::Response(format!("Cancelling embed `{}`.", id))
+    } else {
+        CommandResult::Response(format!(
+            "No embed `{}` in progress. Use `{prefix} admin queue` to list them.",
+            id
+        ))
+    }
+}
+
+async fn handle_domains(args: &[&str], database: &Arc<Database>, prefix: &str) -> CommandResult {
+    let count = match args.first() {
+        None => DEFAULT_DOMAINS_COUNT,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(n) if (1..=MAX_DOMAINS_COUNT).contains(&n) => n,
+            _ => {
+                return CommandResult::Response(format!(
+                    "Usage: `{prefix} admin domains [count]` (count must be between 1 and {MAX_DOMAINS_COUNT})"
+                ));
+            }
+        },
+    };
+
+    let stats = match database.domain_stats(count).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Failed to query domain stats: {:?}", e);
+            return CommandResult::Response(format!("Failed to query domain stats: {}", e));
+        }
+    };
+    if stats.is_empty() {
+        return CommandResult::Response("No embeds attempted yet.".to_string());
+    }
+
+    let mut response = "**Embeds by domain:**\n".to_string();
+    for domain in stats {
+        response.push_str(&format!(
+            "\n- `{}` — {} embedded, {} empty, {} failed",
+            domain.domain, domain.embedded, domain.empty, domain.failed
+        ));
+        if let Some(at) = &domain.last_embedded_at {
+            response.push_str(&format!("; last embedded {} UTC", at));
+        }
+        if domain.is_failing() {
+            response.push_str(&format!(
+                " — ⚠️ nothing from the last {} attempts",
+                domain.failure_streak
+            ));
+        }
+    }
+    CommandResult::Response(response)
+}
+
+async fn handle_purge(
+    room_id: &str,
+    args: &[&str],
+    client: &Client,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let count = match args.first() {
+        None => DEFAULT_PURGE_COUNT,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(n) if (1..=MAX_PURGE_COUNT).contains(&n) => n,
+            _ => {
+                return CommandResult::Response(format!(
+                    "Usage: `{prefix} admin purge [count]` (count must be between 1 and {MAX_PURGE_COUNT})"
+                ));
+            }
+        },
+    };
+
+    info!(
+        "Admin request to purge {} embeds in room {}",
+        count, room_id
+    );
+
+    let event_ids = match database.recent_embeds(room_id, count).await {
+        Ok(event_ids) => event_ids,
+        Err(e) => {
+            error!("Failed to look up embeds in {}: {:?}", room_id, e);
+            return CommandResult::Response(format!("Failed to look up embeds: {}", e));
+        }
+    };
+    if event_ids.is_empty() {
+        return CommandResult::Response("No embeds to remove in this room.".to_string());
+    }
+
+    let room = match RoomId::parse(room_id)
+        .ok()
+        .and_then(|room_id| client.get_room(&room_id))
+    {
+        Some(room) => room,
+        None => {
+            return CommandResult::Response(format!("Not in room `{}`.", room_id));
+        }
+    };
+
+    let mut removed = 0;
+    let mut failed = 0;
+    for event_id in &event_ids {
+        let Ok(parsed) = EventId::parse(event_id) else {
+            failed += 1;
+            continue;
+        };
+        match room.redact(&parsed, Some("Purged by admin"), None).await {
+            Ok(_) => {
+                removed += 1;
+                if let Err(e) = database.forget_embed(event_id).await {
+                    warn!("Failed to forget purged embed {}: {:?}", event_id, e);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to redact embed {}: {:?}", event_id, e);
+                failed += 1;
+            }
+        }
+    }
+
+    let mut response = format!("Removed {} embed(s).", removed);
+    if failed > 0 {
+        response.push_str(&format!(" {} could not be removed.", failed));
+    }
+    CommandResult::Response(response)
+}
+
+async fn handle_set_room_name(
+    room_id: &str,
+    args: &[&str],
+    config: &Config,
+    client: &Client,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    if args.is_empty() {
+        return CommandResult::Response(format!("Usage: `{prefix} admin set-room-name <name>`"));
+    }
+    let name = args.join(" ");
+
+    info!(
+        "Admin request to set display name in {} to {:?}",
+        room_id, name
+    );
+
+    if let Err(e) = database.set_room_display_name(room_id, Some(&name)).await {
+        error!("Failed to set room display name: {:?}", e);
+        return CommandResult::Response(format!("Failed to set display name: {}", e));
+    }
+    update_room_profile(room_id, config, client, database).await
+}
+
+async fn handle_set_room_avatar(
+    room_id: &str,
+    args: &[&str],
+    config: &Config,
+    client: &Client,
+    database: &Arc<Database>,
+    http_client: &reqwest::Client,
+    prefix: &str,
+) -> CommandResult {
+    let Some(avatar) = args.first().copied() else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin set-room-avatar <mxc_or_image_url>`"
+        ));
+    };
+
+    info!("Admin request to set avatar in {} to {}", room_id, avatar);
+
+    let avatar_url = if avatar.starts_with("mxc://") {
+        avatar.to_string()
+    } else {
+        match upload_avatar(avatar, config, client, http_client).await {
+            Ok(mxc) => mxc,
+            Err(e) => {
+                error!("Failed to upload room avatar: {:?}", e);
+                return CommandResult::Response(format!("Failed to upload avatar: {}", e));
+            }
+        }
+    };
+
+    if let Err(e) = database
+        .set_room_avatar_url(room_id, Some(&avatar_url))
+        .await
+    {
+        error!("Failed to set room avatar: {:?}", e);
+        return CommandResult::Response(format!("Failed to set avatar: {}", e));
+    }
+    update_room_profile(room_id, config, client, database).await
+}
+
+async fn handle_clear_room_profile(
+    room_id: &str,
+    config: &Config,
+    client: &Client,
+    database: &Arc<Database>,
+) -> CommandResult {
+    info!("Admin request to clear profile in {}", room_id);
+
+    if let Err(e) = database.clear_room_profile(room_id).await {
+        error!("Failed to clear room profile: {:?}", e);
+        return CommandResult::Response(format!("Failed to clear profile: {}", e));
+    }
+    update_room_profile(room_id, config, client, database).await
+}
+
+/// Download an image and upload it to the homeserver, returning its mxc URI.
+async fn upload_avatar(
+    url_str: &str,
+    config: &Config,
+    client: &Client,
+    http_client: &reqwest::Client,
+) -> Result<String> {
+    let url = Url::parse(url_str).context("Invalid URL")?;
+    let user_agent = http::user_agent(config, &url, Fetch::Media);
+    let data = http_client
+        .get(url.clone())
+        .timeout(config.download_timeout)
+        .header(reqwest::header::USER_AGENT, user_agent)
+        .send()
+        .await
+        .context("Failed to download avatar")?
+        .error_for_status()
+        .context("Avatar download returned error status")?
+        .bytes()
+        .await
+        .context("Failed to read avatar")?;
+
+    let Some(kind) = infer::get(&data).filter(|t| t.mime_type().starts_with("image/")) else {
+        bail!("Not an image");
+    };
+    let mime = kind
+        .mime_type()
+        .parse()
+        .context("Invalid image MIME type")?;
+    let response = client
+        .media()
+        .upload(&mime, data.to_vec(), None)
+        .await
+        .context("Failed to upload avatar")?;
+    Ok(response.content_uri.to_string())
+}
+
+/// Apply the stored profile for `room_id` and report the outcome.
+async fn update_room_profile(
+    room_id: &str,
+    config: &Config,
+    client: &Client,
+    database: &Database,
+) -> CommandResult {
+    let Some(room) = RoomId::parse(room_id)
+        .ok()
+        .and_then(|room_id| client.get_room(&room_id))
+    else {
+        return CommandResult::Response(format!(
+            "Saved, but not in room `{}`; it will be applied after joining.",
+            room_id
+        ));
+    };
+
+    match profile::update_room(client, config, database, &room).await {
+        Ok(_) => CommandResult::Response("Room profile updated.".to_string()),
+        Err(e) => {
+            error!("Failed to update room profile: {:?}", e);
+            CommandResult::Response(format!("Saved, but updating the profile failed: {}", e))
+        }
+    }
+}
+
+/// Load the rewrite rules stored in the database into `config`.
+pub async fn load_url_rewrites(config: &Config, database: &Database) -> Result<()> {
+    let rules = database
+        .list_url_rewrites()
+        .await?
+        .into_iter()
+        .map(|row| {
+            let regex = Regex::new(&row.pattern)
+                .with_context(|| format!("Invalid stored rewrite regex: {}", row.pattern))?;
+            Ok(UrlRewrite::new(regex, row.replacement))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    config.set_runtime_url_rewrites(rules);
+    Ok(())
+}
+
+/// Load the trusted users stored in the database into `config`.
+pub async fn load_trusted_users(config: &Config, database: &Database) -> Result<()> {
+    let users = database
+        .list_trusted_users()
+        .await?
+        .into_iter()
+        .map(|row| row.user_id)
+        .collect();
+    config.set_runtime_trusted_users(users);
+    Ok(())
+}
+
+async fn handle_trust(
+    args: &[&str],
+    sender: &str,
+    config: &Config,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let [user_id] = args else {
+        return CommandResult::Response(format!("Usage: `{prefix} admin trust <user_id>`"));
+    };
+    if let Err(e) = UserId::parse(*user_id) {
+        return CommandResult::Response(format!("Invalid user ID `{}`: {}", user_id, e));
+    }
+    if config.is_trusted(user_id) {
+        return CommandResult::Response(format!("`{}` is already trusted.", user_id));
+    }
+
+    info!("Admin request from {} to trust {}", sender, user_id);
+
+    if let Err(e) = database.add_trusted_user(user_id, sender).await {
+        error!("Failed to add trusted user: {:?}", e);
+        return CommandResult::Response(format!("Failed to trust user: {}", e));
+    }
+    match load_trusted_users(config, database).await {
+        Ok(()) => CommandResult::Response(format!("`{}` can now use admin commands.", user_id)),
+        Err(e) => {
+            error!("Failed to reload trusted users: {:?}", e);
+            CommandResult::Response(format!(
+                "User saved, but reloading trusted users failed: {}",
+                e
+            ))
+        }
+    }
+}
+
+async fn handle_untrust(
+    args: &[&str],
+    sender: &str,
+    config: &Config,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let [user_id] = args else {
+        return CommandResult::Response(format!("Usage: `{prefix} admin untrust <user_id>`"));
+    };
+    if config.trusted_users.iter().any(|u| u == user_id) {
+        return CommandResult::Response(format!(
+            "`{}` is trusted by the configuration, and can only be removed there.",
+            user_id
+        ));
+    }
+    if *user_id == sender {
+        return CommandResult::Response(
+            "You can't untrust yourself; ask another trusted user.".to_string(),
+        );
+    }
+
+    info!("Admin request from {} to untrust {}", sender, user_id);
+
+    match database.remove_trusted_user(user_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return CommandResult::Response(format!(
+                "`{}` isn't trusted. See `{prefix} admin list-trusted`.",
+                user_id
+            ));
+        }
+        Err(e) => {
+            error!("Failed to remove trusted user: {:?}", e);
+            return CommandResult::Response(format!("Failed to untrust user: {}", e));
+        }
+    }
+    match load_trusted_users(config, database).await {
+        Ok(()) => {
+            CommandResult::Response(format!("`{}` can no longer use admin commands.", user_id))
+        }
+        Err(e) => {
+            error!("Failed to reload trusted users: {:?}", e);
+            CommandResult::Response(format!(
+                "User removed, but reloading trusted users failed: {}",
+                e
+            ))
+        }
+    }
+}
+
+async fn handle_list_trusted(config: &Config, database: &Arc<Database>) -> CommandResult {
+    let rows = match database.list_trusted_users().await {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to list trusted users: {:?}", e);
+            return CommandResult::Response(format!("Failed to list trusted users: {}", e));
+        }
+    };
+    let mut lines = vec![format!(
+        "**Trusted users ({}):**\n",
+        config.trusted_users.len() + rows.len()
+    )];
+    for user_id in &config.trusted_users {
+        lines.push(format!("- `{}` (configuration)", user_id));
+    }
+    for row in &rows {
+        lines.push(format!("- `{}` (added by `{}`)", row.user_id, row.added_by));
+    }
+    CommandResult::Response(lines.join("\n"))
+}
+
+async fn handle_add_rewrite(
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let [pattern, replacement] = args else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin add-rewrite <regex> <replacement>`"
+        ));
+    };
+    if let Err(e) = Regex::new(pattern) {
+        return CommandResult::Response(format!("Invalid regex `{}`: {}", pattern, e));
+    }
+
+    info!(
+        "Admin request to add URL rewrite {} -> {}",
+        pattern, replacement
+    );
+
+    if let Err(e) = database.add_url_rewrite(pattern, replacement).await {
+        error!("Failed to add URL rewrite: {:?}", e);
+        return CommandResult::Response(format!("Failed to add rewrite: {}", e));
+    }
+    match load_url_rewrites(config, database).await {
+        Ok(()) => CommandResult::Response(format!(
+            "URLs matching `{}` will be rewritten to `{}`.",
+            pattern, replacement
+        )),
+        Err(e) => {
+            error!("Failed to reload URL rewrites: {:?}", e);
+            CommandResult::Response(format!("Rewrite saved, but reloading rules failed: {}", e))
+        }
+    }
+}
+
+async fn handle_remove_rewrite(
+    args: &[&str],
+    config: &Config,
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let Some(n) = args.first().and_then(|s| s.parse::<usize>().ok()) else {
+        return CommandResult::Response(format!("Usage: `{prefix} admin remove-rewrite <n>`"));
+    };
+
+    let rows = match database.list_url_rewrites().await {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to list URL rewrites: {:?}", e);
+            return CommandResult::Response(format!("Failed to list rewrites: {}", e));
+        }
+    };
+    let Some(row) = n.checked_sub(1).and_then(|i| rows.get(i)) else {
+        return CommandResult::Response(format!(
+            "No rewrite rule #{}. See `{prefix} admin list-rewrites`.",
+            n
+        ));
+    };
+
+    info!("Admin request to remove URL rewrite {}", row.pattern);
+
+    if let Err(e) = database.remove_url_rewrite(row.id).await {
+        error!("Failed to remove URL rewrite: {:?}", e);
+        return CommandResult::Response(format!("Failed to remove rewrite: {}", e));
+    }
+    match load_url_rewrites(config, database).await {
+        Ok(()) => {
+            CommandResult::Response(format!("Rewrite rule `{}` has been removed.", row.pattern))
+        }
+        Err(e) => {
+            error!("Failed to reload URL rewrites: {:?}", e);
+            CommandResult::Response(format!(
+                "Rewrite removed, but reloading rules failed: {}",
+                e
+            ))
+        }
+    }
+}
+
+async fn handle_list_rewrites(config: &Config, database: &Arc<Database>) -> CommandResult {
+    let builtin = format!(
+        "{} more rule(s) come from the configuration.",
+        config.url_rewrites.len()
+    );
+    match database.list_url_rewrites().await {
+        Ok(rows) if rows.is_empty() => {
+            CommandResult::Response(format!("No rewrite rules have been added. {}", builtin))
+        }
+        Ok(rows) => {
+            let mut lines = vec![format!("**URL rewrite rules ({}):**\n", rows.len())];
+            for (i, row) in rows.iter().enumerate() {
+                lines.push(format!(
+                    "{}. `{}` → `{}`",
+                    i + 1,
+                    row.pattern,
+                    row.replacement
+                ));
+            }
+            lines.push(String::new());
+            lines.push(builtin);
+            CommandResult::Response(lines.join("\n"))
+        }
+        Err(e) => {
+            error!("Failed to list URL rewrites: {:?}", e);
+            CommandResult::Response(format!("Failed to list rewrites: {}", e))
+        }
+    }
+}
+
+async fn handle_list_key_sharing(database: &Arc<Database>) -> CommandResult {
+    info!("Admin request to list key-sharing rooms");
+
+    match database.list_key_sharing_rooms().await {
+        Ok(rooms) if rooms.is_empty() => {
+            CommandResult::Response("No rooms have key sharing enabled.".to_string())
+        }
+        Ok(rooms) => {
+            let mut lines = vec![format!(
+                "**Rooms with key sharing enabled ({}):**\n",
+                rooms.len()
+            )];
+            for room_id in &rooms {
+                lines.push(format!("- `{}`", room_id));
+            }
+            CommandResult::Response(lines.join("\n"))
+        }
+        Err(e) => {
+            error!("Failed to list key-sharing rooms: {:?}", e);
+            CommandResult::Response(format!("Failed to list key-sharing rooms: {}", e))
+        }
+    }
+}
+
+/// The language pages linked in the room `room_id` are asked for in: its
+/// own, or else the global one. An empty `room_id`, as for global commands,
+/// has only the global one.
+pub async fn room_language(room_id: &str, config: &Config, database: &Database) -> Option<String> {
+    if room_id.is_empty() {
+        return config.language.clone();
+    }
+    match database.get_room_language(room_id).await {
+        Ok(Some(language)) => Some(language),
+        Ok(None) => config.language.clone(),
+        Err(e) => {
+            error!("Failed to look up room language: {:?}", e);
+            config.language.clone()
+        }
+    }
+}
+
+async fn fetch_and_store_media(
+    url_str: &str,
+    language: Option<&str>,
+    http_client: &reqwest::Client,
+    config: &Config,
+    media_store: &MediaStore,
+    ap_detector: &ActivityPubDetector,
+) -> Result<(String, String, String)> {
+    let url = Url::parse(url_str).context("Invalid URL")?;
+    let meta = Metadata::fetch_from_url(http_client, &url, config, ap_detector, language).await?;
+    let media_url = meta
+        .video_url
+        .or(meta.audio_url)
+        .or(meta.image_url)
+        .unwrap_or_else(|| url.clone());
+
+    let response = http_client
+        .get(media_url.clone())
+        .timeout(config.download_timeout)
+        .header(
+            reqwest::header::USER_AGENT,
+            http::user_agent(config, &media_url, Fetch::Media),
+        )
+        .send()
+        .await
+        .context("Failed to download media")?
+        .error_for_status()
+        .context("Media download returned error status")?;
+
+    let mime_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .split(';')
+        .next()
+        .unwrap_or("application/octet-stream")
+        .trim()
+        .to_string();
+
+    let filename = media_url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .filter(|s| !s.is_empty())
+        .unwrap_or("media")
+        .to_string();
+
+    let data = response.bytes().await.context("Failed to read media")?;
+    let hash = media_store.store(&data).await?;
+    Ok((hash, filename, mime_type))
+}
+
+async fn handle_add_command(
+    room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    http_client: &reqwest::Client,
+    config: &Config,
+    media_store: &MediaStore,
+    ap_detector: &ActivityPubDetector,
+    prefix: &str,
+) -> CommandResult {
+    let (room_id, args) = parse_global_flag(room_id, args);
+    let scope = if room_id.is_empty() { " globally" } else { "" };
+
+    let Some(name) = args.first().copied() else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin add-command [--global] <name> [media_url] [text...]`"
+        ));
+    };
+
+    if !name.starts_with('!') {
+        return CommandResult::Response("Command name must start with `!`.".to_string());
+    }
+
+    let mut rest = &args[1..];
+    let mut media_info = None;
+
+    if let Some(first) = rest.first() {
+        if first.starts_with("http://") || first.starts_with("https://") {
+            let language = room_language(room_id, config, database).await;
+            match fetch_and_store_media(
+                first,
+                language.as_deref(),
+                http_client,
+                config,
+                media_store,
+                ap_detector,
+            )
+            .await
+            {
+                Ok(info) => media_info = Some(info),
+                Err(e) => {
+                    return CommandResult::Response(format!("Failed to fetch media: {}", e));
+                }
+            }
+            rest = &rest[1..];
+        }
+    }
+
+    let text = if rest.is_empty() {
+        None
+    } else {
+        Some(rest.join(" "))
+    };
+
+    if text.is_none() && media_info.is_none() {
+        return CommandResult::Response(format!(
+            "Must provide at least text or a media URL.\n\n\
+             Usage: `{prefix} admin add-command [--global] <name> [media_url] [text...]`"
+        ));
+    }
+
+    let (cas_hash, filename, mime_type) = match &media_info {
+        Some((h, f, m)) => (Some(h.as_str()), Some(f.as_str()), Some(m.as_str())),
+        None => (None, None, None),
+    };
+
+    let response_id = match database
+        .create_canned_response(text.as_deref(), cas_hash, filename, mime_type)
+        .await
+    {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Failed to create canned response: {:?}", e);
+            return CommandResult::Response(format!("Failed to create response: {}", e));
+        }
+    };
+
+    match database
+        .add_custom_command(room_id, name, response_id)
+        .await
+    {
+        Ok(()) => CommandResult::Response(format!(
+            "Custom command `{}` has been set{}{}.",
+            name,
+            scope,
+            if media_info.is_some() {
+                " (with media)"
+            } else {
+                ""
+            }
+        )),
+        Err(e) => {
+            error!("Failed to add custom command: {:?}", e);
+            CommandResult::Response(format!("Failed to add command: {}", e))
+        }
+    }
+}
+
+async fn handle_remove_command(
+    room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let (room_id, args) = parse_global_flag(room_id, args);
+    let scope = if room_id.is_empty() {
+        "globally"
+    } else {
+        "in this room"
+    };
+
+    let Some(name) = args.first().copied() else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin remove-command [--global] <name>`"
+        ));
+    };
+
+    match database.remove_custom_command(room_id, name).await {
+        Ok(true) => CommandResult::Response(format!("Custom command `{}` has been removed.", name)),
+        Ok(false) => {
+            CommandResult::Response(format!("No custom command `{}` found {}.", name, scope))
+        }
+        Err(e) => {
+            error!("Failed to remove custom command: {:?}", e);
+            CommandResult::Response(format!("Failed to remove command: {}", e))
+        }
+    }
+}
+
+async fn handle_list_commands(
+    room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+) -> CommandResult {
+    let (room_id, _args) = parse_global_flag(room_id, args);
+    let scope = if room_id.is_empty() {
+        "globally"
+    } else {
+        "for this room"
+    };
+
+    match database.list_custom_commands(room_id).await {
+        Ok(cmds) if cmds.is_empty() => {
+            CommandResult::Response(format!("No custom commands configured {}.", scope))
+        }
+        Ok(cmds) => {
+            let mut lines = vec![format!("**Custom commands {} ({}):**\n", scope, cmds.len())];
+            for cmd in &cmds {
+                let has_media = cmd.response.media_cas_hash.is_some();
+                let text_preview = cmd.response.text_markdown.as_deref().unwrap_or("(no text)");
+                let truncated = if text_preview.len() > 50 {
+                    format!("{}…", &text_preview[..50])
+                } else {
+                    text_preview.to_string()
+                };
+                lines.push(format!(
+                    "- `{}` — {}{}",
+                    cmd.command_name,
+                    truncated,
+                    if has_media { " 📎" } else { "" }
+                ));
+            }
+            CommandResult::Response(lines.join("\n"))
+        }
+        Err(e) => {
+            error!("Failed to list custom commands: {:?}", e);
+            CommandResult::Response(format!("Failed to list commands: {}", e))
+        }
+    }
+}
+
+async fn handle_add_autoresponder(
+    room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    http_client: &reqwest::Client,
+    config: &Config,
+    media_store: &MediaStore,
+    ap_detector: &ActivityPubDetector,
+    prefix: &str,
+) -> CommandResult {
+    let (room_id, args) = parse_global_flag(room_id, args);
+    let scope = if room_id.is_empty() { " globally" } else { "" };
+
+    let Some(pattern) = args.first().copied() else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin add-autoresponder [--global] <pattern> [probability] [media_url] [text...]`"
+        ));
+    };
+
+    if regex::Regex::new(pattern).is_err() {
+        return CommandResult::Response(format!("`{}` is not a valid regex pattern.", pattern));
+    }
+
+    let mut rest = &args[1..];
+    let mut probability = 1.0;
+
+    if let Some(first) = rest.first() {
+        if let Ok(p) = first.parse::<f64>() {
+            if (0.0..=1.0).contains(&p) {
+                probability = p;
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    let mut media_info = None;
+    if let Some(first) = rest.first() {
+        if first.starts_with("http://") || first.starts_with("https://") {
+            let language = room_language(room_id, config, database).await;
+            match fetch_and_store_media(
+                first,
+                language.as_deref(),
+                http_client,
+                config,
+                media_store,
+                ap_detector,
+            )
+            .await
+            {
+                Ok(info) => media_info = Some(info),
+                Err(e) => {
+                    return CommandResult::Response(format!("Failed to fetch media: {}", e));
+                }
+            }
+            rest = &rest[1..];
+        }
+    }
+
+    let text = if rest.is_empty() {
+        None
+    } else {
+        Some(rest.join(" "))
+    };
+
+    if text.is_none() && media_info.is_none() {
+        return CommandResult::Response(format!(
+            "Must provide at least text or a media URL.\n\n\
+             Usage: `{prefix} admin add-autoresponder [--global] <pattern> [probability] [media_url] [text...]`"
+        ));
+    }
+
+    let (cas_hash, filename, mime_type) = match &media_info {
+        Some((h, f, m)) => (Some(h.as_str()), Some(f.as_str()), Some(m.as_str())),
+        None => (None, None, None),
+    };
+
+    let response_id = match database
+        .create_canned_response(text.as_deref(), cas_hash, filename, mime_type)
+        .await
+    {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Failed to create canned response: {:?}", e);
+            return CommandResult::Response(format!("Failed to create response: {}", e));
+        }
+    };
+
+    match database
+        .add_autoresponder(room_id, pattern, probability, response_id)
+        .await
+    {
+        Ok(()) => {
+            let prob_str = if probability < 1.0 {
+                format!(" ({}% chance)", (probability * 100.0) as u32)
+            } else {
+                String::new()
+            };
+            CommandResult::Response(format!(
+                "Autoresponder for `{}`{} has been set{}{}.",
+                pattern,
+                prob_str,
+                scope,
+                if media_info.is_some() {
+                    " (with media)"
+                } else {
+                    ""
+                }
+            ))
+        }
+        Err(e) => {
+            error!("Failed to add autoresponder: {:?}", e);
+            CommandResult::Response(format!("Failed to add autoresponder: {}", e))
+        }
+    }
+}
+
+async fn handle_remove_autoresponder(
+    room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+    prefix: &str,
+) -> CommandResult {
+    let (room_id, args) = parse_global_flag(room_id, args);
+    let scope = if room_id.is_empty() {
+        "globally"
+    } else {
+        "in this room"
+    };
+
+    let Some(pattern) = args.first().copied() else {
+        return CommandResult::Response(format!(
+            "Usage: `{prefix} admin remove-autoresponder [--global] <pattern>`"
+        ));
+    };
+
+    match database.remove_autoresponder(room_id, pattern).await {
+        Ok(true) => {
+            CommandResult::Response(format!("Autoresponder for `{}` has been removed.", pattern))
+        }
+        Ok(false) => CommandResult::Response(format!(
+            "No autoresponder for `{}` found {}.",
+            pattern, scope
+        )),
+        Err(e) => {
+            error!("Failed to remove autoresponder: {:?}", e);
+            CommandResult::Response(format!("Failed to remove autoresponder: {}", e))
+        }
+    }
+}
+
+async fn handle_list_autoresponders(
+    room_id: &str,
+    args: &[&str],
+    database: &Arc<Database>,
+) -> CommandResult {
+    let (room_id, _args) = parse_global_flag(room_id, args);
+    let scope = if room_id.is_empty() {
+        "globally"
+    } else {
+        "for this room"
+    };
+
+    match database.list_autoresponders(room_id).await {
+        Ok(autos) if autos.is_empty() => {
+            CommandResult::Response(format!("No autoresponders configured {}.", scope))
+        }
+        Ok(autos) => {
+            let mut lines = vec![format!("**Autoresponders {} ({}):**\n", scope, autos.len())];
+            for auto in &autos {
+                let has_media = auto.response.media_cas_hash.is_some();
+                let text_preview = auto
+                    .response
+                    .text_markdown
+                    .as_deref()
+                    .unwrap_or("(no text)");
+                let truncated = if text_preview.len() > 50 {
+                    format!("{}…", &text_preview[..50])
+                } else {
+                    text_preview.to_string()
+                };
+                let prob_str = if auto.probability < 1.0 {
+                    format!(" ({}%)", (auto.probability * 100.0) as u32)
+                } else {
+                    String::new()
+                };
+                lines.push(format!(
+                    "- `{}`{} — {}{}",
+                    auto.pattern,
+                    prob_str,
+                    truncated,
+                    if has_media { " 📎" } else { "" }
+                ));
+            }
+            CommandResult::Response(lines.join("\n"))
+        }
+        Err(e) => {
+            error!("Failed to list autoresponders: {:?}", e);
+            CommandResult::Response(format!("Failed to list autoresponders: {}", e))
+        }
+    }
+}
+
+/// Check autoresponders for a room. Returns the first matching canned response
+/// (after rolling against the configured probability), or `None`.
+pub async fn check_autoresponders(
+    body: &str,
+    room_id: &str,
+    database: &Database,
+) -> Option<CannedResponse> {
+    let autoresponders = match database.get_autoresponders(room_id).await {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to get autoresponders: {:?}", e);
+            return None;
+        }
+    };
+
+    for auto in autoresponders {
+        let re = match regex::Regex::new(&auto.pattern) {
+            Ok(re) => re,
+            Err(e) => {
+                warn!("Invalid autoresponder pattern '{}': {}", auto.pattern, e);
+                continue;
+            }
+        };
+        if re.is_match(body) {
+            if auto.probability < 1.0 {
+                use rand::Rng;
+                if rand::thread_rng().r#gen::<f64>() >= auto.probability {
+                    continue;
+                }
+            }
+            return Some(auto.response);
+        }
+    }
+
+    None
+}
+
+pub(crate) async fn remove_device(
+    client: &Client,
+    config: &Config,
+    device_id: &OwnedDeviceId,
+) -> Result<()> {
+    let devices = [device_id.clone()];
+
+    match client.delete_devices(&devices, None).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            // If the server requires interactive auth, handle the UIAA flow.
+            if let Some(info) = e.as_uiaa_response() {
+                let password = config
+                    .password
+                    .as_deref()
+                    .context("Server requires interactive auth to delete devices, but no password is configured")?;
+
+                let mut auth = uiaa::Password::new(
+                    uiaa::UserIdentifier::Matrix(uiaa::MatrixUserIdentifier::new(
+                        config.username.clone(),
+                    )),
+                    password.to_owned(),
+                );
+                auth.session = info.session.clone();
+
+                client
+                    .delete_devices(&devices, Some(uiaa::AuthData::Password(auth)))
+                    .await
+                    .context("Failed to delete device with password auth")?;
+
+                return Ok(());
+            }
+
+            bail!("Failed to delete device: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReplyMode;
+    use crate::db::{DomainOutcome, RoomDigest};
+
+    fn test_config(trusted: Vec<&str>) -> Config {
+        Config {
+            trusted_users: trusted.into_iter().map(String::from).collect(),
+            ..Config::default()
+        }
+    }
+
+    async fn test_database() -> Arc<Database> {
+        Arc::new(Database::open_in_memory().await.unwrap())
+    }
+
+    async fn run_cmd(
+        body: &str,
+        sender: &str,
+        room_id: &str,
+        config: &Config,
+        client: &Client,
+        database: &Arc<Database>,
+    ) -> CommandResult {
+        let dir = tempfile::TempDir::new().unwrap();
+        let media_store = crate::cas::MediaStore::open(dir.path()).await.unwrap();
+        let http_client = reqwest::Client::new();
+        let ap_detector = crate::activitypub::ActivityPubDetector::new();
+        let jobs = JobRegistry::new();
+        handle_command(
+            body,
+            sender,
+            room_id,
+            config,
+            client,
+            database,
+            &http_client,
+            &media_store,
+            &ap_detector,
+            &jobs,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_not_a_command() {
+        let config = test_config(vec![]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "hello world",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert!(matches!(result, CommandResult::NotACommand));
+    }
+
+    #[tokio::test]
+    async fn test_not_a_command_url() {
+        let config = test_config(vec![]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "https://example.com",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert!(matches!(result, CommandResult::NotACommand));
+    }
+
+    #[tokio::test]
+    async fn test_base_command_shows_help() {
+        let config = test_config(vec![]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
+            other => panic!(
+                "Expected Response, got {:?}",
+                matches!(other, CommandResult::NotACommand)
+            ),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_help_and_version() {
+        let config = test_config(vec![]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+        db.enable_data_saver("!testroom:example.com").await.unwrap();
+
+        let result = run_cmd(
+            "!embedbot help",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("It embeds:"));
+                assert!(msg.contains("- Data saver: **on**\n"));
+                assert!(msg.contains("Usage"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        let result = run_cmd(
+            "!embedbot version",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains(describe::VERSION));
+                assert!(!msg.contains("Settings in this room"));
+            }
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_help_reply_mode() {
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        // A room the bot isn't in isn't a direct chat with it.
+        for (reply_mode, dm_reply_mode, expected) in [
+            (ReplyMode::Reply, ReplyMode::Standalone, "reply"),
+            (ReplyMode::Standalone, ReplyMode::Reply, "standalone"),
+        ] {
+            let config = Config {
+                reply_mode,
+                dm_reply_mode,
+                ..test_config(vec![])
+            };
+            let result = run_cmd(
+                "!embedbot help",
+                "@user:example.com",
+                "!testroom:example.com",
+                &config,
+                &client,
+                &db,
+            )
+            .await;
+            match result {
+                CommandResult::Response(msg) => {
+                    assert!(msg.contains(&format!("- Reply mode: **{}** (default)\n", expected)));
+                }
+                _ => panic!("Expected Response"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_command_trigger() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        // Set up a custom command via DB directly.
+        let rid = db
+            .create_canned_response(Some("Here are useful links!"), None, None, None)
+            .await
+            .unwrap();
+        db.add_custom_command("!testroom:example.com", "!links", rid)
+            .await
+            .unwrap();
+
+        // Should trigger the custom command.
+        let result = run_cmd(
+            "!links",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::CannedResponse(cr) => {
+                assert_eq!(cr.text_markdown.as_deref(), Some("Here are useful links!"));
+            }
+            _ => panic!("Expected CannedResponse"),
+        }
+
+        // Different room should not match.
+        let result = run_cmd(
+            "!links",
+            "@user:example.com",
+            "!otherroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert!(matches!(result, CommandResult::NotACommand));
+
+        // Random non-command text should not match.
+        let result = run_cmd(
+            "hello",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert!(matches!(result, CommandResult::NotACommand));
+    }
+
+    #[tokio::test]
+    async fn test_admin_add_and_remove_command() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        // add-command with text only
+        let result = run_cmd(
+            "!embedbot admin add-command !greet Hello, world!",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match &result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("has been set"), "got: {}", msg);
+                assert!(msg.contains("!greet"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // Verify the command exists.
+        let cr = db
+            .get_custom_command("!testroom:example.com", "!greet")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(cr.text_markdown.as_deref(), Some("Hello, world!"));
+
+        // remove-command
+        let result = run_cmd(
+            "!embedbot admin remove-command !greet",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("removed")),
+            _ => panic!("Expected Response"),
+        }
+
+        assert!(
+            db.get_custom_command("!testroom:example.com", "!greet")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_add_command_missing_args() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin add-command",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
+            _ => panic!("Expected Response"),
+        }
+
+        // Name without text or media
+        let result = run_cmd(
+            "!embedbot admin add-command !test",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Must provide")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_add_command_no_bang() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin add-command greet Hello",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("must start with `!`")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_list_commands() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        // Empty list
+        let result = run_cmd(
+            "!embedbot admin list-commands",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("No custom commands")),
+            _ => panic!("Expected Response"),
+        }
+
+        // Add one and list
+        let rid = db
+            .create_canned_response(Some("hi"), None, None, None)
+            .await
+            .unwrap();
+        db.add_custom_command("!testroom:example.com", "!hi", rid)
+            .await
+            .unwrap();
+
+        let result = run_cmd(
+            "!embedbot admin list-commands",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("!hi"));
+                assert!(msg.contains("1"));
+            }
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_add_and_remove_autoresponder() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin add-autoresponder hello 0.5 hi there!",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match &result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("has been set"), "got: {}", msg);
+                assert!(msg.contains("50%"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        let autos = db
+            .get_autoresponders("!testroom:example.com")
+            .await
+            .unwrap();
+        assert_eq!(autos.len(), 1);
+        assert_eq!(autos[0].pattern, "hello");
+        assert_eq!(autos[0].probability, 0.5);
+        assert_eq!(
+            autos[0].response.text_markdown.as_deref(),
+            Some("hi there!")
+        );
+
+        // list
+        let result = run_cmd(
+            "!embedbot admin list-autoresponders",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("hello"));
+                assert!(msg.contains("50%"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // remove
+        let result = run_cmd(
+            "!embedbot admin remove-autoresponder hello",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("removed")),
+            _ => panic!("Expected Response"),
+        }
+
+        assert!(
+            db.get_autoresponders("!testroom:example.com")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_add_autoresponder_invalid_regex() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin add-autoresponder [invalid response text",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("not a valid regex")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_autoresponders_basic() {
+        let db = test_database().await;
+        let room = "!testroom:example.com";
+
+        let rid = db
+            .create_canned_response(Some("world!"), None, None, None)
+            .await
+            .unwrap();
+        db.add_autoresponder(room, "hello", 1.0, rid).await.unwrap();
+
+        let result = check_autoresponders("hello world", room, &db).await;
+        assert!(result.is_some());
+        assert_eq!(result.unwrap().text_markdown.as_deref(), Some("world!"));
+
+        let result = check_autoresponders("goodbye", room, &db).await;
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_check_autoresponders_probability_zero() {
+        let db = test_database().await;
+        let room = "!testroom:example.com";
+
+        let rid = db
+            .create_canned_response(Some("nope"), None, None, None)
+            .await
+            .unwrap();
+        db.add_autoresponder(room, "hi", 0.0, rid).await.unwrap();
+
+        // With probability 0, should never trigger.
+        for _ in 0..20 {
+            assert!(check_autoresponders("hi", room, &db).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_prefix() {
+        let config = Config {
+            command_prefix: "!mybot".to_string(),
+            trusted_users: vec!["@admin:example.com".to_string()],
+            ..Config::default()
+        };
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        // "!mybot" should be recognised as the bot command.
+        let result = run_cmd(
+            "!mybot",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("Usage"));
+                assert!(msg.contains("!mybot"), "help should use custom prefix");
+                assert!(!msg.contains("!embedbot"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // "!embedbot" should NOT be recognised with the custom prefix.
+        let result = run_cmd(
+            "!embedbot",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert!(matches!(result, CommandResult::NotACommand));
+
+        // Admin help should also use the custom prefix.
+        let result = run_cmd(
+            "!mybot admin",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("!mybot admin"));
+                assert!(!msg.contains("!embedbot"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // Subcommand usage should use the custom prefix.
+        let result = run_cmd(
+            "!mybot admin remove-device",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("!mybot admin remove-device"));
+            }
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_add_global_command() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin add-command --global !greet Hello global!",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match &result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("has been set"), "got: {}", msg);
+                assert!(msg.contains("globally"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // Should be visible from any room.
+        let result = run_cmd(
+            "!greet",
+            "@user:example.com",
+            "!anyroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::CannedResponse(cr) => {
+                assert_eq!(cr.text_markdown.as_deref(), Some("Hello global!"));
+            }
+            _ => panic!("Expected CannedResponse"),
+        }
+
+        // Room-specific override takes priority.
+        let result = run_cmd(
+            "!embedbot admin add-command !greet Hello room!",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert!(matches!(result, CommandResult::Response(_)));
+
+        let result = run_cmd(
+            "!greet",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::CannedResponse(cr) => {
+                assert_eq!(cr.text_markdown.as_deref(), Some("Hello room!"));
+            }
+            _ => panic!("Expected CannedResponse"),
+        }
+
+        // Other rooms still see global.
+        let result = run_cmd(
+            "!greet",
+            "@user:example.com",
+            "!other:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::CannedResponse(cr) => {
+                assert_eq!(cr.text_markdown.as_deref(), Some("Hello global!"));
+            }
+            _ => panic!("Expected CannedResponse"),
+        }
+
+        // list-commands --global shows the global command.
+        let result = run_cmd(
+            "!embedbot admin list-commands --global",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("!greet"));
+                assert!(msg.contains("globally"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // remove --global
+        let result = run_cmd(
+            "!embedbot admin remove-command --global !greet",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("removed")),
+            _ => panic!("Expected Response"),
+        }
+
+        // Still have the room-specific one.
+        let result = run_cmd(
+            "!greet",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert!(matches!(result, CommandResult::CannedResponse(_)));
+    }
+
+    #[tokio::test]
+    async fn test_admin_add_global_autoresponder() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin add-autoresponder --global hello hi there!",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match &result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("has been set"), "got: {}", msg);
+                assert!(msg.contains("globally"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // Should be visible from any room via check_autoresponders.
+        let result = check_autoresponders("hello world", "!anyroom:example.com", &db).await;
+        assert!(result.is_some());
+        assert_eq!(result.unwrap().text_markdown.as_deref(), Some("hi there!"));
+
+        // list-autoresponders --global
+        let result = run_cmd(
+            "!embedbot admin list-autoresponders --global",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("hello"), "got: {}", msg);
+                assert!(msg.contains("globally"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // remove --global
+        let result = run_cmd(
+            "!embedbot admin remove-autoresponder --global hello",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("removed")),
+            _ => panic!("Expected Response"),
+        }
+
+        let result = check_autoresponders("hello world", "!anyroom:example.com", &db).await;
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_admin_help_includes_new_commands() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("add-command"));
+                assert!(msg.contains("remove-command"));
+                assert!(msg.contains("list-commands"));
+                assert!(msg.contains("add-autoresponder"));
+                assert!(msg.contains("remove-autoresponder"));
+                assert!(msg.contains("list-autoresponders"));
+            }
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_subcommand() {
+        let config = test_config(vec![]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot foobar",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("Unknown command"));
+                assert!(msg.contains("foobar"));
+            }
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let config = test_config(vec![]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot refresh https://example.com/article",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Refresh(url) => assert_eq!(url.as_str(), "https://example.com/article"),
+            _ => panic!("Expected Refresh"),
+        }
+
+        let result = run_cmd(
+            "!embedbot refresh",
+            "@user:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("refresh <url>")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_untrusted_user() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin remove-device FOOBAR",
+            "@random:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Permission denied")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_help() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("Usage"));
+                assert!(msg.contains("enable-key-sharing"));
+                assert!(msg.contains("disable-key-sharing"));
+                assert!(msg.contains("list-key-sharing"));
+            }
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_unknown_subcommand() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin nope",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("Unknown admin command"));
+                assert!(msg.contains("nope"));
+            }
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_remove_device_missing_id() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin remove-device",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_reset_identity_untrusted() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin reset-identity",
+            "@random:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Permission denied")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_enable_key_sharing() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin enable-key-sharing !test:example.com",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("enabled"));
+                assert!(msg.contains("!test:example.com"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        // Verify it was actually persisted.
+        assert!(
+            db.is_key_sharing_enabled("!test:example.com")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_enable_key_sharing_bad_room_id() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin enable-key-sharing not-a-room-id",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("does not look like a valid room ID"));
+            }
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_enable_key_sharing_missing_arg() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin enable-key-sharing",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_disable_key_sharing() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        db.enable_key_sharing("!test:example.com").await.unwrap();
+
+        let result = run_cmd(
+            "!embedbot admin disable-key-sharing !test:example.com",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("disabled"));
+                assert!(msg.contains("!test:example.com"));
+            }
+            _ => panic!("Expected Response"),
+        }
+
+        assert!(
+            !db.is_key_sharing_enabled("!test:example.com")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_enable_disable_summaries() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin enable-summaries",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("enabled"));
+                assert!(msg.contains("!testroom:example.com"));
+                assert!(msg.contains("no summary endpoint is configured"));
+            }
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            db.is_summaries_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+
+        let result = run_cmd(
+            "!embedbot admin disable-summaries",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("disabled")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            !db.is_summaries_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_enable_disable_data_saver() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin enable-data-saver",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("enabled")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            db.is_data_saver_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+
+        let result = run_cmd(
+            "!embedbot admin disable-data-saver",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("disabled")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            !db.is_data_saver_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_enable_disable_bare_links() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin enable-bare-links",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("www.")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            db.is_bare_links_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+
+        let result = run_cmd(
+            "!embedbot admin disable-bare-links",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("disabled")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            !db.is_bare_links_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_enable_disable_caption_links() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
+
+        let result = run_cmd(
+            "!embedbot admin enable-caption-links",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("captions")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            db.is_caption_links_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+
+        let result = run_cmd(
+            "!embedbot admin disable-caption-links",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
         )
-        .await
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("disabled")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            !db.is_caption_links_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
     }
 
     #[tokio::test]
-    async fn test_not_a_command() {
-        let config = test_config(vec![]);
+    async fn test_admin_enable_disable_summary_thumbnails() {
+        let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
             .build()
@@ -1030,20 +4218,47 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "hello world",
-            "@user:example.com",
+            "!embedbot admin enable-summary-thumbnails",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
             &db,
         )
         .await;
-        assert!(matches!(result, CommandResult::NotACommand));
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("thumbnail")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            db.is_summary_thumbnails_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+
+        let result = run_cmd(
+            "!embedbot admin disable-summary-thumbnails",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("disabled")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            !db.is_summary_thumbnails_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
     }
 
     #[tokio::test]
-    async fn test_not_a_command_url() {
-        let config = test_config(vec![]);
+    async fn test_admin_enable_disable_video_posters() {
+        let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
             .build()
@@ -1052,20 +4267,50 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "https://example.com",
-            "@user:example.com",
+            "!embedbot admin enable-video-posters",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
             &db,
         )
         .await;
-        assert!(matches!(result, CommandResult::NotACommand));
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("poster image")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            db.is_video_posters_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
+
+        let result = run_cmd(
+            "!embedbot admin disable-video-posters",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("disabled")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(
+            !db.is_video_posters_enabled("!testroom:example.com")
+                .await
+                .unwrap()
+        );
     }
 
     #[tokio::test]
-    async fn test_base_command_shows_help() {
-        let config = test_config(vec![]);
+    async fn test_admin_enable_disable_publish_info() {
+        let config = Config {
+            publish_info_rooms: vec!["!configured:example.com".to_string()],
+            ..test_config(vec!["@admin:example.com"])
+        };
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
             .build()
@@ -1074,8 +4319,8 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot",
-            "@user:example.com",
+            "!embedbot admin enable-publish-info",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
@@ -1083,16 +4328,45 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
-            other => panic!(
-                "Expected Response, got {:?}",
-                matches!(other, CommandResult::NotACommand)
-            ),
+            CommandResult::Response(msg) => assert!(msg.contains("describe itself")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(describe::publishes_in("!testroom:example.com", &config, &db).await);
+        assert!(describe::publishes_in("!configured:example.com", &config, &db).await);
+        assert!(!describe::publishes_in("!other:example.com", &config, &db).await);
+
+        let result = run_cmd(
+            "!embedbot admin disable-publish-info",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("disabled")),
+            _ => panic!("Expected Response"),
+        }
+        assert!(!describe::publishes_in("!testroom:example.com", &config, &db).await);
+
+        let result = run_cmd(
+            "!embedbot admin disable-publish-info !configured:example.com",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("still configured")),
+            _ => panic!("Expected Response"),
         }
     }
 
     #[tokio::test]
-    async fn test_custom_command_trigger() {
+    async fn test_admin_queue_and_cancel() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1101,19 +4375,9 @@ mod tests {
             .unwrap();
         let db = test_database().await;
 
-        // Set up a custom command via DB directly.
-        let rid = db
-            .create_canned_response(Some("Here are useful links!"), None, None, None)
-            .await
-            .unwrap();
-        db.add_custom_command("!testroom:example.com", "!links", rid)
-            .await
-            .unwrap();
-
-        // Should trigger the custom command.
         let result = run_cmd(
-            "!links",
-            "@user:example.com",
+            "!embedbot admin queue",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
@@ -1121,39 +4385,41 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::CannedResponse(cr) => {
-                assert_eq!(cr.text_markdown.as_deref(), Some("Here are useful links!"));
-            }
-            _ => panic!("Expected CannedResponse"),
+            CommandResult::Response(msg) => assert_eq!(msg, "No embeds in progress."),
+            _ => panic!("Expected Response"),
         }
 
-        // Different room should not match.
         let result = run_cmd(
-            "!links",
-            "@user:example.com",
-            "!otherroom:example.com",
+            "!embedbot admin cancel",
+            "@admin:example.com",
+            "!testroom:example.com",
             &config,
             &client,
             &db,
         )
         .await;
-        assert!(matches!(result, CommandResult::NotACommand));
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
+            _ => panic!("Expected Response"),
+        }
 
-        // Random non-command text should not match.
         let result = run_cmd(
-            "hello",
-            "@user:example.com",
+            "!embedbot admin cancel 7",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
             &db,
         )
         .await;
-        assert!(matches!(result, CommandResult::NotACommand));
+        match result {
+            CommandResult::Response(msg) => assert!(msg.starts_with("No embed `7`")),
+            _ => panic!("Expected Response"),
+        }
     }
 
     #[tokio::test]
-    async fn test_admin_add_and_remove_command() {
+    async fn test_admin_domains() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1162,9 +4428,8 @@ mod tests {
             .unwrap();
         let db = test_database().await;
 
-        // add-command with text only
         let result = run_cmd(
-            "!embedbot admin add-command !greet Hello, world!",
+            "!embedbot admin domains",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1172,25 +4437,70 @@ mod tests {
             &db,
         )
         .await;
-        match &result {
+        match result {
+            CommandResult::Response(msg) => assert_eq!(msg, "No embeds attempted yet."),
+            _ => panic!("Expected Response"),
+        }
+
+        for _ in 0..5 {
+            db.record_domain_outcome("broken.example", DomainOutcome::Empty)
+                .await
+                .unwrap();
+        }
+        db.record_domain_outcome("fine.example", DomainOutcome::Embedded)
+            .await
+            .unwrap();
+
+        let result = run_cmd(
+            "!embedbot admin domains",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
             CommandResult::Response(msg) => {
-                assert!(msg.contains("has been set"), "got: {}", msg);
-                assert!(msg.contains("!greet"));
+                let lines: Vec<&str> = msg.lines().collect();
+                assert_eq!(
+                    lines[2],
+                    "- `broken.example` — 0 embedded, 5 empty, 0 failed — ⚠️ nothing from the last 5 attempts"
+                );
+                assert!(lines[3].starts_with(
+                    "- `fine.example` — 1 embedded, 0 empty, 0 failed; last embedded "
+                ));
             }
             _ => panic!("Expected Response"),
         }
 
-        // Verify the command exists.
-        let cr = db
-            .get_custom_command("!testroom:example.com", "!greet")
+        let result = run_cmd(
+            "!embedbot admin domains 0",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
+            _ => panic!("Expected Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_time_format() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
             .await
-            .unwrap()
             .unwrap();
-        assert_eq!(cr.text_markdown.as_deref(), Some("Hello, world!"));
+        let db = test_database().await;
 
-        // remove-command
         let result = run_cmd(
-            "!embedbot admin remove-command !greet",
+            "!embedbot admin set-timezone Mars/Olympus_Mons",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1199,20 +4509,48 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("removed")),
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
             _ => panic!("Expected Response"),
         }
 
-        assert!(
-            db.get_custom_command("!testroom:example.com", "!greet")
+        for cmd in [
+            "!embedbot admin set-timezone Asia/Tokyo",
+            "!embedbot admin set-time-style relative",
+        ] {
+            run_cmd(
+                cmd,
+                "@admin:example.com",
+                "!testroom:example.com",
+                &config,
+                &client,
+                &db,
+            )
+            .await;
+        }
+        assert_eq!(
+            db.get_room_time_format("!testroom:example.com")
                 .await
-                .unwrap()
-                .is_none()
+                .unwrap(),
+            (Some("Asia/Tokyo".to_string()), Some(TimeStyle::Relative))
         );
+
+        let result = run_cmd(
+            "!embedbot admin clear-time-format",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("absolute in UTC")),
+            _ => panic!("Expected Response"),
+        }
     }
 
     #[tokio::test]
-    async fn test_admin_add_command_missing_args() {
+    async fn test_admin_quiet_hours() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1222,7 +4560,7 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin add-command",
+            "!embedbot admin set-quiet-hours 09:00",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1235,9 +4573,8 @@ mod tests {
             _ => panic!("Expected Response"),
         }
 
-        // Name without text or media
         let result = run_cmd(
-            "!embedbot admin add-command !test",
+            "!embedbot admin set-quiet-hours 22:00-07:00 digest",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1246,13 +4583,48 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("Must provide")),
+            CommandResult::Response(msg) => {
+                assert!(msg.contains("from 22:00 to 07:00 (UTC)"));
+                assert!(msg.contains("digest"));
+            }
             _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_quiet_hours("!testroom:example.com").await.unwrap(),
+            QuietHours::parse("22:00-07:00", true)
+        );
+
+        run_cmd(
+            "!embedbot admin set-quiet-hours 09:00-10:00 !other:example.com",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert_eq!(
+            db.get_quiet_hours("!other:example.com").await.unwrap(),
+            QuietHours::parse("09:00-10:00", false)
+        );
+
+        run_cmd(
+            "!embedbot admin clear-quiet-hours",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert_eq!(
+            db.get_quiet_hours("!testroom:example.com").await.unwrap(),
+            None
+        );
     }
 
     #[tokio::test]
-    async fn test_admin_add_command_no_bang() {
+    async fn test_admin_enable_disable_digest() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1262,7 +4634,7 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin add-command greet Hello",
+            "!embedbot admin enable-digest soon",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1271,24 +4643,12 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("must start with `!`")),
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
             _ => panic!("Expected Response"),
         }
-    }
-
-    #[tokio::test]
-    async fn test_admin_list_commands() {
-        let config = test_config(vec!["@admin:example.com"]);
-        let client = Client::builder()
-            .homeserver_url("https://matrix.example.com")
-            .build()
-            .await
-            .unwrap();
-        let db = test_database().await;
 
-        // Empty list
         let result = run_cmd(
-            "!embedbot admin list-commands",
+            "!embedbot admin enable-digest",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1297,21 +4657,32 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("No custom commands")),
+            CommandResult::Response(msg) => assert!(msg.contains("every 60 minute(s)")),
             _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_digest("!testroom:example.com").await.unwrap(),
+            Some(RoomDigest { interval: None })
+        );
 
-        // Add one and list
-        let rid = db
-            .create_canned_response(Some("hi"), None, None, None)
-            .await
-            .unwrap();
-        db.add_custom_command("!testroom:example.com", "!hi", rid)
-            .await
-            .unwrap();
+        run_cmd(
+            "!embedbot admin enable-digest 15 !other:example.com",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert_eq!(
+            db.get_digest("!other:example.com").await.unwrap(),
+            Some(RoomDigest {
+                interval: Some(Duration::from_secs(15 * 60))
+            })
+        );
 
         let result = run_cmd(
-            "!embedbot admin list-commands",
+            "!embedbot admin disable-digest",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1320,16 +4691,14 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("!hi"));
-                assert!(msg.contains("1"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("**disabled**")),
             _ => panic!("Expected Response"),
         }
+        assert_eq!(db.get_digest("!testroom:example.com").await.unwrap(), None);
     }
 
     #[tokio::test]
-    async fn test_admin_add_and_remove_autoresponder() {
+    async fn test_admin_video_format() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1339,7 +4708,7 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin add-autoresponder hello 0.5 hi there!",
+            "!embedbot admin set-video-format mkv",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1347,29 +4716,13 @@ mod tests {
             &db,
         )
         .await;
-        match &result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("has been set"), "got: {}", msg);
-                assert!(msg.contains("50%"));
-            }
+        match result {
+            CommandResult::Response(msg) => assert!(msg.starts_with("Usage:")),
             _ => panic!("Expected Response"),
         }
 
-        let autos = db
-            .get_autoresponders("!testroom:example.com")
-            .await
-            .unwrap();
-        assert_eq!(autos.len(), 1);
-        assert_eq!(autos[0].pattern, "hello");
-        assert_eq!(autos[0].probability, 0.5);
-        assert_eq!(
-            autos[0].response.text_markdown.as_deref(),
-            Some("hi there!")
-        );
-
-        // list
         let result = run_cmd(
-            "!embedbot admin list-autoresponders",
+            "!embedbot admin set-video-format webm",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1378,16 +4731,16 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("hello"));
-                assert!(msg.contains("50%"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("webm")),
             _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_video_format("!testroom:example.com").await.unwrap(),
+            Some(VideoFormat::Webm)
+        );
 
-        // remove
         let result = run_cmd(
-            "!embedbot admin remove-autoresponder hello",
+            "!embedbot admin clear-video-format",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1399,17 +4752,14 @@ mod tests {
             CommandResult::Response(msg) => assert!(msg.contains("removed")),
             _ => panic!("Expected Response"),
         }
-
-        assert!(
-            db.get_autoresponders("!testroom:example.com")
-                .await
-                .unwrap()
-                .is_empty()
+        assert_eq!(
+            db.get_video_format("!testroom:example.com").await.unwrap(),
+            None
         );
     }
 
     #[tokio::test]
-    async fn test_admin_add_autoresponder_invalid_regex() {
+    async fn test_admin_language() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1419,7 +4769,7 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin add-autoresponder [invalid response text",
+            "!embedbot admin set-language klingon!",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1428,95 +4778,12 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("not a valid regex")),
-            _ => panic!("Expected Response"),
-        }
-    }
-
-    #[tokio::test]
-    async fn test_check_autoresponders_basic() {
-        let db = test_database().await;
-        let room = "!testroom:example.com";
-
-        let rid = db
-            .create_canned_response(Some("world!"), None, None, None)
-            .await
-            .unwrap();
-        db.add_autoresponder(room, "hello", 1.0, rid).await.unwrap();
-
-        let result = check_autoresponders("hello world", room, &db).await;
-        assert!(result.is_some());
-        assert_eq!(result.unwrap().text_markdown.as_deref(), Some("world!"));
-
-        let result = check_autoresponders("goodbye", room, &db).await;
-        assert!(result.is_none());
-    }
-
-    #[tokio::test]
-    async fn test_check_autoresponders_probability_zero() {
-        let db = test_database().await;
-        let room = "!testroom:example.com";
-
-        let rid = db
-            .create_canned_response(Some("nope"), None, None, None)
-            .await
-            .unwrap();
-        db.add_autoresponder(room, "hi", 0.0, rid).await.unwrap();
-
-        // With probability 0, should never trigger.
-        for _ in 0..20 {
-            assert!(check_autoresponders("hi", room, &db).await.is_none());
-        }
-    }
-
-    #[tokio::test]
-    async fn test_custom_prefix() {
-        let config = Config {
-            command_prefix: "!mybot".to_string(),
-            trusted_users: vec!["@admin:example.com".to_string()],
-            ..Config::default()
-        };
-        let client = Client::builder()
-            .homeserver_url("https://matrix.example.com")
-            .build()
-            .await
-            .unwrap();
-        let db = test_database().await;
-
-        // "!mybot" should be recognised as the bot command.
-        let result = run_cmd(
-            "!mybot",
-            "@user:example.com",
-            "!testroom:example.com",
-            &config,
-            &client,
-            &db,
-        )
-        .await;
-        match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("Usage"));
-                assert!(msg.contains("!mybot"), "help should use custom prefix");
-                assert!(!msg.contains("!embedbot"));
-            }
+            CommandResult::Response(msg) => assert!(msg.starts_with("Usage:")),
             _ => panic!("Expected Response"),
         }
 
-        // "!embedbot" should NOT be recognised with the custom prefix.
-        let result = run_cmd(
-            "!embedbot",
-            "@user:example.com",
-            "!testroom:example.com",
-            &config,
-            &client,
-            &db,
-        )
-        .await;
-        assert!(matches!(result, CommandResult::NotACommand));
-
-        // Admin help should also use the custom prefix.
         let result = run_cmd(
-            "!mybot admin",
+            "!embedbot admin set-language pt_BR",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1525,16 +4792,19 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("!mybot admin"));
-                assert!(!msg.contains("!embedbot"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("pt-br")),
             _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_room_language("!testroom:example.com")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("pt-br")
+        );
 
-        // Subcommand usage should use the custom prefix.
         let result = run_cmd(
-            "!mybot admin remove-device",
+            "!embedbot admin clear-language",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1543,15 +4813,17 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("!mybot admin remove-device"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("removed")),
             _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_room_language("!testroom:example.com").await.unwrap(),
+            None
+        );
     }
 
     #[tokio::test]
-    async fn test_admin_add_global_command() {
+    async fn test_admin_embed_mode() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1561,7 +4833,7 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin add-command --global !greet Hello global!",
+            "!embedbot admin set-embed-mode sometimes",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1569,34 +4841,31 @@ mod tests {
             &db,
         )
         .await;
-        match &result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("has been set"), "got: {}", msg);
-                assert!(msg.contains("globally"));
-            }
+        match result {
+            CommandResult::Response(msg) => assert!(msg.starts_with("Usage:")),
             _ => panic!("Expected Response"),
         }
 
-        // Should be visible from any room.
         let result = run_cmd(
-            "!greet",
-            "@user:example.com",
-            "!anyroom:example.com",
+            "!embedbot admin set-embed-mode always",
+            "@admin:example.com",
+            "!testroom:example.com",
             &config,
             &client,
             &db,
         )
         .await;
         match result {
-            CommandResult::CannedResponse(cr) => {
-                assert_eq!(cr.text_markdown.as_deref(), Some("Hello global!"));
-            }
-            _ => panic!("Expected CannedResponse"),
+            CommandResult::Response(msg) => assert!(msg.contains("**always**")),
+            _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_embed_mode("!testroom:example.com").await.unwrap(),
+            Some(EmbedMode::Always)
+        );
 
-        // Room-specific override takes priority.
         let result = run_cmd(
-            "!embedbot admin add-command !greet Hello room!",
+            "!embedbot admin clear-embed-mode",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1604,44 +4873,28 @@ mod tests {
             &db,
         )
         .await;
-        assert!(matches!(result, CommandResult::Response(_)));
-
-        let result = run_cmd(
-            "!greet",
-            "@user:example.com",
-            "!testroom:example.com",
-            &config,
-            &client,
-            &db,
-        )
-        .await;
         match result {
-            CommandResult::CannedResponse(cr) => {
-                assert_eq!(cr.text_markdown.as_deref(), Some("Hello room!"));
-            }
-            _ => panic!("Expected CannedResponse"),
+            CommandResult::Response(msg) => assert!(msg.contains("encrypted-only")),
+            _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_embed_mode("!testroom:example.com").await.unwrap(),
+            None
+        );
+    }
 
-        // Other rooms still see global.
-        let result = run_cmd(
-            "!greet",
-            "@user:example.com",
-            "!other:example.com",
-            &config,
-            &client,
-            &db,
-        )
-        .await;
-        match result {
-            CommandResult::CannedResponse(cr) => {
-                assert_eq!(cr.text_markdown.as_deref(), Some("Hello global!"));
-            }
-            _ => panic!("Expected CannedResponse"),
-        }
+    #[tokio::test]
+    async fn test_admin_caption_layout() {
+        let config = test_config(vec!["@admin:example.com"]);
+        let client = Client::builder()
+            .homeserver_url("https://matrix.example.com")
+            .build()
+            .await
+            .unwrap();
+        let db = test_database().await;
 
-        // list-commands --global shows the global command.
         let result = run_cmd(
-            "!embedbot admin list-commands --global",
+            "!embedbot admin set-caption-layout sideways",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1650,16 +4903,12 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("!greet"));
-                assert!(msg.contains("globally"));
-            }
+            CommandResult::Response(msg) => assert!(msg.starts_with("Usage:")),
             _ => panic!("Expected Response"),
         }
 
-        // remove --global
         let result = run_cmd(
-            "!embedbot admin remove-command --global !greet",
+            "!embedbot admin set-caption-layout text-first",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1668,25 +4917,39 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("removed")),
+            CommandResult::Response(msg) => assert!(msg.contains("**text-first**")),
             _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_caption_layout("!testroom:example.com")
+                .await
+                .unwrap(),
+            Some(CaptionLayout::TextFirst)
+        );
 
-        // Still have the room-specific one.
         let result = run_cmd(
-            "!greet",
-            "@user:example.com",
+            "!embedbot admin clear-caption-layout",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
             &db,
         )
         .await;
-        assert!(matches!(result, CommandResult::CannedResponse(_)));
+        match result {
+            CommandResult::Response(msg) => assert!(msg.contains("on-media")),
+            _ => panic!("Expected Response"),
+        }
+        assert_eq!(
+            db.get_caption_layout("!testroom:example.com")
+                .await
+                .unwrap(),
+            None
+        );
     }
 
     #[tokio::test]
-    async fn test_admin_add_global_autoresponder() {
+    async fn test_admin_link_policy() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1696,7 +4959,7 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin add-autoresponder --global hello hi there!",
+            "!embedbot admin set-link-policy some",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1704,22 +4967,13 @@ mod tests {
             &db,
         )
         .await;
-        match &result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("has been set"), "got: {}", msg);
-                assert!(msg.contains("globally"));
-            }
+        match result {
+            CommandResult::Response(msg) => assert!(msg.starts_with("Usage:")),
             _ => panic!("Expected Response"),
         }
 
-        // Should be visible from any room via check_autoresponders.
-        let result = check_autoresponders("hello world", "!anyroom:example.com", &db).await;
-        assert!(result.is_some());
-        assert_eq!(result.unwrap().text_markdown.as_deref(), Some("hi there!"));
-
-        // list-autoresponders --global
         let result = run_cmd(
-            "!embedbot admin list-autoresponders --global",
+            "!embedbot admin set-link-policy all",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1728,16 +4982,16 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("hello"), "got: {}", msg);
-                assert!(msg.contains("globally"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("**all**")),
             _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_link_policy("!testroom:example.com").await.unwrap(),
+            Some(LinkPolicy::All)
+        );
 
-        // remove --global
         let result = run_cmd(
-            "!embedbot admin remove-autoresponder --global hello",
+            "!embedbot admin clear-link-policy",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1746,16 +5000,17 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("removed")),
+            CommandResult::Response(msg) => assert!(msg.contains("first")),
             _ => panic!("Expected Response"),
         }
-
-        let result = check_autoresponders("hello world", "!anyroom:example.com", &db).await;
-        assert!(result.is_none());
+        assert_eq!(
+            db.get_link_policy("!testroom:example.com").await.unwrap(),
+            None
+        );
     }
 
     #[tokio::test]
-    async fn test_admin_help_includes_new_commands() {
+    async fn test_admin_embed_power_level() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1765,7 +5020,7 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin",
+            "!embedbot admin set-embed-power-level high",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1774,31 +5029,13 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("add-command"));
-                assert!(msg.contains("remove-command"));
-                assert!(msg.contains("list-commands"));
-                assert!(msg.contains("add-autoresponder"));
-                assert!(msg.contains("remove-autoresponder"));
-                assert!(msg.contains("list-autoresponders"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
             _ => panic!("Expected Response"),
         }
-    }
-
-    #[tokio::test]
-    async fn test_unknown_subcommand() {
-        let config = test_config(vec![]);
-        let client = Client::builder()
-            .homeserver_url("https://matrix.example.com")
-            .build()
-            .await
-            .unwrap();
-        let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot foobar",
-            "@user:example.com",
+            "!embedbot admin set-embed-power-level 50",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
@@ -1806,27 +5043,19 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("Unknown command"));
-                assert!(msg.contains("foobar"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("**50**")),
             _ => panic!("Expected Response"),
         }
-    }
-
-    #[tokio::test]
-    async fn test_admin_untrusted_user() {
-        let config = test_config(vec!["@admin:example.com"]);
-        let client = Client::builder()
-            .homeserver_url("https://matrix.example.com")
-            .build()
-            .await
-            .unwrap();
-        let db = test_database().await;
+        assert_eq!(
+            db.get_embed_min_power_level("!testroom:example.com")
+                .await
+                .unwrap(),
+            Some(50)
+        );
 
         let result = run_cmd(
-            "!embedbot admin remove-device FOOBAR",
-            "@random:example.com",
+            "!embedbot admin clear-embed-power-level",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
@@ -1834,13 +5063,19 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("Permission denied")),
+            CommandResult::Response(msg) => assert!(msg.contains("everyone")),
             _ => panic!("Expected Response"),
         }
+        assert_eq!(
+            db.get_embed_min_power_level("!testroom:example.com")
+                .await
+                .unwrap(),
+            None
+        );
     }
 
     #[tokio::test]
-    async fn test_admin_help() {
+    async fn test_admin_purge() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1850,7 +5085,7 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin",
+            "!embedbot admin purge 0",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1859,28 +5094,12 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("Usage"));
-                assert!(msg.contains("enable-key-sharing"));
-                assert!(msg.contains("disable-key-sharing"));
-                assert!(msg.contains("list-key-sharing"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
             _ => panic!("Expected Response"),
         }
-    }
-
-    #[tokio::test]
-    async fn test_admin_unknown_subcommand() {
-        let config = test_config(vec!["@admin:example.com"]);
-        let client = Client::builder()
-            .homeserver_url("https://matrix.example.com")
-            .build()
-            .await
-            .unwrap();
-        let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin nope",
+            "!embedbot admin purge",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1889,26 +5108,20 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("Unknown admin command"));
-                assert!(msg.contains("nope"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("No embeds")),
             _ => panic!("Expected Response"),
         }
-    }
-
-    #[tokio::test]
-    async fn test_admin_remove_device_missing_id() {
-        let config = test_config(vec!["@admin:example.com"]);
-        let client = Client::builder()
-            .homeserver_url("https://matrix.example.com")
-            .build()
-            .await
-            .unwrap();
-        let db = test_database().await;
 
+        db.record_embed(
+            "!testroom:example.com",
+            "$embed:example.com",
+            "$source:example.com",
+            "https://example.com/post",
+        )
+        .await
+        .unwrap();
         let result = run_cmd(
-            "!embedbot admin remove-device",
+            "!embedbot admin purge 5",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1917,13 +5130,13 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
+            CommandResult::Response(msg) => assert!(msg.contains("Not in room")),
             _ => panic!("Expected Response"),
         }
     }
 
     #[tokio::test]
-    async fn test_admin_reset_identity_untrusted() {
+    async fn test_admin_room_profile() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -1933,8 +5146,8 @@ mod tests {
         let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin reset-identity",
-            "@random:example.com",
+            "!embedbot admin set-room-name",
+            "@admin:example.com",
             "!testroom:example.com",
             &config,
             &client,
@@ -1942,23 +5155,12 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => assert!(msg.contains("Permission denied")),
+            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
             _ => panic!("Expected Response"),
         }
-    }
-
-    #[tokio::test]
-    async fn test_admin_enable_key_sharing() {
-        let config = test_config(vec!["@admin:example.com"]);
-        let client = Client::builder()
-            .homeserver_url("https://matrix.example.com")
-            .build()
-            .await
-            .unwrap();
-        let db = test_database().await;
 
         let result = run_cmd(
-            "!embedbot admin enable-key-sharing !test:example.com",
+            "!embedbot admin set-room-name Link Preview",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -1967,33 +5169,29 @@ mod tests {
         )
         .await;
         match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("enabled"));
-                assert!(msg.contains("!test:example.com"));
-            }
+            CommandResult::Response(msg) => assert!(msg.contains("not in room")),
             _ => panic!("Expected Response"),
         }
 
-        // Verify it was actually persisted.
-        assert!(
-            db.is_key_sharing_enabled("!test:example.com")
-                .await
-                .unwrap()
+        let result = run_cmd(
+            "!embedbot admin set-room-avatar mxc://example.com/avatar",
+            "@admin:example.com",
+            "!testroom:example.com",
+            &config,
+            &client,
+            &db,
+        )
+        .await;
+        assert!(matches!(result, CommandResult::Response(_)));
+        let profile = db.get_room_profile("!testroom:example.com").await.unwrap();
+        assert_eq!(profile.display_name.as_deref(), Some("Link Preview"));
+        assert_eq!(
+            profile.avatar_url.as_deref(),
+            Some("mxc://example.com/avatar")
         );
-    }
-
-    #[tokio::test]
-    async fn test_admin_enable_key_sharing_bad_room_id() {
-        let config = test_config(vec!["@admin:example.com"]);
-        let client = Client::builder()
-            .homeserver_url("https://matrix.example.com")
-            .build()
-            .await
-            .unwrap();
-        let db = test_database().await;
 
-        let result = run_cmd(
-            "!embedbot admin enable-key-sharing not-a-room-id",
+        run_cmd(
+            "!embedbot admin clear-room-profile",
             "@admin:example.com",
             "!testroom:example.com",
             &config,
@@ -2001,16 +5199,16 @@ mod tests {
             &db,
         )
         .await;
-        match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("does not look like a valid room ID"));
-            }
-            _ => panic!("Expected Response"),
-        }
+        assert!(
+            db.get_room_profile("!testroom:example.com")
+                .await
+                .unwrap()
+                .is_empty()
+        );
     }
 
     #[tokio::test]
-    async fn test_admin_enable_key_sharing_missing_arg() {
+    async fn test_admin_rewrites() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -2018,24 +5216,50 @@ mod tests {
             .await
             .unwrap();
         let db = test_database().await;
-
-        let result = run_cmd(
-            "!embedbot admin enable-key-sharing",
-            "@admin:example.com",
-            "!testroom:example.com",
-            &config,
-            &client,
-            &db,
-        )
-        .await;
-        match result {
-            CommandResult::Response(msg) => assert!(msg.contains("Usage")),
+        let run = |body: &'static str| {
+            run_cmd(
+                body,
+                "@admin:example.com",
+                "!testroom:example.com",
+                &config,
+                &client,
+                &db,
+            )
+        };
+        let response = |result: CommandResult| match result {
+            CommandResult::Response(msg) => msg,
             _ => panic!("Expected Response"),
-        }
+        };
+
+        let msg = response(run("!embedbot admin add-rewrite ([ https://x/").await);
+        assert!(msg.contains("Invalid regex"));
+
+        let msg = response(
+            run(r"!embedbot admin add-rewrite ^https://(www\.)?x\.com/ https://fixupx.com/").await,
+        );
+        assert!(msg.contains("fixupx.com"));
+        let url = Url::parse("https://x.com/user/status/1").unwrap();
+        assert_eq!(
+            config.rewrite_url(&url).as_str(),
+            "https://fixupx.com/user/status/1"
+        );
+
+        let msg = response(run("!embedbot admin list-rewrites").await);
+        assert!(msg.contains("1. `^https://(www\\.)?x\\.com/`"));
+
+        let msg = response(run("!embedbot admin remove-rewrite 2").await);
+        assert!(msg.contains("No rewrite rule #2"));
+        let msg = response(run("!embedbot admin remove-rewrite 1").await);
+        assert!(msg.contains("removed"));
+        // Back to the configured rules.
+        assert_eq!(
+            config.rewrite_url(&url).as_str(),
+            "https://vxtwitter.com/user/status/1"
+        );
     }
 
     #[tokio::test]
-    async fn test_admin_disable_key_sharing() {
+    async fn test_admin_trust() {
         let config = test_config(vec!["@admin:example.com"]);
         let client = Client::builder()
             .homeserver_url("https://matrix.example.com")
@@ -2043,31 +5267,60 @@ mod tests {
             .await
             .unwrap();
         let db = test_database().await;
-
-        db.enable_key_sharing("!test:example.com").await.unwrap();
-
-        let result = run_cmd(
-            "!embedbot admin disable-key-sharing !test:example.com",
-            "@admin:example.com",
-            "!testroom:example.com",
-            &config,
-            &client,
-            &db,
-        )
-        .await;
-        match result {
-            CommandResult::Response(msg) => {
-                assert!(msg.contains("disabled"));
-                assert!(msg.contains("!test:example.com"));
-            }
+        let run = |body: &'static str, sender: &'static str| {
+            run_cmd(body, sender, "!testroom:example.com", &config, &client, &db)
+        };
+        let response = |result: CommandResult| match result {
+            CommandResult::Response(msg) => msg,
             _ => panic!("Expected Response"),
-        }
+        };
 
-        assert!(
-            !db.is_key_sharing_enabled("!test:example.com")
-                .await
-                .unwrap()
+        let msg = response(run("!embedbot admin list-trusted", "@bob:example.com").await);
+        assert!(msg.contains("Permission denied"));
+
+        let msg = response(run("!embedbot admin trust bob", "@admin:example.com").await);
+        assert!(msg.contains("Invalid user ID"));
+        let msg = response(
+            run(
+                "!embedbot admin trust @bob:example.com",
+                "@admin:example.com",
+            )
+            .await,
+        );
+        assert!(msg.contains("can now use admin commands"));
+        assert!(config.is_trusted("@bob:example.com"));
+
+        // The new user can use admin commands, including trust itself.
+        let msg = response(run("!embedbot admin list-trusted", "@bob:example.com").await);
+        assert!(msg.contains("`@admin:example.com` (configuration)"));
+        assert!(msg.contains("`@bob:example.com` (added by `@admin:example.com`)"));
+
+        let msg = response(
+            run(
+                "!embedbot admin untrust @admin:example.com",
+                "@bob:example.com",
+            )
+            .await,
+        );
+        assert!(msg.contains("only be removed there"));
+        let msg = response(
+            run(
+                "!embedbot admin untrust @bob:example.com",
+                "@bob:example.com",
+            )
+            .await,
+        );
+        assert!(msg.contains("can't untrust yourself"));
+        let msg = response(
+            run(
+                "!embedbot admin untrust @bob:example.com",
+                "@admin:example.com",
+            )
+            .await,
         );
+        assert!(msg.contains("no longer"));
+        assert!(!config.is_trusted("@bob:example.com"));
+        assert!(db.list_trusted_users().await.unwrap().is_empty());
     }
 
     #[tokio::test]
@@ -1,19 +1,34 @@
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use rusqlite::Connection;
+use anyhow::{Context, Result, bail};
+use futures_util::FutureExt;
+use futures_util::future::BoxFuture;
+use rusqlite::{Connection, OpenFlags};
 use tokio::sync::Mutex;
 use tracing::{debug, info};
+use url::Url;
 
-/// Current schema version. Bump this when adding new migrations.
-const SCHEMA_VERSION: u32 = 2;
+use crate::config::{CaptionLayout, EmbedMode, LinkPolicy, RoomProfile, TimeStyle, VideoFormat};
+use crate::digest::DigestEntry;
+use crate::metadata::{GalleryImage, Metadata};
+use crate::metadata_cache::MetadataCache;
+use crate::quiet::{self, QuietHours};
+use crate::safety::Threat;
+use crate::store::SharedStore;
+
+/// Current schema version, that of the last migration.
+const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
 
 /// Wrapper around a SQLite connection providing async access to the bot's
-/// persistent storage.
+/// persistent storage. The embed history and caches go through a
+/// [`SharedStore`], which is the same database unless another one is set
+/// with [`Database::set_shared_store`].
 #[derive(Clone)]
 pub struct Database {
     conn: Arc<Mutex<Connection>>,
+    shared: Arc<dyn SharedStore>,
 }
 
 #[derive(Debug, Clone)]
@@ -32,6 +47,131 @@ pub struct CustomCommandRow {
     pub response: CannedResponse,
 }
 
+#[derive(Debug, Clone)]
+pub struct UrlRewriteRow {
+    pub id: i64,
+    pub pattern: String,
+    pub replacement: String,
+}
+
+/// A user trusted with an admin command, on top of the configured ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedUserRow {
+    pub user_id: String,
+    pub added_by: String,
+}
+
+/// A previous upload of some content, as serialized media sources.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadedMedia {
+    pub source: String,
+    pub thumbnail_source: Option<String>,
+}
+
+/// Media the bot uploaded to the media repository, as recorded by
+/// [`Database::record_media_upload`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaUpload {
+    pub mxc_uri: String,
+    /// Hash of the uploaded content, if it's in the upload cache under it.
+    pub content_hash: Option<String>,
+    pub encrypted: bool,
+}
+
+/// Rows removed by [`Database::prune`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    pub embeds: usize,
+    pub summaries: usize,
+    pub uploads: usize,
+    pub galleries: usize,
+    pub verdicts: usize,
+}
+
+/// The images of a gallery that weren't posted with its embed, waiting for
+/// someone to reply to the note about them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingGallery {
+    /// The note saying how many images are left.
+    pub note_event_id: String,
+    /// The thread the embed's gallery was posted in.
+    pub thread_root: String,
+    /// The page the images are from, sent as the `Referer` when downloading
+    /// them.
+    pub referer: String,
+    pub images: Vec<GalleryImage>,
+}
+
+/// A link posted during a room's quiet hours, waiting for them to end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedEmbed {
+    /// The message the link was posted in.
+    pub event_id: String,
+    pub url: String,
+}
+
+/// A room's digest mode, in which links are collected and posted together
+/// every so often rather than embedded one by one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomDigest {
+    /// How long links are collected for, if not the global interval.
+    pub interval: Option<Duration>,
+}
+
+/// How long the rest of a gallery can be asked for.
+const PENDING_GALLERY_MAX_AGE: Duration = Duration::from_secs(7 * 86400);
+
+/// How long a Safe Browsing verdict on a link is trusted before asking
+/// again. Sites get flagged (and cleaned up) within hours, so this is short.
+const LINK_VERDICT_MAX_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// A cached Safe Browsing verdict on a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkVerdict {
+    /// What the link was flagged for, or `None` if it wasn't.
+    pub threat: Option<Threat>,
+}
+
+/// How an attempt to embed a link turned out, for per-domain statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainOutcome {
+    /// Something was posted.
+    Embedded,
+    /// The page had nothing to embed.
+    Empty,
+    /// Fetching or posting failed.
+    Failed,
+}
+
+/// Embed attempts for links to one domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainStats {
+    pub domain: String,
+    pub embedded: u64,
+    pub empty: u64,
+    pub failed: u64,
+    /// Attempts since the last one that posted something.
+    pub failure_streak: u64,
+    /// When something was last posted, as an SQLite UTC datetime.
+    pub last_embedded_at: Option<String>,
+}
+
+impl DomainStats {
+    /// Consecutive attempts without an embed after which a domain is
+    /// reported as failing.
+    pub const FAILING_STREAK: u64 = 5;
+
+    pub fn attempts(&self) -> u64 {
+        self.embedded + self.empty + self.failed
+    }
+
+    /// Whether the last several attempts all came up empty or failed, as
+    /// happens when a site changes its markup.
+    pub fn is_failing(&self) -> bool {
+        self.failure_streak >= Self::FAILING_STREAK
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AutoresponderRow {
     pub pattern: String,
@@ -57,8 +197,10 @@ impl Database {
         .await
         .context("Database open task panicked")??;
 
+        let conn = Arc::new(Mutex::new(conn));
         let db = Self {
-            conn: Arc::new(Mutex::new(conn)),
+            shared: Arc::new(SqliteStore::new(conn.clone())),
+            conn,
         };
 
         db.run_migrations().await?;
@@ -72,8 +214,10 @@ impl Database {
         let conn = Connection::open_in_memory().context("Failed to open in-memory database")?;
         conn.pragma_update(None, "foreign_keys", "ON")?;
 
+        let conn = Arc::new(Mutex::new(conn));
         let db = Self {
-            conn: Arc::new(Mutex::new(conn)),
+            shared: Arc::new(SqliteStore::new(conn.clone())),
+            conn,
         };
 
         db.run_migrations().await?;
@@ -91,9 +235,776 @@ impl Database {
         .await
         .context("Migration task panicked")?
     }
+
+    /// Keep the embed history and caches in `store` instead of this
+    /// database, so they can be shared with other instances of the bot.
+    pub fn set_shared_store(&mut self, store: Arc<dyn SharedStore>) {
+        self.shared = store;
+    }
+}
+
+/// The default [`SharedStore`], backed by the bot's own database. Metadata,
+/// which is only kept for minutes, stays in memory.
+struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+    metadata: MetadataCache,
+}
+
+impl SqliteStore {
+    fn new(conn: Arc<Mutex<Connection>>) -> Self {
+        Self {
+            conn,
+            metadata: MetadataCache::default(),
+        }
+    }
+}
+
+impl SharedStore for SqliteStore {
+    fn get_cached_metadata<'a>(
+        &'a self,
+        url: &'a Url,
+        language: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<Metadata>>> {
+        async move { Ok(self.metadata.get(url, language)) }.boxed()
+    }
+
+    fn store_metadata<'a>(
+        &'a self,
+        url: &'a Url,
+        language: Option<&'a str>,
+        meta: &'a Metadata,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            self.metadata.insert(url, language, meta, ttl);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get_cached_summary<'a>(
+        &'a self,
+        url: &'a str,
+        model: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        let conn = self.conn.clone();
+        let url = url.to_owned();
+        let model = model.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                let result = conn.query_row(
+                    "SELECT summary FROM summary_cache WHERE url = ?1 AND model = ?2",
+                    [&url, &model],
+                    |row| row.get(0),
+                );
+                match result {
+                    Ok(summary) => Ok(Some(summary)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e).context("Failed to query summary cache"),
+                }
+            })
+            .await
+            .context("get_cached_summary task panicked")?
+        }
+        .boxed()
+    }
+
+    fn store_summary<'a>(
+        &'a self,
+        url: &'a str,
+        model: &'a str,
+        summary: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        let conn = self.conn.clone();
+        let url = url.to_owned();
+        let model = model.to_owned();
+        let summary = summary.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                conn.execute(
+                    "INSERT OR REPLACE INTO summary_cache (url, model, summary) VALUES (?1, ?2, ?3)",
+                    [&url, &model, &summary],
+                )
+                .context("Failed to store summary")?;
+                Ok(())
+            })
+            .await
+            .context("store_summary task panicked")?
+        }
+        .boxed()
+    }
+
+    fn record_embed<'a>(
+        &'a self,
+        room_id: &'a str,
+        event_id: &'a str,
+        source_event_id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<()>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let event_id = event_id.to_owned();
+        let source_event_id = source_event_id.to_owned();
+        let url = url.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                conn.execute(
+                    "INSERT OR IGNORE INTO embed_history (room_id, event_id, source_event_id, url)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    [&room_id, &event_id, &source_event_id, &url],
+                )
+                .context("Failed to record embed")?;
+                Ok(())
+            })
+            .await
+            .context("record_embed task panicked")?
+        }
+        .boxed()
+    }
+
+    fn recent_embeds<'a>(
+        &'a self,
+        room_id: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<String>>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                let mut stmt = conn
+                    .prepare(
+                        "SELECT event_id FROM embed_history WHERE room_id = ?1
+                         ORDER BY id DESC LIMIT ?2",
+                    )
+                    .context("Failed to prepare embed history query")?;
+                let rows = stmt
+                    .query_map(rusqlite::params![room_id, limit as i64], |row| row.get(0))
+                    .context("Failed to query embed history")?;
+                let mut event_ids = Vec::new();
+                for row in rows {
+                    event_ids.push(row.context("Failed to read event_id row")?);
+                }
+                Ok(event_ids)
+            })
+            .await
+            .context("recent_embeds task panicked")?
+        }
+        .boxed()
+    }
+
+    fn find_embed<'a>(
+        &'a self,
+        source_event_id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        let conn = self.conn.clone();
+        let source_event_id = source_event_id.to_owned();
+        let url = url.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                let result = conn.query_row(
+                    "SELECT event_id FROM embed_history WHERE source_event_id = ?1 AND url = ?2
+                     ORDER BY id DESC LIMIT 1",
+                    [&source_event_id, &url],
+                    |row| row.get(0),
+                );
+                match result {
+                    Ok(event_id) => Ok(Some(event_id)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e).context("Failed to query embed history"),
+                }
+            })
+            .await
+            .context("find_embed task panicked")?
+        }
+        .boxed()
+    }
+
+    fn latest_embed<'a>(
+        &'a self,
+        room_id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let url = url.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                let result = conn.query_row(
+                    "SELECT event_id FROM embed_history WHERE room_id = ?1 AND url = ?2
+                     ORDER BY id DESC LIMIT 1",
+                    [&room_id, &url],
+                    |row| row.get(0),
+                );
+                match result {
+                    Ok(event_id) => Ok(Some(event_id)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e).context("Failed to query embed history"),
+                }
+            })
+            .await
+            .context("latest_embed task panicked")?
+        }
+        .boxed()
+    }
+
+    fn forget_embed<'a>(&'a self, event_id: &'a str) -> BoxFuture<'a, Result<()>> {
+        let conn = self.conn.clone();
+        let event_id = event_id.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                conn.execute("DELETE FROM embed_history WHERE event_id = ?1", [&event_id])
+                    .context("Failed to forget embed")?;
+                Ok(())
+            })
+            .await
+            .context("forget_embed task panicked")?
+        }
+        .boxed()
+    }
+
+    fn get_uploaded_media<'a>(
+        &'a self,
+        content_hash: &'a str,
+        encrypted: bool,
+    ) -> BoxFuture<'a, Result<Option<UploadedMedia>>> {
+        let conn = self.conn.clone();
+        let content_hash = content_hash.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                conn.execute(
+                    "UPDATE uploaded_media SET last_used_at = datetime('now')
+                     WHERE content_hash = ?1 AND encrypted = ?2",
+                    rusqlite::params![content_hash, encrypted],
+                )
+                .context("Failed to update uploaded media")?;
+                let result = conn.query_row(
+                    "SELECT source, thumbnail_source FROM uploaded_media
+                     WHERE content_hash = ?1 AND encrypted = ?2",
+                    rusqlite::params![content_hash, encrypted],
+                    |row| {
+                        Ok(UploadedMedia {
+                            source: row.get(0)?,
+                            thumbnail_source: row.get(1)?,
+                        })
+                    },
+                );
+                match result {
+                    Ok(media) => Ok(Some(media)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e).context("Failed to query uploaded media"),
+                }
+            })
+            .await
+            .context("get_uploaded_media task panicked")?
+        }
+        .boxed()
+    }
+
+    fn record_uploaded_media<'a>(
+        &'a self,
+        content_hash: &'a str,
+        encrypted: bool,
+        media: &'a UploadedMedia,
+        max_entries: usize,
+    ) -> BoxFuture<'a, Result<()>> {
+        let conn = self.conn.clone();
+        let content_hash = content_hash.to_owned();
+        let media = media.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                conn.execute(
+                    "INSERT OR REPLACE INTO uploaded_media
+                         (content_hash, encrypted, source, thumbnail_source)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    rusqlite::params![
+                        content_hash,
+                        encrypted,
+                        media.source,
+                        media.thumbnail_source
+                    ],
+                )
+                .context("Failed to record uploaded media")?;
+                conn.execute(
+                    "DELETE FROM uploaded_media WHERE rowid NOT IN (
+                         SELECT rowid FROM uploaded_media
+                         ORDER BY last_used_at DESC, rowid DESC LIMIT ?1
+                     )",
+                    [max_entries as i64],
+                )
+                .context("Failed to evict uploaded media")?;
+                Ok(())
+            })
+            .await
+            .context("record_uploaded_media task panicked")?
+        }
+        .boxed()
+    }
+
+    fn forget_uploaded_media<'a>(
+        &'a self,
+        content_hash: &'a str,
+        encrypted: bool,
+    ) -> BoxFuture<'a, Result<()>> {
+        let conn = self.conn.clone();
+        let content_hash = content_hash.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                conn.execute(
+                    "DELETE FROM uploaded_media WHERE content_hash = ?1 AND encrypted = ?2",
+                    rusqlite::params![content_hash, encrypted],
+                )
+                .context("Failed to forget uploaded media")?;
+                Ok(())
+            })
+            .await
+            .context("forget_uploaded_media task panicked")?
+        }
+        .boxed()
+    }
+
+    fn prune<'a>(
+        &'a self,
+        embed_max_age: Option<Duration>,
+        summary_max_age: Option<Duration>,
+        upload_max_entries: usize,
+    ) -> BoxFuture<'a, Result<PruneStats>> {
+        let conn = self.conn.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                let mut stats = PruneStats::default();
+                if let Some(max_age) = embed_max_age {
+                    stats.embeds = conn
+                        .execute(
+                            "DELETE FROM embed_history WHERE posted_at < datetime('now', ?1)",
+                            [age_modifier(max_age)],
+                        )
+                        .context("Failed to prune embed history")?;
+                }
+                if let Some(max_age) = summary_max_age {
+                    stats.summaries = conn
+                        .execute(
+                            "DELETE FROM summary_cache WHERE created_at < datetime('now', ?1)",
+                            [age_modifier(max_age)],
+                        )
+                        .context("Failed to prune summary cache")?;
+                }
+                stats.uploads = conn
+                    .execute(
+                        "DELETE FROM uploaded_media WHERE rowid NOT IN (
+                             SELECT rowid FROM uploaded_media
+                             ORDER BY last_used_at DESC, rowid DESC LIMIT ?1
+                         )",
+                        [upload_max_entries as i64],
+                    )
+                    .context("Failed to evict uploaded media")?;
+                Ok(stats)
+            })
+            .await
+            .context("prune task panicked")?
+        }
+        .boxed()
+    }
+
+    fn forget_unused_uploaded_media<'a>(
+        &'a self,
+        content_hash: &'a str,
+        encrypted: bool,
+        max_age: Duration,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let conn = self.conn.clone();
+        let content_hash = content_hash.to_owned();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.blocking_lock();
+                conn.execute(
+                    "DELETE FROM uploaded_media
+                     WHERE content_hash = ?1 AND encrypted = ?2
+                         AND last_used_at <= datetime('now', ?3)",
+                    rusqlite::params![content_hash, encrypted, age_modifier(max_age)],
+                )
+                .context("Failed to forget uploaded media")?;
+                let kept: bool = conn
+                    .query_row(
+                        "SELECT EXISTS (SELECT 1 FROM uploaded_media
+                                        WHERE content_hash = ?1 AND encrypted = ?2)",
+                        rusqlite::params![content_hash, encrypted],
+                        |row| row.get(0),
+                    )
+                    .context("Failed to query uploaded media")?;
+                Ok(!kept)
+            })
+            .await
+            .context("forget_unused_uploaded_media task panicked")?
+        }
+        .boxed()
+    }
+}
+
+/// A forward change to the database schema.
+struct Migration {
+    version: u32,
+    /// What the migration does, for logs and `migrate --dry-run`.
+    description: &'static str,
+    sql: &'static str,
+}
+
+/// All migrations, oldest first. Add a migration to the end of this list to
+/// change the schema; never edit or remove one that has been released, since
+/// existing databases have already run it.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create key_sharing_rooms",
+        sql: "CREATE TABLE IF NOT EXISTS key_sharing_rooms (
+                  room_id    TEXT PRIMARY KEY,
+                  enabled_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );",
+    },
+    Migration {
+        version: 2,
+        description: "create canned_responses/custom_commands/autoresponders",
+        sql: "CREATE TABLE IF NOT EXISTS canned_responses (
+                  id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                  text_markdown   TEXT,
+                  media_cas_hash  TEXT,
+                  media_mxc_uri   TEXT,
+                  media_filename  TEXT,
+                  media_mime_type TEXT
+              );
+              CREATE TABLE IF NOT EXISTS custom_commands (
+                  id           INTEGER PRIMARY KEY AUTOINCREMENT,
+                  room_id      TEXT NOT NULL,
+                  command_name TEXT NOT NULL,
+                  response_id  INTEGER NOT NULL REFERENCES canned_responses(id),
+                  UNIQUE(room_id, command_name)
+              );
+              CREATE TABLE IF NOT EXISTS autoresponders (
+                  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                  room_id     TEXT NOT NULL,
+                  pattern     TEXT NOT NULL,
+                  response_id INTEGER NOT NULL REFERENCES canned_responses(id),
+                  probability REAL NOT NULL DEFAULT 1.0,
+                  UNIQUE(room_id, pattern)
+              );",
+    },
+    Migration {
+        version: 3,
+        description: "create summary_rooms/summary_cache",
+        sql: "CREATE TABLE IF NOT EXISTS summary_rooms (
+                  room_id    TEXT PRIMARY KEY,
+                  enabled_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );
+              CREATE TABLE IF NOT EXISTS summary_cache (
+                  url        TEXT NOT NULL,
+                  model      TEXT NOT NULL,
+                  summary    TEXT NOT NULL,
+                  created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                  PRIMARY KEY (url, model)
+              );",
+    },
+    Migration {
+        version: 4,
+        description: "create embed_power_levels",
+        sql: "CREATE TABLE IF NOT EXISTS embed_power_levels (
+                  room_id         TEXT PRIMARY KEY,
+                  min_power_level INTEGER NOT NULL
+              );",
+    },
+    Migration {
+        version: 5,
+        description: "create embed_history",
+        sql: "CREATE TABLE IF NOT EXISTS embed_history (
+                  id        INTEGER PRIMARY KEY AUTOINCREMENT,
+                  room_id   TEXT NOT NULL,
+                  event_id  TEXT NOT NULL UNIQUE,
+                  posted_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );
+              CREATE INDEX IF NOT EXISTS embed_history_room ON embed_history (room_id, id);",
+    },
+    Migration {
+        version: 6,
+        description: "create url_rewrites",
+        sql: "CREATE TABLE IF NOT EXISTS url_rewrites (
+                  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                  pattern     TEXT NOT NULL,
+                  replacement TEXT NOT NULL,
+                  created_at  TEXT NOT NULL DEFAULT (datetime('now'))
+              );",
+    },
+    Migration {
+        version: 7,
+        description: "create room_profiles",
+        sql: "CREATE TABLE IF NOT EXISTS room_profiles (
+                  room_id      TEXT PRIMARY KEY,
+                  display_name TEXT,
+                  avatar_url   TEXT
+              );",
+    },
+    Migration {
+        version: 8,
+        description: "create uploaded_media",
+        sql: "CREATE TABLE IF NOT EXISTS uploaded_media (
+                  content_hash     TEXT NOT NULL,
+                  encrypted        INTEGER NOT NULL,
+                  source           TEXT NOT NULL,
+                  thumbnail_source TEXT,
+                  last_used_at     TEXT NOT NULL DEFAULT (datetime('now')),
+                  PRIMARY KEY (content_hash, encrypted)
+              );
+              CREATE INDEX IF NOT EXISTS uploaded_media_last_used
+                  ON uploaded_media (last_used_at);",
+    },
+    Migration {
+        version: 9,
+        description: "create data_saver_rooms",
+        sql: "CREATE TABLE IF NOT EXISTS data_saver_rooms (
+                  room_id    TEXT PRIMARY KEY,
+                  enabled_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );",
+    },
+    Migration {
+        version: 10,
+        description: "create room_video_formats",
+        sql: "CREATE TABLE IF NOT EXISTS room_video_formats (
+                  room_id TEXT PRIMARY KEY,
+                  format  TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 11,
+        description: "create bare_link_rooms",
+        sql: "CREATE TABLE IF NOT EXISTS bare_link_rooms (
+                  room_id    TEXT PRIMARY KEY,
+                  enabled_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );",
+    },
+    Migration {
+        version: 12,
+        description: "create room_time_formats",
+        sql: "CREATE TABLE IF NOT EXISTS room_time_formats (
+                  room_id    TEXT PRIMARY KEY,
+                  timezone   TEXT,
+                  time_style TEXT
+              );",
+    },
+    Migration {
+        version: 13,
+        description: "add embed sources to embed_history",
+        sql: "ALTER TABLE embed_history ADD COLUMN source_event_id TEXT;
+              ALTER TABLE embed_history ADD COLUMN url TEXT;
+              CREATE INDEX IF NOT EXISTS embed_history_source
+                  ON embed_history (source_event_id, url);",
+    },
+    Migration {
+        version: 14,
+        description: "create domain_stats",
+        sql: "CREATE TABLE IF NOT EXISTS domain_stats (
+                  domain           TEXT PRIMARY KEY,
+                  embedded         INTEGER NOT NULL DEFAULT 0,
+                  empty            INTEGER NOT NULL DEFAULT 0,
+                  failed           INTEGER NOT NULL DEFAULT 0,
+                  failure_streak   INTEGER NOT NULL DEFAULT 0,
+                  last_embedded_at TEXT,
+                  last_attempt_at  TEXT NOT NULL DEFAULT (datetime('now'))
+              );",
+    },
+    Migration {
+        version: 15,
+        description: "create room_embed_modes",
+        sql: "CREATE TABLE IF NOT EXISTS room_embed_modes (
+                  room_id TEXT PRIMARY KEY,
+                  mode    TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 16,
+        description: "create caption_link_rooms",
+        sql: "CREATE TABLE IF NOT EXISTS caption_link_rooms (
+                  room_id TEXT PRIMARY KEY
+              );",
+    },
+    Migration {
+        version: 17,
+        description: "create room_caption_layouts",
+        sql: "CREATE TABLE IF NOT EXISTS room_caption_layouts (
+                  room_id TEXT PRIMARY KEY,
+                  layout  TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 18,
+        description: "create pending_galleries",
+        sql: "CREATE TABLE IF NOT EXISTS pending_galleries (
+                  note_event_id TEXT PRIMARY KEY,
+                  room_id       TEXT NOT NULL,
+                  thread_root   TEXT NOT NULL,
+                  referer       TEXT NOT NULL,
+                  images        TEXT NOT NULL,
+                  created_at    TEXT NOT NULL DEFAULT (datetime('now'))
+              );
+              CREATE INDEX IF NOT EXISTS idx_pending_galleries_thread
+                  ON pending_galleries (room_id, thread_root);",
+    },
+    Migration {
+        version: 19,
+        description: "create trusted_users",
+        sql: "CREATE TABLE IF NOT EXISTS trusted_users (
+                  user_id    TEXT PRIMARY KEY,
+                  added_by   TEXT NOT NULL,
+                  created_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );",
+    },
+    Migration {
+        version: 20,
+        description: "create summary_thumbnail_rooms",
+        sql: "CREATE TABLE IF NOT EXISTS summary_thumbnail_rooms (
+                  room_id TEXT PRIMARY KEY
+              );",
+    },
+    Migration {
+        version: 21,
+        description: "create video_poster_rooms",
+        sql: "CREATE TABLE IF NOT EXISTS video_poster_rooms (
+                  room_id TEXT PRIMARY KEY
+              );",
+    },
+    Migration {
+        version: 22,
+        description: "create room_quiet_hours",
+        sql: "CREATE TABLE IF NOT EXISTS room_quiet_hours (
+                  room_id    TEXT PRIMARY KEY,
+                  start_time TEXT NOT NULL,
+                  end_time   TEXT NOT NULL,
+                  digest     INTEGER NOT NULL DEFAULT 0
+              );",
+    },
+    Migration {
+        version: 23,
+        description: "create queued_embeds",
+        sql: "CREATE TABLE IF NOT EXISTS queued_embeds (
+                  id        INTEGER PRIMARY KEY AUTOINCREMENT,
+                  room_id   TEXT NOT NULL,
+                  event_id  TEXT NOT NULL UNIQUE,
+                  url       TEXT NOT NULL,
+                  queued_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );
+              CREATE INDEX IF NOT EXISTS idx_queued_embeds_room
+                  ON queued_embeds (room_id);",
+    },
+    Migration {
+        version: 24,
+        description: "create room_digests",
+        sql: "CREATE TABLE IF NOT EXISTS room_digests (
+                  room_id          TEXT PRIMARY KEY,
+                  interval_minutes INTEGER
+              );",
+    },
+    Migration {
+        version: 25,
+        description: "create digest_entries",
+        sql: "CREATE TABLE IF NOT EXISTS digest_entries (
+                  id         INTEGER PRIMARY KEY AUTOINCREMENT,
+                  room_id    TEXT NOT NULL,
+                  event_id   TEXT NOT NULL,
+                  url        TEXT NOT NULL,
+                  title      TEXT,
+                  site_name  TEXT,
+                  thumbnail  TEXT,
+                  created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                  UNIQUE (room_id, url)
+              );
+              CREATE INDEX IF NOT EXISTS idx_digest_entries_event
+                  ON digest_entries (event_id);",
+    },
+    Migration {
+        version: 26,
+        description: "create link_verdicts",
+        sql: "CREATE TABLE IF NOT EXISTS link_verdicts (
+                  url        TEXT PRIMARY KEY,
+                  threat     TEXT,
+                  checked_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );",
+    },
+    Migration {
+        version: 27,
+        description: "create room_languages",
+        sql: "CREATE TABLE IF NOT EXISTS room_languages (
+                  room_id  TEXT PRIMARY KEY,
+                  language TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 28,
+        description: "create media_uploads",
+        sql: "CREATE TABLE IF NOT EXISTS media_uploads (
+                  mxc_uri      TEXT PRIMARY KEY,
+                  content_hash TEXT,
+                  encrypted    INTEGER NOT NULL,
+                  last_used_at TEXT NOT NULL DEFAULT (datetime('now'))
+              );
+              CREATE INDEX IF NOT EXISTS media_uploads_last_used
+                  ON media_uploads (last_used_at);",
+    },
+    Migration {
+        version: 29,
+        description: "create room_link_policies",
+        sql: "CREATE TABLE IF NOT EXISTS room_link_policies (
+                  room_id TEXT PRIMARY KEY,
+                  policy  TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 30,
+        description: "create info_rooms",
+        sql: "CREATE TABLE IF NOT EXISTS info_rooms (
+                  room_id TEXT PRIMARY KEY
+              );",
+    },
+];
+
+/// The schema version of the database behind `conn`, or 0 if it has none
+/// yet.
+fn schema_version(conn: &Connection) -> u32 {
+    conn.query_row(
+        "SELECT value FROM schema_meta WHERE key = 'version'",
+        [],
+        |row| row.get::<_, String>(0),
+    )
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(0)
+}
+
+/// The migrations a database at schema version `current` still needs, or an
+/// error if it's newer than this build: running an old build against it
+/// could misread or damage data written by the new one.
+fn pending_migrations(current: u32) -> Result<&'static [Migration]> {
+    if current > SCHEMA_VERSION {
+        bail!(
+            "Database is at schema version {}, but this build only supports up to {}; upgrade matrix-embed or restore a backup",
+            current,
+            SCHEMA_VERSION
+        );
+    }
+    let applied = MIGRATIONS.partition_point(|m| m.version <= current);
+    Ok(&MIGRATIONS[applied..])
 }
 
-/// Run all migrations up to [`SCHEMA_VERSION`].
+/// Run all migrations up to [`SCHEMA_VERSION`]. Each one runs in a
+/// transaction along with recording its version, so a failed migration
+/// leaves the database as it was before it.
 fn migrate(conn: &Connection) -> Result<()> {
     // Ensure the metadata table exists.
     conn.execute_batch(
@@ -104,17 +1015,9 @@ fn migrate(conn: &Connection) -> Result<()> {
     )
     .context("Failed to create schema_meta table")?;
 
-    let current: u32 = conn
-        .query_row(
-            "SELECT value FROM schema_meta WHERE key = 'version'",
-            [],
-            |row| row.get::<_, String>(0),
-        )
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0);
-
-    if current >= SCHEMA_VERSION {
+    let current = schema_version(conn);
+    let pending = pending_migrations(current)?;
+    if pending.is_empty() {
         debug!("Database schema is up-to-date (version {})", SCHEMA_VERSION);
         return Ok(());
     }
@@ -124,55 +1027,29 @@ fn migrate(conn: &Connection) -> Result<()> {
         current, SCHEMA_VERSION
     );
 
-    // Version 1
-    if current < 1 {
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS key_sharing_rooms (
-                 room_id    TEXT PRIMARY KEY,
-                 enabled_at TEXT NOT NULL DEFAULT (datetime('now'))
-             );",
-        )
-        .context("Migration v1: failed to create key_sharing_rooms")?;
-    }
-
-    // Version 2
-    if current < 2 {
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS canned_responses (
-                 id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                 text_markdown   TEXT,
-                 media_cas_hash  TEXT,
-                 media_mxc_uri   TEXT,
-                 media_filename  TEXT,
-                 media_mime_type TEXT
-             );
-             CREATE TABLE IF NOT EXISTS custom_commands (
-                 id           INTEGER PRIMARY KEY AUTOINCREMENT,
-                 room_id      TEXT NOT NULL,
-                 command_name TEXT NOT NULL,
-                 response_id  INTEGER NOT NULL REFERENCES canned_responses(id),
-                 UNIQUE(room_id, command_name)
-             );
-             CREATE TABLE IF NOT EXISTS autoresponders (
-                 id          INTEGER PRIMARY KEY AUTOINCREMENT,
-                 room_id     TEXT NOT NULL,
-                 pattern     TEXT NOT NULL,
-                 response_id INTEGER NOT NULL REFERENCES canned_responses(id),
-                 probability REAL NOT NULL DEFAULT 1.0,
-                 UNIQUE(room_id, pattern)
-             );",
+    for migration in pending {
+        debug!(
+            "Running migration v{}: {}",
+            migration.version, migration.description
+        );
+        let tx = conn
+            .unchecked_transaction()
+            .context("Failed to start migration transaction")?;
+        tx.execute_batch(migration.sql).with_context(|| {
+            format!(
+                "Migration v{}: failed to {}",
+                migration.version, migration.description
+            )
+        })?;
+        tx.execute(
+            "INSERT OR REPLACE INTO schema_meta (key, value) VALUES ('version', ?1)",
+            [migration.version.to_string()],
         )
-        .context(
-            "Migration v2: failed to create canned_responses/custom_commands/autoresponders",
-        )?;
+        .context("Failed to update schema version")?;
+        tx.commit()
+            .with_context(|| format!("Migration v{}: failed to commit", migration.version))?;
     }
 
-    conn.execute(
-        "INSERT OR REPLACE INTO schema_meta (key, value) VALUES ('version', ?1)",
-        [SCHEMA_VERSION.to_string()],
-    )
-    .context("Failed to update schema version")?;
-
     info!(
         "Database migration complete (now at version {})",
         SCHEMA_VERSION
@@ -180,6 +1057,64 @@ fn migrate(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+/// List the migrations the database at `path` still needs, without changing
+/// it. A database that doesn't exist yet needs all of them.
+pub fn plan_migrations(path: &Path) -> Result<Vec<(u32, &'static str)>> {
+    let current = if path.exists() {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open database at {}", path.display()))?;
+        schema_version(&conn)
+    } else {
+        0
+    };
+    Ok(pending_migrations(current)?
+        .iter()
+        .map(|m| (m.version, m.description))
+        .collect())
+}
+
+/// The URL rewrite rules managed with admin commands in the database at
+/// `path`, read without migrating or otherwise changing it. A database that
+/// doesn't exist yet has none.
+pub fn read_url_rewrites(path: &Path) -> Result<Vec<UrlRewriteRow>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open database at {}", path.display()))?;
+    let has_table: bool = conn
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'url_rewrites')",
+            [],
+            |row| row.get(0),
+        )
+        .context("Failed to look for url_rewrites table")?;
+    if !has_table {
+        return Ok(Vec::new());
+    }
+    query_url_rewrites(&conn)
+}
+
+fn query_url_rewrites(conn: &Connection) -> Result<Vec<UrlRewriteRow>> {
+    let mut stmt = conn
+        .prepare("SELECT id, pattern, replacement FROM url_rewrites ORDER BY id")
+        .context("Failed to prepare url_rewrites query")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(UrlRewriteRow {
+                id: row.get(0)?,
+                pattern: row.get(1)?,
+                replacement: row.get(2)?,
+            })
+        })
+        .context("Failed to query url_rewrites")?;
+    let mut rewrites = Vec::new();
+    for row in rows {
+        rewrites.push(row.context("Failed to read url_rewrites row")?);
+    }
+    Ok(rewrites)
+}
+
 impl Database {
     /// Mark a room as opted-in for automatic room key distribution.
     pub async fn enable_key_sharing(&self, room_id: &str) -> Result<()> {
@@ -256,371 +1191,3062 @@ impl Database {
     }
 }
 
-fn parse_canned_response(row: &rusqlite::Row) -> rusqlite::Result<CannedResponse> {
-    Ok(CannedResponse {
-        id: row.get(0)?,
-        text_markdown: row.get(1)?,
-        media_cas_hash: row.get(2)?,
-        media_mxc_uri: row.get(3)?,
-        media_filename: row.get(4)?,
-        media_mime_type: row.get(5)?,
-    })
-}
-
 impl Database {
-    pub async fn create_canned_response(
-        &self,
-        text_markdown: Option<&str>,
-        media_cas_hash: Option<&str>,
-        media_filename: Option<&str>,
-        media_mime_type: Option<&str>,
-    ) -> Result<i64> {
+    /// Opt a room in to LLM-generated page summaries.
+    pub async fn enable_summaries(&self, room_id: &str) -> Result<()> {
         let conn = self.conn.clone();
-        let text_markdown = text_markdown.map(|s| s.to_owned());
-        let media_cas_hash = media_cas_hash.map(|s| s.to_owned());
-        let media_filename = media_filename.map(|s| s.to_owned());
-        let media_mime_type = media_mime_type.map(|s| s.to_owned());
+        let room_id = room_id.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
             conn.execute(
-                "INSERT INTO canned_responses (text_markdown, media_cas_hash, media_filename, media_mime_type)
-                 VALUES (?1, ?2, ?3, ?4)",
-                rusqlite::params![text_markdown, media_cas_hash, media_filename, media_mime_type],
+                "INSERT OR IGNORE INTO summary_rooms (room_id) VALUES (?1)",
+                [&room_id],
             )
-            .context("Failed to insert canned response")?;
-            Ok(conn.last_insert_rowid())
+            .context("Failed to enable summaries for room")?;
+            Ok(())
         })
         .await
-        .context("create_canned_response task panicked")?
+        .context("enable_summaries task panicked")?
     }
 
-    pub async fn add_custom_command(
-        &self,
-        room_id: &str,
-        command_name: &str,
-        response_id: i64,
-    ) -> Result<()> {
+    /// Remove a room from the summary opt-in list.
+    pub async fn disable_summaries(&self, room_id: &str) -> Result<()> {
         let conn = self.conn.clone();
         let room_id = room_id.to_owned();
-        let command_name = command_name.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
-            let old_response_id: Option<i64> = conn
-                .query_row(
-                    "SELECT response_id FROM custom_commands WHERE room_id = ?1 AND command_name = ?2",
-                    rusqlite::params![&room_id, &command_name],
-                    |row| row.get(0),
-                )
-                .ok();
-            conn.execute(
-                "INSERT OR REPLACE INTO custom_commands (room_id, command_name, response_id) VALUES (?1, ?2, ?3)",
-                rusqlite::params![&room_id, &command_name, response_id],
-            )
-            .context("Failed to insert custom command")?;
-            if let Some(old_rid) = old_response_id {
-                let _ = conn.execute("DELETE FROM canned_responses WHERE id = ?1", [old_rid]);
-            }
+            conn.execute("DELETE FROM summary_rooms WHERE room_id = ?1", [&room_id])
+                .context("Failed to disable summaries for room")?;
             Ok(())
         })
         .await
-        .context("add_custom_command task panicked")?
+        .context("disable_summaries task panicked")?
     }
 
-    pub async fn remove_custom_command(&self, room_id: &str, command_name: &str) -> Result<bool> {
+    /// Check whether a room has opted in to page summaries.
+    pub async fn is_summaries_enabled(&self, room_id: &str) -> Result<bool> {
         let conn = self.conn.clone();
         let room_id = room_id.to_owned();
-        let command_name = command_name.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
-            let response_id: Option<i64> = conn
+            let exists: bool = conn
                 .query_row(
-                    "SELECT response_id FROM custom_commands WHERE room_id = ?1 AND command_name = ?2",
-                    rusqlite::params![&room_id, &command_name],
+                    "SELECT EXISTS(SELECT 1 FROM summary_rooms WHERE room_id = ?1)",
+                    [&room_id],
                     |row| row.get(0),
                 )
-                .ok();
-            if let Some(rid) = response_id {
-                conn.execute(
-                    "DELETE FROM custom_commands WHERE room_id = ?1 AND command_name = ?2",
-                    rusqlite::params![&room_id, &command_name],
-                )
-                .context("Failed to delete custom command")?;
-                conn.execute("DELETE FROM canned_responses WHERE id = ?1", [rid])
-                    .context("Failed to delete canned response")?;
-                Ok(true)
-            } else {
-                Ok(false)
-            }
+                .context("Failed to query summary status")?;
+            Ok(exists)
         })
         .await
-        .context("remove_custom_command task panicked")?
+        .context("is_summaries_enabled task panicked")?
     }
 
-    /// Look up a custom command, preferring a room-specific match over a global
-    /// one (room_id = `""`).
-    pub async fn get_custom_command(
-        &self,
-        room_id: &str,
-        command_name: &str,
-    ) -> Result<Option<CannedResponse>> {
+    /// Use the data saver encode profile for videos in a room.
+    pub async fn enable_data_saver(&self, room_id: &str) -> Result<()> {
         let conn = self.conn.clone();
         let room_id = room_id.to_owned();
-        let command_name = command_name.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
-            let result = conn.query_row(
-                "SELECT cr.id, cr.text_markdown, cr.media_cas_hash, cr.media_mxc_uri,
-                        cr.media_filename, cr.media_mime_type
-                 FROM custom_commands cc
-                 JOIN canned_responses cr ON cc.response_id = cr.id
-                 WHERE cc.command_name = ?2 AND (cc.room_id = ?1 OR cc.room_id = '')
-                 ORDER BY (cc.room_id = '') ASC
-                 LIMIT 1",
-                rusqlite::params![&room_id, &command_name],
-                parse_canned_response,
-            );
-            match result {
-                Ok(cr) => Ok(Some(cr)),
-                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-                Err(e) => Err(e).context("Failed to query custom command"),
-            }
+            conn.execute(
+                "INSERT OR IGNORE INTO data_saver_rooms (room_id) VALUES (?1)",
+                [&room_id],
+            )
+            .context("Failed to enable data saver for room")?;
+            Ok(())
         })
         .await
-        .context("get_custom_command task panicked")?
+        .context("enable_data_saver task panicked")?
     }
 
-    pub async fn list_custom_commands(&self, room_id: &str) -> Result<Vec<CustomCommandRow>> {
+    /// Go back to the standard encode profile in a room.
+    pub async fn disable_data_saver(&self, room_id: &str) -> Result<()> {
         let conn = self.conn.clone();
         let room_id = room_id.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
-            let mut stmt = conn
-                .prepare(
-                    "SELECT cc.command_name, cr.id, cr.text_markdown, cr.media_cas_hash,
-                        cr.media_mxc_uri, cr.media_filename, cr.media_mime_type
-                 FROM custom_commands cc
-                 JOIN canned_responses cr ON cc.response_id = cr.id
-                 WHERE cc.room_id = ?1
-                 ORDER BY cc.command_name",
-                )
-                .context("Failed to prepare custom_commands query")?;
-            let rows = stmt
-                .query_map([&room_id], |row| {
-                    Ok(CustomCommandRow {
-                        command_name: row.get(0)?,
-                        response: CannedResponse {
-                            id: row.get(1)?,
-                            text_markdown: row.get(2)?,
-                            media_cas_hash: row.get(3)?,
-                            media_mxc_uri: row.get(4)?,
-                            media_filename: row.get(5)?,
-                            media_mime_type: row.get(6)?,
-                        },
-                    })
-                })
-                .context("Failed to query custom_commands")?;
-            let mut result = Vec::new();
-            for row in rows {
-                result.push(row.context("Failed to read custom command row")?);
-            }
-            Ok(result)
+            conn.execute(
+                "DELETE FROM data_saver_rooms WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to disable data saver for room")?;
+            Ok(())
         })
         .await
-        .context("list_custom_commands task panicked")?
+        .context("disable_data_saver task panicked")?
     }
 
-    pub async fn add_autoresponder(
-        &self,
-        room_id: &str,
-        pattern: &str,
-        probability: f64,
-        response_id: i64,
-    ) -> Result<()> {
+    /// Check whether a room uses the data saver encode profile.
+    pub async fn is_data_saver_enabled(&self, room_id: &str) -> Result<bool> {
         let conn = self.conn.clone();
         let room_id = room_id.to_owned();
-        let pattern = pattern.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
-            let old_response_id: Option<i64> = conn
+            let exists: bool = conn
                 .query_row(
-                    "SELECT response_id FROM autoresponders WHERE room_id = ?1 AND pattern = ?2",
-                    rusqlite::params![&room_id, &pattern],
+                    "SELECT EXISTS(SELECT 1 FROM data_saver_rooms WHERE room_id = ?1)",
+                    [&room_id],
                     |row| row.get(0),
                 )
-                .ok();
+                .context("Failed to query data saver status")?;
+            Ok(exists)
+        })
+        .await
+        .context("is_data_saver_enabled task panicked")?
+    }
+
+    /// Also embed `www.` links without a scheme in a room.
+    pub async fn enable_bare_links(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
             conn.execute(
-                "INSERT OR REPLACE INTO autoresponders (room_id, pattern, probability, response_id)
-                 VALUES (?1, ?2, ?3, ?4)",
-                rusqlite::params![&room_id, &pattern, probability, response_id],
+                "INSERT OR IGNORE INTO bare_link_rooms (room_id) VALUES (?1)",
+                [&room_id],
             )
-            .context("Failed to insert autoresponder")?;
-            if let Some(old_rid) = old_response_id {
-                let _ = conn.execute("DELETE FROM canned_responses WHERE id = ?1", [old_rid]);
-            }
+            .context("Failed to enable bare links for room")?;
             Ok(())
         })
         .await
-        .context("add_autoresponder task panicked")?
+        .context("enable_bare_links task panicked")?
     }
 
-    pub async fn remove_autoresponder(&self, room_id: &str, pattern: &str) -> Result<bool> {
+    /// Stop embedding `www.` links without a scheme in a room, unless they're
+    /// enabled globally.
+    pub async fn disable_bare_links(&self, room_id: &str) -> Result<()> {
         let conn = self.conn.clone();
         let room_id = room_id.to_owned();
-        let pattern = pattern.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
-            let response_id: Option<i64> = conn
+            conn.execute("DELETE FROM bare_link_rooms WHERE room_id = ?1", [&room_id])
+                .context("Failed to disable bare links for room")?;
+            Ok(())
+        })
+        .await
+        .context("disable_bare_links task panicked")?
+    }
+
+    /// Check whether a room has `www.` links without a scheme enabled.
+    pub async fn is_bare_links_enabled(&self, room_id: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let exists: bool = conn
                 .query_row(
-                    "SELECT response_id FROM autoresponders WHERE room_id = ?1 AND pattern = ?2",
-                    rusqlite::params![&room_id, &pattern],
+                    "SELECT EXISTS(SELECT 1 FROM bare_link_rooms WHERE room_id = ?1)",
+                    [&room_id],
                     |row| row.get(0),
                 )
-                .ok();
-            if let Some(rid) = response_id {
-                conn.execute(
-                    "DELETE FROM autoresponders WHERE room_id = ?1 AND pattern = ?2",
-                    rusqlite::params![&room_id, &pattern],
-                )
-                .context("Failed to delete autoresponder")?;
-                conn.execute("DELETE FROM canned_responses WHERE id = ?1", [rid])
-                    .context("Failed to delete canned response")?;
-                Ok(true)
-            } else {
-                Ok(false)
-            }
+                .context("Failed to query bare links status")?;
+            Ok(exists)
         })
         .await
-        .context("remove_autoresponder task panicked")?
+        .context("is_bare_links_enabled task panicked")?
     }
 
-    /// Return autoresponders that apply to a room: room-specific entries first,
-    /// then global ones (room_id = `""`).
-    pub async fn get_autoresponders(&self, room_id: &str) -> Result<Vec<AutoresponderRow>> {
+    /// Also embed links in media captions and sticker descriptions in a
+    /// room.
+    pub async fn enable_caption_links(&self, room_id: &str) -> Result<()> {
         let conn = self.conn.clone();
         let room_id = room_id.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
-            let mut stmt = conn
-                .prepare(
-                    "SELECT a.pattern, a.probability, cr.id, cr.text_markdown, cr.media_cas_hash,
-                        cr.media_mxc_uri, cr.media_filename, cr.media_mime_type
-                 FROM autoresponders a
-                 JOIN canned_responses cr ON a.response_id = cr.id
-                 WHERE a.room_id = ?1 OR a.room_id = ''
-                 ORDER BY (a.room_id = '') ASC, a.id ASC",
-                )
-                .context("Failed to prepare autoresponders query")?;
-            let rows = stmt
-                .query_map([&room_id], |row| {
-                    Ok(AutoresponderRow {
-                        pattern: row.get(0)?,
-                        probability: row.get(1)?,
-                        response: CannedResponse {
-                            id: row.get(2)?,
-                            text_markdown: row.get(3)?,
-                            media_cas_hash: row.get(4)?,
-                            media_mxc_uri: row.get(5)?,
-                            media_filename: row.get(6)?,
-                            media_mime_type: row.get(7)?,
-                        },
-                    })
-                })
-                .context("Failed to query autoresponders")?;
-            let mut result = Vec::new();
-            for row in rows {
-                result.push(row.context("Failed to read autoresponder row")?);
-            }
-            Ok(result)
+            conn.execute(
+                "INSERT OR IGNORE INTO caption_link_rooms (room_id) VALUES (?1)",
+                [&room_id],
+            )
+            .context("Failed to enable caption links for room")?;
+            Ok(())
         })
         .await
-        .context("get_autoresponders task panicked")?
+        .context("enable_caption_links task panicked")?
     }
 
-    /// Return autoresponders for an exact room_id only.
-    /// Pass `""` to list only global autoresponders.
-    pub async fn list_autoresponders(&self, room_id: &str) -> Result<Vec<AutoresponderRow>> {
+    /// Stop embedding links in media captions and sticker descriptions in a
+    /// room, unless they're enabled globally.
+    pub async fn disable_caption_links(&self, room_id: &str) -> Result<()> {
         let conn = self.conn.clone();
         let room_id = room_id.to_owned();
         tokio::task::spawn_blocking(move || {
             let conn = conn.blocking_lock();
-            let mut stmt = conn
-                .prepare(
-                    "SELECT a.pattern, a.probability, cr.id, cr.text_markdown, cr.media_cas_hash,
-                            cr.media_mxc_uri, cr.media_filename, cr.media_mime_type
-                    FROM autoresponders a
-                    JOIN canned_responses cr ON a.response_id = cr.id
-                    WHERE a.room_id = ?1
-                    ORDER BY a.id",
-                )
-                .context("Failed to prepare autoresponders list query")?;
-            let rows = stmt
-                .query_map([&room_id], |row| {
-                    Ok(AutoresponderRow {
-                        pattern: row.get(0)?,
-                        probability: row.get(1)?,
-                        response: CannedResponse {
-                            id: row.get(2)?,
-                            text_markdown: row.get(3)?,
-                            media_cas_hash: row.get(4)?,
-                            media_mxc_uri: row.get(5)?,
-                            media_filename: row.get(6)?,
-                            media_mime_type: row.get(7)?,
-                        },
-                    })
-                })
-                .context("Failed to list autoresponders")?;
-            let mut result = Vec::new();
-            for row in rows {
-                result.push(row.context("Failed to read autoresponder row")?);
-            }
-            Ok(result)
+            conn.execute(
+                "DELETE FROM caption_link_rooms WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to disable caption links for room")?;
+            Ok(())
         })
         .await
-        .context("list_autoresponders task panicked")?
+        .context("disable_caption_links task panicked")?
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[tokio::test]
-    async fn test_open_in_memory() {
-        let db = Database::open_in_memory().await.unwrap();
-        // Should be able to query without error.
-        let rooms = db.list_key_sharing_rooms().await.unwrap();
-        assert!(rooms.is_empty());
+    /// Check whether a room has links in captions enabled.
+    pub async fn is_caption_links_enabled(&self, room_id: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let exists: bool = conn
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM caption_link_rooms WHERE room_id = ?1)",
+                    [&room_id],
+                    |row| row.get(0),
+                )
+                .context("Failed to query caption links status")?;
+            Ok(exists)
+        })
+        .await
+        .context("is_caption_links_enabled task panicked")?
     }
 
-    #[tokio::test]
-    async fn test_enable_disable_key_sharing() {
-        let db = Database::open_in_memory().await.unwrap();
-        let room = "!test:example.com";
-
-        assert!(!db.is_key_sharing_enabled(room).await.unwrap());
-
-        db.enable_key_sharing(room).await.unwrap();
-        assert!(db.is_key_sharing_enabled(room).await.unwrap());
-
-        // Enabling again should be idempotent.
-        db.enable_key_sharing(room).await.unwrap();
-        assert!(db.is_key_sharing_enabled(room).await.unwrap());
-
-        db.disable_key_sharing(room).await.unwrap();
-        assert!(!db.is_key_sharing_enabled(room).await.unwrap());
+    /// Post the image of summary cards as a small thumbnail in a room.
+    pub async fn enable_summary_thumbnails(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR IGNORE INTO summary_thumbnail_rooms (room_id) VALUES (?1)",
+                [&room_id],
+            )
+            .context("Failed to enable summary thumbnails for room")?;
+            Ok(())
+        })
+        .await
+        .context("enable_summary_thumbnails task panicked")?
+    }
 
-        // Disabling again should be idempotent.
-        db.disable_key_sharing(room).await.unwrap();
-        assert!(!db.is_key_sharing_enabled(room).await.unwrap());
+    /// Keep summary cards text-only in a room, unless thumbnails are enabled
+    /// globally.
+    pub async fn disable_summary_thumbnails(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM summary_thumbnail_rooms WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to disable summary thumbnails for room")?;
+            Ok(())
+        })
+        .await
+        .context("disable_summary_thumbnails task panicked")?
     }
 
-    #[tokio::test]
-    async fn test_list_key_sharing_rooms() {
-        let db = Database::open_in_memory().await.unwrap();
+    /// Check whether a room has summary thumbnails enabled.
+    pub async fn is_summary_thumbnails_enabled(&self, room_id: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let exists: bool = conn
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM summary_thumbnail_rooms WHERE room_id = ?1)",
+                    [&room_id],
+                    |row| row.get(0),
+                )
+                .context("Failed to query summary thumbnails status")?;
+            Ok(exists)
+        })
+        .await
+        .context("is_summary_thumbnails_enabled task panicked")?
+    }
 
-        db.enable_key_sharing("!b:example.com").await.unwrap();
-        db.enable_key_sharing("!a:example.com").await.unwrap();
-        db.enable_key_sharing("!c:example.com").await.unwrap();
+    /// Also post the poster image of embedded videos in a room.
+    pub async fn enable_video_posters(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR IGNORE INTO video_poster_rooms (room_id) VALUES (?1)",
+                [&room_id],
+            )
+            .context("Failed to enable video posters for room")?;
+            Ok(())
+        })
+        .await
+        .context("enable_video_posters task panicked")?
+    }
+
+    /// Post only the video of embeds in a room, unless posters are enabled
+    /// globally.
+    pub async fn disable_video_posters(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM video_poster_rooms WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to disable video posters for room")?;
+            Ok(())
+        })
+        .await
+        .context("disable_video_posters task panicked")?
+    }
+
+    /// Check whether a room has video posters enabled.
+    pub async fn is_video_posters_enabled(&self, room_id: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let exists: bool = conn
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM video_poster_rooms WHERE room_id = ?1)",
+                    [&room_id],
+                    |row| row.get(0),
+                )
+                .context("Failed to query video posters status")?;
+            Ok(exists)
+        })
+        .await
+        .context("is_video_posters_enabled task panicked")?
+    }
+
+    /// Convert videos in a room to `format`, overriding the global setting.
+    pub async fn set_video_format(&self, room_id: &str, format: VideoFormat) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO room_video_formats (room_id, format) VALUES (?1, ?2)",
+                rusqlite::params![room_id, format.name()],
+            )
+            .context("Failed to set video format for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_video_format task panicked")?
+    }
+
+    /// Remove a room's video format override.
+    pub async fn clear_video_format(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM room_video_formats WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to clear video format for room")?;
+            Ok(())
+        })
+        .await
+        .context("clear_video_format task panicked")?
+    }
+
+    /// Return a room's video format override, if any.
+    pub async fn get_video_format(&self, room_id: &str) -> Result<Option<VideoFormat>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT format FROM room_video_formats WHERE room_id = ?1",
+                [&room_id],
+                |row| row.get::<_, String>(0),
+            );
+            match result {
+                Ok(format) => Ok(VideoFormat::from_name(&format)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query video format"),
+            }
+        })
+        .await
+        .context("get_video_format task panicked")?
+    }
+
+    /// Ask for pages linked in a room in `language`, a normalized BCP 47
+    /// tag, overriding the global setting.
+    pub async fn set_room_language(&self, room_id: &str, language: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let language = language.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO room_languages (room_id, language) VALUES (?1, ?2)",
+                rusqlite::params![room_id, language],
+            )
+            .context("Failed to set language for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_room_language task panicked")?
+    }
+
+    /// Remove a room's language override.
+    pub async fn clear_room_language(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute("DELETE FROM room_languages WHERE room_id = ?1", [&room_id])
+                .context("Failed to clear language for room")?;
+            Ok(())
+        })
+        .await
+        .context("clear_room_language task panicked")?
+    }
+
+    /// Return a room's language override, if any.
+    pub async fn get_room_language(&self, room_id: &str) -> Result<Option<String>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT language FROM room_languages WHERE room_id = ?1",
+                [&room_id],
+                |row| row.get::<_, String>(0),
+            );
+            match result {
+                Ok(language) => Ok(Some(language)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query room language"),
+            }
+        })
+        .await
+        .context("get_room_language task panicked")?
+    }
+
+    /// Set which rooms get embeds for a room, overriding the global setting.
+    pub async fn set_embed_mode(&self, room_id: &str, mode: EmbedMode) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO room_embed_modes (room_id, mode) VALUES (?1, ?2)",
+                rusqlite::params![room_id, mode.name()],
+            )
+            .context("Failed to set embed mode for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_embed_mode task panicked")?
+    }
+
+    /// Remove a room's embed mode override.
+    pub async fn clear_embed_mode(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM room_embed_modes WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to clear embed mode for room")?;
+            Ok(())
+        })
+        .await
+        .context("clear_embed_mode task panicked")?
+    }
+
+    /// Return a room's embed mode override, if any.
+    pub async fn get_embed_mode(&self, room_id: &str) -> Result<Option<EmbedMode>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT mode FROM room_embed_modes WHERE room_id = ?1",
+                [&room_id],
+                |row| row.get::<_, String>(0),
+            );
+            match result {
+                Ok(mode) => Ok(EmbedMode::from_name(&mode)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query embed mode"),
+            }
+        })
+        .await
+        .context("get_embed_mode task panicked")?
+    }
+
+    /// Set how embeds with both text and media are laid out in a room,
+    /// overriding the global setting.
+    pub async fn set_caption_layout(&self, room_id: &str, layout: CaptionLayout) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO room_caption_layouts (room_id, layout) VALUES (?1, ?2)",
+                rusqlite::params![room_id, layout.name()],
+            )
+            .context("Failed to set caption layout for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_caption_layout task panicked")?
+    }
+
+    /// Remove a room's caption layout override.
+    pub async fn clear_caption_layout(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM room_caption_layouts WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to clear caption layout for room")?;
+            Ok(())
+        })
+        .await
+        .context("clear_caption_layout task panicked")?
+    }
+
+    /// Return a room's caption layout override, if any.
+    pub async fn get_caption_layout(&self, room_id: &str) -> Result<Option<CaptionLayout>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT layout FROM room_caption_layouts WHERE room_id = ?1",
+                [&room_id],
+                |row| row.get::<_, String>(0),
+            );
+            match result {
+                Ok(layout) => Ok(CaptionLayout::from_name(&layout)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query caption layout"),
+            }
+        })
+        .await
+        .context("get_caption_layout task panicked")?
+    }
+
+    /// Set which links of a message with several are embedded in a room,
+    /// overriding the global setting.
+    pub async fn set_link_policy(&self, room_id: &str, policy: LinkPolicy) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO room_link_policies (room_id, policy) VALUES (?1, ?2)",
+                rusqlite::params![room_id, policy.name()],
+            )
+            .context("Failed to set link policy for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_link_policy task panicked")?
+    }
+
+    /// Remove a room's link policy override.
+    pub async fn clear_link_policy(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM room_link_policies WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to clear link policy for room")?;
+            Ok(())
+        })
+        .await
+        .context("clear_link_policy task panicked")?
+    }
+
+    /// Return a room's link policy override, if any.
+    pub async fn get_link_policy(&self, room_id: &str) -> Result<Option<LinkPolicy>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT policy FROM room_link_policies WHERE room_id = ?1",
+                [&room_id],
+                |row| row.get::<_, String>(0),
+            );
+            match result {
+                Ok(policy) => Ok(LinkPolicy::from_name(&policy)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query link policy"),
+            }
+        })
+        .await
+        .context("get_link_policy task panicked")?
+    }
+
+    /// Describe the bot in a room, besides the configured ones.
+    pub async fn enable_publish_info(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR IGNORE INTO info_rooms (room_id) VALUES (?1)",
+                [&room_id],
+            )
+            .context("Failed to enable bot info for room")?;
+            Ok(())
+        })
+        .await
+        .context("enable_publish_info task panicked")?
+    }
+
+    /// Stop describing the bot in a room, unless it's configured to.
+    pub async fn disable_publish_info(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute("DELETE FROM info_rooms WHERE room_id = ?1", [&room_id])
+                .context("Failed to disable bot info for room")?;
+            Ok(())
+        })
+        .await
+        .context("disable_publish_info task panicked")?
+    }
+
+    /// Check whether a room has opted in to the bot describing itself.
+    pub async fn is_publish_info_enabled(&self, room_id: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let exists: bool = conn
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM info_rooms WHERE room_id = ?1)",
+                    [&room_id],
+                    |row| row.get(0),
+                )
+                .context("Failed to query bot info status")?;
+            Ok(exists)
+        })
+        .await
+        .context("is_publish_info_enabled task panicked")?
+    }
+
+    /// Remember the images of a gallery that weren't posted in `room_id`,
+    /// until they're asked for.
+    pub async fn store_pending_gallery(
+        &self,
+        room_id: &str,
+        gallery: &PendingGallery,
+    ) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let gallery = gallery.clone();
+        let images: Vec<(&str, Option<&str>)> = gallery
+            .images
+            .iter()
+            .map(|image| (image.url.as_str(), image.alt.as_deref()))
+            .collect();
+        let images = serde_json::to_string(&images).context("Failed to serialize gallery")?;
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO pending_galleries
+                     (note_event_id, room_id, thread_root, referer, images)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![
+                    gallery.note_event_id,
+                    room_id,
+                    gallery.thread_root,
+                    gallery.referer,
+                    images
+                ],
+            )
+            .context("Failed to store pending gallery")?;
+            Ok(())
+        })
+        .await
+        .context("store_pending_gallery task panicked")?
+    }
+
+    /// Return and forget the pending gallery in `room_id` whose note is
+    /// `event_id`, or that was posted in the thread rooted at `event_id`.
+    /// Taking it means it's only ever posted once.
+    pub async fn take_pending_gallery(
+        &self,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<Option<PendingGallery>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let event_id = event_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT note_event_id, thread_root, referer, images FROM pending_galleries
+                 WHERE room_id = ?1 AND (note_event_id = ?2 OR thread_root = ?2)
+                   AND created_at >= datetime('now', ?3)
+                 ORDER BY created_at DESC LIMIT 1",
+                rusqlite::params![room_id, event_id, age_modifier(PENDING_GALLERY_MAX_AGE)],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                },
+            );
+            let (note_event_id, thread_root, referer, images) = match result {
+                Ok(row) => row,
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+                Err(e) => return Err(e).context("Failed to query pending gallery"),
+            };
+            conn.execute(
+                "DELETE FROM pending_galleries WHERE note_event_id = ?1",
+                [&note_event_id],
+            )
+            .context("Failed to forget pending gallery")?;
+            let images: Vec<(String, Option<String>)> =
+                serde_json::from_str(&images).context("Failed to parse pending gallery")?;
+            let images = images
+                .into_iter()
+                .filter_map(|(url, alt)| {
+                    Some(GalleryImage {
+                        url: url::Url::parse(&url).ok()?,
+                        alt,
+                    })
+                })
+                .collect();
+            Ok(Some(PendingGallery {
+                note_event_id,
+                thread_root,
+                referer,
+                images,
+            }))
+        })
+        .await
+        .context("take_pending_gallery task panicked")?
+    }
+
+    /// Queue links posted in a room during `hours` every day, overriding
+    /// any earlier quiet hours.
+    pub async fn set_quiet_hours(&self, room_id: &str, hours: &QuietHours) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let start = hours.start.format("%H:%M").to_string();
+        let end = hours.end.format("%H:%M").to_string();
+        let digest = hours.digest;
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO room_quiet_hours (room_id, start_time, end_time, digest)
+                 VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![room_id, start, end, digest],
+            )
+            .context("Failed to set quiet hours for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_quiet_hours task panicked")?
+    }
+
+    /// Remove a room's quiet hours. Links already queued are posted on the
+    /// next check.
+    pub async fn clear_quiet_hours(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM room_quiet_hours WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to clear quiet hours for room")?;
+            Ok(())
+        })
+        .await
+        .context("clear_quiet_hours task panicked")?
+    }
+
+    /// Return a room's quiet hours, if it has any.
+    pub async fn get_quiet_hours(&self, room_id: &str) -> Result<Option<QuietHours>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT start_time, end_time, digest FROM room_quiet_hours WHERE room_id = ?1",
+                [&room_id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, bool>(2)?,
+                    ))
+                },
+            );
+            match result {
+                Ok((start, end, digest)) => Ok(quiet::parse_time(&start)
+                    .zip(quiet::parse_time(&end))
+                    .map(|(start, end)| QuietHours { start, end, digest })),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query quiet hours"),
+            }
+        })
+        .await
+        .context("get_quiet_hours task panicked")?
+    }
+
+    /// Queue the link in `event_id` until the quiet hours in `room_id` end,
+    /// replacing the one queued before if the message was edited.
+    pub async fn queue_embed(&self, room_id: &str, event_id: &str, url: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let event_id = event_id.to_owned();
+        let url = url.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO queued_embeds (room_id, event_id, url) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(event_id) DO UPDATE SET url = excluded.url",
+                rusqlite::params![room_id, event_id, url],
+            )
+            .context("Failed to queue embed")?;
+            Ok(())
+        })
+        .await
+        .context("queue_embed task panicked")?
+    }
+
+    /// Forget the queued link in `event_id`, if there is one, like when the
+    /// message is redacted.
+    pub async fn forget_queued_embed(&self, event_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let event_id = event_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute("DELETE FROM queued_embeds WHERE event_id = ?1", [&event_id])
+                .context("Failed to forget queued embed")?;
+            Ok(())
+        })
+        .await
+        .context("forget_queued_embed task panicked")?
+    }
+
+    /// The rooms with links queued.
+    pub async fn queued_embed_rooms(&self) -> Result<Vec<String>> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stmt = conn
+                .prepare("SELECT DISTINCT room_id FROM queued_embeds ORDER BY room_id")
+                .context("Failed to prepare queued rooms query")?;
+            let rooms = stmt
+                .query_map([], |row| row.get(0))
+                .context("Failed to query queued rooms")?
+                .collect::<rusqlite::Result<Vec<String>>>()
+                .context("Failed to read queued rooms")?;
+            Ok(rooms)
+        })
+        .await
+        .context("queued_embed_rooms task panicked")?
+    }
+
+    /// Return and forget the links queued in `room_id`, in the order they
+    /// were posted. Taking them means they're only ever posted once.
+    pub async fn take_queued_embeds(&self, room_id: &str) -> Result<Vec<QueuedEmbed>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.blocking_lock();
+            let tx = conn.transaction().context("Failed to start transaction")?;
+            let queued = {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT event_id, url FROM queued_embeds WHERE room_id = ?1 ORDER BY id",
+                    )
+                    .context("Failed to prepare queued embeds query")?;
+                stmt.query_map([&room_id], |row| {
+                    Ok(QueuedEmbed {
+                        event_id: row.get(0)?,
+                        url: row.get(1)?,
+                    })
+                })
+                .context("Failed to query queued embeds")?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context("Failed to read queued embeds")?
+            };
+            tx.execute("DELETE FROM queued_embeds WHERE room_id = ?1", [&room_id])
+                .context("Failed to forget queued embeds")?;
+            tx.commit().context("Failed to commit transaction")?;
+            Ok(queued)
+        })
+        .await
+        .context("take_queued_embeds task panicked")?
+    }
+
+    /// Collect links in a room for a digest every `interval`, or the global
+    /// interval if it's `None`, rather than embedding them one by one.
+    pub async fn enable_digest(&self, room_id: &str, interval: Option<Duration>) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let minutes = interval.map(|interval| (interval.as_secs() / 60) as i64);
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO room_digests (room_id, interval_minutes) VALUES (?1, ?2)",
+                rusqlite::params![room_id, minutes],
+            )
+            .context("Failed to enable digest for room")?;
+            Ok(())
+        })
+        .await
+        .context("enable_digest task panicked")?
+    }
+
+    /// Embed links in a room one by one again. Links already collected are
+    /// posted in one last digest on the next check.
+    pub async fn disable_digest(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute("DELETE FROM room_digests WHERE room_id = ?1", [&room_id])
+                .context("Failed to disable digest for room")?;
+            Ok(())
+        })
+        .await
+        .context("disable_digest task panicked")?
+    }
+
+    /// Return a room's digest mode, if it's enabled.
+    pub async fn get_digest(&self, room_id: &str) -> Result<Option<RoomDigest>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT interval_minutes FROM room_digests WHERE room_id = ?1",
+                [&room_id],
+                |row| row.get::<_, Option<i64>>(0),
+            );
+            match result {
+                Ok(minutes) => Ok(Some(RoomDigest {
+                    interval: minutes.map(|minutes| Duration::from_secs(minutes as u64 * 60)),
+                })),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query digest mode"),
+            }
+        })
+        .await
+        .context("get_digest task panicked")?
+    }
+
+    /// Collect `entry` for the next digest in `room_id`. A link that's
+    /// already waiting is only listed once.
+    pub async fn add_digest_entry(&self, room_id: &str, entry: &DigestEntry) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let entry = entry.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR IGNORE INTO digest_entries
+                     (room_id, event_id, url, title, site_name, thumbnail)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![
+                    room_id,
+                    entry.event_id,
+                    entry.url.as_str(),
+                    entry.title,
+                    entry.site_name,
+                    entry.thumbnail
+                ],
+            )
+            .context("Failed to add digest entry")?;
+            Ok(())
+        })
+        .await
+        .context("add_digest_entry task panicked")?
+    }
+
+    /// Forget the links collected from `event_id` for a digest, like when
+    /// the message is redacted.
+    pub async fn forget_digest_entries(&self, event_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let event_id = event_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM digest_entries WHERE event_id = ?1",
+                [&event_id],
+            )
+            .context("Failed to forget digest entries")?;
+            Ok(())
+        })
+        .await
+        .context("forget_digest_entries task panicked")?
+    }
+
+    /// The rooms whose digest is due: the first link in it was collected
+    /// at least the room's interval (or `default_interval`) ago. Rooms that
+    /// left digest mode with links still collected are always due.
+    pub async fn due_digest_rooms(&self, default_interval: Duration) -> Result<Vec<String>> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT e.room_id FROM digest_entries e
+                     LEFT JOIN room_digests d ON d.room_id = e.room_id
+                     GROUP BY e.room_id
+                     HAVING MAX(d.room_id) IS NULL
+                         OR MIN(e.created_at) <= datetime('now',
+                             '-' || COALESCE(MAX(d.interval_minutes) * 60, ?1) || ' seconds')
+                     ORDER BY e.room_id",
+                )
+                .context("Failed to prepare due digests query")?;
+            let rooms = stmt
+                .query_map([default_interval.as_secs() as i64], |row| row.get(0))
+                .context("Failed to query due digests")?
+                .collect::<rusqlite::Result<Vec<String>>>()
+                .context("Failed to read due digests")?;
+            Ok(rooms)
+        })
+        .await
+        .context("due_digest_rooms task panicked")?
+    }
+
+    /// Return and forget the links collected for the digest in `room_id`,
+    /// in the order they were posted.
+    pub async fn take_digest_entries(&self, room_id: &str) -> Result<Vec<DigestEntry>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.blocking_lock();
+            let tx = conn.transaction().context("Failed to start transaction")?;
+            let rows = {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT event_id, url, title, site_name, thumbnail FROM digest_entries
+                         WHERE room_id = ?1 ORDER BY id",
+                    )
+                    .context("Failed to prepare digest entries query")?;
+                stmt.query_map([&room_id], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, Option<String>>(3)?,
+                        row.get::<_, Option<String>>(4)?,
+                    ))
+                })
+                .context("Failed to query digest entries")?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context("Failed to read digest entries")?
+            };
+            tx.execute("DELETE FROM digest_entries WHERE room_id = ?1", [&room_id])
+                .context("Failed to forget digest entries")?;
+            tx.commit().context("Failed to commit transaction")?;
+            let entries = rows
+                .into_iter()
+                .filter_map(|(event_id, url, title, site_name, thumbnail)| {
+                    Some(DigestEntry {
+                        url: url::Url::parse(&url).ok()?,
+                        title,
+                        site_name,
+                        event_id: Some(event_id),
+                        thumbnail,
+                    })
+                })
+                .collect();
+            Ok(entries)
+        })
+        .await
+        .context("take_digest_entries task panicked")?
+    }
+
+    /// Return the cached Safe Browsing verdict on `url`, unless it's too old
+    /// to go by.
+    pub async fn get_link_verdict(&self, url: &str) -> Result<Option<LinkVerdict>> {
+        let conn = self.conn.clone();
+        let url = url.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT threat FROM link_verdicts
+                 WHERE url = ?1 AND checked_at >= datetime('now', ?2)",
+                [&url, &age_modifier(LINK_VERDICT_MAX_AGE)],
+                |row| row.get::<_, Option<String>>(0),
+            );
+            match result {
+                Ok(threat) => Ok(Some(LinkVerdict {
+                    threat: threat.as_deref().and_then(Threat::from_name),
+                })),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query link verdict"),
+            }
+        })
+        .await
+        .context("get_link_verdict task panicked")?
+    }
+
+    /// Cache the Safe Browsing verdict on `url`: what it was flagged for, if
+    /// anything.
+    pub async fn store_link_verdict(&self, url: &str, threat: Option<Threat>) -> Result<()> {
+        let conn = self.conn.clone();
+        let url = url.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO link_verdicts (url, threat) VALUES (?1, ?2)",
+                rusqlite::params![url, threat.map(Threat::name)],
+            )
+            .context("Failed to store link verdict")?;
+            Ok(())
+        })
+        .await
+        .context("store_link_verdict task panicked")?
+    }
+
+    /// Render times in a room in `timezone` (an IANA name), overriding the
+    /// global setting.
+    pub async fn set_room_timezone(&self, room_id: &str, timezone: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let timezone = timezone.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO room_time_formats (room_id, timezone) VALUES (?1, ?2)
+                 ON CONFLICT(room_id) DO UPDATE SET timezone = excluded.timezone",
+                rusqlite::params![room_id, timezone],
+            )
+            .context("Failed to set timezone for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_room_timezone task panicked")?
+    }
+
+    /// Write times in a room in `style`, overriding the global setting.
+    pub async fn set_room_time_style(&self, room_id: &str, style: TimeStyle) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO room_time_formats (room_id, time_style) VALUES (?1, ?2)
+                 ON CONFLICT(room_id) DO UPDATE SET time_style = excluded.time_style",
+                rusqlite::params![room_id, style.name()],
+            )
+            .context("Failed to set time style for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_room_time_style task panicked")?
+    }
+
+    /// Remove a room's timezone and time style overrides.
+    pub async fn clear_room_time_format(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM room_time_formats WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to clear time format for room")?;
+            Ok(())
+        })
+        .await
+        .context("clear_room_time_format task panicked")?
+    }
+
+    /// Return a room's timezone name and time style overrides, if any.
+    pub async fn get_room_time_format(
+        &self,
+        room_id: &str,
+    ) -> Result<(Option<String>, Option<TimeStyle>)> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT timezone, time_style FROM room_time_formats WHERE room_id = ?1",
+                [&room_id],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<String>>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                    ))
+                },
+            );
+            match result {
+                Ok((timezone, style)) => {
+                    Ok((timezone, style.as_deref().and_then(TimeStyle::from_name)))
+                }
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok((None, None)),
+                Err(e) => Err(e).context("Failed to query time format"),
+            }
+        })
+        .await
+        .context("get_room_time_format task panicked")?
+    }
+
+    /// Look up the metadata fetched from `url` in `language`, if it hasn't
+    /// expired.
+    pub async fn get_cached_metadata(
+        &self,
+        url: &Url,
+        language: Option<&str>,
+    ) -> Result<Option<Metadata>> {
+        self.shared.get_cached_metadata(url, language).await
+    }
+
+    /// Keep `meta`, fetched from `url` in `language`, for `ttl`.
+    pub async fn store_metadata(
+        &self,
+        url: &Url,
+        language: Option<&str>,
+        meta: &Metadata,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.shared.store_metadata(url, language, meta, ttl).await
+    }
+
+    /// Look up a previously generated summary for `url` by `model`.
+    pub async fn get_cached_summary(&self, url: &str, model: &str) -> Result<Option<String>> {
+        self.shared.get_cached_summary(url, model).await
+    }
+
+    /// Cache a generated summary for `url` by `model`.
+    pub async fn store_summary(&self, url: &str, model: &str, summary: &str) -> Result<()> {
+        self.shared.store_summary(url, model, summary).await
+    }
+}
+
+impl Database {
+    /// Require senders in a room to have at least `level` for their links to
+    /// be embedded, overriding the global setting.
+    pub async fn set_embed_min_power_level(&self, room_id: &str, level: i64) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT OR REPLACE INTO embed_power_levels (room_id, min_power_level)
+                 VALUES (?1, ?2)",
+                rusqlite::params![room_id, level],
+            )
+            .context("Failed to set embed power level for room")?;
+            Ok(())
+        })
+        .await
+        .context("set_embed_min_power_level task panicked")?
+    }
+
+    /// Remove a room's embed power level override.
+    pub async fn clear_embed_min_power_level(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "DELETE FROM embed_power_levels WHERE room_id = ?1",
+                [&room_id],
+            )
+            .context("Failed to clear embed power level for room")?;
+            Ok(())
+        })
+        .await
+        .context("clear_embed_min_power_level task panicked")?
+    }
+
+    /// Return a room's embed power level override, if any.
+    pub async fn get_embed_min_power_level(&self, room_id: &str) -> Result<Option<i64>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT min_power_level FROM embed_power_levels WHERE room_id = ?1",
+                [&room_id],
+                |row| row.get(0),
+            );
+            match result {
+                Ok(level) => Ok(Some(level)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query embed power level"),
+            }
+        })
+        .await
+        .context("get_embed_min_power_level task panicked")?
+    }
+}
+
+impl Database {
+    /// Remember an embed the bot posted of `url` from message
+    /// `source_event_id`, so it can be cleaned up later and isn't posted
+    /// again when the message is seen again.
+    pub async fn record_embed(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        source_event_id: &str,
+        url: &str,
+    ) -> Result<()> {
+        self.shared
+            .record_embed(room_id, event_id, source_event_id, url)
+            .await
+    }
+
+    /// Count an attempt to embed a link to `domain`.
+    pub async fn record_domain_outcome(&self, domain: &str, outcome: DomainOutcome) -> Result<()> {
+        let conn = self.conn.clone();
+        let domain = domain.to_owned();
+        let (embedded, empty, failed) = match outcome {
+            DomainOutcome::Embedded => (1, 0, 0),
+            DomainOutcome::Empty => (0, 1, 0),
+            DomainOutcome::Failed => (0, 0, 1),
+        };
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO domain_stats
+                     (domain, embedded, empty, failed, failure_streak, last_embedded_at)
+                 VALUES (?1, ?2, ?3, ?4, 1 - ?2, CASE WHEN ?2 > 0 THEN datetime('now') END)
+                 ON CONFLICT(domain) DO UPDATE SET
+                     embedded = embedded + excluded.embedded,
+                     empty = empty + excluded.empty,
+                     failed = failed + excluded.failed,
+                     failure_streak = CASE WHEN excluded.embedded > 0 THEN 0
+                                           ELSE failure_streak + 1 END,
+                     last_embedded_at = COALESCE(excluded.last_embedded_at, last_embedded_at),
+                     last_attempt_at = datetime('now')",
+                rusqlite::params![domain, embedded, empty, failed],
+            )
+            .context("Failed to record domain outcome")?;
+            Ok(())
+        })
+        .await
+        .context("record_domain_outcome task panicked")?
+    }
+
+    /// Return statistics for up to `limit` domains: failing ones first, then
+    /// the busiest.
+    pub async fn domain_stats(&self, limit: usize) -> Result<Vec<DomainStats>> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT domain, embedded, empty, failed, failure_streak, last_embedded_at
+                     FROM domain_stats
+                     ORDER BY failure_streak >= ?1 DESC, embedded + empty + failed DESC, domain
+                     LIMIT ?2",
+                )
+                .context("Failed to prepare domain stats query")?;
+            let rows = stmt
+                .query_map(
+                    rusqlite::params![DomainStats::FAILING_STREAK as i64, limit as i64],
+                    |row| {
+                        Ok(DomainStats {
+                            domain: row.get(0)?,
+                            embedded: row.get::<_, i64>(1)? as u64,
+                            empty: row.get::<_, i64>(2)? as u64,
+                            failed: row.get::<_, i64>(3)? as u64,
+                            failure_streak: row.get::<_, i64>(4)? as u64,
+                            last_embedded_at: row.get(5)?,
+                        })
+                    },
+                )
+                .context("Failed to query domain stats")?;
+            let mut stats = Vec::new();
+            for row in rows {
+                stats.push(row.context("Failed to read domain stats row")?);
+            }
+            Ok(stats)
+        })
+        .await
+        .context("domain_stats task panicked")?
+    }
+
+    /// Return the event IDs of the bot's `limit` most recent embeds in a room,
+    /// newest first.
+    pub async fn recent_embeds(&self, room_id: &str, limit: usize) -> Result<Vec<String>> {
+        self.shared.recent_embeds(room_id, limit).await
+    }
+
+    /// Return the embed already posted of `url` from message
+    /// `source_event_id`, if there is one.
+    pub async fn find_embed(&self, source_event_id: &str, url: &str) -> Result<Option<String>> {
+        self.shared.find_embed(source_event_id, url).await
+    }
+
+    /// Return the bot's most recent embed of `url` in a room, if there is
+    /// one.
+    pub async fn latest_embed(&self, room_id: &str, url: &str) -> Result<Option<String>> {
+        self.shared.latest_embed(room_id, url).await
+    }
+
+    /// Forget an embed, e.g. once it has been redacted.
+    pub async fn forget_embed(&self, event_id: &str) -> Result<()> {
+        self.shared.forget_embed(event_id).await
+    }
+}
+
+impl Database {
+    /// Add a URL rewrite rule, returning its ID.
+    pub async fn add_url_rewrite(&self, pattern: &str, replacement: &str) -> Result<i64> {
+        let conn = self.conn.clone();
+        let pattern = pattern.to_owned();
+        let replacement = replacement.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO url_rewrites (pattern, replacement) VALUES (?1, ?2)",
+                [&pattern, &replacement],
+            )
+            .context("Failed to add URL rewrite")?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+        .context("add_url_rewrite task panicked")?
+    }
+
+    /// Remove a URL rewrite rule by ID. Returns `true` if it existed.
+    pub async fn remove_url_rewrite(&self, id: i64) -> Result<bool> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let deleted = conn
+                .execute("DELETE FROM url_rewrites WHERE id = ?1", [id])
+                .context("Failed to remove URL rewrite")?;
+            Ok(deleted > 0)
+        })
+        .await
+        .context("remove_url_rewrite task panicked")?
+    }
+
+    /// List URL rewrite rules in the order they were added.
+    pub async fn list_url_rewrites(&self) -> Result<Vec<UrlRewriteRow>> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            query_url_rewrites(&conn)
+        })
+        .await
+        .context("list_url_rewrites task panicked")?
+    }
+
+    /// Trust `user_id` with admin commands. Returns `false` if they already
+    /// were.
+    pub async fn add_trusted_user(&self, user_id: &str, added_by: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let user_id = user_id.to_owned();
+        let added_by = added_by.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let inserted = conn
+                .execute(
+                    "INSERT OR IGNORE INTO trusted_users (user_id, added_by) VALUES (?1, ?2)",
+                    [&user_id, &added_by],
+                )
+                .context("Failed to add trusted user")?;
+            Ok(inserted > 0)
+        })
+        .await
+        .context("add_trusted_user task panicked")?
+    }
+
+    /// Stop trusting `user_id`. Returns `true` if they were trusted.
+    pub async fn remove_trusted_user(&self, user_id: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let user_id = user_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let deleted = conn
+                .execute("DELETE FROM trusted_users WHERE user_id = ?1", [&user_id])
+                .context("Failed to remove trusted user")?;
+            Ok(deleted > 0)
+        })
+        .await
+        .context("remove_trusted_user task panicked")?
+    }
+
+    /// List the users trusted with admin commands, in the order they were
+    /// added.
+    pub async fn list_trusted_users(&self) -> Result<Vec<TrustedUserRow>> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stmt = conn
+                .prepare("SELECT user_id, added_by FROM trusted_users ORDER BY rowid")
+                .context("Failed to prepare trusted_users query")?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok(TrustedUserRow {
+                        user_id: row.get(0)?,
+                        added_by: row.get(1)?,
+                    })
+                })
+                .context("Failed to query trusted_users")?;
+            let mut users = Vec::new();
+            for row in rows {
+                users.push(row.context("Failed to read trusted_users row")?);
+            }
+            Ok(users)
+        })
+        .await
+        .context("list_trusted_users task panicked")?
+    }
+}
+
+impl Database {
+    /// Set (or with `None`, unset) the bot's display name in a room.
+    pub async fn set_room_display_name(
+        &self,
+        room_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let display_name = display_name.map(str::to_owned);
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO room_profiles (room_id, display_name) VALUES (?1, ?2)
+                 ON CONFLICT(room_id) DO UPDATE SET display_name = excluded.display_name",
+                rusqlite::params![room_id, display_name],
+            )
+            .context("Failed to set room display name")?;
+            Ok(())
+        })
+        .await
+        .context("set_room_display_name task panicked")?
+    }
+
+    /// Set (or with `None`, unset) the bot's avatar in a room.
+    pub async fn set_room_avatar_url(&self, room_id: &str, avatar_url: Option<&str>) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let avatar_url = avatar_url.map(str::to_owned);
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO room_profiles (room_id, avatar_url) VALUES (?1, ?2)
+                 ON CONFLICT(room_id) DO UPDATE SET avatar_url = excluded.avatar_url",
+                rusqlite::params![room_id, avatar_url],
+            )
+            .context("Failed to set room avatar")?;
+            Ok(())
+        })
+        .await
+        .context("set_room_avatar_url task panicked")?
+    }
+
+    /// Remove all of a room's profile overrides.
+    pub async fn clear_room_profile(&self, room_id: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute("DELETE FROM room_profiles WHERE room_id = ?1", [&room_id])
+                .context("Failed to clear room profile")?;
+            Ok(())
+        })
+        .await
+        .context("clear_room_profile task panicked")?
+    }
+
+    /// Return a room's profile overrides (empty if there are none).
+    pub async fn get_room_profile(&self, room_id: &str) -> Result<RoomProfile> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT display_name, avatar_url FROM room_profiles WHERE room_id = ?1",
+                [&room_id],
+                |row| {
+                    Ok(RoomProfile {
+                        display_name: row.get(0)?,
+                        avatar_url: row.get(1)?,
+                    })
+                },
+            );
+            match result {
+                Ok(profile) => Ok(profile),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(RoomProfile::default()),
+                Err(e) => Err(e).context("Failed to query room profile"),
+            }
+        })
+        .await
+        .context("get_room_profile task panicked")?
+    }
+
+    /// Look up an earlier upload of the content with `content_hash`, marking
+    /// it as recently used.
+    pub async fn get_uploaded_media(
+        &self,
+        content_hash: &str,
+        encrypted: bool,
+    ) -> Result<Option<UploadedMedia>> {
+        self.shared
+            .get_uploaded_media(content_hash, encrypted)
+            .await
+    }
+
+    /// Remember an upload of the content with `content_hash`, then forget the
+    /// least recently used uploads beyond `max_entries`.
+    pub async fn record_uploaded_media(
+        &self,
+        content_hash: &str,
+        encrypted: bool,
+        media: &UploadedMedia,
+        max_entries: usize,
+    ) -> Result<()> {
+        self.shared
+            .record_uploaded_media(content_hash, encrypted, media, max_entries)
+            .await
+    }
+
+    /// Forget the upload of the content with `content_hash`, so it isn't
+    /// reused.
+    pub async fn forget_uploaded_media(&self, content_hash: &str, encrypted: bool) -> Result<()> {
+        self.shared
+            .forget_uploaded_media(content_hash, encrypted)
+            .await
+    }
+
+    /// Forget the upload of the content with `content_hash` unless it was
+    /// used within `max_age`, by this or another instance sharing the store.
+    /// Returns whether it's gone, and so can't be reused any more.
+    pub async fn forget_unused_uploaded_media(
+        &self,
+        content_hash: &str,
+        encrypted: bool,
+        max_age: Duration,
+    ) -> Result<bool> {
+        self.shared
+            .forget_unused_uploaded_media(content_hash, encrypted, max_age)
+            .await
+    }
+}
+
+impl Database {
+    /// Forget embeds older than `embed_max_age` and summaries older than
+    /// `summary_max_age`, and the least recently used uploads beyond
+    /// `upload_max_entries`, wherever the shared store keeps them. `None`
+    /// keeps everything. Expired pending galleries and link verdicts go too.
+    pub async fn prune(
+        &self,
+        embed_max_age: Option<Duration>,
+        summary_max_age: Option<Duration>,
+        upload_max_entries: usize,
+    ) -> Result<PruneStats> {
+        let shared = self
+            .shared
+            .prune(embed_max_age, summary_max_age, upload_max_entries)
+            .await?;
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stats = shared;
+            stats.galleries = conn
+                .execute(
+                    "DELETE FROM pending_galleries WHERE created_at < datetime('now', ?1)",
+                    [age_modifier(PENDING_GALLERY_MAX_AGE)],
+                )
+                .context("Failed to prune pending galleries")?;
+            stats.verdicts = conn
+                .execute(
+                    "DELETE FROM link_verdicts WHERE checked_at < datetime('now', ?1)",
+                    [age_modifier(LINK_VERDICT_MAX_AGE)],
+                )
+                .context("Failed to prune link verdicts")?;
+            Ok(stats)
+        })
+        .await
+        .context("prune task panicked")?
+    }
+
+    /// Remember that the bot uploaded, or reused, the media at `mxc_uri`, so
+    /// it can be deleted once it hasn't been used for a while.
+    pub async fn record_media_upload(
+        &self,
+        mxc_uri: &str,
+        content_hash: Option<&str>,
+        encrypted: bool,
+    ) -> Result<()> {
+        let conn = self.conn.clone();
+        let mxc_uri = mxc_uri.to_owned();
+        let content_hash = content_hash.map(str::to_owned);
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO media_uploads (mxc_uri, content_hash, encrypted)
+                 VALUES (?1, ?2, ?3)
+                 ON CONFLICT (mxc_uri) DO UPDATE SET
+                     content_hash = COALESCE(excluded.content_hash, content_hash),
+                     last_used_at = datetime('now')",
+                rusqlite::params![mxc_uri, content_hash, encrypted],
+            )
+            .context("Failed to record media upload")?;
+            Ok(())
+        })
+        .await
+        .context("record_media_upload task panicked")?
+    }
+
+    /// Return the media the bot uploaded that hasn't been used for
+    /// `max_age`, least recently used first.
+    pub async fn unused_media_uploads(&self, max_age: Duration) -> Result<Vec<MediaUpload>> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT mxc_uri, content_hash, encrypted FROM media_uploads
+                     WHERE last_used_at <= datetime('now', ?1)
+                     ORDER BY last_used_at",
+                )
+                .context("Failed to prepare media upload query")?;
+            let uploads = stmt
+                .query_map([age_modifier(max_age)], |row| {
+                    Ok(MediaUpload {
+                        mxc_uri: row.get(0)?,
+                        content_hash: row.get(1)?,
+                        encrypted: row.get(2)?,
+                    })
+                })
+                .context("Failed to query media uploads")?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .context("Failed to read media uploads")?;
+            Ok(uploads)
+        })
+        .await
+        .context("unused_media_uploads task panicked")?
+    }
+
+    /// Forget the media upload at `mxc_uri`, once it has been deleted.
+    pub async fn forget_media_upload(&self, mxc_uri: &str) -> Result<()> {
+        let conn = self.conn.clone();
+        let mxc_uri = mxc_uri.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute("DELETE FROM media_uploads WHERE mxc_uri = ?1", [&mxc_uri])
+                .context("Failed to forget media upload")?;
+            Ok(())
+        })
+        .await
+        .context("forget_media_upload task panicked")?
+    }
+
+    /// Rebuild the database file to give space freed by deleted rows back to
+    /// the file system, returning how many bytes that saved.
+    pub async fn vacuum(&self) -> Result<u64> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let size = |conn: &Connection| -> Result<u64> {
+                let pages: i64 = conn
+                    .query_row("PRAGMA page_count", [], |row| row.get(0))
+                    .context("Failed to query page count")?;
+                let page_size: i64 = conn
+                    .query_row("PRAGMA page_size", [], |row| row.get(0))
+                    .context("Failed to query page size")?;
+                Ok((pages * page_size) as u64)
+            };
+            let before = size(&conn)?;
+            conn.execute_batch("VACUUM")
+                .context("Failed to vacuum database")?;
+            Ok(before.saturating_sub(size(&conn)?))
+        })
+        .await
+        .context("vacuum task panicked")?
+    }
+}
+
+/// An SQLite date modifier going back `age`, e.g. `-3600 seconds`.
+fn age_modifier(age: Duration) -> String {
+    format!("-{} seconds", age.as_secs())
+}
+
+fn parse_canned_response(row: &rusqlite::Row) -> rusqlite::Result<CannedResponse> {
+    Ok(CannedResponse {
+        id: row.get(0)?,
+        text_markdown: row.get(1)?,
+        media_cas_hash: row.get(2)?,
+        media_mxc_uri: row.get(3)?,
+        media_filename: row.get(4)?,
+        media_mime_type: row.get(5)?,
+    })
+}
+
+impl Database {
+    pub async fn create_canned_response(
+        &self,
+        text_markdown: Option<&str>,
+        media_cas_hash: Option<&str>,
+        media_filename: Option<&str>,
+        media_mime_type: Option<&str>,
+    ) -> Result<i64> {
+        let conn = self.conn.clone();
+        let text_markdown = text_markdown.map(|s| s.to_owned());
+        let media_cas_hash = media_cas_hash.map(|s| s.to_owned());
+        let media_filename = media_filename.map(|s| s.to_owned());
+        let media_mime_type = media_mime_type.map(|s| s.to_owned());
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            conn.execute(
+                "INSERT INTO canned_responses (text_markdown, media_cas_hash, media_filename, media_mime_type)
+                 VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![text_markdown, media_cas_hash, media_filename, media_mime_type],
+            )
+            .context("Failed to insert canned response")?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+        .context("create_canned_response task panicked")?
+    }
+
+    pub async fn add_custom_command(
+        &self,
+        room_id: &str,
+        command_name: &str,
+        response_id: i64,
+    ) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let command_name = command_name.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let old_response_id: Option<i64> = conn
+                .query_row(
+                    "SELECT response_id FROM custom_commands WHERE room_id = ?1 AND command_name = ?2",
+                    rusqlite::params![&room_id, &command_name],
+                    |row| row.get(0),
+                )
+                .ok();
+            conn.execute(
+                "INSERT OR REPLACE INTO custom_commands (room_id, command_name, response_id) VALUES (?1, ?2, ?3)",
+                rusqlite::params![&room_id, &command_name, response_id],
+            )
+            .context("Failed to insert custom command")?;
+            if let Some(old_rid) = old_response_id {
+                let _ = conn.execute("DELETE FROM canned_responses WHERE id = ?1", [old_rid]);
+            }
+            Ok(())
+        })
+        .await
+        .context("add_custom_command task panicked")?
+    }
+
+    pub async fn remove_custom_command(&self, room_id: &str, command_name: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let command_name = command_name.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let response_id: Option<i64> = conn
+                .query_row(
+                    "SELECT response_id FROM custom_commands WHERE room_id = ?1 AND command_name = ?2",
+                    rusqlite::params![&room_id, &command_name],
+                    |row| row.get(0),
+                )
+                .ok();
+            if let Some(rid) = response_id {
+                conn.execute(
+                    "DELETE FROM custom_commands WHERE room_id = ?1 AND command_name = ?2",
+                    rusqlite::params![&room_id, &command_name],
+                )
+                .context("Failed to delete custom command")?;
+                conn.execute("DELETE FROM canned_responses WHERE id = ?1", [rid])
+                    .context("Failed to delete canned response")?;
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        })
+        .await
+        .context("remove_custom_command task panicked")?
+    }
+
+    /// Look up a custom command, preferring a room-specific match over a global
+    /// one (room_id = `""`).
+    pub async fn get_custom_command(
+        &self,
+        room_id: &str,
+        command_name: &str,
+    ) -> Result<Option<CannedResponse>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let command_name = command_name.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let result = conn.query_row(
+                "SELECT cr.id, cr.text_markdown, cr.media_cas_hash, cr.media_mxc_uri,
+                        cr.media_filename, cr.media_mime_type
+                 FROM custom_commands cc
+                 JOIN canned_responses cr ON cc.response_id = cr.id
+                 WHERE cc.command_name = ?2 AND (cc.room_id = ?1 OR cc.room_id = '')
+                 ORDER BY (cc.room_id = '') ASC
+                 LIMIT 1",
+                rusqlite::params![&room_id, &command_name],
+                parse_canned_response,
+            );
+            match result {
+                Ok(cr) => Ok(Some(cr)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e).context("Failed to query custom command"),
+            }
+        })
+        .await
+        .context("get_custom_command task panicked")?
+    }
+
+    pub async fn list_custom_commands(&self, room_id: &str) -> Result<Vec<CustomCommandRow>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT cc.command_name, cr.id, cr.text_markdown, cr.media_cas_hash,
+                        cr.media_mxc_uri, cr.media_filename, cr.media_mime_type
+                 FROM custom_commands cc
+                 JOIN canned_responses cr ON cc.response_id = cr.id
+                 WHERE cc.room_id = ?1
+                 ORDER BY cc.command_name",
+                )
+                .context("Failed to prepare custom_commands query")?;
+            let rows = stmt
+                .query_map([&room_id], |row| {
+                    Ok(CustomCommandRow {
+                        command_name: row.get(0)?,
+                        response: CannedResponse {
+                            id: row.get(1)?,
+                            text_markdown: row.get(2)?,
+                            media_cas_hash: row.get(3)?,
+                            media_mxc_uri: row.get(4)?,
+                            media_filename: row.get(5)?,
+                            media_mime_type: row.get(6)?,
+                        },
+                    })
+                })
+                .context("Failed to query custom_commands")?;
+            let mut result = Vec::new();
+            for row in rows {
+                result.push(row.context("Failed to read custom command row")?);
+            }
+            Ok(result)
+        })
+        .await
+        .context("list_custom_commands task panicked")?
+    }
+
+    pub async fn add_autoresponder(
+        &self,
+        room_id: &str,
+        pattern: &str,
+        probability: f64,
+        response_id: i64,
+    ) -> Result<()> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let pattern = pattern.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let old_response_id: Option<i64> = conn
+                .query_row(
+                    "SELECT response_id FROM autoresponders WHERE room_id = ?1 AND pattern = ?2",
+                    rusqlite::params![&room_id, &pattern],
+                    |row| row.get(0),
+                )
+                .ok();
+            conn.execute(
+                "INSERT OR REPLACE INTO autoresponders (room_id, pattern, probability, response_id)
+                 VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![&room_id, &pattern, probability, response_id],
+            )
+            .context("Failed to insert autoresponder")?;
+            if let Some(old_rid) = old_response_id {
+                let _ = conn.execute("DELETE FROM canned_responses WHERE id = ?1", [old_rid]);
+            }
+            Ok(())
+        })
+        .await
+        .context("add_autoresponder task panicked")?
+    }
+
+    pub async fn remove_autoresponder(&self, room_id: &str, pattern: &str) -> Result<bool> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        let pattern = pattern.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let response_id: Option<i64> = conn
+                .query_row(
+                    "SELECT response_id FROM autoresponders WHERE room_id = ?1 AND pattern = ?2",
+                    rusqlite::params![&room_id, &pattern],
+                    |row| row.get(0),
+                )
+                .ok();
+            if let Some(rid) = response_id {
+                conn.execute(
+                    "DELETE FROM autoresponders WHERE room_id = ?1 AND pattern = ?2",
+                    rusqlite::params![&room_id, &pattern],
+                )
+                .context("Failed to delete autoresponder")?;
+                conn.execute("DELETE FROM canned_responses WHERE id = ?1", [rid])
+                    .context("Failed to delete canned response")?;
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        })
+        .await
+        .context("remove_autoresponder task panicked")?
+    }
+
+    /// Return autoresponders that apply to a room: room-specific entries first,
+    /// then global ones (room_id = `""`).
+    pub async fn get_autoresponders(&self, room_id: &str) -> Result<Vec<AutoresponderRow>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT a.pattern, a.probability, cr.id, cr.text_markdown, cr.media_cas_hash,
+                        cr.media_mxc_uri, cr.media_filename, cr.media_mime_type
+                 FROM autoresponders a
+                 JOIN canned_responses cr ON a.response_id = cr.id
+                 WHERE a.room_id = ?1 OR a.room_id = ''
+                 ORDER BY (a.room_id = '') ASC, a.id ASC",
+                )
+                .context("Failed to prepare autoresponders query")?;
+            let rows = stmt
+                .query_map([&room_id], |row| {
+                    Ok(AutoresponderRow {
+                        pattern: row.get(0)?,
+                        probability: row.get(1)?,
+                        response: CannedResponse {
+                            id: row.get(2)?,
+                            text_markdown: row.get(3)?,
+                            media_cas_hash: row.get(4)?,
+                            media_mxc_uri: row.get(5)?,
+                            media_filename: row.get(6)?,
+                            media_mime_type: row.get(7)?,
+                        },
+                    })
+                })
+                .context("Failed to query autoresponders")?;
+            let mut result = Vec::new();
+            for row in rows {
+                result.push(row.context("Failed to read autoresponder row")?);
+            }
+            Ok(result)
+        })
+        .await
+        .context("get_autoresponders task panicked")?
+    }
+
+    /// Return autoresponders for an exact room_id only.
+    /// Pass `""` to list only global autoresponders.
+    pub async fn list_autoresponders(&self, room_id: &str) -> Result<Vec<AutoresponderRow>> {
+        let conn = self.conn.clone();
+        let room_id = room_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.blocking_lock();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT a.pattern, a.probability, cr.id, cr.text_markdown, cr.media_cas_hash,
+                            cr.media_mxc_uri, cr.media_filename, cr.media_mime_type
+                    FROM autoresponders a
+                    JOIN canned_responses cr ON a.response_id = cr.id
+                    WHERE a.room_id = ?1
+                    ORDER BY a.id",
+                )
+                .context("Failed to prepare autoresponders list query")?;
+            let rows = stmt
+                .query_map([&room_id], |row| {
+                    Ok(AutoresponderRow {
+                        pattern: row.get(0)?,
+                        probability: row.get(1)?,
+                        response: CannedResponse {
+                            id: row.get(2)?,
+                            text_markdown: row.get(3)?,
+                            media_cas_hash: row.get(4)?,
+                            media_mxc_uri: row.get(5)?,
+                            media_filename: row.get(6)?,
+                            media_mime_type: row.get(7)?,
+                        },
+                    })
+                })
+                .context("Failed to list autoresponders")?;
+            let mut result = Vec::new();
+            for row in rows {
+                result.push(row.context("Failed to read autoresponder row")?);
+            }
+            Ok(result)
+        })
+        .await
+        .context("list_autoresponders task panicked")?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_open_in_memory() {
+        let db = Database::open_in_memory().await.unwrap();
+        // Should be able to query without error.
+        let rooms = db.list_key_sharing_rooms().await.unwrap();
+        assert!(rooms.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_enable_disable_key_sharing() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(!db.is_key_sharing_enabled(room).await.unwrap());
+
+        db.enable_key_sharing(room).await.unwrap();
+        assert!(db.is_key_sharing_enabled(room).await.unwrap());
+
+        // Enabling again should be idempotent.
+        db.enable_key_sharing(room).await.unwrap();
+        assert!(db.is_key_sharing_enabled(room).await.unwrap());
+
+        db.disable_key_sharing(room).await.unwrap();
+        assert!(!db.is_key_sharing_enabled(room).await.unwrap());
+
+        // Disabling again should be idempotent.
+        db.disable_key_sharing(room).await.unwrap();
+        assert!(!db.is_key_sharing_enabled(room).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_data_saver() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(!db.is_data_saver_enabled(room).await.unwrap());
+        db.enable_data_saver(room).await.unwrap();
+        db.enable_data_saver(room).await.unwrap();
+        assert!(db.is_data_saver_enabled(room).await.unwrap());
+        db.disable_data_saver(room).await.unwrap();
+        assert!(!db.is_data_saver_enabled(room).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bare_links() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(!db.is_bare_links_enabled(room).await.unwrap());
+        db.enable_bare_links(room).await.unwrap();
+        assert!(db.is_bare_links_enabled(room).await.unwrap());
+        assert!(
+            !db.is_bare_links_enabled("!other:example.com")
+                .await
+                .unwrap()
+        );
+        db.disable_bare_links(room).await.unwrap();
+        assert!(!db.is_bare_links_enabled(room).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let db = Database::open_in_memory().await.unwrap();
+        db.record_embed(
+            "!room:example.com",
+            "$old",
+            "$src1",
+            "https://example.com/1",
+        )
+        .await
+        .unwrap();
+        db.record_embed(
+            "!room:example.com",
+            "$new",
+            "$src2",
+            "https://example.com/2",
+        )
+        .await
+        .unwrap();
+        db.store_summary("https://example.com/1", "model", "Old")
+            .await
+            .unwrap();
+        {
+            let conn = db.conn.lock().await;
+            conn.execute_batch(
+                "UPDATE embed_history SET posted_at = datetime('now', '-10 days')
+                     WHERE event_id = '$old';
+                 UPDATE summary_cache SET created_at = datetime('now', '-10 days');",
+            )
+            .unwrap();
+        }
+        for hash in ["a", "b", "c"] {
+            let media = UploadedMedia {
+                source: hash.to_string(),
+                thumbnail_source: None,
+            };
+            db.record_uploaded_media(hash, false, &media, 10)
+                .await
+                .unwrap();
+        }
+
+        let week = Duration::from_secs(7 * 86400);
+        let stats = db.prune(Some(week), None, 2).await.unwrap();
+        assert_eq!(
+            stats,
+            PruneStats {
+                embeds: 1,
+                summaries: 0,
+                uploads: 1,
+                galleries: 0,
+                verdicts: 0
+            }
+        );
+        assert_eq!(
+            db.recent_embeds("!room:example.com", 10).await.unwrap(),
+            vec!["$new"]
+        );
+        assert!(
+            db.get_cached_summary("https://example.com/1", "model")
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        let stats = db.prune(None, Some(week), 2).await.unwrap();
+        assert_eq!(stats.summaries, 1);
+        assert_eq!(stats.embeds, 0);
+        db.vacuum().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_caption_links() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(!db.is_caption_links_enabled(room).await.unwrap());
+        db.enable_caption_links(room).await.unwrap();
+        assert!(db.is_caption_links_enabled(room).await.unwrap());
+        assert!(
+            !db.is_caption_links_enabled("!other:example.com")
+                .await
+                .unwrap()
+        );
+        db.disable_caption_links(room).await.unwrap();
+        assert!(!db.is_caption_links_enabled(room).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_summary_thumbnails() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(!db.is_summary_thumbnails_enabled(room).await.unwrap());
+        db.enable_summary_thumbnails(room).await.unwrap();
+        assert!(db.is_summary_thumbnails_enabled(room).await.unwrap());
+        assert!(
+            !db.is_summary_thumbnails_enabled("!other:example.com")
+                .await
+                .unwrap()
+        );
+        db.disable_summary_thumbnails(room).await.unwrap();
+        assert!(!db.is_summary_thumbnails_enabled(room).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_video_posters() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(!db.is_video_posters_enabled(room).await.unwrap());
+        db.enable_video_posters(room).await.unwrap();
+        db.enable_video_posters(room).await.unwrap();
+        assert!(db.is_video_posters_enabled(room).await.unwrap());
+        db.disable_video_posters(room).await.unwrap();
+        assert!(!db.is_video_posters_enabled(room).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_quiet_hours() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_quiet_hours(room).await.unwrap(), None);
+        let hours = QuietHours::parse("22:00-07:00", true).unwrap();
+        db.set_quiet_hours(room, &hours).await.unwrap();
+        assert_eq!(db.get_quiet_hours(room).await.unwrap(), Some(hours));
+        let hours = QuietHours::parse("09:00-10:30", false).unwrap();
+        db.set_quiet_hours(room, &hours).await.unwrap();
+        assert_eq!(db.get_quiet_hours(room).await.unwrap(), Some(hours));
+        db.clear_quiet_hours(room).await.unwrap();
+        assert_eq!(db.get_quiet_hours(room).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_queued_embeds() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+        let other = "!other:example.com";
+
+        db.queue_embed(room, "$a", "https://example.com/a")
+            .await
+            .unwrap();
+        db.queue_embed(room, "$b", "https://example.com/b")
+            .await
+            .unwrap();
+        db.queue_embed(other, "$c", "https://example.com/c")
+            .await
+            .unwrap();
+        // An edit replaces the link, but keeps its place.
+        db.queue_embed(room, "$a", "https://example.com/edited")
+            .await
+            .unwrap();
+        db.queue_embed(other, "$d", "https://example.com/d")
+            .await
+            .unwrap();
+        db.forget_queued_embed("$d").await.unwrap();
+        assert_eq!(
+            db.queued_embed_rooms().await.unwrap(),
+            vec![other.to_string(), room.to_string()]
+        );
+
+        let queued = |event_id: &str, url: &str| QueuedEmbed {
+            event_id: event_id.to_string(),
+            url: url.to_string(),
+        };
+        assert_eq!(
+            db.take_queued_embeds(room).await.unwrap(),
+            vec![
+                queued("$a", "https://example.com/edited"),
+                queued("$b", "https://example.com/b")
+            ]
+        );
+        assert_eq!(db.take_queued_embeds(room).await.unwrap(), vec![]);
+        assert_eq!(db.queued_embed_rooms().await.unwrap(), vec![other]);
+    }
+
+    #[tokio::test]
+    async fn test_digest_mode() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_digest(room).await.unwrap(), None);
+        db.enable_digest(room, None).await.unwrap();
+        assert_eq!(
+            db.get_digest(room).await.unwrap(),
+            Some(RoomDigest { interval: None })
+        );
+        db.enable_digest(room, Some(Duration::from_secs(15 * 60)))
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_digest(room).await.unwrap(),
+            Some(RoomDigest {
+                interval: Some(Duration::from_secs(15 * 60))
+            })
+        );
+        db.disable_digest(room).await.unwrap();
+        assert_eq!(db.get_digest(room).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_digest_entries() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (room, short, off) = ("!a:example.com", "!b:example.com", "!c:example.com");
+        let hour = Duration::from_secs(3600);
+        let entry = |event_id: &str, url: &str| DigestEntry {
+            url: url::Url::parse(url).unwrap(),
+            title: Some("Title".to_string()),
+            site_name: None,
+            event_id: Some(event_id.to_string()),
+            thumbnail: Some("mxc://example.com/thumb".to_string()),
+        };
+
+        db.enable_digest(room, None).await.unwrap();
+        db.enable_digest(short, Some(Duration::from_secs(10 * 60)))
+            .await
+            .unwrap();
+        db.add_digest_entry(room, &entry("$a", "https://example.com/a"))
+            .await
+            .unwrap();
+        // The same link again is only listed once.
+        db.add_digest_entry(room, &entry("$b", "https://example.com/a"))
+            .await
+            .unwrap();
+        db.add_digest_entry(room, &entry("$c", "https://example.com/c"))
+            .await
+            .unwrap();
+        db.add_digest_entry(room, &entry("$d", "https://example.com/d"))
+            .await
+            .unwrap();
+        db.forget_digest_entries("$d").await.unwrap();
+        db.add_digest_entry(short, &entry("$e", "https://example.com/e"))
+            .await
+            .unwrap();
+        assert!(db.due_digest_rooms(hour).await.unwrap().is_empty());
+
+        // Links left over after digest mode is turned off are due at once.
+        db.add_digest_entry(off, &entry("$f", "https://example.com/f"))
+            .await
+            .unwrap();
+        assert_eq!(db.due_digest_rooms(hour).await.unwrap(), vec![off]);
+
+        {
+            let conn = db.conn.lock().await;
+            conn.execute(
+                "UPDATE digest_entries SET created_at = datetime('now', '-20 minutes')",
+                [],
+            )
+            .unwrap();
+        }
+        assert_eq!(db.due_digest_rooms(hour).await.unwrap(), vec![short, off]);
+        assert_eq!(
+            db.due_digest_rooms(Duration::from_secs(15 * 60))
+                .await
+                .unwrap(),
+            vec![room, short, off]
+        );
+
+        assert_eq!(
+            db.take_digest_entries(room).await.unwrap(),
+            vec![
+                entry("$a", "https://example.com/a"),
+                entry("$c", "https://example.com/c")
+            ]
+        );
+        assert_eq!(db.take_digest_entries(room).await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_link_verdicts() {
+        let db = Database::open_in_memory().await.unwrap();
+        let (phish, fine) = ("https://phish.example/", "https://fine.example/");
+
+        assert_eq!(db.get_link_verdict(phish).await.unwrap(), None);
+        db.store_link_verdict(phish, Some(Threat::Phishing))
+            .await
+            .unwrap();
+        db.store_link_verdict(fine, None).await.unwrap();
+        assert_eq!(
+            db.get_link_verdict(phish).await.unwrap(),
+            Some(LinkVerdict {
+                threat: Some(Threat::Phishing)
+            })
+        );
+        assert_eq!(
+            db.get_link_verdict(fine).await.unwrap(),
+            Some(LinkVerdict { threat: None })
+        );
+
+        // Old verdicts are asked for again, and pruned.
+        {
+            let conn = db.conn.lock().await;
+            conn.execute(
+                "UPDATE link_verdicts SET checked_at = datetime('now', '-1 hour')
+                 WHERE url = ?1",
+                [phish],
+            )
+            .unwrap();
+        }
+        assert_eq!(db.get_link_verdict(phish).await.unwrap(), None);
+        let stats = db.prune(None, None, 10).await.unwrap();
+        assert_eq!(stats.verdicts, 1);
+        assert!(db.get_link_verdict(fine).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_embed_mode() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_embed_mode(room).await.unwrap(), None);
+        db.set_embed_mode(room, EmbedMode::Never).await.unwrap();
+        assert_eq!(
+            db.get_embed_mode(room).await.unwrap(),
+            Some(EmbedMode::Never)
+        );
+        db.set_embed_mode(room, EmbedMode::Always).await.unwrap();
+        assert_eq!(
+            db.get_embed_mode(room).await.unwrap(),
+            Some(EmbedMode::Always)
+        );
+        db.clear_embed_mode(room).await.unwrap();
+        assert_eq!(db.get_embed_mode(room).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_pending_gallery() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+        let gallery = PendingGallery {
+            note_event_id: "$note".to_string(),
+            thread_root: "$embed".to_string(),
+            referer: "https://example.com/post".to_string(),
+            images: vec![
+                GalleryImage {
+                    url: url::Url::parse("https://example.com/5.jpg").unwrap(),
+                    alt: Some("A cat".to_string()),
+                },
+                GalleryImage {
+                    url: url::Url::parse("https://example.com/6.jpg").unwrap(),
+                    alt: None,
+                },
+            ],
+        };
+        db.store_pending_gallery(room, &gallery).await.unwrap();
+
+        assert_eq!(
+            db.take_pending_gallery("!other:example.com", "$note")
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(db.take_pending_gallery(room, "$other").await.unwrap(), None);
+        // Either the note or the thread finds it, but only once.
+        assert_eq!(
+            db.take_pending_gallery(room, "$embed").await.unwrap(),
+            Some(gallery.clone())
+        );
+        assert_eq!(db.take_pending_gallery(room, "$note").await.unwrap(), None);
+
+        db.store_pending_gallery(room, &gallery).await.unwrap();
+        {
+            let conn = db.conn.lock().await;
+            conn.execute(
+                "UPDATE pending_galleries SET created_at = datetime('now', '-30 days')",
+                [],
+            )
+            .unwrap();
+        }
+        assert_eq!(db.take_pending_gallery(room, "$note").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_caption_layout() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_caption_layout(room).await.unwrap(), None);
+        db.set_caption_layout(room, CaptionLayout::TextFirst)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_caption_layout(room).await.unwrap(),
+            Some(CaptionLayout::TextFirst)
+        );
+        db.clear_caption_layout(room).await.unwrap();
+        assert_eq!(db.get_caption_layout(room).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_link_policy() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_link_policy(room).await.unwrap(), None);
+        db.set_link_policy(room, LinkPolicy::All).await.unwrap();
+        assert_eq!(
+            db.get_link_policy(room).await.unwrap(),
+            Some(LinkPolicy::All)
+        );
+        db.clear_link_policy(room).await.unwrap();
+        assert_eq!(db.get_link_policy(room).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_publish_info() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(!db.is_publish_info_enabled(room).await.unwrap());
+        db.enable_publish_info(room).await.unwrap();
+        db.enable_publish_info(room).await.unwrap();
+        assert!(db.is_publish_info_enabled(room).await.unwrap());
+        assert!(
+            !db.is_publish_info_enabled("!other:example.com")
+                .await
+                .unwrap()
+        );
+        db.disable_publish_info(room).await.unwrap();
+        assert!(!db.is_publish_info_enabled(room).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_video_format() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_video_format(room).await.unwrap(), None);
+        db.set_video_format(room, VideoFormat::Webm).await.unwrap();
+        assert_eq!(
+            db.get_video_format(room).await.unwrap(),
+            Some(VideoFormat::Webm)
+        );
+        db.set_video_format(room, VideoFormat::Mp4).await.unwrap();
+        assert_eq!(
+            db.get_video_format(room).await.unwrap(),
+            Some(VideoFormat::Mp4)
+        );
+        db.clear_video_format(room).await.unwrap();
+        assert_eq!(db.get_video_format(room).await.unwrap(), None);
+        assert_eq!(
+            db.get_video_format("!other:example.com").await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_room_language() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_room_language(room).await.unwrap(), None);
+        db.set_room_language(room, "de").await.unwrap();
+        db.set_room_language(room, "pt-br").await.unwrap();
+        assert_eq!(
+            db.get_room_language(room).await.unwrap().as_deref(),
+            Some("pt-br")
+        );
+        db.clear_room_language(room).await.unwrap();
+        assert_eq!(db.get_room_language(room).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_room_time_format() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_room_time_format(room).await.unwrap(), (None, None));
+        db.set_room_time_style(room, TimeStyle::Relative)
+            .await
+            .unwrap();
+        db.set_room_timezone(room, "Europe/Berlin").await.unwrap();
+        assert_eq!(
+            db.get_room_time_format(room).await.unwrap(),
+            (Some("Europe/Berlin".to_string()), Some(TimeStyle::Relative))
+        );
+        db.clear_room_time_format(room).await.unwrap();
+        assert_eq!(db.get_room_time_format(room).await.unwrap(), (None, None));
+    }
+
+    #[tokio::test]
+    async fn test_summaries() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(!db.is_summaries_enabled(room).await.unwrap());
+        db.enable_summaries(room).await.unwrap();
+        assert!(db.is_summaries_enabled(room).await.unwrap());
+        db.disable_summaries(room).await.unwrap();
+        assert!(!db.is_summaries_enabled(room).await.unwrap());
+
+        let url = "https://example.com/article";
+        assert!(db.get_cached_summary(url, "a").await.unwrap().is_none());
+        db.store_summary(url, "a", "Summary A").await.unwrap();
+        assert_eq!(
+            db.get_cached_summary(url, "a").await.unwrap().as_deref(),
+            Some("Summary A")
+        );
+        // Cache entries are per model.
+        assert!(db.get_cached_summary(url, "b").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_embed_min_power_level() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert_eq!(db.get_embed_min_power_level(room).await.unwrap(), None);
+        db.set_embed_min_power_level(room, 50).await.unwrap();
+        assert_eq!(db.get_embed_min_power_level(room).await.unwrap(), Some(50));
+        db.set_embed_min_power_level(room, 10).await.unwrap();
+        assert_eq!(db.get_embed_min_power_level(room).await.unwrap(), Some(10));
+        db.clear_embed_min_power_level(room).await.unwrap();
+        assert_eq!(db.get_embed_min_power_level(room).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_url_rewrites() {
+        let db = Database::open_in_memory().await.unwrap();
+
+        let first = db
+            .add_url_rewrite("^https://a/", "https://b/")
+            .await
+            .unwrap();
+        let second = db
+            .add_url_rewrite("^https://c/", "https://d/")
+            .await
+            .unwrap();
+        let rows = db.list_url_rewrites().await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].id, first);
+        assert_eq!(rows[1].replacement, "https://d/");
+
+        assert!(db.remove_url_rewrite(first).await.unwrap());
+        assert!(!db.remove_url_rewrite(first).await.unwrap());
+        let rows = db.list_url_rewrites().await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].id, second);
+    }
+
+    #[tokio::test]
+    async fn test_trusted_users() {
+        let db = Database::open_in_memory().await.unwrap();
+
+        assert!(
+            db.add_trusted_user("@bob:example.com", "@admin:example.com")
+                .await
+                .unwrap()
+        );
+        assert!(
+            !db.add_trusted_user("@bob:example.com", "@carol:example.com")
+                .await
+                .unwrap()
+        );
+        db.add_trusted_user("@carol:example.com", "@bob:example.com")
+            .await
+            .unwrap();
+        let users = db.list_trusted_users().await.unwrap();
+        assert_eq!(
+            users,
+            vec![
+                TrustedUserRow {
+                    user_id: "@bob:example.com".to_string(),
+                    added_by: "@admin:example.com".to_string(),
+                },
+                TrustedUserRow {
+                    user_id: "@carol:example.com".to_string(),
+                    added_by: "@bob:example.com".to_string(),
+                },
+            ]
+        );
+
+        assert!(db.remove_trusted_user("@bob:example.com").await.unwrap());
+        assert!(!db.remove_trusted_user("@bob:example.com").await.unwrap());
+        assert_eq!(db.list_trusted_users().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_url_rewrites() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("embed.db");
+        assert!(read_url_rewrites(&path).unwrap().is_empty());
+        // Reading doesn't create the database.
+        assert!(!path.exists());
+
+        let db = Database::open(&path).await.unwrap();
+        db.add_url_rewrite("^https://a/", "https://b/")
+            .await
+            .unwrap();
+        drop(db);
+        let rows = read_url_rewrites(&path).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].pattern, "^https://a/");
+    }
+
+    #[tokio::test]
+    async fn test_room_profile() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        assert!(db.get_room_profile(room).await.unwrap().is_empty());
+        db.set_room_display_name(room, Some("Linkvorschau"))
+            .await
+            .unwrap();
+        db.set_room_avatar_url(room, Some("mxc://example.com/abc"))
+            .await
+            .unwrap();
+        db.set_room_display_name(room, None).await.unwrap();
+        assert_eq!(
+            db.get_room_profile(room).await.unwrap(),
+            RoomProfile {
+                display_name: None,
+                avatar_url: Some("mxc://example.com/abc".to_string()),
+            }
+        );
+
+        db.clear_room_profile(room).await.unwrap();
+        assert!(db.get_room_profile(room).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_uploaded_media() {
+        let db = Database::open_in_memory().await.unwrap();
+        let media = |source: &str| UploadedMedia {
+            source: source.to_owned(),
+            thumbnail_source: None,
+        };
+
+        db.record_uploaded_media("a", false, &media("mxc://x/a"), 2)
+            .await
+            .unwrap();
+        db.record_uploaded_media("b", false, &media("mxc://x/b"), 2)
+            .await
+            .unwrap();
+        // Encrypted uploads are kept apart from plain ones.
+        assert_eq!(db.get_uploaded_media("a", true).await.unwrap(), None);
+
+        // Only the most recently used entries are kept.
+        db.record_uploaded_media("c", false, &media("mxc://x/c"), 2)
+            .await
+            .unwrap();
+        assert_eq!(db.get_uploaded_media("a", false).await.unwrap(), None);
+        assert_eq!(
+            db.get_uploaded_media("b", false).await.unwrap(),
+            Some(media("mxc://x/b"))
+        );
+        assert!(db.get_uploaded_media("c", false).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_media_uploads() {
+        let db = Database::open_in_memory().await.unwrap();
+        let day = Duration::from_secs(86400);
+
+        db.record_media_upload("mxc://x/a", Some("a"), false)
+            .await
+            .unwrap();
+        db.record_media_upload("mxc://x/b", None, true)
+            .await
+            .unwrap();
+        db.record_media_upload("mxc://x/c", None, false)
+            .await
+            .unwrap();
+        {
+            let conn = db.conn.lock().await;
+            conn.execute_batch(
+                "UPDATE media_uploads SET last_used_at = datetime('now', '-10 days')",
+            )
+            .unwrap();
+        }
+        // Reusing an upload keeps it, and what it was cached under.
+        db.record_media_upload("mxc://x/c", None, false)
+            .await
+            .unwrap();
+        db.record_media_upload("mxc://x/a", None, false)
+            .await
+            .unwrap();
+        {
+            let conn = db.conn.lock().await;
+            conn.execute_batch(
+                "UPDATE media_uploads SET last_used_at = datetime('now', '-5 days')
+                     WHERE mxc_uri = 'mxc://x/a'",
+            )
+            .unwrap();
+        }
+
+        let unused = db.unused_media_uploads(7 * day).await.unwrap();
+        assert_eq!(
+            unused,
+            vec![MediaUpload {
+                mxc_uri: "mxc://x/b".to_string(),
+                content_hash: None,
+                encrypted: true,
+            }]
+        );
+        let unused = db.unused_media_uploads(day).await.unwrap();
+        assert_eq!(unused[1].mxc_uri, "mxc://x/a");
+        assert_eq!(unused[1].content_hash.as_deref(), Some("a"));
+
+        db.forget_media_upload("mxc://x/b").await.unwrap();
+        assert_eq!(db.unused_media_uploads(day).await.unwrap().len(), 1);
+
+        let media = UploadedMedia {
+            source: "mxc://x/a".to_string(),
+            thumbnail_source: None,
+        };
+        db.record_uploaded_media("a", false, &media, 10)
+            .await
+            .unwrap();
+        db.forget_uploaded_media("a", false).await.unwrap();
+        assert_eq!(db.get_uploaded_media("a", false).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_cached_metadata() {
+        let db = Database::open_in_memory().await.unwrap();
+        let url = Url::parse("https://example.com/article").unwrap();
+        let meta = Metadata {
+            title: Some("Title".to_string()),
+            image_url: Some(Url::parse("https://example.com/a.png").unwrap()),
+            ..Default::default()
+        };
+
+        db.store_metadata(&url, Some("de"), &meta, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_cached_metadata(&url, Some("de")).await.unwrap(),
+            Some(meta.clone())
+        );
+        assert_eq!(db.get_cached_metadata(&url, None).await.unwrap(), None);
+
+        // What another store would keep round-trips, and older entries
+        // without newer fields still load.
+        let json = serde_json::to_string(&meta).unwrap();
+        assert_eq!(serde_json::from_str::<Metadata>(&json).unwrap(), meta);
+        assert_eq!(
+            serde_json::from_str::<Metadata>(r#"{"title":"Title"}"#).unwrap(),
+            Metadata {
+                title: Some("Title".to_string()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forget_unused_uploaded_media() {
+        let db = Database::open_in_memory().await.unwrap();
+        let day = Duration::from_secs(86400);
+        let media = UploadedMedia {
+            source: "mxc://x/a".to_string(),
+            thumbnail_source: None,
+        };
+        db.record_uploaded_media("a", false, &media, 10)
+            .await
+            .unwrap();
+
+        // Used just now, e.g. by another instance.
+        assert!(
+            !db.forget_unused_uploaded_media("a", false, day)
+                .await
+                .unwrap()
+        );
+        assert!(db.get_uploaded_media("a", false).await.unwrap().is_some());
+
+        {
+            let conn = db.conn.lock().await;
+            conn.execute_batch(
+                "UPDATE uploaded_media SET last_used_at = datetime('now', '-2 days')",
+            )
+            .unwrap();
+        }
+        assert!(
+            db.forget_unused_uploaded_media("a", false, day)
+                .await
+                .unwrap()
+        );
+        assert_eq!(db.get_uploaded_media("a", false).await.unwrap(), None);
+        // Nothing left to reuse.
+        assert!(
+            db.forget_unused_uploaded_media("a", false, day)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embed_history() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+
+        let url = "https://example.com/post";
+        for (event_id, source) in [("$a", "$1"), ("$b", "$2"), ("$c", "$3")] {
+            db.record_embed(room, event_id, source, url).await.unwrap();
+        }
+        db.record_embed("!other:example.com", "$d", "$4", url)
+            .await
+            .unwrap();
+        // Recording the same event twice is harmless.
+        db.record_embed(room, "$a", "$1", url).await.unwrap();
+
+        assert_eq!(db.recent_embeds(room, 2).await.unwrap(), vec!["$c", "$b"]);
+        assert_eq!(
+            db.find_embed("$3", url).await.unwrap().as_deref(),
+            Some("$c")
+        );
+        assert_eq!(
+            db.find_embed("$3", "https://example.com/other")
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            db.latest_embed(room, url).await.unwrap().as_deref(),
+            Some("$c")
+        );
+        db.forget_embed("$c").await.unwrap();
+        assert_eq!(db.recent_embeds(room, 10).await.unwrap(), vec!["$b", "$a"]);
+        assert_eq!(db.find_embed("$3", url).await.unwrap(), None);
+        assert_eq!(
+            db.latest_embed(room, url).await.unwrap().as_deref(),
+            Some("$b")
+        );
+        assert_eq!(
+            db.latest_embed("!none:example.com", url).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_domain_stats() {
+        let db = Database::open_in_memory().await.unwrap();
+
+        for _ in 0..3 {
+            db.record_domain_outcome("busy.example", DomainOutcome::Embedded)
+                .await
+                .unwrap();
+        }
+        db.record_domain_outcome("busy.example", DomainOutcome::Empty)
+            .await
+            .unwrap();
+        db.record_domain_outcome("broken.example", DomainOutcome::Embedded)
+            .await
+            .unwrap();
+        for _ in 0..DomainStats::FAILING_STREAK {
+            db.record_domain_outcome("broken.example", DomainOutcome::Failed)
+                .await
+                .unwrap();
+        }
+
+        let stats = db.domain_stats(10).await.unwrap();
+        assert_eq!(
+            stats
+                .iter()
+                .map(|s| (s.domain.as_str(), s.embedded, s.empty, s.failed))
+                .collect::<Vec<_>>(),
+            vec![("broken.example", 1, 0, 5), ("busy.example", 3, 1, 0)]
+        );
+        assert!(stats[0].is_failing());
+        assert!(stats[0].last_embedded_at.is_some());
+        assert!(!stats[1].is_failing());
+        assert_eq!(stats[1].failure_streak, 1);
+
+        // A success ends the streak.
+        db.record_domain_outcome("broken.example", DomainOutcome::Embedded)
+            .await
+            .unwrap();
+        let stats = db.domain_stats(1).await.unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].domain, "broken.example");
+        assert_eq!(stats[0].failure_streak, 0);
+    }
+
+    #[tokio::test]
+    async fn test_list_key_sharing_rooms() {
+        let db = Database::open_in_memory().await.unwrap();
+
+        db.enable_key_sharing("!b:example.com").await.unwrap();
+        db.enable_key_sharing("!a:example.com").await.unwrap();
+        db.enable_key_sharing("!c:example.com").await.unwrap();
 
         let rooms = db.list_key_sharing_rooms().await.unwrap();
         assert_eq!(
@@ -629,6 +4255,70 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pending_migrations() {
+        assert_eq!(pending_migrations(0).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(pending_migrations(14).unwrap()[0].version, 15);
+        assert!(pending_migrations(SCHEMA_VERSION).unwrap().is_empty());
+        assert!(pending_migrations(SCHEMA_VERSION + 1).is_err());
+        assert!(
+            MIGRATIONS
+                .windows(2)
+                .all(|w| w[1].version == w[0].version + 1)
+        );
+    }
+
+    #[test]
+    fn test_plan_migrations() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("embed.db");
+        assert_eq!(plan_migrations(&path).unwrap().len(), MIGRATIONS.len());
+        // Planning doesn't create the database.
+        assert!(!path.exists());
+
+        let conn = Connection::open(&path).unwrap();
+        migrate(&conn).unwrap();
+        assert!(plan_migrations(&path).unwrap().is_empty());
+
+        conn.execute(
+            "UPDATE schema_meta SET value = '15' WHERE key = 'version'",
+            [],
+        )
+        .unwrap();
+        assert_eq!(
+            plan_migrations(&path).unwrap(),
+            vec![
+                (16, "create caption_link_rooms"),
+                (17, "create room_caption_layouts"),
+                (18, "create pending_galleries"),
+                (19, "create trusted_users"),
+                (20, "create summary_thumbnail_rooms"),
+                (21, "create video_poster_rooms"),
+                (22, "create room_quiet_hours"),
+                (23, "create queued_embeds"),
+                (24, "create room_digests"),
+                (25, "create digest_entries"),
+                (26, "create link_verdicts"),
+                (27, "create room_languages"),
+                (28, "create media_uploads"),
+                (29, "create room_link_policies"),
+                (30, "create info_rooms")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migrate_rejects_newer_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        conn.execute(
+            "UPDATE schema_meta SET value = ?1 WHERE key = 'version'",
+            [(SCHEMA_VERSION + 1).to_string()],
+        )
+        .unwrap();
+        assert!(migrate(&conn).is_err());
+    }
+
     #[tokio::test]
     async fn test_migration_is_idempotent() {
         let db = Database::open_in_memory().await.unwrap();
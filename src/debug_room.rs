@@ -0,0 +1,137 @@
+use url::Url;
+
+use crate::error::EmbedError;
+use crate::processing::truncate_text;
+
+/// Longest error detail included in a notice.
+const MAX_DETAIL_CHARS: usize = 200;
+
+/// The part of the embed pipeline that failed. Attached to errors as context
+/// so the debug room notice can say where things went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Location,
+    Metadata,
+    Summary,
+    Media,
+    Post,
+}
+
+impl Stage {
+    pub fn label(self) -> &'static str {
+        match self {
+            Stage::Location => "location",
+            Stage::Metadata => "metadata",
+            Stage::Summary => "summary",
+            Stage::Media => "media",
+            Stage::Post => "post",
+        }
+    }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            Stage::Location => "Failed to post location",
+            Stage::Metadata => "Failed to fetch metadata",
+            Stage::Summary => "Failed to summarize page",
+            Stage::Media => "Failed to upload media",
+            Stage::Post => "Failed to send embed",
+        };
+        f.write_str(message)
+    }
+}
+
+/// A short, stable description of what kind of error `error` is: its
+/// [`EmbedError`], or the status it got from a server.
+pub fn classify(error: &anyhow::Error) -> String {
+    let kind = EmbedError::of(error);
+    if matches!(
+        kind,
+        EmbedError::HttpStatus | EmbedError::ServerError | EmbedError::RateLimited
+    ) && let Some(status) = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<reqwest::Error>()?.status())
+    {
+        return format!("HTTP {}", status.as_u16());
+    }
+    kind.to_string()
+}
+
+/// Build the plain and HTML notice posted to the debug room when embedding
+/// `url` in `room_id` failed.
+pub fn failure_notice(
+    room_id: &str,
+    url: &Url,
+    stage: Option<Stage>,
+    error: &anyhow::Error,
+) -> (String, String) {
+    let domain = url.host_str().unwrap_or(url.as_str());
+    let stage = stage.map_or("unknown", Stage::label);
+    let class = classify(error);
+    let detail = truncate_text(&error.root_cause().to_string(), MAX_DETAIL_CHARS, 1);
+    (
+        format!(
+            "Embed failed for {} in {}: {} stage, {}: {}",
+            domain, room_id, stage, class, detail
+        ),
+        format!(
+            "<strong>Embed failed</strong> for <code>{}</code> in <code>{}</code>: \
+             {} stage, <em>{}</em>: {}",
+            html_escape::encode_text(domain),
+            html_escape::encode_text(room_id),
+            stage,
+            html_escape::encode_text(&class),
+            html_escape::encode_text(&detail)
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::{FileTooLarge, UnexpectedContent};
+    use anyhow::Context;
+    use mime_guess::Mime;
+
+    #[test]
+    fn test_classify() {
+        let err = anyhow::Error::new(FileTooLarge {
+            size: 1,
+            streamed: false,
+        })
+        .context(Stage::Media);
+        assert_eq!(classify(&err), "too large");
+        assert_eq!(err.downcast_ref::<Stage>(), Some(&Stage::Media));
+
+        let err: anyhow::Result<()> = Err(anyhow::anyhow!("boom")).context("Failed");
+        assert_eq!(classify(&err.unwrap_err()), "other");
+
+        let err: anyhow::Result<()> = Err(anyhow::anyhow!("ffmpeg failed: boom"))
+            .context(EmbedError::FfmpegFailed)
+            .context(Stage::Media);
+        assert_eq!(classify(&err.unwrap_err()), "ffmpeg failed");
+    }
+
+    #[test]
+    fn test_failure_notice() {
+        let url = Url::parse("https://example.com/clip.mp4").unwrap();
+        let mime_type: Mime = "video/mp4".parse().unwrap();
+        let err = anyhow::Error::new(UnexpectedContent {
+            expected: mime_type,
+            found: "an HTML page",
+        })
+        .context(Stage::Media);
+
+        let (plain, html) = failure_notice("!room:example.com", &url, Some(Stage::Media), &err);
+        assert_eq!(
+            plain,
+            "Embed failed for example.com in !room:example.com: media stage, \
+             unexpected content: Expected video/mp4 but got an HTML page"
+        );
+        assert!(html.starts_with("<strong>Embed failed</strong> for <code>example.com</code>"));
+
+        let (plain, _) = failure_notice("!room:example.com", &url, None, &err);
+        assert!(plain.contains("unknown stage"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result, bail};
+use flate2::write::{GzDecoder, ZlibDecoder};
+use reqwest::header::{CONTENT_ENCODING, HeaderMap};
+
+use crate::processing::FileTooLarge;
+
+/// Content codings we can decode, for the `Accept-Encoding` request header.
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+/// The decompression ratio isn't checked until at least this much has been
+/// decoded, since small files can legitimately compress extremely well.
+const RATIO_CHECK_MIN_BYTES: u64 = 1024 * 1024;
+
+/// Buffer size for the brotli decoder.
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A compressed response decoded to far more than it was on the wire.
+#[derive(Debug)]
+pub struct ExcessiveCompression {
+    pub encoded: u64,
+    pub decoded: u64,
+}
+
+impl std::fmt::Display for ExcessiveCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Decompression ratio too high: {} bytes decoded from {}",
+            self.decoded, self.encoded
+        )
+    }
+}
+
+impl std::error::Error for ExcessiveCompression {}
+
+/// Counts decoded bytes, refusing to write past `limit` so a single chunk
+/// can't expand into an arbitrarily large write.
+struct CountingWriter<W> {
+    inner: W,
+    written: Arc<AtomicU64>,
+    limit: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.written.load(Ordering::Relaxed);
+        if written + buf.len() as u64 > self.limit {
+            self.written
+                .store(written + buf.len() as u64, Ordering::Relaxed);
+            return Err(io::Error::other("decoded size limit exceeded"));
+        }
+        let n = self.inner.write(buf)?;
+        self.written.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Decoder<W: Write> {
+    Identity(W),
+    Gzip(GzDecoder<W>),
+    Deflate(ZlibDecoder<W>),
+    Brotli(Box<brotli::DecompressorWriter<W>>),
+}
+
+/// Decodes a response body according to its `Content-Encoding` while it's
+/// being received, enforcing limits on the decoded size and on how much
+/// larger it is than what was received.
+pub struct BodyDecoder<W: Write> {
+    decoder: Decoder<CountingWriter<W>>,
+    decoded: Arc<AtomicU64>,
+    encoded: u64,
+    max_size: u64,
+    max_ratio: u64,
+}
+
+impl<W: Write> BodyDecoder<W> {
+    pub fn new(headers: &HeaderMap, inner: W, max_size: u64, max_ratio: u64) -> Result<Self> {
+        let decoded = Arc::new(AtomicU64::new(0));
+        let writer = CountingWriter {
+            inner,
+            written: decoded.clone(),
+            limit: max_size,
+        };
+
+        let coding = headers
+            .get(CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("identity")
+            .trim()
+            .to_ascii_lowercase();
+        let decoder = match coding.as_str() {
+            "" | "identity" => Decoder::Identity(writer),
+            "gzip" | "x-gzip" => Decoder::Gzip(GzDecoder::new(writer)),
+            "deflate" => Decoder::Deflate(ZlibDecoder::new(writer)),
+            "br" => Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(
+                writer,
+                BROTLI_BUFFER_SIZE,
+            ))),
+            other => bail!("Unsupported Content-Encoding: {}", other),
+        };
+
+        Ok(Self {
+            decoder,
+            decoded,
+            encoded: 0,
+            max_size,
+            max_ratio,
+        })
+    }
+
+    /// Decode the next chunk of the body as received.
+    pub fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.encoded += chunk.len() as u64;
+        // Flush so decoded output isn't held back in the decoder's buffer,
+        // where the limits can't see it.
+        let result = match &mut self.decoder {
+            Decoder::Identity(w) => w.write_all(chunk),
+            Decoder::Gzip(d) => d.write_all(chunk).and_then(|_| d.flush()),
+            Decoder::Deflate(d) => d.write_all(chunk).and_then(|_| d.flush()),
+            Decoder::Brotli(d) => d.write_all(chunk).and_then(|_| d.flush()),
+        };
+        self.check_limits()?;
+        result.context("Failed to decode response body")
+    }
+
+    /// Finish decoding and return the underlying writer.
+    pub fn finish(self) -> Result<W> {
+        let decoded = self.decoded.clone();
+        let (encoded, max_size, max_ratio) = (self.encoded, self.max_size, self.max_ratio);
+        let writer = match self.decoder {
+            Decoder::Identity(w) => Ok(w),
+            Decoder::Gzip(d) => d.finish(),
+            Decoder::Deflate(d) => d.finish(),
+            Decoder::Brotli(d) => d
+                .into_inner()
+                .map_err(|_| io::Error::other("truncated brotli stream")),
+        };
+        check_limits(
+            decoded.load(Ordering::Relaxed),
+            encoded,
+            max_size,
+            max_ratio,
+        )?;
+        Ok(writer.context("Failed to decode response body")?.inner)
+    }
+
+    /// Number of decoded bytes so far.
+    pub fn decoded(&self) -> u64 {
+        self.decoded.load(Ordering::Relaxed)
+    }
+
+    fn check_limits(&self) -> Result<()> {
+        check_limits(self.decoded(), self.encoded, self.max_size, self.max_ratio)
+    }
+}
+
+fn check_limits(decoded: u64, encoded: u64, max_size: u64, max_ratio: u64) -> Result<()> {
+    if decoded > max_size {
+        return Err(FileTooLarge {
+            size: decoded,
+            streamed: true,
+        }
+        .into());
+    }
+    if max_ratio > 0
+        && decoded >= RATIO_CHECK_MIN_BYTES
+        && decoded > encoded.saturating_mul(max_ratio)
+    {
+        return Err(ExcessiveCompression { encoded, decoded }.into());
+    }
+    Ok(())
+}
+
+/// Read a whole (possibly compressed) response body into memory, subject to
+/// the same limits as [`BodyDecoder`].
+pub async fn read_body(
+    mut response: reqwest::Response,
+    max_size: u64,
+    max_ratio: u64,
+) -> Result<Vec<u8>> {
+    let mut decoder = BodyDecoder::new(response.headers(), Vec::new(), max_size, max_ratio)?;
+    while let Some(chunk) = response.chunk().await? {
+        decoder.write(&chunk)?;
+    }
+    decoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_str(encoding).unwrap());
+        headers
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decode_gzip() {
+        let body = gzip(b"<html>hello</html>");
+        let mut decoder = BodyDecoder::new(&headers("gzip"), Vec::new(), 1024, 100).unwrap();
+        for chunk in body.chunks(4) {
+            decoder.write(chunk).unwrap();
+        }
+        assert_eq!(decoder.finish().unwrap(), b"<html>hello</html>");
+    }
+
+    #[test]
+    fn test_decoded_size_limit() {
+        let body = gzip(&[b'a'; 4096]);
+        let mut decoder = BodyDecoder::new(&headers("gzip"), Vec::new(), 1024, 0).unwrap();
+        let err = decoder.write(&body).unwrap_err();
+        assert!(err.is::<FileTooLarge>());
+
+        let mut decoder = BodyDecoder::new(&HeaderMap::new(), Vec::new(), 1024, 0).unwrap();
+        assert!(decoder.write(&[0; 2048]).unwrap_err().is::<FileTooLarge>());
+    }
+
+    #[test]
+    fn test_decompression_ratio_limit() {
+        let body = gzip(&vec![0; 4 * 1024 * 1024]);
+        let mut decoder = BodyDecoder::new(&headers("gzip"), Vec::new(), u64::MAX, 100).unwrap();
+        let err = decoder.write(&body).unwrap_err();
+        assert!(err.is::<ExcessiveCompression>());
+    }
+
+    #[test]
+    fn test_unsupported_encoding() {
+        assert!(BodyDecoder::new(&headers("compress"), Vec::new(), 1024, 100).is_err());
+    }
+}
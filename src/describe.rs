@@ -0,0 +1,340 @@
+use anyhow::{Context, Result};
+use matrix_sdk::Client;
+use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::events::StateEventType;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db::Database;
+use crate::transcribe;
+
+/// State event type (with an empty state key) describing the bot, published
+/// in rooms where it may send state, so clients and tools can find out what
+/// it does without asking.
+pub const INFO_EVENT_TYPE: &str = "io.github.jchv.matrix_embed.info";
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The kinds of links the bot can embed.
+const EXTRACTORS: &[&str] = &[
+    "Web pages, from their OpenGraph and Twitter card metadata",
+    #[cfg(feature = "extractors")]
+    "Fediverse posts, over ActivityPub",
+    "Direct links to images, videos and audio",
+    "Video player pages",
+    "iCalendar events",
+    "`geo:` locations",
+];
+
+/// What the bot can do with `config` beyond embedding links.
+pub fn capabilities(config: &Config) -> Vec<&'static str> {
+    let mut capabilities = Vec::new();
+    if config.summary_api_url.is_some() {
+        capabilities.push("Summaries of long articles, in rooms that enable them");
+    }
+    if transcribe::is_enabled(config) {
+        capabilities.push("Transcripts of audio and video");
+    }
+    if config.static_map_url.is_some() {
+        capabilities.push("Maps of shared locations");
+    }
+    capabilities
+}
+
+/// A description of the bot, its version and what it can embed, for the
+/// `version` and `help` commands.
+pub fn about(config: &Config) -> String {
+    let mut out = format!(
+        "**matrix-embed {}** posts previews of links.\n\nIt embeds:\n",
+        VERSION
+    );
+    for extractor in EXTRACTORS {
+        out.push_str(&format!("- {}\n", extractor));
+    }
+    let capabilities = capabilities(config);
+    if !capabilities.is_empty() {
+        out.push_str("\nIt also adds:\n");
+        for capability in capabilities {
+            out.push_str(&format!("- {}\n", capability));
+        }
+    }
+    out
+}
+
+/// The settings that apply to embeds in `room_id`, which is a `direct` chat
+/// with the bot or not, marking those that come from the global
+/// configuration rather than the room.
+pub async fn room_settings(
+    config: &Config,
+    database: &Database,
+    room_id: &str,
+    direct: bool,
+) -> Result<String> {
+    let setting = |name: &str, value: &str, overridden: bool| {
+        let source = if overridden { "" } else { " (default)" };
+        format!("- {}: **{}**{}\n", name, value, source)
+    };
+    let toggle = |name: &str, global: bool, room: bool| {
+        let value = if global || room { "on" } else { "off" };
+        setting(name, value, room && !global)
+    };
+
+    let mut out = String::from("Settings in this room:\n");
+
+    let embed_mode = database.get_embed_mode(room_id).await?;
+    out.push_str(&setting(
+        "Embed mode",
+        embed_mode.unwrap_or(config.embed_mode).name(),
+        embed_mode.is_some(),
+    ));
+    let layout = database.get_caption_layout(room_id).await?;
+    out.push_str(&setting(
+        "Caption layout",
+        layout.unwrap_or(config.caption_layout).name(),
+        layout.is_some(),
+    ));
+    out.push_str(&setting(
+        "Reply mode",
+        config.reply_mode_in(direct).name(),
+        false,
+    ));
+    let policy = database.get_link_policy(room_id).await?;
+    out.push_str(&setting(
+        "Link policy",
+        policy.unwrap_or(config.link_policy).name(),
+        policy.is_some(),
+    ));
+    let format = database.get_video_format(room_id).await?;
+    out.push_str(&setting(
+        "Video format",
+        format.unwrap_or(config.video_format).name(),
+        format.is_some(),
+    ));
+    let language = database.get_room_language(room_id).await?;
+    out.push_str(&setting(
+        "Language",
+        language
+            .as_deref()
+            .or(config.language.as_deref())
+            .unwrap_or("any"),
+        language.is_some(),
+    ));
+    let (timezone, style) = database.get_room_time_format(room_id).await?;
+    out.push_str(&setting(
+        "Timezone",
+        timezone.as_deref().unwrap_or(config.timezone.name()),
+        timezone.is_some(),
+    ));
+    out.push_str(&setting(
+        "Time style",
+        style.unwrap_or(config.time_style).name(),
+        style.is_some(),
+    ));
+    let power_level = database.get_embed_min_power_level(room_id).await?;
+    let power_level_value = match power_level.or(config.embed_min_power_level) {
+        Some(level) => level.to_string(),
+        None => "anyone".to_string(),
+    };
+    out.push_str(&setting(
+        "Embeds links from power level",
+        &power_level_value,
+        power_level.is_some(),
+    ));
+    let quiet_hours = database.get_quiet_hours(room_id).await?;
+    let quiet_hours_value = match quiet_hours {
+        Some(hours) if hours.digest => format!("{}, as a digest", hours),
+        Some(hours) => hours.to_string(),
+        None => "none".to_string(),
+    };
+    out.push_str(&setting(
+        "Quiet hours",
+        &quiet_hours_value,
+        quiet_hours.is_some(),
+    ));
+    let digest = database.get_digest(room_id).await?;
+    let digest_value = match digest {
+        Some(digest) => format!(
+            "every {} minutes",
+            digest.interval.unwrap_or(config.digest_interval).as_secs() / 60
+        ),
+        None => "off".to_string(),
+    };
+    out.push_str(&setting("Digest", &digest_value, digest.is_some()));
+
+    if config.summary_api_url.is_some() {
+        let enabled = database.is_summaries_enabled(room_id).await?;
+        out.push_str(&toggle("Summaries", false, enabled));
+    }
+    let enabled = database.is_data_saver_enabled(room_id).await?;
+    out.push_str(&toggle("Data saver", false, enabled));
+    let enabled = database.is_bare_links_enabled(room_id).await?;
+    out.push_str(&toggle(
+        "Links without a scheme",
+        config.bare_www_links,
+        enabled,
+    ));
+    let enabled = database.is_caption_links_enabled(room_id).await?;
+    out.push_str(&toggle("Links in captions", config.caption_links, enabled));
+    let enabled = database.is_summary_thumbnails_enabled(room_id).await?;
+    out.push_str(&toggle(
+        "Summary card thumbnails",
+        config.summary_thumbnails,
+        enabled,
+    ));
+    let enabled = database.is_video_posters_enabled(room_id).await?;
+    out.push_str(&toggle("Video posters", config.video_posters, enabled));
+
+    Ok(out)
+}
+
+/// Content of the [`INFO_EVENT_TYPE`] state event.
+fn info_content(config: &Config) -> serde_json::Value {
+    json!({
+        "version": VERSION,
+        "extractors": EXTRACTORS,
+        "capabilities": capabilities(config),
+        "help": format!("{} help", config.command_prefix),
+    })
+}
+
+/// The topic given to rooms without one.
+fn topic(config: &Config) -> String {
+    format!(
+        "Link previews by matrix-embed {}. Send \"{} help\" to see what it does here.",
+        VERSION, config.command_prefix
+    )
+}
+
+/// Describe the bot in `room` with an [`INFO_EVENT_TYPE`] state event, and
+/// give the room a topic if it has none, as far as the bot's power level
+/// allows.
+pub async fn publish(room: &Room, config: &Config) -> Result<()> {
+    let own_user_id = room.own_user_id();
+    let power_levels = room
+        .power_levels()
+        .await
+        .context("Failed to load power levels")?;
+
+    let content = info_content(config);
+    if power_levels.user_can_send_state(own_user_id, StateEventType::from(INFO_EVENT_TYPE))
+        && published_info(room).await.as_ref() != Some(&content)
+    {
+        room.send_state_event_raw(INFO_EVENT_TYPE, "", content)
+            .await
+            .context("Failed to publish bot info")?;
+        info!("Published bot info in {}", room.room_id());
+    }
+
+    if room.topic().is_none_or(|topic| topic.trim().is_empty())
+        && power_levels.user_can_send_state(own_user_id, StateEventType::RoomTopic)
+    {
+        room.set_room_topic(&topic(config))
+            .await
+            .context("Failed to set room topic")?;
+        info!("Set topic of {}", room.room_id());
+    }
+    Ok(())
+}
+
+/// The content of the [`INFO_EVENT_TYPE`] event in `room`, if it has one.
+async fn published_info(room: &Room) -> Option<serde_json::Value> {
+    let state = room
+        .get_state_event(StateEventType::from(INFO_EVENT_TYPE), "")
+        .await
+        .ok()??;
+    match state {
+        RawAnySyncOrStrippedState::Sync(raw) => raw.get_field("content").ok()?,
+        RawAnySyncOrStrippedState::Stripped(raw) => raw.get_field("content").ok()?,
+    }
+}
+
+/// Whether the bot describes itself in the room `room_id`: because it's
+/// configured to, or because the room opted in.
+pub async fn publishes_in(room_id: &str, config: &Config, database: &Database) -> bool {
+    if config.publish_info_rooms.iter().any(|room| room == room_id) {
+        return true;
+    }
+    match database.is_publish_info_enabled(room_id).await {
+        Ok(enabled) => enabled,
+        Err(e) => {
+            warn!("Failed to check bot info status of {}: {:?}", room_id, e);
+            false
+        }
+    }
+}
+
+/// Describe the bot in every joined room this instance handles that it
+/// [`publishes_in`].
+pub async fn publish_all(client: &Client, config: &Config, database: &Database) {
+    for room in client.joined_rooms() {
+        if !config.shard.owns(room.room_id().as_str())
+            || !publishes_in(room.room_id().as_str(), config, database).await
+        {
+            continue;
+        }
+        if let Err(e) = publish(&room, config).await {
+            warn!("Failed to describe the bot in {}: {:?}", room.room_id(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_about() {
+        let out = about(&Config::default());
+        assert!(out.starts_with(&format!("**matrix-embed {}**", VERSION)));
+        assert_eq!(
+            out.contains("- Fediverse posts, over ActivityPub\n"),
+            cfg!(feature = "extractors")
+        );
+        assert!(!out.contains("It also adds"));
+
+        let config = Config {
+            summary_api_url: Some(url::Url::parse("https://api.example.com/v1").unwrap()),
+            ..Default::default()
+        };
+        assert!(about(&config).contains("It also adds:\n- Summaries"));
+    }
+
+    #[tokio::test]
+    async fn test_room_settings() {
+        let db = Database::open_in_memory().await.unwrap();
+        let room = "!test:example.com";
+        let config = Config {
+            video_posters: true,
+            ..Default::default()
+        };
+        db.set_embed_mode(room, crate::config::EmbedMode::Always)
+            .await
+            .unwrap();
+        db.enable_caption_links(room).await.unwrap();
+        db.set_quiet_hours(
+            room,
+            &crate::quiet::QuietHours::parse("22:00-07:00", true).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let out = room_settings(&config, &db, room, false).await.unwrap();
+        assert!(out.contains("- Embed mode: **always**\n"));
+        assert!(out.contains("- Caption layout: **on-media** (default)\n"));
+        assert!(out.contains("- Reply mode: **reply** (default)\n"));
+        assert!(out.contains("- Link policy: **first** (default)\n"));
+        assert!(out.contains("- Language: **any** (default)\n"));
+        assert!(out.contains("- Embeds links from power level: **anyone** (default)\n"));
+        assert!(out.contains("- Links in captions: **on**\n"));
+        assert!(out.contains("- Quiet hours: **22:00-07:00, as a digest**\n"));
+        assert!(out.contains("- Digest: **off** (default)\n"));
+        assert!(out.contains("- Video posters: **on** (default)\n"));
+        assert!(out.contains("- Data saver: **off** (default)\n"));
+        assert!(!out.contains("Summaries"));
+
+        let out = room_settings(&config, &db, room, true).await.unwrap();
+        assert!(out.contains("- Reply mode: **standalone** (default)\n"));
+    }
+}
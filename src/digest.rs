@@ -0,0 +1,136 @@
+use url::Url;
+
+use crate::idn;
+use crate::metadata::Metadata;
+
+/// Longest side of the thumbnails uploaded for digests, twice the height
+/// they're shown at so they stay sharp on high density screens.
+pub const THUMBNAIL_SIZE: u32 = 96;
+
+/// Height thumbnails are shown at in digests.
+const THUMBNAIL_HEIGHT: u32 = 48;
+
+/// One link in a digest message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestEntry {
+    pub url: Url,
+    pub title: Option<String>,
+    pub site_name: Option<String>,
+    /// The message the link was posted in.
+    pub event_id: Option<String>,
+    /// `mxc://` URI of a small version of the page's image, shown inline.
+    pub thumbnail: Option<String>,
+}
+
+impl DigestEntry {
+    /// The entry for `url`, posted in `event_id`, with what `meta` says
+    /// about it if it could be fetched.
+    pub fn new(url: Url, event_id: Option<String>, meta: Option<&Metadata>) -> DigestEntry {
+        let non_empty = |value: Option<&String>| {
+            value
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        DigestEntry {
+            url,
+            title: non_empty(meta.and_then(|meta| meta.title.as_ref())),
+            site_name: non_empty(meta.and_then(|meta| meta.site_name.as_ref())),
+            event_id,
+            thumbnail: None,
+        }
+    }
+}
+
+/// Render `entries`, posted in `room_id`, as one message under `heading`,
+/// in plain text and HTML. Each is its title (or URL) linked to the page,
+/// with its thumbnail, the site's name and a link back to the message it
+/// was posted in.
+pub fn render(heading: &str, room_id: &str, entries: &[DigestEntry]) -> (String, String) {
+    let mut plain = format!("{}\n", heading);
+    let mut html = format!(
+        "<p><strong>{}</strong></p>\n<ul>\n",
+        html_escape::encode_text(heading)
+    );
+    for entry in entries {
+        let display_url = idn::display_url(&entry.url);
+        let site = entry
+            .site_name
+            .as_ref()
+            .map(|site| format!(" ({})", site))
+            .unwrap_or_default();
+        match &entry.title {
+            Some(title) => plain.push_str(&format!("- {}{}: {}\n", title, site, display_url)),
+            None => plain.push_str(&format!("- {}{}\n", display_url, site)),
+        }
+
+        let text = entry.title.as_deref().unwrap_or(&display_url);
+        html.push_str("<li>");
+        if let Some(thumbnail) = &entry.thumbnail {
+            html.push_str(&format!(
+                "<img src=\"{}\" alt=\"\" height=\"{}\"/> ",
+                html_escape::encode_double_quoted_attribute(thumbnail),
+                THUMBNAIL_HEIGHT
+            ));
+        }
+        html.push_str(&format!(
+            "<a href=\"{}\">{}</a>{}",
+            html_escape::encode_double_quoted_attribute(entry.url.as_str()),
+            html_escape::encode_text(text),
+            html_escape::encode_text(&site)
+        ));
+        if let Some(event_id) = &entry.event_id {
+            html.push_str(&format!(
+                " · <a href=\"https://matrix.to/#/{}/{}\">message</a>",
+                html_escape::encode_double_quoted_attribute(room_id),
+                html_escape::encode_double_quoted_attribute(event_id)
+            ));
+        }
+        html.push_str("</li>\n");
+    }
+    html.push_str("</ul>");
+    (plain, html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let meta = Metadata {
+            title: Some("Tom & Jerry ".to_string()),
+            site_name: Some("Example".to_string()),
+            ..Default::default()
+        };
+        let mut entry = DigestEntry::new(
+            Url::parse("https://example.com/a?b=1&c=2").unwrap(),
+            Some("$event".to_string()),
+            Some(&meta),
+        );
+        entry.thumbnail = Some("mxc://example.com/thumb".to_string());
+        let entries = [
+            entry,
+            DigestEntry::new(
+                Url::parse("https://xn--bcher-kva.example/").unwrap(),
+                None,
+                None,
+            ),
+        ];
+        let (plain, html) = render("Links from 22:00-07:00", "!room:example.com", &entries);
+        assert_eq!(
+            plain,
+            "Links from 22:00-07:00\n\
+             - Tom & Jerry (Example): https://example.com/a?b=1&c=2\n\
+             - https://bücher.example/\n"
+        );
+        assert_eq!(
+            html,
+            "<p><strong>Links from 22:00-07:00</strong></p>\n<ul>\n\
+             <li><img src=\"mxc://example.com/thumb\" alt=\"\" height=\"48\"/> \
+             <a href=\"https://example.com/a?b=1&amp;c=2\">Tom &amp; Jerry</a> (Example) · \
+             <a href=\"https://matrix.to/#/!room:example.com/$event\">message</a></li>\n\
+             <li><a href=\"https://xn--bcher-kva.example/\">https://bücher.example/</a></li>\n\
+             </ul>"
+        );
+    }
+}
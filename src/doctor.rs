@@ -0,0 +1,184 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::process::Command;
+
+use crate::check::Report;
+use crate::config::{Config, StoreBackend};
+use crate::process;
+
+/// How long a program may take to print its version.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The optional Cargo features, and whether this build includes each.
+pub const FEATURES: &[(&str, bool)] = &[
+    ("extractors", cfg!(feature = "extractors")),
+    ("http-api", cfg!(feature = "http-api")),
+    ("media", cfg!(feature = "media")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("postgres", cfg!(feature = "postgres")),
+    ("redis", cfg!(feature = "redis")),
+    ("sentry", cfg!(feature = "sentry")),
+];
+
+/// Whether this build includes `feature`.
+fn has_feature(feature: &str) -> bool {
+    FEATURES
+        .iter()
+        .any(|&(name, enabled)| name == feature && enabled)
+}
+
+/// Report which features this build includes, whether the programs they run
+/// are installed, and anything in `config` that needs what's missing.
+pub async fn doctor(config: &Config) -> Report {
+    let mut report = Report::default();
+    let (enabled, disabled): (Vec<_>, Vec<_>) = FEATURES.iter().partition(|&&(_, enabled)| enabled);
+    let names = |features: &[&(&str, bool)]| {
+        features
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    report
+        .lines
+        .push(format!("Built with: {}", names(&enabled)));
+    if !disabled.is_empty() {
+        report
+            .lines
+            .push(format!("Built without: {}", names(&disabled)));
+    }
+
+    for program in ["ffmpeg", "ffprobe"] {
+        match version(program).await {
+            Some(version) => report.lines.push(version),
+            None if has_feature("media") => report.problems.push(format!(
+                "{} isn't installed or doesn't run; videos will be posted without thumbnails or conversion",
+                program
+            )),
+            // Nothing would run it anyway.
+            None => report.lines.push(format!(
+                "{} isn't installed, which is fine without the `media` feature",
+                program
+            )),
+        }
+    }
+    if let Some(command) = &config.transcription_command {
+        match find_program(command) {
+            Some(path) => report
+                .lines
+                .push(format!("Transcription command: {}", path.display())),
+            None => report.problems.push(format!(
+                "Transcription command {} wasn't found",
+                command.display()
+            )),
+        }
+    }
+
+    let needs = [
+        (
+            config.transcription_command.is_some() || config.transcription_api_url.is_some(),
+            "Transcription",
+            "media",
+        ),
+        (
+            config.health_listen_address.is_some(),
+            "--health-listen-address",
+            "http-api",
+        ),
+        (
+            config.shared_store == StoreBackend::Redis,
+            "--shared-store redis",
+            "redis",
+        ),
+        (
+            config.shared_store == StoreBackend::Postgres,
+            "--shared-store postgres",
+            "postgres",
+        ),
+        (config.sentry_dsn.is_some(), "--sentry-dsn", "sentry"),
+    ];
+    for (configured, option, feature) in needs {
+        if configured && !has_feature(feature) {
+            report.problems.push(format!(
+                "{} needs the `{}` feature, which this build doesn't include",
+                option, feature
+            ));
+        }
+    }
+    report
+}
+
+/// The first line `program -version` prints, or `None` if it doesn't run.
+async fn version(program: &str) -> Option<String> {
+    let output = process::output(
+        Command::new(program)
+            .arg("-version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()),
+        VERSION_TIMEOUT,
+        &[],
+    )
+    .await
+    .ok()
+    .filter(|output| output.status.success())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.lines().next().map(|line| line.trim().to_string())
+}
+
+/// Where `command` is: itself if it's a path to a file, otherwise the first
+/// file of that name in a `PATH` directory.
+fn find_program(command: &Path) -> Option<PathBuf> {
+    if command.components().count() > 1 {
+        return command.is_file().then(|| command.to_path_buf());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(command))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_program() {
+        assert!(find_program(Path::new("sh")).is_some());
+        assert!(find_program(Path::new("/bin/sh")).is_some());
+        assert_eq!(find_program(Path::new("no-such-program-here")), None);
+        assert_eq!(find_program(Path::new("/no/such/program")), None);
+    }
+
+    #[tokio::test]
+    async fn test_doctor() {
+        let report = doctor(&Config::default()).await;
+        assert!(report.lines[0].starts_with("Built with: "));
+        assert!(
+            !report
+                .problems
+                .iter()
+                .any(|problem| problem.contains("feature"))
+        );
+
+        let config = Config {
+            transcription_command: Some(PathBuf::from("/no/such/whisper-cli")),
+            sentry_dsn: Some("https://key@sentry.example.com/1".to_string()),
+            ..Config::default()
+        };
+        let report = doctor(&config).await;
+        assert!(
+            report
+                .problems
+                .contains(&"Transcription command /no/such/whisper-cli wasn't found".to_string())
+        );
+        assert_eq!(
+            report.problems.contains(
+                &"--sentry-dsn needs the `sentry` feature, which this build doesn't include"
+                    .to_string()
+            ),
+            !cfg!(feature = "sentry")
+        );
+    }
+}
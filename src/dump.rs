@@ -0,0 +1,389 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use url::Url;
+
+use crate::debug_room::Stage;
+use crate::error::EmbedError;
+use crate::fixtures;
+
+/// Most responses kept in one dump. Later ones are only counted.
+const MAX_RESPONSES: usize = 16;
+
+/// Most commands kept in one dump. Later ones are only counted.
+const MAX_COMMANDS: usize = 8;
+
+/// Longest stderr kept from one command.
+const MAX_STDERR_BYTES: usize = 16 * 1024;
+
+/// Most dumps kept in the dump directory. The oldest are removed first.
+const MAX_DUMPS: usize = 100;
+
+/// Headers whose values are never written to a dump.
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "x-api-key",
+    "x-csrf-token",
+];
+
+const REDACTED: &str = "[redacted]";
+
+/// Query parameters and similar `key=value` pairs that carry credentials,
+/// like `access_token` or the signature of a presigned S3 URL.
+static SECRET_PARAM_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?i)\b((?:access_token|api_?key|apikey|auth|key|password|secret|session|sig|signature|token|x-amz-credential|x-amz-security-token|x-amz-signature)=)[^&#\s"'<>]+"#,
+    )
+    .expect("valid regex")
+});
+
+tokio::task_local! {
+    static TRACE: Arc<Mutex<Trace>>;
+}
+
+/// What happened while embedding a link, kept in case it fails.
+#[derive(Debug)]
+pub struct Trace {
+    max_body_bytes: usize,
+    stages: Vec<(Stage, Instant)>,
+    responses: Vec<Response>,
+    commands: Vec<CommandOutput>,
+    dropped: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// The start of the body, if it was read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    /// Size of the whole body, before it was cut down to fit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_bytes: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandOutput {
+    program: String,
+    success: bool,
+    stderr: String,
+}
+
+#[derive(Debug, Serialize)]
+struct StageTiming {
+    stage: &'static str,
+    millis: u128,
+}
+
+/// The bundle written to the dump directory.
+#[derive(Debug, Serialize)]
+struct Dump<'a> {
+    id: &'a str,
+    created_at: String,
+    room_id: &'a str,
+    url: String,
+    stage: Option<&'static str>,
+    /// The [`EmbedError`] label of `error`.
+    kind: &'static str,
+    error: String,
+    stages: Vec<StageTiming>,
+    responses: &'a [Response],
+    commands: &'a [CommandOutput],
+    /// Responses and commands left out to keep the dump small.
+    dropped: usize,
+}
+
+impl Trace {
+    fn new(max_body_bytes: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            max_body_bytes,
+            // Every embed starts with its metadata, like jobs do.
+            stages: vec![(Stage::Metadata, now)],
+            responses: Vec::new(),
+            commands: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    /// How long each stage took, in the order they ran.
+    fn stage_timings(&self, end: Instant) -> Vec<StageTiming> {
+        let ends = self
+            .stages
+            .iter()
+            .skip(1)
+            .map(|(_, at)| *at)
+            .chain(std::iter::once(end));
+        self.stages
+            .iter()
+            .zip(ends)
+            .map(|((stage, started), ended)| StageTiming {
+                stage: stage.label(),
+                millis: ended.saturating_duration_since(*started).as_millis(),
+            })
+            .collect()
+    }
+}
+
+/// Run `fut`, keeping a [`Trace`] of what it fetched and ran if
+/// `max_body_bytes` is set. Bodies are cut down to that many bytes.
+pub async fn capture<F: Future>(
+    max_body_bytes: Option<usize>,
+    fut: F,
+) -> (F::Output, Option<Trace>) {
+    let Some(max_body_bytes) = max_body_bytes else {
+        return (fut.await, None);
+    };
+    let trace = Arc::new(Mutex::new(Trace::new(max_body_bytes)));
+    let output = TRACE.scope(trace.clone(), fut).await;
+    let trace = std::mem::replace(&mut *trace.lock().unwrap(), Trace::new(0));
+    (output, Some(trace))
+}
+
+fn with_trace(f: impl FnOnce(&mut Trace)) {
+    let _ = TRACE.try_with(|trace| f(&mut trace.lock().unwrap()));
+}
+
+/// Note that the embed moved on to `stage`.
+pub fn record_stage(stage: Stage) {
+    with_trace(|trace| trace.stages.push((stage, Instant::now())));
+}
+
+/// Keep the status and headers of `response`, with secrets removed.
+pub fn record_response(response: &reqwest::Response) {
+    with_trace(|trace| {
+        if trace.responses.len() >= MAX_RESPONSES {
+            trace.dropped += 1;
+            return;
+        }
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                    REDACTED.to_string()
+                } else {
+                    scrub(&String::from_utf8_lossy(value.as_bytes()))
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+        trace.responses.push(Response {
+            url: scrub(response.url().as_str()),
+            status: response.status().as_u16(),
+            headers,
+            body: None,
+            body_bytes: None,
+        });
+    });
+}
+
+/// Keep the start of `body` as the body of the last response passed to
+/// [`record_response`], scrubbed of scripts and secrets.
+pub fn record_body(body: &[u8]) {
+    with_trace(|trace| {
+        let max_body_bytes = trace.max_body_bytes;
+        let Some(response) = trace.responses.last_mut() else {
+            return;
+        };
+        let content_type = response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "content-type")
+            .map_or("", |(_, value)| value.as_str());
+        let start = String::from_utf8_lossy(&body[..body.len().min(max_body_bytes)]);
+        response.body = Some(scrub(&fixtures::scrub(&start, content_type)));
+        response.body_bytes = Some(body.len());
+    });
+}
+
+/// Keep the stderr of an external command like ffmpeg.
+pub fn record_command(program: &str, output: &std::process::Output) {
+    with_trace(|trace| {
+        if trace.commands.len() >= MAX_COMMANDS {
+            trace.dropped += 1;
+            return;
+        }
+        let stderr = &output.stderr[output.stderr.len().saturating_sub(MAX_STDERR_BYTES)..];
+        trace.commands.push(CommandOutput {
+            program: program.to_string(),
+            success: output.status.success(),
+            stderr: scrub(&String::from_utf8_lossy(stderr)),
+        });
+    });
+}
+
+/// Replace the values of credentials in `text` with a placeholder.
+fn scrub(text: &str) -> String {
+    SECRET_PARAM_RE
+        .replace_all(text, format!("${{1}}{}", REDACTED))
+        .into_owned()
+}
+
+/// Write `trace` of the failed embed of `url` in `room_id` to `dir`, and
+/// return the ID it can be found by.
+pub async fn write(
+    dir: &Path,
+    trace: Trace,
+    room_id: &str,
+    url: &Url,
+    stage: Option<Stage>,
+    error: &anyhow::Error,
+) -> Result<String> {
+    let now = chrono::Utc::now();
+    let id = format!(
+        "{}-{:08x}",
+        now.format("%Y%m%dT%H%M%SZ"),
+        rand::random::<u32>()
+    );
+    let dump = Dump {
+        id: &id,
+        created_at: now.to_rfc3339(),
+        room_id,
+        url: scrub(url.as_str()),
+        stage: stage.map(Stage::label),
+        kind: EmbedError::of(error).label(),
+        error: scrub(&format!("{:?}", error)),
+        stages: trace.stage_timings(Instant::now()),
+        responses: &trace.responses,
+        commands: &trace.commands,
+        dropped: trace.dropped,
+    };
+    let json = serde_json::to_vec_pretty(&dump).context("Failed to serialize debug dump")?;
+    let path = dir.join(format!("{}.json", id));
+    let dir = dir.to_owned();
+    tokio::task::spawn_blocking(move || {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        std::fs::write(&path, json)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        remove_old_dumps(&dir, MAX_DUMPS);
+        anyhow::Ok(())
+    })
+    .await
+    .context("Debug dump task panicked")??;
+    Ok(id)
+}
+
+/// Remove the oldest dumps in `dir` until at most `keep` are left. Dumps are
+/// named by when they were written, so the oldest sort first.
+fn remove_old_dumps(dir: &Path, keep: usize) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut dumps: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    if dumps.len() <= keep {
+        return;
+    }
+    dumps.sort();
+    for path in &dumps[..dumps.len() - keep] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_scrub() {
+        assert_eq!(
+            scrub("https://cdn.example.com/v.mp4?X-Amz-Signature=abc123&size=large"),
+            "https://cdn.example.com/v.mp4?X-Amz-Signature=[redacted]&size=large"
+        );
+        assert_eq!(
+            scrub(r#"<a href="/feed?access_token=s3cr3t">feed</a>"#),
+            r#"<a href="/feed?access_token=[redacted]">feed</a>"#
+        );
+        // Keys that merely end in a secret-sounding word are left alone.
+        assert_eq!(scrub("monkey=banana"), "monkey=banana");
+    }
+
+    #[test]
+    fn test_stage_timings() {
+        let mut trace = Trace::new(1024);
+        let start = trace.stages[0].1;
+        trace
+            .stages
+            .push((Stage::Media, start + Duration::from_millis(30)));
+        let timings = trace.stage_timings(start + Duration::from_millis(100));
+        let timings: Vec<_> = timings.iter().map(|t| (t.stage, t.millis)).collect();
+        assert_eq!(timings, vec![("metadata", 30), ("media", 70)]);
+    }
+
+    #[tokio::test]
+    async fn test_capture_and_write() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let ((), trace) = capture(Some(8), async {
+            record_stage(Stage::Media);
+            record_command(
+                "ffmpeg",
+                &std::process::Output {
+                    status: std::process::ExitStatus::default(),
+                    stdout: Vec::new(),
+                    stderr: b"Invalid data found when processing input".to_vec(),
+                },
+            );
+        })
+        .await;
+        let trace = trace.unwrap();
+        assert_eq!(trace.stages.len(), 2);
+        assert_eq!(trace.commands.len(), 1);
+
+        let url = Url::parse("https://example.com/?token=abc").unwrap();
+        let error = anyhow::anyhow!("ffmpeg failed");
+        let id = write(
+            dir.path(),
+            trace,
+            "!room:example.com",
+            &url,
+            Some(Stage::Media),
+            &error,
+        )
+        .await
+        .unwrap();
+        let dump: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(dir.path().join(format!("{}.json", id))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(dump["url"], "https://example.com/?token=[redacted]");
+        assert_eq!(dump["stage"], "media");
+        assert_eq!(dump["kind"], "other");
+        assert_eq!(dump["commands"][0]["program"], "ffmpeg");
+
+        // Without a body limit, nothing is kept.
+        let ((), trace) = capture(None, async { record_stage(Stage::Media) }).await;
+        assert!(trace.is_none());
+    }
+
+    #[test]
+    fn test_remove_old_dumps() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in [
+            "20250101T000000Z-1",
+            "20250102T000000Z-2",
+            "20250103T000000Z-3",
+        ] {
+            std::fs::write(dir.path().join(format!("{}.json", name)), b"{}").unwrap();
+        }
+        remove_old_dumps(dir.path(), 2);
+        assert!(!dir.path().join("20250101T000000Z-1.json").exists());
+        assert!(dir.path().join("20250102T000000Z-2.json").exists());
+        assert!(dir.path().join("20250103T000000Z-3.json").exists());
+    }
+}
@@ -0,0 +1,183 @@
+use crate::metadata::Emote;
+
+/// Height in pixels emotes are shown at, about that of a line of text.
+const EMOTE_HEIGHT: u32 = 32;
+
+/// Remove the shortcodes of `emotes` from `text`, along with a space next to
+/// each so no gaps are left.
+pub fn strip(text: &str, emotes: &[Emote]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((before, _, after)) = next_shortcode(rest, emotes) {
+        out.push_str(before);
+        if out.is_empty() || out.ends_with(char::is_whitespace) {
+            rest = after.strip_prefix(' ').unwrap_or(after);
+        } else {
+            rest = after;
+        }
+        if rest.is_empty() {
+            out.truncate(out.trim_end_matches(' ').len());
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Like [`strip`], but for the text of `html`. Tags and their attributes
+/// are left alone.
+pub fn strip_html(html: &str, emotes: &[Emote]) -> String {
+    map_text(html, |text| strip(text, emotes))
+}
+
+/// Replace the shortcodes of uploaded `emotes` in the text of `html` with
+/// inline emote images. Tags and their attributes are left alone.
+pub fn inline_html(html: &str, emotes: &[Emote]) -> String {
+    let uploaded: Vec<Emote> = emotes
+        .iter()
+        .filter(|emote| emote.mxc_uri.is_some())
+        .cloned()
+        .collect();
+    if uploaded.is_empty() {
+        return html.to_string();
+    }
+    map_text(html, |text| inline_text(text, &uploaded))
+}
+
+/// Apply `f` to the text between the tags of `html`.
+fn map_text(html: &str, f: impl Fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while !rest.is_empty() {
+        // Text up to the next tag, then the tag itself.
+        let text_end = rest.find('<').unwrap_or(rest.len());
+        out.push_str(&f(&rest[..text_end]));
+        rest = &rest[text_end..];
+        let tag_end = tag_len(rest);
+        out.push_str(&rest[..tag_end]);
+        rest = &rest[tag_end..];
+    }
+    out
+}
+
+/// Replace the shortcodes of `emotes` in escaped HTML text with images.
+fn inline_text(text: &str, emotes: &[Emote]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((before, emote, after)) = next_shortcode(rest, emotes) {
+        out.push_str(before);
+        let shortcode = html_escape::encode_double_quoted_attribute(&emote.shortcode);
+        out.push_str(&format!(
+            "<img data-mx-emoticon src=\"{}\" alt=\":{}:\" title=\":{}:\" height=\"{}\"/>",
+            html_escape::encode_double_quoted_attribute(emote.mxc_uri.as_deref().unwrap_or("")),
+            shortcode,
+            shortcode,
+            EMOTE_HEIGHT
+        ));
+        rest = after;
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Length of the tag at the start of `html`, up to and including its `>`,
+/// skipping over any `>` in quoted attribute values.
+fn tag_len(html: &str) -> usize {
+    let mut quote = None;
+    for (i, ch) in html.char_indices() {
+        match (quote, ch) {
+            (None, '"' | '\'') => quote = Some(ch),
+            (Some(q), _) if ch == q => quote = None,
+            (None, '>') => return i + 1,
+            _ => {}
+        }
+    }
+    html.len()
+}
+
+/// Find the first shortcode of one of `emotes` in `text`, returning the text
+/// before it, the emote and the text after it.
+fn next_shortcode<'a, 'e>(
+    text: &'a str,
+    emotes: &'e [Emote],
+) -> Option<(&'a str, &'e Emote, &'a str)> {
+    let mut search_from = 0;
+    while let Some(offset) = text[search_from..].find(':') {
+        let start = search_from + offset;
+        let name_start = start + 1;
+        let name_len = text[name_start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(text.len() - name_start);
+        let end = name_start + name_len;
+        if name_len > 0
+            && text[end..].starts_with(':')
+            && let Some(emote) = emotes
+                .iter()
+                .find(|emote| emote.shortcode == text[name_start..end])
+        {
+            return Some((&text[..start], emote, &text[end + 1..]));
+        }
+        search_from = name_start;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    fn emote(shortcode: &str, mxc_uri: Option<&str>) -> Emote {
+        Emote {
+            shortcode: shortcode.to_string(),
+            url: Url::parse(&format!("https://example.com/emoji/{}.png", shortcode)).unwrap(),
+            mxc_uri: mxc_uri.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_strip() {
+        let emotes = [emote("blobcat", None), emote("wave", None)];
+        assert_eq!(
+            strip("Alice :blobcat: (@alice@example.com)", &emotes),
+            "Alice (@alice@example.com)"
+        );
+        assert_eq!(strip(":wave: hello :blobcat:", &emotes), "hello");
+        assert_eq!(strip("hi:wave::blobcat:!", &emotes), "hi!");
+        // Only known emotes are shortcodes; times and the like are left be.
+        assert_eq!(
+            strip("at 12:30:00 :unknown: :wave:", &emotes),
+            "at 12:30:00 :unknown:"
+        );
+    }
+
+    #[test]
+    fn test_strip_html() {
+        let emotes = [emote("blobcat", None)];
+        assert_eq!(
+            strip_html(
+                r#"<p>hi :blobcat:</p><p><img alt=":blobcat:"> there</p>"#,
+                &emotes
+            ),
+            r#"<p>hi</p><p><img alt=":blobcat:"> there</p>"#
+        );
+    }
+
+    #[test]
+    fn test_inline_html() {
+        let emotes = [
+            emote("blobcat", Some("mxc://example.com/abc")),
+            emote("wave", None),
+        ];
+        assert_eq!(
+            inline_html(
+                r#"<strong>Alice :blobcat:</strong><p><a href="https://example.com/:blobcat:">:wave: :blobcat:</a></p>"#,
+                &emotes
+            ),
+            r#"<strong>Alice <img data-mx-emoticon src="mxc://example.com/abc" alt=":blobcat:" title=":blobcat:" height="32"/></strong><p><a href="https://example.com/:blobcat:">:wave: <img data-mx-emoticon src="mxc://example.com/abc" alt=":blobcat:" title=":blobcat:" height="32"/></a></p>"#
+        );
+        assert_eq!(
+            inline_html("<p>:wave:</p>", &[emote("wave", None)]),
+            "<p>:wave:</p>"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use crate::decompress::ExcessiveCompression;
+use crate::processing::{DisallowedMediaType, DownloadTooSlow, FileTooLarge, UnexpectedContent};
+
+/// What kind of failure stopped an embed, or part of one.
+///
+/// Failures that don't already have an error type of their own are tagged
+/// with this as context where they happen; [`EmbedError::of`] finds the tag,
+/// or works the kind out from the error types along the chain. Metrics,
+/// retries and debug room notices all go by this, so they agree on what went
+/// wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbedError {
+    NetworkTimeout,
+    ConnectionFailed,
+    /// A request failed some other way, like too many redirects.
+    RequestFailed,
+    /// The server answered with a client error status.
+    HttpStatus,
+    /// The server answered with a server error status.
+    ServerError,
+    /// The server or the homeserver asked us to slow down.
+    RateLimited,
+    /// The response couldn't be read or decoded.
+    BadResponse,
+    TooLarge,
+    TooSlow,
+    ExcessiveCompression,
+    /// The media type isn't one we're allowed to post.
+    UnsupportedMedia,
+    /// The response wasn't the kind of media it was supposed to be.
+    UnexpectedContent,
+    /// ffmpeg or ffprobe failed or timed out.
+    FfmpegFailed,
+    /// The homeserver refused an upload.
+    UploadRejected,
+    Other,
+}
+
+impl EmbedError {
+    pub const ALL: [EmbedError; 15] = [
+        EmbedError::NetworkTimeout,
+        EmbedError::ConnectionFailed,
+        EmbedError::RequestFailed,
+        EmbedError::HttpStatus,
+        EmbedError::ServerError,
+        EmbedError::RateLimited,
+        EmbedError::BadResponse,
+        EmbedError::TooLarge,
+        EmbedError::TooSlow,
+        EmbedError::ExcessiveCompression,
+        EmbedError::UnsupportedMedia,
+        EmbedError::UnexpectedContent,
+        EmbedError::FfmpegFailed,
+        EmbedError::UploadRejected,
+        EmbedError::Other,
+    ];
+
+    /// The kind of failure `error` is.
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(kind) = error.downcast_ref::<EmbedError>() {
+            return *kind;
+        }
+        if error.is::<FileTooLarge>() {
+            return EmbedError::TooLarge;
+        }
+        if error.is::<ExcessiveCompression>() {
+            return EmbedError::ExcessiveCompression;
+        }
+        if error.is::<DownloadTooSlow>() {
+            return EmbedError::TooSlow;
+        }
+        if error.is::<DisallowedMediaType>() {
+            return EmbedError::UnsupportedMedia;
+        }
+        if error.is::<UnexpectedContent>() {
+            return EmbedError::UnexpectedContent;
+        }
+        match error
+            .chain()
+            .find_map(|e| e.downcast_ref::<reqwest::Error>())
+        {
+            Some(e) => Self::of_request(e),
+            None => EmbedError::Other,
+        }
+    }
+
+    fn of_request(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            return EmbedError::NetworkTimeout;
+        }
+        if let Some(status) = error.status() {
+            return if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                EmbedError::RateLimited
+            } else if status.is_server_error() {
+                EmbedError::ServerError
+            } else {
+                EmbedError::HttpStatus
+            };
+        }
+        if error.is_connect() {
+            EmbedError::ConnectionFailed
+        } else if error.is_decode() || error.is_body() {
+            EmbedError::BadResponse
+        } else {
+            EmbedError::RequestFailed
+        }
+    }
+
+    /// Whether trying again a little later might work.
+    pub fn is_transient(self) -> bool {
+        matches!(
+            self,
+            EmbedError::NetworkTimeout
+                | EmbedError::ConnectionFailed
+                | EmbedError::ServerError
+                | EmbedError::RateLimited
+        )
+    }
+
+    /// The name used for this kind in metrics and debug dumps.
+    pub fn label(self) -> &'static str {
+        match self {
+            EmbedError::NetworkTimeout => "network_timeout",
+            EmbedError::ConnectionFailed => "connection_failed",
+            EmbedError::RequestFailed => "request_failed",
+            EmbedError::HttpStatus => "http_status",
+            EmbedError::ServerError => "server_error",
+            EmbedError::RateLimited => "rate_limited",
+            EmbedError::BadResponse => "bad_response",
+            EmbedError::TooLarge => "too_large",
+            EmbedError::TooSlow => "too_slow",
+            EmbedError::ExcessiveCompression => "excessive_compression",
+            EmbedError::UnsupportedMedia => "unsupported_media",
+            EmbedError::UnexpectedContent => "unexpected_content",
+            EmbedError::FfmpegFailed => "ffmpeg_failed",
+            EmbedError::UploadRejected => "upload_rejected",
+            EmbedError::Other => "other",
+        }
+    }
+}
+
+impl std::fmt::Display for EmbedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            EmbedError::NetworkTimeout => "timeout",
+            EmbedError::ConnectionFailed => "connection failed",
+            EmbedError::RequestFailed => "request failed",
+            EmbedError::HttpStatus => "HTTP error",
+            EmbedError::ServerError => "server error",
+            EmbedError::RateLimited => "rate limited",
+            EmbedError::BadResponse => "bad response",
+            EmbedError::TooLarge => "too large",
+            EmbedError::TooSlow => "too slow",
+            EmbedError::ExcessiveCompression => "excessive compression",
+            EmbedError::UnsupportedMedia => "unsupported media",
+            EmbedError::UnexpectedContent => "unexpected content",
+            EmbedError::FfmpegFailed => "ffmpeg failed",
+            EmbedError::UploadRejected => "upload rejected",
+            EmbedError::Other => "other",
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_of() {
+        let err = anyhow::Error::new(FileTooLarge {
+            size: 1,
+            streamed: false,
+        })
+        .context("Failed to download");
+        assert_eq!(EmbedError::of(&err), EmbedError::TooLarge);
+
+        // A tag wins over what's underneath it.
+        let err: anyhow::Result<()> = Err(anyhow::anyhow!("ffmpeg failed: boom"))
+            .context("Remux failed")
+            .context(EmbedError::FfmpegFailed);
+        let err = err.unwrap_err();
+        assert_eq!(EmbedError::of(&err), EmbedError::FfmpegFailed);
+        assert_eq!(
+            format!("{:#}", err),
+            "ffmpeg failed: Remux failed: ffmpeg failed: boom"
+        );
+
+        let err: anyhow::Result<()> = Err(anyhow::anyhow!("boom")).context("Failed");
+        assert_eq!(EmbedError::of(&err.unwrap_err()), EmbedError::Other);
+    }
+
+    #[tokio::test]
+    async fn test_of_status() {
+        use wiremock::matchers::path;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        for (status, route) in [(429, "/busy"), (503, "/down"), (404, "/gone")] {
+            Mock::given(path(route))
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&server)
+                .await;
+        }
+        let client = reqwest::Client::new();
+        for (route, kind) in [
+            ("/busy", EmbedError::RateLimited),
+            ("/down", EmbedError::ServerError),
+            ("/gone", EmbedError::HttpStatus),
+        ] {
+            let err = client
+                .get(format!("{}{}", server.uri(), route))
+                .send()
+                .await
+                .unwrap()
+                .error_for_status()
+                .context("Failed to fetch")
+                .unwrap_err();
+            assert_eq!(EmbedError::of(&err), kind);
+        }
+        assert!(EmbedError::RateLimited.is_transient());
+        assert!(!EmbedError::HttpStatus.is_transient());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::config::Config;
+use crate::http;
+use crate::redirect;
 use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
 use scraper::{Html, Selector};
 use std::{collections::HashSet, sync::LazyLock};
@@ -14,6 +16,147 @@ static QUOTED_LINKS: LazyLock<Selector> =
 /// for plain-text URLs (i.e. URLs not wrapped in an <a> tag).
 static QUOTED_REPLY: LazyLock<Selector> = LazyLock::new(|| Selector::parse("mx-reply").unwrap());
 
+/// Prefixes that start a link in message text.
+const LINK_SCHEMES: &[&str] = &["http://", "https://", "geo:"];
+
+/// Schemes that can be embedded. Links with other schemes need a URL rewrite
+/// to one of these, e.g. to a web gateway.
+const EMBEDDABLE_SCHEMES: &[&str] = &["http", "https", "geo"];
+
+/// Characters that can't be part of a link in message text, so they end one.
+const LINK_TERMINATORS: &[char] = &[
+    '<', '>', '"', '“', '”', '„', '«', '»', '「', '」', '『', '』',
+];
+
+/// Brackets a link may be wrapped in, or contain in balanced pairs (as in
+/// Wikipedia URLs).
+const BRACKETS: &[(char, char)] = &[
+    ('(', ')'),
+    ('[', ']'),
+    ('{', '}'),
+    ('（', '）'),
+    ('［', '］'),
+    ('【', '】'),
+];
+
+/// Trailing characters that end a sentence rather than a URL.
+const TRAILING_PUNCTUATION: &[char] = &[
+    '.', ',', ':', ';', '!', '?', '\'', '"', '…', '’', '”', '»', '。', '、', '，', '：', '；',
+    '！', '？',
+];
+
+/// Find links in plain message text.
+///
+/// Links are found wherever a scheme or `www.` starts that isn't part of a
+/// longer word, so Markdown links and links glued to punctuation are found
+/// too. Sentence punctuation after a link and brackets wrapped around it are
+/// trimmed, while balanced brackets inside it (as in Wikipedia URLs) are kept.
+/// Links wrapped in angle brackets are skipped, like Discord does, so users
+/// can opt out of embeds. With `bare_www`, `www.` links without a scheme are
+/// found too and get `https://` prepended. Links with `extra_schemes` (like
+/// `gemini`) are found as well.
+fn find_links(text: &str, bare_www: bool, extra_schemes: &[String]) -> Vec<String> {
+    let mut prefixes: Vec<String> = LINK_SCHEMES.iter().map(|s| s.to_string()).collect();
+    prefixes.extend(
+        extra_schemes
+            .iter()
+            .map(|scheme| format!("{}://", scheme.to_ascii_lowercase())),
+    );
+    if bare_www {
+        prefixes.push("www.".to_string());
+    }
+
+    // Prefixes are ASCII, so byte offsets in `lower` are the same as in
+    // `text`.
+    let lower = text.to_ascii_lowercase();
+    let mut links = Vec::new();
+    let mut pos = 0;
+    while let Some((start, prefix)) = prefixes
+        .iter()
+        .filter_map(|prefix| find_link_start(text, &lower, pos, prefix).map(|i| (i, prefix)))
+        .min_by_key(|&(i, _)| i)
+    {
+        let end = start + link_len(&text[start..]);
+        pos = end;
+        if text[..start].ends_with('<') {
+            continue;
+        }
+
+        let link = trim_link_end(&text[start..end]);
+        if prefix == "www." {
+            if link.len() > "www.".len() && link[4..].contains('.') {
+                links.push(format!("https://{}", link));
+            }
+        } else {
+            links.push(link.to_string());
+        }
+    }
+    links
+}
+
+/// The first offset from `pos` on where `prefix` starts a link in `text`,
+/// i.e. isn't the end of a longer word (or, for `www.`, of a host name).
+fn find_link_start(text: &str, lower: &str, mut pos: usize, prefix: &str) -> Option<usize> {
+    loop {
+        let start = pos + lower[pos..].find(prefix)?;
+        let starts_link = text[..start].chars().next_back().is_none_or(|c| {
+            !(c.is_alphanumeric() || c == '_' || prefix == "www." && ".-/@".contains(c))
+        });
+        if starts_link {
+            return Some(start);
+        }
+        // The prefix starts with an ASCII character, so the next one is on
+        // a character boundary.
+        pos = start + 1;
+    }
+}
+
+/// The length of the link at the start of `text`: up to whitespace, a
+/// character that can't be in a link, or a closing bracket that wasn't
+/// opened in it.
+fn link_len(text: &str) -> usize {
+    let mut open = Vec::new();
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() || LINK_TERMINATORS.contains(&c) {
+            return i;
+        }
+        if let Some(&(_, close)) = BRACKETS.iter().find(|&&(open, _)| open == c) {
+            open.push(close);
+        } else if BRACKETS.iter().any(|&(_, close)| close == c) && open.pop() != Some(c) {
+            return i;
+        }
+    }
+    text.len()
+}
+
+/// Strip trailing punctuation from a link.
+fn trim_link_end(link: &str) -> &str {
+    link.trim_end_matches(TRAILING_PUNCTUATION)
+}
+
+/// Strip the rich-reply fallback from a plain-text body.
+///
+/// Replies from older clients start with the quoted message, e.g.
+/// `> <@user:example.com> https://…`, followed by an empty line. Only the text
+/// after it was actually typed by the sender. Quotes that don't start with a
+/// sender (i.e. ones the user wrote in Markdown) are left alone.
+fn strip_reply_fallback(body: &str) -> &str {
+    let Some(first) = body.lines().next() else {
+        return body;
+    };
+    if !(first.starts_with("> <") || first.starts_with("> * <")) {
+        return body;
+    }
+
+    let mut rest = body;
+    while let Some(line) = rest.lines().next()
+        && line.starts_with('>')
+    {
+        rest = rest[line.len()..].strip_prefix('\n').unwrap_or("");
+    }
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
 /// Extract URLs from the quoted message of a formatted body.
 ///
 /// Some Matrix clients embed a quoted message in the <mx-reply> in the body of
@@ -30,7 +173,11 @@ static QUOTED_REPLY: LazyLock<Selector> = LazyLock::new(|| Selector::parse("mx-r
 /// it should offer a pretty good workaround for the problem.
 ///
 /// So far this seems to only impact Fluffychat, but there might be others.
-fn extract_quoted_urls(formatted_body: &str) -> HashSet<Url> {
+fn extract_quoted_urls(
+    formatted_body: &str,
+    bare_www: bool,
+    extra_schemes: &[String],
+) -> HashSet<Url> {
     let doc = Html::parse_fragment(formatted_body);
 
     // Collect URLs from <a href> tags within mx-reply.
@@ -43,50 +190,70 @@ fn extract_quoted_urls(formatted_body: &str) -> HashSet<Url> {
     // Collect verbatim URLs as well.
     for reply_el in doc.select(&QUOTED_REPLY) {
         for text in reply_el.text() {
-            for word in text.split_whitespace() {
-                if (word.starts_with("http://") || word.starts_with("https://"))
-                    && let Ok(url) = Url::parse(word)
-                {
-                    urls.insert(url);
-                }
-            }
+            urls.extend(
+                find_links(text, bare_www, extra_schemes)
+                    .iter()
+                    .filter_map(|link| Url::parse(link).ok()),
+            );
         }
     }
 
     urls
 }
 
-/// Extract a suitable URL to embed from the message. For now, this only ever
-/// extracts a single message.
-pub fn extract_url(text: &TextMessageEventContent, config: &Config) -> Option<Url> {
+/// The configured priority of `url`, from the most specific matching domain.
+fn link_priority(config: &Config, url: &Url) -> i32 {
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    config
+        .domain_priorities
+        .iter()
+        .filter(|(domain, _)| http::matches_domain(&host, domain))
+        .max_by_key(|(domain, _)| domain.len())
+        .map_or(0, |&(_, priority)| priority)
+}
+
+/// Extract the URLs that could be embedded from the message, highest domain
+/// priority first and otherwise in the order they appear, without repeats.
+/// `bare_www` is whether the room has `www.` links without a scheme enabled.
+pub fn extract_urls(text: &TextMessageEventContent, config: &Config, bare_www: bool) -> Vec<Url> {
     // Collect URLs from the formatted body's <mx-reply> so we can ignore
     // links that belong to the quoted message.
     let reply_urls = text
         .formatted
         .as_ref()
-        .map(|f| extract_quoted_urls(&f.body))
+        .map(|f| extract_quoted_urls(&f.body, bare_www, &config.extra_link_schemes))
         .unwrap_or_default();
 
-    for word in text.body.split_whitespace() {
-        if (word.starts_with("http://") || word.starts_with("https://"))
-            && let Ok(url) = Url::parse(word)
-        {
+    let body = strip_reply_fallback(&text.body);
+    let mut urls: Vec<Url> = Vec::new();
+    for link in find_links(body, bare_www, &config.extra_link_schemes) {
+        if let Ok(url) = Url::parse(&link) {
             if reply_urls.contains(&url) {
                 debug!("Skipping URL found in reply: {}", url);
                 continue;
             }
 
+            let url = redirect::unwrap(&url, &config.redirect_unwrap_rules);
+
             if config.is_url_ignored(&url) {
                 debug!("Ignoring URL (matched ignored pattern): {}", url);
                 continue;
             }
 
-            // Apply URL rewrites. Return first URL for now?
-            return Some(config.rewrite_url(&url));
+            let url = config.rewrite_url(&url);
+            if !EMBEDDABLE_SCHEMES.contains(&url.scheme()) {
+                debug!("No rewrite to an embeddable scheme for {}", url);
+                continue;
+            }
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
         }
     }
 
-    None
+    // The sort is stable, so links of the same priority keep their order.
+    urls.sort_by_key(|url| std::cmp::Reverse(link_priority(config, url)));
+    urls
 }
 
 #[cfg(test)]
@@ -95,21 +262,21 @@ mod tests {
 
     #[test]
     fn test_extract_quoted_urls_empty_string() {
-        let urls = extract_quoted_urls("");
+        let urls = extract_quoted_urls("", false, &[]);
         assert!(urls.is_empty());
     }
 
     #[test]
     fn test_extract_quoted_urls_no_mx_reply() {
         let html = r#"Hello <a href="https://example.com">link</a>"#;
-        let urls = extract_quoted_urls(html);
+        let urls = extract_quoted_urls(html, false, &[]);
         assert!(urls.is_empty());
     }
 
     #[test]
     fn test_extract_quoted_urls_single_url() {
         let html = r#"<mx-reply><blockquote><a href="https://matrix.to/#/@user:example.com">@user</a><br>Check out <a href="https://example.com/page">https://example.com/page</a></blockquote></mx-reply>My reply message"#;
-        let urls = extract_quoted_urls(html);
+        let urls = extract_quoted_urls(html, false, &[]);
         assert_eq!(urls.len(), 2);
         assert!(urls.contains(&Url::parse("https://matrix.to/#/@user:example.com").unwrap()));
         assert!(urls.contains(&Url::parse("https://example.com/page").unwrap()));
@@ -118,7 +285,7 @@ mod tests {
     #[test]
     fn test_extract_quoted_urls_ignores_links_outside_mx_reply() {
         let html = r#"<mx-reply><blockquote><a href="https://quoted.example.com">link</a></blockquote></mx-reply>See <a href="https://reply.example.com">this</a>"#;
-        let urls = extract_quoted_urls(html);
+        let urls = extract_quoted_urls(html, false, &[]);
         assert_eq!(urls.len(), 1);
         assert!(urls.contains(&Url::parse("https://quoted.example.com").unwrap()));
         assert!(!urls.contains(&Url::parse("https://reply.example.com").unwrap()));
@@ -127,7 +294,7 @@ mod tests {
     #[test]
     fn test_extract_quoted_urls_html_entities_decoded() {
         let html = r#"<mx-reply><blockquote><a href="https://example.com/search?a=1&amp;b=2">link</a></blockquote></mx-reply>"#;
-        let urls = extract_quoted_urls(html);
+        let urls = extract_quoted_urls(html, false, &[]);
         assert_eq!(urls.len(), 1);
         // It's important to make sure that the result we get has the HTML entities decoded.
         // This happens by virtue of parsing the HTML, so we don't actually need to do anything special to get this behavior.
@@ -139,7 +306,7 @@ mod tests {
     fn test_extract_quoted_urls_invalid_href_skipped() {
         // We don't want to crash just because a URL is invalid; let's just make sure we skip over them.
         let html = r#"<mx-reply><blockquote><a href="not a url">bad</a> and <a href="https://good.example.com">good</a></blockquote></mx-reply>"#;
-        let urls = extract_quoted_urls(html);
+        let urls = extract_quoted_urls(html, false, &[]);
         assert_eq!(urls.len(), 1);
         assert!(urls.contains(&Url::parse("https://good.example.com").unwrap()));
     }
@@ -147,76 +314,327 @@ mod tests {
     #[test]
     fn test_extract_quoted_urls_no_links_in_mx_reply() {
         let html = r#"<mx-reply><blockquote>Just plain text</blockquote></mx-reply>"#;
-        let urls = extract_quoted_urls(html);
+        let urls = extract_quoted_urls(html, false, &[]);
         assert!(urls.is_empty());
     }
 
     #[test]
     fn test_extract_quoted_urls_plain_text_url_no_anchor() {
         let html = r#"<mx-reply><blockquote><a href="https://matrix.to/#/!room/$event">In reply to</a> <a href="https://matrix.to/#/@user:matrix.org">@user:matrix.org</a><br>https:&#47;&#47;x.com&#47;user&#47;status&#47;1234567890123456789</blockquote></mx-reply>Reply"#;
-        let urls = extract_quoted_urls(html);
+        let urls = extract_quoted_urls(html, false, &[]);
         assert!(
             urls.contains(&Url::parse("https://x.com/user/status/1234567890123456789").unwrap())
         );
     }
 
     #[test]
-    fn test_extract_url_ignore_quoted_plain_text_url() {
+    fn test_extract_urls_ignore_quoted_plain_text_url() {
         assert_eq!(
-            extract_url(
+            extract_urls(
                 &TextMessageEventContent::html(
                     "> <@user:matrix.org> https://x.com/user/status/1234567890123456789\n\nReply",
                     r#"<mx-reply><blockquote><a href="https://matrix.to/#/!room/$event">In reply to</a> <a href="https://matrix.to/#/@user:matrix.org">@user:matrix.org</a><br>https:&#47;&#47;x.com&#47;user&#47;status&#47;1234567890123456789</blockquote></mx-reply>Reply"#
                 ),
                 &Default::default(),
+                false,
             ),
-            None
+            Vec::<Url>::new()
         );
     }
 
     #[test]
-    fn test_extract_url_empty_string() {
+    fn test_strip_reply_fallback() {
+        assert_eq!(
+            strip_reply_fallback(
+                "> <@user:example.com> https://quoted.example.com\n> second line\n\nhttps://new.example.com"
+            ),
+            "https://new.example.com"
+        );
+        assert_eq!(
+            strip_reply_fallback("> * <@user:example.com> waves\n\nhi"),
+            "hi"
+        );
+        // A quote typed by the user isn't a fallback.
         assert_eq!(
-            extract_url(&TextMessageEventContent::plain(""), &Default::default()),
-            None
+            strip_reply_fallback("> https://example.com\n\nthis"),
+            "> https://example.com\n\nthis"
         );
+        assert_eq!(strip_reply_fallback("> <@user:example.com> hi"), "");
     }
 
     #[test]
-    fn test_extract_url_basic_url() {
+    fn test_extract_urls_ignore_plain_reply_fallback() {
+        // No formatted body to tell us what was quoted.
         assert_eq!(
-            extract_url(
+            extract_urls(
+                &TextMessageEventContent::plain(
+                    "> <@user:example.com> https://quoted.example.com\n\nnice"
+                ),
+                &Default::default(),
+                false,
+            ),
+            Vec::<Url>::new()
+        );
+    }
+
+    #[test]
+    fn test_extract_urls_empty_string() {
+        assert_eq!(
+            extract_urls(
+                &TextMessageEventContent::plain(""),
+                &Default::default(),
+                false
+            ),
+            Vec::<Url>::new()
+        );
+    }
+
+    #[test]
+    fn test_extract_urls_basic_url() {
+        assert_eq!(
+            extract_urls(
                 &TextMessageEventContent::plain("https://example.com"),
                 &Default::default(),
+                false,
+            ),
+            vec![Url::parse("https://example.com").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_extract_urls_unwraps_redirects_before_rewriting() {
+        assert_eq!(
+            extract_urls(
+                &TextMessageEventContent::plain(
+                    "https://www.google.com/url?q=https://x.com/user/status/1&sa=D"
+                ),
+                &Default::default(),
+                false,
+            ),
+            vec![Url::parse("https://vxtwitter.com/user/status/1").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_find_links_trims_punctuation_and_brackets() {
+        assert_eq!(
+            find_links(
+                "see https://example.com/page. (also https://example.org/a)",
+                false,
+                &[]
+            ),
+            vec!["https://example.com/page", "https://example.org/a"]
+        );
+        assert_eq!(
+            find_links("\"https://example.com/?q=1\", right?", false, &[]),
+            vec!["https://example.com/?q=1"]
+        );
+        // Balanced brackets are part of the URL.
+        assert_eq!(
+            find_links(
+                "(https://en.wikipedia.org/wiki/Rust_(language))",
+                false,
+                &[]
+            ),
+            vec!["https://en.wikipedia.org/wiki/Rust_(language)"]
+        );
+        assert_eq!(
+            find_links("geo:48.2082,16.3738!", false, &[]),
+            vec!["geo:48.2082,16.3738"]
+        );
+        // Markdown links, and links glued to punctuation.
+        assert_eq!(
+            find_links(
+                "[docs](https://example.com/a) [https://example.com/x](https://example.com/y)",
+                false,
+                &[]
+            ),
+            vec![
+                "https://example.com/a",
+                "https://example.com/x",
+                "https://example.com/y"
+            ]
+        );
+        assert_eq!(
+            find_links(
+                "see:https://example.com/b foo,https://example.com/c",
+                false,
+                &[]
+            ),
+            vec!["https://example.com/b", "https://example.com/c"]
+        );
+        assert!(find_links("xhttps://example.com/ apogeo:1,2", false, &[]).is_empty());
+        // Unicode quotes, ellipses and brackets.
+        assert_eq!(
+            find_links(
+                "“https://example.com/d” https://example.com/e… ‘https://example.com/f’",
+                false,
+                &[]
+            ),
+            vec![
+                "https://example.com/d",
+                "https://example.com/e",
+                "https://example.com/f"
+            ]
+        );
+        assert_eq!(
+            find_links(
+                "「https://example.com/g」。（https://example.com/h）、«https://example.com/i»",
+                false,
+                &[]
+            ),
+            vec![
+                "https://example.com/g",
+                "https://example.com/h",
+                "https://example.com/i"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_links_skips_angle_brackets() {
+        assert!(find_links("<https://example.com/>", false, &[]).is_empty());
+        assert!(find_links("(<https://example.com/>)", false, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_find_links_bare_www() {
+        assert!(find_links("go to www.example.com.", false, &[]).is_empty());
+        assert_eq!(
+            find_links("go to www.example.com.", true, &[]),
+            vec!["https://www.example.com"]
+        );
+        assert!(find_links("www. is a prefix", true, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_find_links_extra_schemes() {
+        let text = "read gemini://example.org/post. or ipfs://bafy";
+        assert!(find_links(text, false, &[]).is_empty());
+        assert_eq!(
+            find_links(text, false, &["gemini".to_string(), "IPFS".to_string()]),
+            vec!["gemini://example.org/post", "ipfs://bafy"]
+        );
+    }
+
+    #[test]
+    fn test_extract_urls_extra_schemes() {
+        let config = Config {
+            extra_link_schemes: vec!["gemini".to_string(), "ftp".to_string()],
+            ..Default::default()
+        };
+        // Gemini links go through the default gateway rewrite.
+        assert_eq!(
+            extract_urls(
+                &TextMessageEventContent::plain("gemini://example.org/post"),
+                &config,
+                false,
+            ),
+            vec![Url::parse("https://portal.mozz.us/gemini/example.org/post").unwrap()]
+        );
+        // FTP links have no rewrite, so there's nothing to embed.
+        assert_eq!(
+            extract_urls(
+                &TextMessageEventContent::plain("ftp://example.org/file https://example.com"),
+                &config,
+                false,
+            ),
+            vec![Url::parse("https://example.com").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_extract_urls_domain_priorities() {
+        let text = TextMessageEventContent::plain(
+            "https://t.co/abc https://example.com/article https://i.mirror.example/a.png",
+        );
+        // Without priorities, links keep their order.
+        assert_eq!(
+            extract_urls(&text, &Default::default(), false),
+            vec![
+                Url::parse("https://t.co/abc").unwrap(),
+                Url::parse("https://example.com/article").unwrap(),
+                Url::parse("https://i.mirror.example/a.png").unwrap(),
+            ]
+        );
+        let config = Config {
+            domain_priorities: vec![
+                ("t.co".to_string(), -10),
+                ("mirror.example".to_string(), 5),
+                ("i.mirror.example".to_string(), -1),
+            ],
+            ..Default::default()
+        };
+        // The most specific domain decides, and ties keep their order.
+        assert_eq!(
+            extract_urls(&text, &config, false),
+            vec![
+                Url::parse("https://example.com/article").unwrap(),
+                Url::parse("https://i.mirror.example/a.png").unwrap(),
+                Url::parse("https://t.co/abc").unwrap(),
+            ]
+        );
+        let text = TextMessageEventContent::plain(
+            "https://t.co/abc https://cdn.mirror.example/a.png https://mirror.example/b.png https://cdn.mirror.example/a.png",
+        );
+        assert_eq!(
+            extract_urls(&text, &config, false),
+            vec![
+                Url::parse("https://cdn.mirror.example/a.png").unwrap(),
+                Url::parse("https://mirror.example/b.png").unwrap(),
+                Url::parse("https://t.co/abc").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_urls_bare_www() {
+        let text = TextMessageEventContent::plain("see www.example.com/foo");
+        assert!(extract_urls(&text, &Default::default(), false).is_empty());
+        assert_eq!(
+            extract_urls(&text, &Default::default(), true),
+            vec![Url::parse("https://www.example.com/foo").unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_extract_urls_geo_uri() {
+        assert_eq!(
+            extract_urls(
+                &TextMessageEventContent::plain("meet here: geo:48.2082,16.3738"),
+                &Default::default(),
+                false,
             ),
-            Some(Url::parse("https://example.com").unwrap())
+            vec![Url::parse("geo:48.2082,16.3738").unwrap()]
         );
     }
 
     #[test]
-    fn test_extract_url_ignore_bracketed() {
+    fn test_extract_urls_ignore_bracketed() {
         assert_eq!(
-            extract_url(
+            extract_urls(
                 &TextMessageEventContent::plain(
                     "<https://ignored.example.com> https://accepted.example.com"
                 ),
                 &Default::default(),
+                false,
             ),
-            Some(Url::parse("https://accepted.example.com").unwrap())
+            vec![Url::parse("https://accepted.example.com").unwrap()]
         );
     }
 
     #[test]
-    fn test_extract_url_ignore_quoted() {
+    fn test_extract_urls_ignore_quoted() {
         assert_eq!(
-            extract_url(
+            extract_urls(
                 &TextMessageEventContent::html(
                     "> https://quoted.example.com\n\nSee https://reply.example.com",
                     r#"<mx-reply><blockquote><a href="https://quoted.example.com">https://quoted.example.com</a></blockquote></mx-reply>See <a href="https://reply.example.com">https://reply.example.com</a>"#
                 ),
                 &Default::default(),
+                false,
             ),
-            Some(Url::parse("https://reply.example.com").unwrap())
+            vec![Url::parse("https://reply.example.com").unwrap()]
         );
     }
 }
@@ -0,0 +1,456 @@
+use anyhow::{Context, Result, anyhow, bail};
+use mime_guess::Mime;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::config::{EncodeSettings, VideoCodec, VideoFormat};
+use crate::dump;
+use crate::error::EmbedError;
+use crate::media::MediaInfo;
+use crate::process;
+
+const FFPROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const FFMPEG_THUMBNAIL_TIMEOUT: Duration = Duration::from_secs(20);
+
+const FFMPEG_REMUX_TIMEOUT: Duration = Duration::from_secs(20);
+const FFMPEG_REENCODE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const FFPROBE_AUDIO_TIMEOUT: Duration = Duration::from_secs(10);
+const FFMPEG_AUDIO_EXTRACT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Probes media dimensions using ffprobe.
+/// Runs: ffprobe -v error -select_streams v:0 -show_entries stream=width,height -of csv=s=x:p=0 <file>
+pub async fn probe_media(path: &Path) -> Result<MediaInfo> {
+    let output = process::output(
+        Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "stream=width,height",
+                "-of",
+                "csv=s=x:p=0",
+            ])
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+        FFPROBE_TIMEOUT,
+        &[],
+    )
+    .await
+    .context(EmbedError::FfmpegFailed)?;
+    dump::record_command("ffprobe", &output);
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("ffprobe failed: {}", stderr)).context(EmbedError::FfmpegFailed);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let trimmed = stdout.trim();
+
+    if trimmed.is_empty() {
+        bail!("ffprobe returned empty output");
+    }
+
+    let parts: Vec<&str> = trimmed.split('x').collect();
+    if parts.len() != 2 {
+        bail!("Unexpected ffprobe output format: {}", trimmed);
+    }
+
+    let width = parts[0].parse().context("Failed to parse width")?;
+    let height = parts[1].parse().context("Failed to parse height")?;
+
+    Ok(MediaInfo { width, height })
+}
+
+/// Generates a WebP thumbnail of the first frame using ffmpeg.
+/// Runs: ffmpeg -i <file> -ss 00:00:00 -vframes 1 -vf scale='min({target_width},iw)':-1 -f webp -c:v libwebp -
+pub async fn generate_thumbnail(path: &Path, target_width: u32) -> Result<Vec<u8>> {
+    let output = process::output(
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-i"])
+            .arg(path)
+            .args([
+                "-ss",
+                "0",
+                "-vframes",
+                "1",
+                "-vf",
+                &format!("scale='min({},iw)':-1", target_width),
+                "-f",
+                "webp",
+                "-c:v",
+                "libwebp",
+                "-",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+        FFMPEG_THUMBNAIL_TIMEOUT,
+        &[],
+    )
+    .await
+    .context(EmbedError::FfmpegFailed)?;
+    dump::record_command("ffmpeg", &output);
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("ffmpeg failed: {}", stderr)).context(EmbedError::FfmpegFailed);
+    }
+
+    Ok(output.stdout)
+}
+
+/// Remuxes the video at `input` to a `format` file at `output`, and returns
+/// the MIME type of the result.
+///
+/// First attempts a fast stream-copy remux (`-c copy`). If that fails (e.g.
+/// codecs incompatible with the container), falls back to reencoding with
+/// `fallback`. Works on files so ffmpeg can seek freely (needed for the MP4
+/// moov atom and `-movflags +faststart`).
+pub async fn remux_video(
+    input: &Path,
+    output: &Path,
+    format: VideoFormat,
+    fallback: &EncodeSettings,
+) -> Result<Mime> {
+    // Attempt 1: fast remux with stream copy (no reencoding)
+    info!("Attempting remux to {} (stream copy)", format.name());
+    let remux_result = process::output(
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-i"])
+            .arg(input)
+            .args(remux_args(format))
+            .arg("-y")
+            .arg(output)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped()),
+        FFMPEG_REMUX_TIMEOUT,
+        &[output],
+    )
+    .await
+    .context(EmbedError::FfmpegFailed)?;
+    dump::record_command("ffmpeg", &remux_result);
+
+    if remux_result.status.success() {
+        info!("Remux to {} (stream copy) succeeded", format.name());
+        return Ok(format.mime_type().parse().unwrap());
+    }
+
+    let stderr = String::from_utf8_lossy(&remux_result.stderr);
+    warn!(
+        "Stream-copy remux failed ({}), falling back to reencode",
+        stderr.trim()
+    );
+    encode_video(input, output, fallback).await
+}
+
+/// Reencodes the video at `input` to a file at `output` with `settings`, and
+/// returns the MIME type of the result.
+pub async fn encode_video(input: &Path, output: &Path, settings: &EncodeSettings) -> Result<Mime> {
+    let (args, mime_type) = encode_args(settings);
+    info!(
+        "Attempting reencode ({:?}, crf {})",
+        settings.codec, settings.crf
+    );
+    let reencode_result = process::output(
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-i"])
+            .arg(input)
+            .args(&args)
+            .arg("-y")
+            .arg(output)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped()),
+        FFMPEG_REENCODE_TIMEOUT,
+        &[output],
+    )
+    .await
+    .context(EmbedError::FfmpegFailed)?;
+    dump::record_command("ffmpeg", &reencode_result);
+
+    if !reencode_result.status.success() {
+        let stderr = String::from_utf8_lossy(&reencode_result.stderr);
+        return Err(anyhow!("ffmpeg reencode failed: {}", stderr.trim()))
+            .context(EmbedError::FfmpegFailed);
+    }
+
+    info!("Reencode succeeded");
+    Ok(mime_type.parse().unwrap())
+}
+
+/// ffmpeg output options for copying streams into a `format` container.
+fn remux_args(format: VideoFormat) -> &'static [&'static str] {
+    match format {
+        VideoFormat::Mp4 => &["-c", "copy", "-movflags", "+faststart", "-f", "mp4"],
+        VideoFormat::Webm => &["-c", "copy", "-f", "webm"],
+    }
+}
+
+/// ffmpeg output options for reencoding with `settings`, and the MIME type of
+/// the output.
+fn encode_args(settings: &EncodeSettings) -> (Vec<String>, &'static str) {
+    let (video_codec, audio_codec, format) = match settings.codec {
+        VideoCodec::H264 => ("libx264", "aac", "mp4"),
+        VideoCodec::H265 => ("libx265", "aac", "mp4"),
+        VideoCodec::Vp9 => ("libvpx-vp9", "libopus", "webm"),
+        VideoCodec::Av1 => ("libsvtav1", "aac", "mp4"),
+    };
+
+    let mut args = vec!["-c:v".to_string(), video_codec.to_string()];
+    let preset = settings
+        .preset
+        .as_deref()
+        .filter(|preset| settings.codec.accepts_preset(preset))
+        .or(settings.codec.default_preset());
+    if let Some(preset) = preset {
+        args.extend(["-preset".to_string(), preset.to_string()]);
+    }
+    args.extend(["-crf".to_string(), settings.crf.to_string()]);
+    match settings.codec {
+        // Constant quality mode needs the bitrate limit lifted.
+        VideoCodec::Vp9 => args.extend(["-b:v".to_string(), "0".to_string()]),
+        // Apple players only recognize HEVC with this tag.
+        VideoCodec::H265 => args.extend(["-tag:v".to_string(), "hvc1".to_string()]),
+        _ => {}
+    }
+    if let Some(max_height) = settings.max_height {
+        args.extend([
+            "-vf".to_string(),
+            format!("scale=-2:'min({},ih)'", max_height),
+        ]);
+    }
+    args.extend([
+        "-c:a".to_string(),
+        audio_codec.to_string(),
+        "-b:a".to_string(),
+        settings.audio_bitrate.clone(),
+    ]);
+    if format == "mp4" {
+        args.extend(["-movflags".to_string(), "+faststart".to_string()]);
+    }
+    args.extend(["-f".to_string(), format.to_string()]);
+
+    let mime_type = if format == "webm" {
+        "video/webm"
+    } else {
+        "video/mp4"
+    };
+    (args, mime_type)
+}
+
+/// Returns the duration of the media if it has an audio stream, or `None` if
+/// it doesn't.
+/// Runs: ffprobe -v error -select_streams a:0 -show_entries stream=codec_type:format=duration -of default=noprint_wrappers=1 <file>
+pub async fn probe_audio_duration(path: &Path) -> Result<Option<Duration>> {
+    let output = process::output(
+        Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "a:0",
+                "-show_entries",
+                "stream=codec_type:format=duration",
+                "-of",
+                "default=noprint_wrappers=1",
+            ])
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+        FFPROBE_AUDIO_TIMEOUT,
+        &[],
+    )
+    .await
+    .context(EmbedError::FfmpegFailed)?;
+    dump::record_command("ffprobe", &output);
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("ffprobe failed: {}", stderr.trim())).context(EmbedError::FfmpegFailed);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut has_audio = false;
+    let mut duration = None;
+    for line in stdout.lines() {
+        match line.trim().split_once('=') {
+            Some(("codec_type", "audio")) => has_audio = true,
+            Some(("duration", value)) => duration = value.parse::<f64>().ok(),
+            _ => {}
+        }
+    }
+
+    if !has_audio {
+        return Ok(None);
+    }
+
+    let duration = duration.context("ffprobe did not report a duration")?;
+    Ok(Some(Duration::from_secs_f64(duration)))
+}
+
+/// Extracts the first audio stream as 16 kHz mono WAV, the input format
+/// expected by Whisper.
+/// Runs: ffmpeg -i <file> -vn -ac 1 -ar 16000 -c:a pcm_s16le -f wav -
+pub async fn extract_audio_wav(path: &Path) -> Result<Vec<u8>> {
+    let output = process::output(
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-i"])
+            .arg(path)
+            .args([
+                "-vn",
+                "-ac",
+                "1",
+                "-ar",
+                "16000",
+                "-c:a",
+                "pcm_s16le",
+                "-f",
+                "wav",
+                "-",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+        FFMPEG_AUDIO_EXTRACT_TIMEOUT,
+        &[],
+    )
+    .await
+    .context(EmbedError::FfmpegFailed)?;
+    dump::record_command("ffmpeg", &output);
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("ffmpeg audio extraction failed: {}", stderr.trim()))
+            .context(EmbedError::FfmpegFailed);
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ImageLimits;
+    use crate::media::generate_blurhash;
+    use std::path::PathBuf;
+
+    fn get_test_file_path(filename: &str) -> PathBuf {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("tests/data");
+        d.push(filename);
+        d
+    }
+
+    #[tokio::test]
+    async fn test_probe_media() {
+        let path = get_test_file_path("big_buck_bunny.webm");
+
+        let info = probe_media(&path).await.expect("Failed to probe media");
+        assert_eq!(info.width, 1280);
+        assert_eq!(info.height, 720);
+    }
+
+    #[tokio::test]
+    async fn test_generate_thumbnail() {
+        let path = get_test_file_path("big_buck_bunny.webm");
+
+        let thumb_data = generate_thumbnail(&path, 320)
+            .await
+            .expect("Failed to generate thumbnail");
+        assert!(!thumb_data.is_empty());
+
+        // Verify thumbnail is a valid image and has correct width
+        let img = image::load_from_memory(&thumb_data).expect("Failed to load thumbnail as image");
+        assert_eq!(img.width(), 320);
+    }
+
+    #[tokio::test]
+    async fn test_probe_audio_duration() {
+        let path = get_test_file_path("big_buck_bunny.webm");
+
+        let duration = probe_audio_duration(&path)
+            .await
+            .expect("Failed to probe audio")
+            .expect("Test file should have an audio stream");
+        assert!(duration > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_generate_blurhash() {
+        // First generate a thumbnail to use for blurhash
+        let path = get_test_file_path("big_buck_bunny.webm");
+        let thumb_data = generate_thumbnail(&path, 320)
+            .await
+            .expect("Failed to generate thumbnail");
+
+        let hash = generate_blurhash(&thumb_data, &ImageLimits::default())
+            .await
+            .expect("Failed to generate blurhash");
+        assert!(!hash.is_empty());
+    }
+
+    #[test]
+    fn test_remux_args() {
+        assert_eq!(
+            remux_args(VideoFormat::Mp4).join(" "),
+            "-c copy -movflags +faststart -f mp4"
+        );
+        assert_eq!(remux_args(VideoFormat::Webm).join(" "), "-c copy -f webm");
+    }
+
+    #[test]
+    fn test_encode_args() {
+        let (args, mime_type) = encode_args(&EncodeSettings::default());
+        assert_eq!(
+            args.join(" "),
+            "-c:v libx264 -preset fast -crf 23 -c:a aac -b:a 128k -movflags +faststart -f mp4"
+        );
+        assert_eq!(mime_type, "video/mp4");
+
+        let (args, mime_type) = encode_args(&EncodeSettings {
+            codec: VideoCodec::Vp9,
+            crf: 35,
+            max_height: Some(480),
+            audio_bitrate: "64k".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            args.join(" "),
+            "-c:v libvpx-vp9 -crf 35 -b:v 0 -vf scale=-2:'min(480,ih)' -c:a libopus -b:a 64k -f webm"
+        );
+        assert_eq!(mime_type, "video/webm");
+
+        let (args, _) = encode_args(&EncodeSettings {
+            codec: VideoCodec::Av1,
+            ..Default::default()
+        });
+        assert_eq!(
+            args.join(" "),
+            "-c:v libsvtav1 -preset 8 -crf 23 -c:a aac -b:a 128k -movflags +faststart -f mp4"
+        );
+
+        // A preset for another encoder falls back to this one's default.
+        let (args, _) = encode_args(&EncodeSettings {
+            codec: VideoCodec::Av1,
+            preset: Some("slow".to_string()),
+            ..Default::default()
+        });
+        assert!(args.join(" ").contains("-preset 8 "));
+        let (args, _) = encode_args(&EncodeSettings {
+            preset: Some("slow".to_string()),
+            ..Default::default()
+        });
+        assert!(args.join(" ").contains("-preset slow "));
+    }
+}
@@ -0,0 +1,321 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::activitypub;
+use crate::calendar;
+use crate::cas::content_hash;
+use crate::metadata::Metadata;
+
+/// `<script>` elements, other than the JSON-LD ones extractors read.
+static SCRIPT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?is)<script\b([^>]*)>.*?</script\s*>"#).expect("valid regex"));
+
+/// `nonce` attributes, which change on every request.
+static NONCE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)\snonce\s*=\s*("[^"]*"|'[^']*'|[^\s>]+)"#).expect("valid regex")
+});
+
+/// Per-session tokens in `<meta>` tags, like Rails' `csrf-token`.
+static TOKEN_META_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?i)(<meta\b[^>]*\bname\s*=\s*["'][^"']*(?:csrf|token|nonce)[^"']*["'][^>]*\bcontent\s*=\s*)("[^"]*"|'[^']*')"#,
+    )
+    .expect("valid regex")
+});
+
+/// Keys dropped from recorded JSON: keys and other bulk nothing extracts.
+const SCRUBBED_JSON_KEYS: &[&str] = &["publicKey", "endpoints"];
+
+tokio::task_local! {
+    static RECORDING: Arc<Mutex<Vec<Exchange>>>;
+}
+
+/// A response fetched while recording, as it's kept in a fixture.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// The URL that was requested.
+    pub url: String,
+    /// Where the response came from, after redirects.
+    pub final_url: String,
+    pub content_type: String,
+    /// The body, scrubbed of scripts and per-request tokens.
+    pub body: String,
+}
+
+/// The parts of extracted metadata a fixture pins down.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Snapshot {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub card: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_alt: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub gallery: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_url: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub emotes: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn new(meta: &Metadata) -> Self {
+        let url = |url: &Option<Url>| url.as_ref().map(Url::to_string);
+        Self {
+            card: meta.card.clone(),
+            title: meta.title.clone(),
+            site_name: meta.site_name.clone(),
+            locale: meta.locale.clone(),
+            description: meta.description.clone(),
+            image_url: url(&meta.image_url),
+            image_alt: meta.image_alt.clone(),
+            gallery: meta.gallery.iter().map(|i| i.url.to_string()).collect(),
+            video_url: url(&meta.video_url),
+            audio_url: url(&meta.audio_url),
+            player_url: url(&meta.player_url),
+            published: meta.published.map(|time| time.to_rfc3339()),
+            canonical_url: url(&meta.canonical_url),
+            emotes: meta.emotes.iter().map(|e| e.shortcode.clone()).collect(),
+        }
+    }
+}
+
+/// The responses fetched to preview a link, and the metadata extracted
+/// from them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fixture {
+    pub url: String,
+    pub responses: Vec<Exchange>,
+    pub expected: Snapshot,
+}
+
+impl Fixture {
+    /// A fixture of `responses` to fetching `url`, expecting what replaying
+    /// them extracts now.
+    pub fn new(url: &Url, responses: Vec<Exchange>) -> Self {
+        let mut fixture = Self {
+            url: url.to_string(),
+            responses,
+            expected: Snapshot::default(),
+        };
+        if let Some(meta) = replay(&fixture) {
+            fixture.expected = Snapshot::new(&meta);
+        }
+        fixture
+    }
+}
+
+/// Run `fut`, collecting the responses passed to [`record`] along the way.
+pub async fn recording<F: Future>(fut: F) -> (F::Output, Vec<Exchange>) {
+    let responses = Arc::new(Mutex::new(Vec::new()));
+    let output = RECORDING.scope(responses.clone(), fut).await;
+    let responses = std::mem::take(&mut *responses.lock().unwrap());
+    (output, responses)
+}
+
+/// Keep a fetched response, scrubbed, if it was fetched while
+/// [`recording`]. Otherwise this does nothing.
+pub fn record(url: &str, final_url: &str, content_type: &str, body: &[u8]) {
+    let _ = RECORDING.try_with(|responses| {
+        let body = String::from_utf8_lossy(body);
+        responses.lock().unwrap().push(Exchange {
+            url: url.to_owned(),
+            final_url: final_url.to_owned(),
+            content_type: content_type.to_owned(),
+            body: scrub(&body, content_type),
+        });
+    });
+}
+
+/// Remove what shouldn't be kept in a fixture from a response body: scripts
+/// (other than JSON-LD), nonces and session tokens from HTML, and keys from
+/// JSON.
+pub fn scrub(body: &str, content_type: &str) -> String {
+    if content_type.contains("json") {
+        return match serde_json::from_str::<serde_json::Value>(body) {
+            Ok(mut value) => {
+                scrub_json(&mut value);
+                serde_json::to_string_pretty(&value).unwrap_or_else(|_| body.to_owned())
+            }
+            Err(_) => body.to_owned(),
+        };
+    }
+    if !content_type.contains("html") {
+        return body.to_owned();
+    }
+    let body = SCRIPT_RE.replace_all(body, |caps: &regex::Captures| {
+        if caps[1].to_ascii_lowercase().contains("ld+json") {
+            caps[0].to_owned()
+        } else {
+            String::new()
+        }
+    });
+    let body = NONCE_RE.replace_all(&body, "");
+    TOKEN_META_RE.replace_all(&body, "${1}\"\"").into_owned()
+}
+
+fn scrub_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for key in SCRUBBED_JSON_KEYS {
+                map.remove(*key);
+            }
+            map.values_mut().for_each(scrub_json);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(scrub_json),
+        _ => {}
+    }
+}
+
+/// Extract metadata from a fixture's responses again, with the extractor
+/// that handled the first of them.
+pub fn replay(fixture: &Fixture) -> Option<Metadata> {
+    let page = fixture.responses.first()?;
+    let url = Url::parse(&page.url).ok()?;
+    let final_url = Url::parse(&page.final_url).ok()?;
+    if page.content_type.contains("json") {
+        activitypub::metadata_from_json(page.body.as_bytes(), |actor_url| {
+            fixture
+                .responses
+                .iter()
+                .find(|response| response.url == actor_url)
+                .map(|response| response.body.clone().into_bytes())
+        })
+    } else if calendar::is_calendar(&page.content_type, url.path()) {
+        calendar::parse_ics(&page.body, chrono_tz::UTC)
+    } else {
+        Some(Metadata::parse_from_html(&page.body, &final_url))
+    }
+}
+
+/// Write `fixture` to `dir`, named after its URL, and return its path.
+pub fn save(dir: &Path, fixture: &Fixture) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let host = Url::parse(&fixture.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_else(|| "fixture".to_owned());
+    let path = dir.join(format!(
+        "{}-{}.json",
+        host,
+        &content_hash(fixture.url.as_bytes())[..12]
+    ));
+    let json = serde_json::to_string_pretty(fixture).context("Failed to serialize fixture")?;
+    std::fs::write(&path, json + "\n")
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixtures_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/fixtures")
+    }
+
+    #[test]
+    fn test_replay_fixtures() {
+        let mut count = 0;
+        for entry in std::fs::read_dir(fixtures_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let fixture: Fixture =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            let meta =
+                replay(&fixture).unwrap_or_else(|| panic!("{} extracts nothing", path.display()));
+            assert_eq!(
+                Snapshot::new(&meta),
+                fixture.expected,
+                "{} extracts something else",
+                path.display()
+            );
+            count += 1;
+        }
+        assert!(count > 0, "no fixtures in {}", fixtures_dir().display());
+    }
+
+    #[test]
+    fn test_scrub_html() {
+        let html = r#"<html><head>
+<meta name="csrf-token" content="s3cr3t">
+<meta property="og:title" content="Title">
+<script nonce="abc123">window.session = "xyz";</script>
+<script type="application/ld+json">{"@type":"Article"}</script>
+</head><body><p nonce='n'>Hi</p></body></html>"#;
+        assert_eq!(
+            scrub(html, "text/html; charset=utf-8"),
+            r#"<html><head>
+<meta name="csrf-token" content="">
+<meta property="og:title" content="Title">
+
+<script type="application/ld+json">{"@type":"Article"}</script>
+</head><body><p>Hi</p></body></html>"#
+        );
+    }
+
+    #[test]
+    fn test_scrub_json() {
+        let json = r#"{"type":"Person","name":"Alice","publicKey":{"publicKeyPem":"..."}}"#;
+        let scrubbed: serde_json::Value =
+            serde_json::from_str(&scrub(json, "application/activity+json")).unwrap();
+        assert_eq!(
+            scrubbed,
+            serde_json::json!({"type": "Person", "name": "Alice"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recording() {
+        record(
+            "https://example.com/",
+            "https://example.com/",
+            "text/html",
+            b"ignored",
+        );
+        let ((), responses) = recording(async {
+            record(
+                "https://example.com/a",
+                "https://example.com/b",
+                "text/html",
+                b"<p>page</p>",
+            );
+        })
+        .await;
+        assert_eq!(
+            responses,
+            vec![Exchange {
+                url: "https://example.com/a".to_string(),
+                final_url: "https://example.com/b".to_string(),
+                content_type: "text/html".to_string(),
+                body: "<p>page</p>".to_string(),
+            }]
+        );
+    }
+}
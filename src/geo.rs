@@ -0,0 +1,267 @@
+use url::Url;
+
+/// Zoom level used for static map thumbnails when the link doesn't specify one.
+pub const DEFAULT_ZOOM: u8 = 15;
+
+/// A point on the map extracted from a `geo:` URI or a maps link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub zoom: Option<u8>,
+    /// Human-readable label, if the link carried one (e.g. `geo:…?q=…(Label)`).
+    pub label: Option<String>,
+}
+
+impl GeoPoint {
+    /// Format as an RFC 5870 `geo:` URI, suitable for `m.location`.
+    pub fn geo_uri(&self) -> String {
+        format!("geo:{},{}", self.latitude, self.longitude)
+    }
+
+    /// Fill in a static map URL template. Supported placeholders are `{lat}`,
+    /// `{lon}` and `{zoom}`.
+    pub fn static_map_url(&self, template: &str) -> Option<Url> {
+        let url = template
+            .replace("{lat}", &self.latitude.to_string())
+            .replace("{lon}", &self.longitude.to_string())
+            .replace("{zoom}", &self.zoom.unwrap_or(DEFAULT_ZOOM).to_string());
+        Url::parse(&url).ok()
+    }
+
+    /// Plain-text body for the location event.
+    pub fn body(&self) -> String {
+        match &self.label {
+            Some(label) => format!("{} ({})", label, self.geo_uri()),
+            None => format!("Location: {}", self.geo_uri()),
+        }
+    }
+}
+
+/// Try to extract a location from a `geo:` URI or a Google Maps /
+/// OpenStreetMap link. Returns `None` for anything else.
+pub fn parse_geo_url(url: &Url) -> Option<GeoPoint> {
+    if url.scheme() == "geo" {
+        return parse_geo_uri(url);
+    }
+
+    let host = url.host_str()?.trim_start_matches("www.");
+    if host == "openstreetmap.org" {
+        parse_osm(url)
+    } else if is_google_maps(host, url.path()) {
+        parse_google_maps(url)
+    } else {
+        None
+    }
+}
+
+/// Parse `geo:lat,lon[,alt][;params][?q=...&z=...]`.
+fn parse_geo_uri(url: &Url) -> Option<GeoPoint> {
+    let coords = url.path().split(';').next()?;
+    let (mut latitude, mut longitude) = parse_lat_lon(coords)?;
+
+    let mut zoom = None;
+    let mut label = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "z" => zoom = value.parse().ok(),
+            // Android-style `geo:0,0?q=lat,lon(Label)`; the query wins.
+            "q" => {
+                let (q_coords, q_label) = match value.split_once('(') {
+                    Some((coords, rest)) => (coords, rest.strip_suffix(')')),
+                    None => (value.as_ref(), None),
+                };
+                if let Some((lat, lon)) = parse_lat_lon(q_coords) {
+                    latitude = lat;
+                    longitude = lon;
+                }
+                label = q_label
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty());
+            }
+            _ => {}
+        }
+    }
+
+    Some(GeoPoint {
+        latitude,
+        longitude,
+        zoom,
+        label,
+    })
+}
+
+/// OpenStreetMap links carry the marker in `mlat`/`mlon`, and the viewport in
+/// the `#map=zoom/lat/lon` fragment.
+fn parse_osm(url: &Url) -> Option<GeoPoint> {
+    let mut mlat = None;
+    let mut mlon = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "mlat" => mlat = value.parse::<f64>().ok(),
+            "mlon" => mlon = value.parse::<f64>().ok(),
+            _ => {}
+        }
+    }
+
+    let fragment = url
+        .fragment()
+        .and_then(|f| f.split('&').find_map(|p| p.strip_prefix("map=")))
+        .map(|m| m.split('/').collect::<Vec<_>>());
+    let (frag_zoom, frag_lat_lon) = match fragment.as_deref() {
+        Some([z, lat, lon]) => (
+            z.parse::<u8>().ok(),
+            lat.parse::<f64>().ok().zip(lon.parse::<f64>().ok()),
+        ),
+        _ => (None, None),
+    };
+
+    let (latitude, longitude) = mlat.zip(mlon).or(frag_lat_lon)?;
+    valid_lat_lon(latitude, longitude)?;
+
+    Some(GeoPoint {
+        latitude,
+        longitude,
+        zoom: frag_zoom,
+        label: None,
+    })
+}
+
+fn is_google_maps(host: &str, path: &str) -> bool {
+    let is_google = host.starts_with("google.") || host.starts_with("maps.google.");
+    is_google && (host.starts_with("maps.") || path.starts_with("/maps"))
+}
+
+/// Google Maps puts coordinates either in the path (`/@lat,lon,15z`) or in a
+/// query parameter (`q=`, `ll=`, or `query=` for the `api=1` format).
+fn parse_google_maps(url: &Url) -> Option<GeoPoint> {
+    if let Some(at) = url.path().split('/').find_map(|s| s.strip_prefix('@')) {
+        let mut parts = at.split(',');
+        let lat = parts.next().and_then(|s| s.parse::<f64>().ok());
+        let lon = parts.next().and_then(|s| s.parse::<f64>().ok());
+        let zoom = parts
+            .next()
+            .and_then(|s| s.strip_suffix('z'))
+            .and_then(|s| s.parse::<f64>().ok())
+            .map(|z| z.round().clamp(0.0, 21.0) as u8);
+        if let (Some(latitude), Some(longitude)) = (lat, lon)
+            && valid_lat_lon(latitude, longitude).is_some()
+        {
+            return Some(GeoPoint {
+                latitude,
+                longitude,
+                zoom,
+                label: None,
+            });
+        }
+    }
+
+    url.query_pairs()
+        .find_map(|(key, value)| match key.as_ref() {
+            "q" | "ll" | "query" => {
+                let (latitude, longitude) = parse_lat_lon(&value)?;
+                Some(GeoPoint {
+                    latitude,
+                    longitude,
+                    zoom: None,
+                    label: None,
+                })
+            }
+            _ => None,
+        })
+}
+
+/// Parse `"lat,lon"` (extra components such as altitude are ignored).
+fn parse_lat_lon(s: &str) -> Option<(f64, f64)> {
+    let mut parts = s.split(',');
+    let latitude = parts.next()?.trim().parse::<f64>().ok()?;
+    let longitude = parts.next()?.trim().parse::<f64>().ok()?;
+    valid_lat_lon(latitude, longitude)?;
+    Some((latitude, longitude))
+}
+
+fn valid_lat_lon(latitude: f64, longitude: f64) -> Option<()> {
+    ((-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude)).then_some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Option<GeoPoint> {
+        parse_geo_url(&Url::parse(s).unwrap())
+    }
+
+    #[test]
+    fn test_geo_uri() {
+        let p = parse("geo:48.2082,16.3738").unwrap();
+        assert_eq!(p.latitude, 48.2082);
+        assert_eq!(p.longitude, 16.3738);
+        assert_eq!(p.geo_uri(), "geo:48.2082,16.3738");
+
+        let p = parse("geo:48.2082,16.3738,200;u=35?z=12").unwrap();
+        assert_eq!(p.zoom, Some(12));
+
+        let p = parse("geo:0,0?q=48.2082,16.3738(Stephansdom)").unwrap();
+        assert_eq!((p.latitude, p.longitude), (48.2082, 16.3738));
+        assert_eq!(p.label.as_deref(), Some("Stephansdom"));
+    }
+
+    #[test]
+    fn test_geo_uri_invalid() {
+        assert!(parse("geo:91,0").is_none());
+        assert!(parse("geo:abc,def").is_none());
+    }
+
+    #[test]
+    fn test_google_maps() {
+        let p =
+            parse("https://www.google.com/maps/place/Foo/@51.5007,-0.1246,17z/data=abc").unwrap();
+        assert_eq!(
+            (p.latitude, p.longitude, p.zoom),
+            (51.5007, -0.1246, Some(17))
+        );
+
+        let p = parse("https://www.google.com/maps/search/?api=1&query=47.5951,-122.3316").unwrap();
+        assert_eq!((p.latitude, p.longitude), (47.5951, -122.3316));
+
+        let p = parse("https://maps.google.com/?q=35.6586,139.7454").unwrap();
+        assert_eq!((p.latitude, p.longitude), (35.6586, 139.7454));
+
+        // Search without coordinates isn't a location.
+        assert!(parse("https://www.google.com/maps/search/?api=1&query=pizza").is_none());
+        // Not a maps link at all.
+        assert!(parse("https://www.google.com/search?q=1,2").is_none());
+    }
+
+    #[test]
+    fn test_openstreetmap() {
+        let p = parse(
+            "https://www.openstreetmap.org/?mlat=52.5163&mlon=13.3777#map=18/52.5163/13.3777",
+        )
+        .unwrap();
+        assert_eq!(
+            (p.latitude, p.longitude, p.zoom),
+            (52.5163, 13.3777, Some(18))
+        );
+
+        let p = parse("https://www.openstreetmap.org/#map=12/40.7128/-74.0060").unwrap();
+        assert_eq!(
+            (p.latitude, p.longitude, p.zoom),
+            (40.7128, -74.006, Some(12))
+        );
+
+        assert!(parse("https://www.openstreetmap.org/about").is_none());
+    }
+
+    #[test]
+    fn test_static_map_url() {
+        let p = parse("geo:1.5,-2.25").unwrap();
+        assert_eq!(
+            p.static_map_url("https://maps.example.com/static?center={lat},{lon}&zoom={zoom}")
+                .unwrap()
+                .as_str(),
+            "https://maps.example.com/static?center=1.5,-2.25&zoom=15"
+        );
+    }
+}
@@ -1,55 +1,147 @@
 use std::future::Future;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use futures_util::StreamExt;
 use matrix_sdk::{
-    Client,
-    attachment::AttachmentConfig,
+    Client, RoomState,
+    attachment::{AttachmentInfo, BaseImageInfo},
     room::{
         Room,
         reply::{EnforceThread, Reply},
     },
     ruma::{
-        OwnedEventId,
+        EventId, OwnedEventId, OwnedMxcUri, OwnedTransactionId, RoomId, UserId,
+        api::client::typing::create_typing_event::{
+            self,
+            v3::{Typing, TypingInfo},
+        },
         events::{
-            relation::InReplyTo,
+            StateEventType,
+            macros::EventContent,
+            reaction::ReactionEventContent,
+            relation::{Annotation, InReplyTo, Thread},
             room::{
+                ThumbnailInfo,
+                encrypted::{EncryptedEventScheme, OriginalSyncRoomEncryptedEvent},
                 message::{
-                    AddMentions, ForwardThread, MessageType, OriginalSyncRoomMessageEvent,
-                    Relation, RoomMessageEventContent, TextMessageEventContent,
+                    AddMentions, FormattedBody, ForwardThread, LocationInfo,
+                    LocationMessageEventContent, MessageType, OriginalSyncRoomMessageEvent,
+                    Relation, ReplacementMetadata, RoomMessageEventContent,
+                    TextMessageEventContent,
                 },
+                power_levels::UserPowerLevel,
                 redaction::SyncRoomRedactionEvent,
             },
+            sticker::OriginalSyncStickerEvent,
         },
     },
 };
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use tracing::{debug, error, info, warn};
 use url::Url;
 
 use crate::{
     activitypub::ActivityPubDetector,
     cas::MediaStore,
-    command,
-    config::Config,
-    db::{CannedResponse, Database},
-    extract::extract_url,
-    metadata::Metadata,
-    processing::{MessageParams, process_metadata, process_response},
-    tracker::{EventTracker, TrackedEntry},
+    claim, command,
+    config::{
+        CaptionLayout, Config, EmbedMode, EmoteMode, LinkPolicy, MediaKind, MediaMode, ReplyMode,
+        VideoTarget,
+    },
+    db::{CannedResponse, Database, DomainOutcome, PendingGallery, QueuedEmbed},
+    debug_room::{self, Stage},
+    decompress,
+    digest::{self, DigestEntry},
+    dump,
+    error::EmbedError,
+    extract::extract_urls,
+    geo::{self, GeoPoint},
+    http::{self, Fetch, HttpClients},
+    idn,
+    jobs::{Job, JobCancelled, JobRegistry},
+    media::{downscale_image, image_dimensions},
+    media_cache::CacheSlot,
+    metadata::{GalleryImage, Metadata},
+    metrics::{self, NO_MEDIA, Step, UtdOutcome, metrics},
+    processing::{
+        AttachmentData, FileTooLarge, MessageParams, VideoPreview, fetch_video_preview,
+        looks_like_embed, media_candidate, oversized_video_note, precheck_media, process_metadata,
+        process_response, reply_fallback, select_rendition, shrink_to_thumbnail, summary_image,
+        upgrade_image_url,
+    },
+    quiet::QuietHours,
+    reporting,
+    safety::{self, Verdict},
+    summary,
+    timestamp::TimeFormat,
+    tracker::{EmbedTxns, EventTracker, TrackedEntry},
+    upload, webhook,
 };
 
+/// State event type (with an empty state key) that opts a room out of
+/// embeds, for rooms that don't want the marker in their topic.
+pub const NO_EMBEDS_STATE_EVENT: &str = "io.github.jchv.matrix_embed.no_embeds";
+
+/// How often rooms are checked for the end of their quiet hours, and for
+/// digests that are due.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delay before first trying again to decrypt a message that couldn't be
+/// decrypted when it arrived, e.g. because its key is still on its way or
+/// has to be fetched from the key backup. It doubles after each attempt.
+const UTD_FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Delay before trying media again after a failure that may not last, like
+/// a timeout or a rate limit.
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_secs(3);
+
+/// How long before expiry a typing notice is refreshed.
+const TYPING_REFRESH_MARGIN: Duration = Duration::from_secs(2);
+
+/// Largest custom emote image that's shown inline.
+const MAX_EMOTE_SIZE: u64 = 256 * 1024;
+
+/// Most custom emotes shown inline in one embed; any others stay shortcodes.
+const MAX_INLINE_EMOTES: usize = 16;
+
+/// Most links of one message embedded under [`LinkPolicy::All`].
+const MAX_LINKS_PER_MESSAGE: usize = 5;
+
 /// Determines how the bot's reply relates back to the original message.
+#[derive(Clone)]
 enum ReplyTarget {
     Event(Box<OriginalSyncRoomMessageEvent>),
     EventId(OwnedEventId),
+    /// Post standalone messages, per the room's reply mode.
+    None,
+    /// Post in the thread rooted at `root`, as a reply to `in_reply_to` for
+    /// clients without threads.
+    Thread {
+        root: OwnedEventId,
+        in_reply_to: OwnedEventId,
+    },
+    /// Edit this earlier embed into the new one, when refreshing it.
+    Replace(OwnedEventId),
 }
 
-impl ReplyTarget {
-    fn event_id(&self) -> &matrix_sdk::ruma::EventId {
-        match self {
-            ReplyTarget::Event(ev) => &ev.event_id,
-            ReplyTarget::EventId(id) => id,
+/// The reply mode for `room`: the direct chat mode if it's a DM with the
+/// bot, otherwise the normal one.
+async fn reply_mode(room: &Room, config: &Config) -> ReplyMode {
+    if config.dm_reply_mode == config.reply_mode {
+        return config.reply_mode;
+    }
+    match room.is_direct().await {
+        Ok(direct) => config.reply_mode_in(direct),
+        Err(e) => {
+            warn!(
+                "Failed to check whether {} is a direct chat: {:?}",
+                room.room_id(),
+                e
+            );
+            config.reply_mode
         }
     }
 }
@@ -62,24 +154,36 @@ pub async fn handle_message(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
     config: Arc<Config>,
-    http_client: reqwest::Client,
+    http_clients: HttpClients,
     client: Client,
     tracker: Arc<EventTracker>,
+    jobs: Arc<JobRegistry>,
     ap_detector: Arc<ActivityPubDetector>,
     database: Arc<Database>,
     media_store: Arc<MediaStore>,
 ) -> Result<()> {
+    if is_bot_output(&event, &config) {
+        debug!(
+            "Ignoring {} from {}, which looks like a bot's",
+            event.event_id, event.sender
+        );
+        return Ok(());
+    }
+
     if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
         let original_event_id = replacement.event_id.clone();
         let new_msgtype = replacement.new_content.msgtype.clone();
         return handle_replacement(
             original_event_id,
             &new_msgtype,
+            &event.sender,
             room,
             config,
-            http_client,
+            http_clients,
             tracker,
+            jobs,
             ap_detector,
+            database,
         )
         .await;
     }
@@ -91,18 +195,22 @@ pub async fn handle_message(
         &config,
         &client,
         &database,
-        &http_client,
+        http_clients.default_client(),
         &media_store,
         &ap_detector,
+        &jobs,
     )
     .await
     {
         command::CommandResult::Response(response) => {
-            room.send(
-                RoomMessageEventContent::text_markdown(response).make_reply_to(
-                    &event,
-                    ForwardThread::Yes,
-                    AddMentions::No,
+            room.send_raw(
+                "m.room.message",
+                marked(
+                    &RoomMessageEventContent::text_markdown(response).make_reply_to(
+                        &event,
+                        ForwardThread::Yes,
+                        AddMentions::No,
+                    ),
                 ),
             )
             .await?;
@@ -119,39 +227,62 @@ pub async fn handle_message(
                  Import this file in Element via *All Settings → Encryption → Import Keys*. You may need to exit and re-open Element to see old messages.",
                 key_count, passphrase,
             );
-            let caption = TextMessageEventContent::markdown(caption_text);
-            let reply = Reply {
-                event_id: event.event_id.clone(),
-                enforce_thread: EnforceThread::MaybeThreaded,
-                add_mentions: AddMentions::No,
-            };
-            let attach_config = AttachmentConfig::new()
-                .caption(Some(caption))
-                .reply(Some(reply));
-
-            room.send_attachment(
-                "room-keys.txt",
-                &"text/plain".parse().expect("valid mime"),
+            let attachment = AttachmentData {
+                filename: "room-keys.txt".to_owned(),
+                mime_type: mime_guess::mime::TEXT_PLAIN,
                 data,
-                attach_config,
+                info: None,
+                thumbnail: None,
+                caption: Some(TextMessageEventContent::markdown(caption_text)),
+            };
+            let msgtype =
+                upload::attachment_message(&room, &config, &database, attachment, "room-keys.txt")
+                    .await?;
+            let content = make_threaded_reply(
+                &room,
+                RoomMessageEventContent::new(msgtype),
+                &event.event_id,
+                AddMentions::No,
             )
             .await?;
+            room.send_raw("m.room.message", marked(&content)).await?;
             return Ok(());
         }
         command::CommandResult::CannedResponse(canned) => {
             let eid = event.event_id.clone();
-            if let Err(e) = send_canned_response(&room, &canned, &eid, &media_store).await {
+            if let Err(e) =
+                send_canned_response(&room, &config, &database, &canned, &eid, &media_store).await
+            {
                 error!("Failed to send canned response: {:?}", e);
             }
             return Ok(());
         }
+        command::CommandResult::Refresh(url) => {
+            refresh_link(
+                event,
+                url,
+                room,
+                config,
+                http_clients,
+                tracker,
+                jobs,
+                ap_detector,
+                database,
+            )
+            .await;
+            return Ok(());
+        }
         command::CommandResult::NotACommand => {}
     }
 
-    let url = if let MessageType::Text(text) = &event.content.msgtype {
-        extract_url(text, &config)
-    } else {
-        None
+    if let Some(gallery) = requested_gallery(&event, &room, &database).await {
+        post_pending_gallery(&http_clients, &room, &config, &database, gallery).await;
+        return Ok(());
+    }
+
+    let urls = match linkable_text(&event.content.msgtype, &room, &config, &database).await {
+        Some(text) => links_to_embed(&text, &event.sender, &room, &config, &database).await,
+        None => Vec::new(),
     };
 
     let body = event.content.body().to_owned();
@@ -160,22 +291,35 @@ pub async fn handle_message(
     let room_for_auto = room.clone();
 
     let original_event_id = event.event_id.clone();
+    let reply_target = match reply_mode(&room, &config).await {
+        ReplyMode::Reply => ReplyTarget::Event(Box::new(event)),
+        ReplyMode::Standalone => ReplyTarget::None,
+    };
     run_embed_task(
         tracker,
+        jobs,
         original_event_id,
-        ReplyTarget::Event(Box::new(event)),
+        reply_target,
         room,
         config,
-        http_client,
-        url,
+        http_clients,
+        urls,
         ap_detector,
+        database.clone(),
     )
     .await;
 
     // Autoresponders run last; skipped when earlier branches return early.
     if let Some(canned) = command::check_autoresponders(&body, &room_id_str, &database).await {
-        if let Err(e) =
-            send_canned_response(&room_for_auto, &canned, &event_id_for_auto, &media_store).await
+        if let Err(e) = send_canned_response(
+            &room_for_auto,
+            &config,
+            &database,
+            &canned,
+            &event_id_for_auto,
+            &media_store,
+        )
+        .await
         {
             warn!("Failed to send autoresponder: {:?}", e);
         }
@@ -184,11 +328,185 @@ pub async fn handle_message(
     Ok(())
 }
 
+/// Try again to decrypt a message that couldn't be decrypted when it
+/// arrived, for up to the configured retry window, and handle it like any
+/// other message once it can be. Attempts back off, but one is made as soon
+/// as a key for the message's session arrives.
+pub async fn retry_undecryptable(
+    event: OriginalSyncRoomEncryptedEvent,
+    room: Room,
+    config: Arc<Config>,
+    http_clients: HttpClients,
+    client: Client,
+    tracker: Arc<EventTracker>,
+    jobs: Arc<JobRegistry>,
+    ap_detector: Arc<ActivityPubDetector>,
+    database: Arc<Database>,
+    media_store: Arc<MediaStore>,
+) -> Result<()> {
+    let started = Instant::now();
+    let session_id = match &event.content.scheme {
+        EncryptedEventScheme::MegolmV1AesSha2(content) => Some(content.session_id.clone()),
+        _ => None,
+    };
+    metrics().record_utd();
+    info!(
+        "Couldn't decrypt {} from {} in {} (session {})",
+        event.event_id,
+        event.sender,
+        room.room_id(),
+        session_id.as_deref().unwrap_or("unknown"),
+    );
+
+    let Some(window) = config.utd_retry_window else {
+        metrics().record_utd_outcome(UtdOutcome::NotRetried, Duration::ZERO);
+        return Ok(());
+    };
+    let deadline = started + window;
+    let mut keys = client
+        .encryption()
+        .room_keys_received_stream()
+        .await
+        .map(Box::pin);
+    let mut delay = UTD_FIRST_RETRY_DELAY;
+
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let sleep = tokio::time::sleep(delay.min(deadline - now));
+        tokio::pin!(sleep);
+        // Wake up early if the key for this message arrives.
+        loop {
+            let Some(stream) = keys.as_mut() else {
+                (&mut sleep).await;
+                break;
+            };
+            let received = tokio::select! {
+                _ = &mut sleep => break,
+                received = stream.next() => received,
+            };
+            match received {
+                Some(Ok(infos))
+                    if infos.iter().any(|info| {
+                        info.room_id.as_str() == room.room_id().as_str()
+                            && session_id.as_deref().is_none_or(|id| info.session_id == id)
+                    }) =>
+                {
+                    debug!("Received a key for {}", event.event_id);
+                    break;
+                }
+                Some(_) => {}
+                None => keys = None,
+            }
+        }
+        delay *= 2;
+
+        let timeline_event = match room.event(&event.event_id, None).await {
+            Ok(timeline_event) => timeline_event,
+            Err(e) => {
+                warn!(
+                    "Failed to fetch undecryptable event {}: {:?}",
+                    event.event_id, e
+                );
+                continue;
+            }
+        };
+        let raw = timeline_event.raw();
+        let event_type = raw.get_field::<String>("type").ok().flatten();
+        if event_type.as_deref() == Some("m.room.encrypted") {
+            debug!("Still can't decrypt {}", event.event_id);
+            continue;
+        }
+        metrics().record_utd_outcome(UtdOutcome::Decrypted, started.elapsed());
+        if event_type.as_deref() != Some("m.room.message") || is_generated(raw.json()) {
+            return Ok(());
+        }
+        let message: OriginalSyncRoomMessageEvent =
+            serde_json::from_str(raw.json().get()).context("Failed to parse decrypted message")?;
+        info!(
+            "Decrypted {} after {:.0?}",
+            event.event_id,
+            started.elapsed()
+        );
+        return handle_message(
+            message,
+            room,
+            config,
+            http_clients,
+            client,
+            tracker,
+            jobs,
+            ap_detector,
+            database,
+            media_store,
+        )
+        .await;
+    }
+
+    metrics().record_utd_outcome(UtdOutcome::Expired, started.elapsed());
+    let backup_state = client.encryption().backups().state();
+    warn!(
+        "Giving up on decrypting {} from {} (session {}) after {:?}; key backup state: {:?}",
+        event.event_id,
+        event.sender,
+        session_id.as_deref().unwrap_or("unknown"),
+        window,
+        backup_state,
+    );
+    Ok(())
+}
+
+/// Handle an incoming sticker. Its description is only checked for links in
+/// rooms with caption links enabled.
+pub async fn handle_sticker(
+    event: OriginalSyncStickerEvent,
+    room: Room,
+    config: Arc<Config>,
+    http_clients: HttpClients,
+    tracker: Arc<EventTracker>,
+    jobs: Arc<JobRegistry>,
+    ap_detector: Arc<ActivityPubDetector>,
+    database: Arc<Database>,
+) -> Result<()> {
+    if !caption_links(&room, &config, &database).await {
+        return Ok(());
+    }
+
+    let text = TextMessageEventContent::plain(&event.content.body);
+    let urls = links_to_embed(&text, &event.sender, &room, &config, &database).await;
+    if urls.is_empty() {
+        return Ok(());
+    }
+
+    let reply_target = match reply_mode(&room, &config).await {
+        ReplyMode::Reply => ReplyTarget::EventId(event.event_id.clone()),
+        ReplyMode::Standalone => ReplyTarget::None,
+    };
+    run_embed_task(
+        tracker,
+        jobs,
+        event.event_id,
+        reply_target,
+        room,
+        config,
+        http_clients,
+        urls,
+        ap_detector,
+        database,
+    )
+    .await;
+
+    Ok(())
+}
+
 /// Handle an incoming redaction event.
 pub async fn handle_redaction(
     event: SyncRoomRedactionEvent,
     room: Room,
     tracker: Arc<EventTracker>,
+    database: Arc<Database>,
 ) -> Result<()> {
     let SyncRoomRedactionEvent::Original(event) = event else {
         return Ok(());
@@ -214,20 +532,30 @@ pub async fn handle_redaction(
 
     debug!("Processing redaction of event {}", redacted_event_id);
 
+    // A link still waiting for quiet hours to end or for a digest shouldn't
+    // be posted after all.
+    forget_unposted(&database, &redacted_event_id).await;
+
     match tracker.get_event_entry(&redacted_event_id).await {
         Some(TrackedEntry {
-            reply_event_id: Some(reply_event_id),
+            reply_event_ids,
+            extracted_urls,
             ..
-        }) => {
-            info!(
-                "Redacting our reply {} (original {} was redacted)",
-                reply_event_id, redacted_event_id
-            );
-            if let Err(e) = room
-                .redact(&reply_event_id, Some("Original message was redacted"), None)
-                .await
-            {
-                error!("Failed to redact our reply {}: {:?}", reply_event_id, e);
+        }) if !reply_event_ids.is_empty() => {
+            for url in &extracted_urls {
+                tracker.bump_generation(&redacted_event_id, url).await;
+            }
+            for reply_event_id in reply_event_ids {
+                info!(
+                    "Redacting our reply {} (original {} was redacted)",
+                    reply_event_id, redacted_event_id
+                );
+                if let Err(e) = room
+                    .redact(&reply_event_id, Some("Original message was redacted"), None)
+                    .await
+                {
+                    error!("Failed to redact our reply {}: {:?}", reply_event_id, e);
+                }
             }
         }
         _ => {
@@ -245,36 +573,43 @@ pub async fn handle_redaction(
 async fn handle_replacement(
     original_event_id: OwnedEventId,
     new_msgtype: &MessageType,
+    sender: &UserId,
     room: Room,
     config: Arc<Config>,
-    http_client: reqwest::Client,
+    http_clients: HttpClients,
     tracker: Arc<EventTracker>,
+    jobs: Arc<JobRegistry>,
     ap_detector: Arc<ActivityPubDetector>,
+    database: Arc<Database>,
 ) -> Result<()> {
-    let new_url = if let MessageType::Text(text) = new_msgtype {
-        extract_url(text, &config)
-    } else {
-        None
+    let new_urls = match linkable_text(new_msgtype, &room, &config, &database).await {
+        Some(text) => links_to_embed(&text, sender, &room, &config, &database).await,
+        None => Vec::new(),
     };
 
     debug!(
-        "Processing replacement for {}: new_url={:?}",
+        "Processing replacement for {}: new_urls={:?}",
         original_event_id,
-        new_url.as_ref().map(|u| u.as_str())
+        new_urls.iter().map(Url::as_str).collect::<Vec<_>>()
     );
 
     match tracker.get_event_entry(&original_event_id).await {
         // Already processed message
         Some(TrackedEntry {
-            reply_event_id,
-            extracted_url: old_url,
+            reply_event_ids,
+            extracted_urls: old_urls,
             ..
         }) => {
-            if new_url == old_url {
+            if new_urls == old_urls {
                 return Ok(());
             }
 
-            if let Some(reply_event_id) = reply_event_id {
+            if !reply_event_ids.is_empty() {
+                for old_url in &old_urls {
+                    tracker.bump_generation(&original_event_id, old_url).await;
+                }
+            }
+            for reply_event_id in reply_event_ids {
                 // There was already a reply; delete it.
                 info!(
                     "Redacting outdated reply {} for edited event {}",
@@ -286,17 +621,33 @@ async fn handle_replacement(
                 {
                     error!("Failed to redact reply {}: {:?}", reply_event_id, e);
                 }
+                if let Err(e) = database.forget_embed(reply_event_id.as_str()).await {
+                    warn!("Failed to forget embed {}: {:?}", reply_event_id, e);
+                }
             }
+            // A link queued for after quiet hours or collected for a digest
+            // is replaced by the new one, which goes the same way.
+            forget_unposted(&database, &original_event_id).await;
+            // The new links' embeds are added as they're posted.
+            tracker
+                .register(original_event_id.clone(), Vec::new(), Vec::new())
+                .await;
 
+            let reply_target = match reply_mode(&room, &config).await {
+                ReplyMode::Reply => ReplyTarget::EventId(original_event_id.clone()),
+                ReplyMode::Standalone => ReplyTarget::None,
+            };
             run_embed_task(
                 tracker,
+                jobs,
                 original_event_id.clone(),
-                ReplyTarget::EventId(original_event_id),
+                reply_target,
                 room,
                 config,
-                http_client,
-                new_url,
+                http_clients,
+                new_urls,
                 ap_detector,
+                database,
             )
             .await;
         }
@@ -313,194 +664,2687 @@ async fn handle_replacement(
     Ok(())
 }
 
-async fn run_embed_task(
-    tracker: Arc<EventTracker>,
-    original_event_id: OwnedEventId,
-    reply_target: ReplyTarget,
+/// Embed `url` again with freshly fetched data, as asked for by `command`:
+/// edit the bot's last embed of it in `room` if there is one, otherwise post
+/// a new one.
+async fn refresh_link(
+    command: OriginalSyncRoomMessageEvent,
+    url: Url,
     room: Room,
     config: Arc<Config>,
-    http_client: reqwest::Client,
-    url: Option<Url>,
+    http_clients: HttpClients,
+    tracker: Arc<EventTracker>,
+    jobs: Arc<JobRegistry>,
     ap_detector: Arc<ActivityPubDetector>,
+    database: Arc<Database>,
 ) {
-    match url {
-        Some(url) => {
-            debug!("Found URL: {}", url);
-            match process_and_post(
-                &http_client,
-                &room,
-                &config,
-                &url,
-                reply_target,
-                &ap_detector,
-            )
-            .await
-            {
-                Ok(reply_event_id) => {
-                    tracker
-                        .register(original_event_id, Some(url.clone()), reply_event_id)
-                        .await
-                }
-                Err(e) => {
-                    warn!("Failed to process URL {}: {:?}", url, e);
-                }
+    if !may_embed(&room, &config, &database, &command.sender).await {
+        return;
+    }
+    let previous = match database
+        .latest_embed(room.room_id().as_str(), url.as_str())
+        .await
+    {
+        Ok(event_id) => event_id.and_then(|event_id| EventId::parse(event_id).ok()),
+        Err(e) => {
+            warn!("Failed to look up earlier embeds of {}: {:?}", url, e);
+            None
+        }
+    };
+    let command_event_id = command.event_id.clone();
+    let reply_target = match previous {
+        Some(event_id) => {
+            info!("Refreshing embed {} of {}", event_id, url);
+            ReplyTarget::Replace(event_id)
+        }
+        None => {
+            info!(
+                "Refreshing {}, which has no embed in {}",
+                url,
+                room.room_id()
+            );
+            match reply_mode(&room, &config).await {
+                ReplyMode::Reply => ReplyTarget::Event(Box::new(command)),
+                ReplyMode::Standalone => ReplyTarget::None,
             }
         }
-        None => tracker.register(original_event_id, None, None).await,
-    }
+    };
+    embed_link(
+        tracker,
+        jobs,
+        command_event_id,
+        reply_target,
+        room,
+        config,
+        http_clients,
+        url,
+        ap_detector,
+        database,
+        Instant::now(),
+    )
+    .await;
 }
 
-async fn process_and_post(
-    http_client: &reqwest::Client,
-    room: &Room,
-    config: &Config,
-    url: &Url,
-    reply_target: ReplyTarget,
-    ap_detector: &ActivityPubDetector,
-) -> Result<Option<OwnedEventId>> {
-    let meta = Metadata::fetch_from_url(http_client, url, ap_detector).await?;
-
-    if meta.is_empty() {
-        return Ok(None);
+/// Whether `room` opted out of embeds: its topic or canonical alias carries
+/// the no-embed marker, or it has a [`NO_EMBEDS_STATE_EVENT`] state event.
+/// These are read from the client's state store, which sync keeps current
+/// as the topic changes, so no requests are made.
+async fn has_opted_out(room: &Room, config: &Config) -> bool {
+    if room
+        .topic()
+        .is_some_and(|topic| config.has_no_embed_marker(&topic))
+        || room
+            .canonical_alias()
+            .is_some_and(|alias| config.has_no_embed_marker(alias.as_str()))
+    {
+        return true;
+    }
+    match room
+        .get_state_event(StateEventType::from(NO_EMBEDS_STATE_EVENT), "")
+        .await
+    {
+        Ok(event) => event.is_some(),
+        Err(e) => {
+            warn!(
+                "Failed to look up {} in {}: {:?}",
+                NO_EMBEDS_STATE_EVENT,
+                room.room_id(),
+                e
+            );
+            false
+        }
     }
-
-    let params = process_metadata(meta, config);
-
-    post_message(http_client, room, config, params, &reply_target, url).await
 }
 
-/// Post the embed reply (media and/or text) and return the event ID of
-/// the message we sent (if any).
-async fn post_message(
-    http_client: &reqwest::Client,
-    room: &Room,
-    config: &Config,
-    params: MessageParams,
-    reply_target: &ReplyTarget,
-    referer: &Url,
-) -> Result<Option<OwnedEventId>> {
-    let has_text = !params.body.is_empty() || !params.html_body.is_empty();
+/// Content field marking events posted by this bot. Events carrying it are
+/// never processed, whichever instance of the bot posted them, so two
+/// instances in a room can't embed each other's embeds.
+pub const GENERATED_MARKER: &str = "io.github.jchv.matrix_embed.generated";
 
-    let caption = if has_text {
-        Some(TextMessageEventContent::html(
-            params.body.clone(),
-            params.html_body.clone(),
-        ))
-    } else {
-        None
-    };
+/// Content of a [`claim::CLAIM_EVENT_TYPE`] event, claiming the links in a
+/// message for the bot that sends it.
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "io.github.jchv.matrix_embed.claim", kind = MessageLike)]
+pub struct ClaimEventContent {
+    /// The message whose links are claimed.
+    pub event_id: OwnedEventId,
+}
 
-    if let Some(media_url) = params.media_url {
-        info!("Downloading media from {}", media_url);
+/// Serialize `content` for sending with [`GENERATED_MARKER`] set.
+fn marked(content: &impl Serialize) -> serde_json::Value {
+    let mut json = serde_json::to_value(content).expect("event content serializes to JSON");
+    json[GENERATED_MARKER] = serde_json::Value::Bool(true);
+    json
+}
 
-        let reply = Reply {
-            event_id: reply_target.event_id().to_owned(),
-            enforce_thread: EnforceThread::MaybeThreaded,
-            add_mentions: AddMentions::No,
-        };
+/// Whether `event`, as raw JSON, was posted by an instance of this bot.
+pub fn is_generated(event: &RawValue) -> bool {
+    serde_json::from_str::<serde_json::Value>(event.get())
+        .is_ok_and(|event| event["content"][GENERATED_MARKER] == true)
+}
 
-        let result = with_typing(
-            room,
-            download_and_upload(
-                http_client,
-                room,
-                &media_url,
-                config,
-                caption,
-                Some(referer),
-                reply,
-            ),
-        )
-        .await;
+/// Whether `event` is from one of the ignored senders, or formatted like
+/// one of our embeds (as another instance of this bot would post it).
+/// Notices aren't checked, since links in them are never embedded anyway.
+fn is_bot_output(event: &OriginalSyncRoomMessageEvent, config: &Config) -> bool {
+    if config
+        .ignored_senders
+        .iter()
+        .any(|u| u == event.sender.as_str())
+    {
+        return true;
+    }
+    if config.ignore_embeds
+        && let MessageType::Text(text) = &event.content.msgtype
+        && let Some(formatted) = &text.formatted
+    {
+        return looks_like_embed(&formatted.body);
+    }
+    false
+}
 
-        match result {
-            Ok(event_id) => return Ok(Some(event_id)),
-            Err(e) => {
-                error!("Failed to upload media: {:?}", e);
-                // Fallback: post text embed if available.
-                if has_text {
-                    let content = make_text_reply(params.body, params.html_body, reply_target);
-                    let response = room.send(content).await?;
-                    return Ok(Some(response.response.event_id));
-                }
-            }
+/// How embeds with both text and media are laid out in `room`: its override
+/// if it has one, otherwise the global setting.
+async fn room_caption_layout(room: &Room, config: &Config, database: &Database) -> CaptionLayout {
+    match database.get_caption_layout(room.room_id().as_str()).await {
+        Ok(layout) => layout.unwrap_or(config.caption_layout),
+        Err(e) => {
+            error!("Failed to look up caption layout: {:?}", e);
+            config.caption_layout
         }
-    } else if has_text {
-        let content = make_text_reply(params.body, params.html_body, reply_target);
-        let response = room.send(content).await?;
-        return Ok(Some(response.response.event_id));
     }
-
-    Ok(None)
 }
 
-/// Construct a text reply using the full reply fallback when the
-/// original event is available, or a bare `m.in_reply_to` relation
-/// otherwise.
-fn make_text_reply(
-    body: String,
-    html_body: String,
-    reply_target: &ReplyTarget,
-) -> RoomMessageEventContent {
-    match reply_target {
-        ReplyTarget::Event(event) => RoomMessageEventContent::text_html(body, html_body)
-            .make_reply_to(event.as_ref(), ForwardThread::Yes, AddMentions::No),
-        ReplyTarget::EventId(id) => {
-            let mut content = RoomMessageEventContent::text_html(body, html_body);
-            content.relates_to = Some(Relation::Reply(
-                matrix_sdk::ruma::events::relation::Reply::new(InReplyTo::new(id.clone())),
-            ));
-            content
+/// Which links of a message with several are embedded in `room`: its
+/// override if it has one, otherwise the global setting.
+async fn room_link_policy(room: &Room, config: &Config, database: &Database) -> LinkPolicy {
+    match database.get_link_policy(room.room_id().as_str()).await {
+        Ok(policy) => policy.unwrap_or(config.link_policy),
+        Err(e) => {
+            error!("Failed to look up link policy: {:?}", e);
+            config.link_policy
         }
     }
 }
 
-/// Download media from a URL and re-upload it to the Matrix room.
-///
-/// Returns the event ID of the sent attachment message.
-pub async fn download_and_upload(
-    client: &reqwest::Client,
+/// The links in `text` to embed in `room`, in order, per its link policy.
+/// There are none if `sender` may not have links embedded there.
+async fn links_to_embed(
+    text: &TextMessageEventContent,
+    sender: &UserId,
     room: &Room,
-    url: &Url,
     config: &Config,
-    text: Option<TextMessageEventContent>,
-    referer: Option<&Url>,
-    reply: Reply,
-) -> Result<OwnedEventId> {
-    let mut request = client.get(url.clone()).timeout(config.download_timeout);
-    if let Some(referer) = referer {
-        request = request.header(reqwest::header::REFERER, referer.as_str());
+    database: &Database,
+) -> Vec<Url> {
+    let bare_www = bare_www_links(room, config, database).await;
+    let mut urls = extract_urls(text, config, bare_www);
+    if urls.is_empty() || !may_embed(room, config, database, sender).await {
+        return Vec::new();
     }
-    let response = request.send().await.context("Failed to start download")?;
-
-    let attachment = process_response(response, config, text).await?;
-
-    let response = room
-        .send_attachment(
-            &attachment.filename,
-            &attachment.mime_type,
-            attachment.data,
-            attachment.attachment_config.reply(Some(reply)),
-        )
-        .await?;
-
-    Ok(response.event_id)
+    urls.truncate(match room_link_policy(room, config, database).await {
+        LinkPolicy::First => 1,
+        LinkPolicy::All => MAX_LINKS_PER_MESSAGE,
+    });
+    urls
 }
 
-/// Send a canned response (from a custom command or autoresponder) as a reply.
-async fn send_canned_response(
-    room: &Room,
-    canned: &CannedResponse,
-    event_id: &matrix_sdk::ruma::EventId,
-    media_store: &MediaStore,
-) -> Result<()> {
+/// Whether the room's embed mode, or the global one, allows embeds there.
+/// Clients preview links themselves in unencrypted rooms, so by default only
+/// encrypted ones get embeds.
+async fn embed_mode_allows(room: &Room, config: &Config, database: &Database) -> bool {
+    let mode = match database.get_embed_mode(room.room_id().as_str()).await {
+        Ok(mode) => mode.unwrap_or(config.embed_mode),
+        Err(e) => {
+            error!("Failed to look up embed mode: {:?}", e);
+            config.embed_mode
+        }
+    };
+    match mode {
+        EmbedMode::Always => true,
+        EmbedMode::Never => false,
+        EmbedMode::EncryptedOnly => match room.latest_encryption_state().await {
+            Ok(state) => state.is_encrypted(),
+            Err(e) => {
+                warn!(
+                    "Failed to check whether {} is encrypted: {:?}",
+                    room.room_id(),
+                    e
+                );
+                true
+            }
+        },
+    }
+}
+
+/// Check whether links from `sender` should be embedded in `room`.
+///
+/// Rooms that opted out with a marker, or whose embed mode rules them out,
+/// never pass. Otherwise, trusted and explicitly allowed users always pass.
+/// Otherwise the sender's current power level is compared against the room's
+/// override, falling back to the global minimum; with neither set, everyone
+/// passes.
+async fn may_embed(room: &Room, config: &Config, database: &Database, sender: &UserId) -> bool {
+    if has_opted_out(room, config).await {
+        debug!("Not embedding links in {}: room opted out", room.room_id());
+        return false;
+    }
+    if !embed_mode_allows(room, config, database).await {
+        debug!(
+            "Not embedding links in {}: ruled out by embed mode",
+            room.room_id()
+        );
+        return false;
+    }
+
+    let sender_str = sender.as_str();
+    if config.is_trusted(sender_str) || config.embed_allowed_users.iter().any(|u| u == sender_str) {
+        return true;
+    }
+
+    let min_level = match database
+        .get_embed_min_power_level(room.room_id().as_str())
+        .await
+    {
+        Ok(level) => level.or(config.embed_min_power_level),
+        Err(e) => {
+            error!("Failed to look up embed power level: {:?}", e);
+            config.embed_min_power_level
+        }
+    };
+    let Some(min_level) = min_level else {
+        return true;
+    };
+
+    let power_levels = match room.power_levels().await {
+        Ok(power_levels) => power_levels,
+        Err(e) => {
+            warn!(
+                "Failed to load power levels for {}: {:?}",
+                room.room_id(),
+                e
+            );
+            return false;
+        }
+    };
+    let allowed = if let UserPowerLevel::Int(level) = power_levels.for_user(sender) {
+        i64::from(level) >= min_level
+    } else {
+        // Room creators have infinite power in room version 12.
+        true
+    };
+    if !allowed {
+        debug!(
+            "Not embedding link from {}: below power level {}",
+            sender, min_level
+        );
+    }
+    allowed
+}
+
+async fn run_embed_task(
+    tracker: Arc<EventTracker>,
+    jobs: Arc<JobRegistry>,
+    original_event_id: OwnedEventId,
+    reply_target: ReplyTarget,
+    room: Room,
+    config: Arc<Config>,
+    http_clients: HttpClients,
+    urls: Vec<Url>,
+    ap_detector: Arc<ActivityPubDetector>,
+    database: Arc<Database>,
+) {
+    // The message arrived just before this, so embed latency counts from here.
+    let received = Instant::now();
+    if urls.is_empty() {
+        tracker
+            .register(original_event_id, Vec::new(), Vec::new())
+            .await;
+        return;
+    }
+
+    // Only claimed once, before the first link that isn't embedded yet.
+    let mut claim_delay = config.claim_delay;
+    let mut urls = urls.into_iter();
+    while let Some(url) = urls.next() {
+        debug!("Found URL: {}", url);
+        match database
+            .find_embed(original_event_id.as_str(), url.as_str())
+            .await
+        {
+            Ok(Some(event_id)) => {
+                info!(
+                    "Already embedded {} from {} as {}",
+                    url, original_event_id, event_id
+                );
+                let reply_event_ids = EventId::parse(&event_id).into_iter().collect();
+                tracker
+                    .add(original_event_id.clone(), url, reply_event_ids)
+                    .await;
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to look up earlier embeds: {:?}", e),
+        }
+
+        if let Some(max_delay) = claim_delay.take()
+            && !claim_embed(&room, &original_event_id, max_delay).await
+        {
+            info!(
+                "Leaving {} from {} to the bot that claimed it",
+                url, original_event_id
+            );
+            for url in std::iter::once(url).chain(urls) {
+                tracker
+                    .add(original_event_id.clone(), url, Vec::new())
+                    .await;
+            }
+            return;
+        }
+
+        if let Some((hours, true)) = room_quiet_hours(&room, &config, &database).await {
+            match database
+                .queue_embed(
+                    room.room_id().as_str(),
+                    original_event_id.as_str(),
+                    url.as_str(),
+                )
+                .await
+            {
+                Ok(()) => {
+                    info!(
+                        "Queued {} from {} until quiet hours ({}) end",
+                        url, original_event_id, hours
+                    );
+                    tracker
+                        .add(original_event_id.clone(), url, Vec::new())
+                        .await;
+                    continue;
+                }
+                Err(e) => warn!("Failed to queue {}, embedding it now: {:?}", url, e),
+            }
+        }
+
+        embed_link(
+            tracker.clone(),
+            jobs.clone(),
+            original_event_id.clone(),
+            reply_target.clone(),
+            room.clone(),
+            config.clone(),
+            http_clients.clone(),
+            url,
+            ap_detector.clone(),
+            database.clone(),
+            received,
+        )
+        .await;
+    }
+}
+
+/// Embed `url` from `original_event_id` now, reporting how it went.
+async fn embed_link(
+    tracker: Arc<EventTracker>,
+    jobs: Arc<JobRegistry>,
+    original_event_id: OwnedEventId,
+    reply_target: ReplyTarget,
+    room: Room,
+    config: Arc<Config>,
+    http_clients: HttpClients,
+    url: Url,
+    ap_detector: Arc<ActivityPubDetector>,
+    database: Arc<Database>,
+    received: Instant,
+) {
+    let refreshing = matches!(reply_target, ReplyTarget::Replace(_));
+    match database.get_digest(room.room_id().as_str()).await {
+        Ok(Some(_)) if !refreshing => {
+            collect_for_digest(
+                &room,
+                &config,
+                &http_clients,
+                &ap_detector,
+                &database,
+                &original_event_id,
+                url.clone(),
+            )
+            .await;
+            tracker.add(original_event_id, url, Vec::new()).await;
+            return;
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to look up digest mode: {:?}", e),
+    }
+
+    let working_reaction = if config.reaction_feedback {
+        send_reaction(&room, &original_event_id, &config.working_reaction).await
+    } else {
+        None
+    };
+
+    let job = jobs.start(room.room_id(), &url);
+    let dump_body_size = config
+        .debug_dump_path
+        .is_some()
+        .then_some(config.debug_dump_body_size);
+    let domain_class = config.domain_class(&url).to_string();
+    let ((result, media), trace) = dump::capture(
+        dump_body_size,
+        metrics::scope_embed(
+            domain_class.clone(),
+            job.run(process_and_post(
+                &tracker,
+                &job,
+                &original_event_id,
+                &http_clients,
+                &room,
+                &config,
+                &url,
+                reply_target,
+                &ap_detector,
+                &database,
+            )),
+        ),
+    )
+    .await;
+    drop(job);
+    if let Ok(event_ids) = &result
+        && !event_ids.is_empty()
+    {
+        metrics().record_embed_latency(&domain_class, media.label(), received.elapsed());
+        webhook::notify(
+            &config,
+            room.room_id().as_str(),
+            &url,
+            webhook::Event::EmbedPosted {
+                media_type: media.label(),
+                media_bytes: media.bytes,
+            },
+        );
+    }
+
+    if let Some(reaction_event_id) = working_reaction
+        && let Err(e) = room.redact(&reaction_event_id, None, None).await
+    {
+        warn!("Failed to remove reaction {}: {:?}", reaction_event_id, e);
+    }
+
+    match result {
+        Ok(reply_event_ids) => {
+            let outcome = if reply_event_ids.is_empty() {
+                DomainOutcome::Empty
+            } else {
+                DomainOutcome::Embedded
+            };
+            record_domain_outcome(&database, &url, outcome).await;
+            // A refresh edits the embed that's already recorded; the edit
+            // is cleaned up along with it.
+            let reply_event_ids = if refreshing {
+                Vec::new()
+            } else {
+                reply_event_ids
+            };
+            // The main event goes last, so it's the one found as the embed
+            // of the message later.
+            for reply_event_id in reply_event_ids.iter().rev() {
+                if let Err(e) = database
+                    .record_embed(
+                        room.room_id().as_str(),
+                        reply_event_id.as_str(),
+                        original_event_id.as_str(),
+                        url.as_str(),
+                    )
+                    .await
+                {
+                    warn!("Failed to record embed {}: {:?}", reply_event_id, e);
+                }
+            }
+            tracker
+                .add(original_event_id, url.clone(), reply_event_ids)
+                .await
+        }
+        Err(e) if e.is::<JobCancelled>() => {
+            info!("Embed of {} was cancelled", url);
+            // Remember the URL so an edit doesn't bring the embed back.
+            tracker.add(original_event_id, url, Vec::new()).await
+        }
+        Err(e) => {
+            let stage = e.downcast_ref::<Stage>().copied();
+            match write_dump(&config, trace, room.room_id(), &url, stage, &e).await {
+                Some(id) => warn!("Failed to process URL {} (dump {}): {:?}", url, id, e),
+                None => warn!("Failed to process URL {}: {:?}", url, e),
+            }
+            record_domain_outcome(&database, &url, DomainOutcome::Failed).await;
+            webhook::notify(
+                &config,
+                room.room_id().as_str(),
+                &url,
+                webhook::Event::EmbedFailed {
+                    stage: stage.map(Stage::label),
+                    kind: EmbedError::of(&e).label(),
+                },
+            );
+            report_failure(&room, &config, &url, stage, &e).await;
+            if config.reaction_feedback {
+                send_reaction(&room, &original_event_id, &config.failure_reaction).await;
+            }
+        }
+    }
+}
+
+/// The quiet hours of `room`, if it has any, and whether they're on now in
+/// its timezone.
+async fn room_quiet_hours(
+    room: &Room,
+    config: &Config,
+    database: &Database,
+) -> Option<(QuietHours, bool)> {
+    let hours = match database.get_quiet_hours(room.room_id().as_str()).await {
+        Ok(hours) => hours?,
+        Err(e) => {
+            error!("Failed to look up quiet hours: {:?}", e);
+            return None;
+        }
+    };
+    let timezone = room_time_format(room, config, database).await.timezone;
+    let now = chrono::Utc::now().with_timezone(&timezone);
+    Some((hours, hours.contains(&now)))
+}
+
+/// Spawn a background task posting the links queued in each room this
+/// instance handles once its quiet hours are over, and digests once they're
+/// due.
+pub fn spawn_scheduled_posts(
+    client: Client,
+    config: Arc<Config>,
+    http_clients: HttpClients,
+    tracker: Arc<EventTracker>,
+    jobs: Arc<JobRegistry>,
+    ap_detector: Arc<ActivityPubDetector>,
+    database: Arc<Database>,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let room_ids = match database.queued_embed_rooms().await {
+                Ok(room_ids) => room_ids,
+                Err(e) => {
+                    warn!("Failed to look up queued embeds: {:?}", e);
+                    Vec::new()
+                }
+            };
+            for room in scheduled_rooms(&client, &config, room_ids) {
+                flush_queued_embeds(
+                    &room,
+                    &config,
+                    &http_clients,
+                    &tracker,
+                    &jobs,
+                    &ap_detector,
+                    &database,
+                )
+                .await;
+            }
+
+            let room_ids = match database.due_digest_rooms(config.digest_interval).await {
+                Ok(room_ids) => room_ids,
+                Err(e) => {
+                    warn!("Failed to look up due digests: {:?}", e);
+                    Vec::new()
+                }
+            };
+            for room in scheduled_rooms(&client, &config, room_ids) {
+                post_digest(&room, &database).await;
+            }
+        }
+    });
+}
+
+/// The rooms among `room_ids` that this instance handles and knows about.
+fn scheduled_rooms(client: &Client, config: &Config, room_ids: Vec<String>) -> Vec<Room> {
+    room_ids
+        .iter()
+        .filter(|room_id| config.shard.owns(room_id))
+        .filter_map(|room_id| client.get_room(&RoomId::parse(room_id).ok()?))
+        .collect()
+}
+
+/// Post the links queued in `room`, unless it's still in its quiet hours:
+/// each as an embed, or all in one digest message if the room asked for
+/// that. Links queued in rooms the bot has left are dropped.
+async fn flush_queued_embeds(
+    room: &Room,
+    config: &Arc<Config>,
+    http_clients: &HttpClients,
+    tracker: &Arc<EventTracker>,
+    jobs: &Arc<JobRegistry>,
+    ap_detector: &Arc<ActivityPubDetector>,
+    database: &Arc<Database>,
+) {
+    let joined = room.state() == RoomState::Joined;
+    let digest = match room_quiet_hours(room, config, database).await {
+        Some((_, true)) if joined => return,
+        Some((hours, _)) => hours.digest,
+        None => false,
+    };
+    let queued = match database.take_queued_embeds(room.room_id().as_str()).await {
+        Ok(queued) => queued,
+        Err(e) => {
+            warn!(
+                "Failed to take queued embeds in {}: {:?}",
+                room.room_id(),
+                e
+            );
+            return;
+        }
+    };
+    if !joined {
+        info!(
+            "Dropping {} queued link(s) in {}, which the bot has left",
+            queued.len(),
+            room.room_id()
+        );
+        return;
+    }
+    info!(
+        "Quiet hours in {} are over, posting {} queued link(s)",
+        room.room_id(),
+        queued.len()
+    );
+
+    if digest {
+        post_quiet_hours_digest(room, config, http_clients, ap_detector, database, queued).await;
+        return;
+    }
+    for entry in queued {
+        let (Ok(event_id), Ok(url)) = (EventId::parse(&entry.event_id), Url::parse(&entry.url))
+        else {
+            warn!("Ignoring invalid queued embed {:?}", entry);
+            continue;
+        };
+        let reply_target = match reply_mode(room, config).await {
+            ReplyMode::Reply => ReplyTarget::EventId(event_id.clone()),
+            ReplyMode::Standalone => ReplyTarget::None,
+        };
+        embed_link(
+            tracker.clone(),
+            jobs.clone(),
+            event_id,
+            reply_target,
+            room.clone(),
+            config.clone(),
+            http_clients.clone(),
+            url,
+            ap_detector.clone(),
+            database.clone(),
+            Instant::now(),
+        )
+        .await;
+    }
+}
+
+/// Post the links queued in `room` as one digest message.
+async fn post_quiet_hours_digest(
+    room: &Room,
+    config: &Config,
+    http_clients: &HttpClients,
+    ap_detector: &ActivityPubDetector,
+    database: &Database,
+    queued: Vec<QueuedEmbed>,
+) {
+    let mut entries = Vec::new();
+    for entry in queued {
+        let (Ok(event_id), Ok(url)) = (EventId::parse(&entry.event_id), Url::parse(&entry.url))
+        else {
+            warn!("Ignoring invalid queued embed {:?}", entry);
+            continue;
+        };
+        entries.push(
+            digest_entry(
+                room,
+                config,
+                http_clients,
+                ap_detector,
+                database,
+                url,
+                &event_id,
+            )
+            .await,
+        );
+    }
+    if entries.is_empty() {
+        return;
+    }
+    let heading = format!("Links posted during quiet hours ({}):", entries.len());
+    send_digest(room, &heading, &entries).await;
+}
+
+/// Collect `url` from `event_id` for the next digest in `room`.
+async fn collect_for_digest(
+    room: &Room,
+    config: &Config,
+    http_clients: &HttpClients,
+    ap_detector: &ActivityPubDetector,
+    database: &Database,
+    event_id: &EventId,
+    url: Url,
+) {
+    let entry = digest_entry(
+        room,
+        config,
+        http_clients,
+        ap_detector,
+        database,
+        url,
+        event_id,
+    )
+    .await;
+    match database
+        .add_digest_entry(room.room_id().as_str(), &entry)
+        .await
+    {
+        Ok(()) => info!(
+            "Collected {} from {} for the digest in {}",
+            entry.url,
+            event_id,
+            room.room_id()
+        ),
+        Err(e) => warn!("Failed to collect {} for digest: {:?}", entry.url, e),
+    }
+}
+
+/// The digest entry for `url`, posted in `event_id`, with the page's title
+/// and, in unencrypted rooms, a small thumbnail of its image. Whatever can't
+/// be fetched is left out, so the link is listed regardless.
+async fn digest_entry(
+    room: &Room,
+    config: &Config,
+    http_clients: &HttpClients,
+    ap_detector: &ActivityPubDetector,
+    database: &Database,
+    url: Url,
+    event_id: &EventId,
+) -> DigestEntry {
+    if let Some(verdict) = screen_link(http_clients, config, database, &url).await {
+        let mut entry = DigestEntry::new(url, Some(event_id.to_string()), None);
+        entry.title = Some(safety::label(&verdict));
+        return entry;
+    }
+    let meta = if matches!(url.scheme(), "http" | "https") {
+        let language = room_language(room, config, database).await;
+        match fetch_metadata(
+            http_clients,
+            config,
+            database,
+            &url,
+            ap_detector,
+            language.as_deref(),
+            false,
+        )
+        .await
+        {
+            Ok(meta) => Some(meta),
+            Err(e) => {
+                warn!("Failed to fetch {} for digest: {:?}", url, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let image_url = meta.as_ref().and_then(|meta| meta.image_url.clone());
+    let mut entry = DigestEntry::new(url, Some(event_id.to_string()), meta.as_ref());
+
+    let Some(image_url) = image_url else {
+        return entry;
+    };
+    match room.latest_encryption_state().await {
+        // Inline images can't be encrypted.
+        Ok(state) if !state.is_encrypted() => {}
+        Ok(_) => return entry,
+        Err(e) => {
+            warn!("Failed to check room encryption: {:?}", e);
+            return entry;
+        }
+    }
+    match upload_digest_thumbnail(http_clients, room, config, database, &image_url).await {
+        Ok(uri) => entry.thumbnail = Some(uri.to_string()),
+        Err(e) => debug!("No digest thumbnail from {}: {:?}", image_url, e),
+    }
+    entry
+}
+
+/// Download the image at `url` and upload a small version of it for showing
+/// inline in a digest.
+async fn upload_digest_thumbnail(
+    http_clients: &HttpClients,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    url: &Url,
+) -> Result<OwnedMxcUri> {
+    let (data, mime_type) =
+        fetch_image(http_clients.for_url(url), config, url, config.max_file_size).await?;
+    let (data, mime_type) =
+        match downscale_image(&data, digest::THUMBNAIL_SIZE, &config.image_limits).await? {
+            Some(small) => small,
+            None => (data, mime_type),
+        };
+    upload::upload_inline_image(&room.client(), config, database, &mime_type, data).await
+}
+
+/// Post the links collected for the digest in `room`, if there are any.
+/// Those collected in rooms the bot has left are dropped.
+async fn post_digest(room: &Room, database: &Database) {
+    let entries = match database.take_digest_entries(room.room_id().as_str()).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(
+                "Failed to take digest entries in {}: {:?}",
+                room.room_id(),
+                e
+            );
+            return;
+        }
+    };
+    if entries.is_empty() {
+        return;
+    }
+    if room.state() != RoomState::Joined {
+        info!(
+            "Dropping digest of {} link(s) in {}, which the bot has left",
+            entries.len(),
+            room.room_id()
+        );
+        return;
+    }
+    let heading = format!("Links shared recently ({}):", entries.len());
+    send_digest(room, &heading, &entries).await;
+}
+
+/// Post `entries` in `room` as one digest message under `heading`. Failures
+/// are logged and otherwise ignored.
+async fn send_digest(room: &Room, heading: &str, entries: &[DigestEntry]) {
+    let (body, html_body) = digest::render(heading, room.room_id().as_str(), entries);
+    let content = RoomMessageEventContent::text_html(body, html_body);
+    match room.send_raw("m.room.message", marked(&content)).await {
+        Ok(_) => info!(
+            "Posted digest of {} link(s) in {}",
+            entries.len(),
+            room.room_id()
+        ),
+        Err(e) => warn!("Failed to post digest in {}: {:?}", room.room_id(), e),
+    }
+}
+
+/// Forget the link in `event_id` if it's waiting for quiet hours to end or
+/// for a digest. Failures are logged and otherwise ignored.
+async fn forget_unposted(database: &Database, event_id: &EventId) {
+    if let Err(e) = database.forget_queued_embed(event_id.as_str()).await {
+        warn!("Failed to forget queued embed of {}: {:?}", event_id, e);
+    }
+    if let Err(e) = database.forget_digest_entries(event_id.as_str()).await {
+        warn!("Failed to forget digest entries of {}: {:?}", event_id, e);
+    }
+}
+
+/// Agree with other bots in `room` on whether this one embeds the links in
+/// `event_id`, claiming them after a random delay of up to `max_delay`.
+async fn claim_embed(room: &Room, event_id: &EventId, max_delay: Duration) -> bool {
+    let content = ClaimEventContent {
+        event_id: event_id.to_owned(),
+    };
+    let send_claim = async {
+        room.send_raw(claim::CLAIM_EVENT_TYPE, marked(&content))
+            .await
+            .context("Failed to send claim")?;
+        anyhow::Ok(())
+    };
+    claim::claims()
+        .contend(
+            event_id,
+            room.own_user_id(),
+            max_delay,
+            claim::SETTLE_DELAY,
+            send_claim,
+        )
+        .await
+}
+
+/// Write a debug dump of the failed embed of `url`, if dumps are enabled,
+/// and return its ID. Failures are logged and otherwise ignored.
+async fn write_dump(
+    config: &Config,
+    trace: Option<dump::Trace>,
+    room_id: &RoomId,
+    url: &Url,
+    stage: Option<Stage>,
+    error: &anyhow::Error,
+) -> Option<String> {
+    let (dir, trace) = (config.debug_dump_path.as_ref()?, trace?);
+    match dump::write(dir, trace, room_id.as_str(), url, stage, error).await {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!("Failed to write debug dump for {}: {:?}", url, e);
+            None
+        }
+    }
+}
+
+/// Count how an embed of `url` turned out in the per-domain statistics.
+/// Failures are logged and otherwise ignored.
+async fn record_domain_outcome(database: &Database, url: &Url, outcome: DomainOutcome) {
+    let Some(domain) = url.host_str() else {
+        return;
+    };
+    if let Err(e) = database.record_domain_outcome(domain, outcome).await {
+        warn!("Failed to record outcome for {}: {:?}", domain, e);
+    }
+}
+
+/// React to `event_id` with `key`, returning the reaction's event ID so it can
+/// be removed later. Failures are logged and otherwise ignored.
+async fn send_reaction(room: &Room, event_id: &EventId, key: &str) -> Option<OwnedEventId> {
+    let content = ReactionEventContent::new(Annotation::new(event_id.to_owned(), key.to_owned()));
+    match room.send_raw("m.reaction", marked(&content)).await {
+        Ok(response) => Some(response.response.event_id),
+        Err(e) => {
+            warn!("Failed to react to {}: {:?}", event_id, e);
+            None
+        }
+    }
+}
+
+/// Report a failed embed to Sentry and post a notice about it to the debug
+/// room, if there is one. Failures are logged and otherwise ignored.
+async fn report_failure(
+    room: &Room,
+    config: &Config,
+    url: &Url,
+    stage: Option<Stage>,
+    error: &anyhow::Error,
+) {
+    metrics().record_failure(EmbedError::of(error));
+    reporting::capture_error(error, Some(url), stage);
+    if let Some(event) = webhook::Event::quota_exceeded(error, config) {
+        webhook::notify(config, room.room_id().as_str(), url, event);
+    }
+
+    let Some(debug_room_id) = &config.debug_room else {
+        return;
+    };
+    let debug_room = RoomId::parse(debug_room_id)
+        .ok()
+        .and_then(|room_id| room.client().get_room(&room_id));
+    let Some(debug_room) = debug_room else {
+        warn!("Debug room {} is not joined", debug_room_id);
+        return;
+    };
+
+    let (body, html_body) = debug_room::failure_notice(room.room_id().as_str(), url, stage, error);
+    let content = RoomMessageEventContent::notice_html(body, html_body);
+    if let Err(e) = debug_room
+        .send_raw("m.room.message", marked(&content))
+        .await
+    {
+        warn!("Failed to report error to {}: {:?}", debug_room_id, e);
+    }
+}
+
+async fn process_and_post(
+    tracker: &EventTracker,
+    job: &Job,
+    original_event_id: &EventId,
+    http_clients: &HttpClients,
+    room: &Room,
+    config: &Config,
+    url: &Url,
+    reply_target: ReplyTarget,
+    ap_detector: &ActivityPubDetector,
+    database: &Database,
+) -> Result<Vec<OwnedEventId>> {
+    let txns = tracker.embed_txns(original_event_id, url).await;
+    if let Some(point) = geo::parse_geo_url(url) {
+        debug!("URL {} is a location: {:?}", url, point);
+        job.set_stage(Stage::Location);
+        let event_id = post_location(
+            http_clients.default_client(),
+            room,
+            config,
+            database,
+            &point,
+            &reply_target,
+            txns.txn_id("embed"),
+        )
+        .await
+        .context(Stage::Location)?;
+        return Ok(vec![event_id]);
+    }
+
+    if let Some(verdict) = screen_link(http_clients, config, database, url).await {
+        let event_id = post_link_warning(
+            room,
+            config,
+            url,
+            &verdict,
+            &reply_target,
+            txns.txn_id("embed"),
+        )
+        .await?;
+        return Ok(vec![event_id]);
+    }
+
+    let refreshing = matches!(reply_target, ReplyTarget::Replace(_));
+    let language = room_language(room, config, database).await;
+    let language = language.as_deref();
+    let mut meta = fetch_metadata(
+        http_clients,
+        config,
+        database,
+        url,
+        ap_detector,
+        language,
+        refreshing,
+    )
+    .await
+    .context(Stage::Metadata)?;
+    if config.follow_og_url && meta.is_weak() {
+        meta = follow_content_url(http_clients, config, url, meta, ap_detector, language).await;
+    }
+
+    // Redirects and rel="canonical" can take us somewhere the rules applied to
+    // the posted link didn't see, so check them again against where we ended up.
+    let canonical = meta.canonical_url.clone().unwrap_or_else(|| url.clone());
+    let mut url = url;
+    let rewritten;
+    if canonical != *url {
+        debug!("Canonical URL for {} is {}", url, canonical);
+        if config.is_url_ignored(&canonical) {
+            debug!("Ignoring {}: canonical URL {} is ignored", url, canonical);
+            return Ok(Vec::new());
+        }
+        if let Some(verdict) = screen_link(http_clients, config, database, &canonical).await {
+            let event_id = post_link_warning(
+                room,
+                config,
+                &canonical,
+                &verdict,
+                &reply_target,
+                txns.txn_id("embed"),
+            )
+            .await?;
+            return Ok(vec![event_id]);
+        }
+        rewritten = config.rewrite_url(&canonical);
+        if rewritten != canonical && rewritten != *url {
+            info!("Canonical URL {} rewritten to {}", canonical, rewritten);
+            meta = fetch_metadata(
+                http_clients,
+                config,
+                database,
+                &rewritten,
+                ap_detector,
+                language,
+                refreshing,
+            )
+            .await
+            .context(Stage::Metadata)?;
+            url = &rewritten;
+        }
+    }
+
+    // A refresh is asked for, so it's never a duplicate.
+    if !refreshing
+        && !tracker
+            .claim_url(room.room_id(), &canonical, original_event_id)
+            .await
+    {
+        debug!(
+            "Not embedding {}: {} was embedded in this room recently",
+            url, canonical
+        );
+        return Ok(Vec::new());
+    }
+
+    // Whatever stops the embed from being posted, another message with the
+    // link can have a go.
+    let posted = async {
+        if meta.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if meta.video_url.is_none()
+            && meta.audio_url.is_none()
+            && config
+                .media_order(Some(meta.canonical_url.as_ref().unwrap_or(url)))
+                .contains(&MediaKind::Video)
+            && let Some(player_url) = meta.player_url.clone()
+        {
+            match Metadata::fetch_player_media(
+                http_clients.for_url(&player_url),
+                &player_url,
+                config,
+            )
+            .await
+            {
+                Ok(Some(video_url)) => {
+                    debug!("Found video {} in player {}", video_url, player_url);
+                    meta.video_url = Some(video_url);
+                    meta.player_url = None;
+                }
+                Ok(None) => debug!("No video found in player {}", player_url),
+                Err(e) => warn!("Failed to fetch player {}: {:?}", player_url, e),
+            }
+        }
+
+        meta.url_warning = idn::lookalike_warning(url);
+        // Link back to the page in the embed, even when it didn't name itself.
+        meta.canonical_url.get_or_insert_with(|| url.clone());
+        upgrade_image_url(&mut meta, config);
+
+        if !meta.video_renditions.is_empty() {
+            let max_height = room_video_target(room, config, database)
+                .await
+                .encode
+                .max_height;
+            if let Some(rendition) =
+                select_rendition(&meta.video_renditions, config.max_file_size, max_height)
+            {
+                debug!("Selected video rendition {:?}", rendition);
+                meta.video_url = Some(rendition.url.clone());
+            }
+        }
+
+        // The summary can take a while, so check the media in the meantime.
+        let media_url = media_candidate(&meta, url, config).cloned();
+        let precheck = async {
+            match &media_url {
+                Some(media_url) if config.precheck_media => {
+                    precheck_media(
+                        http_clients.for_url(media_url),
+                        media_url,
+                        Some(url),
+                        config,
+                    )
+                    .await
+                }
+                _ => Ok(()),
+            }
+        };
+        let summary = add_summary(
+            http_clients.default_client(),
+            room,
+            config,
+            database,
+            url,
+            &mut meta,
+            refreshing,
+        );
+        job.set_stage(Stage::Summary);
+        let ((), precheck) = tokio::join!(summary, precheck);
+
+        upload_emotes(http_clients, room, config, database, &mut meta).await;
+
+        let times = room_time_format(room, config, database).await;
+        let summary_thumbnail =
+            summary_image(&meta).is_some() && summary_thumbnails(room, config, database).await;
+        let mut params = process_metadata(meta, url, config, &times, summary_thumbnail);
+        // A refreshed embed is one edited event, so it goes without the extras.
+        params.post_poster = !refreshing
+            && params.poster_url.is_some()
+            && video_posters(room, config, database).await;
+        let continuation = params.continuation.take();
+        let gallery = std::mem::take(&mut params.gallery);
+        let gallery_rest = std::mem::take(&mut params.gallery_rest);
+        params.media_rejected = precheck.err();
+
+        job.set_stage(Stage::Media);
+        let event_ids = post_message(
+            http_clients,
+            room,
+            config,
+            database,
+            params,
+            &reply_target,
+            url,
+            &txns,
+        )
+        .await
+        .context(Stage::Post)?;
+        if let Some(event_id) = event_ids.first()
+            && !refreshing
+        {
+            post_thread_extras(
+                http_clients,
+                room,
+                config,
+                database,
+                &reply_target,
+                event_id,
+                continuation,
+                gallery,
+                gallery_rest,
+                url,
+                &txns,
+            )
+            .await;
+        }
+        Ok::<_, anyhow::Error>(event_ids)
+    }
+    .await;
+    if !refreshing && !posted.as_ref().is_ok_and(|event_ids| !event_ids.is_empty()) {
+        tracker
+            .release_url(room.room_id(), &canonical, original_event_id)
+            .await;
+    }
+    posted
+}
+
+/// Post what didn't fit in the embed `event_id` in a thread: the thread the
+/// original message is in, or a new one on the embed. That's the text cut
+/// from the caption, then the rest of the gallery. Past the first few
+/// images, the gallery ends with a note saying how many more there are, and
+/// they're kept until someone replies `all` to it. Failures are logged and
+/// otherwise ignored.
+async fn post_thread_extras(
+    http_clients: &HttpClients,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    reply_target: &ReplyTarget,
+    event_id: &EventId,
+    continuation: Option<(String, String)>,
+    gallery: Vec<GalleryImage>,
+    gallery_rest: Vec<GalleryImage>,
+    referer: &Url,
+    txns: &EmbedTxns,
+) {
+    let root = match reply_target {
+        ReplyTarget::Event(event) => match &event.content.relates_to {
+            Some(Relation::Thread(thread)) => thread.event_id.clone(),
+            _ => event_id.to_owned(),
+        },
+        _ => event_id.to_owned(),
+    };
+    let thread_root = root.clone();
+    let thread = ReplyTarget::Thread {
+        root,
+        in_reply_to: event_id.to_owned(),
+    };
+
+    if let Some((body, html_body)) = continuation {
+        let content = make_reply(
+            RoomMessageEventContent::text_html(body, html_body),
+            room.room_id(),
+            config,
+            &thread,
+        );
+        let request = room
+            .send_raw("m.room.message", marked(&content))
+            .with_transaction_id(txns.txn_id("continuation"));
+        if let Err(e) = request.await {
+            warn!("Failed to post rest of embed {}: {:?}", event_id, e);
+        }
+    }
+
+    for (i, image) in gallery.into_iter().enumerate() {
+        let result = download_and_upload(
+            http_clients,
+            room,
+            &image.url,
+            config,
+            database,
+            None,
+            image.alt.as_deref(),
+            Some(referer),
+            &thread,
+            Some(txns.txn_id(&format!("gallery-{i}"))),
+            false,
+        )
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to post gallery image {}: {:?}", image.url, e);
+        }
+    }
+
+    if gallery_rest.is_empty() {
+        return;
+    }
+    let note = format!("(+{} more — reply `all` for the rest)", gallery_rest.len());
+    let content = make_reply(
+        RoomMessageEventContent::notice_markdown(note),
+        room.room_id(),
+        config,
+        &thread,
+    );
+    let request = room
+        .send_raw("m.room.message", marked(&content))
+        .with_transaction_id(txns.txn_id("gallery-more"));
+    let note_event_id = match request.await {
+        Ok(response) => response.response.event_id,
+        Err(e) => {
+            warn!("Failed to post gallery note for {}: {:?}", event_id, e);
+            return;
+        }
+    };
+    let pending = PendingGallery {
+        note_event_id: note_event_id.to_string(),
+        thread_root: thread_root.to_string(),
+        referer: referer.to_string(),
+        images: gallery_rest,
+    };
+    if let Err(e) = database
+        .store_pending_gallery(room.room_id().as_str(), &pending)
+        .await
+    {
+        error!("Failed to store rest of gallery for {}: {:?}", event_id, e);
+    }
+}
+
+/// Whether `body` asks for the rest of a gallery, ignoring a rich-reply
+/// fallback quote.
+fn asks_for_gallery(body: &str) -> bool {
+    let text: String = body
+        .lines()
+        .filter(|line| !line.starts_with('>'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    text.trim()
+        .trim_matches(['\'', '"', '.', '!'])
+        .eq_ignore_ascii_case("all")
+}
+
+/// If `event` is a reply of `all` to a gallery note, or to a thread with
+/// one, take the pending gallery it asks for.
+async fn requested_gallery(
+    event: &OriginalSyncRoomMessageEvent,
+    room: &Room,
+    database: &Database,
+) -> Option<PendingGallery> {
+    let candidates = match &event.content.relates_to {
+        Some(Relation::Reply(reply)) => vec![reply.in_reply_to.event_id.clone()],
+        Some(Relation::Thread(thread)) => thread
+            .in_reply_to
+            .iter()
+            .map(|in_reply_to| in_reply_to.event_id.clone())
+            .chain([thread.event_id.clone()])
+            .collect(),
+        _ => return None,
+    };
+    if !asks_for_gallery(event.content.body()) {
+        return None;
+    }
+    for event_id in candidates {
+        match database
+            .take_pending_gallery(room.room_id().as_str(), event_id.as_str())
+            .await
+        {
+            Ok(Some(gallery)) => return Some(gallery),
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to look up pending gallery: {:?}", e);
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Post the rest of a gallery in its thread, after its note.
+async fn post_pending_gallery(
+    http_clients: &HttpClients,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    gallery: PendingGallery,
+) {
+    let (Ok(root), Ok(note)) = (
+        EventId::parse(&gallery.thread_root),
+        EventId::parse(&gallery.note_event_id),
+    ) else {
+        warn!("Pending gallery has invalid event IDs");
+        return;
+    };
+    let referer = Url::parse(&gallery.referer).ok();
+    let thread = ReplyTarget::Thread {
+        root,
+        in_reply_to: note,
+    };
+    info!(
+        "Posting {} more gallery image(s) in {}",
+        gallery.images.len(),
+        room.room_id()
+    );
+    for image in gallery.images {
+        let result = with_typing(
+            room,
+            config,
+            download_and_upload(
+                http_clients,
+                room,
+                &image.url,
+                config,
+                database,
+                None,
+                image.alt.as_deref(),
+                referer.as_ref(),
+                &thread,
+                None,
+                false,
+            ),
+        )
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to post gallery image {}: {:?}", image.url, e);
+        }
+    }
+}
+
+/// Second extraction pass for pages that only point elsewhere, like link
+/// shorteners: fetch the page `meta.content_url` names and merge what it has
+/// into `meta`. Failures are logged and leave `meta` as it was.
+async fn follow_content_url(
+    http_clients: &HttpClients,
+    config: &Config,
+    url: &Url,
+    meta: Metadata,
+    ap_detector: &ActivityPubDetector,
+    language: Option<&str>,
+) -> Metadata {
+    let Some(content_url) = meta.content_url.clone() else {
+        return meta;
+    };
+    if content_url == *url || config.is_url_ignored(&content_url) {
+        return meta;
+    }
+    let content_url = config.rewrite_url(&content_url);
+    debug!("Following {} to {} for more metadata", url, content_url);
+
+    match Metadata::fetch_from_url(
+        http_clients.for_url(&content_url),
+        &content_url,
+        config,
+        ap_detector,
+        language,
+    )
+    .await
+    {
+        Ok(target) => target.merge(meta),
+        Err(e) => {
+            debug!("Failed to fetch metadata from {}: {:?}", content_url, e);
+            meta
+        }
+    }
+}
+
+/// Upload the custom emotes in `meta` so they can be shown inline, if they
+/// are to be and the room is unencrypted. Emotes that fail to upload stay
+/// shortcodes.
+async fn upload_emotes(
+    http_clients: &HttpClients,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    meta: &mut Metadata,
+) {
+    if config.custom_emotes != EmoteMode::Inline || meta.emotes.is_empty() {
+        return;
+    }
+    match room.latest_encryption_state().await {
+        Ok(state) if !state.is_encrypted() => {}
+        Ok(_) => return,
+        Err(e) => {
+            warn!("Failed to check room encryption: {:?}", e);
+            return;
+        }
+    }
+
+    let client = room.client();
+    for emote in meta.emotes.iter_mut().take(MAX_INLINE_EMOTES) {
+        let http_client = http_clients.for_url(&emote.url);
+        match upload_emote(http_client, &client, config, database, &emote.url).await {
+            Ok(uri) => emote.mxc_uri = Some(uri.to_string()),
+            Err(e) => debug!(
+                "Not inlining emote :{}: from {}: {:?}",
+                emote.shortcode, emote.url, e
+            ),
+        }
+    }
+}
+
+/// Download the custom emote image at `url` and upload it for inlining.
+async fn upload_emote(
+    http_client: &reqwest::Client,
+    client: &Client,
+    config: &Config,
+    database: &Database,
+    url: &Url,
+) -> Result<OwnedMxcUri> {
+    let (data, mime_type) = fetch_image(http_client, config, url, MAX_EMOTE_SIZE).await?;
+    upload::upload_inline_image(client, config, database, &mime_type, data).await
+}
+
+/// Download the image at `url`, of at most `max_size` bytes, for showing
+/// inline or as a thumbnail. Returns it with its type, sniffed from the
+/// content. A larger image is turned away by its `Content-Length` if it has
+/// one, and otherwise isn't read past the limit.
+async fn fetch_image(
+    http_client: &reqwest::Client,
+    config: &Config,
+    url: &Url,
+    max_size: u64,
+) -> Result<(Vec<u8>, mime_guess::Mime)> {
+    let response = http_client
+        .get(url.clone())
+        .timeout(config.download_timeout)
+        .header(
+            reqwest::header::USER_AGENT,
+            http::user_agent(config, url, Fetch::Media),
+        )
+        .send()
+        .await
+        .context("Failed to request image")?
+        .error_for_status()
+        .context("Image request returned error status")?;
+    if let Some(len) = response.content_length()
+        && len > max_size
+    {
+        return Err(FileTooLarge {
+            size: len,
+            streamed: false,
+        }
+        .into());
+    }
+    let data = decompress::read_body(response, max_size, config.max_decompression_ratio)
+        .await
+        .context("Failed to read image")?;
+
+    let mime_type: mime_guess::Mime = match infer::get(&data) {
+        Some(kind) if kind.matcher_type() == infer::MatcherType::Image => {
+            kind.mime_type().parse()?
+        }
+        _ => bail!("Not an image"),
+    };
+    Ok((data, mime_type))
+}
+
+/// Attach an LLM-generated summary to `meta` if the room has opted in and the
+/// page has enough readable text, generating a `fresh` one rather than
+/// reusing a cached one if asked to. Failures are logged and otherwise
+/// ignored.
+async fn add_summary(
+    http_client: &reqwest::Client,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    url: &Url,
+    meta: &mut Metadata,
+    fresh: bool,
+) {
+    if config.summary_api_url.is_none() {
+        return;
+    }
+    let Some(text) = meta.text.as_deref() else {
+        return;
+    };
+    if !summary::is_long_form(text, config) {
+        return;
+    }
+
+    match database.is_summaries_enabled(room.room_id().as_str()).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            error!("Failed to check summary status: {:?}", e);
+            return;
+        }
+    }
+
+    match summary::summarize(http_client, config, database, url, text, fresh).await {
+        Ok(summary) => meta.summary = summary,
+        Err(e) => {
+            warn!("Failed to summarize {}: {:?}", url, e);
+            report_failure(room, config, url, Some(Stage::Summary), &e).await;
+        }
+    }
+}
+
+/// Post the embed reply (media and/or text) and return the event IDs of
+/// the messages we sent, the main one first. Depending on the room's caption
+/// layout, the text is either the caption of the media or a message of its
+/// own. If the second of two messages can't be sent, the first is redacted
+/// again so no half of the embed is left behind.
+async fn post_message(
+    http_clients: &HttpClients,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    params: MessageParams,
+    reply_target: &ReplyTarget,
+    referer: &Url,
+    txns: &EmbedTxns,
+) -> Result<Vec<OwnedEventId>> {
+    let has_text = !params.body.is_empty() || !params.html_body.is_empty();
+    let layout = room_caption_layout(room, config, database).await;
+    // Text and media in separate events can't both replace one embed.
+    if layout == CaptionLayout::OnMedia
+        || !has_text
+        || params.media_url.is_none()
+        || matches!(reply_target, ReplyTarget::Replace(_))
+    {
+        let event_id = post_media(
+            http_clients,
+            room,
+            config,
+            database,
+            params,
+            reply_target,
+            referer,
+            txns,
+            "embed",
+        )
+        .await?;
+        return Ok(event_id.into_iter().collect());
+    }
+
+    let text = make_text_reply(
+        params.body.clone(),
+        params.html_body.clone(),
+        room.room_id(),
+        config,
+        reply_target,
+    );
+    let media = MessageParams {
+        body: String::new(),
+        html_body: String::new(),
+        ..params
+    };
+
+    match layout {
+        CaptionLayout::TextFirst => {
+            // The text goes out right away, so there's context in the room
+            // while a large upload is still going.
+            let text_event_id = room
+                .send_raw("m.room.message", marked(&text))
+                .with_transaction_id(txns.txn_id("embed"))
+                .await?
+                .response
+                .event_id;
+            let media_result = post_media(
+                http_clients,
+                room,
+                config,
+                database,
+                media,
+                reply_target,
+                referer,
+                txns,
+                "media",
+            )
+            .await;
+            match media_result {
+                Ok(media_event_id) => Ok([Some(text_event_id), media_event_id]
+                    .into_iter()
+                    .flatten()
+                    .collect()),
+                Err(e) => {
+                    redact_partial_embed(room, &text_event_id).await;
+                    Err(e.context("Failed to post media after embed text"))
+                }
+            }
+        }
+        _ => {
+            let media_result = post_media(
+                http_clients,
+                room,
+                config,
+                database,
+                media,
+                reply_target,
+                referer,
+                txns,
+                "media",
+            )
+            .await;
+            let media_event_id = match media_result {
+                Ok(event_id) => event_id,
+                Err(e) => {
+                    error!("Failed to post media before embed text: {:?}", e);
+                    None
+                }
+            };
+            let text_result = room
+                .send_raw("m.room.message", marked(&text))
+                .with_transaction_id(txns.txn_id("embed"))
+                .await;
+            match text_result {
+                Ok(response) => Ok([media_event_id, Some(response.response.event_id)]
+                    .into_iter()
+                    .flatten()
+                    .collect()),
+                Err(e) => {
+                    if let Some(media_event_id) = &media_event_id {
+                        redact_partial_embed(room, media_event_id).await;
+                    }
+                    Err(anyhow::Error::from(e).context("Failed to post embed text after media"))
+                }
+            }
+        }
+    }
+}
+
+/// Redact `event_id`, the half of an embed that went out before the other
+/// half failed.
+async fn redact_partial_embed(room: &Room, event_id: &EventId) {
+    if let Err(e) = room
+        .redact(event_id, Some("The rest of the embed failed"), None)
+        .await
+    {
+        error!("Failed to redact partial embed {}: {:?}", event_id, e);
+    }
+}
+
+/// Post the media of an embed with the text as its caption, falling back to
+/// the text alone if the media can't be posted, and return the event ID of
+/// the message we sent (if any). `part` names the event for its
+/// transaction ID.
+async fn post_media(
+    http_clients: &HttpClients,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    params: MessageParams,
+    reply_target: &ReplyTarget,
+    referer: &Url,
+    txns: &EmbedTxns,
+    part: &str,
+) -> Result<Option<OwnedEventId>> {
+    let has_text = !params.body.is_empty() || !params.html_body.is_empty();
+
+    let caption = if has_text {
+        Some(TextMessageEventContent::html(
+            params.body.clone(),
+            params.html_body.clone(),
+        ))
+    } else {
+        None
+    };
+
+    if let Some(media_url) = params.media_url {
+        let result = match params.media_rejected {
+            Some(e) => Err(e),
+            None => {
+                info!("Downloading media from {}", media_url);
+                let http_client = http_clients.for_url(&media_url);
+                let upload = download_and_upload(
+                    http_clients,
+                    room,
+                    &media_url,
+                    config,
+                    database,
+                    caption.clone(),
+                    params.alt_text.as_deref(),
+                    Some(referer),
+                    reply_target,
+                    Some(txns.txn_id(part)),
+                    params.media_is_thumbnail,
+                );
+                if params.media_is_video && config.video_preview_bytes > 0 {
+                    let preview =
+                        fetch_video_preview(http_client, &media_url, Some(referer), config);
+                    let upload = upload_with_preview(
+                        room,
+                        config,
+                        database,
+                        caption.clone(),
+                        reply_target,
+                        txns,
+                        preview,
+                        upload,
+                    );
+                    with_typing(room, config, upload).await
+                } else {
+                    with_typing(room, config, upload).await
+                }
+            }
+        };
+
+        // A timeout or a busy server may well be gone by now, so try once
+        // more before giving up on this media.
+        let result = match result {
+            Err(e) if EmbedError::of(&e).is_transient() => {
+                warn!(
+                    "Failed to upload media ({}), trying again: {:?}",
+                    EmbedError::of(&e),
+                    e
+                );
+                tokio::time::sleep(TRANSIENT_RETRY_DELAY).await;
+                with_typing(
+                    room,
+                    config,
+                    download_and_upload(
+                        http_clients,
+                        room,
+                        &media_url,
+                        config,
+                        database,
+                        caption.clone(),
+                        params.alt_text.as_deref(),
+                        Some(referer),
+                        reply_target,
+                        Some(txns.txn_id(part)),
+                        params.media_is_thumbnail,
+                    ),
+                )
+                .await
+            }
+            result => result,
+        };
+
+        // The full-resolution original may not exist under the guessed
+        // name, or be too large; the image the page gave will do.
+        let result = match (result, &params.fallback_image_url) {
+            (Err(e), Some(fallback_url)) => {
+                warn!(
+                    "Failed to upload original image, trying {}: {:?}",
+                    fallback_url, e
+                );
+                with_typing(
+                    room,
+                    config,
+                    download_and_upload(
+                        http_clients,
+                        room,
+                        fallback_url,
+                        config,
+                        database,
+                        caption.clone(),
+                        params.alt_text.as_deref(),
+                        Some(referer),
+                        reply_target,
+                        Some(txns.txn_id(part)),
+                        params.media_is_thumbnail,
+                    ),
+                )
+                .await
+            }
+            (result, _) => result,
+        };
+
+        match result {
+            Ok(event_id) => {
+                if params.post_poster
+                    && let Some(poster_url) = &params.poster_url
+                {
+                    info!("Posting poster image {}", poster_url);
+                    let result = download_and_upload(
+                        http_clients,
+                        room,
+                        poster_url,
+                        config,
+                        database,
+                        None,
+                        None,
+                        Some(referer),
+                        reply_target,
+                        Some(txns.txn_id(&format!("{part}-poster"))),
+                        false,
+                    )
+                    .await;
+                    if let Err(e) = result {
+                        warn!("Failed to upload poster image: {:?}", e);
+                    }
+                }
+                return Ok(Some(event_id));
+            }
+            Err(e) => {
+                error!("Failed to upload media: {:?}", e);
+                report_failure(room, config, &media_url, Some(Stage::Media), &e).await;
+
+                if config.retry_alternate_video
+                    && EmbedError::of(&e) == EmbedError::UnexpectedContent
+                    && let Some(alternate_url) = &params.alternate_video_url
+                {
+                    info!("Trying alternate video {}", alternate_url);
+                    let result = with_typing(
+                        room,
+                        config,
+                        download_and_upload(
+                            http_clients,
+                            room,
+                            alternate_url,
+                            config,
+                            database,
+                            caption,
+                            None,
+                            Some(referer),
+                            reply_target,
+                            Some(txns.txn_id(part)),
+                            false,
+                        ),
+                    )
+                    .await;
+                    match result {
+                        Ok(event_id) => return Ok(Some(event_id)),
+                        Err(e) => error!("Failed to upload alternate video: {:?}", e),
+                    }
+                }
+
+                let mut body = params.body;
+                let mut html_body = params.html_body;
+
+                // Oversized video: note the size and link to the source,
+                // and try to post the poster frame instead.
+                if params.media_is_video
+                    && let Some(too_large) = e.downcast_ref::<FileTooLarge>()
+                {
+                    let (note, html_note) =
+                        oversized_video_note(&media_url, too_large, params.video_duration);
+                    if !body.is_empty() {
+                        body.push_str("\n\n");
+                    }
+                    body.push_str(&note);
+                    html_body.push_str(&html_note);
+
+                    if let Some(poster_url) = &params.poster_url {
+                        info!("Posting poster image {} instead", poster_url);
+                        let caption =
+                            TextMessageEventContent::html(body.clone(), html_body.clone());
+                        let result = with_typing(
+                            room,
+                            config,
+                            download_and_upload(
+                                http_clients,
+                                room,
+                                poster_url,
+                                config,
+                                database,
+                                Some(caption),
+                                None,
+                                Some(referer),
+                                reply_target,
+                                Some(txns.txn_id(part)),
+                                false,
+                            ),
+                        )
+                        .await;
+                        match result {
+                            Ok(event_id) => return Ok(Some(event_id)),
+                            Err(e) => error!("Failed to upload poster image: {:?}", e),
+                        }
+                    }
+                }
+
+                // Fallback: post text embed if available.
+                if !body.is_empty() || !html_body.is_empty() {
+                    let content =
+                        make_text_reply(body, html_body, room.room_id(), config, reply_target);
+                    let response = room
+                        .send_raw("m.room.message", marked(&content))
+                        .with_transaction_id(txns.txn_id(part))
+                        .await?;
+                    return Ok(Some(response.response.event_id));
+                }
+            }
+        }
+    } else if has_text {
+        let content = make_text_reply(
+            params.body,
+            params.html_body,
+            room.room_id(),
+            config,
+            reply_target,
+        );
+        let response = room
+            .send_raw("m.room.message", marked(&content))
+            .with_transaction_id(txns.txn_id(part))
+            .await?;
+        return Ok(Some(response.response.event_id));
+    }
+
+    Ok(None)
+}
+
+/// Run `upload`, and if `preview` of the video is ready first, post it as a
+/// placeholder while the upload goes on. The placeholder is redacted
+/// afterwards whether or not the upload succeeded, and skipped if the upload
+/// finishes before it's ready to send.
+async fn upload_with_preview(
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    caption: Option<TextMessageEventContent>,
+    reply_target: &ReplyTarget,
+    txns: &EmbedTxns,
+    preview: impl Future<Output = Result<Option<VideoPreview>>>,
+    upload: impl Future<Output = Result<OwnedEventId>>,
+) -> Result<OwnedEventId> {
+    tokio::pin!(upload);
+    // Nothing is visible until the placeholder is sent, so it can still be
+    // dropped if the upload wins.
+    let content = async {
+        match preview.await? {
+            Some(preview) => {
+                preview_message(room, config, database, preview, caption, reply_target)
+                    .await
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    };
+    let content = tokio::select! {
+        result = &mut upload => return result,
+        content = content => content,
+    };
+    let content = match content {
+        Ok(Some(content)) => content,
+        Ok(None) => return upload.await,
+        Err(e) => {
+            debug!("No video preview: {:?}", e);
+            return upload.await;
+        }
+    };
+
+    // Keep the download going while the placeholder is sent; a send already
+    // under way is finished, so its event can be redacted.
+    let send = post_placeholder(room, database, content, txns);
+    tokio::pin!(send);
+    let (placeholder, result) = tokio::select! {
+        result = &mut upload => (send.await, result),
+        placeholder = &mut send => (placeholder, upload.await),
+    };
+
+    match placeholder {
+        Ok(placeholder) => {
+            match room
+                .redact(&placeholder, Some("Video finished uploading"), None)
+                .await
+            {
+                Ok(_) => {
+                    if let Err(e) = database.forget_embed(placeholder.as_str()).await {
+                        warn!("Failed to forget video preview {}: {:?}", placeholder, e);
+                    }
+                }
+                Err(e) => warn!("Failed to redact video preview {}: {:?}", placeholder, e),
+            }
+        }
+        Err(e) => warn!("Failed to post video preview: {:?}", e),
+    }
+    result
+}
+
+/// Send a video preview placeholder, recording it with the rest of the embed
+/// so it can be purged. Its transaction ID is derived from the embed's, so
+/// sending it again after a restart doesn't post it twice.
+async fn post_placeholder(
+    room: &Room,
+    database: &Database,
+    content: RoomMessageEventContent,
+    txns: &EmbedTxns,
+) -> Result<OwnedEventId> {
+    let response = room
+        .send_raw("m.room.message", marked(&content))
+        .with_transaction_id(txns.txn_id("preview"))
+        .await?;
+    let event_id = response.response.event_id;
+    if let Err(e) = database
+        .record_embed(
+            room.room_id().as_str(),
+            event_id.as_str(),
+            txns.source().as_str(),
+            txns.url().as_str(),
+        )
+        .await
+    {
+        warn!("Failed to record video preview {}: {:?}", event_id, e);
+    }
+    Ok(event_id)
+}
+
+/// The message of a video preview: an image with the embed's caption.
+async fn preview_message(
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    preview: VideoPreview,
+    caption: Option<TextMessageEventContent>,
+    reply_target: &ReplyTarget,
+) -> Result<RoomMessageEventContent> {
+    let attachment = AttachmentData {
+        filename: "preview.webp".to_string(),
+        mime_type: "image/webp".parse().unwrap(),
+        data: preview.thumbnail,
+        info: Some(AttachmentInfo::Image(BaseImageInfo {
+            width: Some(preview.width.into()),
+            height: Some(preview.height.into()),
+            blurhash: preview.blurhash,
+            ..Default::default()
+        })),
+        thumbnail: None,
+        caption,
+    };
+    let msgtype =
+        upload::attachment_message(room, config, database, attachment, "preview.webp").await?;
+    make_media_reply(
+        room,
+        RoomMessageEventContent::new(msgtype),
+        config,
+        reply_target,
+    )
+    .await
+}
+
+/// Construct a text embed message, as a reply if configured.
+fn make_text_reply(
+    body: String,
+    html_body: String,
+    room_id: &RoomId,
+    config: &Config,
+    reply_target: &ReplyTarget,
+) -> RoomMessageEventContent {
+    make_reply(
+        RoomMessageEventContent::text_html(body, html_body),
+        room_id,
+        config,
+        reply_target,
+    )
+}
+
+/// `AddMentions` setting for embed replies.
+fn add_mentions(config: &Config) -> AddMentions {
+    if config.mention_sender {
+        AddMentions::Yes
+    } else {
+        AddMentions::No
+    }
+}
+
+/// Relate arbitrary message content to `reply_target`: a reply, a thread
+/// message, or nothing for [`ReplyTarget::None`].
+///
+/// When the original event is available it gets a full reply (with mentions
+/// and, if enabled, the rich-reply fallback quote); otherwise only a bare
+/// `m.in_reply_to` relation is added.
+fn make_reply(
+    content: RoomMessageEventContent,
+    room_id: &RoomId,
+    config: &Config,
+    reply_target: &ReplyTarget,
+) -> RoomMessageEventContent {
+    match reply_target {
+        ReplyTarget::Event(event) => {
+            let mut content = content;
+            if config.reply_fallback {
+                add_reply_fallback(&mut content, room_id, event);
+            }
+            content.make_reply_to(event.as_ref(), ForwardThread::Yes, add_mentions(config))
+        }
+        ReplyTarget::EventId(id) => {
+            let mut content = content;
+            content.relates_to = Some(Relation::Reply(
+                matrix_sdk::ruma::events::relation::Reply::new(InReplyTo::new(id.clone())),
+            ));
+            content
+        }
+        ReplyTarget::None => content,
+        ReplyTarget::Thread { root, in_reply_to } => {
+            let mut content = content;
+            content.relates_to = Some(Relation::Thread(Thread::plain(
+                root.clone(),
+                in_reply_to.clone(),
+            )));
+            content
+        }
+        ReplyTarget::Replace(event_id) => {
+            content.make_replacement(ReplacementMetadata::new(event_id.clone(), None))
+        }
+    }
+}
+
+/// Relate media content to `reply_target` like [`make_reply`], except that a
+/// reply to a message in a thread goes in that thread too. Media has no
+/// fallback quote, so there's nothing else the original event is needed for.
+async fn make_media_reply(
+    room: &Room,
+    content: RoomMessageEventContent,
+    config: &Config,
+    reply_target: &ReplyTarget,
+) -> Result<RoomMessageEventContent> {
+    let event_id = match reply_target {
+        ReplyTarget::Event(event) => &event.event_id,
+        ReplyTarget::EventId(id) => id,
+        _ => return Ok(make_reply(content, room.room_id(), config, reply_target)),
+    };
+    make_threaded_reply(room, content, event_id, add_mentions(config)).await
+}
+
+/// Make `content` a reply to `event_id`, in the same thread if that event is
+/// in one.
+async fn make_threaded_reply(
+    room: &Room,
+    content: RoomMessageEventContent,
+    event_id: &EventId,
+    add_mentions: AddMentions,
+) -> Result<RoomMessageEventContent> {
     let reply = Reply {
         event_id: event_id.to_owned(),
         enforce_thread: EnforceThread::MaybeThreaded,
-        add_mentions: AddMentions::No,
+        add_mentions,
+    };
+    room.make_reply_event(content.into(), reply)
+        .await
+        .context("Failed to build reply")
+}
+
+/// Prepend the rich-reply fallback quote of `event` to a text message.
+fn add_reply_fallback(
+    content: &mut RoomMessageEventContent,
+    room_id: &RoomId,
+    event: &OriginalSyncRoomMessageEvent,
+) {
+    let MessageType::Text(text) = &mut content.msgtype else {
+        return;
+    };
+
+    let (plain_quote, html_quote) = reply_fallback(
+        room_id.as_str(),
+        event.event_id.as_str(),
+        event.sender.as_str(),
+        event.content.body(),
+    );
+    let html = match &text.formatted {
+        Some(formatted) => formatted.body.clone(),
+        None => html_escape::encode_text(&text.body).to_string(),
+    };
+
+    text.body = format!("{}{}", plain_quote, text.body);
+    text.formatted = Some(FormattedBody::html(format!("{}{}", html_quote, html)));
+}
+
+/// Fetch the metadata of `url` in `language`, reusing what was fetched
+/// recently unless `fresh` data is asked for.
+async fn fetch_metadata(
+    http_clients: &HttpClients,
+    config: &Config,
+    database: &Database,
+    url: &Url,
+    ap_detector: &ActivityPubDetector,
+    language: Option<&str>,
+    fresh: bool,
+) -> Result<Metadata> {
+    if !fresh {
+        match database.get_cached_metadata(url, language).await {
+            Ok(Some(meta)) => {
+                debug!("Using cached metadata for {}", url);
+                return Ok(meta);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to look up cached metadata: {:?}", e),
+        }
+    }
+    let started = Instant::now();
+    let fetched = Metadata::fetch_from_url(
+        http_clients.for_url(url),
+        url,
+        config,
+        ap_detector,
+        language,
+    )
+    .await;
+    metrics().record_step(Step::Metadata, NO_MEDIA, started.elapsed());
+    let meta = match fetched {
+        Ok(meta) => meta,
+        Err(e) => {
+            // A frontend instance that's down is passed over for a while.
+            if EmbedError::of(&e).is_transient() {
+                config.mark_rewrite_failed(url);
+            }
+            return Err(e);
+        }
+    };
+    if let Err(e) = database
+        .store_metadata(url, language, &meta, config.cache_ttl(url))
+        .await
+    {
+        warn!("Failed to cache metadata for {}: {:?}", url, e);
+    }
+    Ok(meta)
+}
+
+/// Check `url` against the blocklists and Safe Browsing. A link that can't
+/// be checked is embedded as usual, so an outage doesn't stop all embeds.
+async fn screen_link(
+    http_clients: &HttpClients,
+    config: &Config,
+    database: &Database,
+    url: &Url,
+) -> Option<Verdict> {
+    match safety::check(http_clients.default_client(), config, database, url).await {
+        Ok(Some(verdict)) => {
+            info!(
+                "Not embedding {}: flagged as {} by {:?}",
+                url,
+                verdict.threat.name(),
+                verdict.source
+            );
+            Some(verdict)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Failed to check whether {} is safe: {:?}", url, e);
+            None
+        }
+    }
+}
+
+/// Post a warning about `url` in place of its embed.
+async fn post_link_warning(
+    room: &Room,
+    config: &Config,
+    url: &Url,
+    verdict: &Verdict,
+    reply_target: &ReplyTarget,
+    txn_id: OwnedTransactionId,
+) -> Result<OwnedEventId> {
+    let (body, html_body) = safety::warning(url, verdict);
+    let content = make_reply(
+        RoomMessageEventContent::notice_html(body, html_body),
+        room.room_id(),
+        config,
+        reply_target,
+    );
+    let response = room
+        .send_raw("m.room.message", marked(&content))
+        .with_transaction_id(txn_id)
+        .await
+        .context("Failed to post link warning")?;
+    Ok(response.response.event_id)
+}
+
+/// Post an `m.location` event for `point`, attaching a static map thumbnail
+/// when a map service is configured.
+async fn post_location(
+    http_client: &reqwest::Client,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    point: &GeoPoint,
+    reply_target: &ReplyTarget,
+    txn_id: OwnedTransactionId,
+) -> Result<OwnedEventId> {
+    let mut location = LocationMessageEventContent::new(point.body(), point.geo_uri());
+
+    if let Some(template) = &config.static_map_url
+        && let Some(map_url) = point.static_map_url(template)
+    {
+        match upload_static_map(http_client, room, config, database, &map_url).await {
+            Ok(info) => location.info = Some(Box::new(info)),
+            Err(e) => warn!("Failed to attach static map {}: {:?}", map_url, e),
+        }
+    }
+
+    let content = make_reply(
+        RoomMessageEventContent::new(MessageType::Location(location)),
+        room.room_id(),
+        config,
+        reply_target,
+    );
+    let response = room
+        .send_raw("m.room.message", marked(&content))
+        .with_transaction_id(txn_id)
+        .await?;
+    Ok(response.response.event_id)
+}
+
+/// Download a static map image and upload it as a location thumbnail.
+async fn upload_static_map(
+    http_client: &reqwest::Client,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    map_url: &Url,
+) -> Result<LocationInfo> {
+    let (data, mime_type) = fetch_image(http_client, config, map_url, config.max_file_size)
+        .await
+        .context("Failed to fetch static map")?;
+
+    let mut thumbnail_info = ThumbnailInfo::new();
+    thumbnail_info.mimetype = Some(mime_type.to_string());
+    thumbnail_info.size = Some((data.len() as u32).into());
+    if let Ok(info) = image_dimensions(&data) {
+        thumbnail_info.width = Some(info.width.into());
+        thumbnail_info.height = Some(info.height.into());
+    }
+
+    let encrypted = room.latest_encryption_state().await?.is_encrypted();
+    let source = upload::upload_media(&room.client(), encrypted, &mime_type, data)
+        .await
+        .context("Failed to upload static map")?;
+    upload::track_upload(database, &source, None).await;
+
+    let mut info = LocationInfo::new();
+    info.thumbnail_source = Some(source);
+    info.thumbnail_info = Some(Box::new(thumbnail_info));
+    Ok(info)
+}
+
+/// Whether `www.` links without a scheme are embedded in `room`, either
+/// globally or because the room enabled them.
+async fn bare_www_links(room: &Room, config: &Config, database: &Database) -> bool {
+    if config.bare_www_links {
+        return true;
+    }
+    match database
+        .is_bare_links_enabled(room.room_id().as_str())
+        .await
+    {
+        Ok(enabled) => enabled,
+        Err(e) => {
+            error!("Failed to check bare links status: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Whether links in media captions and sticker descriptions are embedded in
+/// `room`, either globally or because the room enabled them.
+async fn caption_links(room: &Room, config: &Config, database: &Database) -> bool {
+    if config.caption_links {
+        return true;
+    }
+    match database
+        .is_caption_links_enabled(room.room_id().as_str())
+        .await
+    {
+        Ok(enabled) => enabled,
+        Err(e) => {
+            error!("Failed to check caption links status: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Whether embedded videos are followed by their poster image in `room`,
+/// either globally or because the room enabled it.
+async fn video_posters(room: &Room, config: &Config, database: &Database) -> bool {
+    if config.video_posters {
+        return true;
+    }
+    match database
+        .is_video_posters_enabled(room.room_id().as_str())
+        .await
+    {
+        Ok(enabled) => enabled,
+        Err(e) => {
+            error!("Failed to check video posters status: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Whether the image of summary cards is posted as a small thumbnail in
+/// `room`, either globally or because the room enabled it.
+async fn summary_thumbnails(room: &Room, config: &Config, database: &Database) -> bool {
+    if config.summary_thumbnails {
+        return true;
+    }
+    match database
+        .is_summary_thumbnails_enabled(room.room_id().as_str())
+        .await
+    {
+        Ok(enabled) => enabled,
+        Err(e) => {
+            error!("Failed to check summary thumbnails status: {:?}", e);
+            false
+        }
+    }
+}
+
+/// The text of a message to look for links in: the body of a text message,
+/// or the caption of an image, video, audio or file message if `room` has
+/// caption links enabled.
+async fn linkable_text(
+    msgtype: &MessageType,
+    room: &Room,
+    config: &Config,
+    database: &Database,
+) -> Option<TextMessageEventContent> {
+    let (caption, formatted) = match msgtype {
+        MessageType::Text(text) => return Some(text.clone()),
+        MessageType::Image(content) => (content.caption(), content.formatted_caption()),
+        MessageType::Video(content) => (content.caption(), content.formatted_caption()),
+        MessageType::Audio(content) => (content.caption(), content.formatted_caption()),
+        MessageType::File(content) => (content.caption(), content.formatted_caption()),
+        _ => return None,
+    };
+    let caption = caption?;
+    if !caption_links(room, config, database).await {
+        return None;
+    }
+    let mut text = TextMessageEventContent::plain(caption);
+    text.formatted = formatted.cloned();
+    Some(text)
+}
+
+/// How times are written in `room`, from its timezone and time style
+/// settings.
+async fn room_time_format(room: &Room, config: &Config, database: &Database) -> TimeFormat {
+    let (timezone, style) = match database.get_room_time_format(room.room_id().as_str()).await {
+        Ok(format) => format,
+        Err(e) => {
+            error!("Failed to look up room time format: {:?}", e);
+            (None, None)
+        }
+    };
+    let timezone = timezone.and_then(|name| match name.parse() {
+        Ok(timezone) => Some(timezone),
+        Err(e) => {
+            warn!("Ignoring invalid room timezone {}: {}", name, e);
+            None
+        }
+    });
+    config.time_format(timezone, style)
+}
+
+/// The language pages linked in `room` are asked for in: its own, or else
+/// the global one.
+async fn room_language(room: &Room, config: &Config, database: &Database) -> Option<String> {
+    command::room_language(room.room_id().as_str(), config, database).await
+}
+
+/// What videos in `room` are converted to, from its data saver and video
+/// format settings.
+async fn room_video_target(room: &Room, config: &Config, database: &Database) -> VideoTarget {
+    let data_saver = match database
+        .is_data_saver_enabled(room.room_id().as_str())
+        .await
+    {
+        Ok(enabled) => enabled,
+        Err(e) => {
+            error!("Failed to check data saver status: {:?}", e);
+            false
+        }
+    };
+    let room_format = match database.get_video_format(room.room_id().as_str()).await {
+        Ok(format) => format,
+        Err(e) => {
+            error!("Failed to look up room video format: {:?}", e);
+            None
+        }
+    };
+    config.video_target(room_format, data_saver)
+}
+
+/// Download media from a URL and re-upload it to the Matrix room, reusing an
+/// earlier upload if the content is identical.
+///
+/// If there's no caption, `alt_text` is used as the body of image uploads in
+/// place of the filename, since that's what screen readers announce. With
+/// `as_thumbnail`, images are downscaled to a small thumbnail first.
+///
+/// Returns the event ID of the sent attachment message.
+pub async fn download_and_upload(
+    http_clients: &HttpClients,
+    room: &Room,
+    url: &Url,
+    config: &Config,
+    database: &Database,
+    text: Option<TextMessageEventContent>,
+    alt_text: Option<&str>,
+    referer: Option<&Url>,
+    reply_target: &ReplyTarget,
+    txn_id: Option<OwnedTransactionId>,
+    as_thumbnail: bool,
+) -> Result<OwnedEventId> {
+    let alt_text = alt_text.filter(|_| text.is_none());
+    let client = http_clients.for_url(url);
+    let mut request = client
+        .get(url.clone())
+        .timeout(config.download_timeout)
+        .header(
+            reqwest::header::USER_AGENT,
+            http::user_agent(config, url, Fetch::Media),
+        );
+    if let Some(referer) = referer {
+        request = request.header(reqwest::header::REFERER, referer.as_str());
+    }
+    let resume_request = request.try_clone();
+    let cache = match http_clients.media_cache() {
+        Some(cache) => {
+            // A refresh downloads the media again, and caches what it gets.
+            let cached = match reply_target {
+                ReplyTarget::Replace(_) => None,
+                _ => cache.get(url).await,
+            };
+            if let Some(cached) = &cached {
+                request = cached.revalidate(request);
+            }
+            Some(CacheSlot { cache, url, cached })
+        }
+        None => None,
     };
+    let response = request.send().await.context("Failed to start download")?;
+
+    let video = room_video_target(room, config, database).await;
+    let attachment = process_response(
+        client,
+        response,
+        resume_request,
+        cache,
+        config,
+        &video,
+        text,
+    )
+    .await?;
+    let attachment = if as_thumbnail {
+        shrink_to_thumbnail(attachment, config).await
+    } else {
+        attachment
+    };
+    metrics::note_embed_media(&attachment.mime_type, attachment.data.len());
+    let media_type = metrics::media_label(&attachment.mime_type);
+    let upload_started = Instant::now();
+
+    let body = match alt_text {
+        Some(alt) if attachment.mime_type.type_() == mime_guess::mime::IMAGE => alt.to_owned(),
+        _ => attachment.filename.clone(),
+    };
+
+    let inline = config.media_mode == MediaMode::Inline
+        && attachment.mime_type.type_() == mime_guess::mime::IMAGE
+        && !room.latest_encryption_state().await?.is_encrypted();
+    let msgtype = if inline {
+        upload::inline_image_message(room, config, database, attachment, &body).await?
+    } else {
+        upload::attachment_message(room, config, database, attachment, &body).await?
+    };
+    let content = make_media_reply(
+        room,
+        RoomMessageEventContent::new(msgtype),
+        config,
+        reply_target,
+    )
+    .await?;
+    let mut request = room.send_raw("m.room.message", marked(&content));
+    if let Some(txn_id) = txn_id {
+        request = request.with_transaction_id(txn_id);
+    }
+    let response = request.await?;
+    metrics().record_step(Step::Upload, media_type, upload_started.elapsed());
+
+    Ok(response.response.event_id)
+}
 
-    if let Some(cas_hash) = &canned.media_cas_hash {
+/// Send a canned response (from a custom command or autoresponder) as a reply.
+async fn send_canned_response(
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    canned: &CannedResponse,
+    event_id: &matrix_sdk::ruma::EventId,
+    media_store: &MediaStore,
+) -> Result<()> {
+    let content = if let Some(cas_hash) = &canned.media_cas_hash {
         let data = media_store.load(cas_hash).await?;
         let mime_str = canned
             .media_mime_type
@@ -515,38 +3359,88 @@ async fn send_canned_response(
             .as_deref()
             .map(TextMessageEventContent::markdown);
 
-        let attach_config = AttachmentConfig::new().caption(caption).reply(Some(reply));
-
-        room.send_attachment(filename, &mime, data, attach_config)
-            .await?;
+        let attachment = AttachmentData {
+            filename: filename.to_owned(),
+            mime_type: mime,
+            data,
+            info: None,
+            thumbnail: None,
+            caption,
+        };
+        let msgtype =
+            upload::attachment_message(room, config, database, attachment, filename).await?;
+        RoomMessageEventContent::new(msgtype)
     } else if let Some(text) = &canned.text_markdown {
-        let mut content = RoomMessageEventContent::text_markdown(text);
-        content.relates_to = Some(Relation::Reply(
-            matrix_sdk::ruma::events::relation::Reply::new(InReplyTo::new(event_id.to_owned())),
-        ));
-        room.send(content).await?;
-    }
+        RoomMessageEventContent::text_markdown(text)
+    } else {
+        return Ok(());
+    };
+    let content = make_threaded_reply(room, content, event_id, AddMentions::No).await?;
+    room.send_raw("m.room.message", marked(&content)).await?;
 
     Ok(())
 }
 
 /// Keep a typing indicator active for the duration of an async operation.
-async fn with_typing<F, T>(room: &Room, fut: F) -> T
+///
+/// The notice is sent with the configured timeout and refreshed shortly
+/// before it expires. It is cleared when the operation finishes, and also if
+/// it panics or is cancelled.
+async fn with_typing<F, T>(room: &Room, config: &Config, fut: F) -> T
 where
     F: Future<Output = T>,
 {
+    if !config.typing_notices {
+        return fut.await;
+    }
+
     let typing_room = room.clone();
-    let typing_task = tokio::spawn(async move {
+    let timeout = config.typing_timeout;
+    let refresh = timeout.saturating_sub(TYPING_REFRESH_MARGIN);
+    let task = tokio::spawn(async move {
         loop {
-            let _ = typing_room.typing_notice(true).await;
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            if let Err(e) = send_typing(&typing_room, Typing::Yes(TypingInfo::new(timeout))).await {
+                debug!("Failed to send typing notice: {:?}", e);
+            }
+            tokio::time::sleep(refresh).await;
         }
     });
+    let _guard = TypingGuard {
+        room: room.clone(),
+        task,
+    };
+
+    fut.await
+}
 
-    let result = fut.await;
+/// Stops the typing refresh loop and clears the notice when dropped.
+struct TypingGuard {
+    room: Room,
+    task: tokio::task::JoinHandle<()>,
+}
 
-    typing_task.abort();
-    let _ = room.typing_notice(false).await;
+impl Drop for TypingGuard {
+    fn drop(&mut self) {
+        self.task.abort();
+        let room = self.room.clone();
+        tokio::spawn(async move {
+            if let Err(e) = send_typing(&room, Typing::No).await {
+                debug!("Failed to clear typing notice: {:?}", e);
+            }
+        });
+    }
+}
 
-    result
+/// Send a typing notice directly. `Room::typing_notice` uses a fixed timeout
+/// and debounces requests, so it can't be used with a custom timeout.
+async fn send_typing(room: &Room, typing: Typing) -> Result<()> {
+    let client = room.client();
+    let user_id = client.user_id().context("Client is not logged in")?;
+    let request = create_typing_event::v3::Request::new(
+        user_id.to_owned(),
+        room.room_id().to_owned(),
+        typing,
+    );
+    client.send(request).await?;
+    Ok(())
 }
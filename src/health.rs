@@ -0,0 +1,235 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+#[cfg(feature = "http-api")]
+use tokio::net::TcpStream;
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
+
+/// Sync is considered unhealthy if it hasn't succeeded for this long.
+const MAX_SYNC_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Delay before the first retry after a failed sync.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the retry delay.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Default)]
+struct SyncStatus {
+    last_success: Option<Instant>,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct HealthReport {
+    healthy: bool,
+    seconds_since_last_sync: Option<u64>,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+/// Shared view of how the sync loop is doing, reported by the health
+/// endpoint.
+#[derive(Debug, Default)]
+pub struct SyncHealth {
+    status: Mutex<SyncStatus>,
+}
+
+impl SyncHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_success(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.last_success = Some(Instant::now());
+        status.consecutive_failures = 0;
+        status.last_error = None;
+    }
+
+    /// Record a failed sync and return the number of consecutive failures.
+    pub fn record_failure(&self, error: &str) -> u32 {
+        let mut status = self.status.lock().unwrap();
+        status.consecutive_failures += 1;
+        status.last_error = Some(error.to_string());
+        status.consecutive_failures
+    }
+
+    #[cfg_attr(not(feature = "http-api"), allow(dead_code))]
+    fn report(&self) -> HealthReport {
+        let status = self.status.lock().unwrap();
+        let age = status.last_success.map(|t| t.elapsed());
+        HealthReport {
+            healthy: age.is_some_and(|age| age < MAX_SYNC_AGE),
+            seconds_since_last_sync: age.map(|age| age.as_secs()),
+            consecutive_failures: status.consecutive_failures,
+            last_error: status.last_error.clone(),
+        }
+    }
+}
+
+/// Exponential backoff for the `failures`th consecutive failed sync.
+pub fn backoff_delay(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    INITIAL_BACKOFF
+        .saturating_mul(1 << exponent)
+        .min(MAX_BACKOFF)
+}
+
+/// Serve a minimal HTTP health endpoint on `addr`. Requests for `/metrics` get
+/// the process metrics in the Prometheus text format, with the `metrics`
+/// feature; any other request gets a JSON report, with status 200 if sync is
+/// healthy and 503 otherwise.
+#[cfg(feature = "http-api")]
+pub async fn serve(addr: SocketAddr, health: Arc<SyncHealth>) -> Result<()> {
+    use anyhow::Context;
+    use tokio::net::TcpListener;
+    use tracing::{debug, info, warn};
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind health endpoint to {}", addr))?;
+    info!("Health endpoint listening on {}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let health = health.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream, &health).await {
+                            debug!("Health request from {} failed: {:?}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept health connection: {:?}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(feature = "http-api"))]
+pub async fn serve(_addr: SocketAddr, _health: Arc<SyncHealth>) -> Result<()> {
+    anyhow::bail!(
+        "This build doesn't support the health endpoint; rebuild with the `http-api` feature"
+    );
+}
+
+#[cfg(feature = "http-api")]
+async fn respond(mut stream: TcpStream, health: &SyncHealth) -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Only the request line matters; we don't care about headers or a body.
+    let mut buf = [0u8; 1024];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .ok()
+        .and_then(|r| r.ok())
+        .unwrap_or(0);
+    let request = String::from_utf8_lossy(&buf[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, content_type, body) = match path {
+        #[cfg(feature = "metrics")]
+        "/metrics" => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics::metrics().render(),
+        ),
+        _ => {
+            let report = health.report();
+            let status = if report.healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, "application/json", serde_json::to_string(&report)?)
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(backoff_delay(1), Duration::from_secs(1));
+        assert_eq!(backoff_delay(2), Duration::from_secs(2));
+        assert_eq!(backoff_delay(5), Duration::from_secs(16));
+        assert_eq!(backoff_delay(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_sync_health() {
+        let health = SyncHealth::new();
+        assert!(!health.report().healthy);
+
+        health.record_success();
+        assert!(health.report().healthy);
+
+        assert_eq!(health.record_failure("timeout"), 1);
+        assert_eq!(health.record_failure("timeout"), 2);
+        // Still healthy until the last success is too old.
+        assert!(health.report().healthy);
+        assert_eq!(health.report().last_error.as_deref(), Some("timeout"));
+
+        health.record_success();
+        assert_eq!(health.report().consecutive_failures, 0);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_serve() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let health = Arc::new(SyncHealth::new());
+        serve(addr, health.clone()).await.unwrap();
+
+        let fetch_path = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let fetch = || fetch_path("/health");
+
+        assert!(fetch().await.starts_with("HTTP/1.1 503"));
+        health.record_success();
+        let response = fetch().await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains(r#""healthy":true"#));
+
+        let response = fetch_path("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("embed_downloads_total"));
+    }
+}
@@ -0,0 +1,378 @@
+use std::cell::RefCell;
+
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::states::RawKind;
+use html5ever::tokenizer::{
+    BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+};
+
+/// How much of the page is handed to the tokenizer at once.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Tags whose attributes metadata is read from.
+const KEPT_TAGS: &[&str] = &["meta", "link", "video"];
+
+/// Attributes kept in [`Scan::markup`], the only ones the article text
+/// extraction looks at.
+const MARKUP_ATTRIBUTES: &[&str] = &["class", "id", "role", "itemprop"];
+
+/// Elements whose whole subtree is dropped: inline images and formulas can
+/// be as large as scripts, and nothing is read from them.
+const FOREIGN_ELEMENTS: &[&str] = &["svg", "math", "template"];
+
+/// Roughly how many bytes the DOM the article text is extracted from takes
+/// per byte of [`Scan::markup`] it's built from.
+const DOM_BYTES_PER_MARKUP_BYTE: usize = 4;
+
+/// What keeping `len` bytes of [`Scan::markup`] costs against the budget:
+/// the markup itself and the DOM later built from it.
+fn markup_cost(len: usize) -> usize {
+    len * (1 + DOM_BYTES_PER_MARKUP_BYTE)
+}
+
+/// A start tag kept by [`scan`], with its attributes decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+}
+
+impl Tag {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the space-separated `rel` attribute includes `rel`.
+    pub fn has_rel(&self, rel: &str) -> bool {
+        self.attr("rel").is_some_and(|value| {
+            value
+                .split_whitespace()
+                .any(|r| r.eq_ignore_ascii_case(rel))
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.name.len()
+            + self
+                .attrs
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>()
+    }
+}
+
+/// What [`scan`] kept of a page.
+#[derive(Debug, Default)]
+pub struct Scan {
+    /// The `meta`, `link` and `video` tags, and the `source` tags of videos,
+    /// in document order.
+    pub tags: Vec<Tag>,
+    /// The text of the document's `title`.
+    pub title: Option<String>,
+    /// The contents of the `application/ld+json` scripts.
+    pub json_ld: Vec<String>,
+    /// The page without its scripts, styles, inline SVGs, comments and any
+    /// attributes but a few, for the article text to be extracted from.
+    pub markup: String,
+    /// An estimate of the most bytes held at once while scanning, counting
+    /// what was kept, the DOM to be built from the markup, the token being
+    /// looked at and the chunk of input being tokenized. The page itself,
+    /// which the caller already holds in full, isn't counted.
+    pub estimated_peak_bytes: usize,
+    /// Whether the budget ran out before the end of the page, so the rest
+    /// of it was skipped.
+    pub truncated: bool,
+}
+
+#[derive(Default)]
+struct State {
+    scan: Scan,
+    budget: usize,
+    /// Bytes of the kept tags, and the cost of the kept markup.
+    kept: usize,
+    /// Inside a script, style or other element whose text isn't kept.
+    in_raw_text: bool,
+    /// The JSON-LD script being read, if inside one.
+    json_ld: Option<String>,
+    /// The title being read, if inside the first one.
+    title: Option<String>,
+    /// Depth inside [`FOREIGN_ELEMENTS`], which are dropped whole.
+    foreign_depth: usize,
+    /// Depth inside `video` elements, whose `source` tags are kept.
+    video_depth: usize,
+    /// Size of the chunk of input being tokenized.
+    chunk: usize,
+}
+
+impl State {
+    /// Note that a token of `size` bytes is being looked at, and keep
+    /// `kept` bytes more, unless that's over the budget.
+    fn hold(&mut self, size: usize, kept: usize) -> bool {
+        self.scan.estimated_peak_bytes = self
+            .scan
+            .estimated_peak_bytes
+            .max(self.kept + self.chunk + size + kept);
+        // Nothing after the point the budget ran out is kept, even from the
+        // rest of the chunk the tokenizer is still going through.
+        if self.scan.truncated || self.kept + kept > self.budget {
+            self.scan.truncated = true;
+            return false;
+        }
+        self.kept += kept;
+        true
+    }
+
+    fn start_tag(&mut self, tag: html5ever::tokenizer::Tag) -> TokenSinkResult<()> {
+        let name: &str = &tag.name;
+        let tag_size = name.len()
+            + tag
+                .attrs
+                .iter()
+                .map(|attr| attr.name.local.len() + attr.value.len())
+                .sum::<usize>();
+        if self.foreign_depth > 0 || FOREIGN_ELEMENTS.contains(&name) {
+            if !tag.self_closing && FOREIGN_ELEMENTS.contains(&name) {
+                self.foreign_depth += 1;
+            }
+            self.hold(tag_size, 0);
+            return TokenSinkResult::Continue;
+        }
+
+        if KEPT_TAGS.contains(&name) || (name == "source" && self.video_depth > 0) {
+            let kept = Tag {
+                name: name.to_string(),
+                attrs: tag
+                    .attrs
+                    .iter()
+                    .map(|attr| (attr.name.local.to_string(), attr.value.to_string()))
+                    .collect(),
+            };
+            if self.hold(tag_size, kept.size()) {
+                self.scan.tags.push(kept);
+            }
+        }
+        if name == "video" && !tag.self_closing {
+            self.video_depth += 1;
+        }
+
+        let raw_text = match name {
+            "script" => Some(RawKind::ScriptData),
+            "style" | "xmp" | "iframe" | "noembed" | "noframes" => Some(RawKind::Rawtext),
+            "textarea" => Some(RawKind::Rcdata),
+            _ => None,
+        };
+        if let Some(kind) = raw_text {
+            self.in_raw_text = true;
+            let is_json_ld = tag.attrs.iter().any(|attr| {
+                &*attr.name.local == "type"
+                    && attr
+                        .value
+                        .trim()
+                        .eq_ignore_ascii_case("application/ld+json")
+            });
+            if name == "script" && is_json_ld {
+                self.json_ld = Some(String::new());
+            }
+            return TokenSinkResult::RawData(kind);
+        }
+
+        let mut markup = format!("<{}", name);
+        for attr in &tag.attrs {
+            if MARKUP_ATTRIBUTES.contains(&&*attr.name.local) {
+                markup.push_str(&format!(
+                    " {}=\"{}\"",
+                    attr.name.local,
+                    html_escape::encode_double_quoted_attribute(&attr.value)
+                ));
+            }
+        }
+        markup.push('>');
+        if self.hold(tag_size, markup_cost(markup.len())) {
+            self.scan.markup.push_str(&markup);
+        }
+        // The title is text, not markup, but it's kept.
+        if name == "title" {
+            if self.scan.title.is_none() {
+                self.title = Some(String::new());
+            }
+            return TokenSinkResult::RawData(RawKind::Rcdata);
+        }
+        TokenSinkResult::Continue
+    }
+
+    fn end_tag(&mut self, name: &str) {
+        if self.foreign_depth > 0 {
+            if FOREIGN_ELEMENTS.contains(&name) {
+                self.foreign_depth -= 1;
+            }
+            return;
+        }
+        if self.in_raw_text {
+            self.in_raw_text = false;
+            if let Some(json_ld) = self.json_ld.take()
+                && !self.scan.truncated
+            {
+                self.scan.json_ld.push(json_ld);
+            }
+            return;
+        }
+        if name == "video" {
+            self.video_depth = self.video_depth.saturating_sub(1);
+        }
+        if name == "title"
+            && let Some(title) = self.title.take()
+            && !self.scan.truncated
+        {
+            self.scan.title = Some(title);
+        }
+        let markup = format!("</{}>", name);
+        if self.hold(markup.len(), markup_cost(markup.len())) {
+            self.scan.markup.push_str(&markup);
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.json_ld.is_some() {
+            if self.hold(text.len(), text.len())
+                && let Some(json_ld) = &mut self.json_ld
+            {
+                json_ld.push_str(text);
+            }
+            return;
+        }
+        if self.in_raw_text || self.foreign_depth > 0 {
+            self.hold(text.len(), 0);
+            return;
+        }
+        let escaped = html_escape::encode_text(text);
+        let title_len = self.title.as_ref().map_or(0, |_| text.len());
+        if self.hold(text.len(), markup_cost(escaped.len()) + title_len) {
+            self.scan.markup.push_str(&escaped);
+            if let Some(title) = &mut self.title {
+                title.push_str(text);
+            }
+        }
+    }
+}
+
+struct Sink(RefCell<State>);
+
+impl TokenSink for Sink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let mut state = self.0.borrow_mut();
+        match token {
+            Token::TagToken(tag) if tag.kind == TagKind::StartTag => state.start_tag(tag),
+            Token::TagToken(tag) => {
+                state.end_tag(&tag.name);
+                TokenSinkResult::Continue
+            }
+            Token::CharacterTokens(text) => {
+                state.text(&text);
+                TokenSinkResult::Continue
+            }
+            Token::CommentToken(comment) => {
+                state.hold(comment.len(), 0);
+                TokenSinkResult::Continue
+            }
+            _ => TokenSinkResult::Continue,
+        }
+    }
+}
+
+/// Tokenize `html` a chunk at a time, keeping only what metadata and the
+/// article text are read from, so huge inline scripts and styles are never
+/// held in full. Once what's kept, with the DOM the article text will be
+/// extracted from, would exceed `budget` bytes, the rest of the page is
+/// skipped. The budget doesn't bound `html` itself, which the caller has
+/// already read in full; the page size limit does.
+pub fn scan(html: &str, budget: usize) -> Scan {
+    let tokenizer = Tokenizer::new(
+        Sink(RefCell::new(State {
+            budget,
+            ..Default::default()
+        })),
+        TokenizerOpts::default(),
+    );
+    let input = BufferQueue::default();
+    let mut start = 0;
+    while start < html.len() {
+        let mut end = (start + CHUNK_SIZE).min(html.len());
+        while !html.is_char_boundary(end) {
+            end += 1;
+        }
+        tokenizer.sink.0.borrow_mut().chunk = end - start;
+        input.push_back(StrTendril::from_slice(&html[start..end]));
+        let _ = tokenizer.feed(&input);
+        if tokenizer.sink.0.borrow().scan.truncated {
+            break;
+        }
+        start = end;
+    }
+    tokenizer.end();
+    tokenizer.sink.0.into_inner().scan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan() {
+        let html = r#"<html><head>
+            <meta property="og:title" content="Tom &amp; Jerry">
+            <script>var s = "<meta property='og:title' content='Nope'>";</script>
+            <script type="application/ld+json">{"name": "a &amp; <b>"}</script>
+            <style>p { color: red }</style>
+            <link rel="Canonical stylesheet" href="/a">
+            <title>A &lt;title&gt;</title>
+            </head><body class="post" onload="go()">
+            <svg><path d="M0 0"/><meta name="og:image" content="nope"></svg>
+            <!-- <meta name="og:description" content="Nope"> -->
+            <video src="a.mp4"><source src="a.webm"></video>
+            <picture><source srcset="b.avif"></picture>
+            <p id="lead">Text &amp; more</p>
+            </body></html>"#;
+        let scan = scan(html, 1024 * 1024);
+        assert!(!scan.truncated);
+        let names: Vec<_> = scan.tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, ["meta", "link", "video", "source"]);
+        assert_eq!(scan.tags[0].attr("content"), Some("Tom & Jerry"));
+        assert_eq!(scan.json_ld, [r#"{"name": "a &amp; <b>"}"#]);
+        assert_eq!(scan.title.as_deref(), Some("A <title>"));
+        assert!(scan.tags[1].has_rel("canonical"));
+        assert!(!scan.tags[1].has_rel("amphtml"));
+
+        assert!(scan.markup.contains("<title>A &lt;title&gt;</title>"));
+        assert!(scan.markup.contains("<body class=\"post\">"));
+        assert!(scan.markup.contains("<p id=\"lead\">Text &amp; more</p>"));
+        assert!(!scan.markup.contains("color"));
+        assert!(!scan.markup.contains("path"));
+        assert!(!scan.markup.contains("Nope"));
+    }
+
+    #[test]
+    fn test_scan_budget() {
+        let script = format!("<script>{}</script>", "x".repeat(1024 * 1024));
+        let html = format!(
+            r#"<meta property="og:title" content="Title">{}<p>{}</p><meta name="late">"#,
+            script,
+            "words ".repeat(1000)
+        );
+
+        // Scripts count toward the peak only a chunk at a time.
+        let scan = scan(&html, 64 * 1024);
+        assert!(!scan.truncated);
+        assert_eq!(scan.tags.len(), 2);
+        assert!(scan.estimated_peak_bytes < 2 * CHUNK_SIZE + 32 * 1024);
+
+        let truncated = super::scan(&html, 1024);
+        assert!(truncated.truncated);
+        assert_eq!(truncated.tags.len(), 1);
+        assert!(markup_cost(truncated.markup.len()) <= 1024);
+    }
+}
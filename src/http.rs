@@ -0,0 +1,193 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use reqwest::{ClientBuilder, Proxy};
+use url::Url;
+
+use crate::config::Config;
+use crate::media_cache::MediaCache;
+
+/// HTTP clients used for outgoing requests.
+///
+/// Hosts listed in `http1_only_domains` (and their subdomains) get a client
+/// restricted to HTTP/1.1, for CDNs whose HTTP/2 downloads stall.
+///
+/// Media downloads also go through the media cache, if there is one.
+#[derive(Clone)]
+pub struct HttpClients {
+    default: reqwest::Client,
+    http1: reqwest::Client,
+    http1_only_domains: Arc<Vec<String>>,
+    media_cache: Option<Arc<MediaCache>>,
+}
+
+impl HttpClients {
+    pub fn new(config: &Config) -> Result<Self> {
+        let default = builder(config)?;
+        let default = if config.http2 {
+            default.build()?
+        } else {
+            default.http1_only().build()?
+        };
+        let http1 = if config.http2 && !config.http1_only_domains.is_empty() {
+            builder(config)?.http1_only().build()?
+        } else {
+            default.clone()
+        };
+
+        Ok(Self {
+            default,
+            http1,
+            http1_only_domains: Arc::new(
+                config
+                    .http1_only_domains
+                    .iter()
+                    .map(|d| d.trim_start_matches('.').to_ascii_lowercase())
+                    .collect(),
+            ),
+            media_cache: None,
+        })
+    }
+
+    /// Cache media downloads in `cache`.
+    pub fn with_media_cache(mut self, cache: MediaCache) -> Self {
+        self.media_cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// The cache for media downloads, if there is one.
+    pub fn media_cache(&self) -> Option<&MediaCache> {
+        self.media_cache.as_deref()
+    }
+
+    /// Client for requests that aren't tied to a particular media host.
+    pub fn default_client(&self) -> &reqwest::Client {
+        &self.default
+    }
+
+    /// Client to use for requests to `url`.
+    pub fn for_url(&self, url: &Url) -> &reqwest::Client {
+        match url.host_str() {
+            Some(host) if is_listed(host, &self.http1_only_domains) => &self.http1,
+            _ => &self.default,
+        }
+    }
+}
+
+fn builder(config: &Config) -> Result<ClientBuilder> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(&config.user_agent)
+        .pool_max_idle_per_host(config.http_pool_max_idle_per_host)
+        .pool_idle_timeout(config.http_pool_idle_timeout)
+        .tcp_keepalive(config.http_tcp_keepalive);
+    if let Some(proxy) = config.proxy.clone() {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    Ok(builder)
+}
+
+/// What a request is fetching, which decides its user agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fetch {
+    Metadata,
+    Media,
+}
+
+/// The user agent to send when fetching `url`: the most specific per-domain
+/// override if there is one, otherwise the configured default.
+pub fn user_agent<'a>(config: &'a Config, url: &Url, fetch: Fetch) -> &'a str {
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    let domain_override = config
+        .user_agent_overrides
+        .iter()
+        .filter(|(domain, _)| matches_domain(&host, domain))
+        .max_by_key(|(domain, _)| domain.len())
+        .map(|(_, ua)| ua);
+    let user_agent = match fetch {
+        Fetch::Metadata => domain_override.and_then(|ua| ua.metadata.as_deref()),
+        Fetch::Media => domain_override
+            .and_then(|ua| ua.media.as_deref())
+            .or(config.media_user_agent.as_deref()),
+    };
+    user_agent.unwrap_or(&config.user_agent)
+}
+
+/// Returns `true` if `host` is one of `domains` or a subdomain of one.
+pub fn is_listed(host: &str, domains: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    domains.iter().any(|domain| matches_domain(&host, domain))
+}
+
+/// Returns `true` if `host` is `domain` or a subdomain of it. Both must be
+/// lowercase.
+pub fn matches_domain(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_listed() {
+        let domains = vec!["cdn.example.com".to_string()];
+        assert!(is_listed("cdn.example.com", &domains));
+        assert!(is_listed("media.CDN.example.com", &domains));
+        assert!(!is_listed("badcdn.example.com", &domains));
+        assert!(!is_listed("example.com", &domains));
+    }
+
+    #[test]
+    fn test_user_agent() {
+        use crate::config::UserAgentOverride;
+
+        let config = Config {
+            user_agent: "bot".to_string(),
+            media_user_agent: Some("browser".to_string()),
+            user_agent_overrides: vec![
+                (
+                    "example.com".to_string(),
+                    UserAgentOverride {
+                        metadata: Some("example-meta".to_string()),
+                        media: None,
+                    },
+                ),
+                (
+                    "cdn.example.com".to_string(),
+                    UserAgentOverride {
+                        metadata: None,
+                        media: Some("cdn-media".to_string()),
+                    },
+                ),
+            ],
+            ..Default::default()
+        };
+        let page = Url::parse("https://www.example.com/post").unwrap();
+        let cdn = Url::parse("https://cdn.example.com/video.mp4").unwrap();
+        let other = Url::parse("https://example.org/").unwrap();
+
+        assert_eq!(user_agent(&config, &page, Fetch::Metadata), "example-meta");
+        assert_eq!(user_agent(&config, &page, Fetch::Media), "browser");
+        // The more specific domain wins, even where it has no override.
+        assert_eq!(user_agent(&config, &cdn, Fetch::Metadata), "bot");
+        assert_eq!(user_agent(&config, &cdn, Fetch::Media), "cdn-media");
+        assert_eq!(user_agent(&config, &other, Fetch::Metadata), "bot");
+        assert_eq!(user_agent(&config, &other, Fetch::Media), "browser");
+    }
+
+    #[test]
+    fn test_for_url() {
+        let config = Config {
+            http1_only_domains: vec![".cdn.example.com".to_string()],
+            ..Default::default()
+        };
+        let clients = HttpClients::new(&config).unwrap();
+        let listed = Url::parse("https://a.cdn.example.com/video.mp4").unwrap();
+        let other = Url::parse("https://example.org/").unwrap();
+        assert!(std::ptr::eq(clients.for_url(&listed), &clients.http1));
+        assert!(std::ptr::eq(clients.for_url(&other), &clients.default));
+    }
+}
@@ -0,0 +1,119 @@
+use url::Url;
+
+/// Scripts that contain letters commonly used to imitate Latin ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    Other,
+}
+
+/// Cyrillic and Greek letters that are visually identical (or nearly so) to
+/// Latin letters in most fonts.
+const LATIN_LOOKALIKES: &str = "аеорсухіјѕԁһӏԛԝАВЕКМНОРСТХУІЈЅοαιυνκτρεηΑΒΕΖΗΙΚΜΝΟΡΤΥΧ";
+
+/// Format `url` for display, with an internationalized host shown in its
+/// Unicode form rather than as punycode. `Url` itself always stores the
+/// punycode form, which is what gets fetched.
+pub fn display_url(url: &Url) -> String {
+    let Some(host) = url.host_str() else {
+        return url.to_string();
+    };
+    if !host.split('.').any(|label| label.starts_with("xn--")) {
+        return url.to_string();
+    }
+    let (unicode, result) = idna::domain_to_unicode(host);
+    if result.is_err() {
+        return url.to_string();
+    }
+    url.as_str().replacen(host, &unicode, 1)
+}
+
+/// Returns a warning if the host of `url` looks like it imitates another
+/// domain: a label mixing Latin with Cyrillic or Greek letters, or one written
+/// entirely in Cyrillic or Greek letters that look Latin.
+pub fn lookalike_warning(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    if !host.split('.').any(|label| label.starts_with("xn--")) {
+        return None;
+    }
+    let (unicode, result) = idna::domain_to_unicode(host);
+    result.ok()?;
+
+    let suspicious = unicode.split('.').any(|label| {
+        let scripts: Vec<Script> = label
+            .chars()
+            .filter(|c| c.is_alphabetic())
+            .map(script)
+            .collect();
+        let has = |s| scripts.contains(&s);
+        let mixed = has(Script::Latin) && (has(Script::Cyrillic) || has(Script::Greek));
+        let all_lookalikes = !scripts.is_empty()
+            && !has(Script::Latin)
+            && !has(Script::Other)
+            && label
+                .chars()
+                .filter(|c| c.is_alphabetic())
+                .all(|c| LATIN_LOOKALIKES.contains(c));
+        mixed || all_lookalikes
+    });
+
+    suspicious.then(|| {
+        format!(
+            "The domain {} ({}) uses characters that imitate Latin letters; it may not be the site it looks like.",
+            unicode, host
+        )
+    })
+}
+
+fn script(c: char) -> Script {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Script::Latin,
+        '\u{0400}'..='\u{052F}' => Script::Cyrillic,
+        '\u{0370}'..='\u{03FF}' => Script::Greek,
+        _ => Script::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_url() {
+        let url = Url::parse("https://bücher.example/straße?q=ü").unwrap();
+        assert_eq!(url.host_str(), Some("xn--bcher-kva.example"));
+        assert_eq!(
+            display_url(&url),
+            "https://bücher.example/stra%C3%9Fe?q=%C3%BC"
+        );
+
+        let url = Url::parse("https://example.com/").unwrap();
+        assert_eq!(display_url(&url), "https://example.com/");
+    }
+
+    #[test]
+    fn test_lookalike_warning() {
+        // Cyrillic "а" in an otherwise Latin label.
+        let url = Url::parse("https://pаypal.com/login").unwrap();
+        assert!(lookalike_warning(&url).unwrap().contains("pаypal.com"));
+
+        // Entirely Cyrillic, but every letter looks Latin.
+        let url = Url::parse("https://аррӏе.com/").unwrap();
+        assert!(lookalike_warning(&url).is_some());
+
+        // Genuine non-Latin and accented domains are fine.
+        for url in [
+            "https://пример.рф/",
+            "https://bücher.example/",
+            "https://例え.jp/",
+        ] {
+            assert!(
+                lookalike_warning(&Url::parse(url).unwrap()).is_none(),
+                "{}",
+                url
+            );
+        }
+    }
+}
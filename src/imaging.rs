@@ -0,0 +1,213 @@
+use std::io::Cursor;
+
+use anyhow::{Context, Result, bail};
+use image::GenericImageView;
+use mime_guess::Mime;
+use tokio::sync::Semaphore;
+
+use crate::config::ImageLimits;
+use crate::media::MediaInfo;
+
+/// Most images decoded in-process at once, so a burst of embeds can't take
+/// over the blocking thread pool.
+const MAX_CONCURRENT_IMAGE_JOBS: usize = 4;
+static IMAGE_JOBS: Semaphore = Semaphore::const_new(MAX_CONCURRENT_IMAGE_JOBS);
+
+/// Reads the dimensions of an image that's already in memory, such as a
+/// thumbnail, without running ffprobe.
+pub fn image_dimensions(data: &[u8]) -> Result<MediaInfo> {
+    let (width, height) = image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context("Failed to read image")?
+        .into_dimensions()
+        .context("Failed to read image dimensions")?;
+    Ok(MediaInfo { width, height })
+}
+
+/// Decoder limits matching `limits`, so an image that claims to be small but
+/// isn't fails to decode instead of allocating without bound.
+fn decoder_limits(limits: &ImageLimits) -> image::Limits {
+    let mut decoder = image::Limits::default();
+    decoder.max_image_width = Some(limits.max_dimension);
+    decoder.max_image_height = Some(limits.max_dimension);
+    // Decoded as RGBA, at most four bytes a pixel.
+    decoder.max_alloc = Some(limits.max_pixels.saturating_mul(4));
+    decoder
+}
+
+/// Run CPU-heavy image work on the blocking thread pool rather than a
+/// runtime worker, a few jobs at a time.
+async fn run_image_job<T: Send + 'static>(
+    job: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let _permit = IMAGE_JOBS
+        .acquire()
+        .await
+        .context("Image job semaphore closed")?;
+    tokio::task::spawn_blocking(job)
+        .await
+        .context("Image job panicked")?
+}
+
+/// Downscales a still image to fit in a `max_side` square, as a PNG if it
+/// has transparency and a JPEG otherwise. Returns `None` if it fits already.
+pub async fn downscale_image(
+    image_data: &[u8],
+    max_side: u32,
+    limits: &ImageLimits,
+) -> Result<Option<(Vec<u8>, Mime)>> {
+    let image_data = image_data.to_vec();
+    let limits = *limits;
+    run_image_job(move || downscale(&image_data, max_side, &limits)).await
+}
+
+fn downscale(
+    image_data: &[u8],
+    max_side: u32,
+    limits: &ImageLimits,
+) -> Result<Option<(Vec<u8>, Mime)>> {
+    let info = image_dimensions(image_data)?;
+    if info.width <= max_side && info.height <= max_side {
+        return Ok(None);
+    }
+    if !limits.allows(info.width, info.height) {
+        bail!(
+            "{}x{} image is too large to downscale",
+            info.width,
+            info.height
+        );
+    }
+    let mut reader = image::ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .context("Failed to read image for downscaling")?;
+    reader.limits(decoder_limits(limits));
+    let img = reader
+        .decode()
+        .context("Failed to load image for downscaling")?;
+    let small = img.thumbnail(max_side, max_side);
+
+    let mut out = Cursor::new(Vec::new());
+    let mime_type = if small.color().has_alpha() {
+        small
+            .write_to(&mut out, image::ImageFormat::Png)
+            .context("Failed to encode downscaled image")?;
+        mime_guess::mime::IMAGE_PNG
+    } else {
+        small
+            .to_rgb8()
+            .write_with_encoder(image::codecs::jpeg::JpegEncoder::new_with_quality(
+                &mut out, 85,
+            ))
+            .context("Failed to encode downscaled image")?;
+        mime_guess::mime::IMAGE_JPEG
+    };
+    Ok(Some((out.into_inner(), mime_type)))
+}
+
+pub async fn generate_blurhash(image_data: &[u8], limits: &ImageLimits) -> Result<String> {
+    let image_data = image_data.to_vec();
+    let limits = *limits;
+    run_image_job(move || blurhash_of(&image_data, &limits)).await
+}
+
+fn blurhash_of(image_data: &[u8], limits: &ImageLimits) -> Result<String> {
+    let info = image_dimensions(image_data)?;
+    if !limits.allows(info.width, info.height) {
+        bail!(
+            "{}x{} image is too large for a blurhash",
+            info.width,
+            info.height
+        );
+    }
+    let mut reader = image::ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .context("Failed to read image for blurhash")?;
+    reader.limits(decoder_limits(limits));
+    let img = reader
+        .decode()
+        .context("Failed to load image for blurhash")?;
+    let (width, height) = img.dimensions();
+
+    blurhash::encode(4, 3, width, height, &img.to_rgba8()).context("Failed to generate blurhash")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn get_test_file_path(filename: &str) -> PathBuf {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("tests/data");
+        d.push(filename);
+        d
+    }
+
+    #[tokio::test]
+    async fn test_downscale_image() {
+        let encode = |img: image::DynamicImage| {
+            let mut data = Cursor::new(Vec::new());
+            img.write_to(&mut data, image::ImageFormat::Png).unwrap();
+            data.into_inner()
+        };
+        let limits = ImageLimits::default();
+
+        let photo = encode(image::RgbImage::new(800, 400).into());
+        let (small, mime_type) = downscale_image(&photo, 256, &limits)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mime_type, mime_guess::mime::IMAGE_JPEG);
+        let info = image_dimensions(&small).unwrap();
+        assert_eq!((info.width, info.height), (256, 128));
+
+        // Transparency is kept.
+        let logo = encode(image::RgbaImage::new(300, 300).into());
+        let (_, mime_type) = downscale_image(&logo, 256, &limits).await.unwrap().unwrap();
+        assert_eq!(mime_type, mime_guess::mime::IMAGE_PNG);
+
+        // Small enough already.
+        let icon = encode(image::RgbImage::new(64, 64).into());
+        assert!(
+            downscale_image(&icon, 256, &limits)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_blurhash_limits() {
+        let mut png = Vec::new();
+        image::RgbImage::new(64, 32)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        assert!(
+            generate_blurhash(&png, &ImageLimits::default())
+                .await
+                .is_ok()
+        );
+        let limits = ImageLimits {
+            max_dimension: 48,
+            ..Default::default()
+        };
+        assert!(generate_blurhash(&png, &limits).await.is_err());
+        let limits = ImageLimits {
+            max_pixels: 1024,
+            ..Default::default()
+        };
+        assert!(generate_blurhash(&png, &limits).await.is_err());
+    }
+
+    #[test]
+    fn test_image_dimensions() {
+        let path = get_test_file_path("me-static.webp");
+        let data = fs::read(&path).expect("Failed to read test file");
+        let info = image_dimensions(&data).expect("Failed to read dimensions");
+        let img = image::load_from_memory(&data).unwrap();
+        assert_eq!((info.width, info.height), img.dimensions());
+        assert!(image_dimensions(b"not an image").is_err());
+    }
+}
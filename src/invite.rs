@@ -0,0 +1,120 @@
+use matrix_sdk::{
+    Client,
+    ruma::{RoomId, UserId, events::room::member::MembershipState},
+};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+/// What to do with an invite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InviteDecision {
+    Accept,
+    /// Join, but don't embed anything until an admin enables embeds in the
+    /// room.
+    AcceptMuted,
+    Ignore,
+}
+
+/// Decide whether to accept an invite from `sender`. Invites are accepted
+/// from trusted users, users matching an `--invite-from` pattern, and
+/// members of a trusted room; anyone else's invite is only accepted muted,
+/// and only with `--accept-all-invites`.
+pub async fn decide(client: &Client, config: &Config, sender: &UserId) -> InviteDecision {
+    if is_allowed_user(config, sender.as_str()) || shares_trusted_room(client, config, sender).await
+    {
+        InviteDecision::Accept
+    } else if config.accept_all_invites {
+        InviteDecision::AcceptMuted
+    } else {
+        InviteDecision::Ignore
+    }
+}
+
+/// Whether `user_id` is a trusted user or matches one of the invite
+/// patterns.
+fn is_allowed_user(config: &Config, user_id: &str) -> bool {
+    config.is_trusted(user_id)
+        || config
+            .invite_from
+            .iter()
+            .any(|pattern| matches_user_pattern(pattern, user_id))
+}
+
+/// Whether `user_id` matches `pattern`: either an exact user ID, or
+/// `*:<server name>` for every user on that server.
+fn matches_user_pattern(pattern: &str, user_id: &str) -> bool {
+    match pattern.strip_prefix("*:") {
+        Some(server) => user_id
+            .split_once(':')
+            .is_some_and(|(_, user_server)| user_server.eq_ignore_ascii_case(server)),
+        None => pattern == user_id,
+    }
+}
+
+/// Whether `user_id` has joined one of the trusted rooms the bot is in.
+async fn shares_trusted_room(client: &Client, config: &Config, user_id: &UserId) -> bool {
+    for room_id in &config.trusted_rooms {
+        let Ok(room_id) = RoomId::parse(room_id) else {
+            continue;
+        };
+        let Some(room) = client.get_room(&room_id) else {
+            debug!("Not in trusted room {}", room_id);
+            continue;
+        };
+        match room.get_member(user_id).await {
+            Ok(Some(member)) if *member.membership() == MembershipState::Join => {
+                return true;
+            }
+            Ok(_) => {}
+            Err(e) => warn!(
+                "Failed to look up {} in trusted room {}: {:?}",
+                user_id, room_id, e
+            ),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_user_pattern() {
+        assert!(matches_user_pattern(
+            "@alice:example.org",
+            "@alice:example.org"
+        ));
+        assert!(!matches_user_pattern(
+            "@alice:example.org",
+            "@bob:example.org"
+        ));
+        assert!(matches_user_pattern("*:example.org", "@bob:example.org"));
+        assert!(matches_user_pattern("*:Example.org", "@bob:example.org"));
+        assert!(!matches_user_pattern(
+            "*:example.org",
+            "@bob:evil-example.org"
+        ));
+        assert!(!matches_user_pattern(
+            "*:example.org",
+            "@bob:example.org.evil"
+        ));
+        assert!(matches_user_pattern(
+            "*:example.org:8448",
+            "@bob:example.org:8448"
+        ));
+    }
+
+    #[test]
+    fn test_is_allowed_user() {
+        let config = Config {
+            trusted_users: vec!["@admin:example.com".to_string()],
+            invite_from: vec!["*:example.org".to_string()],
+            ..Default::default()
+        };
+        assert!(is_allowed_user(&config, "@admin:example.com"));
+        assert!(is_allowed_user(&config, "@anyone:example.org"));
+        assert!(!is_allowed_user(&config, "@other:example.com"));
+    }
+}
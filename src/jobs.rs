@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use tokio::sync::watch;
+use url::Url;
+
+use crate::debug_room::Stage;
+use crate::dump;
+
+/// Returned by [`Job::run`] when an admin cancelled the job.
+#[derive(Debug)]
+pub struct JobCancelled;
+
+impl std::fmt::Display for JobCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cancelled by an admin")
+    }
+}
+
+impl std::error::Error for JobCancelled {}
+
+/// A snapshot of a running embed, as listed by `queue`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobInfo {
+    pub id: u64,
+    pub url: Url,
+    pub room_id: OwnedRoomId,
+    pub stage: Stage,
+    pub elapsed: Duration,
+}
+
+struct JobState {
+    url: Url,
+    room_id: OwnedRoomId,
+    stage: Stage,
+    started_at: Instant,
+    cancel: watch::Sender<bool>,
+}
+
+/// Embeds in progress, so admins can see what the bot is busy with and stop
+/// one that's stuck.
+#[derive(Default)]
+pub struct JobRegistry {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<u64, JobState>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register an embed of `url` in `room_id`. It's listed until the
+    /// returned [`Job`] is dropped.
+    pub fn start(self: &Arc<Self>, room_id: &RoomId, url: &Url) -> Job {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (cancel, cancelled) = watch::channel(false);
+        self.jobs.lock().unwrap().insert(
+            id,
+            JobState {
+                url: url.clone(),
+                room_id: room_id.to_owned(),
+                stage: Stage::Metadata,
+                started_at: Instant::now(),
+                cancel,
+            },
+        );
+        Job {
+            id,
+            registry: Arc::clone(self),
+            cancelled,
+        }
+    }
+
+    /// Running jobs, oldest first.
+    pub fn list(&self) -> Vec<JobInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, job)| JobInfo {
+                id,
+                url: job.url.clone(),
+                room_id: job.room_id.clone(),
+                stage: job.stage,
+                elapsed: job.started_at.elapsed(),
+            })
+            .collect()
+    }
+
+    /// Ask job `id` to stop. Returns `false` if there's no such job.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.jobs.lock().unwrap().get(&id) {
+            Some(job) => {
+                job.cancel.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A running embed. Removed from its registry when dropped.
+pub struct Job {
+    id: u64,
+    registry: Arc<JobRegistry>,
+    cancelled: watch::Receiver<bool>,
+}
+
+impl Job {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Record what the job is busy with now.
+    pub fn set_stage(&self, stage: Stage) {
+        dump::record_stage(stage);
+        if let Some(job) = self.registry.jobs.lock().unwrap().get_mut(&self.id) {
+            job.stage = stage;
+        }
+    }
+
+    /// Run `fut` until it finishes or the job is cancelled. Cancelling drops
+    /// `fut`, which aborts its downloads and kills any ffmpeg it started.
+    pub async fn run<T>(&self, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        let mut cancelled = self.cancelled.clone();
+        tokio::select! {
+            result = fut => result,
+            _ = cancelled.wait_for(|&cancelled| cancelled) => Err(JobCancelled.into()),
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.registry.jobs.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::room_id;
+
+    #[tokio::test]
+    async fn test_job_registry() {
+        let registry = Arc::new(JobRegistry::new());
+        let url = Url::parse("https://example.com/clip.mp4").unwrap();
+        let room = room_id!("!room:example.com");
+
+        let first = registry.start(room, &url);
+        let second = registry.start(room, &url);
+        second.set_stage(Stage::Media);
+        let jobs = registry.list();
+        assert_eq!(
+            jobs.iter().map(|j| (j.id, j.stage)).collect::<Vec<_>>(),
+            vec![(first.id(), Stage::Metadata), (second.id(), Stage::Media)]
+        );
+
+        assert!(registry.cancel(second.id()));
+        let result = second.run(std::future::pending::<Result<()>>()).await;
+        assert!(result.unwrap_err().is::<JobCancelled>());
+        assert_eq!(first.run(async { Ok(1) }).await.unwrap(), 1);
+
+        let id = second.id();
+        drop(second);
+        assert!(!registry.cancel(id));
+        assert_eq!(registry.list().len(), 1);
+    }
+}
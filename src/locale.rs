@@ -0,0 +1,76 @@
+/// `tag` as a lowercase BCP 47 language tag, with OpenGraph's `en_US`
+/// spelling turned into `en-us`, or `None` if it isn't one.
+pub fn normalize(tag: &str) -> Option<String> {
+    let tag = tag.trim().replace('_', "-").to_ascii_lowercase();
+    let mut subtags = tag.split('-');
+    let language = subtags.next()?;
+    let valid = (2..=3).contains(&language.len())
+        && language.bytes().all(|b| b.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        });
+    valid.then_some(tag)
+}
+
+/// The primary language subtag of a normalized `tag`, e.g. `pt` of `pt-br`.
+fn language(tag: &str) -> &str {
+    tag.split('-').next().unwrap_or(tag)
+}
+
+/// Whether content in `locale` is in the language `wanted`, regardless of
+/// region or script: a page in `en_US` suits a room that wants `en`, and a
+/// page in `de` suits one that wants `de-AT`.
+pub fn matches(locale: &str, wanted: &str) -> bool {
+    let (Some(locale), Some(wanted)) = (normalize(locale), normalize(wanted)) else {
+        return false;
+    };
+    language(&locale) == language(&wanted)
+}
+
+/// The best of `candidates` for `wanted`: an exact match if there is one,
+/// otherwise one in the same language.
+pub fn best_match<'a, T>(
+    candidates: impl IntoIterator<Item = (&'a str, T)>,
+    wanted: &str,
+) -> Option<T> {
+    let wanted_tag = normalize(wanted)?;
+    let mut fallback = None;
+    for (locale, candidate) in candidates {
+        if normalize(locale).as_deref() == Some(&wanted_tag) {
+            return Some(candidate);
+        }
+        if fallback.is_none() && matches(locale, wanted) {
+            fallback = Some(candidate);
+        }
+    }
+    fallback
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("en_US").as_deref(), Some("en-us"));
+        assert_eq!(normalize(" pt-BR ").as_deref(), Some("pt-br"));
+        assert_eq!(normalize("zh-Hant-TW").as_deref(), Some("zh-hant-tw"));
+        assert_eq!(normalize("x"), None);
+        assert_eq!(normalize("en-"), None);
+        assert_eq!(normalize("english please"), None);
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("en_US", "en"));
+        assert!(matches("de", "de-AT"));
+        assert!(matches("fr_CA", "fr_FR"));
+        assert!(!matches("en_US", "de"));
+        assert!(!matches("", "de"));
+
+        let candidates = [("en", 1), ("fr_CA", 2), ("fr_FR", 3)];
+        assert_eq!(best_match(candidates, "fr-FR"), Some(3));
+        assert_eq!(best_match(candidates, "fr"), Some(2));
+        assert_eq!(best_match(candidates, "ja"), None);
+    }
+}
@@ -4,34 +4,96 @@ use matrix_sdk::{
     Client, SessionMeta,
     authentication::{SessionTokens, matrix::MatrixSession},
     config::SyncSettings,
-    encryption::VerificationState,
+    encryption::{
+        BackupDownloadStrategy, EncryptionSettings, VerificationState, recovery::RecoveryState,
+    },
+    event_handler::RawEvent,
     room::Room,
-    ruma::events::room::{
-        member::{MembershipState, StrippedRoomMemberEvent, SyncRoomMemberEvent},
-        message::OriginalSyncRoomMessageEvent,
-        redaction::SyncRoomRedactionEvent,
+    ruma::{
+        UInt,
+        api::client::{
+            authenticated_media::get_media_config,
+            error::ErrorKind,
+            filter::{Filter, FilterDefinition, LazyLoadOptions, RoomEventFilter},
+            sync::sync_events,
+        },
+        events::{
+            room::{
+                encrypted::OriginalSyncRoomEncryptedEvent,
+                member::{MembershipState, StrippedRoomMemberEvent, SyncRoomMemberEvent},
+                message::OriginalSyncRoomMessageEvent,
+                redaction::SyncRoomRedactionEvent,
+            },
+            sticker::OriginalSyncStickerEvent,
+        },
     },
     store::RoomLoadSettings,
 };
 use mime_guess::Mime;
-use reqwest::Proxy;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{debug, error, info, warn};
 
 mod activitypub;
+mod calendar;
 mod cas;
+mod check;
+mod claim;
 mod command;
 mod config;
 mod db;
+mod debug_room;
+mod decompress;
+mod describe;
+mod digest;
+mod doctor;
+mod dump;
+mod emote;
+mod error;
 mod extract;
+#[cfg(feature = "media")]
+mod ffmpeg;
+mod fixtures;
+mod geo;
 mod handler;
+mod health;
+mod html_scan;
+mod http;
+mod idn;
+#[cfg(feature = "media")]
+mod imaging;
+mod invite;
+mod jobs;
 mod key_sharing;
+mod locale;
+mod maintenance;
 mod media;
+mod media_cache;
+mod media_gc;
 mod metadata;
+mod metadata_cache;
+mod metrics;
+#[cfg(feature = "extractors")]
+mod oembed;
+mod process;
 mod processing;
+mod profile;
+mod quiet;
+mod readability;
+mod redirect;
+mod reporting;
+mod safety;
+mod sanitize;
+mod shard;
+mod store;
+mod summary;
+mod timestamp;
 mod tracker;
+mod transcribe;
+mod upload;
+mod webhook;
 
 /// Persisted session data.
 ///
@@ -57,7 +119,21 @@ async fn main() -> Result<()> {
         .init();
 
     // Load config from CLI args / files.
-    let config = Config::load().await?;
+    let mut config = Config::load().await?;
+    let _reporting = reporting::init(&config);
+    match &config.command {
+        Some(config::Command::Migrate { dry_run }) => return migrate(&config, *dry_run).await,
+        Some(config::Command::CheckConfig { sample_urls }) => {
+            return check_config(&config, sample_urls.as_deref()).await;
+        }
+        Some(config::Command::Doctor) => return doctor(&config).await,
+        Some(config::Command::Preview {
+            url,
+            record_fixtures,
+        }) => return preview(&config, url, record_fixtures.as_deref()).await,
+        Some(config::Command::MediaGc { dry_run }) => return media_gc(&config, *dry_run).await,
+        None => {}
+    }
     let session_file = config.state_store_path.join("session.json");
 
     // Authenticate
@@ -74,17 +150,31 @@ async fn main() -> Result<()> {
     }
 
     ensure_verified(&client, &config).await;
-    spawn_session_change_listener(&client, session_file);
-
-    let mut http_builder = reqwest::Client::builder()
-        .user_agent("Mozilla/5.0 (compatible; Discordbot/2.0; +https://discordapp.com)");
-    if let Some(proxy) = config.proxy.clone() {
-        http_builder = http_builder.proxy(Proxy::all(proxy)?);
+    ensure_key_backup(&client, &config).await;
+    spawn_session_change_listener(&client, session_file.clone());
+    apply_upload_limit(&client, &mut config).await;
+
+    let mut http_clients = http::HttpClients::new(&config)?;
+    if config.media_cache_max_size > 0 {
+        let cache =
+            media_cache::MediaCache::open(&config.media_cache_path, config.media_cache_max_size)?;
+        http_clients = http_clients.with_media_cache(cache);
     }
-    let http_client = http_builder.build()?;
     // Open (or create) the persistent database.
-    let database = db::Database::open(&config.database_path).await?;
+    let mut database = db::Database::open(&config.database_path).await?;
+    if let Some(shared) = store::connect(&config).await? {
+        database.set_shared_store(shared);
+    }
     let database = Arc::new(database);
+    command::load_url_rewrites(&config, &database)
+        .await
+        .context("Failed to load URL rewrite rules")?;
+    command::load_trusted_users(&config, &database)
+        .await
+        .context("Failed to load trusted users")?;
+    if let Some(interval) = config.domain_report_interval {
+        spawn_domain_report(database.clone(), interval);
+    }
 
     // Open (or create) the content-addressable media store.
     let media_store = cas::MediaStore::open(&config.media_store_path).await?;
@@ -92,42 +182,70 @@ async fn main() -> Result<()> {
 
     let config = Arc::new(config);
 
-    let tracker = Arc::new(tracker::EventTracker::new());
+    if config.shard.is_sharded() {
+        info!(
+            "Running as shard {} of {}",
+            config.shard.index(),
+            config.shard.count()
+        );
+    }
+
+    let tracker = Arc::new(tracker::EventTracker::new(config.dedup_window));
     tracker.spawn_cleanup_task();
 
+    let jobs = Arc::new(jobs::JobRegistry::new());
+
     let ap_detector = Arc::new(activitypub::ActivityPubDetector::new());
+    maintenance::spawn(config.clone(), database.clone(), ap_detector.clone());
+    handler::spawn_scheduled_posts(
+        client.clone(),
+        config.clone(),
+        http_clients.clone(),
+        tracker.clone(),
+        jobs.clone(),
+        ap_detector.clone(),
+        database.clone(),
+    );
 
     // Message handler
     client.add_event_handler({
         let config = config.clone();
-        let http_client = http_client.clone();
+        let http_clients = http_clients.clone();
         let client = client.clone();
         let tracker = tracker.clone();
+        let jobs = jobs.clone();
         let ap_detector = ap_detector.clone();
         let database = database.clone();
         let media_store = media_store.clone();
 
-        move |event: OriginalSyncRoomMessageEvent, room: Room| {
+        move |event: OriginalSyncRoomMessageEvent, room: Room, raw: RawEvent| {
             let config = config.clone();
-            let http_client = http_client.clone();
+            let http_clients = http_clients.clone();
             let client = client.clone();
             let tracker = tracker.clone();
+            let jobs = jobs.clone();
             let ap_detector = ap_detector.clone();
             let database = database.clone();
             let media_store = media_store.clone();
             debug!("Event: {:?}", event);
             async move {
-                // Ignore own messages.
-                if event.sender == room.own_user_id() {
+                // Ignore own messages, and those of other instances of the
+                // bot.
+                if event.sender == room.own_user_id() || handler::is_generated(&raw) {
+                    return;
+                }
+                // Rooms outside our shard are handled by another instance.
+                if !config.shard.owns(room.room_id().as_str()) {
                     return;
                 }
                 if let Err(e) = handler::handle_message(
                     event,
                     room,
                     config,
-                    http_client,
+                    http_clients,
                     client,
                     tracker,
+                    jobs,
                     ap_detector,
                     database,
                     media_store,
@@ -135,18 +253,133 @@ async fn main() -> Result<()> {
                 .await
                 {
                     error!("Error handling message: {:?}", e);
+                    reporting::capture_error(&e, None, None);
                 }
             }
         }
     });
 
+    // Sticker handler, for links in sticker descriptions
+    client.add_event_handler({
+        let config = config.clone();
+        let http_clients = http_clients.clone();
+        let tracker = tracker.clone();
+        let jobs = jobs.clone();
+        let ap_detector = ap_detector.clone();
+        let database = database.clone();
+
+        move |event: OriginalSyncStickerEvent, room: Room, raw: RawEvent| {
+            let config = config.clone();
+            let http_clients = http_clients.clone();
+            let tracker = tracker.clone();
+            let jobs = jobs.clone();
+            let ap_detector = ap_detector.clone();
+            let database = database.clone();
+            async move {
+                if event.sender == room.own_user_id() || handler::is_generated(&raw) {
+                    return;
+                }
+                if !config.shard.owns(room.room_id().as_str()) {
+                    return;
+                }
+                if let Err(e) = handler::handle_sticker(
+                    event,
+                    room,
+                    config,
+                    http_clients,
+                    tracker,
+                    jobs,
+                    ap_detector,
+                    database,
+                )
+                .await
+                {
+                    error!("Error handling sticker: {:?}", e);
+                    reporting::capture_error(&e, None, None);
+                }
+            }
+        }
+    });
+
+    // Undecryptable message handler: the key may still arrive, or be
+    // fetched from the key backup, so try again for a while.
+    client.add_event_handler({
+        let config = config.clone();
+        let http_clients = http_clients.clone();
+        let client = client.clone();
+        let tracker = tracker.clone();
+        let jobs = jobs.clone();
+        let ap_detector = ap_detector.clone();
+        let database = database.clone();
+        let media_store = media_store.clone();
+
+        move |event: OriginalSyncRoomEncryptedEvent, room: Room| {
+            let config = config.clone();
+            let http_clients = http_clients.clone();
+            let client = client.clone();
+            let tracker = tracker.clone();
+            let jobs = jobs.clone();
+            let ap_detector = ap_detector.clone();
+            let database = database.clone();
+            let media_store = media_store.clone();
+            async move {
+                if event.sender == room.own_user_id() {
+                    return;
+                }
+                if !config.shard.owns(room.room_id().as_str()) {
+                    return;
+                }
+                // Retrying takes a while; don't hold up the sync loop.
+                tokio::spawn(async move {
+                    if let Err(e) = handler::retry_undecryptable(
+                        event,
+                        room,
+                        config,
+                        http_clients,
+                        client,
+                        tracker,
+                        jobs,
+                        ap_detector,
+                        database,
+                        media_store,
+                    )
+                    .await
+                    {
+                        error!("Error handling undecryptable message: {:?}", e);
+                        reporting::capture_error(&e, None, None);
+                    }
+                });
+            }
+        }
+    });
+
+    // Claim handler, for agreeing with other bots on who embeds a message.
+    // Our own claims are recorded too, with the timestamp the homeserver
+    // gave them.
+    if config.claim_delay.is_some() {
+        client.add_event_handler(|event: handler::OriginalSyncClaimEvent| async move {
+            claim::claims().record(
+                &event.content.event_id,
+                &event.sender,
+                event.origin_server_ts.get().into(),
+            );
+        });
+    }
+
     // Redaction handler
     client.add_event_handler({
+        let config = config.clone();
         let tracker = tracker.clone();
+        let database = database.clone();
         move |event: SyncRoomRedactionEvent, room: Room| {
+            let config = config.clone();
             let tracker = tracker.clone();
+            let database = database.clone();
             async move {
-                if let Err(e) = handler::handle_redaction(event, room, tracker).await {
+                if !config.shard.owns(room.room_id().as_str()) {
+                    return;
+                }
+                if let Err(e) = handler::handle_redaction(event, room, tracker, database).await {
                     error!("Error handling redaction: {:?}", e);
                 }
             }
@@ -155,9 +388,11 @@ async fn main() -> Result<()> {
 
     // Membership handler — detect new joins for room-key sharing.
     client.add_event_handler({
+        let config = config.clone();
         let database = database.clone();
         let client_for_keys = client.clone();
         move |event: SyncRoomMemberEvent, room: Room| {
+            let config = config.clone();
             let database = database.clone();
             let client_for_keys = client_for_keys.clone();
             async move {
@@ -165,13 +400,31 @@ async fn main() -> Result<()> {
                     return;
                 };
 
+                if !config.shard.owns(room.room_id().as_str()) {
+                    return;
+                }
+
                 // Only care about users who just joined.
                 if event.content.membership != MembershipState::Join {
                     return;
                 }
 
-                // Ignore our own joins.
+                // Our own joins only matter for per-room profiles and the
+                // bot's description.
                 if event.state_key == room.own_user_id().as_str() {
+                    profile::handle_own_membership(
+                        &client_for_keys,
+                        &config,
+                        &database,
+                        &room,
+                        &event.content,
+                    )
+                    .await;
+                    if describe::publishes_in(room.room_id().as_str(), &config, &database).await
+                        && let Err(e) = describe::publish(&room, &config).await
+                    {
+                        warn!("Failed to describe the bot in {}: {:?}", room.room_id(), e);
+                    }
                     return;
                 }
 
@@ -226,8 +479,10 @@ async fn main() -> Result<()> {
     // Invite handler
     client.add_event_handler({
         let config = config.clone();
-        move |event: StrippedRoomMemberEvent, room: Room| {
+        let database = database.clone();
+        move |event: StrippedRoomMemberEvent, room: Room, client: Client| {
             let config = config.clone();
+            let database = database.clone();
             async move {
                 if event.content.membership != MembershipState::Invite {
                     return;
@@ -235,13 +490,50 @@ async fn main() -> Result<()> {
 
                 info!("Received invite from {}", event.sender);
 
-                if config.trusted_users.contains(&event.sender.to_string()) {
-                    info!("Accepting invite from trusted user {}", event.sender);
-                    if let Err(e) = room.join().await {
-                        error!("Failed to join room: {:?}", e);
+                if !config.shard.owns(room.room_id().as_str()) {
+                    info!("Leaving invite to {} for another shard", room.room_id());
+                    return;
+                }
+
+                match invite::decide(&client, &config, &event.sender).await {
+                    invite::InviteDecision::Accept => {
+                        info!("Accepting invite from {}", event.sender);
+                    }
+                    invite::InviteDecision::AcceptMuted => {
+                        info!(
+                            "Accepting invite from {}; embeds stay off in {} until an admin sets its embed mode",
+                            event.sender,
+                            room.room_id()
+                        );
+                        // Mute the room before joining, so nothing is
+                        // embedded in between.
+                        if let Err(e) = database
+                            .set_embed_mode(room.room_id().as_str(), config::EmbedMode::Never)
+                            .await
+                        {
+                            error!("Failed to turn off embeds before joining: {:?}", e);
+                            return;
+                        }
+                    }
+                    invite::InviteDecision::Ignore => {
+                        warn!("Ignoring invite from untrusted user {}", event.sender);
+                        return;
                     }
-                } else {
-                    warn!("Ignoring invite from untrusted user {}", event.sender);
+                }
+                if let Err(e) = room.join().await {
+                    error!("Failed to join room: {:?}", e);
+                    return;
+                }
+                // Record DMs in our `m.direct` too, so they get the
+                // direct chat reply mode.
+                if event.content.is_direct == Some(true)
+                    && let Err(e) = room.set_is_direct(true).await
+                {
+                    error!(
+                        "Failed to mark {} as a direct chat: {:?}",
+                        room.room_id(),
+                        e
+                    );
                 }
             }
         }
@@ -285,13 +577,234 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Changing the global profile resets it in every room, so per-room
+    // overrides have to be applied afterwards.
+    profile::apply_all(&client, &config, &database).await;
+    describe::publish_all(&client, &config, &database).await;
+
+    let sync_health = Arc::new(health::SyncHealth::new());
+    if let Some(addr) = config.health_listen_address {
+        health::serve(addr, sync_health.clone()).await?;
+    }
+
     // Sync loop
     info!("Bot started, syncing...");
-    client.sync(SyncSettings::default()).await?;
+    run_sync_loop(&client, &config, &session_file, &sync_health).await
+}
+
+/// Run the pending database migrations, or with `dry_run`, just list them.
+async fn migrate(config: &Config, dry_run: bool) -> Result<()> {
+    if !dry_run {
+        db::Database::open(&config.database_path).await?;
+        return Ok(());
+    }
+    let pending = db::plan_migrations(&config.database_path)?;
+    if pending.is_empty() {
+        info!("Database is up-to-date; no migrations to run");
+    }
+    for (version, description) in pending {
+        info!("Would run migration v{}: {}", version, description);
+    }
+    Ok(())
+}
+
+/// Delete old media the bot uploaded, or with `dry_run` only list it.
+async fn media_gc(config: &Config, dry_run: bool) -> Result<()> {
+    let mut database = db::Database::open(&config.database_path).await?;
+    // Other instances may reuse the uploads through the shared cache.
+    if let Some(shared) = store::connect(config).await? {
+        database.set_shared_store(shared);
+    }
+    let stats = media_gc::run(config, &database, dry_run).await?;
+    if !dry_run {
+        info!(
+            "Deleted {} old media file(s), {} failed, {} still in use",
+            stats.deleted, stats.failed, stats.kept
+        );
+    }
+    Ok(())
+}
 
+/// Check the configuration, which has already been loaded and so is valid
+/// as far as parsing goes, and run `sample_urls` through the rewrite rules.
+async fn check_config(config: &Config, sample_urls: Option<&Path>) -> Result<()> {
+    let admin_rules = db::read_url_rewrites(&config.database_path)?;
+    let samples = match sample_urls {
+        Some(path) => tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read sample URLs: {:?}", path))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let report = check::check(config, &admin_rules, &samples);
+    for line in &report.lines {
+        println!("{}", line);
+    }
+    for problem in &report.problems {
+        println!("Problem: {}", problem);
+    }
+    if !report.problems.is_empty() {
+        bail!(
+            "Found {} problem(s) in the configuration",
+            report.problems.len()
+        );
+    }
+    println!("No problems found");
     Ok(())
 }
 
+/// Report what this build includes and whether the programs it runs are
+/// installed.
+async fn doctor(config: &Config) -> Result<()> {
+    let report = doctor::doctor(config).await;
+    for line in &report.lines {
+        println!("{}", line);
+    }
+    for problem in &report.problems {
+        println!("Problem: {}", problem);
+    }
+    if !report.problems.is_empty() {
+        bail!("Found {} problem(s)", report.problems.len());
+    }
+    println!("No problems found");
+    Ok(())
+}
+
+/// Fetch `url` and print the embed it would get. With `record_fixtures`,
+/// also save what was fetched as a fixture for the extractor tests.
+async fn preview(config: &Config, url: &url::Url, record_fixtures: Option<&Path>) -> Result<()> {
+    let http_clients = http::HttpClients::new(config)?;
+    let ap_detector = activitypub::ActivityPubDetector::new();
+    let url = config.rewrite_url(url);
+    let (meta, responses) = fixtures::recording(metadata::Metadata::fetch_from_url(
+        http_clients.for_url(&url),
+        &url,
+        config,
+        &ap_detector,
+        config.language.as_deref(),
+    ))
+    .await;
+    let meta = meta.with_context(|| format!("Failed to fetch metadata for {}", url))?;
+
+    if let Some(dir) = record_fixtures {
+        let fixture = fixtures::Fixture::new(&url, responses);
+        if fixture.expected != fixtures::Snapshot::new(&meta) {
+            warn!("Replaying the recorded responses extracts different metadata");
+        }
+        let path = fixtures::save(dir, &fixture)?;
+        info!("Saved fixture to {}", path.display());
+    }
+
+    let params = processing::process_metadata(
+        meta,
+        &url,
+        config,
+        &config.time_format(None, None),
+        config.summary_thumbnails,
+    );
+    println!("{}", params.body);
+    if let Some(media_url) = &params.media_url {
+        println!("Media: {}", media_url);
+    }
+    for image in &params.gallery {
+        println!("Gallery: {}", image.url);
+    }
+    Ok(())
+}
+
+/// How many times in a row the bot logs in again, without a sync getting
+/// through in between, before a failed login makes it give up.
+const MAX_RELOGIN_ATTEMPTS: u32 = 8;
+
+/// Sync forever, retrying failed syncs with exponential backoff.
+///
+/// If the homeserver rejects our access token, log in again with the same
+/// device (so the crypto store stays valid) when a password is configured;
+/// otherwise give up, since retrying can't help. Logging in again backs off
+/// the same way until a sync gets through, and gives up when the
+/// [`MAX_RELOGIN_ATTEMPTS`]th attempt in a row fails.
+async fn run_sync_loop(
+    client: &Client,
+    config: &Config,
+    session_file: &Path,
+    health: &health::SyncHealth,
+) -> Result<()> {
+    let settings = sync_settings(config);
+    let mut relogins = 0;
+    loop {
+        let error = match client.sync_once(settings.clone()).await {
+            Ok(_) => {
+                health.record_success();
+                relogins = 0;
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        let failures = health.record_failure(&error.to_string());
+        if let Some(ErrorKind::UnknownToken { .. }) = error.client_api_error_kind() {
+            if config.password.is_none() {
+                return Err(error).context(
+                    "Access token is invalid and no password is configured to log in again",
+                );
+            }
+            warn!("Access token was rejected by the homeserver: {}", error);
+            // The last login failed, or its token was rejected too.
+            if relogins > 0 {
+                let delay = health::backoff_delay(relogins);
+                info!("Logging in again in {}s", delay.as_secs());
+                tokio::time::sleep(delay).await;
+            }
+            relogins += 1;
+            if let Err(e) = relogin(client, config, session_file).await {
+                if relogins >= MAX_RELOGIN_ATTEMPTS {
+                    return Err(e).context("Access token is invalid and logging in again failed");
+                }
+                warn!("Logging in again failed ({} in a row): {:?}", relogins, e);
+            }
+            continue;
+        }
+
+        let delay = health::backoff_delay(failures);
+        warn!(
+            "Sync failed ({} in a row), retrying in {}s: {}",
+            failures,
+            delay.as_secs(),
+            error
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Log in again with the configured password, reusing the current device ID.
+async fn relogin(client: &Client, config: &Config, session_file: &Path) -> Result<()> {
+    let password = config
+        .password
+        .as_deref()
+        .context("No password configured")?;
+    let device_id = client.device_id().context("Client has no device ID")?;
+
+    info!(
+        "Logging in again as {} (device {})",
+        config.username, device_id
+    );
+    client
+        .matrix_auth()
+        .login_username(&config.username, password)
+        .device_id(device_id.as_str())
+        .initial_device_display_name("matrix-embed")
+        .send()
+        .await
+        .context("Login failed")?;
+
+    save_session(client, session_file).await
+}
+
 /// Top-level authentication flow.
 ///
 /// 1. If a `session.json` exists, try to restore from it and validate the token
@@ -334,14 +847,10 @@ async fn restore_or_login(config: &Config, session_file: &Path) -> Result<Client
 /// (DNS failure, connection refused, timeout, etc.) as opposed to an
 /// HTTP-level rejection like 401 Unauthorized.
 fn is_network_error(err: &anyhow::Error) -> bool {
-    for cause in err.chain() {
-        if let Some(reqwest_err) = cause.downcast_ref::<reqwest::Error>() {
-            if reqwest_err.is_connect() || reqwest_err.is_timeout() {
-                return true;
-            }
-        }
-    }
-    false
+    matches!(
+        error::EmbedError::of(err),
+        error::EmbedError::NetworkTimeout | error::EmbedError::ConnectionFailed
+    )
 }
 
 /// Attempt to restore from `session.json`, then validate the token with a
@@ -372,6 +881,7 @@ async fn try_restore_session(config: &Config, session_file: &Path) -> Result<Cli
     let client = Client::builder()
         .homeserver_url(&config.homeserver_url)
         .sqlite_store(&config.state_store_path, None)
+        .with_encryption_settings(encryption_settings())
         .build()
         .await
         .context("Failed to build client for session restore")?;
@@ -421,6 +931,7 @@ async fn login_fresh(config: &Config) -> Result<Client> {
     let client = Client::builder()
         .homeserver_url(&config.homeserver_url)
         .sqlite_store(&config.state_store_path, None)
+        .with_encryption_settings(encryption_settings())
         .build()
         .await
         .context("Failed to build client")?;
@@ -572,13 +1083,169 @@ async fn ensure_verified(client: &Client, config: &Config) {
     );
 }
 
+/// Back up room keys to the server, and fetch keys from the backup for
+/// messages that can't be decrypted, so the bot can still read encrypted
+/// rooms after its store is wiped.
+fn encryption_settings() -> EncryptionSettings {
+    EncryptionSettings {
+        auto_enable_backups: true,
+        backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
+        ..Default::default()
+    }
+}
+
+/// Make sure room keys are backed up on the server, creating a backup
+/// protected by the recovery passphrase if there is none yet.
+async fn ensure_key_backup(client: &Client, config: &Config) {
+    let recovery = client.encryption().recovery();
+    match recovery.state() {
+        RecoveryState::Enabled => info!("Key backup is enabled."),
+        RecoveryState::Disabled => {
+            let Some(passphrase) = &config.recovery_passphrase else {
+                warn!(
+                    "There is no key backup, so keys for encrypted rooms are lost if the store is wiped. \
+                     Provide --recovery-passphrase-file to create one."
+                );
+                return;
+            };
+            info!("No key backup found; creating one protected by the recovery passphrase...");
+            match recovery.enable().with_passphrase(passphrase).await {
+                Ok(_) => info!("Key backup and recovery enabled."),
+                Err(e) => warn!("Failed to enable key backup: {:#}", e),
+            }
+        }
+        RecoveryState::Incomplete => warn!(
+            "Key backup is set up but this device can't use it. \
+             Provide the right --recovery-passphrase-file to connect to it."
+        ),
+        RecoveryState::Unknown => debug!("Key backup state is not known yet."),
+    }
+}
+
+/// Clamp `max_file_size` to the homeserver's `m.upload.size`, so we don't
+/// spend time downloading and remuxing media the server would reject anyway.
+async fn apply_upload_limit(client: &Client, config: &mut Config) {
+    let request = get_media_config::v1::Request::new();
+    match client.send(request).await {
+        Ok(response) => {
+            let limit = u64::from(response.upload_size);
+            if config.clamp_max_file_size(limit) {
+                info!(
+                    "Clamping max file size to the homeserver upload limit of {} bytes",
+                    limit
+                );
+            } else {
+                debug!(
+                    "Homeserver upload limit is {} bytes; keeping max file size of {} bytes",
+                    limit, config.max_file_size
+                );
+            }
+        }
+        Err(e) => warn!("Failed to fetch media config from homeserver: {}", e),
+    }
+}
+
+/// Timeline event types the bot acts on. `m.room.*` also covers state events
+/// like power levels and encryption, which must not be dropped from the
+/// timeline.
+const SYNC_TIMELINE_TYPES: &[&str] = &["m.room.*", "m.sticker"];
+
+/// The timeline event types to sync with `config`.
+fn sync_timeline_types(config: &Config) -> Vec<String> {
+    let mut types: Vec<String> = SYNC_TIMELINE_TYPES.iter().map(|t| t.to_string()).collect();
+    // Rooms can opt out at any time, and the opt-out is only seen if its
+    // state event reaches the store.
+    types.push(handler::NO_EMBEDS_STATE_EVENT.to_string());
+    // Claims in unencrypted rooms; in encrypted ones they're m.room.encrypted.
+    if config.claim_delay.is_some() {
+        types.push(claim::CLAIM_EVENT_TYPE.to_string());
+    }
+    types
+}
+
+/// Sync settings with a filter that skips what the bot never looks at:
+/// presence, typing/receipts, non-message timeline events, and full member
+/// lists (members are lazy-loaded, and fetched in full by the SDK when it
+/// needs them for encryption).
+fn sync_settings(config: &Config) -> SyncSettings {
+    let mut timeline = RoomEventFilter::default();
+    timeline.limit = Some(UInt::from(config.sync_timeline_limit));
+    timeline.types = Some(sync_timeline_types(config));
+    timeline.lazy_load_options = LazyLoadOptions::Enabled {
+        include_redundant_members: false,
+    };
+
+    let mut state = RoomEventFilter::default();
+    state.lazy_load_options = LazyLoadOptions::Enabled {
+        include_redundant_members: false,
+    };
+
+    let mut filter = FilterDefinition::default();
+    filter.presence = Filter::empty();
+    filter.room.ephemeral = RoomEventFilter::empty();
+    filter.room.timeline = timeline;
+    filter.room.state = state;
+
+    SyncSettings::default().filter(sync_events::v3::Filter::FilterDefinition(filter))
+}
+
+// ===========================================================================
+// Domain report
+// ===========================================================================
+
+/// Domains included in each periodic report.
+const DOMAIN_REPORT_SIZE: usize = 20;
+
+/// Spawn a background task that logs how embeds of each domain turned out
+/// every `interval`, so a site that changed its markup and stopped producing
+/// embeds shows up in the logs.
+fn spawn_domain_report(database: Arc<db::Database>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick is immediate; there's nothing to report at startup.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let stats = match database.domain_stats(DOMAIN_REPORT_SIZE).await {
+                Ok(stats) => stats,
+                Err(e) => {
+                    warn!("Failed to query domain stats: {:?}", e);
+                    continue;
+                }
+            };
+
+            let failing = stats.iter().filter(|s| s.is_failing()).count();
+            info!(
+                "Domain report: {} domain(s) shown, {} failing",
+                stats.len(),
+                failing
+            );
+            for domain in &stats {
+                if domain.is_failing() {
+                    warn!(
+                        "Nothing embedded from {} in the last {} attempts (last embedded: {})",
+                        domain.domain,
+                        domain.failure_streak,
+                        domain.last_embedded_at.as_deref().unwrap_or("never")
+                    );
+                } else {
+                    info!(
+                        "{}: {} embedded, {} empty, {} failed",
+                        domain.domain, domain.embedded, domain.empty, domain.failed
+                    );
+                }
+            }
+        }
+    });
+}
+
 // ===========================================================================
 // Session-change listener
 // ===========================================================================
 
 /// Spawn a background task that persists `session.json` whenever the SDK
 /// reports that tokens have been refreshed or invalidated.
-fn spawn_session_change_listener(client: &Client, session_file: std::path::PathBuf) {
+fn spawn_session_change_listener(client: &Client, session_file: PathBuf) {
     let mut receiver = client.subscribe_to_session_changes();
     let client = client.clone();
 
@@ -612,3 +1279,22 @@ fn spawn_session_change_listener(client: &Client, session_file: std::path::PathB
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_timeline_types() {
+        let types = sync_timeline_types(&Config::default());
+        assert!(types.contains(&"m.room.*".to_string()));
+        assert!(types.contains(&handler::NO_EMBEDS_STATE_EVENT.to_string()));
+        assert!(!types.contains(&claim::CLAIM_EVENT_TYPE.to_string()));
+
+        let config = Config {
+            claim_delay: Some(Duration::from_secs(1)),
+            ..Config::default()
+        };
+        assert!(sync_timeline_types(&config).contains(&claim::CLAIM_EVENT_TYPE.to_string()));
+    }
+}
@@ -0,0 +1,212 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::activitypub::ActivityPubDetector;
+use crate::config::Config;
+use crate::db::Database;
+use crate::media_gc;
+use crate::metrics::{Pruned, Reclaimed, metrics};
+
+/// Prefix of the temporary files and directories the bot creates, so that
+/// ones left behind by a crash can be found again.
+pub const TEMP_PREFIX: &str = "matrix-embed-";
+
+/// Temporary files older than this are assumed to be left over from a
+/// crashed or killed process. Nothing the bot does takes anywhere near this
+/// long.
+const ORPHANED_TEMP_AGE: Duration = Duration::from_secs(6 * 3600);
+
+/// Run [`run`] every `config.maintenance_interval`, starting right away so
+/// that leftovers from a previous run are cleaned up at startup.
+pub fn spawn(config: Arc<Config>, database: Arc<Database>, ap_detector: Arc<ActivityPubDetector>) {
+    let Some(interval) = config.maintenance_interval else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            run(&config, &database, &ap_detector).await;
+        }
+    });
+}
+
+/// Prune expired cache entries and old embed history, shrink the database,
+/// remove orphaned temporary files and, if configured to, delete old media
+/// from the homeserver.
+async fn run(config: &Config, database: &Database, ap_detector: &ActivityPubDetector) {
+    debug!("Running maintenance");
+
+    match database
+        .prune(
+            config.embed_history_max_age,
+            config.summary_cache_max_age,
+            config.upload_cache_max_entries,
+        )
+        .await
+    {
+        Ok(stats) => {
+            metrics().record_pruned(Pruned::EmbedHistory, stats.embeds);
+            metrics().record_pruned(Pruned::Summaries, stats.summaries);
+            metrics().record_pruned(Pruned::Uploads, stats.uploads);
+            metrics().record_pruned(Pruned::PendingGalleries, stats.galleries);
+            metrics().record_pruned(Pruned::LinkVerdicts, stats.verdicts);
+            info!(
+                "Maintenance: removed {} old embed(s), {} cached summary(ies), {} upload(s), {} pending gallery(ies), {} link verdict(s)",
+                stats.embeds, stats.summaries, stats.uploads, stats.galleries, stats.verdicts
+            );
+        }
+        Err(e) => warn!("Failed to prune database: {:?}", e),
+    }
+
+    if config.media_max_age.is_some() && config.media_admin_token.is_some() {
+        match media_gc::run(config, database, false).await {
+            Ok(stats) => {
+                metrics().record_pruned(Pruned::Media, stats.deleted);
+                info!(
+                    "Maintenance: deleted {} old media file(s), {} failed, {} still in use",
+                    stats.deleted, stats.failed, stats.kept
+                );
+            }
+            Err(e) => warn!("Failed to delete old media: {:?}", e),
+        }
+    }
+
+    let detections = ap_detector.prune_expired().await;
+    metrics().record_pruned(Pruned::Detections, detections);
+
+    match database.vacuum().await {
+        Ok(bytes) => {
+            metrics().record_reclaimed(Reclaimed::Database, bytes);
+            info!("Maintenance: vacuuming reclaimed {} byte(s)", bytes);
+        }
+        Err(e) => warn!("Failed to vacuum database: {:?}", e),
+    }
+
+    let temp_dir = std::env::temp_dir();
+    match tokio::task::spawn_blocking(move || {
+        remove_orphaned_temp_files(&temp_dir, ORPHANED_TEMP_AGE)
+    })
+    .await
+    {
+        Ok((files, bytes)) => {
+            metrics().record_pruned(Pruned::TempFiles, files);
+            metrics().record_reclaimed(Reclaimed::TempFiles, bytes);
+            if files > 0 {
+                info!(
+                    "Maintenance: removed {} orphaned temp file(s), {} byte(s)",
+                    files, bytes
+                );
+            }
+        }
+        Err(e) => warn!("Temp file cleanup task panicked: {:?}", e),
+    }
+
+    metrics().record_maintenance_run();
+}
+
+/// Remove files and directories in `dir` named with [`TEMP_PREFIX`] that
+/// haven't been modified for `max_age`. Returns how many files were removed
+/// and their total size.
+fn remove_orphaned_temp_files(dir: &Path, max_age: Duration) -> (usize, u64) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to list {}: {:?}", dir.display(), e);
+            return (0, 0);
+        }
+    };
+    let mut files = 0;
+    let mut bytes = 0;
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+            continue;
+        }
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        let age = meta
+            .modified()
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+        let path = entry.path();
+        let (count, size) = if meta.is_dir() {
+            dir_usage(&path)
+        } else {
+            (1, meta.len())
+        };
+        let removed = if meta.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match removed {
+            Ok(()) => {
+                debug!("Removed orphaned temp file {}", path.display());
+                files += count;
+                bytes += size;
+            }
+            Err(e) => warn!("Failed to remove {}: {:?}", path.display(), e),
+        }
+    }
+    (files, bytes)
+}
+
+/// Count the files under `dir` and their total size.
+fn dir_usage(dir: &Path) -> (usize, u64) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return (0, 0);
+    };
+    let mut files = 0;
+    let mut bytes = 0;
+    for entry in entries.flatten() {
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_dir() {
+            let (count, size) = dir_usage(&entry.path());
+            files += count;
+            bytes += size;
+        } else {
+            files += 1;
+            bytes += meta.len();
+        }
+    }
+    (files, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_orphaned_temp_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("matrix-embed-abc"), b"12345").unwrap();
+        let workdir = dir.path().join("matrix-embed-work");
+        std::fs::create_dir(&workdir).unwrap();
+        std::fs::write(workdir.join("frame.png"), b"123").unwrap();
+        std::fs::write(workdir.join("out.mp4"), b"1234567").unwrap();
+        std::fs::write(dir.path().join("unrelated"), b"keep").unwrap();
+
+        // Everything is fresh, so nothing is old enough to be orphaned.
+        assert_eq!(
+            remove_orphaned_temp_files(dir.path(), Duration::from_secs(3600)),
+            (0, 0)
+        );
+        assert_eq!(
+            remove_orphaned_temp_files(dir.path(), Duration::ZERO),
+            (3, 15)
+        );
+        assert!(!workdir.exists());
+        assert!(dir.path().join("unrelated").exists());
+    }
+}
@@ -0,0 +1,314 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use reqwest::header::{
+    CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::cas::content_hash;
+
+/// Extension of the file next to each cached body that holds its headers.
+const HEADERS_EXTENSION: &str = "json";
+
+/// An on-disk cache of recently downloaded media, so a link posted in several
+/// rooms is only downloaded from its origin once.
+///
+/// Entries are keyed by the URL the media was requested from, and are only
+/// kept if the response had a validator (`ETag` or `Last-Modified`). A
+/// cached entry is revalidated with a conditional request before it's used,
+/// so a changed file is downloaded again. Each entry is the body, named by
+/// the hash of the URL, and the headers it came with next to it. Using an
+/// entry bumps its modification time, and the least recently used entries
+/// are removed whenever the cache grows past `max_bytes`.
+#[derive(Debug)]
+pub struct MediaCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+/// What's kept of a cached response's headers: enough to process the body
+/// again, and to ask the server whether it has changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedHeaders {
+    /// Where the media was downloaded from, after redirects.
+    pub final_url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_type: Option<String>,
+    pub content_disposition: Option<String>,
+}
+
+impl CachedHeaders {
+    pub fn new(final_url: &Url, headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            final_url: final_url.to_string(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            content_type: header(CONTENT_TYPE),
+            content_disposition: header(CONTENT_DISPOSITION),
+        }
+    }
+
+    /// Whether the server can tell us if the media has changed, which is
+    /// what makes it worth caching.
+    pub fn has_validators(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+/// A cache entry found for a URL.
+#[derive(Debug, Clone)]
+pub struct CachedMedia {
+    pub headers: CachedHeaders,
+    pub path: PathBuf,
+}
+
+impl CachedMedia {
+    /// Make `request` conditional on the cached copy, so an unchanged file
+    /// gets a `304 Not Modified` instead of being sent again.
+    pub fn revalidate(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(etag) = &self.headers.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.headers.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+}
+
+/// Where a download fits in the media cache: the cache, the URL the media
+/// was requested from, and the copy the request revalidated, if any.
+pub struct CacheSlot<'a> {
+    pub cache: &'a MediaCache,
+    pub url: &'a Url,
+    pub cached: Option<CachedMedia>,
+}
+
+impl MediaCache {
+    pub fn open(dir: &Path, max_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create media cache at {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            max_bytes,
+        })
+    }
+
+    fn body_path(&self, url: &Url) -> PathBuf {
+        self.dir.join(content_hash(url.as_str().as_bytes()))
+    }
+
+    /// Look up the cached media for `url`, marking it as recently used.
+    pub async fn get(&self, url: &Url) -> Option<CachedMedia> {
+        let path = self.body_path(url);
+        let headers = tokio::fs::read(path.with_extension(HEADERS_EXTENSION))
+            .await
+            .ok()?;
+        let headers: CachedHeaders = match serde_json::from_slice(&headers) {
+            Ok(headers) => headers,
+            Err(e) => {
+                warn!("Ignoring unreadable media cache entry for {}: {:?}", url, e);
+                return None;
+            }
+        };
+        let touched = std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        if let Err(e) = touched {
+            debug!("Media cache entry for {} is gone: {:?}", url, e);
+            return None;
+        }
+        Some(CachedMedia { headers, path })
+    }
+
+    /// Keep the downloaded `file` for `url`, then make room by removing the
+    /// least recently used entries. The file is copied, so it can still be
+    /// processed afterwards.
+    pub async fn insert(&self, url: &Url, headers: &CachedHeaders, file: &Path) -> Result<()> {
+        let path = self.body_path(url);
+        let headers = serde_json::to_vec(headers).context("Failed to serialize headers")?;
+        let dir = self.dir.clone();
+        let file = file.to_owned();
+        let max_bytes = self.max_bytes;
+        tokio::task::spawn_blocking(move || {
+            // Write both halves under temporary names first, so a reader
+            // never sees a partial body.
+            let body = tempfile::NamedTempFile::new_in(&dir)
+                .context("Failed to create media cache file")?;
+            std::fs::copy(&file, body.path()).context("Failed to copy into media cache")?;
+            let sidecar = tempfile::NamedTempFile::new_in(&dir)
+                .context("Failed to create media cache file")?;
+            std::fs::write(sidecar.path(), &headers).context("Failed to write media cache")?;
+            body.persist(&path)
+                .context("Failed to store media cache entry")?;
+            sidecar
+                .persist(path.with_extension(HEADERS_EXTENSION))
+                .context("Failed to store media cache entry")?;
+
+            let (removed, bytes) = evict(&dir, max_bytes);
+            if removed > 0 {
+                debug!("Removed {} media cache entries, {} byte(s)", removed, bytes);
+            }
+            Ok(())
+        })
+        .await
+        .context("Media cache task panicked")?
+    }
+}
+
+/// Remove the least recently used entries in `dir` until the bodies add up
+/// to at most `max_bytes`, along with headers left without a body. Returns
+/// how many entries were removed and their size.
+fn evict(dir: &Path, max_bytes: u64) -> (usize, u64) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return (0, 0);
+    };
+    let mut bodies = Vec::new();
+    let mut sidecars = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Temporary files of inserts in progress start with a dot.
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if path.extension().is_some_and(|ext| ext == HEADERS_EXTENSION) {
+            sidecars.push(path);
+            continue;
+        }
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        let used = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        bodies.push((used, meta.len(), path));
+    }
+
+    for sidecar in sidecars {
+        if !sidecar.with_extension("").exists() {
+            let _ = std::fs::remove_file(sidecar);
+        }
+    }
+
+    let mut total: u64 = bodies.iter().map(|(_, len, _)| len).sum();
+    if total <= max_bytes {
+        return (0, 0);
+    }
+    bodies.sort();
+    let mut removed = 0;
+    let mut bytes = 0;
+    for (_, len, path) in bodies {
+        if total <= max_bytes {
+            break;
+        }
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to remove {}: {:?}", path.display(), e);
+            continue;
+        }
+        let _ = std::fs::remove_file(path.with_extension(HEADERS_EXTENSION));
+        total -= len;
+        removed += 1;
+        bytes += len;
+    }
+    (removed, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn headers(url: &Url) -> CachedHeaders {
+        CachedHeaders {
+            final_url: url.to_string(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            content_type: Some("video/mp4".to_string()),
+            content_disposition: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_and_get() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = MediaCache::open(&dir.path().join("cache"), 1024).unwrap();
+        let url = Url::parse("https://example.com/video.mp4").unwrap();
+        assert!(cache.get(&url).await.is_none());
+
+        let download = dir.path().join("download");
+        std::fs::write(&download, b"12345").unwrap();
+        cache.insert(&url, &headers(&url), &download).await.unwrap();
+        // The download is copied, not moved.
+        assert!(download.exists());
+
+        let cached = cache.get(&url).await.unwrap();
+        assert_eq!(cached.headers, headers(&url));
+        assert_eq!(std::fs::read(&cached.path).unwrap(), b"12345");
+
+        let request = cached.revalidate(reqwest::Client::new().get(url.clone()));
+        let request = request.build().unwrap();
+        assert_eq!(request.headers()[IF_NONE_MATCH], "\"abc\"");
+        assert!(request.headers().get(IF_MODIFIED_SINCE).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_evict_least_recently_used() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = MediaCache::open(dir.path(), 10).unwrap();
+        let download = dir.path().join(".download");
+        std::fs::write(&download, b"1234").unwrap();
+        let urls: Vec<Url> = (1..=3)
+            .map(|i| Url::parse(&format!("https://example.com/{}.jpg", i)).unwrap())
+            .collect();
+
+        for url in &urls[..2] {
+            cache.insert(url, &headers(url), &download).await.unwrap();
+        }
+        // Make the first entry older, then use it so it's the newest.
+        let old = SystemTime::now() - Duration::from_secs(60);
+        for url in &urls[..2] {
+            let file = std::fs::File::options()
+                .write(true)
+                .open(cache.body_path(url))
+                .unwrap();
+            file.set_modified(old).unwrap();
+        }
+        assert!(cache.get(&urls[0]).await.is_some());
+
+        cache
+            .insert(&urls[2], &headers(&urls[2]), &download)
+            .await
+            .unwrap();
+        assert!(cache.get(&urls[0]).await.is_some());
+        assert!(cache.get(&urls[1]).await.is_none());
+        assert!(cache.get(&urls[2]).await.is_some());
+        assert!(
+            !cache
+                .body_path(&urls[1])
+                .with_extension(HEADERS_EXTENSION)
+                .exists()
+        );
+    }
+
+    #[test]
+    fn test_has_validators() {
+        let url = Url::parse("https://example.com/a.jpg").unwrap();
+        let mut headers = headers(&url);
+        assert!(headers.has_validators());
+        headers.etag = None;
+        assert!(!headers.has_validators());
+        headers.last_modified = Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string());
+        assert!(headers.has_validators());
+    }
+}
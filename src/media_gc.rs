@@ -0,0 +1,231 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use reqwest::StatusCode;
+use reqwest::header::AUTHORIZATION;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::config::Config;
+use crate::db::{Database, MediaUpload};
+
+/// How long a single admin API request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What [`run`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Media deleted, or already gone from the media repository.
+    pub deleted: usize,
+    /// Media that couldn't be deleted, and is tried again next time.
+    pub failed: usize,
+    /// Media kept because another instance sharing the upload cache reused
+    /// it recently.
+    pub kept: usize,
+}
+
+/// Delete the media the bot uploaded that hasn't been used for
+/// `config.media_max_age`, through the Synapse admin API with
+/// `config.media_admin_token`. With `dry_run`, only log what would be
+/// deleted.
+///
+/// Uploads are recorded in the bot's own database, but another instance
+/// sharing the store can reuse them through the upload cache. So before an
+/// upload is deleted it's taken out of the cache, unless the cache says it
+/// was used within `media_max_age`, in which case it's kept for now.
+pub async fn run(config: &Config, database: &Database, dry_run: bool) -> Result<GcStats> {
+    let Some(max_age) = config.media_max_age else {
+        bail!("--media-max-age-days is required to delete old media");
+    };
+    let uploads = database.unused_media_uploads(max_age).await?;
+    let mut stats = GcStats::default();
+    if dry_run {
+        for upload in &uploads {
+            info!("Would delete {}", upload.mxc_uri);
+        }
+        return Ok(stats);
+    }
+    let Some(token) = &config.media_admin_token else {
+        bail!("--media-admin-token-file is required to delete old media");
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .context("Failed to build HTTP client")?;
+    for upload in uploads {
+        if !release(database, &upload, max_age).await? {
+            debug!("Keeping {}: it was reused recently", upload.mxc_uri);
+            stats.kept += 1;
+            continue;
+        }
+        match delete(&client, &config.homeserver_url, token, &upload.mxc_uri).await {
+            Ok(()) => {
+                database.forget_media_upload(&upload.mxc_uri).await?;
+                stats.deleted += 1;
+            }
+            Err(e) => {
+                warn!("Failed to delete {}: {:?}", upload.mxc_uri, e);
+                stats.failed += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Delete the media at `mxc_uri` from the media repository of the
+/// homeserver at `homeserver_url`. Media that's already gone counts as
+/// deleted.
+async fn delete(
+    client: &reqwest::Client,
+    homeserver_url: &Url,
+    token: &str,
+    mxc_uri: &str,
+) -> Result<()> {
+    let url = admin_url(homeserver_url, mxc_uri)?;
+    let response = client
+        .delete(url)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .send()
+        .await
+        .context("Failed to reach the admin API")?;
+    if response.status() == StatusCode::NOT_FOUND {
+        debug!("{} was already deleted", mxc_uri);
+        return Ok(());
+    }
+    response
+        .error_for_status()
+        .context("The admin API refused to delete the media")?;
+    debug!("Deleted {}", mxc_uri);
+    Ok(())
+}
+
+/// The Synapse admin API endpoint for the media at `mxc_uri`.
+fn admin_url(homeserver_url: &Url, mxc_uri: &str) -> Result<Url> {
+    let Some((server_name, media_id)) = mxc_uri
+        .strip_prefix("mxc://")
+        .and_then(|rest| rest.split_once('/'))
+        .filter(|(server_name, media_id)| !server_name.is_empty() && !media_id.is_empty())
+    else {
+        bail!("Not an mxc:// URI: {}", mxc_uri);
+    };
+    let mut url = homeserver_url.clone();
+    url.path_segments_mut()
+        .map_err(|()| anyhow::anyhow!("Invalid homeserver URL: {}", homeserver_url))?
+        .pop_if_empty()
+        .extend(["_synapse", "admin", "v1", "media", server_name, media_id]);
+    Ok(url)
+}
+
+/// Take `upload` out of the upload cache so it isn't reused once it's
+/// deleted, returning `false` if the cache says it was used within
+/// `max_age` after all. Then it's marked as used here too, to be looked at
+/// again later.
+async fn release(database: &Database, upload: &MediaUpload, max_age: Duration) -> Result<bool> {
+    let Some(content_hash) = &upload.content_hash else {
+        return Ok(true);
+    };
+    if database
+        .forget_unused_uploaded_media(content_hash, upload.encrypted, max_age)
+        .await?
+    {
+        return Ok(true);
+    }
+    database
+        .record_media_upload(&upload.mxc_uri, None, upload.encrypted)
+        .await?;
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::UploadedMedia;
+
+    #[test]
+    fn test_admin_url() {
+        let homeserver = Url::parse("https://matrix.example.com").unwrap();
+        assert_eq!(
+            admin_url(&homeserver, "mxc://example.com/AbC123")
+                .unwrap()
+                .as_str(),
+            "https://matrix.example.com/_synapse/admin/v1/media/example.com/AbC123"
+        );
+        let homeserver = Url::parse("https://example.com/matrix/").unwrap();
+        assert_eq!(
+            admin_url(&homeserver, "mxc://example.com/a/b")
+                .unwrap()
+                .as_str(),
+            "https://example.com/matrix/_synapse/admin/v1/media/example.com/a%2Fb"
+        );
+        assert!(admin_url(&homeserver, "https://example.com/a").is_err());
+        assert!(admin_url(&homeserver, "mxc://example.com/").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .and(path("/_synapse/admin/v1/media/example.com/old"))
+            .and(header("authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{}"))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/_synapse/admin/v1/media/example.com/gone"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/_synapse/admin/v1/media/example.com/locked"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+
+        let db = Database::open_in_memory().await.unwrap();
+        for id in ["old", "gone", "locked"] {
+            db.record_media_upload(&format!("mxc://example.com/{}", id), Some(id), false)
+                .await
+                .unwrap();
+            let media = UploadedMedia {
+                source: format!("\"mxc://example.com/{}\"", id),
+                thumbnail_source: None,
+            };
+            db.record_uploaded_media(id, false, &media, 10)
+                .await
+                .unwrap();
+        }
+        let config = Config {
+            homeserver_url: Url::parse(&server.uri()).unwrap(),
+            media_max_age: Some(Duration::ZERO),
+            media_admin_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+
+        assert_eq!(run(&config, &db, true).await.unwrap(), GcStats::default());
+        assert_eq!(
+            run(&config, &db, false).await.unwrap(),
+            GcStats {
+                deleted: 2,
+                failed: 1,
+                kept: 0
+            }
+        );
+        assert_eq!(db.get_uploaded_media("old", false).await.unwrap(), None);
+        // Media that couldn't be deleted is out of the cache all the same, so
+        // nothing reuses it before the next try.
+        assert_eq!(db.get_uploaded_media("locked", false).await.unwrap(), None);
+        let left = db.unused_media_uploads(Duration::ZERO).await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].mxc_uri, "mxc://example.com/locked");
+
+        let config = Config {
+            media_max_age: None,
+            ..config
+        };
+        assert!(run(&config, &db, false).await.is_err());
+    }
+}
@@ -1,39 +1,238 @@
-use anyhow::{Result, bail};
-use scraper::{Html, Selector};
-use std::sync::LazyLock;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, FixedOffset};
+use reqwest::header::{ACCEPT_ENCODING, ACCEPT_LANGUAGE, USER_AGENT};
+use scraper::Html;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
 use tracing::{debug, info, warn};
 use url::Url;
 
 use crate::activitypub::ActivityPubDetector;
+use crate::calendar;
+use crate::config::Config;
+use crate::decompress;
+use crate::dump;
+use crate::fixtures;
+use crate::html_scan::{self, Scan, Tag};
+use crate::http::{self, Fetch};
+use crate::locale;
+use crate::metrics::metrics;
+#[cfg(feature = "extractors")]
+use crate::oembed;
+use crate::readability;
+use crate::timestamp;
 
-// Match both property="og:..." and name="og:..." since some stuff uses name even though it is non-standard.
-static OPENGRAPH_SELECTOR: LazyLock<Selector> =
-    LazyLock::new(|| Selector::parse(r#"meta[property^="og:"], meta[name^="og:"]"#).unwrap());
+/// Statuses bot walls answer with, as opposed to the page being gone.
+const BLOCKED_STATUSES: &[u16] = &[401, 403, 429, 503];
 
-// Misskey apparently uses property for twitter meta tags, even though that's only used by opengraph.
-static TWITTER_SELECTOR: LazyLock<Selector> = LazyLock::new(|| {
-    Selector::parse(r#"meta[property^="twitter:"], meta[name^="twitter:"]"#).unwrap()
-});
+/// Prefixes of schema.org `@type`s, which some pages spell out in full.
+const SCHEMA_ORG_PREFIXES: &[&str] = &["http://schema.org/", "https://schema.org/", "schema:"];
 
-#[derive(Debug, Clone, Default, Eq, PartialEq)]
+/// A fetched page's metadata, where its AMP version is if it links one, and
+/// the versions of it in other languages it links, by language tag.
+struct Page {
+    metadata: Metadata,
+    amp_url: Option<Url>,
+    localized: Vec<(String, Url)>,
+}
+
+/// One of several encodings of the same video.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Rendition {
+    pub url: Url,
+    pub height: Option<u32>,
+    /// Size in bytes, if the source says.
+    pub size: Option<u64>,
+}
+
+/// An `og:image` and the structured properties that followed it.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OgImage {
+    pub url: Option<Url>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub mime_type: Option<String>,
+    pub alt: Option<String>,
+}
+
+impl OgImage {
+    /// Pixel count, or 0 if the page didn't give both dimensions.
+    fn area(&self) -> u64 {
+        match (self.width, self.height) {
+            (Some(w), Some(h)) => u64::from(w) * u64::from(h),
+            _ => 0,
+        }
+    }
+
+    /// Whether the declared type is one we can post as an image. Images
+    /// without a declared type get the benefit of the doubt.
+    fn has_usable_type(&self) -> bool {
+        self.mime_type.as_deref().is_none_or(|t| {
+            let t = t.trim().to_ascii_lowercase();
+            t.starts_with("image/") && t != "image/svg+xml"
+        })
+    }
+}
+
+/// An image in a post that has several.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GalleryImage {
+    pub url: Url,
+    pub alt: Option<String>,
+}
+
+/// A custom emote used in a post, like `:blobcat:`, and its image.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Emote {
+    /// The shortcode, without colons.
+    pub shortcode: String,
+    pub url: Url,
+    /// The image uploaded to the media repository, once it has been.
+    pub mxc_uri: Option<String>,
+}
+
+/// Page metadata. It's kept in the shared store between fetches, so fields
+/// missing from what's stored there come out as their defaults.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Metadata {
     pub card: Option<String>,
     pub title: Option<String>,
+    /// Name of the site as a whole, from `og:site_name`.
+    pub site_name: Option<String>,
+    /// Language the page is in, from `og:locale`, e.g. `en_US`.
+    pub locale: Option<String>,
+    /// Other languages the page is available in, from `og:locale:alternate`.
+    pub alternate_locales: Vec<String>,
     pub description: Option<String>,
+    /// `description` as HTML, from extractors whose source has formatting.
+    /// Not yet sanitized.
+    pub description_html: Option<String>,
     pub image_url: Option<Url>,
+    /// Alt text for `image_url`, from `og:image:alt` or `twitter:image:alt`.
+    pub image_alt: Option<String>,
+    /// `image_url` as the page gave it, if that was swapped for a
+    /// full-resolution original that might not exist.
+    pub original_image_url: Option<Url>,
+    /// More images after `image_url`, for posts that have several.
+    pub gallery: Vec<GalleryImage>,
+    /// Every `og:image` on the page, in order, with its size, type and alt
+    /// text. `image_url` is picked from these.
+    pub og_images: Vec<OgImage>,
     pub video_url: Option<Url>,
+    /// Another `og:video` candidate, tried if `video_url` turns out not to be
+    /// a video.
+    pub alternate_video_url: Option<Url>,
+    /// Length of `video_url` in seconds, from `og:video:duration`.
+    pub video_duration: Option<u64>,
+    /// Every encoding of `video_url` on offer, when the source lists several
+    /// to pick from.
+    pub video_renditions: Vec<Rendition>,
     pub audio_url: Option<Url>,
+    /// Embeddable player page from `twitter:player`, for when there's no
+    /// direct video link.
+    pub player_url: Option<Url>,
+    /// Readable body text of the page, used as input for summaries.
+    pub text: Option<String>,
+    /// Generated summary of `text`, if one was requested.
+    pub summary: Option<String>,
+    /// When the page or post was published.
+    pub published: Option<DateTime<FixedOffset>>,
+    /// Phishing hint about the link's domain, e.g. for lookalike IDNs.
+    pub url_warning: Option<String>,
+    /// The page's own idea of its address: `link rel="canonical"` if present,
+    /// otherwise the URL we ended up at after redirects.
+    pub canonical_url: Option<Url>,
+    /// Where the page says its content really lives, from `og:url` or
+    /// `link rel="canonical"`, if that's somewhere other than the page itself.
+    pub content_url: Option<Url>,
+    /// Custom emotes whose shortcodes appear in the title and description,
+    /// from extractors whose source lists them.
+    pub emotes: Vec<Emote>,
 }
 
 impl Metadata {
+    /// Returns `true` if there's nothing to embed. Page text alone doesn't
+    /// count, since it's only used to produce a summary.
     pub fn is_empty(&self) -> bool {
-        self == &Metadata::default()
+        self.card.is_none()
+            && self.title.is_none()
+            && self.description.is_none()
+            && self.image_url.is_none()
+            && self.video_url.is_none()
+            && self.audio_url.is_none()
+            && self.player_url.is_none()
+            && self.summary.is_none()
     }
 
+    /// Returns `true` if there's no media and no description, as on link
+    /// shorteners and other pages that only point elsewhere.
+    pub fn is_weak(&self) -> bool {
+        self.image_url.is_none()
+            && self.video_url.is_none()
+            && self.audio_url.is_none()
+            && self.description.is_none()
+    }
+
+    /// Fill in whatever `self` lacks from `other`. Media URLs are taken along
+    /// with the details that describe them, and the card type only if `self`
+    /// has no media of its own for it to apply to.
+    pub fn merge(mut self, other: Metadata) -> Metadata {
+        let has_media =
+            self.image_url.is_some() || self.video_url.is_some() || self.audio_url.is_some();
+        if self.card.is_none() && !has_media {
+            self.card = other.card;
+        }
+        if self.description.is_none() {
+            self.description = other.description;
+            self.description_html = other.description_html;
+        }
+        if self.image_url.is_none() && other.image_url.is_some() {
+            self.image_url = other.image_url;
+            self.image_alt = other.image_alt;
+            self.original_image_url = other.original_image_url;
+            self.gallery = other.gallery;
+            self.og_images = other.og_images;
+        }
+        if self.video_url.is_none() && other.video_url.is_some() {
+            self.video_url = other.video_url;
+            self.alternate_video_url = other.alternate_video_url;
+            self.video_duration = other.video_duration;
+            self.video_renditions = other.video_renditions;
+        }
+        if self.locale.is_none() {
+            self.locale = other.locale;
+            self.alternate_locales = other.alternate_locales;
+        }
+        Metadata {
+            title: self.title.or(other.title),
+            site_name: self.site_name.or(other.site_name),
+            audio_url: self.audio_url.or(other.audio_url),
+            player_url: self.player_url.or(other.player_url),
+            text: self.text.or(other.text),
+            summary: self.summary.or(other.summary),
+            published: self.published.or(other.published),
+            url_warning: self.url_warning.or(other.url_warning),
+            canonical_url: self.canonical_url.or(other.canonical_url),
+            content_url: self.content_url.or(other.content_url),
+            emotes: if self.emotes.is_empty() {
+                other.emotes
+            } else {
+                self.emotes
+            },
+            ..self
+        }
+    }
+
+    /// Fetch the metadata of `url`. Pages are asked for in `language`, a
+    /// BCP 47 tag, if given, and a page in another language that links a
+    /// version in that one is described by that version.
     pub async fn fetch_from_url(
         client: &reqwest::Client,
         url: &Url,
+        config: &Config,
         ap_detector: &ActivityPubDetector,
+        language: Option<&str>,
     ) -> Result<Metadata> {
         // Try ActivityPub first.
         if let Some(meta) = ap_detector.fetch_metadata(client, url).await {
@@ -41,8 +240,15 @@ impl Metadata {
             return Ok(meta);
         }
 
-        let content_type = match client.head(url.clone()).send().await {
+        let user_agent = http::user_agent(config, url, Fetch::Metadata);
+        let content_type = match client
+            .head(url.clone())
+            .header(USER_AGENT, user_agent)
+            .send()
+            .await
+        {
             Ok(resp) => {
+                dump::record_response(&resp);
                 if let Err(e) = resp.error_for_status_ref() {
                     debug!("HEAD request returned error status for {}: {}", url, e);
                     None
@@ -84,110 +290,744 @@ impl Metadata {
                     });
                 }
                 _ => {
-                    if mime_type != "text/html" && mime_type != "application/xhtml+xml" {
+                    if mime_type != "text/html"
+                        && mime_type != "application/xhtml+xml"
+                        && !calendar::is_calendar(mime_type, url.path())
+                    {
                         bail!("Unsupported content type: {}", mime_type);
                     }
                 }
             }
         }
 
-        // Either it was HTML, or we couldn't determine the type — fetch and
-        // parse as HTML.
-        Ok(Self::parse_from_html(
-            &client
-                .get(url.clone())
-                .send()
-                .await?
-                .error_for_status()?
-                .text()
-                .await?,
+        // Either it was HTML (or a calendar), or we couldn't determine the
+        // type — fetch it and look at what we actually got.
+        let page = match (
+            Self::fetch_page(client, url, config, user_agent, language).await,
+            language,
+        ) {
+            (Ok(page), Some(language)) => Ok(Self::localize(client, page, config, language).await),
+            (page, _) => page,
+        };
+        let host = url.host_str().unwrap_or_default();
+        if !http::is_listed(host, &config.alternate_source_domains) {
+            return page.map(|page| page.metadata);
+        }
+
+        // Pages behind a bot wall either refuse outright or come back with
+        // nothing to embed; their AMP or mobile versions often don't.
+        let (page, amp_url) = match page {
+            Ok(page) if !page.metadata.is_weak() => return Ok(page.metadata),
+            Ok(page) => (Ok(page.metadata), page.amp_url),
+            Err(e) if looks_blocked(&e) => (Err(e), None),
+            Err(e) => return Err(e),
+        };
+        for alternate in amp_url.into_iter().chain(mobile_variant(url)) {
+            info!("{} seems to block bots, trying {}", url, alternate);
+            let user_agent = http::user_agent(config, &alternate, Fetch::Metadata);
+            match Self::fetch_page(client, &alternate, config, user_agent, language).await {
+                Ok(alternate) if !alternate.metadata.is_weak() => return Ok(alternate.metadata),
+                Ok(_) => debug!("{} has nothing to embed either", alternate),
+                Err(e) => debug!("Failed to fetch {}: {:?}", alternate, e),
+            }
+        }
+        page
+    }
+
+    /// Fetch the HTML page or calendar at `url`, preferably in `language`,
+    /// and parse it.
+    async fn fetch_page(
+        client: &reqwest::Client,
+        url: &Url,
+        config: &Config,
+        user_agent: &str,
+        language: Option<&str>,
+    ) -> Result<Page> {
+        let mut request = client
+            .get(url.clone())
+            .header(ACCEPT_ENCODING, decompress::ACCEPT_ENCODING)
+            .header(USER_AGENT, user_agent);
+        if let Some(language) = language {
+            // Anything is better than nothing, if the site doesn't have it.
+            request = request.header(ACCEPT_LANGUAGE, format!("{}, *;q=0.5", language));
+        }
+        let response = request.send().await?;
+        dump::record_response(&response);
+        let response = response.error_for_status()?;
+        let final_url = response.url().clone();
+        let mime_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.split(';').next())
+            .unwrap_or("")
+            .trim()
+            .to_string();
+        let body = decompress::read_body(
+            response,
+            config.max_page_size,
+            config.max_decompression_ratio,
+        )
+        .await?;
+        dump::record_body(&body);
+        fixtures::record(url.as_str(), final_url.as_str(), &mime_type, &body);
+        let body = String::from_utf8_lossy(&body);
+
+        if calendar::is_calendar(&mime_type, url.path()) {
+            let metadata = calendar::parse_ics(&body, config.timezone)
+                .context("Calendar file did not contain any events")?;
+            return Ok(Page {
+                metadata,
+                amp_url: None,
+                localized: Vec::new(),
+            });
+        }
+
+        let scan = scan_html(&body, &final_url, config.max_parse_memory);
+        let metadata = Self::parse_scan(&scan, &final_url, config.html_fallback);
+        #[cfg(feature = "extractors")]
+        let metadata = oembed::complete(client, &scan.tags, &final_url, metadata, config).await;
+        Ok(Page {
+            metadata,
+            amp_url: parse_amp_url(&scan.tags, &final_url),
+            localized: parse_localized(&scan.tags, &final_url),
+        })
+    }
+
+    /// `page` as described by its version in `language`, if it's in another
+    /// language and lists that one in `og:locale:alternate` and links it,
+    /// with whatever that version lacks filled in from `page`. Otherwise, or
+    /// if that version can't be fetched, `page` as it is.
+    async fn localize(
+        client: &reqwest::Client,
+        page: Page,
+        config: &Config,
+        language: &str,
+    ) -> Page {
+        let metadata = &page.metadata;
+        if metadata
+            .locale
+            .as_deref()
+            .is_some_and(|locale| locale::matches(locale, language))
+        {
+            return page;
+        }
+        // Sites link every version of a page, but only those they list as
+        // alternates are actually translated.
+        let offered = |tag: &str| {
+            metadata
+                .alternate_locales
+                .iter()
+                .any(|alternate| locale::matches(alternate, tag))
+        };
+        let candidates = page
+            .localized
+            .iter()
+            .filter(|(tag, url)| offered(tag) && Some(url) != metadata.canonical_url.as_ref())
+            .map(|(tag, url)| (tag.as_str(), url));
+        let Some(variant_url) = locale::best_match(candidates, language).cloned() else {
+            return page;
+        };
+
+        debug!(
+            "Fetching the {} version of the page, {}",
+            language, variant_url
+        );
+        let user_agent = http::user_agent(config, &variant_url, Fetch::Metadata);
+        match Self::fetch_page(client, &variant_url, config, user_agent, Some(language)).await {
+            Ok(variant) if !variant.metadata.is_weak() => {
+                // It's still the page that was linked, not the translation.
+                let canonical_url = page.metadata.canonical_url.clone();
+                let content_url = page.metadata.content_url.clone();
+                let mut metadata = variant.metadata.merge(page.metadata);
+                metadata.canonical_url = canonical_url;
+                metadata.content_url = content_url;
+                Page { metadata, ..page }
+            }
+            Ok(_) => {
+                debug!("{} has nothing to embed", variant_url);
+                page
+            }
+            Err(e) => {
+                debug!("Failed to fetch {}: {:?}", variant_url, e);
+                page
+            }
+        }
+    }
+
+    /// Parse the metadata of the HTML page at `page_url`, falling back to
+    /// its `title` and meta description where it has no metadata tags.
+    pub fn parse_from_html(html_content: &str, page_url: &Url) -> Metadata {
+        Self::parse_scan(
+            &scan_html(html_content, page_url, usize::MAX),
+            page_url,
+            true,
+        )
+    }
+
+    /// Parse the metadata of the HTML page at `page_url` from what
+    /// [`html_scan::scan`] kept of it. With `html_fallback`, the document's
+    /// `title` and meta description stand in for a missing title and
+    /// description.
+    fn parse_scan(scan: &Scan, page_url: &Url, html_fallback: bool) -> Metadata {
+        let canonical_url = Self::parse_canonical(&scan.tags, page_url);
+        let mut metadata = Metadata {
+            canonical_url: Some(canonical_url.clone()),
+            ..Default::default()
+        };
+        Self::parse_og_meta(&scan.tags, page_url, &mut metadata);
+        Self::parse_twitter_meta(&scan.tags, page_url, &mut metadata);
+        metadata.published = scan
+            .tags
+            .iter()
+            .filter(|tag| {
+                tag.name == "meta"
+                    && [tag.attr("property"), tag.attr("name")]
+                        .contains(&Some("article:published_time"))
+            })
+            .filter_map(|tag| tag.attr("content"))
+            .find_map(timestamp::parse);
+        // Many news sites only describe their articles well in JSON-LD.
+        metadata = metadata.merge(Self::parse_json_ld(&scan.json_ld, page_url));
+        metadata.content_url = metadata
+            .content_url
+            .take()
+            .or(Some(canonical_url))
+            .filter(|url| url != page_url);
+
+        if html_fallback {
+            if metadata.title.is_none() {
+                metadata.title = scan.title.as_deref().and_then(collapse_whitespace);
+            }
+            if metadata.description.is_none() {
+                metadata.description = scan
+                    .tags
+                    .iter()
+                    .filter(|tag| {
+                        tag.name == "meta"
+                            && tag
+                                .attr("name")
+                                .is_some_and(|name| name.trim().eq_ignore_ascii_case("description"))
+                    })
+                    .filter_map(|tag| tag.attr("content"))
+                    .find_map(collapse_whitespace);
+            }
+        }
+
+        // Blogs often lack a description; fall back to the article's lead.
+        let article = readability::extract(&Html::parse_document(&scan.markup));
+        if metadata.description.is_none() {
+            metadata.description = article.lead;
+        }
+        metadata.text = article.text;
+        metadata
+    }
+
+    /// Find a direct link to the media an embeddable player page plays.
+    pub async fn fetch_player_media(
+        client: &reqwest::Client,
+        player_url: &Url,
+        config: &Config,
+    ) -> Result<Option<Url>> {
+        let response = client
+            .get(player_url.clone())
+            .header(ACCEPT_ENCODING, decompress::ACCEPT_ENCODING)
+            .header(
+                USER_AGENT,
+                http::user_agent(config, player_url, Fetch::Metadata),
+            )
+            .send()
+            .await?;
+        dump::record_response(&response);
+        let response = response.error_for_status()?;
+        let final_url = response.url().clone();
+        let is_video = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.trim_start().starts_with("video/"));
+        if is_video {
+            return Ok(Some(final_url));
+        }
+
+        let body = decompress::read_body(
+            response,
+            config.max_page_size,
+            config.max_decompression_ratio,
+        )
+        .await?;
+        dump::record_body(&body);
+        Ok(Self::parse_player_media(
+            &String::from_utf8_lossy(&body),
+            &final_url,
+            config.max_parse_memory,
         ))
     }
 
-    pub fn parse_from_html(html_content: &str) -> Metadata {
-        let document = Html::parse_document(html_content);
+    /// The video a player page plays: its `og:video`, or else the first
+    /// `<video>` element's source.
+    fn parse_player_media(html_content: &str, page_url: &Url, budget: usize) -> Option<Url> {
+        let scan = scan_html(html_content, page_url, budget);
         let mut metadata = Metadata::default();
-        Self::parse_og_meta(&document, &mut metadata);
-        Self::parse_twitter_meta(&document, &mut metadata);
-        metadata
+        Self::parse_og_meta(&scan.tags, page_url, &mut metadata);
+        if metadata.video_url.is_some() {
+            return metadata.video_url;
+        }
+        scan.tags
+            .iter()
+            .filter(|tag| matches!(tag.name.as_str(), "video" | "source"))
+            .filter_map(|tag| tag.attr("src"))
+            .filter_map(|src| page_url.join(src.trim()).ok())
+            .find(|url| matches!(url.scheme(), "http" | "https"))
     }
 
-    fn parse_og_meta(document: &Html, metadata: &mut Metadata) {
-        for element in document.select(&OPENGRAPH_SELECTOR) {
-            let prop = element
-                .value()
-                .attr("property")
-                .or(element.value().attr("name"));
-            let content = element.value().attr("content");
-
-            if let (Some(prop), Some(content)) = (prop, content) {
-                match prop {
-                    "og:title" => metadata.title = Some(content.to_string()),
-                    "og:description" => metadata.description = Some(content.to_string()),
-                    "og:image" => {
-                        if let Ok(u) = Url::parse(content) {
-                            metadata.image_url = Some(u);
-                        }
+    fn parse_canonical(tags: &[Tag], page_url: &Url) -> Url {
+        tags.iter()
+            .filter(|tag| tag.name == "link" && tag.has_rel("canonical"))
+            .filter_map(|tag| tag.attr("href"))
+            .filter_map(|href| page_url.join(href.trim()).ok())
+            .find(|url| matches!(url.scheme(), "http" | "https"))
+            .unwrap_or_else(|| page_url.clone())
+    }
+
+    /// Read the OpenGraph tags among `tags` into `metadata`. Relative media
+    /// URLs are resolved against `page_url`.
+    fn parse_og_meta(tags: &[Tag], page_url: &Url, metadata: &mut Metadata) {
+        let mut video_candidates = Vec::new();
+        // Both property="og:..." and name="og:...", since some sites use
+        // name even though it's non-standard.
+        for (prop, content) in meta_properties(tags, "og:", ["property", "name"]) {
+            match prop {
+                "og:title" => metadata.title = Some(content.to_string()),
+                "og:site_name" if !content.trim().is_empty() => {
+                    metadata.site_name = Some(content.trim().to_string())
+                }
+                "og:locale" if locale::normalize(content).is_some() => {
+                    metadata.locale = Some(content.trim().to_string())
+                }
+                "og:locale:alternate" if locale::normalize(content).is_some() => {
+                    metadata.alternate_locales.push(content.trim().to_string())
+                }
+                "og:url" => {
+                    if let Ok(u) = Url::parse(content.trim())
+                        && matches!(u.scheme(), "http" | "https")
+                    {
+                        metadata.content_url = Some(u);
                     }
-                    "og:video" => {
-                        if let Ok(u) = Url::parse(content) {
-                            metadata.video_url = Some(u);
-                        }
+                }
+                "og:description" => metadata.description = Some(content.to_string()),
+                "og:image" | "og:image:url" => metadata.og_images.push(OgImage {
+                    url: page_url.join(content.trim()).ok(),
+                    ..Default::default()
+                }),
+                // The structured properties describe the `og:image`
+                // before them; any without one are ignored.
+                "og:image:secure_url" => {
+                    if let Some(image) = metadata.og_images.last_mut()
+                        && let Ok(u) = page_url.join(content.trim())
+                        && u.scheme() == "https"
+                    {
+                        image.url = Some(u);
                     }
-                    "og:audio" => {
-                        if let Ok(u) = Url::parse(content) {
-                            metadata.audio_url = Some(u);
-                        }
+                }
+                "og:image:width" => {
+                    if let Some(image) = metadata.og_images.last_mut() {
+                        image.width = content.trim().parse().ok();
+                    }
+                }
+                "og:image:height" => {
+                    if let Some(image) = metadata.og_images.last_mut() {
+                        image.height = content.trim().parse().ok();
+                    }
+                }
+                "og:image:type" => {
+                    if let Some(image) = metadata.og_images.last_mut() {
+                        image.mime_type = Some(content.trim().to_string());
+                    }
+                }
+                "og:image:alt" if !content.trim().is_empty() => {
+                    if let Some(image) = metadata.og_images.last_mut() {
+                        image.alt = Some(content.trim().to_string());
+                    }
+                }
+                "og:video" => {
+                    if let Ok(u) = page_url.join(content.trim()) {
+                        video_candidates.push(u.clone());
+                        metadata.video_url = Some(u);
+                    }
+                }
+                "og:video:url" | "og:video:secure_url" => {
+                    if let Ok(u) = page_url.join(content.trim()) {
+                        video_candidates.push(u);
                     }
-                    _ => {}
                 }
+                "og:video:duration" => {
+                    metadata.video_duration = content.trim().parse().ok();
+                }
+                "og:audio" => {
+                    if let Ok(u) = page_url.join(content.trim()) {
+                        metadata.audio_url = Some(u);
+                    }
+                }
+                _ => {}
             }
         }
+        if metadata.video_url.is_none() {
+            metadata.video_url = video_candidates.first().cloned();
+        }
+        metadata.alternate_video_url = video_candidates
+            .into_iter()
+            .find(|u| Some(u) != metadata.video_url.as_ref());
+        if let Some(image) = best_og_image(&metadata.og_images) {
+            metadata.image_url = image.url.clone();
+            metadata.image_alt = image.alt.clone();
+        }
     }
 
-    fn parse_twitter_meta(document: &Html, metadata: &mut Metadata) {
-        for element in document.select(&TWITTER_SELECTOR) {
-            if let (Some(name), Some(content)) = (
-                element
-                    .value()
-                    .attr("name")
-                    .or(element.value().attr("property")),
-                element.value().attr("content"),
-            ) {
-                match name {
-                    "twitter:card" => metadata.card = Some(content.to_string()),
-                    "twitter:title" => {
-                        if metadata.title.is_none() {
-                            metadata.title = Some(content.to_string())
-                        }
+    /// Read the Twitter card tags among `tags` into what `metadata` lacks.
+    /// Relative media URLs are resolved against `page_url`.
+    fn parse_twitter_meta(tags: &[Tag], page_url: &Url, metadata: &mut Metadata) {
+        // Misskey uses property for twitter meta tags, even though that's
+        // only used by OpenGraph.
+        for (name, content) in meta_properties(tags, "twitter:", ["name", "property"]) {
+            match name {
+                "twitter:card" => metadata.card = Some(content.to_string()),
+                "twitter:title" => {
+                    if metadata.title.is_none() {
+                        metadata.title = Some(content.to_string())
                     }
-                    "twitter:description" => {
-                        if metadata.description.is_none() {
-                            metadata.description = Some(content.to_string())
-                        }
+                }
+                "twitter:description" => {
+                    if metadata.description.is_none() {
+                        metadata.description = Some(content.to_string())
                     }
-                    "twitter:image" => {
-                        if metadata.image_url.is_none()
-                            && let Ok(u) = Url::parse(content)
-                        {
-                            metadata.image_url = Some(u);
-                        }
+                }
+                "twitter:image" => {
+                    if metadata.image_url.is_none()
+                        && let Ok(u) = page_url.join(content.trim())
+                    {
+                        metadata.image_url = Some(u);
                     }
-                    "twitter:creator" => {
-                        if metadata.title.is_none() {
-                            let creator = content.to_string();
-                            if let Some(creator) = creator.strip_prefix("@") {
-                                metadata.title = Some(creator.to_string());
-                            } else {
-                                metadata.title = Some(creator);
-                            }
+                }
+                "twitter:player" => {
+                    if let Ok(u) = page_url.join(content.trim()) {
+                        metadata.player_url = Some(u);
+                    }
+                }
+                "twitter:player:stream" => {
+                    if metadata.video_url.is_none()
+                        && let Ok(u) = page_url.join(content.trim())
+                    {
+                        metadata.video_url = Some(u);
+                    }
+                }
+                "twitter:image:alt"
+                    if metadata.image_alt.is_none() && !content.trim().is_empty() =>
+                {
+                    metadata.image_alt = Some(content.trim().to_string());
+                }
+                "twitter:creator" => {
+                    if metadata.title.is_none() {
+                        let creator = content.to_string();
+                        if let Some(creator) = creator.strip_prefix("@") {
+                            metadata.title = Some(creator.to_string());
+                        } else {
+                            metadata.title = Some(creator);
                         }
                     }
-                    _ => {}
                 }
+                _ => {}
+            }
+        }
+    }
+
+    /// The metadata in a page's JSON-LD `blocks`, from the nodes that
+    /// describe the page: articles (or posts), videos, images and products.
+    /// Earlier nodes win, and all their images are candidates.
+    fn parse_json_ld(blocks: &[String], page_url: &Url) -> Metadata {
+        let mut metadata = Metadata::default();
+        let nodes: Vec<serde_json::Value> = blocks
+            .iter()
+            .filter_map(|block| serde_json::from_str(block.trim()).ok())
+            .collect();
+        let mut video_seen = false;
+        for node in nodes.iter().flat_map(json_ld_nodes) {
+            let types = json_ld_types(node);
+            let is_video = types.contains(&"VideoObject");
+            let is_image = types.contains(&"ImageObject");
+            let describes_page = is_video
+                || is_image
+                || types.iter().any(|kind| {
+                    *kind == "Product" || kind.ends_with("Article") || kind.ends_with("Posting")
+                });
+            if !describes_page {
+                continue;
+            }
+
+            if metadata.title.is_none() {
+                metadata.title = json_ld_text(&node["headline"]).or(json_ld_text(&node["name"]));
+            }
+            if metadata.description.is_none() {
+                metadata.description = json_ld_text(&node["description"]);
+            }
+            if metadata.site_name.is_none() {
+                metadata.site_name = json_ld_text(&node["publisher"]["name"]);
+            }
+            if metadata.published.is_none() {
+                metadata.published = json_ld_text(&node["datePublished"])
+                    .or(json_ld_text(&node["uploadDate"]))
+                    .and_then(|date| timestamp::parse(&date));
+            }
+            if is_image {
+                metadata
+                    .og_images
+                    .extend(json_ld_images(node, page_url, &["contentUrl", "url"]));
+                if let Some(image) = metadata.og_images.last_mut()
+                    && image.alt.is_none()
+                {
+                    image.alt = json_ld_text(&node["caption"]);
+                }
+            } else {
+                for key in ["image", "thumbnailUrl", "thumbnail"] {
+                    metadata.og_images.extend(json_ld_images(
+                        &node[key],
+                        page_url,
+                        &["url", "contentUrl"],
+                    ));
+                }
+            }
+            if is_video && !video_seen {
+                video_seen = true;
+                metadata.video_url = json_ld_url(&node["contentUrl"], page_url);
+                metadata.player_url = json_ld_url(&node["embedUrl"], page_url);
+                metadata.video_duration = json_ld_text(&node["duration"])
+                    .as_deref()
+                    .and_then(parse_iso8601_duration);
             }
         }
+        if let Some(image) = best_og_image(&metadata.og_images) {
+            metadata.image_url = image.url.clone();
+            metadata.image_alt = image.alt.clone();
+        }
+        metadata
+    }
+}
+
+/// The nodes of a JSON-LD document: itself, the items of a top-level array,
+/// or the nodes of its `@graph`.
+fn json_ld_nodes(value: &serde_json::Value) -> Vec<&serde_json::Value> {
+    match value {
+        serde_json::Value::Array(items) => items.iter().flat_map(json_ld_nodes).collect(),
+        serde_json::Value::Object(object) => match object.get("@graph") {
+            Some(graph) => json_ld_nodes(graph),
+            None => vec![value],
+        },
+        _ => vec![],
+    }
+}
+
+/// The `@type`s of a JSON-LD node, without the schema.org prefix.
+fn json_ld_types(node: &serde_json::Value) -> Vec<&str> {
+    let types = match &node["@type"] {
+        serde_json::Value::String(kind) => vec![kind.as_str()],
+        serde_json::Value::Array(kinds) => kinds.iter().filter_map(|kind| kind.as_str()).collect(),
+        _ => vec![],
+    };
+    types
+        .into_iter()
+        .map(|kind| {
+            SCHEMA_ORG_PREFIXES
+                .iter()
+                .find_map(|prefix| kind.strip_prefix(prefix))
+                .unwrap_or(kind)
+        })
+        .collect()
+}
+
+/// A text property, which may be given as a list or a value object.
+fn json_ld_text(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(text) => Some(text.trim().to_string()).filter(|t| !t.is_empty()),
+        serde_json::Value::Array(items) => items.iter().find_map(json_ld_text),
+        serde_json::Value::Object(object) => object.get("@value").and_then(json_ld_text),
+        _ => None,
+    }
+}
+
+/// A URL property, resolved against the page's address.
+fn json_ld_url(value: &serde_json::Value, page_url: &Url) -> Option<Url> {
+    let url = match value {
+        serde_json::Value::Object(object) => object.get("@id").and_then(json_ld_text),
+        value => json_ld_text(value),
+    }?;
+    page_url
+        .join(&url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// A width or height, given as a number, a string or a `QuantitativeValue`.
+fn json_ld_size(value: &serde_json::Value) -> Option<u32> {
+    match value {
+        serde_json::Value::Number(number) => number.as_u64()?.try_into().ok(),
+        serde_json::Value::String(text) => text.trim().trim_end_matches("px").parse().ok(),
+        serde_json::Value::Object(object) => object.get("value").and_then(json_ld_size),
+        _ => None,
+    }
+}
+
+/// The images an image property gives: URLs, or image objects whose URL is
+/// the first of `url_keys` they have.
+fn json_ld_images(value: &serde_json::Value, page_url: &Url, url_keys: &[&str]) -> Vec<OgImage> {
+    match value {
+        serde_json::Value::Array(items) => items
+            .iter()
+            .flat_map(|item| json_ld_images(item, page_url, url_keys))
+            .collect(),
+        serde_json::Value::Object(_) => url_keys
+            .iter()
+            .find_map(|key| json_ld_url(&value[key], page_url))
+            .map(|url| OgImage {
+                url: Some(url),
+                width: json_ld_size(&value["width"]),
+                height: json_ld_size(&value["height"]),
+                mime_type: json_ld_text(&value["encodingFormat"]),
+                alt: None,
+            })
+            .into_iter()
+            .collect(),
+        value => json_ld_url(value, page_url)
+            .map(|url| OgImage {
+                url: Some(url),
+                ..Default::default()
+            })
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// Seconds in an ISO 8601 duration like `PT1H2M3S`, as JSON-LD gives video
+/// lengths. Years, months and weeks have no fixed length and aren't read.
+fn parse_iso8601_duration(duration: &str) -> Option<u64> {
+    let mut rest = duration.trim().strip_prefix('P')?;
+    let mut seconds = 0.0;
+    let mut in_time = false;
+    while !rest.is_empty() {
+        if let Some(time) = rest.strip_prefix('T') {
+            in_time = true;
+            rest = time;
+            continue;
+        }
+        let end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let value: f64 = rest[..end].parse().ok()?;
+        let unit = match (rest[end..].chars().next()?, in_time) {
+            ('D', false) => 86400.0,
+            ('H', true) => 3600.0,
+            ('M', true) => 60.0,
+            ('S', true) => 1.0,
+            _ => return None,
+        };
+        seconds += value * unit;
+        rest = &rest[end + 1..];
+    }
+    Some(seconds.round() as u64)
+}
+
+/// `text` with each run of whitespace made a single space and trimmed, or
+/// `None` if that leaves nothing.
+fn collapse_whitespace(text: &str) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!text.is_empty()).then_some(text)
+}
+
+/// Scan `html`, the page at `page_url`, within `budget` bytes (see
+/// [`html_scan::scan`]), recording how long it took and how much it held.
+fn scan_html(html: &str, page_url: &Url, budget: usize) -> Scan {
+    let started = Instant::now();
+    let scan = html_scan::scan(html, budget);
+    if scan.truncated {
+        debug!(
+            "Only parsed the start of {}, the rest is over the parse memory budget",
+            page_url
+        );
+    }
+    metrics().record_html_parse(started.elapsed(), scan.estimated_peak_bytes, scan.truncated);
+    scan
+}
+
+/// The `meta` tags whose `property` or `name` starts with `prefix`, as the
+/// first of `attributes` they have and their content.
+fn meta_properties<'a>(
+    tags: &'a [Tag],
+    prefix: &'a str,
+    attributes: [&'static str; 2],
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    tags.iter()
+        .filter(move |tag| {
+            tag.name == "meta"
+                && attributes.iter().any(|attr| {
+                    tag.attr(attr)
+                        .is_some_and(|value| value.starts_with(prefix))
+                })
+        })
+        .filter_map(move |tag| {
+            let property = tag.attr(attributes[0]).or(tag.attr(attributes[1]))?;
+            Some((property, tag.attr("content")?))
+        })
+}
+
+/// The image to embed out of a page's `og:image`s: the largest one of a type
+/// we can post, or the first if none give their size.
+fn best_og_image(images: &[OgImage]) -> Option<&OgImage> {
+    images
+        .iter()
+        .filter(|image| image.url.is_some() && image.has_usable_type())
+        .min_by_key(|image| std::cmp::Reverse(image.area()))
+}
+
+/// Returns `true` if `error` is a refusal a bot wall might have sent.
+fn looks_blocked(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|e| e.downcast_ref::<reqwest::Error>()?.status())
+        .any(|status| BLOCKED_STATUSES.contains(&status.as_u16()))
+}
+
+/// The AMP version of the page at `page_url`, from its `rel=amphtml` link.
+fn parse_amp_url(tags: &[Tag], page_url: &Url) -> Option<Url> {
+    tags.iter()
+        .filter(|tag| tag.name == "link" && tag.has_rel("amphtml"))
+        .filter_map(|tag| tag.attr("href"))
+        .filter_map(|href| page_url.join(href.trim()).ok())
+        .find(|url| matches!(url.scheme(), "http" | "https") && url != page_url)
+}
+
+/// The versions of the page at `page_url` in other languages, from its
+/// `link rel="alternate" hreflang` tags, by language tag.
+fn parse_localized(tags: &[Tag], page_url: &Url) -> Vec<(String, Url)> {
+    tags.iter()
+        .filter(|tag| tag.name == "link" && tag.has_rel("alternate"))
+        .filter_map(|tag| {
+            let language = locale::normalize(tag.attr("hreflang")?)?;
+            let url = page_url.join(tag.attr("href")?.trim()).ok()?;
+            matches!(url.scheme(), "http" | "https").then_some((language, url))
+        })
+        .collect()
+}
+
+/// The same page on the site's `m.` subdomain, which many sites serve
+/// their mobile version on.
+fn mobile_variant(url: &Url) -> Option<Url> {
+    let Some(url::Host::Domain(host)) = url.host() else {
+        return None;
+    };
+    if host.starts_with("m.") || host.starts_with("mobile.") {
+        return None;
     }
+    let domain = host.strip_prefix("www.").unwrap_or(host);
+    let mut mobile = url.clone();
+    mobile.set_host(Some(&format!("m.{}", domain))).ok()?;
+    Some(mobile)
 }
 
 #[cfg(test)]
@@ -196,13 +1036,300 @@ mod tests {
     use std::fs;
     use std::path::PathBuf;
 
+    fn page_url() -> Url {
+        Url::parse("https://example.com/post?utm_source=feed").unwrap()
+    }
+
+    #[test]
+    fn test_parse_amp_url() {
+        let html = r#"<html><head>
+<link rel="amphtml" href="/post/amp">
+</head></html>"#;
+        let amp_url = |html| parse_amp_url(&html_scan::scan(html, usize::MAX).tags, &page_url());
+        assert_eq!(
+            amp_url(html).unwrap().as_str(),
+            "https://example.com/post/amp"
+        );
+        assert_eq!(amp_url("<html></html>"), None);
+    }
+
+    #[test]
+    fn test_mobile_variant() {
+        let mobile = |url: &str| mobile_variant(&Url::parse(url).unwrap()).map(String::from);
+        assert_eq!(
+            mobile("https://www.example.com/a?b=c").as_deref(),
+            Some("https://m.example.com/a?b=c")
+        );
+        assert_eq!(
+            mobile("https://example.com/a").as_deref(),
+            Some("https://m.example.com/a")
+        );
+        assert_eq!(mobile("https://m.example.com/a"), None);
+        assert_eq!(mobile("http://127.0.0.1:8080/a"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_amp_when_blocked() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let html = |body: &str| {
+            ResponseTemplate::new(200).set_body_raw(body.to_owned(), "text/html; charset=utf-8")
+        };
+        Mock::given(method("GET"))
+            .and(path("/post"))
+            .respond_with(html(
+                r#"<html><head><title>Just a moment...</title>
+<link rel="amphtml" href="/post/amp"></head></html>"#,
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/post/amp"))
+            .respond_with(html(
+                r#"<html><head><meta property="og:title" content="Post">
+<meta property="og:description" content="The whole story"></head></html>"#,
+            ))
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::new();
+        let url = Url::parse(&format!("{}/post", server.uri())).unwrap();
+        let ap_detector = ActivityPubDetector::new();
+
+        // Only listed domains are tried elsewhere.
+        let config = Config::default();
+        let meta = Metadata::fetch_from_url(&client, &url, &config, &ap_detector, None)
+            .await
+            .unwrap();
+        assert_eq!(meta.description, None);
+
+        let config = Config {
+            alternate_source_domains: vec!["127.0.0.1".to_string()],
+            ..Config::default()
+        };
+        let meta = Metadata::fetch_from_url(&client, &url, &config, &ap_detector, None)
+            .await
+            .unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Post"));
+        assert_eq!(meta.description.as_deref(), Some("The whole story"));
+    }
+
+    #[cfg(feature = "extractors")]
+    #[tokio::test]
+    async fn test_fetch_oembed() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/watch"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"<html><head>
+<meta property="og:title" content="OG title">
+<meta property="og:description" content="OG description">
+<link rel="alternate" type="application/json+oembed" href="/oembed?url=watch">
+</head></html>"#,
+                "text/html",
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/oembed"))
+            .and(query_param("url", "watch"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"{"type": "video", "title": "oEmbed title", "provider_name": "Example",
+                    "thumbnail_url": "/thumb.jpg",
+                    "html": "<iframe src=\"/embed/1\"></iframe>"}"#,
+                "application/json",
+            ))
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::new();
+        let url = Url::parse(&format!("{}/watch", server.uri())).unwrap();
+        let meta = Metadata::fetch_from_url(
+            &client,
+            &url,
+            &Config::default(),
+            &ActivityPubDetector::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(meta.title.as_deref(), Some("oEmbed title"));
+        assert_eq!(meta.site_name.as_deref(), Some("Example"));
+        assert_eq!(meta.description.as_deref(), Some("OG description"));
+        assert_eq!(
+            meta.image_url,
+            Some(Url::parse(&format!("{}/thumb.jpg", server.uri())).unwrap())
+        );
+        assert_eq!(
+            meta.player_url,
+            Some(Url::parse(&format!("{}/embed/1", server.uri())).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_locale() {
+        let html = r#"<html><head>
+<meta property="og:title" content="Title">
+<meta property="og:locale" content="en_GB">
+<meta property="og:locale:alternate" content="fr_FR">
+<meta property="og:locale:alternate" content="not a locale">
+<meta property="og:locale:alternate" content="de_DE">
+<link rel="alternate" hreflang="fr" href="/fr/post">
+<link rel="alternate" hreflang="x-default" href="/post">
+<link rel="alternate" hreflang="de-DE" href="https://de.example.com/post">
+</head></html>"#;
+        let scan = html_scan::scan(html, usize::MAX);
+        let meta = Metadata::parse_scan(&scan, &page_url(), true);
+        assert_eq!(meta.locale.as_deref(), Some("en_GB"));
+        assert_eq!(meta.alternate_locales, ["fr_FR", "de_DE"]);
+        assert_eq!(
+            parse_localized(&scan.tags, &page_url()),
+            [
+                (
+                    "fr".to_string(),
+                    Url::parse("https://example.com/fr/post").unwrap()
+                ),
+                (
+                    "de-de".to_string(),
+                    Url::parse("https://de.example.com/post").unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_localized() {
+        use wiremock::matchers::{header_regex, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let html = |body: &str| {
+            ResponseTemplate::new(200).set_body_raw(body.to_owned(), "text/html; charset=utf-8")
+        };
+        Mock::given(method("GET"))
+            .and(path("/post"))
+            .respond_with(html(
+                r#"<html><head>
+<meta property="og:title" content="The news">
+<meta property="og:image" content="https://example.com/a.jpg">
+<meta property="og:locale" content="en_US">
+<meta property="og:locale:alternate" content="de_DE">
+<link rel="alternate" hreflang="de" href="/de/post">
+<link rel="alternate" hreflang="ja" href="/ja/post">
+</head></html>"#,
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/de/post"))
+            .and(header_regex("accept-language", r"^de-AT, \*;q=0\.5$"))
+            .respond_with(html(
+                r#"<html><head>
+<meta property="og:title" content="Die Nachrichten">
+<meta property="og:description" content="Alles Neue">
+<meta property="og:locale" content="de_DE">
+</head></html>"#,
+            ))
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::new();
+        let url = Url::parse(&format!("{}/post", server.uri())).unwrap();
+        let config = Config::default();
+        let ap_detector = ActivityPubDetector::new();
+
+        let meta = Metadata::fetch_from_url(&client, &url, &config, &ap_detector, Some("de-AT"))
+            .await
+            .unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Die Nachrichten"));
+        assert_eq!(meta.description.as_deref(), Some("Alles Neue"));
+        assert_eq!(meta.locale.as_deref(), Some("de_DE"));
+        assert_eq!(
+            meta.image_url.as_ref().map(Url::as_str),
+            Some("https://example.com/a.jpg")
+        );
+        assert_eq!(meta.canonical_url, Some(url.clone()));
+
+        // Japanese is linked, but not listed as a translation.
+        for language in [None, Some("en"), Some("ja")] {
+            let meta = Metadata::fetch_from_url(&client, &url, &config, &ap_detector, language)
+                .await
+                .unwrap();
+            assert_eq!(meta.title.as_deref(), Some("The news"));
+        }
+    }
+
+    #[test]
+    fn test_parse_json_ld() {
+        let html = r#"<html><head>
+<meta property="og:title" content="OG title">
+<script type="application/ld+json">
+{"@context": "https://schema.org", "@graph": [
+    {"@type": "BreadcrumbList", "name": "Home"},
+    {"@type": ["NewsArticle"], "headline": "Headline", "description": "The whole story",
+     "datePublished": "2024-03-01T12:00:00Z",
+     "publisher": {"@type": "Organization", "name": "Example News"},
+     "image": [{"@type": "ImageObject", "url": "/small.jpg", "width": 400, "height": 300},
+               {"@type": "ImageObject", "url": "/large.jpg", "width": "1600", "height": {"value": 900}}]}
+]}
+</script>
+<script type="application/ld+json">not json</script>
+</head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        // The tags win over JSON-LD where there are both.
+        assert_eq!(metadata.title.as_deref(), Some("OG title"));
+        assert_eq!(metadata.description.as_deref(), Some("The whole story"));
+        assert_eq!(metadata.site_name.as_deref(), Some("Example News"));
+        assert_eq!(
+            metadata.image_url.as_ref().map(Url::as_str),
+            Some("https://example.com/large.jpg")
+        );
+        assert_eq!(
+            metadata.published.map(|p| p.to_rfc3339()).as_deref(),
+            Some("2024-03-01T12:00:00+00:00")
+        );
+
+        let html = r#"<script type="application/ld+json">
+{"@context": "https://schema.org", "@type": "http://schema.org/VideoObject",
+ "name": "A video", "contentUrl": "https://cdn.example.com/a.mp4",
+ "embedUrl": "https://example.com/embed/a", "thumbnailUrl": ["https://cdn.example.com/a.jpg"],
+ "duration": "PT1M33S", "uploadDate": "2024-03-01"}
+</script>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.title.as_deref(), Some("A video"));
+        assert_eq!(
+            metadata.video_url.as_ref().map(Url::as_str),
+            Some("https://cdn.example.com/a.mp4")
+        );
+        assert_eq!(
+            metadata.player_url.as_ref().map(Url::as_str),
+            Some("https://example.com/embed/a")
+        );
+        assert_eq!(
+            metadata.image_url.as_ref().map(Url::as_str),
+            Some("https://cdn.example.com/a.jpg")
+        );
+        assert_eq!(metadata.video_duration, Some(93));
+    }
+
+    #[test]
+    fn test_parse_iso8601_duration() {
+        assert_eq!(parse_iso8601_duration("PT1H2M3S"), Some(3723));
+        assert_eq!(parse_iso8601_duration("P1DT30M"), Some(88200));
+        assert_eq!(parse_iso8601_duration("PT2.6S"), Some(3));
+        assert_eq!(parse_iso8601_duration("P1M"), None);
+        assert_eq!(parse_iso8601_duration("1:33"), None);
+    }
+
     #[test]
     fn test_parse_metadata_with_difficult_og_tags() {
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         d.push("tests/data/tweet.html");
 
         let html_content = fs::read_to_string(d).expect("Failed to read test/data/tweet.html");
-        let metadata = Metadata::parse_from_html(&html_content);
+        let metadata = Metadata::parse_from_html(&html_content, &page_url());
 
         assert_eq!(metadata.title, Some("ebifurako (@_ebi_furako)".to_string()));
         assert_eq!(
@@ -214,4 +1341,323 @@ mod tests {
             Some(Url::parse("https://pbs.twimg.com/ext_tw_video_thumb/2021579491018170368/pu/img/iuleedOC8SZIFlOx.jpg").unwrap())
         );
     }
+
+    #[test]
+    fn test_parse_text_prefers_article() {
+        let html = r#"<html><body>
+            <nav><p>Menu</p></nav>
+            <article><h1>Title</h1><p>First  <b>para</b>.</p><p></p><p>Second para.</p></article>
+            <footer><p>Copyright</p></footer>
+        </body></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.text.as_deref(),
+            Some("First para.\n\nSecond para.")
+        );
+        // Text alone isn't something we can embed.
+        assert!(metadata.is_empty());
+    }
+
+    #[test]
+    fn test_parse_image_alt() {
+        let html = r#"<html><head>
+            <meta name="twitter:image:alt" content="Twitter alt">
+            <meta property="og:image" content="https://example.com/cat.jpg">
+            <meta property="og:image:alt" content="A cat asleep on a keyboard">
+        </head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.image_alt.as_deref(),
+            Some("A cat asleep on a keyboard")
+        );
+
+        let html =
+            r#"<html><head><meta name="twitter:image:alt" content="Twitter alt"></head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.image_alt.as_deref(), Some("Twitter alt"));
+    }
+
+    #[test]
+    fn test_parse_og_image_array() {
+        let html = r#"<html><head>
+            <meta property="og:image:alt" content="Orphaned alt">
+            <meta property="og:image" content="https://example.com/logo.svg">
+            <meta property="og:image:type" content="image/svg+xml">
+            <meta property="og:image:width" content="4000">
+            <meta property="og:image:height" content="4000">
+            <meta property="og:image" content="http://example.com/small.jpg">
+            <meta property="og:image:secure_url" content="https://example.com/small.jpg">
+            <meta property="og:image:width" content="400">
+            <meta property="og:image:height" content="300">
+            <meta property="og:image:alt" content="Small">
+            <meta property="og:image" content="https://example.com/large.jpg">
+            <meta property="og:image:type" content="image/jpeg">
+            <meta property="og:image:width" content="1200">
+            <meta property="og:image:height" content="630">
+            <meta property="og:image:alt" content="Large">
+            <meta property="og:image" content="https://example.com/unknown.jpg">
+        </head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.og_images.len(), 4);
+        assert_eq!(
+            metadata.og_images[1],
+            OgImage {
+                url: Some(Url::parse("https://example.com/small.jpg").unwrap()),
+                width: Some(400),
+                height: Some(300),
+                mime_type: None,
+                alt: Some("Small".to_string()),
+            }
+        );
+        assert_eq!(
+            metadata.image_url.unwrap().as_str(),
+            "https://example.com/large.jpg"
+        );
+        assert_eq!(metadata.image_alt.as_deref(), Some("Large"));
+
+        // Without sizes, the first image wins.
+        let html = r#"<html><head>
+            <meta property="og:image" content="https://example.com/first.jpg">
+            <meta property="og:image" content="https://example.com/second.jpg">
+        </head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.image_url.unwrap().as_str(),
+            "https://example.com/first.jpg"
+        );
+        assert_eq!(metadata.image_alt, None);
+    }
+
+    #[test]
+    fn test_parse_relative_urls() {
+        let html = r#"<html><head>
+            <meta property="og:image" content="/images/cover.jpg">
+            <meta property="og:video" content="media/clip.mp4">
+            <meta property="og:audio" content="//cdn.example.net/track.mp3">
+            <meta name="twitter:player" content=" /embed/1 ">
+        </head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.image_url.as_ref().map(Url::as_str),
+            Some("https://example.com/images/cover.jpg")
+        );
+        assert_eq!(
+            metadata.video_url.as_ref().map(Url::as_str),
+            Some("https://example.com/media/clip.mp4")
+        );
+        assert_eq!(
+            metadata.audio_url.as_ref().map(Url::as_str),
+            Some("https://cdn.example.net/track.mp3")
+        );
+        assert_eq!(
+            metadata.player_url.as_ref().map(Url::as_str),
+            Some("https://example.com/embed/1")
+        );
+    }
+
+    #[test]
+    fn test_parse_published() {
+        let html = r#"<html><head>
+            <meta property="article:published_time" content="2025-01-15T12:00:00+01:00">
+        </head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.published,
+            DateTime::parse_from_rfc3339("2025-01-15T11:00:00Z").ok()
+        );
+    }
+
+    #[test]
+    fn test_parse_twitter_player() {
+        let html = r#"<html><head>
+            <meta name="twitter:card" content="player">
+            <meta name="twitter:player" content="https://video.example.com/embed/123">
+        </head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.player_url.unwrap().as_str(),
+            "https://video.example.com/embed/123"
+        );
+        assert!(metadata.video_url.is_none());
+
+        let html =
+            r#"<meta name="twitter:player:stream" content="https://video.example.com/123.mp4">"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.video_url.unwrap().as_str(),
+            "https://video.example.com/123.mp4"
+        );
+    }
+
+    #[test]
+    fn test_parse_player_media() {
+        let player = Url::parse("https://video.example.com/embed/123").unwrap();
+        let html = r#"<html><body>
+            <video controls><source src="/media/123.mp4" type="video/mp4"></video>
+        </body></html>"#;
+        assert_eq!(
+            Metadata::parse_player_media(html, &player, usize::MAX)
+                .unwrap()
+                .as_str(),
+            "https://video.example.com/media/123.mp4"
+        );
+
+        let html = r#"<html><head>
+            <meta property="og:video" content="https://cdn.example.com/123.webm">
+        </head><body><video src="blob:whatever"></video></body></html>"#;
+        assert_eq!(
+            Metadata::parse_player_media(html, &player, usize::MAX)
+                .unwrap()
+                .as_str(),
+            "https://cdn.example.com/123.webm"
+        );
+
+        let html = r#"<video src="data:video/mp4;base64,AAAA"></video>"#;
+        assert_eq!(
+            Metadata::parse_player_media(html, &player, usize::MAX),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_alternate_video() {
+        let html = r#"<html><head>
+            <meta property="og:video" content="https://example.com/embed.html">
+            <meta property="og:video:secure_url" content="https://example.com/embed.html">
+            <meta property="og:video:url" content="https://cdn.example.com/clip.mp4">
+        </head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.video_url.unwrap().as_str(),
+            "https://example.com/embed.html"
+        );
+        assert_eq!(
+            metadata.alternate_video_url.unwrap().as_str(),
+            "https://cdn.example.com/clip.mp4"
+        );
+
+        let html = r#"<meta property="og:video" content="https://example.com/clip.mp4">"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert!(metadata.alternate_video_url.is_none());
+    }
+
+    #[test]
+    fn test_description_falls_back_to_lead() {
+        let lead = "A post without any OpenGraph description, but with plenty of body text.";
+        let html = format!(
+            r#"<html><head><meta property="og:title" content="Post"></head>
+            <body><article><p>{lead}</p></article></body></html>"#
+        );
+        let metadata = Metadata::parse_from_html(&html, &page_url());
+        assert_eq!(metadata.description.as_deref(), Some(lead));
+
+        let html = format!(
+            r#"<html><head><meta property="og:description" content="Explicit"></head>
+            <body><article><p>{lead}</p></article></body></html>"#
+        );
+        let metadata = Metadata::parse_from_html(&html, &page_url());
+        assert_eq!(metadata.description.as_deref(), Some("Explicit"));
+    }
+
+    #[test]
+    fn test_parse_html_fallback() {
+        let html = r#"<html><head>
+            <title>
+                A plain   page
+            </title>
+            <meta name="Description" content="  What it's about ">
+            </head><body><svg><title>Icon</title></svg></body></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.title.as_deref(), Some("A plain page"));
+        assert_eq!(metadata.description.as_deref(), Some("What it's about"));
+
+        let scan = html_scan::scan(html, usize::MAX);
+        let metadata = Metadata::parse_scan(&scan, &page_url(), false);
+        assert!(metadata.is_empty());
+
+        // Metadata tags take precedence.
+        let html = r#"<html><head><title>Page | Site</title>
+            <meta name="description" content="Plain">
+            <meta property="og:title" content="Page">
+            <meta name="twitter:description" content="Card"></head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.title.as_deref(), Some("Page"));
+        assert_eq!(metadata.description.as_deref(), Some("Card"));
+    }
+
+    #[test]
+    fn test_parse_site_name() {
+        let html =
+            r#"<html><head><meta property="og:site_name" content=" Example News "></head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.site_name.as_deref(), Some("Example News"));
+
+        let html = r#"<html><head><meta property="og:site_name" content=""></head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.site_name, None);
+    }
+
+    #[test]
+    fn test_parse_canonical_url() {
+        let html = r#"<html><head><link rel="canonical" href="/post"></head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.canonical_url.unwrap().as_str(),
+            "https://example.com/post"
+        );
+
+        let html = r#"<html><head><link rel="canonical" href="javascript:void(0)"></head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.canonical_url, Some(page_url()));
+        assert_eq!(metadata.content_url, None);
+    }
+
+    #[test]
+    fn test_parse_content_url() {
+        let html = r#"<html><head>
+            <link rel="canonical" href="/post">
+            <meta property="og:url" content="https://news.example.org/story">
+        </head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.content_url.unwrap().as_str(),
+            "https://news.example.org/story"
+        );
+
+        let html = r#"<html><head><link rel="canonical" href="/post"></head></html>"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(
+            metadata.content_url.unwrap().as_str(),
+            "https://example.com/post"
+        );
+
+        let html = r#"<meta property="og:url" content="https://example.com/post?utm_source=feed">"#;
+        let metadata = Metadata::parse_from_html(html, &page_url());
+        assert_eq!(metadata.content_url, None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let shortener = Metadata {
+            title: Some("Short link".to_string()),
+            card: Some("summary".to_string()),
+            ..Default::default()
+        };
+        assert!(shortener.is_weak());
+
+        let target = Metadata {
+            title: Some("Story".to_string()),
+            description: Some("What happened".to_string()),
+            video_url: Some(Url::parse("https://example.org/clip.mp4").unwrap()),
+            video_duration: Some(30),
+            ..Default::default()
+        };
+        let merged = target.clone().merge(shortener);
+        assert_eq!(merged.title.as_deref(), Some("Story"));
+        // The shortener's card type would hide the video.
+        assert_eq!(merged.card, None);
+        assert_eq!(merged.video_url, target.video_url);
+        assert_eq!(merged.video_duration, Some(30));
+        assert!(!merged.is_weak());
+    }
 }
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::metadata::Metadata;
+
+/// Most pages kept at once. Past this, the entry closest to expiring makes
+/// room for a new one.
+const MAX_ENTRIES: usize = 1000;
+
+struct Entry {
+    meta: Metadata,
+    expires_at: Instant,
+}
+
+/// Page metadata by the URL it was fetched from and the language it was
+/// asked for in, reused until its TTL (which depends on the domain, see
+/// [`crate::config::Config::cache_ttl`]) runs out, so a link posted in
+/// several rooms is only fetched once per language.
+///
+/// This is where the default [`crate::store::SharedStore`] keeps metadata;
+/// it only needs to outlive a fetch, not a restart.
+#[derive(Default)]
+pub struct MetadataCache {
+    entries: Mutex<HashMap<(Url, Option<String>), Entry>>,
+}
+
+impl MetadataCache {
+    /// The metadata fetched from `url` in `language`, if it hasn't expired.
+    pub fn get(&self, url: &Url, language: Option<&str>) -> Option<Metadata> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&(url.clone(), language.map(str::to_string)))?;
+        (entry.expires_at > Instant::now()).then(|| entry.meta.clone())
+    }
+
+    /// Keep `meta`, fetched from `url` in `language`, for `ttl`. Nothing is
+    /// kept with a zero TTL.
+    pub fn insert(&self, url: &Url, language: Option<&str>, meta: &Metadata, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires_at > now);
+        if entries.len() >= MAX_ENTRIES
+            && let Some(soonest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone())
+        {
+            entries.remove(&soonest);
+        }
+        entries.insert(
+            (url.clone(), language.map(str::to_string)),
+            Entry {
+                meta: meta.clone(),
+                expires_at: now + ttl,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_cache() {
+        let cache = MetadataCache::default();
+        let url = Url::parse("https://example.com/article").unwrap();
+        let meta = Metadata {
+            title: Some("Title".to_string()),
+            ..Default::default()
+        };
+
+        cache.insert(&url, None, &meta, Duration::ZERO);
+        assert_eq!(cache.get(&url, None), None);
+
+        cache.insert(&url, None, &meta, Duration::from_secs(60));
+        assert_eq!(cache.get(&url, None), Some(meta.clone()));
+        assert_eq!(cache.get(&url, Some("de")), None);
+
+        cache.insert(&url, None, &meta, Duration::from_nanos(1));
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(cache.get(&url, None), None);
+    }
+}
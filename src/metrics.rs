@@ -0,0 +1,838 @@
+// Without the `metrics` feature, metrics are still counted, since that
+// happens all over and is cheap, but never rendered.
+#![cfg_attr(not(feature = "metrics"), allow(dead_code))]
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use mime_guess::Mime;
+
+use crate::error::EmbedError;
+
+/// Upper bounds (in bytes per second) of the download throughput buckets.
+const THROUGHPUT_BUCKETS: &[f64] = &[
+    16_384.0,
+    65_536.0,
+    262_144.0,
+    1_048_576.0,
+    4_194_304.0,
+    16_777_216.0,
+    67_108_864.0,
+];
+
+/// Upper bounds (in seconds) of the buckets for how long messages took to
+/// become decryptable.
+const LATE_DECRYPTION_BUCKETS: &[f64] = &[2.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Upper bounds (in seconds) of the buckets for how long embeds and their
+/// steps took.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Upper bounds (in seconds) of the buckets for how long parsing a page
+/// took.
+const PARSE_DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Upper bounds (in bytes) of the buckets for the most memory parsing a page
+/// held at once.
+const PARSE_MEMORY_BUCKETS: &[f64] = &[
+    16_384.0,
+    65_536.0,
+    262_144.0,
+    1_048_576.0,
+    4_194_304.0,
+    16_777_216.0,
+];
+
+/// The media type label of latencies that don't involve media, like
+/// fetching metadata or posting a text-only embed.
+pub const NO_MEDIA: &str = "none";
+
+tokio::task_local! {
+    static EMBED: EmbedLabels;
+}
+
+/// Labels for the latencies recorded while embedding a link.
+struct EmbedLabels {
+    domain_class: String,
+    media: Mutex<EmbedMedia>,
+}
+
+/// The media an embed posted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EmbedMedia {
+    /// The type of its first media, if it had any.
+    pub media_type: Option<&'static str>,
+    /// Size of all its media together.
+    pub bytes: u64,
+}
+
+impl EmbedMedia {
+    /// The media type label of the embed.
+    pub fn label(&self) -> &'static str {
+        self.media_type.unwrap_or(NO_MEDIA)
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Process-wide metrics, served in the Prometheus text format on the health
+/// endpoint's `/metrics` path.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// How a media download ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadOutcome {
+    Completed,
+    /// The server said the cached copy is still current.
+    Cached,
+    TooLarge,
+    TooSlow,
+    Failed,
+}
+
+impl DownloadOutcome {
+    const ALL: [DownloadOutcome; 5] = [
+        DownloadOutcome::Completed,
+        DownloadOutcome::Cached,
+        DownloadOutcome::TooLarge,
+        DownloadOutcome::TooSlow,
+        DownloadOutcome::Failed,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            DownloadOutcome::Completed => "completed",
+            DownloadOutcome::Cached => "cached",
+            DownloadOutcome::TooLarge => "too_large",
+            DownloadOutcome::TooSlow => "too_slow",
+            DownloadOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// What became of a message that couldn't be decrypted on arrival.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UtdOutcome {
+    /// Its key arrived in time and it was handled.
+    Decrypted,
+    /// It still couldn't be decrypted when the retry window ran out.
+    Expired,
+    /// Retrying is disabled.
+    NotRetried,
+}
+
+impl UtdOutcome {
+    const ALL: [UtdOutcome; 3] = [
+        UtdOutcome::Decrypted,
+        UtdOutcome::Expired,
+        UtdOutcome::NotRetried,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            UtdOutcome::Decrypted => "decrypted",
+            UtdOutcome::Expired => "expired",
+            UtdOutcome::NotRetried => "not_retried",
+        }
+    }
+}
+
+/// Why a child process like ffmpeg was killed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Killed {
+    TimedOut,
+    /// Nothing was waiting for it anymore, e.g. because its job was
+    /// cancelled.
+    Cancelled,
+}
+
+impl Killed {
+    const ALL: [Killed; 2] = [Killed::TimedOut, Killed::Cancelled];
+
+    fn label(self) -> &'static str {
+        match self {
+            Killed::TimedOut => "timed_out",
+            Killed::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Entries removed by the maintenance task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pruned {
+    EmbedHistory,
+    Summaries,
+    Uploads,
+    PendingGalleries,
+    LinkVerdicts,
+    Detections,
+    TempFiles,
+    Media,
+}
+
+impl Pruned {
+    const ALL: [Pruned; 8] = [
+        Pruned::EmbedHistory,
+        Pruned::Summaries,
+        Pruned::Uploads,
+        Pruned::PendingGalleries,
+        Pruned::LinkVerdicts,
+        Pruned::Detections,
+        Pruned::TempFiles,
+        Pruned::Media,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Pruned::EmbedHistory => "embed_history",
+            Pruned::Summaries => "summaries",
+            Pruned::Uploads => "uploads",
+            Pruned::PendingGalleries => "pending_galleries",
+            Pruned::LinkVerdicts => "link_verdicts",
+            Pruned::Detections => "detections",
+            Pruned::TempFiles => "temp_files",
+            Pruned::Media => "media",
+        }
+    }
+}
+
+/// Where the maintenance task freed disk space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reclaimed {
+    Database,
+    TempFiles,
+}
+
+impl Reclaimed {
+    const ALL: [Reclaimed; 2] = [Reclaimed::Database, Reclaimed::TempFiles];
+
+    fn label(self) -> &'static str {
+        match self {
+            Reclaimed::Database => "database",
+            Reclaimed::TempFiles => "temp_files",
+        }
+    }
+}
+
+/// A step of embedding a link, timed for latency metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Step {
+    /// Fetching the page and reading its metadata.
+    Metadata,
+    Download,
+    /// Reading the dimensions of media with ffprobe.
+    Probe,
+    Thumbnail,
+    /// Remuxing or reencoding video.
+    Transcode,
+    /// Uploading media to the homeserver and posting it.
+    Upload,
+}
+
+impl Step {
+    fn label(self) -> &'static str {
+        match self {
+            Step::Metadata => "metadata",
+            Step::Download => "download",
+            Step::Probe => "probe",
+            Step::Thumbnail => "thumbnail",
+            Step::Transcode => "transcode",
+            Step::Upload => "upload",
+        }
+    }
+}
+
+/// The media type label for `mime_type`: its top-level type if it's media,
+/// otherwise "other".
+pub fn media_label(mime_type: &Mime) -> &'static str {
+    match mime_type.type_() {
+        mime_guess::mime::IMAGE => "image",
+        mime_guess::mime::VIDEO => "video",
+        mime_guess::mime::AUDIO => "audio",
+        _ => "other",
+    }
+}
+
+/// Run `embed`, which embeds a link to a site of `domain_class`, labelling
+/// the latencies of the steps it takes with that class. Also returns the
+/// media it noted with [`note_embed_media`].
+pub async fn scope_embed<F: Future>(domain_class: String, embed: F) -> (F::Output, EmbedMedia) {
+    let labels = EmbedLabels {
+        domain_class,
+        media: Mutex::new(EmbedMedia::default()),
+    };
+    EMBED
+        .scope(labels, async {
+            let output = embed.await;
+            let media = EMBED.with(|labels| *labels.media.lock().unwrap());
+            (output, media)
+        })
+        .await
+}
+
+/// Note that the embed being run posted `bytes` of media of `mime_type`.
+/// Only the first media sets its type, so images in a thread under it
+/// don't.
+pub fn note_embed_media(mime_type: &Mime, bytes: usize) {
+    let _ = EMBED.try_with(|labels| {
+        let mut media = labels.media.lock().unwrap();
+        media.media_type.get_or_insert(media_label(mime_type));
+        media.bytes += bytes as u64;
+    });
+}
+
+/// Escape `value` for use as a label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Non-cumulative count per bucket, plus one for `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// Write the histogram's series, with `labels` (like `a="b",c="d"`) if
+    /// there are any.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let (prefix, suffix) = if labels.is_empty() {
+            (String::new(), String::new())
+        } else {
+            (format!("{},", labels), format!("{{{}}}", labels))
+        };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, prefix, bound, cumulative
+            );
+        }
+        cumulative += self.counts[self.bounds.len()];
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, prefix, cumulative
+        );
+        let _ = writeln!(out, "{}_sum{} {}", name, suffix, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, suffix, cumulative);
+    }
+}
+
+#[derive(Debug)]
+struct DownloadMetrics {
+    outcomes: [u64; DownloadOutcome::ALL.len()],
+    bytes: u64,
+    throughput: Histogram,
+}
+
+impl Default for DownloadMetrics {
+    fn default() -> Self {
+        Self {
+            outcomes: [0; DownloadOutcome::ALL.len()],
+            bytes: 0,
+            throughput: Histogram::new(THROUGHPUT_BUCKETS),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct UtdMetrics {
+    seen: u64,
+    outcomes: [u64; UtdOutcome::ALL.len()],
+    /// Seconds until late decryption, for messages that were decrypted.
+    delay: Histogram,
+}
+
+impl Default for UtdMetrics {
+    fn default() -> Self {
+        Self {
+            seen: 0,
+            outcomes: [0; UtdOutcome::ALL.len()],
+            delay: Histogram::new(LATE_DECRYPTION_BUCKETS),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LatencyMetrics {
+    /// By step, domain class and media type.
+    steps: BTreeMap<(Step, String, &'static str), Histogram>,
+    /// From receiving a message to posting its embed, by domain class and
+    /// media type.
+    embeds: BTreeMap<(String, &'static str), Histogram>,
+}
+
+#[derive(Debug)]
+struct ParseMetrics {
+    duration: Histogram,
+    /// Estimated, see [`crate::html_scan::Scan::estimated_peak_bytes`].
+    estimated_peak_bytes: Histogram,
+    /// Pages cut short by the parse memory budget.
+    truncated: u64,
+}
+
+impl Default for ParseMetrics {
+    fn default() -> Self {
+        Self {
+            duration: Histogram::new(PARSE_DURATION_BUCKETS),
+            estimated_peak_bytes: Histogram::new(PARSE_MEMORY_BUCKETS),
+            truncated: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MaintenanceMetrics {
+    runs: u64,
+    pruned: [u64; Pruned::ALL.len()],
+    reclaimed_bytes: [u64; Reclaimed::ALL.len()],
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    failures: Mutex<[u64; EmbedError::ALL.len()]>,
+    killed_children: Mutex<[u64; Killed::ALL.len()]>,
+    downloads: Mutex<DownloadMetrics>,
+    utds: Mutex<UtdMetrics>,
+    latencies: Mutex<LatencyMetrics>,
+    parses: Mutex<ParseMetrics>,
+    maintenance: Mutex<MaintenanceMetrics>,
+}
+
+impl Metrics {
+    /// Count an embed, or part of one, that failed with `kind`.
+    pub fn record_failure(&self, kind: EmbedError) {
+        let index = EmbedError::ALL.iter().position(|&k| k == kind).unwrap();
+        self.failures.lock().unwrap()[index] += 1;
+    }
+
+    /// Count a child process that was killed.
+    pub fn record_killed_child(&self, reason: Killed) {
+        let index = Killed::ALL.iter().position(|&r| r == reason).unwrap();
+        self.killed_children.lock().unwrap()[index] += 1;
+    }
+
+    /// Record a finished (or abandoned) media download of `bytes` bytes.
+    pub fn record_download(&self, outcome: DownloadOutcome, bytes: u64, elapsed: Duration) {
+        let mut downloads = self.downloads.lock().unwrap();
+        let index = DownloadOutcome::ALL
+            .iter()
+            .position(|&o| o == outcome)
+            .unwrap();
+        downloads.outcomes[index] += 1;
+        downloads.bytes += bytes;
+        if outcome == DownloadOutcome::Completed && !elapsed.is_zero() {
+            downloads
+                .throughput
+                .observe(bytes as f64 / elapsed.as_secs_f64());
+        }
+    }
+
+    /// Count a message that couldn't be decrypted on arrival.
+    pub fn record_utd(&self) {
+        self.utds.lock().unwrap().seen += 1;
+    }
+
+    /// Record what became of a message that couldn't be decrypted on
+    /// arrival, `elapsed` after it arrived.
+    pub fn record_utd_outcome(&self, outcome: UtdOutcome, elapsed: Duration) {
+        let mut utds = self.utds.lock().unwrap();
+        let index = UtdOutcome::ALL.iter().position(|&o| o == outcome).unwrap();
+        utds.outcomes[index] += 1;
+        if outcome == UtdOutcome::Decrypted {
+            utds.delay.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// Record how long `step` took, for media of `media_type` (see
+    /// [`media_label`]). It's labelled with the domain class of the embed
+    /// being run, if any.
+    pub fn record_step(&self, step: Step, media_type: &'static str, elapsed: Duration) {
+        let domain_class = EMBED
+            .try_with(|labels| labels.domain_class.clone())
+            .unwrap_or_else(|_| "other".to_string());
+        self.latencies
+            .lock()
+            .unwrap()
+            .steps
+            .entry((step, domain_class, media_type))
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Record how long it took from receiving a message to posting the
+    /// embed of its link.
+    pub fn record_embed_latency(
+        &self,
+        domain_class: &str,
+        media_type: &'static str,
+        elapsed: Duration,
+    ) {
+        self.latencies
+            .lock()
+            .unwrap()
+            .embeds
+            .entry((domain_class.to_string(), media_type))
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Record how long parsing an HTML page took and the most memory it held
+    /// at once, and whether the page was cut short by the budget.
+    pub fn record_html_parse(
+        &self,
+        elapsed: Duration,
+        estimated_peak_bytes: usize,
+        truncated: bool,
+    ) {
+        let mut parses = self.parses.lock().unwrap();
+        parses.duration.observe(elapsed.as_secs_f64());
+        parses
+            .estimated_peak_bytes
+            .observe(estimated_peak_bytes as f64);
+        if truncated {
+            parses.truncated += 1;
+        }
+    }
+
+    /// Count a finished run of the maintenance task.
+    pub fn record_maintenance_run(&self) {
+        self.maintenance.lock().unwrap().runs += 1;
+    }
+
+    /// Record `count` entries removed by the maintenance task.
+    pub fn record_pruned(&self, kind: Pruned, count: usize) {
+        let index = Pruned::ALL.iter().position(|&k| k == kind).unwrap();
+        self.maintenance.lock().unwrap().pruned[index] += count as u64;
+    }
+
+    /// Record `bytes` of disk space freed by the maintenance task.
+    pub fn record_reclaimed(&self, kind: Reclaimed, bytes: u64) {
+        let index = Reclaimed::ALL.iter().position(|&k| k == kind).unwrap();
+        self.maintenance.lock().unwrap().reclaimed_bytes[index] += bytes;
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    #[cfg(feature = "metrics")]
+    pub fn render(&self) -> String {
+        let failures = self.failures.lock().unwrap();
+        let killed_children = self.killed_children.lock().unwrap();
+        let downloads = self.downloads.lock().unwrap();
+        let utds = self.utds.lock().unwrap();
+        let latencies = self.latencies.lock().unwrap();
+        let parses = self.parses.lock().unwrap();
+        let maintenance = self.maintenance.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP embed_failures_total Failed embeds and media by kind of error.\n");
+        out.push_str("# TYPE embed_failures_total counter\n");
+        for (kind, count) in EmbedError::ALL.iter().zip(*failures) {
+            let _ = writeln!(
+                out,
+                "embed_failures_total{{kind=\"{}\"}} {}",
+                kind.label(),
+                count
+            );
+        }
+
+        out.push_str(
+            "# HELP embed_killed_children_total Child processes like ffmpeg that were killed, by why.\n",
+        );
+        out.push_str("# TYPE embed_killed_children_total counter\n");
+        for (reason, count) in Killed::ALL.iter().zip(*killed_children) {
+            let _ = writeln!(
+                out,
+                "embed_killed_children_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                count
+            );
+        }
+
+        out.push_str("# HELP embed_downloads_total Media downloads by outcome.\n");
+        out.push_str("# TYPE embed_downloads_total counter\n");
+        for (outcome, count) in DownloadOutcome::ALL.iter().zip(downloads.outcomes) {
+            let _ = writeln!(
+                out,
+                "embed_downloads_total{{outcome=\"{}\"}} {}",
+                outcome.label(),
+                count
+            );
+        }
+
+        out.push_str("# HELP embed_download_bytes_total Bytes received for media downloads.\n");
+        out.push_str("# TYPE embed_download_bytes_total counter\n");
+        let _ = writeln!(out, "embed_download_bytes_total {}", downloads.bytes);
+
+        out.push_str(
+            "# HELP embed_download_throughput_bytes_per_second Average speed of completed media downloads.\n",
+        );
+        out.push_str("# TYPE embed_download_throughput_bytes_per_second histogram\n");
+        downloads
+            .throughput
+            .render(&mut out, "embed_download_throughput_bytes_per_second", "");
+
+        out.push_str("# HELP embed_utd_total Messages that couldn't be decrypted on arrival.\n");
+        out.push_str("# TYPE embed_utd_total counter\n");
+        let _ = writeln!(out, "embed_utd_total {}", utds.seen);
+
+        out.push_str(
+            "# HELP embed_utd_outcomes_total Messages that couldn't be decrypted on arrival, by what became of them.\n",
+        );
+        out.push_str("# TYPE embed_utd_outcomes_total counter\n");
+        for (outcome, count) in UtdOutcome::ALL.iter().zip(utds.outcomes) {
+            let _ = writeln!(
+                out,
+                "embed_utd_outcomes_total{{outcome=\"{}\"}} {}",
+                outcome.label(),
+                count
+            );
+        }
+
+        out.push_str(
+            "# HELP embed_late_decryption_seconds How long messages took to become decryptable.\n",
+        );
+        out.push_str("# TYPE embed_late_decryption_seconds histogram\n");
+        utds.delay
+            .render(&mut out, "embed_late_decryption_seconds", "");
+
+        out.push_str(
+            "# HELP embed_step_duration_seconds How long each step of embedding a link took.\n",
+        );
+        out.push_str("# TYPE embed_step_duration_seconds histogram\n");
+        for ((step, domain_class, media_type), histogram) in &latencies.steps {
+            let labels = format!(
+                "step=\"{}\",domain_class=\"{}\",media_type=\"{}\"",
+                step.label(),
+                escape_label(domain_class),
+                media_type
+            );
+            histogram.render(&mut out, "embed_step_duration_seconds", &labels);
+        }
+
+        out.push_str(
+            "# HELP embed_latency_seconds How long it took from receiving a message to posting the embed of its link.\n",
+        );
+        out.push_str("# TYPE embed_latency_seconds histogram\n");
+        for ((domain_class, media_type), histogram) in &latencies.embeds {
+            let labels = format!(
+                "domain_class=\"{}\",media_type=\"{}\"",
+                escape_label(domain_class),
+                media_type
+            );
+            histogram.render(&mut out, "embed_latency_seconds", &labels);
+        }
+
+        out.push_str("# HELP embed_html_parse_seconds How long parsing fetched HTML pages took.\n");
+        out.push_str("# TYPE embed_html_parse_seconds histogram\n");
+        parses
+            .duration
+            .render(&mut out, "embed_html_parse_seconds", "");
+
+        out.push_str(
+            "# HELP embed_html_parse_estimated_peak_bytes Estimate of the most memory parsing a fetched HTML page held at once, besides the page itself.\n",
+        );
+        out.push_str("# TYPE embed_html_parse_estimated_peak_bytes histogram\n");
+        parses
+            .estimated_peak_bytes
+            .render(&mut out, "embed_html_parse_estimated_peak_bytes", "");
+
+        out.push_str(
+            "# HELP embed_html_parse_truncated_total HTML pages whose end was skipped for going over the parse memory budget.\n",
+        );
+        out.push_str("# TYPE embed_html_parse_truncated_total counter\n");
+        let _ = writeln!(out, "embed_html_parse_truncated_total {}", parses.truncated);
+
+        out.push_str(
+            "# HELP embed_maintenance_runs_total Finished runs of the maintenance task.\n",
+        );
+        out.push_str("# TYPE embed_maintenance_runs_total counter\n");
+        let _ = writeln!(out, "embed_maintenance_runs_total {}", maintenance.runs);
+
+        out.push_str(
+            "# HELP embed_maintenance_pruned_total Entries removed by the maintenance task.\n",
+        );
+        out.push_str("# TYPE embed_maintenance_pruned_total counter\n");
+        for (kind, count) in Pruned::ALL.iter().zip(maintenance.pruned) {
+            let _ = writeln!(
+                out,
+                "embed_maintenance_pruned_total{{kind=\"{}\"}} {}",
+                kind.label(),
+                count
+            );
+        }
+
+        out.push_str(
+            "# HELP embed_maintenance_reclaimed_bytes_total Disk space freed by the maintenance task.\n",
+        );
+        out.push_str("# TYPE embed_maintenance_reclaimed_bytes_total counter\n");
+        for (kind, bytes) in Reclaimed::ALL.iter().zip(maintenance.reclaimed_bytes) {
+            let _ = writeln!(
+                out,
+                "embed_maintenance_reclaimed_bytes_total{{kind=\"{}\"}} {}",
+                kind.label(),
+                bytes
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_failures() {
+        let metrics = Metrics::default();
+        metrics.record_failure(EmbedError::FfmpegFailed);
+        metrics.record_failure(EmbedError::FfmpegFailed);
+        metrics.record_failure(EmbedError::RateLimited);
+        metrics.record_killed_child(Killed::TimedOut);
+
+        let out = metrics.render();
+        assert!(out.contains("embed_failures_total{kind=\"ffmpeg_failed\"} 2\n"));
+        assert!(out.contains("embed_failures_total{kind=\"rate_limited\"} 1\n"));
+        assert!(out.contains("embed_failures_total{kind=\"too_large\"} 0\n"));
+        assert!(out.contains("embed_killed_children_total{reason=\"timed_out\"} 1\n"));
+        assert!(out.contains("embed_killed_children_total{reason=\"cancelled\"} 0\n"));
+    }
+
+    #[test]
+    fn test_render_downloads() {
+        let metrics = Metrics::default();
+        metrics.record_download(
+            DownloadOutcome::Completed,
+            1_000_000,
+            Duration::from_secs(2),
+        );
+        metrics.record_download(DownloadOutcome::TooSlow, 5_000, Duration::from_secs(10));
+
+        let out = metrics.render();
+        assert!(out.contains("embed_downloads_total{outcome=\"completed\"} 1\n"));
+        assert!(out.contains("embed_downloads_total{outcome=\"too_slow\"} 1\n"));
+        assert!(out.contains("embed_download_bytes_total 1005000\n"));
+        // 500 kB/s lands in the 1 MiB/s bucket.
+        assert!(out.contains("_bucket{le=\"262144\"} 0\n"));
+        assert!(out.contains("_bucket{le=\"1048576\"} 1\n"));
+        assert!(out.contains("_bucket{le=\"+Inf\"} 1\n"));
+        assert!(out.contains("embed_download_throughput_bytes_per_second_count 1\n"));
+    }
+
+    #[test]
+    fn test_render_utds() {
+        let metrics = Metrics::default();
+        metrics.record_utd();
+        metrics.record_utd();
+        metrics.record_utd_outcome(UtdOutcome::Decrypted, Duration::from_secs(4));
+        metrics.record_utd_outcome(UtdOutcome::Expired, Duration::from_secs(120));
+
+        let out = metrics.render();
+        assert!(out.contains("embed_utd_total 2\n"));
+        assert!(out.contains("embed_utd_outcomes_total{outcome=\"decrypted\"} 1\n"));
+        assert!(out.contains("embed_utd_outcomes_total{outcome=\"expired\"} 1\n"));
+        assert!(out.contains("embed_late_decryption_seconds_bucket{le=\"2\"} 0\n"));
+        assert!(out.contains("embed_late_decryption_seconds_bucket{le=\"5\"} 1\n"));
+        assert!(out.contains("embed_late_decryption_seconds_count 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_latencies() {
+        let metrics = Metrics::default();
+        // Outside of an embed, like for the check command.
+        metrics.record_step(Step::Metadata, NO_MEDIA, Duration::from_millis(300));
+
+        let video: Mime = "video/mp4".parse().unwrap();
+        let ((), media) = scope_embed("video".to_string(), async {
+            metrics.record_step(Step::Download, media_label(&video), Duration::from_secs(3));
+            note_embed_media(&video, 1000);
+            note_embed_media(&"image/png".parse().unwrap(), 24);
+        })
+        .await;
+        assert_eq!(media.label(), "video");
+        assert_eq!(media.bytes, 1024);
+        metrics.record_embed_latency("video", media.label(), Duration::from_secs(4));
+
+        let out = metrics.render();
+        assert!(out.contains(
+            "embed_step_duration_seconds_bucket{step=\"metadata\",domain_class=\"other\",media_type=\"none\",le=\"0.5\"} 1\n"
+        ));
+        assert!(out.contains(
+            "embed_step_duration_seconds_bucket{step=\"download\",domain_class=\"video\",media_type=\"video\",le=\"2.5\"} 0\n"
+        ));
+        assert!(out.contains(
+            "embed_step_duration_seconds_count{step=\"download\",domain_class=\"video\",media_type=\"video\"} 1\n"
+        ));
+        assert!(out.contains(
+            "embed_latency_seconds_bucket{domain_class=\"video\",media_type=\"video\",le=\"5\"} 1\n"
+        ));
+        assert!(out.contains(
+            "embed_latency_seconds_sum{domain_class=\"video\",media_type=\"video\"} 4\n"
+        ));
+        assert_eq!(escape_label("a\"b\\"), "a\\\"b\\\\");
+    }
+
+    #[test]
+    fn test_render_html_parses() {
+        let metrics = Metrics::default();
+        metrics.record_html_parse(Duration::from_millis(3), 100_000, false);
+        metrics.record_html_parse(Duration::from_millis(40), 2_000_000, true);
+
+        let out = metrics.render();
+        assert!(out.contains("embed_html_parse_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("embed_html_parse_seconds_count 2\n"));
+        assert!(out.contains("embed_html_parse_estimated_peak_bytes_bucket{le=\"262144\"} 1\n"));
+        assert!(out.contains("embed_html_parse_estimated_peak_bytes_bucket{le=\"4194304\"} 2\n"));
+        assert!(out.contains("embed_html_parse_truncated_total 1\n"));
+    }
+    #[test]
+    fn test_render_maintenance() {
+        let metrics = Metrics::default();
+        metrics.record_pruned(Pruned::EmbedHistory, 3);
+        metrics.record_pruned(Pruned::EmbedHistory, 2);
+        metrics.record_reclaimed(Reclaimed::TempFiles, 4096);
+        metrics.record_maintenance_run();
+
+        let out = metrics.render();
+        assert!(out.contains("embed_maintenance_runs_total 1\n"));
+        assert!(out.contains("embed_maintenance_pruned_total{kind=\"embed_history\"} 5\n"));
+        assert!(out.contains("embed_maintenance_pruned_total{kind=\"uploads\"} 0\n"));
+        assert!(
+            out.contains("embed_maintenance_reclaimed_bytes_total{kind=\"temp_files\"} 4096\n")
+        );
+        assert!(out.contains("embed_maintenance_reclaimed_bytes_total{kind=\"database\"} 0\n"));
+    }
+}
@@ -0,0 +1,325 @@
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use reqwest::header::{ACCEPT_ENCODING, USER_AGENT};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Deserializer};
+use tracing::debug;
+use url::Url;
+
+use crate::config::Config;
+use crate::decompress;
+use crate::dump;
+use crate::html_scan::Tag;
+use crate::http::{self, Fetch};
+use crate::metadata::{Metadata, OgImage};
+
+/// Largest oEmbed response read. They're small JSON objects; anything
+/// bigger isn't one.
+const MAX_RESPONSE_SIZE: u64 = 256 * 1024;
+
+static IFRAME_SELECTOR: LazyLock<Selector> =
+    LazyLock::new(|| Selector::parse("iframe[src]").unwrap());
+
+/// An oEmbed response, as far as it's used. See <https://oembed.com>.
+#[derive(Debug, Default, Deserialize)]
+struct Response {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    title: Option<String>,
+    author_name: Option<String>,
+    provider_name: Option<String>,
+    /// The image itself, for `photo` responses.
+    url: Option<String>,
+    #[serde(default, deserialize_with = "size")]
+    width: Option<u32>,
+    #[serde(default, deserialize_with = "size")]
+    height: Option<u32>,
+    thumbnail_url: Option<String>,
+    #[serde(default, deserialize_with = "size")]
+    thumbnail_width: Option<u32>,
+    #[serde(default, deserialize_with = "size")]
+    thumbnail_height: Option<u32>,
+    /// Markup to embed, for `video` and `rich` responses.
+    html: Option<String>,
+}
+
+/// A width or height, which the spec makes a number but some providers send
+/// as a string.
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Number(f64),
+        Text(String),
+        Other(serde::de::IgnoredAny),
+    }
+    Ok(match Size::deserialize(deserializer)? {
+        Size::Number(n) if (0.0..=f64::from(u32::MAX)).contains(&n) => Some(n as u32),
+        Size::Text(text) => text.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/// The JSON oEmbed endpoint the page at `page_url` advertises with a
+/// `link rel="alternate"` tag, if any.
+pub fn discover(tags: &[Tag], page_url: &Url) -> Option<Url> {
+    tags.iter()
+        .filter(|tag| {
+            tag.name == "link"
+                && tag.has_rel("alternate")
+                && tag
+                    .attr("type")
+                    .is_some_and(|kind| kind.trim().eq_ignore_ascii_case("application/json+oembed"))
+        })
+        .filter_map(|tag| tag.attr("href"))
+        .filter_map(|href| page_url.join(href.trim()).ok())
+        .find(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Query the oEmbed endpoint at `endpoint` and map its response into
+/// [`Metadata`].
+pub async fn fetch(client: &reqwest::Client, endpoint: &Url, config: &Config) -> Result<Metadata> {
+    let response = client
+        .get(endpoint.clone())
+        .header(ACCEPT_ENCODING, decompress::ACCEPT_ENCODING)
+        .header(
+            USER_AGENT,
+            http::user_agent(config, endpoint, Fetch::Metadata),
+        )
+        .send()
+        .await?;
+    dump::record_response(&response);
+    let response = response.error_for_status()?;
+    let body =
+        decompress::read_body(response, MAX_RESPONSE_SIZE, config.max_decompression_ratio).await?;
+    dump::record_body(&body);
+    let response: Response = serde_json::from_slice(&body)?;
+    Ok(to_metadata(response, endpoint))
+}
+
+/// `metadata` of the page at `page_url`, completed from the oEmbed endpoint
+/// its `tags` advertise, if any.
+pub async fn complete(
+    client: &reqwest::Client,
+    tags: &[Tag],
+    page_url: &Url,
+    metadata: Metadata,
+    config: &Config,
+) -> Metadata {
+    let Some(endpoint) = discover(tags, page_url) else {
+        return metadata;
+    };
+    match fetch(client, &endpoint, config).await {
+        Ok(oembed) => {
+            debug!("Got oEmbed metadata for {} from {}", page_url, endpoint);
+            merge(oembed, metadata)
+        }
+        Err(e) => {
+            debug!("Failed to fetch oEmbed {}: {:?}", endpoint, e);
+            metadata
+        }
+    }
+}
+
+/// The metadata an oEmbed `response` from `endpoint` gives. Its author goes
+/// with the provider's name, or becomes the title if there's no other.
+fn to_metadata(response: Response, endpoint: &Url) -> Metadata {
+    let non_empty = |value: Option<String>| {
+        value
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let media_url = |value: Option<String>| {
+        value
+            .and_then(|value| endpoint.join(value.trim()).ok())
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+    };
+
+    let mut metadata = Metadata::default();
+    let author = non_empty(response.author_name);
+    let provider = non_empty(response.provider_name);
+    match non_empty(response.title) {
+        Some(title) => {
+            metadata.title = Some(title);
+            metadata.site_name = match (author, provider) {
+                (Some(author), Some(provider)) => Some(format!("{} · {}", author, provider)),
+                (author, provider) => author.or(provider),
+            };
+        }
+        None => {
+            metadata.title = author;
+            metadata.site_name = provider;
+        }
+    }
+
+    let kind = response.kind.unwrap_or_default();
+    let image = match media_url(response.url).filter(|_| kind == "photo") {
+        Some(url) => Some(OgImage {
+            url: Some(url),
+            width: response.width,
+            height: response.height,
+            ..Default::default()
+        }),
+        None => media_url(response.thumbnail_url).map(|url| OgImage {
+            url: Some(url),
+            width: response.thumbnail_width,
+            height: response.thumbnail_height,
+            ..Default::default()
+        }),
+    };
+    if let Some(image) = image {
+        metadata.image_url = image.url.clone();
+        metadata.og_images.push(image);
+    }
+
+    if let Some(html) = non_empty(response.html) {
+        match kind.as_str() {
+            "video" => {
+                metadata.player_url = Html::parse_fragment(&html)
+                    .select(&IFRAME_SELECTOR)
+                    .filter_map(|iframe| iframe.value().attr("src"))
+                    .find_map(|src| media_url(Some(src.to_string())));
+            }
+            // Posts embedded as a quote of their text, like tweets, rather
+            // than as an iframe.
+            "rich" => {
+                let fragment = Html::parse_fragment(&html);
+                let text = fragment
+                    .root_element()
+                    .descendants()
+                    .filter(|node| {
+                        node.parent()
+                            .and_then(|parent| parent.value().as_element())
+                            .is_none_or(|parent| !matches!(parent.name(), "script" | "style"))
+                    })
+                    .filter_map(|node| node.value().as_text().map(|text| &**text))
+                    .collect::<String>();
+                let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if !text.is_empty() {
+                    metadata.description = Some(text);
+                    metadata.description_html = Some(html);
+                }
+            }
+            _ => {}
+        }
+    }
+    metadata
+}
+
+/// `oembed` with whatever it lacks filled in from `page`, the metadata of
+/// the page that advertised it.
+pub fn merge(oembed: Metadata, page: Metadata) -> Metadata {
+    // The page's card type still describes it, even with the image from
+    // oEmbed.
+    let card = page.card.clone();
+    let mut metadata = oembed.merge(page);
+    metadata.card = metadata.card.or(card);
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html_scan;
+
+    fn endpoint() -> Url {
+        Url::parse("https://www.example.com/oembed?url=x").unwrap()
+    }
+
+    #[test]
+    fn test_discover() {
+        let page_url = Url::parse("https://www.example.com/watch?v=1").unwrap();
+        let html = r#"<head>
+            <link rel="alternate" type="application/rss+xml" href="/feed">
+            <link rel="alternate" type="text/xml+oembed" href="/oembed?format=xml">
+            <link rel="alternate" type="application/json+oembed" href="/oembed?format=json">
+        </head>"#;
+        let tags = html_scan::scan(html, usize::MAX).tags;
+        assert_eq!(
+            discover(&tags, &page_url).unwrap().as_str(),
+            "https://www.example.com/oembed?format=json"
+        );
+        assert_eq!(discover(&tags[..2], &page_url), None);
+    }
+
+    #[test]
+    fn test_to_metadata() {
+        let video: Response = serde_json::from_str(
+            r#"{
+                "type": "video",
+                "title": "A video",
+                "author_name": "Someone",
+                "provider_name": "Example",
+                "thumbnail_url": "https://i.example.com/1.jpg",
+                "thumbnail_width": 480,
+                "thumbnail_height": "360",
+                "html": "<iframe width=\"200\" src=\"//www.example.com/embed/1\"></iframe>"
+            }"#,
+        )
+        .unwrap();
+        let meta = to_metadata(video, &endpoint());
+        assert_eq!(meta.title.as_deref(), Some("A video"));
+        assert_eq!(meta.site_name.as_deref(), Some("Someone · Example"));
+        assert_eq!(
+            meta.image_url.as_ref().map(Url::as_str),
+            Some("https://i.example.com/1.jpg")
+        );
+        assert_eq!(meta.og_images[0].height, Some(360));
+        assert_eq!(
+            meta.player_url.as_ref().map(Url::as_str),
+            Some("https://www.example.com/embed/1")
+        );
+
+        let photo: Response = serde_json::from_str(
+            r#"{
+                "type": "photo",
+                "author_name": "Someone",
+                "url": "https://i.example.com/full.jpg",
+                "width": 2048,
+                "height": 1536,
+                "thumbnail_url": "https://i.example.com/small.jpg"
+            }"#,
+        )
+        .unwrap();
+        let meta = to_metadata(photo, &endpoint());
+        assert_eq!(meta.title.as_deref(), Some("Someone"));
+        assert_eq!(meta.site_name, None);
+        assert_eq!(
+            meta.image_url.as_ref().map(Url::as_str),
+            Some("https://i.example.com/full.jpg")
+        );
+        assert_eq!(meta.og_images[0].width, Some(2048));
+
+        let rich: Response = serde_json::from_str(
+            r#"{
+                "type": "rich",
+                "author_name": "Someone",
+                "html": "<blockquote><p>Hello <a href=\"https://example.com\">world</a></p></blockquote><script>load()</script>"
+            }"#,
+        )
+        .unwrap();
+        let meta = to_metadata(rich, &endpoint());
+        assert_eq!(meta.description.as_deref(), Some("Hello world"));
+        assert!(meta.description_html.unwrap().starts_with("<blockquote>"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let oembed = Metadata {
+            title: Some("From oEmbed".to_string()),
+            image_url: Some(Url::parse("https://i.example.com/1.jpg").unwrap()),
+            ..Default::default()
+        };
+        let page = Metadata {
+            card: Some("summary_large_image".to_string()),
+            title: Some("From the page".to_string()),
+            description: Some("Description".to_string()),
+            ..Default::default()
+        };
+        let meta = merge(oembed, page);
+        assert_eq!(meta.title.as_deref(), Some("From oEmbed"));
+        assert_eq!(meta.description.as_deref(), Some("Description"));
+        assert_eq!(meta.card.as_deref(), Some("summary_large_image"));
+    }
+}
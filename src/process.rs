@@ -0,0 +1,194 @@
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::metrics::{Killed, metrics};
+
+/// A command that ran for longer than it was allowed to, and was killed.
+#[derive(Debug)]
+pub struct TimedOut {
+    program: String,
+    limit: Duration,
+}
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} timed out after {}s",
+            self.program,
+            self.limit.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Kills a child process, and everything it started, unless it's known to
+/// have exited. Also removes the files it was writing unless it succeeded.
+struct Guard {
+    program: String,
+    /// The child's process ID, which is also its process group ID, while it
+    /// may still be running.
+    pid: Option<u32>,
+    reason: Killed,
+    artifacts: Vec<PathBuf>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            let why = match self.reason {
+                Killed::TimedOut => "it timed out",
+                Killed::Cancelled => "it's no longer needed",
+            };
+            warn!("Killing {} (pid {}): {}", self.program, pid, why);
+            kill_group(pid);
+            metrics().record_killed_child(self.reason);
+        }
+        for path in &self.artifacts {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Run `command` to completion and collect its output, like
+/// [`Command::output`], but kill it and every process it started if it runs
+/// for longer than `limit` or the caller stops waiting for it, like when its
+/// job is cancelled. `artifacts` are files the command writes, which are
+/// removed unless it exits successfully.
+pub async fn output(command: &mut Command, limit: Duration, artifacts: &[&Path]) -> Result<Output> {
+    let program = command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned();
+    // In a process group of its own, it can be killed along with whatever it
+    // started without touching anything else.
+    #[cfg(unix)]
+    command.process_group(0);
+    let child = command
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    let mut guard = Guard {
+        program: program.clone(),
+        pid: child.id(),
+        reason: Killed::Cancelled,
+        artifacts: artifacts.iter().map(|path| path.to_path_buf()).collect(),
+    };
+
+    match tokio::time::timeout(limit, child.wait_with_output()).await {
+        Ok(output) => {
+            guard.pid = None;
+            let output = output.with_context(|| format!("Failed to run {}", program))?;
+            if output.status.success() {
+                guard.artifacts.clear();
+            }
+            Ok(output)
+        }
+        Err(_) => {
+            guard.reason = Killed::TimedOut;
+            Err(TimedOut { program, limit }.into())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn kill_group(pgid: u32) {
+    // SAFETY: killpg only sends a signal, and has no memory safety
+    // requirements.
+    let killed = unsafe { libc::killpg(pgid as libc::pid_t, libc::SIGKILL) };
+    if killed != 0 {
+        let e = std::io::Error::last_os_error();
+        // The whole group may have exited already.
+        if e.raw_os_error() != Some(libc::ESRCH) {
+            warn!("Failed to kill process group {}: {}", pgid, e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(_pgid: u32) {
+    // Without process groups, `kill_on_drop` killing the child itself is
+    // the best we can do.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    #[tokio::test]
+    async fn test_output() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let artifact = dir.path().join("out");
+        let output = output(
+            Command::new("sh")
+                .args(["-c", "echo done > \"$0\"; echo hi"])
+                .arg(&artifact)
+                .stdout(Stdio::piped()),
+            Duration::from_secs(10),
+            &[&artifact],
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.stdout, b"hi\n");
+        // It succeeded, so what it wrote is kept.
+        assert!(artifact.exists());
+    }
+
+    #[tokio::test]
+    async fn test_output_failure_removes_artifacts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let artifact = dir.path().join("out");
+        let output = output(
+            Command::new("sh")
+                .args(["-c", "echo partial > \"$0\"; exit 1"])
+                .arg(&artifact),
+            Duration::from_secs(10),
+            &[&artifact],
+        )
+        .await
+        .unwrap();
+        assert!(!output.status.success());
+        assert!(!artifact.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_output_timeout_kills_group() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pid_file = dir.path().join("pid");
+        let err = output(
+            Command::new("sh")
+                .args(["-c", "sleep 30 & echo $! > \"$0\"; wait"])
+                .arg(&pid_file),
+            Duration::from_millis(500),
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert!(err.is::<TimedOut>());
+
+        // The grandchild was killed along with the shell.
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let mut alive = true;
+        for _ in 0..50 {
+            alive = std::fs::read_to_string(&stat).is_ok_and(|stat| {
+                let state = stat.rsplit(')').next().unwrap_or("").trim_start();
+                !state.starts_with('Z') && !state.starts_with('X')
+            });
+            if !alive {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!alive, "sleep {} is still running", pid.trim());
+    }
+}
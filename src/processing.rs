@@ -1,41 +1,188 @@
-use crate::config::Config;
+use crate::config::{Config, EmoteMode, MediaKind, VideoFormat, VideoTarget};
+use crate::decompress::{BodyDecoder, ExcessiveCompression};
+use crate::dump;
+use crate::emote;
+use crate::http::{self, Fetch};
+use crate::idn;
+use crate::maintenance::TEMP_PREFIX;
 use crate::media::{
-    generate_blurhash, generate_thumbnail, probe_is_animated, probe_media, remux_to_mp4,
+    downscale_image, encode_video, generate_blurhash, generate_thumbnail, image_dimensions,
+    probe_is_animated, probe_media, remux_video,
 };
-use crate::metadata::Metadata;
-use anyhow::{Result, bail};
-use matrix_sdk::attachment::{AttachmentConfig, BaseAudioInfo, BaseVideoInfo};
+use crate::media_cache::{CacheSlot, CachedHeaders};
+use crate::metadata::{GalleryImage, Metadata, Rendition};
+use crate::metrics::{DownloadOutcome, Step, media_label, metrics};
+use crate::sanitize::{isolate, sanitize_html, strip_invisible};
+use crate::timestamp::TimeFormat;
+use crate::transcribe;
+use anyhow::{Context, Result, bail};
+use matrix_sdk::attachment::{AttachmentInfo, BaseAudioInfo, BaseVideoInfo};
 use matrix_sdk::attachment::{BaseImageInfo, Thumbnail};
 use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
 use mime_guess::Mime;
 use reqwest::Url;
-use std::io::Write;
+use reqwest::header::{
+    ACCEPT_RANGES, CONTENT_RANGE, ETAG, HeaderMap, HeaderValue, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// How much of a download is read to recognize its type. Only what could be
+/// a longer JSON document is read in full.
+const SNIFF_BYTES: u64 = 64 * 1024;
+
+/// How often the speed of a stalled download is checked.
+const SPEED_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest side of the image posted with a summary card.
+const SUMMARY_THUMBNAIL_SIZE: u32 = 256;
 
 #[derive(Debug)]
 pub struct MessageParams {
     pub body: String,
     pub html_body: String,
+    /// Plain and HTML text that didn't fit in the embed, to be posted as a
+    /// follow-up in a thread.
+    pub continuation: Option<(String, String)>,
     pub media_url: Option<Url>,
+    /// Alt text to use as the body of an uncaptioned image upload.
+    pub alt_text: Option<String>,
+    pub media_is_video: bool,
+    /// Whether `media_url` is the image of a summary card, to be posted
+    /// downscaled to a thumbnail.
+    pub media_is_thumbnail: bool,
+    /// Poster image to post instead when `media_url` is a video that's too
+    /// large to upload.
+    pub poster_url: Option<Url>,
+    /// Whether to also post `poster_url` after the video.
+    pub post_poster: bool,
+    /// Length of the video at `media_url` in seconds, if known.
+    pub video_duration: Option<u64>,
+    /// Another candidate for the video, to try if `media_url` isn't one.
+    pub alternate_video_url: Option<Url>,
+    /// The image the page gave, to try if the full-resolution original at
+    /// `media_url` can't be used.
+    pub fallback_image_url: Option<Url>,
+    /// More images to post in a thread under the embed.
+    pub gallery: Vec<GalleryImage>,
+    /// The images after `gallery`, posted only if someone asks for them.
+    pub gallery_rest: Vec<GalleryImage>,
+    /// Why a pre-check ruled out downloading `media_url`, if it did.
+    pub media_rejected: Option<anyhow::Error>,
+}
+
+/// Returned by [`process_response`] when the download exceeds
+/// `max_file_size`.
+#[derive(Debug)]
+pub struct FileTooLarge {
+    /// Size in bytes. When `streamed` is set this is only how much was read
+    /// before giving up, so the real size is larger.
+    pub size: u64,
+    pub streamed: bool,
+}
+
+impl std::fmt::Display for FileTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.streamed {
+            write!(f, "File too large (streamed): {}", self.size)
+        } else {
+            write!(f, "File too large based on Content-Length: {}", self.size)
+        }
+    }
+}
+
+impl std::error::Error for FileTooLarge {}
+
+/// A download was abandoned because its average speed was below
+/// `min_download_speed`.
+#[derive(Debug)]
+pub struct DownloadTooSlow {
+    pub bytes_per_second: u64,
+}
+
+impl std::fmt::Display for DownloadTooSlow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download too slow: {} bytes/s", self.bytes_per_second)
+    }
+}
+
+impl std::error::Error for DownloadTooSlow {}
+
+/// Returned by [`process_response`] when the downloaded media's type isn't
+/// in `allowed_media_types`.
+#[derive(Debug)]
+pub struct DisallowedMediaType {
+    pub mime_type: Mime,
+}
+
+impl std::fmt::Display for DisallowedMediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Media type not allowed: {}", self.mime_type)
+    }
+}
+
+impl std::error::Error for DisallowedMediaType {}
+
+/// Returned by [`process_response`] when the body isn't the media its
+/// `Content-Type` or URL claimed, e.g. an HTML error page served as `.mp4`.
+#[derive(Debug)]
+pub struct UnexpectedContent {
+    pub expected: Mime,
+    pub found: &'static str,
+}
+
+impl std::fmt::Display for UnexpectedContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected {} but got {}", self.expected, self.found)
+    }
 }
 
+impl std::error::Error for UnexpectedContent {}
+
+/// Matrix events are limited to 64 KiB. Embeds whose plain and HTML text
+/// together exceed this are split, leaving room for the rest of the event.
+pub const MAX_CAPTION_BYTES: usize = 32 * 1024;
+
+/// How much of the description is kept in an embed that was split.
+const SPLIT_CAPTION_DESCRIPTION_CHARS: usize = 280;
+
+/// Longest description posted in the follow-up to a split embed. Even with
+/// every character escaped in the HTML, this fits within an event.
+const MAX_CONTINUATION_CHARS: usize = 2000;
+
 pub struct AttachmentData {
     pub filename: String,
     pub mime_type: Mime,
     pub data: Vec<u8>,
-    pub attachment_config: AttachmentConfig,
+    pub info: Option<AttachmentInfo>,
+    pub thumbnail: Option<Thumbnail>,
+    pub caption: Option<TextMessageEventContent>,
 }
 
 /// Truncates text to fit within the given character and line limits.
-/// Appends "…" if the text was truncated.
-fn truncate_text(text: &str, max_chars: usize, max_lines: usize) -> String {
+/// Appends "…" if the text was truncated. When the character limit falls in
+/// the middle of a word, the partial word is dropped as well, unless that would
+/// throw away more than half of the text. Text is only cut between grapheme
+/// clusters, so accents, emoji modifiers and joined emoji stay whole.
+pub fn truncate_text(text: &str, max_chars: usize, max_lines: usize) -> String {
+    match truncation_offset(text, max_chars, max_lines) {
+        Some(offset) => format!("{}…", text[..offset].trim_end()),
+        None => text.to_owned(),
+    }
+}
+
+/// Where [`truncate_text`] cuts `text`, as a byte offset, or `None` if it
+/// fits.
+fn truncation_offset(text: &str, max_chars: usize, max_lines: usize) -> Option<usize> {
     let mut result = String::new();
     let mut char_count = 0;
     let mut line_count = 1;
     let mut truncated = false;
 
-    for ch in text.chars() {
-        if ch == '\n' {
+    for grapheme in text.graphemes(true) {
+        if grapheme.contains('\n') {
             if line_count >= max_lines {
                 truncated = true;
                 break;
@@ -43,128 +190,928 @@ fn truncate_text(text: &str, max_chars: usize, max_lines: usize) -> String {
             line_count += 1;
         }
 
-        char_count += 1;
+        char_count += grapheme.chars().count();
         if char_count > max_chars {
             truncated = true;
+            let mid_word = grapheme.starts_with(char::is_alphanumeric)
+                && result
+                    .chars()
+                    .next_back()
+                    .is_some_and(char::is_alphanumeric);
+            if mid_word
+                && let Some(boundary) = result.rfind(char::is_whitespace)
+                && result[..boundary].chars().count() >= max_chars / 2
+            {
+                result.truncate(boundary);
+            }
             break;
         }
 
-        result.push(ch);
+        result.push_str(grapheme);
+    }
+
+    // Only whole graphemes from the start were kept, so the result is a
+    // prefix of the text.
+    truncated.then_some(result.len())
+}
+
+/// Build the plain-text and HTML rich-reply fallback quoting `quoted_body`,
+/// to be prepended to a reply's body and formatted body respectively.
+pub fn reply_fallback(
+    room_id: &str,
+    event_id: &str,
+    sender: &str,
+    quoted_body: &str,
+) -> (String, String) {
+    let mut plain = String::new();
+    for (i, line) in quoted_body.lines().enumerate() {
+        if i == 0 {
+            plain.push_str(&format!("> <{}> {}\n", sender, line));
+        } else {
+            plain.push_str(&format!("> {}\n", line));
+        }
+    }
+    plain.push('\n');
+
+    let html = format!(
+        "<mx-reply><blockquote>\
+         <a href=\"https://matrix.to/#/{room_id}/{event_id}\">In reply to</a> \
+         <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br/>{quote}\
+         </blockquote></mx-reply>",
+        room_id = html_escape::encode_double_quoted_attribute(room_id),
+        event_id = html_escape::encode_double_quoted_attribute(event_id),
+        sender = html_escape::encode_text(sender),
+        quote = html_escape::encode_text(quoted_body).replace('\n', "<br/>"),
+    );
+
+    (plain, html)
+}
+
+fn is_media_type(mime_type: &Mime) -> bool {
+    [
+        mime_guess::mime::IMAGE,
+        mime_guess::mime::VIDEO,
+        mime_guess::mime::AUDIO,
+    ]
+    .contains(&mime_type.type_())
+}
+
+/// Whether a video of type `mime_type` should be remuxed for `format`.
+/// Matroska is always remuxed for client compatibility; rooms that prefer
+/// WebM also get everything else converted.
+fn needs_remux(mime_type: &Mime, format: VideoFormat) -> bool {
+    mime_type.essence_str() == "video/x-matroska"
+        || (format == VideoFormat::Webm && mime_type.essence_str() != format.mime_type())
+}
+
+/// Describe HTML and JSON types the way [`sniff_markup`] does.
+fn markup_type(mime_type: &Mime) -> Option<&'static str> {
+    match mime_type.essence_str() {
+        "text/html" | "application/xhtml+xml" => Some("an HTML page"),
+        "application/json" => Some("a JSON document"),
+        _ => None,
+    }
+}
+
+/// Recognize bodies that are HTML or JSON documents, returning a description
+/// for error messages.
+fn sniff_markup(data: &[u8]) -> Option<&'static str> {
+    let data = data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data);
+    let start = data.iter().position(|b| !b.is_ascii_whitespace())?;
+    let data = &data[start..];
+
+    let head = &data[..data.len().min(64)];
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    if ["<!doctype html", "<html", "<head", "<body"]
+        .iter()
+        .any(|tag| head.starts_with(tag))
+    {
+        return Some("an HTML page");
+    }
+    if matches!(data[0], b'{' | b'[') && serde_json::from_slice::<serde_json::Value>(data).is_ok() {
+        return Some("a JSON document");
+    }
+    None
+}
+
+/// Format a byte count for humans, e.g. `"12.3 MB"`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
     }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
 
-    if truncated {
-        format!("{}…", result.trim_end())
+/// Format a duration in seconds as `m:ss` or `h:mm:ss`.
+pub fn format_duration(seconds: u64) -> String {
+    let (h, m, s) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+    if h > 0 {
+        format!("{}:{:02}:{:02}", h, m, s)
     } else {
-        result
+        format!("{}:{:02}", m, s)
+    }
+}
+
+/// Build the plain and HTML caption note used when a video was too large to
+/// upload, linking to the source so it can be streamed directly.
+pub fn oversized_video_note(
+    url: &Url,
+    too_large: &FileTooLarge,
+    duration: Option<u64>,
+) -> (String, String) {
+    let mut details = vec![format!(
+        "{}{}",
+        if too_large.streamed { "over " } else { "" },
+        format_size(too_large.size)
+    )];
+    if let Some(duration) = duration {
+        details.push(format_duration(duration));
+    }
+    let label = format!("Video too large to upload ({})", details.join(", "));
+    let escaped_url = html_escape::encode_double_quoted_attribute(url.as_str());
+    let display_url = idn::display_url(url);
+    (
+        format!("{}: {}", label, display_url),
+        format!(
+            "<p><em>{}:</em> <a href=\"{}\">{}</a></p>",
+            label,
+            escaped_url,
+            html_escape::encode_text(&display_url)
+        ),
+    )
+}
+
+/// Where an embedded page came from, shown at the end of its embed.
+#[derive(Debug, Clone, Copy)]
+struct CaptionSource<'a> {
+    site_name: Option<&'a str>,
+    url: &'a Url,
+}
+
+/// Build the plain and HTML text of an embed from its parts, with the HTML
+/// starting on a new line after `media` if there is any. `description_html`
+/// is used for the HTML in place of the escaped `description` if given.
+///
+/// Both say the same thing: the plain text has the title, description and
+/// notes on lines of their own and ends with the site name and link, so
+/// clients that don't render HTML still show where the embed is from.
+fn format_caption(
+    title: Option<&str>,
+    description: Option<&str>,
+    description_html: Option<&str>,
+    notes: &[(&str, String)],
+    source: Option<CaptionSource>,
+    media: bool,
+) -> (String, String) {
+    // All of this comes from the page, so keep it from hiding or reordering
+    // the text around it.
+    let title = title.map(|s| isolate(&strip_invisible(s)));
+    let description = description.map(|s| isolate(&strip_invisible(s)));
+    let description_html = description_html.map(isolate);
+    let notes: Vec<(&str, String)> = notes
+        .iter()
+        .map(|(label, text)| (*label, isolate(&strip_invisible(text))))
+        .collect();
+
+    // A link alone isn't worth a caption.
+    if title.is_none() && description.is_none() && notes.is_empty() {
+        return (String::new(), String::new());
+    }
+    let site_name = source
+        .and_then(|source| source.site_name)
+        .map(|s| isolate(&strip_invisible(s)));
+    let source_url = source.map(|source| (source.url, idn::display_url(source.url)));
+
+    let mut body = match (&title, &description) {
+        (Some(t), Some(d)) => format!("{}\n{}", t, d),
+        (Some(t), None) => t.to_string(),
+        (None, Some(d)) => d.to_string(),
+        (None, None) => String::new(),
+    };
+    for (label, text) in &notes {
+        if !body.is_empty() {
+            body.push_str("\n\n");
+        }
+        body.push_str(&format!("{}: {}", label, text));
+    }
+    if let Some((_, display_url)) = &source_url {
+        body.push_str("\n\n");
+        match &site_name {
+            Some(site_name) => body.push_str(&format!("{} · {}", site_name, display_url)),
+            None => body.push_str(display_url),
+        }
     }
+
+    let html_title = title.as_deref().map(|s| {
+        let escaped = html_escape::encode_text(s);
+        escaped.replace('\n', "<br/>")
+    });
+
+    let html_desc = description.as_deref().map(|s| match description_html {
+        Some(html) => html,
+        None => {
+            let escaped = html_escape::encode_text(s);
+            escaped.replace('\n', "<br/>")
+        }
+    });
+
+    let html_notes: String = notes
+        .iter()
+        .map(|(label, text)| {
+            format!(
+                "<p><em>{}:</em> {}</p>",
+                label,
+                html_escape::encode_text(text)
+            )
+        })
+        .collect();
+
+    let html_source = source_url
+        .map(|(url, display_url)| {
+            let text = site_name.unwrap_or(display_url);
+            format!(
+                "<p><a href=\"{}\">{}</a></p>",
+                html_escape::encode_double_quoted_attribute(url.as_str()),
+                html_escape::encode_text(&text)
+            )
+        })
+        .unwrap_or_default();
+
+    let html_body = format!(
+        "{}<blockquote>{}{}{}{}</blockquote>",
+        if media { "<br/>" } else { "" },
+        html_title
+            .map(|s| format!("<strong>{}</strong>", s))
+            .unwrap_or_default(),
+        html_desc
+            .map(|s| format!("<p>{}</p>", s))
+            .unwrap_or_default(),
+        html_notes,
+        html_source,
+    );
+    (body, html_body)
+}
+
+/// The formatted body of an embed with its image inline: the image, then the
+/// caption from [`format_caption`], if any.
+pub fn inline_image_html(src: &str, alt: &str, caption_html: Option<&str>) -> String {
+    format!(
+        "<img src=\"{}\" alt=\"{}\"/>{}",
+        html_escape::encode_double_quoted_attribute(src),
+        html_escape::encode_double_quoted_attribute(alt),
+        caption_html.unwrap_or_default()
+    )
+}
+
+/// Whether `html` (a message's formatted body) has the shape of an embed
+/// caption from [`format_caption`], possibly after a rich-reply fallback or
+/// an inline image. Editors put line breaks around quoted paragraphs; embeds
+/// don't.
+pub fn looks_like_embed(html: &str) -> bool {
+    let html = match html.split_once("</mx-reply>") {
+        Some((_, rest)) if html.starts_with("<mx-reply>") => rest,
+        _ => html,
+    };
+    let html = match html.split_once("/>") {
+        Some((_, rest)) if html.starts_with("<img ") => rest,
+        _ => html,
+    };
+    let html = html.strip_prefix("<br/>").unwrap_or(html);
+    html.starts_with("<blockquote><strong>") || html.starts_with("<blockquote><p>")
 }
 
-pub fn process_metadata(meta: Metadata, config: &Config) -> MessageParams {
-    let media_url = match meta.card.as_deref() {
+/// The media that an embed of `meta`, fetched from `url`, would attach, if
+/// any.
+pub fn media_candidate<'a>(meta: &'a Metadata, url: &Url, config: &Config) -> Option<&'a Url> {
+    match meta.card.as_deref() {
         Some("summary") => None,
         Some("tweet") => None,
-        _ => meta.video_url.or(meta.audio_url).or(meta.image_url),
+        _ => config
+            .media_order(Some(meta.canonical_url.as_ref().unwrap_or(url)))
+            .iter()
+            .find_map(|kind| match kind {
+                MediaKind::Video => meta.video_url.as_ref(),
+                MediaKind::Audio => meta.audio_url.as_ref(),
+                MediaKind::Image => meta.image_url.as_ref(),
+            }),
+    }
+}
+
+/// The image of a summary card, which [`media_candidate`] leaves out since
+/// the card is meant to be mostly text.
+pub fn summary_image(meta: &Metadata) -> Option<&Url> {
+    match meta.card.as_deref() {
+        Some("summary" | "tweet") => meta.image_url.as_ref(),
+        _ => None,
+    }
+}
+
+/// Swap `meta.image_url` for the full-resolution original when a media URL
+/// rewrite rule matches it, keeping the URL it replaced to fall back to.
+pub fn upgrade_image_url(meta: &mut Metadata, config: &Config) {
+    if let Some(image_url) = &meta.image_url {
+        let original = config.rewrite_media_url(image_url);
+        if original != *image_url {
+            debug!("Using full-resolution image {} for {}", original, image_url);
+            meta.original_image_url = meta.image_url.replace(original);
+        }
+    }
+}
+
+/// Pick the rendition to download: the tallest one no taller than
+/// `max_height` that fits in `max_size` bytes, or failing that the shortest
+/// one that fits. Renditions of unknown size might fit, but lose to ones
+/// known to. Returns `None` if nothing fits.
+pub fn select_rendition(
+    renditions: &[Rendition],
+    max_size: u64,
+    max_height: Option<u32>,
+) -> Option<&Rendition> {
+    let fitting: Vec<&Rendition> = renditions
+        .iter()
+        .filter(|r| r.size.is_none_or(|size| size <= max_size))
+        .collect();
+    let within_height = |r: &&&Rendition| match (max_height, r.height) {
+        (Some(max_height), Some(height)) => height <= max_height,
+        _ => true,
+    };
+    // Reversed so the first listed wins a tie.
+    fitting
+        .iter()
+        .rev()
+        .filter(within_height)
+        .max_by_key(|r| (r.size.is_some(), r.height.unwrap_or(0)))
+        .or_else(|| {
+            fitting
+                .iter()
+                .min_by_key(|r| (r.size.is_none(), r.height.unwrap_or(u32::MAX)))
+        })
+        .copied()
+}
+
+/// Build the text of an embed of `meta`, fetched from `url`, and pick its
+/// media. With
+/// `summary_thumbnail`, the image of a summary card is posted as a small
+/// thumbnail rather than left out.
+pub fn process_metadata(
+    meta: Metadata,
+    url: &Url,
+    config: &Config,
+    times: &TimeFormat,
+    summary_thumbnail: bool,
+) -> MessageParams {
+    let image_url = meta.image_url.clone();
+    let thumbnail_url = summary_image(&meta).filter(|_| summary_thumbnail).cloned();
+    let media_is_thumbnail = thumbnail_url.is_some();
+    let media_url = thumbnail_url.or_else(|| media_candidate(&meta, url, config).cloned());
+    let media_is_image = media_url.is_some() && media_url == image_url;
+    let media_is_video = media_url.is_some() && media_url == meta.video_url;
+
+    // Custom emotes are shortcodes in the text until they're inlined.
+    let strip_emotes = config.custom_emotes == EmoteMode::Strip && !meta.emotes.is_empty();
+    let unemote = |text: String| {
+        if strip_emotes {
+            emote::strip(&text, &meta.emotes)
+        } else {
+            text
+        }
     };
 
     // Filter out titles matching any ignored pattern
-    let title = meta.title.filter(|t| {
-        !config
-            .ignored_title_patterns
-            .iter()
-            .any(|re| re.is_match(t))
+    let title = meta.title.map(unemote).filter(|t| {
+        !t.is_empty()
+            && !config
+                .ignored_title_patterns
+                .iter()
+                .any(|re| re.is_match(t))
     });
-    let description = meta.description.map(|d| {
+    let description = meta.description.map(unemote).map(|d| {
         truncate_text(
             &d,
             config.max_embed_description_chars,
             config.max_embed_description_lines,
         )
     });
+    let description_html = meta
+        .description_html
+        .filter(|_| description.is_some())
+        .map(|html| {
+            if strip_emotes {
+                emote::strip_html(&html, &meta.emotes)
+            } else {
+                html
+            }
+        });
     let has_title = title.is_some();
     let has_desc = description.is_some();
 
-    let body = match (&title, &description) {
-        (Some(t), Some(d)) => format!("{}: {}", t, d),
-        (Some(t), None) => t.clone(),
-        (None, Some(d)) => d.clone(),
-        (None, None) => String::new(),
+    // Labelled paragraphs appended after the description.
+    let mut notes = Vec::new();
+    if let Some(warning) = meta.url_warning {
+        notes.push(("Warning", warning));
+    }
+    if let Some(published) = &meta.published {
+        notes.push(("Published", times.render(published, chrono::Utc::now())));
+    }
+    if let Some(summary) = meta.summary {
+        notes.push(("Summary", summary));
+    }
+    // Without a video to upload, at least link to where it can be played.
+    if media_url.is_none()
+        && let Some(player_url) = &meta.player_url
+    {
+        notes.push(("Player", player_url.to_string()));
+    }
+
+    // An uncaptioned image carries its alt text in the event body. Anywhere
+    // else (a caption, or a video whose poster image had alt text) it goes
+    // into the caption.
+    let mut alt_text = None;
+    if let Some(alt) = meta.image_alt {
+        if media_is_image && !has_title && !has_desc && notes.is_empty() {
+            alt_text = Some(strip_invisible(&alt));
+        } else if media_url.is_some() {
+            notes.push(("Image description", alt));
+        }
+    }
+
+    let source = meta.canonical_url.as_ref().map(|url| CaptionSource {
+        site_name: meta.site_name.as_deref(),
+        url,
+    });
+    let sanitized_description = description_html.as_deref().map(|html| {
+        sanitize_html(
+            html,
+            config.max_embed_description_chars,
+            config.max_embed_description_lines,
+        )
+    });
+    let (mut body, mut html_body) = format_caption(
+        title.as_deref(),
+        description.as_deref(),
+        sanitized_description.as_deref(),
+        &notes,
+        source,
+        media_url.is_some(),
+    );
+    let inline_emotes = |html: String| {
+        if config.custom_emotes == EmoteMode::Inline {
+            emote::inline_html(&html, &meta.emotes)
+        } else {
+            html
+        }
     };
+    html_body = inline_emotes(html_body);
 
-    let html_body = if has_title || has_desc {
-        let html_title = title.map(|s| {
-            let escaped = html_escape::encode_text(&s);
-            escaped.replace('\n', "<br/>")
+    // Too much to fit in one event: keep the title and the start of the
+    // description, and leave the rest for a follow-up in a thread.
+    let mut continuation = None;
+    if body.len() + html_body.len() > MAX_CAPTION_BYTES {
+        let short_description = description.as_deref().map(|d| {
+            truncate_text(
+                d,
+                SPLIT_CAPTION_DESCRIPTION_CHARS,
+                config.max_embed_description_lines,
+            )
         });
-
-        let html_desc = description.map(|s| {
-            let escaped = html_escape::encode_text(&s);
-            escaped.replace('\n', "<br/>")
+        let short_description_html = description_html.as_deref().map(|html| {
+            sanitize_html(
+                html,
+                SPLIT_CAPTION_DESCRIPTION_CHARS,
+                config.max_embed_description_lines,
+            )
+        });
+        (body, html_body) = format_caption(
+            title.as_deref(),
+            short_description.as_deref(),
+            short_description_html.as_deref(),
+            &[],
+            source,
+            media_url.is_some(),
+        );
+        html_body = inline_emotes(html_body);
+        // Carry on from where the caption's description was cut.
+        let description = description.as_deref().and_then(|d| {
+            let offset = truncation_offset(
+                d,
+                SPLIT_CAPTION_DESCRIPTION_CHARS,
+                config.max_embed_description_lines,
+            )?;
+            let rest = format!("…{}", d[offset..].trim_start());
+            Some(truncate_text(&rest, MAX_CONTINUATION_CHARS, usize::MAX))
         });
+        // Plain text only, so the size bound above holds.
+        continuation = Some(format_caption(
+            None,
+            description.as_deref(),
+            None,
+            &notes,
+            None,
+            false,
+        ));
+    }
 
-        format!(
-            "{}<blockquote>{}{}</blockquote>",
-            if media_url.is_some() { "<br/>" } else { "" },
-            html_title
-                .map(|s| format!("<strong>{}</strong>", s))
-                .unwrap_or_default(),
-            html_desc
-                .map(|s| format!("<p>{}</p>", s))
-                .unwrap_or_default(),
-        )
-    } else {
-        String::new()
+    // The rest of a multi-image post goes in a thread under the embed, or
+    // past the first few, waits there until someone asks for it.
+    let mut gallery: Vec<GalleryImage> = match &media_url {
+        Some(media_url) if !media_is_thumbnail => meta
+            .gallery
+            .into_iter()
+            .filter(|image| image.url != *media_url)
+            .collect(),
+        _ => vec![],
     };
+    let gallery_rest = gallery.split_off(gallery.len().min(config.gallery_max_images));
 
     MessageParams {
         body,
         html_body,
+        continuation,
         media_url,
+        alt_text,
+        media_is_video,
+        media_is_thumbnail,
+        poster_url: if media_is_video { image_url } else { None },
+        post_poster: false,
+        video_duration: if media_is_video {
+            meta.video_duration
+        } else {
+            None
+        },
+        alternate_video_url: if media_is_video {
+            meta.alternate_video_url
+        } else {
+            None
+        },
+        fallback_image_url: if media_is_image {
+            meta.original_image_url
+        } else {
+            None
+        },
+        gallery,
+        gallery_rest,
+        media_rejected: None,
     }
 }
 
-pub async fn process_response(
-    mut response: reqwest::Response,
+/// Downscale an image `attachment` to a small thumbnail, the way the image
+/// of a summary card is posted. Anything else, and images that can't be
+/// downscaled, are left as they are.
+pub async fn shrink_to_thumbnail(
+    mut attachment: AttachmentData,
     config: &Config,
-    text: Option<TextMessageEventContent>,
-) -> Result<AttachmentData> {
-    let content_length = response.content_length();
-    if let Some(len) = content_length
+) -> AttachmentData {
+    if attachment.mime_type.type_() != mime_guess::mime::IMAGE {
+        return attachment;
+    }
+    let (data, mime_type) = match downscale_image(
+        &attachment.data,
+        SUMMARY_THUMBNAIL_SIZE,
+        &config.image_limits,
+    )
+    .await
+    {
+        Ok(Some(small)) => small,
+        Ok(None) => return attachment,
+        Err(e) => {
+            warn!("Failed to downscale image, posting it as is: {:?}", e);
+            return attachment;
+        }
+    };
+    debug!(
+        "Downscaled {} byte image to {} bytes",
+        attachment.data.len(),
+        data.len()
+    );
+
+    let blurhash = match attachment.info {
+        Some(AttachmentInfo::Image(info)) => info.blurhash,
+        _ => None,
+    };
+    let info = image_dimensions(&data).ok();
+    attachment.info = Some(AttachmentInfo::Image(BaseImageInfo {
+        width: info.as_ref().map(|info| info.width.into()),
+        height: info.as_ref().map(|info| info.height.into()),
+        blurhash,
+        is_animated: Some(false),
+        ..Default::default()
+    }));
+    // It's no bigger than its thumbnail would be.
+    attachment.thumbnail = None;
+    let extension = if mime_type == mime_guess::mime::IMAGE_PNG {
+        "png"
+    } else {
+        "jpg"
+    };
+    attachment.filename = std::path::Path::new(&attachment.filename)
+        .with_extension(extension)
+        .to_string_lossy()
+        .into_owned();
+    attachment.mime_type = mime_type;
+    attachment.data = data;
+    attachment
+}
+
+/// Ask the server about the media at `url` with a HEAD request, so a
+/// download that's bound to fail can be skipped. This is cheap enough to run
+/// while the rest of the embed is being prepared.
+///
+/// Only a definite answer is an error: a response that's too large, not an
+/// allowed media type, or a page where the URL promised media. Failed
+/// requests and missing headers pass, since the download checks again.
+pub async fn precheck_media(
+    client: &reqwest::Client,
+    url: &Url,
+    referer: Option<&Url>,
+    config: &Config,
+) -> Result<()> {
+    let mut request = client
+        .head(url.clone())
+        .timeout(config.download_timeout)
+        .header(
+            reqwest::header::USER_AGENT,
+            http::user_agent(config, url, Fetch::Media),
+        );
+    if let Some(referer) = referer {
+        request = request.header(reqwest::header::REFERER, referer.as_str());
+    }
+    match request.send().await.and_then(|r| r.error_for_status()) {
+        Ok(response) => check_media_headers(response.url(), response.headers(), config),
+        Err(e) => {
+            debug!("Media pre-check of {} was inconclusive: {}", url, e);
+            Ok(())
+        }
+    }
+}
+
+/// The checks of [`precheck_media`] on the headers of a HEAD response.
+fn check_media_headers(url: &Url, headers: &HeaderMap, config: &Config) -> Result<()> {
+    if let Some(len) = headers
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
         && len > config.max_file_size
     {
-        bail!("File too large based on Content-Length: {}", len);
+        return Err(FileTooLarge {
+            size: len,
+            streamed: false,
+        }
+        .into());
     }
 
-    let mut mime_type: Mime = response
-        .headers()
+    let Some(declared_type) = headers
         .get(reqwest::header::CONTENT_TYPE)
         .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(mime_guess::from_path(response.url().path()).first_or_octet_stream());
+        .and_then(|s| s.parse::<Mime>().ok())
+    else {
+        return Ok(());
+    };
+    if is_media_type(&declared_type) {
+        if !config.is_media_type_allowed(&declared_type) {
+            return Err(DisallowedMediaType {
+                mime_type: declared_type,
+            }
+            .into());
+        }
+    } else if let Some(found) = markup_type(&declared_type)
+        && let Some(expected) = mime_guess::from_path(url.path())
+            .first()
+            .filter(is_media_type)
+    {
+        return Err(UnexpectedContent { expected, found }.into());
+    }
+    Ok(())
+}
+
+/// A WebP thumbnail of a video, made from the start of the file.
+pub struct VideoPreview {
+    pub thumbnail: Vec<u8>,
+    /// Size of the thumbnail.
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: Option<String>,
+}
+
+/// Download only the first `video_preview_bytes` of the video at `url` and
+/// make a thumbnail from that, so there's something to show while the whole
+/// video downloads.
+///
+/// Returns `None` if the server doesn't support range requests, or if the
+/// video is no larger than the prefix anyway.
+pub async fn fetch_video_preview(
+    client: &reqwest::Client,
+    url: &Url,
+    referer: Option<&Url>,
+    config: &Config,
+) -> Result<Option<VideoPreview>> {
+    let limit = config.video_preview_bytes;
+    let mut request = client
+        .get(url.clone())
+        .timeout(config.download_timeout)
+        .header(
+            reqwest::header::USER_AGENT,
+            http::user_agent(config, url, Fetch::Media),
+        )
+        .header(reqwest::header::ACCEPT_ENCODING, "identity")
+        .header(RANGE, format!("bytes=0-{}", limit - 1));
+    if let Some(referer) = referer {
+        request = request.header(reqwest::header::REFERER, referer.as_str());
+    }
+    let mut response = request
+        .send()
+        .await
+        .context("Failed to request video preview")?;
 
-    let content_disposition = response
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Ok(None);
+    }
+    let total = response
         .headers()
-        .get(reqwest::header::CONTENT_DISPOSITION)
+        .get(CONTENT_RANGE)
         .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_string());
+        .and_then(content_range_total);
+    if total.is_some_and(|total| total <= limit) {
+        return Ok(None);
+    }
+
+    let mut prefix = tempfile::Builder::new().prefix(TEMP_PREFIX).tempfile()?;
+    let mut received: u64 = 0;
+    while let Some(chunk) = response.chunk().await? {
+        let wanted = chunk.len().min((limit - received) as usize);
+        prefix.write_all(&chunk[..wanted])?;
+        received += wanted as u64;
+        if received >= limit {
+            break;
+        }
+    }
+    prefix.flush()?;
+
+    let info = probe_media(prefix.path())
+        .await
+        .context("Failed to probe video preview")?;
+    if !config.image_limits.allows(info.width, info.height) {
+        debug!(
+            "Not previewing {}x{} video {}: over the image size limit",
+            info.width, info.height, url
+        );
+        return Ok(None);
+    }
+    let thumbnail = generate_thumbnail(prefix.path(), 600)
+        .await
+        .context("Failed to generate video preview")?;
+    let thumbnail_info = image_dimensions(&thumbnail)?;
+    let blurhash = generate_blurhash(&thumbnail, &config.image_limits)
+        .await
+        .ok();
+    debug!(
+        "Generated preview of {}x{} video {} from {} bytes",
+        info.width, info.height, url, received
+    );
 
-    let final_url = response.url().clone();
+    Ok(Some(VideoPreview {
+        thumbnail,
+        width: thumbnail_info.width,
+        height: thumbnail_info.height,
+        blurhash,
+    }))
+}
 
-    let mut tmp_file = tempfile::NamedTempFile::new()?;
-    let mut downloaded: u64 = 0;
+/// Download `response` and turn it into an attachment.
+///
+/// Videos are remuxed to `video`'s format, or reencoded when their streams
+/// can't be copied into it. With `video.reencode_all` (as in data saver
+/// rooms), every video is reencoded instead.
+///
+/// If the transfer is interrupted and the server accepts byte ranges,
+/// `resume_request` (the request that produced `response`) is re-sent with a
+/// `Range` header to continue from where it stopped, up to
+/// `download_resume_attempts` times.
+///
+/// With a `cache` slot, a `304 Not Modified` response to revalidating the
+/// cached copy uses that copy instead, and a downloaded file is added to the
+/// cache if it can be revalidated later.
+pub async fn process_response(
+    client: &reqwest::Client,
+    response: reqwest::Response,
+    resume_request: Option<reqwest::RequestBuilder>,
+    cache: Option<CacheSlot<'_>>,
+    config: &Config,
+    video: &VideoTarget,
+    mut text: Option<TextMessageEventContent>,
+) -> Result<AttachmentData> {
+    dump::record_response(&response);
+    let content_length = response.content_length();
+    if let Some(len) = content_length
+        && len > config.max_file_size
+    {
+        return Err(FileTooLarge {
+            size: len,
+            streamed: false,
+        }
+        .into());
+    }
 
-    while let Some(chunk) = response.chunk().await? {
-        downloaded += chunk.len() as u64;
-        if downloaded > config.max_file_size {
-            bail!("File too large (streamed): {}", downloaded);
+    // A cached copy the server says is still current stands in for the
+    // response, headers and all.
+    let revalidated = match &cache {
+        Some(slot) if response.status() == reqwest::StatusCode::NOT_MODIFIED => {
+            slot.cached.as_ref()
+        }
+        _ => None,
+    };
+    let headers = match revalidated {
+        Some(cached) => cached.headers.clone(),
+        None => CachedHeaders::new(response.url(), response.headers()),
+    };
+    let final_url = Url::parse(&headers.final_url).unwrap_or_else(|_| response.url().clone());
+
+    let declared_type: Option<Mime> = headers.content_type.as_deref().and_then(|s| s.parse().ok());
+    let extension_type = mime_guess::from_path(final_url.path()).first();
+    let mut mime_type = declared_type
+        .clone()
+        .or(extension_type.clone())
+        .unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM);
+
+    let content_disposition = headers.content_disposition.clone();
+
+    // Every stage works on files in here, so only the final artifact is
+    // read into memory.
+    let workdir = tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .tempdir()
+        .context("Failed to create working directory")?;
+    let mut path = workdir.path().join("download");
+    if let Some(cached) = revalidated {
+        let size = tokio::fs::copy(&cached.path, &path)
+            .await
+            .context("Failed to copy cached media")?;
+        metrics().record_download(DownloadOutcome::Cached, 0, Duration::ZERO);
+        debug!("Using {} cached bytes from {}", size, final_url);
+    } else {
+        let mut file = std::fs::File::create(&path).context("Failed to create download file")?;
+        let mut downloaded: u64 = 0;
+        let started = Instant::now();
+        let result =
+            download_body(response, resume_request, config, &mut file, &mut downloaded).await;
+        drop(file);
+
+        let elapsed = started.elapsed();
+        let outcome = match &result {
+            Ok(()) => DownloadOutcome::Completed,
+            Err(e) if e.is::<FileTooLarge>() || e.is::<ExcessiveCompression>() => {
+                DownloadOutcome::TooLarge
+            }
+            Err(e) if e.is::<DownloadTooSlow>() => DownloadOutcome::TooSlow,
+            Err(_) => DownloadOutcome::Failed,
+        };
+        metrics().record_download(outcome, downloaded, elapsed);
+        metrics().record_step(Step::Download, media_label(&mime_type), elapsed);
+        result?;
+        debug!(
+            "Downloaded {} bytes from {} in {:.1}s",
+            downloaded,
+            final_url,
+            elapsed.as_secs_f64()
+        );
+
+        if let Some(slot) = &cache
+            && headers.has_validators()
+            && let Err(e) = slot.cache.insert(slot.url, &headers, &path).await
+        {
+            warn!("Failed to cache media from {}: {:?}", final_url, e);
         }
-        tmp_file.write_all(&chunk)?;
     }
 
-    let path = tmp_file.path();
-    let mut data = tokio::fs::read(path).await?;
+    let head = read_head(&path, SNIFF_BYTES)?;
+    let markup = match sniff_markup(&head) {
+        None if head.len() as u64 == SNIFF_BYTES
+            && matches!(head.trim_ascii_start().first(), Some(b'{' | b'[')) =>
+        {
+            sniff_markup(&tokio::fs::read(&path).await?)
+        }
+        markup => markup,
+    };
+
+    // Error pages are a common reason for media to not be what was promised.
+    // If either the server or the URL claimed media, don't upload one.
+    if let Some(found) = markup
+        && let Some(expected) = [declared_type, extension_type]
+            .into_iter()
+            .flatten()
+            .find(is_media_type)
+    {
+        return Err(UnexpectedContent { expected, found }.into());
+    }
 
     // Sniff MIME type from content
-    if let Some(kind) = infer::get(&data) {
+    if let Some(kind) = infer::get(&head) {
         debug!("Sniffed MIME type from content: {}", kind.mime_type());
         if let Ok(sniffed) = kind.mime_type().parse::<Mime>() {
             mime_type = sniffed;
@@ -173,20 +1120,52 @@ pub async fn process_response(
 
     debug!("Final MIME type: {}", mime_type);
 
-    // Remux Matroska video to MP4 for better client compatibility
-    if mime_type == "video/x-matroska" {
-        match remux_to_mp4(&data).await {
-            Ok(mp4_data) => {
-                info!("Successfully remuxed MKV to MP4");
-                data = mp4_data;
-                mime_type = "video/mp4".parse().unwrap();
+    if !config.is_media_type_allowed(&mime_type) {
+        return Err(DisallowedMediaType { mime_type }.into());
+    }
+
+    let is_video = mime_type.type_() == mime_guess::mime::VIDEO;
+    let transcode_started = Instant::now();
+    if video.reencode_all && is_video {
+        let encoded = workdir.path().join("encoded");
+        let result = encode_video(&path, &encoded, &video.encode).await;
+        metrics().record_step(Step::Transcode, "video", transcode_started.elapsed());
+        match result {
+            Ok(encoded_type) => {
+                path = encoded;
+                mime_type = encoded_type;
+            }
+            Err(e) => {
+                warn!("Failed to reencode video, using original: {:?}", e);
+            }
+        }
+    } else if is_video && needs_remux(&mime_type, video.format) {
+        let remuxed = workdir.path().join("remuxed");
+        let result = remux_video(&path, &remuxed, video.format, &video.encode).await;
+        metrics().record_step(Step::Transcode, "video", transcode_started.elapsed());
+        match result {
+            Ok(remuxed_type) => {
+                info!("Successfully remuxed {} to {}", mime_type, remuxed_type);
+                path = remuxed;
+                mime_type = remuxed_type;
             }
             Err(e) => {
-                warn!("Failed to remux MKV to MP4, using original: {:?}", e);
+                warn!("Failed to remux video, using original: {:?}", e);
             }
         }
     }
 
+    if transcribe::is_enabled(config)
+        && (mime_type.type_() == mime_guess::mime::AUDIO
+            || mime_type.type_() == mime_guess::mime::VIDEO)
+    {
+        match transcribe::transcribe(client, config, &path).await {
+            Ok(Some(transcript)) => text = Some(transcribe::append_transcript(text, &transcript)),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to transcribe media: {:?}", e),
+        }
+    }
+
     let mime_extensions = mime_guess::get_mime_extensions(&mime_type);
     let preferred_extension = mime_extensions
         .and_then(|exts| exts.first())
@@ -198,29 +1177,56 @@ pub async fn process_response(
         debug!("No preferred extension found for MIME type: {}", mime_type);
     }
 
-    let mut attachment_config = AttachmentConfig::new();
+    let mut attachment_info = None;
+    let mut attachment_thumbnail = None;
+
+    let data = tokio::fs::read(&path)
+        .await
+        .context("Failed to read downloaded media")?;
 
-    match probe_media(&data).await {
+    let probe_started = Instant::now();
+    let probed = probe_media(&path).await;
+    metrics().record_step(
+        Step::Probe,
+        media_label(&mime_type),
+        probe_started.elapsed(),
+    );
+    match probed {
         Ok(info) => {
             debug!("Dimensions: {}x{}", info.width, info.height);
 
             let mut thumbnail_data = None;
             let mut blurhash = None;
 
-            if let Ok(thumb) = generate_thumbnail(&data, 600).await {
-                debug!("Thumbnail generated");
+            // Only the thumbnail and blurhash are skipped for huge media;
+            // it's still posted.
+            if !config.image_limits.allows(info.width, info.height) {
+                warn!(
+                    "Not thumbnailing {}x{} media: over the image size limit",
+                    info.width, info.height
+                );
+            } else {
+                let thumbnail_started = Instant::now();
+                if let Ok(thumb) = generate_thumbnail(&path, 600).await {
+                    debug!("Thumbnail generated");
+
+                    if let Ok(bh) = generate_blurhash(&thumb, &config.image_limits).await {
+                        debug!("Blurhash: {}", bh.clone());
+                        blurhash = Some(bh);
+                    }
 
-                if let Ok(bh) = generate_blurhash(&thumb) {
-                    debug!("Blurhash: {}", bh.clone());
-                    blurhash = Some(bh);
+                    thumbnail_data = Some(thumb);
                 }
-
-                thumbnail_data = Some(thumb);
+                metrics().record_step(
+                    Step::Thumbnail,
+                    media_label(&mime_type),
+                    thumbnail_started.elapsed(),
+                );
             }
 
             if let Some(thumb) = thumbnail_data {
                 let thumb_mime: Mime = "image/webp".parse().unwrap();
-                let (thumb_width, thumb_height) = if let Ok(info) = probe_media(&thumb).await {
+                let (thumb_width, thumb_height) = if let Ok(info) = image_dimensions(&thumb) {
                     (Some(info.width.into()), Some(info.height.into()))
                 } else {
                     (None, None)
@@ -234,7 +1240,7 @@ pub async fn process_response(
                         height: h,
                         size: (thumb.len() as u32).into(),
                     };
-                    attachment_config = attachment_config.thumbnail(Some(thumbnail));
+                    attachment_thumbnail = Some(thumbnail);
                     debug!("Thumbnail added");
                 }
             }
@@ -242,30 +1248,24 @@ pub async fn process_response(
             // Add the info to the specific config type
             if mime_type.type_() == mime_guess::mime::IMAGE {
                 let is_animated = probe_is_animated(&data);
-                attachment_config = attachment_config.info(
-                    matrix_sdk::attachment::AttachmentInfo::Image(BaseImageInfo {
-                        width: Some(info.width.into()),
-                        height: Some(info.height.into()),
-                        blurhash,
-                        is_animated,
-                        ..Default::default()
-                    }),
-                );
+                attachment_info = Some(AttachmentInfo::Image(BaseImageInfo {
+                    width: Some(info.width.into()),
+                    height: Some(info.height.into()),
+                    blurhash,
+                    is_animated,
+                    ..Default::default()
+                }));
             } else if mime_type.type_() == mime_guess::mime::VIDEO {
-                attachment_config = attachment_config.info(
-                    matrix_sdk::attachment::AttachmentInfo::Video(BaseVideoInfo {
-                        width: Some(info.width.into()),
-                        height: Some(info.height.into()),
-                        blurhash,
-                        ..Default::default()
-                    }),
-                );
+                attachment_info = Some(AttachmentInfo::Video(BaseVideoInfo {
+                    width: Some(info.width.into()),
+                    height: Some(info.height.into()),
+                    blurhash,
+                    ..Default::default()
+                }));
             } else if mime_type.type_() == mime_guess::mime::AUDIO {
-                attachment_config = attachment_config.info(
-                    matrix_sdk::attachment::AttachmentInfo::Audio(BaseAudioInfo {
-                        ..Default::default()
-                    }),
-                );
+                attachment_info = Some(AttachmentInfo::Audio(BaseAudioInfo {
+                    ..Default::default()
+                }));
             }
         }
         Err(e) => {
@@ -331,38 +1331,198 @@ pub async fn process_response(
         debug!("Using fallback filename: {}", filename);
     }
 
-    if let Some(caption) = text {
-        attachment_config = attachment_config.caption(Some(caption));
-    }
-
     Ok(AttachmentData {
         filename,
         mime_type,
         data,
-        attachment_config,
+        info: attachment_info,
+        thumbnail: attachment_thumbnail,
+        caption: text,
     })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use wiremock::matchers::method;
-    use wiremock::{Mock, MockServer, ResponseTemplate};
+/// Stream `response` into `file`, decoding any `Content-Encoding`, resuming
+/// with Range requests when the connection drops and giving up on transfers
+/// that are too large (once decoded) or too slow. `downloaded` counts bytes
+/// received and is kept up to date so it's accurate even on failure.
+async fn download_body(
+    mut response: reqwest::Response,
+    resume_request: Option<reqwest::RequestBuilder>,
+    config: &Config,
+    file: &mut std::fs::File,
+    downloaded: &mut u64,
+) -> Result<()> {
+    let mut decoder = BodyDecoder::new(
+        response.headers(),
+        file,
+        config.max_file_size,
+        config.max_decompression_ratio,
+    )?;
+    let accepts_ranges = accepts_byte_ranges(response.headers());
+    let validator = resume_validator(response.headers());
+    let started = Instant::now();
+    let mut resume_attempts = 0;
 
-    #[test]
-    fn test_process_metadata() {
-        let meta = Metadata {
-            card: Some("summary_large_image".to_string()),
-            title: Some("Test Title".to_string()),
-            description: Some("Test Description".to_string()),
+    loop {
+        // Wake up periodically even if nothing arrives, so a stalled
+        // connection is noticed before the overall timeout.
+        let chunk = match tokio::time::timeout(SPEED_CHECK_INTERVAL, response.chunk()).await {
+            Err(_) => {
+                check_download_speed(*downloaded, started.elapsed(), config)?;
+                continue;
+            }
+            Ok(Ok(Some(chunk))) => chunk,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                let request = resume_request
+                    .as_ref()
+                    .filter(|_| accepts_ranges && resume_attempts < config.download_resume_attempts)
+                    .and_then(|r| r.try_clone());
+                let Some(request) = request else {
+                    return Err(e.into());
+                };
+                resume_attempts += 1;
+                warn!(
+                    "Download interrupted after {} bytes, resuming (attempt {}/{}): {}",
+                    downloaded, resume_attempts, config.download_resume_attempts, e
+                );
+                response = resume_download(request, *downloaded, validator.as_ref()).await?;
+                continue;
+            }
+        };
+
+        *downloaded += chunk.len() as u64;
+        decoder.write(&chunk)?;
+        check_download_speed(*downloaded, started.elapsed(), config)?;
+    }
+
+    decoder.finish()?;
+    Ok(())
+}
+
+/// Fail if, after the grace period, the average speed of a download is below
+/// the configured minimum.
+fn check_download_speed(
+    downloaded: u64,
+    elapsed: Duration,
+    config: &Config,
+) -> Result<(), DownloadTooSlow> {
+    if config.min_download_speed == 0 || elapsed < config.slow_download_grace {
+        return Ok(());
+    }
+    let bytes_per_second = (downloaded as f64 / elapsed.as_secs_f64()) as u64;
+    if bytes_per_second < config.min_download_speed {
+        return Err(DownloadTooSlow { bytes_per_second });
+    }
+    Ok(())
+}
+
+/// Returns `true` if the server advertises `Accept-Ranges: bytes`.
+fn accepts_byte_ranges(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT_RANGES)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("bytes"))
+}
+
+/// Strong validator to send as `If-Range`, so a resumed download fails
+/// instead of splicing together two different versions of the file.
+fn resume_validator(headers: &HeaderMap) -> Option<HeaderValue> {
+    headers
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .cloned()
+}
+
+/// Read up to `limit` bytes from the start of the file at `path`.
+fn read_head(path: &std::path::Path, limit: u64) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    std::fs::File::open(path)
+        .and_then(|file| file.take(limit).read_to_end(&mut head))
+        .context("Failed to read downloaded media")?;
+    Ok(head)
+}
+
+/// Parse the first byte position from a `Content-Range: bytes a-b/len` header.
+fn content_range_start(value: &str) -> Option<u64> {
+    let range = value.trim().strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Parse the complete length from a `Content-Range: bytes a-b/len` header.
+fn content_range_total(value: &str) -> Option<u64> {
+    let (_, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    total.trim().parse().ok()
+}
+
+/// Re-send `request` asking for the bytes from `offset` onwards.
+async fn resume_download(
+    request: reqwest::RequestBuilder,
+    offset: u64,
+    validator: Option<&HeaderValue>,
+) -> Result<reqwest::Response> {
+    let mut request = request.header(RANGE, format!("bytes={}-", offset));
+    if let Some(validator) = validator {
+        request = request.header(IF_RANGE, validator.clone());
+    }
+    let response = request
+        .send()
+        .await
+        .context("Failed to send resume request")?;
+
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        bail!(
+            "Server did not resume the download (status {})",
+            response.status()
+        );
+    }
+    let start = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(content_range_start);
+    if start != Some(offset) {
+        bail!(
+            "Server resumed the download at {:?} instead of {}",
+            start,
+            offset
+        );
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn times() -> TimeFormat {
+        Config::default().time_format(None, None)
+    }
+
+    /// The link the test metadata was fetched from.
+    fn page() -> Url {
+        Url::parse("https://example.com/page").unwrap()
+    }
+
+    #[test]
+    fn test_process_metadata() {
+        let meta = Metadata {
+            card: Some("summary_large_image".to_string()),
+            title: Some("Test Title".to_string()),
+            description: Some("Test Description".to_string()),
             image_url: None,
             video_url: Some(Url::parse("https://example.com/video.mp4").unwrap()),
             audio_url: None,
+            ..Default::default()
         };
 
-        let params = process_metadata(meta, &Config::default());
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
 
-        assert_eq!(params.body, "Test Title: Test Description");
+        assert_eq!(params.body, "Test Title\nTest Description");
         assert!(params.html_body.contains("<strong>Test Title</strong>"));
         assert!(params.html_body.contains("<p>Test Description</p>"));
         assert_eq!(
@@ -371,6 +1531,534 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_process_metadata_published() {
+        let meta = Metadata {
+            title: Some("News".to_string()),
+            published: chrono::DateTime::parse_from_rfc3339("2025-01-15T12:00:00Z").ok(),
+            ..Default::default()
+        };
+        let times = TimeFormat {
+            timezone: chrono_tz::Asia::Tokyo,
+            ..times()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times, false);
+        assert_eq!(params.body, "News\n\nPublished: Wed Jan 15 2025, 21:00 JST");
+    }
+
+    #[test]
+    fn test_process_metadata_description_html() {
+        let meta = Metadata {
+            title: Some("Alice".to_string()),
+            description: Some("Hello world\nexample.com".to_string()),
+            description_html: Some(
+                r#"<p>Hello <b>world</b><script>x</script></p><p><a href="https://example.com/">example.com</a></p>"#
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+        assert_eq!(params.body, "Alice\nHello world\nexample.com");
+        assert_eq!(
+            params.html_body,
+            r#"<blockquote><strong>Alice</strong><p>Hello <b>world</b><br/><a href="https://example.com/">example.com</a></p></blockquote>"#
+        );
+    }
+
+    #[test]
+    fn test_process_metadata_image_alt() {
+        let image_url = Url::parse("https://example.com/cat.jpg").unwrap();
+
+        // Uncaptioned image: alt text becomes the body.
+        let meta = Metadata {
+            image_url: Some(image_url.clone()),
+            image_alt: Some("A cat".to_string()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+        assert_eq!(params.alt_text.as_deref(), Some("A cat"));
+        assert!(params.body.is_empty());
+
+        // Captioned image: alt text goes into the caption.
+        let meta = Metadata {
+            title: Some("Cats".to_string()),
+            image_url: Some(image_url.clone()),
+            image_alt: Some("A cat".to_string()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+        assert_eq!(params.alt_text, None);
+        assert_eq!(params.body, "Cats\n\nImage description: A cat");
+
+        // Video with a poster image: alt text goes into the caption.
+        let meta = Metadata {
+            image_url: Some(image_url),
+            image_alt: Some("A cat".to_string()),
+            video_url: Some(Url::parse("https://example.com/cat.mp4").unwrap()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+        assert_eq!(params.alt_text, None);
+        assert_eq!(params.body, "Image description: A cat");
+        assert!(
+            params
+                .html_body
+                .contains("<p><em>Image description:</em> A cat</p>")
+        );
+    }
+
+    #[test]
+    fn test_looks_like_embed() {
+        let (_, html) = format_caption(Some("Title"), Some("Description"), None, &[], None, false);
+        assert!(looks_like_embed(&html));
+        let (_, html) = format_caption(None, Some("Description"), None, &[], None, true);
+        assert!(looks_like_embed(&html));
+        assert!(looks_like_embed(&format!(
+            "<mx-reply><blockquote>quoted</blockquote></mx-reply>{}",
+            html
+        )));
+        assert!(looks_like_embed(&inline_image_html(
+            "mxc://example.com/abc",
+            "cat.jpg",
+            Some(&html)
+        )));
+
+        assert!(!looks_like_embed(
+            "<blockquote>\n<p>Someone said this</p>\n</blockquote>\n<p>and I agree</p>"
+        ));
+        assert!(!looks_like_embed(
+            "see <a href=\"https://example.com\">this</a>"
+        ));
+    }
+
+    #[test]
+    fn test_inline_image_html() {
+        let (_, caption) = format_caption(Some("Cats"), None, None, &[], None, true);
+        assert_eq!(
+            inline_image_html("mxc://example.com/abc", "A \"cat\"", Some(&caption)),
+            "<img src=\"mxc://example.com/abc\" alt=\"A &quot;cat&quot;\"/>\
+             <br/><blockquote><strong>Cats</strong></blockquote>"
+        );
+        assert_eq!(
+            inline_image_html("mxc://example.com/abc", "cat.jpg", None),
+            "<img src=\"mxc://example.com/abc\" alt=\"cat.jpg\"/>"
+        );
+    }
+
+    #[test]
+    fn test_select_rendition() {
+        let rendition = |name: &str, height, size| Rendition {
+            url: Url::parse(&format!("https://example.com/{}.mp4", name)).unwrap(),
+            height: Some(height),
+            size,
+        };
+        let renditions = [
+            rendition("1080", 1080, Some(80_000_000)),
+            rendition("720", 720, Some(40_000_000)),
+            rendition("480", 480, None),
+            rendition("240", 240, Some(5_000_000)),
+        ];
+        let select = |max_size, max_height| {
+            select_rendition(&renditions, max_size, max_height).map(|r| r.height.unwrap())
+        };
+
+        assert_eq!(select(100_000_000, None), Some(1080));
+        assert_eq!(select(50_000_000, None), Some(720));
+        // A known fit beats one that might not.
+        assert_eq!(select(50_000_000, Some(480)), Some(240));
+        assert_eq!(select(10_000_000, None), Some(240));
+        // Nothing within the height preference: the shortest will do.
+        assert_eq!(select(100_000_000, Some(144)), Some(240));
+        assert_eq!(select_rendition(&renditions[..2], 1_000_000, None), None);
+        assert_eq!(select_rendition(&[], 1_000_000, None), None);
+    }
+
+    #[test]
+    fn test_upgrade_image_url() {
+        let config = Config::default();
+        let image_url = Url::parse("https://pbs.twimg.com/media/AbC123.jpg").unwrap();
+        let mut meta = Metadata {
+            image_url: Some(image_url.clone()),
+            ..Default::default()
+        };
+        upgrade_image_url(&mut meta, &config);
+        assert_eq!(
+            meta.image_url.as_ref().unwrap().as_str(),
+            "https://pbs.twimg.com/media/AbC123.jpg?name=orig"
+        );
+        assert_eq!(meta.original_image_url.as_ref(), Some(&image_url));
+
+        let params = process_metadata(meta.clone(), &page(), &config, &times(), false);
+        assert_eq!(params.media_url, meta.image_url);
+        assert_eq!(params.fallback_image_url, Some(image_url));
+
+        // Only the attached image falls back; a poster frame doesn't.
+        meta.video_url = Some(Url::parse("https://video.twimg.com/clip.mp4").unwrap());
+        let params = process_metadata(meta, &page(), &config, &times(), false);
+        assert_eq!(params.fallback_image_url, None);
+
+        let mut meta = Metadata {
+            image_url: Some(Url::parse("https://example.com/cat.jpg").unwrap()),
+            ..Default::default()
+        };
+        upgrade_image_url(&mut meta, &config);
+        assert_eq!(meta.original_image_url, None);
+    }
+
+    #[test]
+    fn test_process_metadata_gallery() {
+        let image = |name: &str| GalleryImage {
+            url: Url::parse(&format!("https://example.com/{}.jpg", name)).unwrap(),
+            alt: None,
+        };
+        let meta = Metadata {
+            image_url: Some(image("1").url),
+            gallery: vec![image("2"), image("3"), image("4")],
+            ..Default::default()
+        };
+        let config = Config {
+            gallery_max_images: 2,
+            ..Default::default()
+        };
+
+        let params = process_metadata(meta.clone(), &page(), &config, &times(), false);
+        assert_eq!(params.gallery, vec![image("2"), image("3")]);
+        assert_eq!(params.gallery_rest, vec![image("4")]);
+
+        // A summary card has no media, so no gallery either.
+        let meta = Metadata {
+            card: Some("summary".to_string()),
+            ..meta
+        };
+        let params = process_metadata(meta.clone(), &page(), &config, &times(), false);
+        assert!(params.gallery.is_empty());
+        assert!(params.gallery_rest.is_empty());
+
+        // Nor when its image is posted as a thumbnail.
+        let params = process_metadata(meta, &page(), &config, &times(), true);
+        assert!(params.gallery.is_empty());
+        assert!(params.gallery_rest.is_empty());
+    }
+
+    #[test]
+    fn test_process_metadata_summary_thumbnail() {
+        let meta = Metadata {
+            card: Some("summary".to_string()),
+            title: Some("Post".to_string()),
+            image_url: Some(Url::parse("https://example.com/square.png").unwrap()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta.clone(), &page(), &Config::default(), &times(), false);
+        assert_eq!(params.media_url, None);
+        assert!(!params.media_is_thumbnail);
+        assert!(!params.html_body.starts_with("<br/>"));
+
+        let params = process_metadata(meta.clone(), &page(), &Config::default(), &times(), true);
+        assert_eq!(params.media_url, meta.image_url);
+        assert!(params.media_is_thumbnail);
+        assert!(params.html_body.starts_with("<br/>"));
+
+        // Other cards are unaffected.
+        let meta = Metadata {
+            card: Some("summary_large_image".to_string()),
+            ..meta
+        };
+        let params = process_metadata(meta.clone(), &page(), &Config::default(), &times(), true);
+        assert_eq!(params.media_url, meta.image_url);
+        assert!(!params.media_is_thumbnail);
+    }
+
+    #[test]
+    fn test_process_metadata_media_order() {
+        let meta = Metadata {
+            canonical_url: Some(Url::parse("https://artist.bandcamp.com/track/song").unwrap()),
+            image_url: Some(Url::parse("https://example.com/cover.jpg").unwrap()),
+            video_url: Some(Url::parse("https://example.com/video.mp4").unwrap()),
+            audio_url: Some(Url::parse("https://example.com/song.mp3").unwrap()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta.clone(), &page(), &Config::default(), &times(), false);
+        assert_eq!(params.media_url, meta.video_url);
+        assert_eq!(params.poster_url, meta.image_url);
+
+        let config = Config {
+            domain_media_orders: vec![(
+                "bandcamp.com".to_string(),
+                vec![MediaKind::Audio, MediaKind::Image],
+            )],
+            ..Default::default()
+        };
+        let params = process_metadata(meta.clone(), &page(), &config, &times(), false);
+        assert_eq!(params.media_url, meta.audio_url);
+        assert!(!params.media_is_video);
+
+        // Without a canonical URL, the order for the link itself is used.
+        let bandcamp = Url::parse("https://artist.bandcamp.com/track/song").unwrap();
+        let uncanonical = Metadata {
+            canonical_url: None,
+            ..meta.clone()
+        };
+        let params = process_metadata(uncanonical.clone(), &bandcamp, &config, &times(), false);
+        assert_eq!(params.media_url, meta.audio_url);
+        let params = process_metadata(uncanonical, &page(), &config, &times(), false);
+        assert_eq!(params.media_url, meta.video_url);
+
+        // Kinds left out of the order are never attached.
+        let config = Config {
+            media_order: vec![MediaKind::Video],
+            ..Default::default()
+        };
+        let meta = Metadata {
+            video_url: None,
+            ..meta
+        };
+        let params = process_metadata(meta, &page(), &config, &times(), false);
+        assert_eq!(params.media_url, None);
+    }
+
+    #[cfg(feature = "media")]
+    #[tokio::test]
+    async fn test_shrink_to_thumbnail() {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::from(image::RgbImage::new(1200, 1200))
+            .write_to(&mut data, image::ImageFormat::Png)
+            .unwrap();
+        let attachment = AttachmentData {
+            filename: "cover.png".to_string(),
+            mime_type: mime_guess::mime::IMAGE_PNG,
+            data: data.into_inner(),
+            info: Some(AttachmentInfo::Image(BaseImageInfo {
+                width: Some(1200u32.into()),
+                height: Some(1200u32.into()),
+                blurhash: Some("LKO2?U%2Tw=w]~RBVZRi};RPxuwH".to_string()),
+                ..Default::default()
+            })),
+            thumbnail: None,
+            caption: None,
+        };
+        let attachment = shrink_to_thumbnail(attachment, &Config::default()).await;
+        assert_eq!(attachment.filename, "cover.jpg");
+        assert_eq!(attachment.mime_type, mime_guess::mime::IMAGE_JPEG);
+        let Some(AttachmentInfo::Image(info)) = attachment.info else {
+            panic!("expected image info");
+        };
+        assert_eq!(info.width, Some(SUMMARY_THUMBNAIL_SIZE.into()));
+        assert!(info.blurhash.is_some());
+    }
+
+    #[test]
+    fn test_oversized_video_note() {
+        let url = Url::parse("https://example.com/v.mp4?a=1&b=2").unwrap();
+        let too_large = FileTooLarge {
+            size: 150 * 1024 * 1024,
+            streamed: false,
+        };
+        let (body, html) = oversized_video_note(&url, &too_large, Some(272));
+        assert_eq!(
+            body,
+            "Video too large to upload (150.0 MB, 4:32): https://example.com/v.mp4?a=1&b=2"
+        );
+        assert_eq!(
+            html,
+            "<p><em>Video too large to upload (150.0 MB, 4:32):</em> \
+             <a href=\"https://example.com/v.mp4?a=1&amp;b=2\">https://example.com/v.mp4?a=1&amp;b=2</a></p>"
+        );
+
+        let too_large = FileTooLarge {
+            size: 2048,
+            streamed: true,
+        };
+        let (body, _) = oversized_video_note(&url, &too_large, None);
+        assert!(body.starts_with("Video too large to upload (over 2.0 KB): "));
+    }
+
+    #[test]
+    fn test_process_metadata_with_url_warning() {
+        let meta = Metadata {
+            title: Some("Log in".to_string()),
+            url_warning: Some("Suspicious domain".to_string()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+        assert_eq!(params.body, "Log in\n\nWarning: Suspicious domain");
+    }
+
+    #[test]
+    fn test_reply_fallback() {
+        let (plain, html) = reply_fallback(
+            "!room:example.com",
+            "$event",
+            "@alice:example.com",
+            "look at this\nhttps://example.com/<x>",
+        );
+        assert_eq!(
+            plain,
+            "> <@alice:example.com> look at this\n> https://example.com/<x>\n\n"
+        );
+        assert_eq!(
+            html,
+            "<mx-reply><blockquote>\
+             <a href=\"https://matrix.to/#/!room:example.com/$event\">In reply to</a> \
+             <a href=\"https://matrix.to/#/@alice:example.com\">@alice:example.com</a><br/>\
+             look at this<br/>https://example.com/&lt;x&gt;\
+             </blockquote></mx-reply>"
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(5), "0:05");
+        assert_eq!(format_duration(3723), "1:02:03");
+    }
+
+    #[test]
+    fn test_process_metadata_source() {
+        let meta = Metadata {
+            title: Some("Launch day".to_string()),
+            description: Some("We're live.".to_string()),
+            site_name: Some("Example News".to_string()),
+            canonical_url: Some(Url::parse("https://example.com/launch?a=1&b=2").unwrap()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+        assert_eq!(
+            params.body,
+            "Launch day\nWe're live.\n\nExample News · https://example.com/launch?a=1&b=2"
+        );
+        assert_eq!(
+            params.html_body,
+            r#"<blockquote><strong>Launch day</strong><p>We're live.</p><p><a href="https://example.com/launch?a=1&amp;b=2">Example News</a></p></blockquote>"#
+        );
+
+        // Without a site name, the link is its own label.
+        let meta = Metadata {
+            title: Some("Launch day".to_string()),
+            canonical_url: Some(Url::parse("https://xn--bcher-kva.example/").unwrap()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+        assert_eq!(params.body, "Launch day\n\nhttps://bücher.example/");
+        assert!(params.html_body.ends_with(
+            r#"<p><a href="https://xn--bcher-kva.example/">https://bücher.example/</a></p></blockquote>"#
+        ));
+
+        // A link alone doesn't make a caption.
+        let meta = Metadata {
+            image_url: Some(Url::parse("https://example.com/cat.jpg").unwrap()),
+            canonical_url: Some(Url::parse("https://example.com/cat").unwrap()),
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+        assert!(params.body.is_empty());
+        assert!(params.html_body.is_empty());
+    }
+
+    #[test]
+    fn test_process_metadata_emotes() {
+        let meta = Metadata {
+            title: Some("Alice :blobcat:".to_string()),
+            description: Some("hello :blobcat:".to_string()),
+            emotes: vec![crate::metadata::Emote {
+                shortcode: "blobcat".to_string(),
+                url: Url::parse("https://example.com/emoji/blobcat.png").unwrap(),
+                mxc_uri: Some("mxc://example.com/blobcat".to_string()),
+            }],
+            ..Default::default()
+        };
+
+        let params = process_metadata(meta.clone(), &page(), &Config::default(), &times(), false);
+        assert_eq!(params.body, "Alice\nhello");
+
+        let config = Config {
+            custom_emotes: EmoteMode::Inline,
+            ..Default::default()
+        };
+        let params = process_metadata(meta, &page(), &config, &times(), false);
+        assert_eq!(params.body, "Alice :blobcat:\nhello :blobcat:");
+        assert!(params.html_body.starts_with(
+            r#"<blockquote><strong>Alice <img data-mx-emoticon src="mxc://example.com/blobcat""#
+        ));
+    }
+
+    #[test]
+    fn test_process_metadata_splits_long_caption() {
+        let description: String = (0..10_000).map(|i| format!("w{i} ")).collect();
+        let meta = Metadata {
+            title: Some("Thread".to_string()),
+            description: Some(description.clone()),
+            summary: Some("Short".to_string()),
+            image_url: Some(Url::parse("https://example.com/cat.jpg").unwrap()),
+            ..Default::default()
+        };
+        let config = Config {
+            max_embed_description_chars: 100_000,
+            ..Default::default()
+        };
+
+        let params = process_metadata(meta, &page(), &config, &times(), false);
+
+        assert!(params.body.starts_with("Thread\nw0 w1 "));
+        assert!(params.body.len() < 300);
+        let kept = params.body.split('…').next().unwrap();
+        let last: usize = kept.rsplit_once('w').unwrap().1.parse().unwrap();
+        assert!(
+            params
+                .html_body
+                .starts_with("<br/><blockquote><strong>Thread</strong>")
+        );
+        let (body, html_body) = params.continuation.unwrap();
+        assert!(body.starts_with(&format!("…w{} w{} ", last + 1, last + 2)));
+        assert!(body.ends_with("…\n\nSummary: Short"));
+        assert!(body.len() + html_body.len() < MAX_CAPTION_BYTES);
+        assert!(html_body.starts_with(&format!("<blockquote><p>…w{} ", last + 1)));
+
+        let meta = Metadata {
+            title: Some("Short".to_string()),
+            description: Some(description[..100].to_string()),
+            ..Default::default()
+        };
+        assert!(
+            process_metadata(meta, &page(), &config, &times(), false)
+                .continuation
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_process_metadata_player_link() {
+        let meta = Metadata {
+            title: Some("Clip".to_string()),
+            player_url: Some(Url::parse("https://video.example.com/embed/123").unwrap()),
+            ..Default::default()
+        };
+
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+
+        assert_eq!(params.media_url, None);
+        assert_eq!(
+            params.body,
+            "Clip\n\nPlayer: https://video.example.com/embed/123"
+        );
+    }
+
+    #[test]
+    fn test_process_metadata_with_summary() {
+        let meta = Metadata {
+            title: Some("Article".to_string()),
+            summary: Some("It is <short>.".to_string()),
+            ..Default::default()
+        };
+
+        let params = process_metadata(meta, &page(), &Config::default(), &times(), false);
+
+        assert_eq!(params.body, "Article\n\nSummary: It is <short>.");
+        assert!(
+            params
+                .html_body
+                .contains("<p><em>Summary:</em> It is &lt;short&gt;.</p>")
+        );
+    }
+
     #[tokio::test]
     async fn test_process_response_video() {
         let mock_server = MockServer::start().await;
@@ -398,7 +2086,8 @@ mod tests {
             ..Config::default()
         };
 
-        let attachment = process_response(response, &config, None)
+        let video = config.video_target(None, false);
+        let attachment = process_response(&client, response, None, None, &config, &video, None)
             .await
             .expect("Failed to process response");
 
@@ -406,6 +2095,265 @@ mod tests {
         assert_eq!(attachment.filename, "media.webm");
     }
 
+    #[tokio::test]
+    async fn test_process_response_disallowed_type() {
+        let mock_server = MockServer::start().await;
+
+        // A zip archive served as an image.
+        let mut body = b"PK\x03\x04".to_vec();
+        body.resize(64, 0);
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Content-Type", "image/png")
+                    .set_body_bytes(body),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let response = client.get(mock_server.uri()).send().await.unwrap();
+
+        let config = Config::default();
+        let video = config.video_target(None, false);
+        let err = process_response(&client, response, None, None, &config, &video, None)
+            .await
+            .err()
+            .expect("zip archive should be rejected");
+        let err = err.downcast_ref::<DisallowedMediaType>().unwrap();
+        assert_eq!(err.mime_type.essence_str(), "application/zip");
+    }
+
+    #[tokio::test]
+    async fn test_process_response_error_page() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Content-Type", "application/octet-stream")
+                    .set_body_string("\n<!DOCTYPE html><html><body>Not found</body></html>"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let url = format!("{}/clip.mp4", mock_server.uri());
+        let response = client.get(url).send().await.unwrap();
+
+        let config = Config::default();
+        let video = config.video_target(None, false);
+        let err = process_response(&client, response, None, None, &config, &video, None)
+            .await
+            .err()
+            .expect("HTML page should be rejected");
+        let err = err.downcast_ref::<UnexpectedContent>().unwrap();
+        assert_eq!(err.expected.essence_str(), "video/mp4");
+        assert_eq!(err.found, "an HTML page");
+    }
+
+    #[test]
+    fn test_sniff_markup() {
+        assert_eq!(
+            sniff_markup(b"\xef\xbb\xbf  <HTML lang=en>"),
+            Some("an HTML page")
+        );
+        assert_eq!(
+            sniff_markup(br#"{"error": "forbidden"}"#),
+            Some("a JSON document")
+        );
+        assert_eq!(sniff_markup(b"{not json"), None);
+        assert_eq!(
+            sniff_markup(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+            None
+        );
+        assert_eq!(sniff_markup(b""), None);
+    }
+
+    #[test]
+    fn test_check_media_headers() {
+        let config = Config {
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let url = Url::parse("https://example.com/clip.mp4").unwrap();
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        assert!(check_media_headers(&url, &headers(&[]), &config).is_ok());
+        assert!(
+            check_media_headers(
+                &url,
+                &headers(&[("content-type", "video/mp4"), ("content-length", "512")]),
+                &config
+            )
+            .is_ok()
+        );
+        // Generic types are left for sniffing after the download.
+        assert!(
+            check_media_headers(
+                &url,
+                &headers(&[("content-type", "application/octet-stream")]),
+                &config
+            )
+            .is_ok()
+        );
+
+        let err = check_media_headers(&url, &headers(&[("content-length", "4096")]), &config)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<FileTooLarge>().unwrap().size, 4096);
+
+        let err = check_media_headers(
+            &url,
+            &headers(&[("content-type", "image/x-icon")]),
+            &Config {
+                allowed_media_types: vec!["video".to_string()],
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(err.is::<DisallowedMediaType>());
+
+        let err = check_media_headers(
+            &url,
+            &headers(&[("content-type", "text/html; charset=utf-8")]),
+            &config,
+        )
+        .unwrap_err();
+        let err = err.downcast_ref::<UnexpectedContent>().unwrap();
+        assert_eq!(err.expected.essence_str(), "video/mp4");
+        assert_eq!(err.found, "an HTML page");
+    }
+
+    #[tokio::test]
+    async fn test_precheck_media() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Content-Type", "video/mp4")
+                    .insert_header("Content-Length", "4096"),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let url = Url::parse(&format!("{}/clip.mp4", mock_server.uri())).unwrap();
+        let config = Config {
+            max_file_size: 1024,
+            ..Default::default()
+        };
+        let err = precheck_media(&client, &url, None, &config)
+            .await
+            .unwrap_err();
+        assert!(err.is::<FileTooLarge>());
+
+        // Servers that don't answer HEAD don't stop the download.
+        let unanswered = MockServer::start().await;
+        let url = Url::parse(&format!("{}/clip.mp4", unanswered.uri())).unwrap();
+        assert!(precheck_media(&client, &url, None, &config).await.is_ok());
+    }
+
+    #[test]
+    fn test_content_range_start() {
+        assert_eq!(content_range_start("bytes 1000-1999/2000"), Some(1000));
+        assert_eq!(content_range_start("bytes 0-0/*"), Some(0));
+        assert_eq!(content_range_start("bytes */2000"), None);
+        assert_eq!(content_range_start("items 1-2/3"), None);
+    }
+
+    #[test]
+    fn test_needs_remux() {
+        let mkv: Mime = "video/x-matroska".parse().unwrap();
+        let mp4: Mime = "video/mp4".parse().unwrap();
+        let webm: Mime = "video/webm".parse().unwrap();
+        assert!(needs_remux(&mkv, VideoFormat::Mp4));
+        assert!(!needs_remux(&mp4, VideoFormat::Mp4));
+        assert!(!needs_remux(&webm, VideoFormat::Mp4));
+        assert!(needs_remux(&mkv, VideoFormat::Webm));
+        assert!(needs_remux(&mp4, VideoFormat::Webm));
+        assert!(!needs_remux(&webm, VideoFormat::Webm));
+    }
+
+    #[test]
+    fn test_content_range_total() {
+        assert_eq!(content_range_total("bytes 0-999/2000"), Some(2000));
+        assert_eq!(content_range_total("bytes 0-999/*"), None);
+        assert_eq!(content_range_total("bytes */2000"), Some(2000));
+    }
+
+    #[test]
+    fn test_check_download_speed() {
+        let config = Config {
+            min_download_speed: 10_000,
+            slow_download_grace: Duration::from_secs(5),
+            ..Default::default()
+        };
+        // Slow, but still within the grace period.
+        assert!(check_download_speed(1_000, Duration::from_secs(4), &config).is_ok());
+        assert!(check_download_speed(100_000, Duration::from_secs(8), &config).is_ok());
+        let err = check_download_speed(8_000, Duration::from_secs(8), &config).unwrap_err();
+        assert_eq!(err.bytes_per_second, 1_000);
+
+        let config = Config {
+            min_download_speed: 0,
+            ..config
+        };
+        assert!(check_download_speed(0, Duration::from_secs(60), &config).is_ok());
+    }
+
+    #[test]
+    fn test_resume_validator() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LAST_MODIFIED,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        headers.insert(ETAG, "W/\"weak\"".parse().unwrap());
+        // Weak ETags can't be used with If-Range.
+        assert_eq!(
+            resume_validator(&headers).unwrap(),
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+        headers.insert(ETAG, "\"strong\"".parse().unwrap());
+        assert_eq!(resume_validator(&headers).unwrap(), "\"strong\"");
+        assert!(!accepts_byte_ranges(&headers));
+        headers.insert(ACCEPT_RANGES, "bytes".parse().unwrap());
+        assert!(accepts_byte_ranges(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_resume_download() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=4-"))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .insert_header("content-range", "bytes 4-7/8")
+                    .set_body_bytes(b"5678".to_vec()),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let response = resume_download(client.get(mock_server.uri()), 4, None)
+            .await
+            .unwrap();
+        assert_eq!(response.bytes().await.unwrap().as_ref(), b"5678");
+
+        // A server that starts over isn't accepted as a resume.
+        assert!(
+            resume_download(client.get(mock_server.uri()), 2, None)
+                .await
+                .is_err()
+        );
+    }
+
     #[test]
     fn test_truncate_text_no_op() {
         assert_eq!(truncate_text("hello", 640, 8), "hello");
@@ -439,7 +2387,22 @@ mod tests {
     #[test]
     fn test_truncate_text_char_limit_wins() {
         // 10 chars hits before 8 lines
-        assert_eq!(truncate_text("aaa\nbbb\nccc\nddd", 10, 8), "aaa\nbbb\ncc…");
+        assert_eq!(truncate_text("aaa\nbbb\nccc\nddd", 10, 8), "aaa\nbbb…");
+    }
+
+    #[test]
+    fn test_truncate_text_word_boundary() {
+        assert_eq!(
+            truncate_text("The quick brown fox jumps", 13, 8),
+            "The quick…"
+        );
+        // Punctuation after a whole word isn't a partial word.
+        assert_eq!(truncate_text("one two, three", 7, 8), "one two…");
+        // A single long word is cut rather than dropped.
+        assert_eq!(
+            truncate_text("a supercalifragilistic", 10, 8),
+            "a supercal…"
+        );
     }
 
     #[test]
@@ -463,5 +2426,13 @@ mod tests {
         assert_eq!(truncate_text("🎉🎊🎈🎁🎂🎄", 4, 8), "🎉🎊🎈🎁…");
         assert_eq!(truncate_text("café", 4, 8), "café");
         assert_eq!(truncate_text("café!", 4, 8), "café…");
+        // A combining accent stays with its letter.
+        assert_eq!(truncate_text("cafe\u{301}", 4, 8), "caf…");
+        // A ZWJ sequence is kept or dropped as a whole.
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(
+            truncate_text(&format!("{family}{family}"), 7, 8),
+            format!("{family}…")
+        );
     }
 }
@@ -0,0 +1,145 @@
+use anyhow::{Context, Result};
+use matrix_sdk::Client;
+use matrix_sdk::room::Room;
+use matrix_sdk::ruma::OwnedMxcUri;
+use matrix_sdk::ruma::events::room::member::{MembershipState, RoomMemberEventContent};
+use tracing::{error, info, warn};
+
+use crate::config::{Config, RoomProfile};
+use crate::db::Database;
+
+/// The profile overrides for `room_id`: those set with admin commands, then
+/// those from the config file.
+pub async fn room_overrides(
+    config: &Config,
+    database: &Database,
+    room_id: &str,
+) -> Result<RoomProfile> {
+    let stored = database.get_room_profile(room_id).await?;
+    Ok(match config.room_profiles.get(room_id) {
+        Some(configured) => stored.or(configured),
+        None => stored,
+    })
+}
+
+/// The bot's global display name and avatar.
+pub async fn global_profile(client: &Client) -> Result<RoomProfile> {
+    let account = client.account();
+    Ok(RoomProfile {
+        display_name: account
+            .get_display_name()
+            .await
+            .context("Failed to get display name")?,
+        avatar_url: account
+            .get_avatar_url()
+            .await
+            .context("Failed to get avatar URL")?
+            .map(|url| url.to_string()),
+    })
+}
+
+/// Update the bot's member event in `room` to show `profile`. Returns `false`
+/// if it already does.
+pub async fn apply(room: &Room, profile: &RoomProfile) -> Result<bool> {
+    let own_user_id = room.own_user_id();
+    if let Some(member) = room.get_member_no_sync(own_user_id).await? {
+        let current = RoomProfile {
+            display_name: member.display_name().map(str::to_owned),
+            avatar_url: member.avatar_url().map(|url| url.to_string()),
+        };
+        if current == *profile {
+            return Ok(false);
+        }
+    }
+
+    let mut content = RoomMemberEventContent::new(MembershipState::Join);
+    content.displayname = profile.display_name.clone();
+    content.avatar_url = profile.avatar_url.clone().map(OwnedMxcUri::from);
+    room.send_state_event_for_key(own_user_id, content)
+        .await
+        .context("Failed to update room member event")?;
+    info!(
+        "Updated profile in {}: {:?}",
+        room.room_id(),
+        profile.display_name
+    );
+    Ok(true)
+}
+
+/// Bring the bot's profile in `room` in line with its overrides, reverting to
+/// the global profile where there are none.
+pub async fn update_room(
+    client: &Client,
+    config: &Config,
+    database: &Database,
+    room: &Room,
+) -> Result<bool> {
+    let overrides = room_overrides(config, database, room.room_id().as_str()).await?;
+    let profile = overrides.or(&global_profile(client).await?);
+    apply(room, &profile).await
+}
+
+/// Re-apply profile overrides after the bot's own membership changed, e.g.
+/// when it joined a room or the homeserver propagated a global profile change.
+pub async fn handle_own_membership(
+    client: &Client,
+    config: &Config,
+    database: &Database,
+    room: &Room,
+    content: &RoomMemberEventContent,
+) {
+    let overrides = match room_overrides(config, database, room.room_id().as_str()).await {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            error!("Failed to look up profile for {}: {:?}", room.room_id(), e);
+            return;
+        }
+    };
+    if overrides.is_empty() {
+        return;
+    }
+
+    let shown = RoomProfile {
+        display_name: content.displayname.clone(),
+        avatar_url: content.avatar_url.as_ref().map(|url| url.to_string()),
+    };
+    let wanted_name =
+        overrides.display_name.is_none() || overrides.display_name == shown.display_name;
+    let wanted_avatar = overrides.avatar_url.is_none() || overrides.avatar_url == shown.avatar_url;
+    if wanted_name && wanted_avatar {
+        return;
+    }
+
+    if let Err(e) = update_room(client, config, database, room).await {
+        warn!("Failed to apply profile in {}: {:?}", room.room_id(), e);
+    }
+}
+
+/// Apply profile overrides in every joined room that has any.
+pub async fn apply_all(client: &Client, config: &Config, database: &Database) {
+    let global = match global_profile(client).await {
+        Ok(global) => global,
+        Err(e) => {
+            error!("Failed to get global profile: {:?}", e);
+            return;
+        }
+    };
+
+    for room in client.joined_rooms() {
+        let room_id = room.room_id().as_str();
+        if !config.shard.owns(room_id) {
+            continue;
+        }
+        let overrides = match room_overrides(config, database, room_id).await {
+            Ok(overrides) if overrides.is_empty() => continue,
+            Ok(overrides) => overrides,
+            Err(e) => {
+                error!("Failed to look up profile for {}: {:?}", room_id, e);
+                continue;
+            }
+        };
+        if let Err(e) = apply(&room, &overrides.or(&global)).await {
+            warn!("Failed to apply profile in {}: {:?}", room_id, e);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+use chrono::{DateTime, NaiveTime, TimeZone};
+
+/// A daily window in a room, in its local time, during which links aren't
+/// embedded as they're posted but queued until it ends, like for meetings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Post the queued links as one digest message when the window ends,
+    /// rather than embedding each of them.
+    pub digest: bool,
+}
+
+impl QuietHours {
+    /// Parse a window like `22:00-07:00`, which may run past midnight. A
+    /// window that starts when it ends is rejected rather than taken as
+    /// all day or never.
+    pub fn parse(window: &str, digest: bool) -> Option<QuietHours> {
+        let (start, end) = window.split_once('-')?;
+        let start = parse_time(start)?;
+        let end = parse_time(end)?;
+        (start != end).then_some(QuietHours { start, end, digest })
+    }
+
+    /// Whether `now` falls in the window, going by its local time.
+    pub fn contains<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        let time = now.time();
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Parse a time of day like `07:30`.
+pub fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Tz;
+
+    #[test]
+    fn test_parse() {
+        let hours = QuietHours::parse("22:00-07:30", true).unwrap();
+        assert_eq!(hours.start, NaiveTime::from_hms_opt(22, 0, 0).unwrap());
+        assert_eq!(hours.end, NaiveTime::from_hms_opt(7, 30, 0).unwrap());
+        assert!(hours.digest);
+        assert_eq!(hours.to_string(), "22:00-07:30");
+
+        assert_eq!(
+            QuietHours::parse(" 9:00 - 10:00 ", false).map(|h| h.to_string()),
+            Some("09:00-10:00".to_string())
+        );
+        assert_eq!(QuietHours::parse("09:00-09:00", false), None);
+        assert_eq!(QuietHours::parse("09:00", false), None);
+        assert_eq!(QuietHours::parse("25:00-07:00", false), None);
+    }
+
+    #[test]
+    fn test_contains() {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        let at = |hour, minute| {
+            tz.with_ymd_and_hms(2025, 1, 15, hour, minute, 0)
+                .single()
+                .unwrap()
+        };
+
+        let meeting = QuietHours::parse("09:00-10:00", false).unwrap();
+        assert!(!meeting.contains(&at(8, 59)));
+        assert!(meeting.contains(&at(9, 0)));
+        assert!(meeting.contains(&at(9, 59)));
+        assert!(!meeting.contains(&at(10, 0)));
+
+        let night = QuietHours::parse("22:00-07:00", false).unwrap();
+        assert!(night.contains(&at(23, 0)));
+        assert!(night.contains(&at(0, 0)));
+        assert!(night.contains(&at(6, 59)));
+        assert!(!night.contains(&at(7, 0)));
+        assert!(!night.contains(&at(12, 0)));
+
+        // It goes by the local time, not UTC.
+        let utc = at(9, 30).with_timezone(&chrono::Utc);
+        assert!(!meeting.contains(&utc));
+    }
+}
@@ -0,0 +1,230 @@
+use scraper::{ElementRef, Html, Selector};
+use std::sync::LazyLock;
+
+/// Containers that usually hold the main content, most specific first.
+static CONTENT_SELECTORS: LazyLock<Vec<Selector>> = LazyLock::new(|| {
+    [
+        "[itemprop=articleBody]",
+        "article",
+        "[role=main]",
+        "main",
+        ".entry-content",
+        ".post-content",
+        "#content",
+    ]
+    .iter()
+    .map(|s| Selector::parse(s).unwrap())
+    .collect()
+});
+
+static PARAGRAPH_SELECTOR: LazyLock<Selector> = LazyLock::new(|| Selector::parse("p").unwrap());
+static LINK_SELECTOR: LazyLock<Selector> = LazyLock::new(|| Selector::parse("a").unwrap());
+
+/// Elements whose paragraphs are never part of the article body.
+const BOILERPLATE_TAGS: &[&str] = &["nav", "header", "footer", "aside", "form", "figcaption"];
+
+/// Class/id fragments that mark sidebars, share widgets and the like.
+const BOILERPLATE_MARKERS: &[&str] = &[
+    "comment",
+    "share",
+    "social",
+    "related",
+    "newsletter",
+    "subscribe",
+    "cookie",
+    "sidebar",
+    "promo",
+    "byline",
+    "caption",
+];
+
+/// Phrases that show up in consent banners, paywalls and footers.
+const BOILERPLATE_PHRASES: &[&str] = &[
+    "cookie",
+    "javascript",
+    "subscribe",
+    "sign up",
+    "log in to",
+    "all rights reserved",
+    "your browser",
+];
+
+/// Paragraphs shorter than this aren't considered for the lead.
+const MIN_LEAD_CHARS: usize = 60;
+
+/// Paragraphs where more than this fraction of text is links are skipped.
+const MAX_LINK_DENSITY: f64 = 0.5;
+
+/// Readable text extracted from a page.
+#[derive(Debug, Default, PartialEq)]
+pub struct Article {
+    /// All body paragraphs, separated by blank lines.
+    pub text: Option<String>,
+    /// First substantial paragraph, suitable as a description fallback.
+    pub lead: Option<String>,
+}
+
+/// Extract the article body from `document`.
+///
+/// The largest known content container is used when there is one, otherwise
+/// the whole document. Paragraphs inside navigation, footers, share widgets
+/// and similar boilerplate are skipped, as are link-heavy paragraphs.
+pub fn extract(document: &Html) -> Article {
+    let paragraphs = match content_root(document) {
+        Some(root) => collect_paragraphs(root.select(&PARAGRAPH_SELECTOR), Some(root)),
+        None => collect_paragraphs(document.select(&PARAGRAPH_SELECTOR), None),
+    };
+
+    let lead = paragraphs
+        .iter()
+        .find(|p| p.chars().count() >= MIN_LEAD_CHARS && !has_boilerplate_phrase(p))
+        .cloned();
+
+    Article {
+        text: if paragraphs.is_empty() {
+            None
+        } else {
+            Some(paragraphs.join("\n\n"))
+        },
+        lead,
+    }
+}
+
+/// Pick the content container with the most paragraph text.
+fn content_root(document: &Html) -> Option<ElementRef<'_>> {
+    let mut best: Option<(ElementRef, usize)> = None;
+    for selector in CONTENT_SELECTORS.iter() {
+        for element in document.select(selector) {
+            let len: usize = element
+                .select(&PARAGRAPH_SELECTOR)
+                .map(|p| p.text().map(str::len).sum::<usize>())
+                .sum();
+            // Ties go to the earlier (more specific) selector.
+            if len > best.map_or(0, |(_, best_len)| best_len) {
+                best = Some((element, len));
+            }
+        }
+    }
+    best.map(|(element, _)| element)
+}
+
+fn collect_paragraphs<'a>(
+    paragraphs: impl Iterator<Item = ElementRef<'a>>,
+    root: Option<ElementRef<'a>>,
+) -> Vec<String> {
+    paragraphs
+        .filter(|p| !in_boilerplate(p, root))
+        .filter(|p| link_density(p) <= MAX_LINK_DENSITY)
+        .map(|p| normalize_whitespace(&p.text().collect::<String>()))
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Check `element` and its ancestors up to (not including) the content root.
+/// `<body>` and `<html>` are skipped since their classes describe the page as
+/// a whole (e.g. WordPress' `has-sidebar`).
+fn in_boilerplate(element: &ElementRef, root: Option<ElementRef>) -> bool {
+    let ancestors = element
+        .ancestors()
+        .take_while(|node| root.is_none_or(|root| root.id() != node.id()))
+        .filter_map(ElementRef::wrap);
+    std::iter::once(*element)
+        .chain(ancestors)
+        .filter(|e| !matches!(e.value().name(), "body" | "html"))
+        .any(|e| {
+            let el = e.value();
+            BOILERPLATE_TAGS.contains(&el.name())
+                || [el.attr("class"), el.attr("id")]
+                    .into_iter()
+                    .flatten()
+                    .any(|attr| {
+                        let attr = attr.to_ascii_lowercase();
+                        BOILERPLATE_MARKERS.iter().any(|m| attr.contains(m))
+                    })
+        })
+}
+
+fn link_density(element: &ElementRef) -> f64 {
+    let total: usize = element.text().map(|t| t.trim().len()).sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let linked: usize = element
+        .select(&LINK_SELECTOR)
+        .flat_map(|a| a.text())
+        .map(|t| t.trim().len())
+        .sum();
+    linked as f64 / total as f64
+}
+
+fn has_boilerplate_phrase(text: &str) -> bool {
+    let lower = text.to_lowercase();
+    BOILERPLATE_PHRASES.iter().any(|p| lower.contains(p))
+}
+
+fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract_html(html: &str) -> Article {
+        extract(&Html::parse_document(html))
+    }
+
+    #[test]
+    fn test_extract_skips_boilerplate() {
+        let article = extract_html(
+            r#"<html><body>
+            <header><p>Welcome to my blog, where I write about all sorts of things every week.</p></header>
+            <div class="cookie-banner"><p>We use cookies to improve your experience on this website.</p></div>
+            <article>
+                <p class="byline">By Someone</p>
+                <p>Short intro.</p>
+                <p>This is the   first real paragraph of the post, and it is long enough to be a lead.</p>
+                <p><a href="/a">Previous post</a> <a href="/b">Next post</a></p>
+                <p>Second paragraph.</p>
+                <div class="share-buttons"><p>Share this on social media</p></div>
+            </article>
+            <footer><p>Copyright 2025</p></footer>
+            </body></html>"#,
+        );
+        assert_eq!(
+            article.lead.as_deref(),
+            Some(
+                "This is the first real paragraph of the post, and it is long enough to be a lead."
+            )
+        );
+        assert_eq!(
+            article.text.as_deref(),
+            Some(
+                "Short intro.\n\n\
+                 This is the first real paragraph of the post, and it is long enough to be a lead.\n\n\
+                 Second paragraph."
+            )
+        );
+    }
+
+    #[test]
+    fn test_extract_without_container() {
+        let article = extract_html(
+            "<html><body><p>Please enable JavaScript in your browser to view this page properly.</p>\
+             <p>Plain pages without any article element still get their paragraphs picked up.</p>\
+             </body></html>",
+        );
+        assert_eq!(
+            article.lead.as_deref(),
+            Some("Plain pages without any article element still get their paragraphs picked up.")
+        );
+    }
+
+    #[test]
+    fn test_extract_empty() {
+        assert_eq!(
+            extract_html("<html><body><div>No paragraphs</div></body></html>"),
+            Article::default()
+        );
+    }
+}
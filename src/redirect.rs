@@ -0,0 +1,136 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use regex::Regex;
+use serde::Deserialize;
+use tracing::debug;
+use url::Url;
+
+/// Redirector links nested deeper than this are left alone.
+const MAX_UNWRAP_DEPTH: usize = 5;
+
+/// A redirector whose destination is carried in a query parameter, e.g.
+/// `https://www.google.com/url?q=<destination>`.
+#[derive(Debug, Clone)]
+pub struct UnwrapRule {
+    /// Matched against the whole redirector URL.
+    pub pattern: Regex,
+    /// Query parameter holding the destination.
+    pub param: String,
+    /// If set, the parameter is this prefix followed by the base64url-encoded
+    /// destination (Bing uses `a1`).
+    pub base64_prefix: Option<String>,
+}
+
+/// On-disk form of an [`UnwrapRule`].
+#[derive(Debug, Deserialize)]
+pub struct UnwrapRuleConfig {
+    pub regex: String,
+    pub param: String,
+    #[serde(default)]
+    pub base64_prefix: Option<String>,
+}
+
+pub fn default_unwrap_rules() -> Vec<UnwrapRule> {
+    let rule = |pattern: &str, param: &str| UnwrapRule {
+        pattern: Regex::new(pattern).unwrap(),
+        param: param.to_string(),
+        base64_prefix: None,
+    };
+    vec![
+        rule(r"^https?://(www\.)?google\.[a-z.]+/url\?", "q"),
+        rule(r"^https?://(www\.)?google\.[a-z.]+/url\?", "url"),
+        rule(r"^https?://lm?\.facebook\.com/l\.php\?", "u"),
+        rule(r"^https?://l\.instagram\.com/\?", "u"),
+        rule(r"^https?://(www\.)?youtube\.com/redirect\?", "q"),
+        rule(r"^https?://steamcommunity\.com/linkfilter/\?", "url"),
+        UnwrapRule {
+            pattern: Regex::new(r"^https?://(www\.)?bing\.com/ck/a\?").unwrap(),
+            param: "u".to_string(),
+            base64_prefix: Some("a1".to_string()),
+        },
+    ]
+}
+
+/// Follow redirector links in `url` to their destination, without making any
+/// requests. Returns `url` unchanged if no rule matches.
+pub fn unwrap(url: &Url, rules: &[UnwrapRule]) -> Url {
+    let mut current = url.clone();
+    for _ in 0..MAX_UNWRAP_DEPTH {
+        let Some(next) = rules.iter().find_map(|rule| unwrap_once(&current, rule)) else {
+            break;
+        };
+        debug!("Unwrapped redirect {} -> {}", current, next);
+        current = next;
+    }
+    current
+}
+
+fn unwrap_once(url: &Url, rule: &UnwrapRule) -> Option<Url> {
+    if !rule.pattern.is_match(url.as_str()) {
+        return None;
+    }
+    let value = url
+        .query_pairs()
+        .find(|(key, _)| *key == rule.param)
+        .map(|(_, value)| value.into_owned())?;
+
+    let destination = match &rule.base64_prefix {
+        Some(prefix) => {
+            let encoded = value.strip_prefix(prefix.as_str())?;
+            let decoded = URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('=')).ok()?;
+            String::from_utf8(decoded).ok()?
+        }
+        None => value,
+    };
+
+    let destination = Url::parse(&destination).ok()?;
+    matches!(destination.scheme(), "http" | "https").then_some(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unwrap_str(url: &str) -> String {
+        unwrap(&Url::parse(url).unwrap(), &default_unwrap_rules()).to_string()
+    }
+
+    #[test]
+    fn test_unwrap_default_rules() {
+        assert_eq!(
+            unwrap_str("https://www.google.com/url?sa=t&q=https://example.com/a%3Fb%3Dc&usg=x"),
+            "https://example.com/a?b=c"
+        );
+        assert_eq!(
+            unwrap_str("https://l.facebook.com/l.php?u=https%3A%2F%2Fexample.com%2F&h=AT0"),
+            "https://example.com/"
+        );
+        assert_eq!(
+            unwrap_str("https://www.bing.com/ck/a?!&&p=abc&u=a1aHR0cHM6Ly9leGFtcGxlLmNvbS8&ntb=1"),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn test_unwrap_nested() {
+        assert_eq!(
+            unwrap_str(
+                "https://www.google.com/url?q=https%3A%2F%2Fl.facebook.com%2Fl.php%3Fu%3Dhttps%253A%252F%252Fexample.com%252F"
+            ),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn test_unwrap_leaves_other_urls() {
+        assert_eq!(
+            unwrap_str("https://example.com/url?q=https://other.com/"),
+            "https://example.com/url?q=https://other.com/"
+        );
+        // Only http(s) destinations are followed.
+        assert_eq!(
+            unwrap_str("https://www.google.com/url?q=javascript:alert(1)"),
+            "https://www.google.com/url?q=javascript:alert(1)"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use regex::Regex;
+use std::sync::LazyLock;
+use url::Url;
+
+use crate::config::Config;
+use crate::debug_room::Stage;
+
+static SECRET_PARAM_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(access_token|token|key|secret|password|sig|signature)=[^&\s]+").unwrap()
+});
+
+static BEARER_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)\bbearer\s+\S+").unwrap());
+
+/// Matrix access and refresh tokens.
+static MATRIX_TOKEN_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\bsy[tr]_[A-Za-z0-9_]+").unwrap());
+
+static URL_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)\bhttps?://([^/\s"'<>?#)]+)[^\s"'<>)]*"#).unwrap());
+
+/// Keeps error reporting running; pending reports are flushed when dropped.
+pub struct Guard {
+    #[cfg(feature = "sentry")]
+    _sentry: Option<sentry::ClientInitGuard>,
+}
+
+/// Start reporting panics and embed failures to Sentry, if a DSN is
+/// configured and this build has the `sentry` feature.
+pub fn init(config: &Config) -> Guard {
+    #[cfg(feature = "sentry")]
+    {
+        use std::sync::Arc;
+
+        let sentry = config.sentry_dsn.as_deref().map(|dsn| {
+            sentry::init((
+                dsn,
+                sentry::ClientOptions {
+                    release: sentry::release_name!(),
+                    send_default_pii: false,
+                    before_send: Some(Arc::new(|event| Some(scrub_event(event)))),
+                    ..Default::default()
+                },
+            ))
+        });
+        Guard { _sentry: sentry }
+    }
+
+    #[cfg(not(feature = "sentry"))]
+    {
+        if config.sentry_dsn.is_some() {
+            tracing::warn!(
+                "A Sentry DSN is configured, but this build doesn't include Sentry support"
+            );
+        }
+        Guard {}
+    }
+}
+
+/// Report a pipeline error, tagged with the domain of `url`, the `stage` it
+/// failed in and its kind of error.
+pub fn capture_error(error: &anyhow::Error, url: Option<&Url>, stage: Option<Stage>) {
+    #[cfg(feature = "sentry")]
+    sentry::with_scope(
+        |scope| {
+            if let Some(domain) = url.and_then(Url::host_str) {
+                scope.set_tag("domain", domain);
+            }
+            if let Some(stage) = stage {
+                scope.set_tag("stage", stage.label());
+            }
+            scope.set_tag("kind", crate::error::EmbedError::of(error).label());
+        },
+        || sentry::integrations::anyhow::capture_anyhow(error),
+    );
+
+    #[cfg(not(feature = "sentry"))]
+    let _ = (error, url, stage);
+}
+
+#[cfg(feature = "sentry")]
+fn scrub_event(mut event: sentry::protocol::Event<'static>) -> sentry::protocol::Event<'static> {
+    event.request = None;
+    event.user = None;
+    if let Some(message) = &mut event.message {
+        *message = scrub(message);
+    }
+    if let Some(logentry) = &mut event.logentry {
+        logentry.message = scrub(&logentry.message);
+    }
+    for exception in &mut event.exception.values {
+        if let Some(value) = &mut exception.value {
+            *value = scrub(value);
+        }
+    }
+    for breadcrumb in &mut event.breadcrumbs.values {
+        if let Some(message) = &mut breadcrumb.message {
+            *message = scrub(message);
+        }
+    }
+    event
+}
+
+/// Strip secrets and everything but the host from URLs in `text`, since
+/// error messages often quote the URL a request was made to.
+pub fn scrub(text: &str) -> String {
+    let text = SECRET_PARAM_REGEX.replace_all(text, "$1=[redacted]");
+    let text = BEARER_REGEX.replace_all(&text, "Bearer [redacted]");
+    let text = MATRIX_TOKEN_REGEX.replace_all(&text, "[redacted]");
+    let text = URL_REGEX.replace_all(&text, |caps: &regex::Captures| {
+        let scheme_end = caps[0].find("://").unwrap_or(0);
+        let host = caps[1].rsplit('@').next().unwrap_or_default();
+        format!("{}://{}/…", &caps[0][..scheme_end], host)
+    });
+    text.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrub() {
+        assert_eq!(
+            scrub("error sending request for url (https://user:pw@example.com/private/path?id=1)"),
+            "error sending request for url (https://example.com/…)"
+        );
+        assert_eq!(
+            scrub("GET /_matrix/client/v3/sync?access_token=syt_abc_123&since=s1 failed"),
+            "GET /_matrix/client/v3/sync?access_token=[redacted]&since=s1 failed"
+        );
+        assert_eq!(
+            scrub("Authorization: Bearer abc.def and syr_refresh_Token"),
+            "Authorization: Bearer [redacted] and [redacted]"
+        );
+        assert_eq!(
+            scrub("Failed to fetch metadata"),
+            "Failed to fetch metadata"
+        );
+    }
+}
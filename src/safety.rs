@@ -0,0 +1,540 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use url::Url;
+
+use crate::config::Config;
+use crate::db::Database;
+
+/// The Safe Browsing Lookup API (v4) endpoint.
+pub const DEFAULT_SAFE_BROWSING_API_URL: &str =
+    "https://safebrowsing.googleapis.com/v4/threatMatches:find";
+
+/// How long Safe Browsing has to answer. Every link waits on it, so it's
+/// kept short; a link that can't be checked is embedded anyway.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Names in hosts files that map local addresses rather than block anything.
+const LOCAL_HOSTS: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "0.0.0.0",
+];
+
+/// Why a link was flagged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threat {
+    Malware,
+    Phishing,
+    UnwantedSoftware,
+    HarmfulApplication,
+    /// Listed in a local blocklist, which doesn't say why.
+    Blocklisted,
+}
+
+impl Threat {
+    pub fn name(self) -> &'static str {
+        match self {
+            Threat::Malware => "malware",
+            Threat::Phishing => "phishing",
+            Threat::UnwantedSoftware => "unwanted-software",
+            Threat::HarmfulApplication => "harmful-application",
+            Threat::Blocklisted => "blocklisted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "malware" => Some(Threat::Malware),
+            "phishing" => Some(Threat::Phishing),
+            "unwanted-software" => Some(Threat::UnwantedSoftware),
+            "harmful-application" => Some(Threat::HarmfulApplication),
+            "blocklisted" => Some(Threat::Blocklisted),
+            _ => None,
+        }
+    }
+
+    /// The threat for a Safe Browsing `threatType`. Types we didn't ask for
+    /// are taken as malware rather than ignored.
+    fn from_safe_browsing(threat_type: &str) -> Self {
+        match threat_type {
+            "SOCIAL_ENGINEERING" => Threat::Phishing,
+            "UNWANTED_SOFTWARE" => Threat::UnwantedSoftware,
+            "POTENTIALLY_HARMFUL_APPLICATION" => Threat::HarmfulApplication,
+            _ => Threat::Malware,
+        }
+    }
+
+    /// What the threat is, to finish "flagged as ...".
+    fn description(self) -> &'static str {
+        match self {
+            Threat::Malware => "malware",
+            Threat::Phishing => "phishing",
+            Threat::UnwantedSoftware => "unwanted software",
+            Threat::HarmfulApplication => "a harmful application",
+            Threat::Blocklisted => "unsafe",
+        }
+    }
+}
+
+/// Where a verdict came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Blocklist,
+    SafeBrowsing,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Source::Blocklist => "a blocklist",
+            Source::SafeBrowsing => "Google Safe Browsing",
+        }
+    }
+}
+
+/// A link that was flagged as unsafe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub threat: Threat,
+    pub source: Source,
+}
+
+/// Domains and URLs that are never embedded, loaded from local lists.
+#[derive(Clone, Default)]
+pub struct Blocklist {
+    /// Lowercase domains, which also block their subdomains.
+    domains: HashSet<String>,
+    /// URLs as returned by [`normalize`].
+    urls: HashSet<String>,
+}
+
+impl fmt::Debug for Blocklist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The lists can have hundreds of thousands of entries.
+        f.debug_struct("Blocklist")
+            .field("domains", &self.domains.len())
+            .field("urls", &self.urls.len())
+            .finish()
+    }
+}
+
+impl Blocklist {
+    /// Add the entries in `text`, which is either a PhishTank CSV export,
+    /// going by its header, or a hosts file (`0.0.0.0 bad.example`), list of
+    /// domains or list of URLs, one per line, with `#` starting comments.
+    pub fn add(&mut self, text: &str) {
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+        let Some(first) = lines.next() else {
+            return;
+        };
+        if let Some(url_column) = csv_fields(first)
+            .iter()
+            .position(|field| field.eq_ignore_ascii_case("url"))
+        {
+            for line in lines {
+                if let Some(url) = csv_fields(line).get(url_column) {
+                    self.add_entry(url);
+                }
+            }
+            return;
+        }
+
+        for line in std::iter::once(first).chain(lines) {
+            let line = match line.find('#') {
+                Some(0) => continue,
+                // A `#` after whitespace starts a comment; one inside a URL
+                // is its fragment.
+                Some(i) if line[..i].ends_with(char::is_whitespace) => &line[..i],
+                _ => line,
+            };
+            let mut entries = line.split_whitespace().peekable();
+            if entries
+                .peek()
+                .is_some_and(|entry| entry.parse::<IpAddr>().is_ok())
+            {
+                entries.next();
+                for host in entries {
+                    if !LOCAL_HOSTS.contains(&host.to_ascii_lowercase().as_str()) {
+                        self.add_entry(host);
+                    }
+                }
+            } else if let Some(entry) = entries.next() {
+                self.add_entry(entry);
+            }
+        }
+    }
+
+    fn add_entry(&mut self, entry: &str) {
+        let entry = entry.trim();
+        if entry.contains("://") {
+            if let Ok(url) = Url::parse(entry) {
+                self.urls.insert(normalize(&url));
+            }
+        } else {
+            let domain = entry.trim_matches('.').to_ascii_lowercase();
+            if !domain.is_empty() {
+                self.domains.insert(domain);
+            }
+        }
+    }
+
+    /// Returns `true` if `url` is listed, or its host or a domain it's under
+    /// is.
+    pub fn contains(&self, url: &Url) -> bool {
+        if let Some(host) = url.host_str() {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            let mut domain = host.as_str();
+            loop {
+                if self.domains.contains(domain) {
+                    return true;
+                }
+                match domain.split_once('.') {
+                    Some((_, parent)) => domain = parent,
+                    None => break,
+                }
+            }
+        }
+        self.urls.contains(&normalize(url))
+    }
+}
+
+/// `url` without its fragment or a trailing slash, so lists match however
+/// the link was written.
+fn normalize(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.as_str().trim_end_matches('/').to_string()
+}
+
+/// The fields of a CSV line, with quotes removed.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LookupRequest<'a> {
+    client: ClientInfo<'a>,
+    threat_info: ThreatInfo<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientInfo<'a> {
+    client_id: &'a str,
+    client_version: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatInfo<'a> {
+    threat_types: &'a [&'a str],
+    platform_types: &'a [&'a str],
+    threat_entry_types: &'a [&'a str],
+    threat_entries: Vec<ThreatEntry<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ThreatEntry<'a> {
+    url: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupResponse {
+    #[serde(default)]
+    matches: Vec<ThreatMatch>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatMatch {
+    threat_type: String,
+}
+
+/// Check `url` against the configured blocklists, then Safe Browsing if
+/// there's an API key for it. Safe Browsing verdicts, including links that
+/// weren't flagged, are cached for a while. Returns `Ok(None)` if the link
+/// looks safe, or isn't a web link.
+pub async fn check(
+    client: &reqwest::Client,
+    config: &Config,
+    database: &Database,
+    url: &Url,
+) -> Result<Option<Verdict>> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Ok(None);
+    }
+    if config.blocklist.contains(url) {
+        return Ok(Some(Verdict {
+            threat: Threat::Blocklisted,
+            source: Source::Blocklist,
+        }));
+    }
+    let Some(api_key) = &config.safe_browsing_api_key else {
+        return Ok(None);
+    };
+
+    let threat = match database.get_link_verdict(url.as_str()).await? {
+        Some(verdict) => {
+            debug!("Using cached verdict for {}", url);
+            verdict.threat
+        }
+        None => {
+            let threat = lookup(client, &config.safe_browsing_api_url, api_key, url).await?;
+            database.store_link_verdict(url.as_str(), threat).await?;
+            threat
+        }
+    };
+    Ok(threat.map(|threat| Verdict {
+        threat,
+        source: Source::SafeBrowsing,
+    }))
+}
+
+/// Look `url` up with the Safe Browsing Lookup API at `api_url`.
+async fn lookup(
+    client: &reqwest::Client,
+    api_url: &Url,
+    api_key: &str,
+    url: &Url,
+) -> Result<Option<Threat>> {
+    let request = LookupRequest {
+        client: ClientInfo {
+            client_id: "matrix-embed",
+            client_version: env!("CARGO_PKG_VERSION"),
+        },
+        threat_info: ThreatInfo {
+            threat_types: &[
+                "MALWARE",
+                "SOCIAL_ENGINEERING",
+                "UNWANTED_SOFTWARE",
+                "POTENTIALLY_HARMFUL_APPLICATION",
+            ],
+            platform_types: &["ANY_PLATFORM"],
+            threat_entry_types: &["URL"],
+            threat_entries: vec![ThreatEntry { url: url.as_str() }],
+        },
+    };
+    // The key goes in a header rather than the URL, which errors and logs
+    // include.
+    let response: LookupResponse = client
+        .post(api_url.clone())
+        .header("X-Goog-Api-Key", api_key)
+        .timeout(TIMEOUT)
+        .json(&request)
+        .send()
+        .await
+        .context("Failed to send Safe Browsing request")?
+        .error_for_status()
+        .context("Safe Browsing returned error status")?
+        .json()
+        .await
+        .context("Failed to parse Safe Browsing response")?;
+    Ok(response
+        .matches
+        .first()
+        .map(|threat_match| Threat::from_safe_browsing(&threat_match.threat_type)))
+}
+
+/// A short warning about a flagged link, to show in place of its title.
+pub fn label(verdict: &Verdict) -> String {
+    format!(
+        "⚠️ Flagged as {} by {}",
+        verdict.threat.description(),
+        verdict.source.name()
+    )
+}
+
+/// The notice posted instead of an embed of `url`, in plain text and HTML.
+/// Only the link's host is named, so the notice doesn't offer another way to
+/// open it.
+pub fn warning(url: &Url, verdict: &Verdict) -> (String, String) {
+    let host = url
+        .host_str()
+        .map(|host| idna::domain_to_unicode(host).0)
+        .unwrap_or_default();
+    let text = format!(
+        "was flagged as {} by {}, so it wasn't previewed. Think twice before opening it.",
+        verdict.threat.description(),
+        verdict.source.name()
+    );
+    let plain = format!("⚠️ Warning: the link to {} {}", host, text);
+    let html = format!(
+        "<p>⚠️ <strong><font data-mx-color=\"#d32f2f\">Warning:</font></strong> \
+         the link to <code>{}</code> {}</p>",
+        html_escape::encode_text(&host),
+        html_escape::encode_text(&text)
+    );
+    (plain, html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_blocklist_hosts_and_lists() {
+        let mut blocklist = Blocklist::default();
+        blocklist.add(
+            "# hosts file\n\
+             127.0.0.1 localhost\n\
+             0.0.0.0 ads.example tracker.example # both\n\
+             \n\
+             Bad.Example.\n\
+             https://host.example/phish/login.html#top\n",
+        );
+        let blocked = |url: &str| blocklist.contains(&Url::parse(url).unwrap());
+        assert!(blocked("https://ads.example/"));
+        assert!(blocked("http://cdn.tracker.example/x.js"));
+        assert!(blocked("https://www.bad.example/"));
+        assert!(blocked("https://host.example/phish/login.html"));
+        assert!(!blocked("https://host.example/"));
+        assert!(!blocked("https://notbad.example/"));
+        assert!(!blocked("http://localhost/"));
+        assert!(!blocked("https://example/"));
+    }
+
+    #[test]
+    fn test_blocklist_phishtank() {
+        let mut blocklist = Blocklist::default();
+        blocklist.add(
+            "phish_id,url,phish_detail_url,submission_time,verified,verification_time,online,target\n\
+             123,http://login.example/verify/,http://www.phishtank.com/phish_detail.php?phish_id=123,2025-01-15T12:00:00+00:00,yes,2025-01-15T12:05:00+00:00,yes,Other\n\
+             124,\"http://shop.example/a,b?x=\"\"1\"\"\",http://www.phishtank.com/phish_detail.php?phish_id=124,2025-01-15T12:00:00+00:00,yes,2025-01-15T12:05:00+00:00,yes,Other\n",
+        );
+        let blocked = |url: &str| blocklist.contains(&Url::parse(url).unwrap());
+        assert!(blocked("http://login.example/verify"));
+        assert!(blocked("http://shop.example/a,b?x=\"1\""));
+        assert!(!blocked("http://login.example/"));
+        assert!(!blocked(
+            "http://www.phishtank.com/phish_detail.php?phish_id=123"
+        ));
+        assert_eq!(
+            format!("{:?}", blocklist),
+            "Blocklist { domains: 0, urls: 2 }"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-goog-api-key", "secret"))
+            .and(body_partial_json(serde_json::json!({
+                "threatInfo": {"threatEntries": [{"url": "https://phish.example/"}]}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "matches": [{
+                    "threatType": "SOCIAL_ENGINEERING",
+                    "platformType": "ANY_PLATFORM",
+                    "threat": {"url": "https://phish.example/"},
+                    "cacheDuration": "300s",
+                    "threatEntryType": "URL",
+                }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "threatInfo": {"threatEntries": [{"url": "https://fine.example/"}]}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut blocklist = Blocklist::default();
+        blocklist.add("blocked.example");
+        let config = Config {
+            blocklist,
+            safe_browsing_api_key: Some("secret".to_string()),
+            safe_browsing_api_url: Url::parse(&server.uri()).unwrap(),
+            ..Default::default()
+        };
+        let db = Database::open_in_memory().await.unwrap();
+        let client = reqwest::Client::new();
+        let verdict = async |url: &str| {
+            check(&client, &config, &db, &Url::parse(url).unwrap())
+                .await
+                .unwrap()
+        };
+
+        let phishing = Some(Verdict {
+            threat: Threat::Phishing,
+            source: Source::SafeBrowsing,
+        });
+        assert_eq!(verdict("https://phish.example/").await, phishing);
+        // Cached, so Safe Browsing is only asked once.
+        assert_eq!(verdict("https://phish.example/").await, phishing);
+        assert_eq!(verdict("https://fine.example/").await, None);
+        assert_eq!(verdict("https://fine.example/").await, None);
+        assert_eq!(
+            verdict("https://www.blocked.example/").await,
+            Some(Verdict {
+                threat: Threat::Blocklisted,
+                source: Source::Blocklist,
+            })
+        );
+        assert_eq!(verdict("mailto:someone@phish.example").await, None);
+    }
+
+    #[test]
+    fn test_warning() {
+        let verdict = Verdict {
+            threat: Threat::Phishing,
+            source: Source::SafeBrowsing,
+        };
+        assert_eq!(
+            label(&verdict),
+            "⚠️ Flagged as phishing by Google Safe Browsing"
+        );
+        let (plain, html) = warning(
+            &Url::parse("https://xn--bcher-kva.example/login?next=<b>").unwrap(),
+            &verdict,
+        );
+        assert_eq!(
+            plain,
+            "⚠️ Warning: the link to bücher.example was flagged as phishing by Google Safe Browsing, \
+             so it wasn't previewed. Think twice before opening it."
+        );
+        assert_eq!(
+            html,
+            "<p>⚠️ <strong><font data-mx-color=\"#d32f2f\">Warning:</font></strong> \
+             the link to <code>bücher.example</code> was flagged as phishing by Google Safe \
+             Browsing, so it wasn't previewed. Think twice before opening it.</p>"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use scraper::{ElementRef, Html, Node};
+
+use crate::processing::truncate_text;
+
+/// Tags kept from extractor HTML. Paragraphs are turned into line breaks,
+/// since the description already sits in a paragraph of its own.
+const ALLOWED_TAGS: &[&str] = &["a", "b", "strong", "i", "em", "code", "br", "p"];
+
+/// Sanitize `html` from a site-specific extractor (a fediverse post, say)
+/// for use as an embed description. Links, emphasis and line breaks are
+/// kept; scripts and styles are dropped along with their content, and other
+/// tags are replaced by their text.
+///
+/// The result is cut to `max_chars` characters of text and `max_lines`
+/// lines, like [`truncate_text`] does for plain descriptions, without
+/// leaving tags open.
+pub fn sanitize_html(html: &str, max_chars: usize, max_lines: usize) -> String {
+    let mut cleaner = ammonia::Builder::empty();
+    cleaner
+        .add_tags(ALLOWED_TAGS)
+        .add_tag_attributes("a", &["href"])
+        .add_url_schemes(&["http", "https", "mailto"])
+        .add_clean_content_tags(&["script", "style"])
+        .url_relative(ammonia::UrlRelative::Deny);
+    let cleaned = cleaner.clean(html).to_string();
+    fit_html(&cleaned, max_chars, max_lines)
+}
+
+/// Remove characters that can hide or rearrange text: control characters
+/// other than line breaks and tabs, explicit bidi formatting, and invisible
+/// spaces. Joiners are kept, since emoji sequences and some scripts need
+/// them.
+pub fn strip_invisible(text: &str) -> String {
+    text.chars()
+        .filter(|&ch| {
+            !matches!(ch,
+                '\u{0}'..='\u{8}' | '\u{b}'..='\u{1f}' | '\u{7f}'..='\u{9f}'
+                | '\u{ad}' | '\u{61c}' | '\u{200b}' | '\u{200e}' | '\u{200f}'
+                | '\u{202a}'..='\u{202e}' | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{2069}' | '\u{feff}')
+        })
+        .collect()
+}
+
+/// Wrap `text` in a first-strong isolate if it has right-to-left characters,
+/// so it can't reorder the message text around it. `text` may be HTML.
+pub fn isolate(text: &str) -> String {
+    if text.chars().any(is_rtl) {
+        format!("\u{2068}{}\u{2069}", text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Whether `ch` is in a right-to-left script block: Hebrew, Arabic, Syriac,
+/// Thaana, NKo and their relatives.
+fn is_rtl(ch: char) -> bool {
+    matches!(ch,
+        '\u{590}'..='\u{8ff}' | '\u{fb1d}'..='\u{fdff}' | '\u{fe70}'..='\u{fefe}'
+        | '\u{10800}'..='\u{10fff}' | '\u{1e800}'..='\u{1efff}')
+}
+
+/// Re-serialize already sanitized `html` with at most `max_chars` characters
+/// of text and `max_lines` lines, closing any tags that were open where it
+/// was cut.
+fn fit_html(html: &str, max_chars: usize, max_lines: usize) -> String {
+    let fragment = Html::parse_fragment(html);
+    let mut fitter = Fitter {
+        out: String::new(),
+        chars_left: max_chars,
+        breaks_left: max_lines.saturating_sub(1),
+        done: false,
+    };
+    fitter.children(fragment.root_element());
+    fitter.out.trim().to_string()
+}
+
+struct Fitter {
+    out: String,
+    chars_left: usize,
+    breaks_left: usize,
+    done: bool,
+}
+
+impl Fitter {
+    fn children(&mut self, parent: ElementRef) {
+        for child in parent.children() {
+            if self.done {
+                return;
+            }
+            if let Node::Text(text) = child.value() {
+                self.text(text);
+            }
+            let Some(element) = ElementRef::wrap(child) else {
+                continue;
+            };
+            match element.value().name() {
+                "br" => self.line_break(),
+                "p" => {
+                    if !self.out.is_empty() {
+                        self.line_break();
+                    }
+                    self.children(element);
+                }
+                "a" => match element.value().attr("href") {
+                    Some(href) => {
+                        self.out.push_str(&format!(
+                            "<a href=\"{}\">",
+                            html_escape::encode_double_quoted_attribute(href)
+                        ));
+                        self.children(element);
+                        self.out.push_str("</a>");
+                    }
+                    None => self.children(element),
+                },
+                name @ ("b" | "strong" | "i" | "em" | "code") => {
+                    self.out.push_str(&format!("<{}>", name));
+                    self.children(element);
+                    self.out.push_str(&format!("</{}>", name));
+                }
+                _ => self.children(element),
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        let text = strip_invisible(text);
+        let text = text.as_str();
+        let len = text.chars().count();
+        if len <= self.chars_left {
+            self.chars_left -= len;
+            self.out.push_str(&html_escape::encode_text(text));
+        } else {
+            let cut = truncate_text(text, self.chars_left, usize::MAX);
+            self.out.push_str(&html_escape::encode_text(&cut));
+            self.done = true;
+        }
+    }
+
+    /// Start a new line, or end the text if that's one line too many.
+    fn line_break(&mut self) {
+        if self.breaks_left == 0 {
+            self.out.truncate(self.out.trim_end().len());
+            self.out.push('…');
+            self.done = true;
+        } else {
+            self.breaks_left -= 1;
+            self.out.push_str("<br/>");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_html() {
+        let html = r#"<p>Hello <strong>world</strong>!<script>alert(1)</script></p><p>See <a href="https://example.com/" onclick="evil()" rel="tag"><span class="invisible">https://</span>example.com</a><style>p { color: red }</style></p>"#;
+        assert_eq!(
+            sanitize_html(html, 500, 10),
+            r#"Hello <strong>world</strong>!<br/>See <a href="https://example.com/">https://example.com</a>"#
+        );
+        assert_eq!(
+            sanitize_html(
+                r#"<a href="javascript:alert(1)">x</a><img src="x">"#,
+                500,
+                10
+            ),
+            "x"
+        );
+    }
+
+    #[test]
+    fn test_fit_html() {
+        let html = r#"<p>One <a href="https://example.com/">two three</a> four</p><p>five</p>"#;
+        assert_eq!(
+            fit_html(html, 9, 10),
+            r#"One <a href="https://example.com/">two…</a>"#
+        );
+        assert_eq!(
+            fit_html(html, 500, 1),
+            r#"One <a href="https://example.com/">two three</a> four…"#
+        );
+        assert_eq!(fit_html("a<br>b<br>c", 500, 2), "a<br/>b…");
+        assert_eq!(fit_html(html, 500, 2), fit_html(html, 500, 10));
+        assert_eq!(fit_html("a\u{202e}b", 500, 10), "ab");
+    }
+
+    #[test]
+    fn test_strip_invisible() {
+        assert_eq!(
+            strip_invisible("a\u{202e}b\u{200b}c\u{7}d\u{feff}\ne\tf"),
+            "abcd\ne\tf"
+        );
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(strip_invisible(family), family);
+    }
+
+    #[test]
+    fn test_isolate() {
+        assert_eq!(isolate("Hello"), "Hello");
+        assert_eq!(isolate("שלום"), "\u{2068}שלום\u{2069}");
+        assert_eq!(isolate("<b>مرحبا</b>"), "\u{2068}<b>مرحبا</b>\u{2069}");
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{Result, bail};
+use sha2::{Digest, Sha256};
+
+/// The slice of rooms handled by this instance when running several
+/// instances side by side.
+///
+/// Rooms are assigned by hashing the room ID, so every instance agrees on
+/// the assignment without coordinating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    index: u32,
+    count: u32,
+}
+
+impl Shard {
+    pub fn new(index: u32, count: u32) -> Result<Self> {
+        if count == 0 {
+            bail!("Shard count must be at least 1");
+        }
+        if index >= count {
+            bail!(
+                "Shard index {} out of range for shard count {}",
+                index,
+                count
+            );
+        }
+        Ok(Self { index, count })
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Returns `true` if there is more than one shard.
+    pub fn is_sharded(&self) -> bool {
+        self.count > 1
+    }
+
+    /// Returns `true` if `room_id` belongs to this shard.
+    pub fn owns(&self, room_id: &str) -> bool {
+        !self.is_sharded() || shard_of(room_id, self.count) == self.index
+    }
+}
+
+impl Default for Shard {
+    fn default() -> Self {
+        Self { index: 0, count: 1 }
+    }
+}
+
+/// Stable shard assignment for `room_id`. This must not change between
+/// releases, or rooms would move between instances on upgrade.
+fn shard_of(room_id: &str, count: u32) -> u32 {
+    let digest = Sha256::digest(room_id.as_bytes());
+    let prefix = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (prefix % u64::from(count)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_validates() {
+        assert!(Shard::new(0, 0).is_err());
+        assert!(Shard::new(2, 2).is_err());
+        assert!(Shard::new(1, 2).is_ok());
+    }
+
+    #[test]
+    fn test_every_room_has_exactly_one_shard() {
+        let shards: Vec<Shard> = (0..4).map(|i| Shard::new(i, 4).unwrap()).collect();
+        for n in 0..100 {
+            let room_id = format!("!room{}:example.com", n);
+            assert_eq!(shards.iter().filter(|s| s.owns(&room_id)).count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_assignment_is_stable() {
+        // Pinned, since rooms would move between instances if these changed.
+        assert_eq!(shard_of("!abc:example.com", 2), 0);
+        assert_eq!(shard_of("!abc:example.com", 3), 2);
+        assert_eq!(shard_of("!abc:example.com", 7), 2);
+        assert_eq!(shard_of("!room2:example.com", 7), 3);
+        assert_eq!(shard_of("!room3:example.com", 4), 2);
+        assert!(Shard::default().owns("!abc:example.com"));
+        assert!(Shard::new(2, 3).unwrap().owns("!abc:example.com"));
+        assert!(!Shard::new(1, 3).unwrap().owns("!abc:example.com"));
+    }
+
+    #[test]
+    fn test_assignment_spread() {
+        let mut counts = [0; 4];
+        for n in 0..1000 {
+            counts[shard_of(&format!("!room{}:example.com", n), 4) as usize] += 1;
+        }
+        assert_eq!(counts, [246, 255, 273, 226]);
+        // Each shard gets its share, give or take 10%.
+        assert!(counts.iter().all(|&count| (225..=275).contains(&count)));
+    }
+}
@@ -0,0 +1,1293 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use futures_util::future::BoxFuture;
+
+use url::Url;
+
+use crate::config::{Config, StoreBackend};
+use crate::db::{PruneStats, UploadedMedia};
+use crate::metadata::Metadata;
+
+/// State that several instances of the bot can share: the history of posted
+/// embeds, which is also used to avoid posting an embed twice, and the
+/// metadata, summary and upload caches.
+///
+/// By default this lives in the bot's SQLite database, which only one
+/// instance can use. Running several replicas needs a store they can all
+/// reach, Redis or Postgres, chosen with `--shared-store`.
+pub trait SharedStore: Send + Sync {
+    /// Look up the metadata fetched from `url` in `language`, if it hasn't
+    /// expired.
+    fn get_cached_metadata<'a>(
+        &'a self,
+        url: &'a Url,
+        language: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Option<Metadata>>>;
+
+    /// Keep `meta`, fetched from `url` in `language`, for `ttl`. Nothing is
+    /// kept with a zero TTL.
+    fn store_metadata<'a>(
+        &'a self,
+        url: &'a Url,
+        language: Option<&'a str>,
+        meta: &'a Metadata,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Look up a previously generated summary for `url` by `model`.
+    fn get_cached_summary<'a>(
+        &'a self,
+        url: &'a str,
+        model: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>>>;
+
+    /// Cache a generated summary for `url` by `model`.
+    fn store_summary<'a>(
+        &'a self,
+        url: &'a str,
+        model: &'a str,
+        summary: &'a str,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Remember an embed the bot posted of `url` from message
+    /// `source_event_id`.
+    fn record_embed<'a>(
+        &'a self,
+        room_id: &'a str,
+        event_id: &'a str,
+        source_event_id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Return the event IDs of the bot's `limit` most recent embeds in a room,
+    /// newest first.
+    fn recent_embeds<'a>(
+        &'a self,
+        room_id: &'a str,
+        limit: usize,
+    ) -> BoxFuture<'a, Result<Vec<String>>>;
+
+    /// Return the embed already posted of `url` from message
+    /// `source_event_id`, if there is one.
+    fn find_embed<'a>(
+        &'a self,
+        source_event_id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>>>;
+
+    /// Return the bot's most recent embed of `url` in a room, if there is
+    /// one.
+    fn latest_embed<'a>(
+        &'a self,
+        room_id: &'a str,
+        url: &'a str,
+    ) -> BoxFuture<'a, Result<Option<String>>>;
+
+    /// Forget an embed, e.g. once it has been redacted.
+    fn forget_embed<'a>(&'a self, event_id: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Look up an earlier upload of the content with `content_hash`, marking
+    /// it as recently used.
+    fn get_uploaded_media<'a>(
+        &'a self,
+        content_hash: &'a str,
+        encrypted: bool,
+    ) -> BoxFuture<'a, Result<Option<UploadedMedia>>>;
+
+    /// Remember an upload of the content with `content_hash`, then forget the
+    /// least recently used uploads beyond `max_entries`.
+    fn record_uploaded_media<'a>(
+        &'a self,
+        content_hash: &'a str,
+        encrypted: bool,
+        media: &'a UploadedMedia,
+        max_entries: usize,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Forget the upload of the content with `content_hash`, e.g. once it
+    /// has been deleted from the media repository.
+    fn forget_uploaded_media<'a>(
+        &'a self,
+        content_hash: &'a str,
+        encrypted: bool,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Forget embeds older than `embed_max_age` and summaries older than
+    /// `summary_max_age`, and the least recently used uploads beyond
+    /// `upload_max_entries`. `None` keeps everything.
+    fn prune<'a>(
+        &'a self,
+        embed_max_age: Option<Duration>,
+        summary_max_age: Option<Duration>,
+        upload_max_entries: usize,
+    ) -> BoxFuture<'a, Result<PruneStats>>;
+
+    /// Forget the upload of the content with `content_hash` unless any
+    /// instance used it within `max_age`, returning whether it's gone now.
+    /// The check and the removal are one step, so once this returns `true`
+    /// no instance can reuse the upload.
+    fn forget_unused_uploaded_media<'a>(
+        &'a self,
+        content_hash: &'a str,
+        encrypted: bool,
+        max_age: Duration,
+    ) -> BoxFuture<'a, Result<bool>>;
+}
+
+/// Connect to the configured shared store, if it isn't the bot's own
+/// database.
+pub async fn connect(config: &Config) -> Result<Option<Arc<dyn SharedStore>>> {
+    match config.shared_store {
+        StoreBackend::Sqlite => Ok(None),
+        StoreBackend::Redis => {
+            let Some(url) = &config.redis_url else {
+                bail!("--redis-url is required with --shared-store redis");
+            };
+            connect_redis(url, &config.redis_key_prefix).await
+        }
+        StoreBackend::Postgres => {
+            let Some(url) = &config.postgres_url else {
+                bail!("--postgres-url is required with --shared-store postgres");
+            };
+            connect_postgres(url).await
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+async fn connect_redis(url: &str, key_prefix: &str) -> Result<Option<Arc<dyn SharedStore>>> {
+    let store = redis_store::RedisStore::connect(url, key_prefix).await?;
+    Ok(Some(Arc::new(store)))
+}
+
+#[cfg(not(feature = "redis"))]
+async fn connect_redis(_url: &str, _key_prefix: &str) -> Result<Option<Arc<dyn SharedStore>>> {
+    bail!("This build doesn't support Redis; rebuild with the `redis` feature");
+}
+
+#[cfg(feature = "postgres")]
+async fn connect_postgres(url: &str) -> Result<Option<Arc<dyn SharedStore>>> {
+    let store = postgres_store::PostgresStore::connect(url).await?;
+    Ok(Some(Arc::new(store)))
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn connect_postgres(_url: &str) -> Result<Option<Arc<dyn SharedStore>>> {
+    bail!("This build doesn't support Postgres; rebuild with the `postgres` feature");
+}
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use anyhow::{Context, Result};
+    use futures_util::FutureExt;
+    use futures_util::future::BoxFuture;
+    use redis::AsyncCommands;
+    use redis::aio::ConnectionManager;
+    use tracing::info;
+
+    use url::Url;
+
+    use super::SharedStore;
+    use crate::db::{PruneStats, UploadedMedia};
+    use crate::metadata::Metadata;
+
+    /// Number of embeds remembered per room, for cleaning up recent embeds.
+    const ROOM_HISTORY_LEN: isize = 1000;
+
+    /// Look up an upload and mark it as used, in one step so it can't be
+    /// forgotten as unused in between. `KEYS` are the upload and `uploads`,
+    /// `ARGV` the member and the time.
+    const GET_UPLOAD_SCRIPT: &str = r#"
+        local upload = redis.call('HGETALL', KEYS[1])
+        if #upload > 0 then
+            redis.call('ZADD', KEYS[2], ARGV[2], ARGV[1])
+        end
+        return upload
+    "#;
+
+    /// Forget an upload unless it was used after a cutoff, returning 1 if it's
+    /// gone. `KEYS` are the upload and `uploads`, `ARGV` the member and the
+    /// cutoff.
+    const FORGET_UNUSED_UPLOAD_SCRIPT: &str = r#"
+        local used = redis.call('ZSCORE', KEYS[2], ARGV[1])
+        if used and tonumber(used) > tonumber(ARGV[2]) then
+            return 0
+        end
+        redis.call('DEL', KEYS[1])
+        redis.call('ZREM', KEYS[2], ARGV[1])
+        return 1
+    "#;
+
+    /// A [`SharedStore`] in Redis. Keys are:
+    ///
+    /// - `embed:<event ID>`: a hash with the room, source event and URL of an
+    ///   embed.
+    /// - `embed-source:<event ID>`: a hash from URLs to the embeds posted of
+    ///   them from a message.
+    /// - `room-embeds:<room ID>`: a list of recent embeds, newest first.
+    /// - `embeds`: a sorted set of embeds by when they were posted.
+    /// - `metadata:<language>:<URL>`: the metadata of a page as JSON, expiring
+    ///   with its TTL. The language is empty if none was asked for.
+    /// - `summary:<model>`: a hash from URLs to summaries.
+    /// - `summaries`: a sorted set of `<model>` and URL, a newline apart, by
+    ///   when the summary was cached.
+    /// - `upload:<0 or 1>:<hash>`: a hash with the media sources of an upload,
+    ///   unencrypted or encrypted.
+    /// - `uploads`: a sorted set of uploads by when they were last used.
+    ///
+    /// All of them start with the configured prefix.
+    pub struct RedisStore {
+        conn: ConnectionManager,
+        prefix: String,
+    }
+
+    impl RedisStore {
+        pub async fn connect(url: &str, prefix: &str) -> Result<Self> {
+            let client = redis::Client::open(url).context("Invalid Redis URL")?;
+            let conn = client
+                .get_connection_manager()
+                .await
+                .context("Failed to connect to Redis")?;
+            info!("Connected to Redis shared store");
+            Ok(Self {
+                conn,
+                prefix: prefix.to_owned(),
+            })
+        }
+
+        fn key(&self, name: &str) -> String {
+            format!("{}{}", self.prefix, name)
+        }
+
+        fn metadata_key(&self, url: &Url, language: Option<&str>) -> String {
+            self.key(&format!("metadata:{}:{}", language.unwrap_or(""), url))
+        }
+
+        fn upload_member(content_hash: &str, encrypted: bool) -> String {
+            format!("{}:{}", encrypted as u8, content_hash)
+        }
+
+        /// Forget the least recently used uploads beyond `max_entries`,
+        /// returning how many there were.
+        async fn evict_uploads(&self, max_entries: usize) -> Result<usize> {
+            let mut conn = self.conn.clone();
+            // The least recently used uploads sort first.
+            let stale: Vec<String> = conn
+                .zrange(self.key("uploads"), 0, -(max_entries as isize) - 1)
+                .await
+                .context("Failed to query uploaded media")?;
+            if stale.is_empty() {
+                return Ok(0);
+            }
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for member in &stale {
+                pipe.del(self.key(&format!("upload:{}", member))).ignore();
+            }
+            pipe.zrem(self.key("uploads"), &stale).ignore();
+            let _: () = pipe
+                .query_async(&mut conn)
+                .await
+                .context("Failed to evict uploaded media")?;
+            Ok(stale.len())
+        }
+
+        /// The members of the sorted set `name` scored before `max_age` ago.
+        async fn older_than(&self, name: &str, max_age: Duration) -> Result<Vec<String>> {
+            let mut conn = self.conn.clone();
+            let cutoff = chrono::Utc::now().timestamp() - max_age.as_secs() as i64;
+            conn.zrangebyscore(self.key(name), "-inf", cutoff)
+                .await
+                .context("Failed to query shared store")
+        }
+    }
+
+    impl SharedStore for RedisStore {
+        fn get_cached_metadata<'a>(
+            &'a self,
+            url: &'a Url,
+            language: Option<&'a str>,
+        ) -> BoxFuture<'a, Result<Option<Metadata>>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let json: Option<String> = conn
+                    .get(self.metadata_key(url, language))
+                    .await
+                    .context("Failed to query metadata cache")?;
+                json.map(|json| serde_json::from_str(&json))
+                    .transpose()
+                    .context("Failed to parse cached metadata")
+            }
+            .boxed()
+        }
+
+        fn store_metadata<'a>(
+            &'a self,
+            url: &'a Url,
+            language: Option<&'a str>,
+            meta: &'a Metadata,
+            ttl: Duration,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                if ttl.as_secs() == 0 {
+                    return Ok(());
+                }
+                let json = serde_json::to_string(meta).context("Failed to serialize metadata")?;
+                let mut conn = self.conn.clone();
+                let _: () = conn
+                    .set_ex(self.metadata_key(url, language), json, ttl.as_secs())
+                    .await
+                    .context("Failed to store metadata")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn get_cached_summary<'a>(
+            &'a self,
+            url: &'a str,
+            model: &'a str,
+        ) -> BoxFuture<'a, Result<Option<String>>> {
+            async move {
+                let mut conn = self.conn.clone();
+                conn.hget(self.key(&format!("summary:{}", model)), url)
+                    .await
+                    .context("Failed to query summary cache")
+            }
+            .boxed()
+        }
+
+        fn store_summary<'a>(
+            &'a self,
+            url: &'a str,
+            model: &'a str,
+            summary: &'a str,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let _: () = redis::pipe()
+                    .atomic()
+                    .hset(self.key(&format!("summary:{}", model)), url, summary)
+                    .ignore()
+                    .zadd(
+                        self.key("summaries"),
+                        format!("{}\n{}", model, url),
+                        chrono::Utc::now().timestamp(),
+                    )
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await
+                    .context("Failed to store summary")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn record_embed<'a>(
+            &'a self,
+            room_id: &'a str,
+            event_id: &'a str,
+            source_event_id: &'a str,
+            url: &'a str,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let room_key = self.key(&format!("room-embeds:{}", room_id));
+                let _: () = redis::pipe()
+                    .atomic()
+                    .hset_multiple(
+                        self.key(&format!("embed:{}", event_id)),
+                        &[
+                            ("room_id", room_id),
+                            ("source_event_id", source_event_id),
+                            ("url", url),
+                        ],
+                    )
+                    .ignore()
+                    .hset(
+                        self.key(&format!("embed-source:{}", source_event_id)),
+                        url,
+                        event_id,
+                    )
+                    .ignore()
+                    .lpush(&room_key, event_id)
+                    .ignore()
+                    .ltrim(&room_key, 0, ROOM_HISTORY_LEN - 1)
+                    .ignore()
+                    .zadd(self.key("embeds"), event_id, chrono::Utc::now().timestamp())
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await
+                    .context("Failed to record embed")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn recent_embeds<'a>(
+            &'a self,
+            room_id: &'a str,
+            limit: usize,
+        ) -> BoxFuture<'a, Result<Vec<String>>> {
+            async move {
+                if limit == 0 {
+                    return Ok(Vec::new());
+                }
+                let mut conn = self.conn.clone();
+                conn.lrange(
+                    self.key(&format!("room-embeds:{}", room_id)),
+                    0,
+                    limit as isize - 1,
+                )
+                .await
+                .context("Failed to query embed history")
+            }
+            .boxed()
+        }
+
+        fn find_embed<'a>(
+            &'a self,
+            source_event_id: &'a str,
+            url: &'a str,
+        ) -> BoxFuture<'a, Result<Option<String>>> {
+            async move {
+                let mut conn = self.conn.clone();
+                conn.hget(self.key(&format!("embed-source:{}", source_event_id)), url)
+                    .await
+                    .context("Failed to query embed history")
+            }
+            .boxed()
+        }
+
+        fn latest_embed<'a>(
+            &'a self,
+            room_id: &'a str,
+            url: &'a str,
+        ) -> BoxFuture<'a, Result<Option<String>>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let event_ids: Vec<String> = conn
+                    .lrange(self.key(&format!("room-embeds:{}", room_id)), 0, -1)
+                    .await
+                    .context("Failed to query embed history")?;
+                if event_ids.is_empty() {
+                    return Ok(None);
+                }
+                let mut pipe = redis::pipe();
+                for event_id in &event_ids {
+                    pipe.hget(self.key(&format!("embed:{}", event_id)), "url");
+                }
+                let urls: Vec<Option<String>> = pipe
+                    .query_async(&mut conn)
+                    .await
+                    .context("Failed to query embed history")?;
+                Ok(event_ids
+                    .into_iter()
+                    .zip(urls)
+                    .find(|(_, embed_url)| embed_url.as_deref() == Some(url))
+                    .map(|(event_id, _)| event_id))
+            }
+            .boxed()
+        }
+
+        fn forget_embed<'a>(&'a self, event_id: &'a str) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let embed_key = self.key(&format!("embed:{}", event_id));
+                let embed: HashMap<String, String> = conn
+                    .hgetall(&embed_key)
+                    .await
+                    .context("Failed to query embed history")?;
+                let (Some(room_id), Some(source_event_id), Some(url)) = (
+                    embed.get("room_id"),
+                    embed.get("source_event_id"),
+                    embed.get("url"),
+                ) else {
+                    let _: () = conn
+                        .zrem(self.key("embeds"), event_id)
+                        .await
+                        .context("Failed to forget embed")?;
+                    return Ok(());
+                };
+                let _: () = redis::pipe()
+                    .atomic()
+                    .del(&embed_key)
+                    .ignore()
+                    .hdel(self.key(&format!("embed-source:{}", source_event_id)), url)
+                    .ignore()
+                    .lrem(self.key(&format!("room-embeds:{}", room_id)), 0, event_id)
+                    .ignore()
+                    .zrem(self.key("embeds"), event_id)
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await
+                    .context("Failed to forget embed")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn get_uploaded_media<'a>(
+            &'a self,
+            content_hash: &'a str,
+            encrypted: bool,
+        ) -> BoxFuture<'a, Result<Option<UploadedMedia>>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let member = Self::upload_member(content_hash, encrypted);
+                let mut upload: HashMap<String, String> = redis::cmd("EVAL")
+                    .arg(GET_UPLOAD_SCRIPT)
+                    .arg(2)
+                    .arg(self.key(&format!("upload:{}", member)))
+                    .arg(self.key("uploads"))
+                    .arg(&member)
+                    .arg(chrono::Utc::now().timestamp())
+                    .query_async(&mut conn)
+                    .await
+                    .context("Failed to query uploaded media")?;
+                let Some(source) = upload.remove("source") else {
+                    return Ok(None);
+                };
+                Ok(Some(UploadedMedia {
+                    source,
+                    thumbnail_source: upload.remove("thumbnail_source"),
+                }))
+            }
+            .boxed()
+        }
+
+        fn record_uploaded_media<'a>(
+            &'a self,
+            content_hash: &'a str,
+            encrypted: bool,
+            media: &'a UploadedMedia,
+            max_entries: usize,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let member = Self::upload_member(content_hash, encrypted);
+                let upload_key = self.key(&format!("upload:{}", member));
+                let mut fields = vec![("source", media.source.as_str())];
+                if let Some(thumbnail_source) = &media.thumbnail_source {
+                    fields.push(("thumbnail_source", thumbnail_source));
+                }
+                let _: () = redis::pipe()
+                    .atomic()
+                    .del(&upload_key)
+                    .ignore()
+                    .hset_multiple(&upload_key, &fields)
+                    .ignore()
+                    .zadd(self.key("uploads"), &member, chrono::Utc::now().timestamp())
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await
+                    .context("Failed to record uploaded media")?;
+                self.evict_uploads(max_entries).await?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn forget_uploaded_media<'a>(
+            &'a self,
+            content_hash: &'a str,
+            encrypted: bool,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let member = Self::upload_member(content_hash, encrypted);
+                let _: () = redis::pipe()
+                    .atomic()
+                    .del(self.key(&format!("upload:{}", member)))
+                    .ignore()
+                    .zrem(self.key("uploads"), &member)
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await
+                    .context("Failed to forget uploaded media")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn prune<'a>(
+            &'a self,
+            embed_max_age: Option<Duration>,
+            summary_max_age: Option<Duration>,
+            upload_max_entries: usize,
+        ) -> BoxFuture<'a, Result<PruneStats>> {
+            async move {
+                let mut stats = PruneStats::default();
+                if let Some(max_age) = embed_max_age {
+                    let event_ids = self.older_than("embeds", max_age).await?;
+                    for event_id in &event_ids {
+                        self.forget_embed(event_id).await?;
+                    }
+                    stats.embeds = event_ids.len();
+                }
+                if let Some(max_age) = summary_max_age {
+                    let members = self.older_than("summaries", max_age).await?;
+                    if !members.is_empty() {
+                        let mut pipe = redis::pipe();
+                        pipe.atomic();
+                        for member in &members {
+                            if let Some((model, url)) = member.split_once('\n') {
+                                pipe.hdel(self.key(&format!("summary:{}", model)), url)
+                                    .ignore();
+                            }
+                        }
+                        pipe.zrem(self.key("summaries"), &members).ignore();
+                        let mut conn = self.conn.clone();
+                        let _: () = pipe
+                            .query_async(&mut conn)
+                            .await
+                            .context("Failed to prune summary cache")?;
+                    }
+                    stats.summaries = members.len();
+                }
+                stats.uploads = self.evict_uploads(upload_max_entries).await?;
+                Ok(stats)
+            }
+            .boxed()
+        }
+
+        fn forget_unused_uploaded_media<'a>(
+            &'a self,
+            content_hash: &'a str,
+            encrypted: bool,
+            max_age: Duration,
+        ) -> BoxFuture<'a, Result<bool>> {
+            async move {
+                let mut conn = self.conn.clone();
+                let member = Self::upload_member(content_hash, encrypted);
+                let cutoff = chrono::Utc::now().timestamp() - max_age.as_secs() as i64;
+                let forgotten: bool = redis::cmd("EVAL")
+                    .arg(FORGET_UNUSED_UPLOAD_SCRIPT)
+                    .arg(2)
+                    .arg(self.key(&format!("upload:{}", member)))
+                    .arg(self.key("uploads"))
+                    .arg(&member)
+                    .arg(cutoff)
+                    .query_async(&mut conn)
+                    .await
+                    .context("Failed to forget uploaded media")?;
+                Ok(forgotten)
+            }
+            .boxed()
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres_store {
+    use std::time::Duration;
+
+    use anyhow::{Context, Result};
+    use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod};
+    use futures_util::FutureExt;
+    use futures_util::future::BoxFuture;
+    use tokio_postgres::NoTls;
+    use tracing::info;
+
+    use url::Url;
+
+    use super::SharedStore;
+    use crate::db::{PruneStats, UploadedMedia};
+    use crate::metadata::Metadata;
+
+    /// Most connections each instance keeps open.
+    const POOL_SIZE: usize = 8;
+
+    /// The tables, created when connecting if they don't exist yet. Other
+    /// instances may be using them, so changes to this need to keep working
+    /// with the tables as they are.
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS embed_history (
+            id              BIGSERIAL PRIMARY KEY,
+            room_id         TEXT NOT NULL,
+            event_id        TEXT NOT NULL UNIQUE,
+            source_event_id TEXT NOT NULL,
+            url             TEXT NOT NULL,
+            posted_at       TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        CREATE INDEX IF NOT EXISTS embed_history_room ON embed_history (room_id, id);
+        CREATE INDEX IF NOT EXISTS embed_history_source
+            ON embed_history (source_event_id, url);
+        CREATE INDEX IF NOT EXISTS embed_history_posted ON embed_history (posted_at);
+        CREATE TABLE IF NOT EXISTS metadata_cache (
+            url        TEXT NOT NULL,
+            language   TEXT NOT NULL,
+            metadata   TEXT NOT NULL,
+            expires_at TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (url, language)
+        );
+        CREATE TABLE IF NOT EXISTS summary_cache (
+            url        TEXT NOT NULL,
+            model      TEXT NOT NULL,
+            summary    TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (url, model)
+        );
+        CREATE TABLE IF NOT EXISTS uploaded_media (
+            content_hash     TEXT NOT NULL,
+            encrypted        BOOLEAN NOT NULL,
+            source           TEXT NOT NULL,
+            thumbnail_source TEXT,
+            last_used_at     TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (content_hash, encrypted)
+        );
+        CREATE INDEX IF NOT EXISTS uploaded_media_last_used
+            ON uploaded_media (last_used_at);
+    ";
+
+    /// Forget the least recently used uploads beyond `$1`.
+    const EVICT_UPLOADS: &str = "
+        DELETE FROM uploaded_media WHERE (content_hash, encrypted) NOT IN (
+            SELECT content_hash, encrypted FROM uploaded_media
+            ORDER BY last_used_at DESC LIMIT $1
+        )";
+
+    /// A [`SharedStore`] in Postgres, in tables of the same names as the
+    /// SQLite ones. They go in the connection's schema, so several bots can
+    /// share a database by setting `options=-csearch_path%3D<schema>` in the
+    /// URL. Connections aren't encrypted, so the server should be on a
+    /// private network.
+    pub struct PostgresStore {
+        pool: Pool,
+    }
+
+    impl PostgresStore {
+        pub async fn connect(url: &str) -> Result<Self> {
+            let config: tokio_postgres::Config = url.parse().context("Invalid Postgres URL")?;
+            let manager = Manager::from_config(
+                config,
+                NoTls,
+                ManagerConfig {
+                    recycling_method: RecyclingMethod::Fast,
+                },
+            );
+            let pool = Pool::builder(manager)
+                .max_size(POOL_SIZE)
+                .build()
+                .context("Failed to set up Postgres connections")?;
+            let store = Self { pool };
+            store
+                .client()
+                .await?
+                .batch_execute(SCHEMA)
+                .await
+                .context("Failed to create the shared store's tables")?;
+            info!("Connected to Postgres shared store");
+            Ok(store)
+        }
+
+        async fn client(&self) -> Result<Object> {
+            self.pool
+                .get()
+                .await
+                .context("Failed to connect to Postgres")
+        }
+    }
+
+    /// `duration` in seconds, as Postgres's `make_interval` takes it.
+    fn secs(duration: Duration) -> f64 {
+        duration.as_secs_f64()
+    }
+
+    impl SharedStore for PostgresStore {
+        fn get_cached_metadata<'a>(
+            &'a self,
+            url: &'a Url,
+            language: Option<&'a str>,
+        ) -> BoxFuture<'a, Result<Option<Metadata>>> {
+            async move {
+                let row = self
+                    .client()
+                    .await?
+                    .query_opt(
+                        "SELECT metadata FROM metadata_cache
+                         WHERE url = $1 AND language = $2 AND expires_at > now()",
+                        &[&url.as_str(), &language.unwrap_or("")],
+                    )
+                    .await
+                    .context("Failed to query metadata cache")?;
+                row.map(|row| serde_json::from_str(row.get(0)))
+                    .transpose()
+                    .context("Failed to parse cached metadata")
+            }
+            .boxed()
+        }
+
+        fn store_metadata<'a>(
+            &'a self,
+            url: &'a Url,
+            language: Option<&'a str>,
+            meta: &'a Metadata,
+            ttl: Duration,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                if ttl.as_secs() == 0 {
+                    return Ok(());
+                }
+                let json = serde_json::to_string(meta).context("Failed to serialize metadata")?;
+                self.client()
+                    .await?
+                    .execute(
+                        "INSERT INTO metadata_cache (url, language, metadata, expires_at)
+                         VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+                         ON CONFLICT (url, language) DO UPDATE
+                         SET metadata = EXCLUDED.metadata, expires_at = EXCLUDED.expires_at",
+                        &[&url.as_str(), &language.unwrap_or(""), &json, &secs(ttl)],
+                    )
+                    .await
+                    .context("Failed to store metadata")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn get_cached_summary<'a>(
+            &'a self,
+            url: &'a str,
+            model: &'a str,
+        ) -> BoxFuture<'a, Result<Option<String>>> {
+            async move {
+                let row = self
+                    .client()
+                    .await?
+                    .query_opt(
+                        "SELECT summary FROM summary_cache WHERE url = $1 AND model = $2",
+                        &[&url, &model],
+                    )
+                    .await
+                    .context("Failed to query summary cache")?;
+                Ok(row.map(|row| row.get(0)))
+            }
+            .boxed()
+        }
+
+        fn store_summary<'a>(
+            &'a self,
+            url: &'a str,
+            model: &'a str,
+            summary: &'a str,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                self.client()
+                    .await?
+                    .execute(
+                        "INSERT INTO summary_cache (url, model, summary) VALUES ($1, $2, $3)
+                         ON CONFLICT (url, model) DO UPDATE
+                         SET summary = EXCLUDED.summary, created_at = now()",
+                        &[&url, &model, &summary],
+                    )
+                    .await
+                    .context("Failed to store summary")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn record_embed<'a>(
+            &'a self,
+            room_id: &'a str,
+            event_id: &'a str,
+            source_event_id: &'a str,
+            url: &'a str,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                self.client()
+                    .await?
+                    .execute(
+                        "INSERT INTO embed_history (room_id, event_id, source_event_id, url)
+                         VALUES ($1, $2, $3, $4)
+                         ON CONFLICT (event_id) DO NOTHING",
+                        &[&room_id, &event_id, &source_event_id, &url],
+                    )
+                    .await
+                    .context("Failed to record embed")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn recent_embeds<'a>(
+            &'a self,
+            room_id: &'a str,
+            limit: usize,
+        ) -> BoxFuture<'a, Result<Vec<String>>> {
+            async move {
+                let rows = self
+                    .client()
+                    .await?
+                    .query(
+                        "SELECT event_id FROM embed_history WHERE room_id = $1
+                         ORDER BY id DESC LIMIT $2",
+                        &[&room_id, &(limit as i64)],
+                    )
+                    .await
+                    .context("Failed to query embed history")?;
+                Ok(rows.iter().map(|row| row.get(0)).collect())
+            }
+            .boxed()
+        }
+
+        fn find_embed<'a>(
+            &'a self,
+            source_event_id: &'a str,
+            url: &'a str,
+        ) -> BoxFuture<'a, Result<Option<String>>> {
+            async move {
+                let row = self
+                    .client()
+                    .await?
+                    .query_opt(
+                        "SELECT event_id FROM embed_history WHERE source_event_id = $1 AND url = $2
+                         ORDER BY id DESC LIMIT 1",
+                        &[&source_event_id, &url],
+                    )
+                    .await
+                    .context("Failed to query embed history")?;
+                Ok(row.map(|row| row.get(0)))
+            }
+            .boxed()
+        }
+
+        fn latest_embed<'a>(
+            &'a self,
+            room_id: &'a str,
+            url: &'a str,
+        ) -> BoxFuture<'a, Result<Option<String>>> {
+            async move {
+                let row = self
+                    .client()
+                    .await?
+                    .query_opt(
+                        "SELECT event_id FROM embed_history WHERE room_id = $1 AND url = $2
+                         ORDER BY id DESC LIMIT 1",
+                        &[&room_id, &url],
+                    )
+                    .await
+                    .context("Failed to query embed history")?;
+                Ok(row.map(|row| row.get(0)))
+            }
+            .boxed()
+        }
+
+        fn forget_embed<'a>(&'a self, event_id: &'a str) -> BoxFuture<'a, Result<()>> {
+            async move {
+                self.client()
+                    .await?
+                    .execute(
+                        "DELETE FROM embed_history WHERE event_id = $1",
+                        &[&event_id],
+                    )
+                    .await
+                    .context("Failed to forget embed")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn get_uploaded_media<'a>(
+            &'a self,
+            content_hash: &'a str,
+            encrypted: bool,
+        ) -> BoxFuture<'a, Result<Option<UploadedMedia>>> {
+            async move {
+                let row = self
+                    .client()
+                    .await?
+                    .query_opt(
+                        "UPDATE uploaded_media SET last_used_at = now()
+                         WHERE content_hash = $1 AND encrypted = $2
+                         RETURNING source, thumbnail_source",
+                        &[&content_hash, &encrypted],
+                    )
+                    .await
+                    .context("Failed to query uploaded media")?;
+                Ok(row.map(|row| UploadedMedia {
+                    source: row.get(0),
+                    thumbnail_source: row.get(1),
+                }))
+            }
+            .boxed()
+        }
+
+        fn record_uploaded_media<'a>(
+            &'a self,
+            content_hash: &'a str,
+            encrypted: bool,
+            media: &'a UploadedMedia,
+            max_entries: usize,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let client = self.client().await?;
+                client
+                    .execute(
+                        "INSERT INTO uploaded_media
+                             (content_hash, encrypted, source, thumbnail_source)
+                         VALUES ($1, $2, $3, $4)
+                         ON CONFLICT (content_hash, encrypted) DO UPDATE
+                         SET source = EXCLUDED.source,
+                             thumbnail_source = EXCLUDED.thumbnail_source,
+                             last_used_at = now()",
+                        &[
+                            &content_hash,
+                            &encrypted,
+                            &media.source,
+                            &media.thumbnail_source,
+                        ],
+                    )
+                    .await
+                    .context("Failed to record uploaded media")?;
+                client
+                    .execute(EVICT_UPLOADS, &[&(max_entries as i64)])
+                    .await
+                    .context("Failed to evict uploaded media")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn forget_uploaded_media<'a>(
+            &'a self,
+            content_hash: &'a str,
+            encrypted: bool,
+        ) -> BoxFuture<'a, Result<()>> {
+            async move {
+                self.client()
+                    .await?
+                    .execute(
+                        "DELETE FROM uploaded_media WHERE content_hash = $1 AND encrypted = $2",
+                        &[&content_hash, &encrypted],
+                    )
+                    .await
+                    .context("Failed to forget uploaded media")?;
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn prune<'a>(
+            &'a self,
+            embed_max_age: Option<Duration>,
+            summary_max_age: Option<Duration>,
+            upload_max_entries: usize,
+        ) -> BoxFuture<'a, Result<PruneStats>> {
+            async move {
+                let client = self.client().await?;
+                let mut stats = PruneStats::default();
+                if let Some(max_age) = embed_max_age {
+                    stats.embeds = client
+                        .execute(
+                            "DELETE FROM embed_history
+                             WHERE posted_at < now() - make_interval(secs => $1)",
+                            &[&secs(max_age)],
+                        )
+                        .await
+                        .context("Failed to prune embed history")?
+                        as usize;
+                }
+                if let Some(max_age) = summary_max_age {
+                    stats.summaries = client
+                        .execute(
+                            "DELETE FROM summary_cache
+                             WHERE created_at < now() - make_interval(secs => $1)",
+                            &[&secs(max_age)],
+                        )
+                        .await
+                        .context("Failed to prune summary cache")?
+                        as usize;
+                }
+                stats.uploads = client
+                    .execute(EVICT_UPLOADS, &[&(upload_max_entries as i64)])
+                    .await
+                    .context("Failed to evict uploaded media")?
+                    as usize;
+                // Expired metadata is never read again.
+                client
+                    .execute("DELETE FROM metadata_cache WHERE expires_at <= now()", &[])
+                    .await
+                    .context("Failed to prune metadata cache")?;
+                Ok(stats)
+            }
+            .boxed()
+        }
+
+        fn forget_unused_uploaded_media<'a>(
+            &'a self,
+            content_hash: &'a str,
+            encrypted: bool,
+            max_age: Duration,
+        ) -> BoxFuture<'a, Result<bool>> {
+            async move {
+                // Both parts of the statement see the table as it was before
+                // the delete, so the upload is kept only if it was used since
+                // the cutoff.
+                let row = self
+                    .client()
+                    .await?
+                    .query_one(
+                        "WITH forgotten AS (
+                             DELETE FROM uploaded_media
+                             WHERE content_hash = $1 AND encrypted = $2
+                                 AND last_used_at <= now() - make_interval(secs => $3)
+                         )
+                         SELECT NOT EXISTS (
+                             SELECT 1 FROM uploaded_media
+                             WHERE content_hash = $1 AND encrypted = $2
+                                 AND last_used_at > now() - make_interval(secs => $3)
+                         )",
+                        &[&content_hash, &encrypted, &secs(max_age)],
+                    )
+                    .await
+                    .context("Failed to forget uploaded media")?;
+                Ok(row.get(0))
+            }
+            .boxed()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+
+    /// Run the shared parts of [`Database`] against `store`, or against the
+    /// database itself with `None`. IDs are unique to the run, so a server
+    /// left with data from earlier runs is fine.
+    async fn check_store(store: Option<Arc<dyn SharedStore>>) {
+        let mut db = Database::open_in_memory().await.unwrap();
+        if let Some(store) = store {
+            db.set_shared_store(store);
+        }
+        let run = rand::random::<u64>();
+        let room = format!("!room{run}:example.com");
+        let source = format!("$source{run}");
+        let (first, second) = (format!("$first{run}"), format!("$second{run}"));
+        let link = format!("https://example.com/{run}");
+
+        db.record_embed(&room, &first, &source, &link)
+            .await
+            .unwrap();
+        db.record_embed(&room, &second, &source, &link)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.recent_embeds(&room, 10).await.unwrap(),
+            vec![second.clone(), first.clone()]
+        );
+        assert_eq!(
+            db.recent_embeds(&room, 1).await.unwrap(),
+            vec![second.clone()]
+        );
+        assert_eq!(
+            db.find_embed(&source, &link).await.unwrap(),
+            Some(second.clone())
+        );
+        assert_eq!(
+            db.latest_embed(&room, &link).await.unwrap(),
+            Some(second.clone())
+        );
+        db.forget_embed(&second).await.unwrap();
+        assert_eq!(db.find_embed(&source, &link).await.unwrap(), Some(first));
+        assert_eq!(db.find_embed("$other", &link).await.unwrap(), None);
+
+        assert_eq!(db.get_cached_summary(&link, "model").await.unwrap(), None);
+        db.store_summary(&link, "model", "A summary.")
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_cached_summary(&link, "model").await.unwrap(),
+            Some("A summary.".to_string())
+        );
+        assert_eq!(db.get_cached_summary(&link, "other").await.unwrap(), None);
+
+        let url = Url::parse(&link).unwrap();
+        let meta = Metadata {
+            title: Some("Title".to_string()),
+            ..Default::default()
+        };
+        db.store_metadata(&url, Some("en"), &meta, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_cached_metadata(&url, Some("en")).await.unwrap(),
+            Some(meta.clone())
+        );
+        assert_eq!(
+            db.get_cached_metadata(&url, Some("de")).await.unwrap(),
+            None
+        );
+        db.store_metadata(&url, None, &meta, Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(db.get_cached_metadata(&url, None).await.unwrap(), None);
+
+        let hash = format!("hash{run}");
+        let media = UploadedMedia {
+            source: format!("mxc://example.com/{run}"),
+            thumbnail_source: None,
+        };
+        db.record_uploaded_media(&hash, false, &media, 1000)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_uploaded_media(&hash, false).await.unwrap(),
+            Some(media.clone())
+        );
+        assert_eq!(db.get_uploaded_media(&hash, true).await.unwrap(), None);
+        assert!(
+            !db.forget_unused_uploaded_media(&hash, false, Duration::from_secs(3600))
+                .await
+                .unwrap()
+        );
+        assert!(
+            db.forget_unused_uploaded_media(&hash, false, Duration::ZERO)
+                .await
+                .unwrap()
+        );
+        assert_eq!(db.get_uploaded_media(&hash, false).await.unwrap(), None);
+        db.record_uploaded_media(&hash, true, &media, 1000)
+            .await
+            .unwrap();
+        db.forget_uploaded_media(&hash, true).await.unwrap();
+        assert_eq!(db.get_uploaded_media(&hash, true).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        check_store(None).await;
+    }
+
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    async fn test_redis_store() {
+        let Ok(url) = std::env::var("TEST_REDIS_URL") else {
+            eprintln!("TEST_REDIS_URL isn't set; skipping");
+            return;
+        };
+        let store = redis_store::RedisStore::connect(&url, "matrix-embed-test:")
+            .await
+            .unwrap();
+        check_store(Some(Arc::new(store))).await;
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn test_postgres_store() {
+        let Ok(url) = std::env::var("TEST_POSTGRES_URL") else {
+            eprintln!("TEST_POSTGRES_URL isn't set; skipping");
+            return;
+        };
+        let store = postgres_store::PostgresStore::connect(&url).await.unwrap();
+        check_store(Some(Arc::new(store))).await;
+    }
+}
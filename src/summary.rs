@@ -0,0 +1,230 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::cas;
+use crate::config::Config;
+use crate::db::Database;
+
+/// Maximum number of characters of page text sent to the model.
+const MAX_INPUT_CHARS: usize = 12_000;
+
+#[derive(Debug, Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatChoice {
+    message: ChatResponseMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResponseMessage {
+    content: Option<String>,
+}
+
+/// Returns `true` if `text` is long enough to be worth summarizing.
+pub fn is_long_form(text: &str, config: &Config) -> bool {
+    text.split_whitespace().count() >= config.summary_min_words
+}
+
+/// Summarize the readable text of `url` using the configured
+/// OpenAI-compatible endpoint.
+///
+/// Summaries are cached per URL, model and prompt, so repeated links don't
+/// incur another API call, unless a `fresh` one is asked for. Returns `Ok(None)` if
+/// summaries aren't configured.
+pub async fn summarize(
+    client: &reqwest::Client,
+    config: &Config,
+    database: &Database,
+    url: &Url,
+    text: &str,
+    fresh: bool,
+) -> Result<Option<String>> {
+    let Some(api_url) = &config.summary_api_url else {
+        return Ok(None);
+    };
+
+    if !fresh
+        && let Some(summary) = database
+            .get_cached_summary(url.as_str(), &cache_key(config))
+            .await?
+    {
+        debug!("Using cached summary for {}", url);
+        return Ok(Some(summary));
+    }
+
+    let input: String = text.chars().take(MAX_INPUT_CHARS).collect();
+    let request = ChatRequest {
+        model: &config.summary_model,
+        messages: vec![
+            ChatMessage {
+                role: "system",
+                content: &config.summary_prompt,
+            },
+            ChatMessage {
+                role: "user",
+                content: &input,
+            },
+        ],
+    };
+
+    let endpoint = chat_completions_url(api_url)?;
+    info!("Requesting summary for {} from {}", url, endpoint);
+
+    let mut builder = client
+        .post(endpoint)
+        .timeout(config.download_timeout)
+        .json(&request);
+    if let Some(key) = &config.summary_api_key {
+        builder = builder.bearer_auth(key);
+    }
+
+    let response: ChatResponse = builder
+        .send()
+        .await
+        .context("Failed to send summary request")?
+        .error_for_status()
+        .context("Summary endpoint returned error status")?
+        .json()
+        .await
+        .context("Failed to parse summary response")?;
+
+    let summary = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|c| c.message.content)
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty());
+
+    if let Some(summary) = &summary {
+        database
+            .store_summary(url.as_str(), &cache_key(config), summary)
+            .await?;
+    }
+
+    Ok(summary)
+}
+
+/// What summaries are cached under, besides the URL: the model, with a hash
+/// of the prompt, so changing either doesn't reuse summaries made with the
+/// old one.
+fn cache_key(config: &Config) -> String {
+    let prompt_hash = cas::content_hash(config.summary_prompt.as_bytes());
+    format!("{}#{}", config.summary_model, &prompt_hash[..16])
+}
+
+/// Resolve `chat/completions` relative to the configured base URL, which may
+/// or may not have a trailing slash (e.g. `https://api.openai.com/v1`).
+fn chat_completions_url(base: &Url) -> Result<Url> {
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base.join("chat/completions")
+        .context("Invalid summary API URL")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_chat_completions_url() {
+        let base = Url::parse("https://api.example.com/v1").unwrap();
+        assert_eq!(
+            chat_completions_url(&base).unwrap().as_str(),
+            "https://api.example.com/v1/chat/completions"
+        );
+        let base = Url::parse("https://api.example.com/v1/").unwrap();
+        assert_eq!(
+            chat_completions_url(&base).unwrap().as_str(),
+            "https://api.example.com/v1/chat/completions"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summarize_caches_result() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("authorization", "Bearer secret"))
+            .and(body_partial_json(
+                serde_json::json!({ "model": "test-model" }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "choices": [{ "message": { "role": "assistant", "content": " A summary. " } }]
+            })))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        let config = Config {
+            summary_api_url: Some(Url::parse(&format!("{}/v1", mock_server.uri())).unwrap()),
+            summary_api_key: Some("secret".to_string()),
+            summary_model: "test-model".to_string(),
+            ..Default::default()
+        };
+        let database = Database::open_in_memory().await.unwrap();
+        let client = reqwest::Client::new();
+        let url = Url::parse("https://example.com/article").unwrap();
+
+        for _ in 0..2 {
+            let summary = summarize(&client, &config, &database, &url, "Some text", false)
+                .await
+                .unwrap();
+            assert_eq!(summary.as_deref(), Some("A summary."));
+        }
+        // A fresh summary skips the cache.
+        let summary = summarize(&client, &config, &database, &url, "Some text", true)
+            .await
+            .unwrap();
+        assert_eq!(summary.as_deref(), Some("A summary."));
+
+        // So does one asked for with another prompt.
+        let config = Config {
+            summary_prompt: "Summarize in French.".to_string(),
+            ..config
+        };
+        let summary = summarize(&client, &config, &database, &url, "Some text", false)
+            .await
+            .unwrap();
+        assert_eq!(summary.as_deref(), Some("A summary."));
+    }
+
+    #[tokio::test]
+    async fn test_summarize_not_configured() {
+        let config = Config::default();
+        let database = Database::open_in_memory().await.unwrap();
+        let url = Url::parse("https://example.com/article").unwrap();
+        let summary = summarize(
+            &reqwest::Client::new(),
+            &config,
+            &database,
+            &url,
+            "text",
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(summary.is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeDelta, Utc};
+use chrono_tz::Tz;
+
+use crate::config::TimeStyle;
+
+/// How times in a room's embeds are written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeFormat {
+    pub timezone: Tz,
+    pub style: TimeStyle,
+    /// `strftime`-style format for absolute times.
+    pub date_format: String,
+}
+
+impl TimeFormat {
+    /// Render `time` in this format, relative to `now` if that's the style.
+    pub fn render(&self, time: &DateTime<FixedOffset>, now: DateTime<Utc>) -> String {
+        match self.style {
+            TimeStyle::Absolute => time
+                .with_timezone(&self.timezone)
+                .format(&self.date_format)
+                .to_string(),
+            TimeStyle::Relative => relative(time.with_timezone(&Utc), now),
+        }
+    }
+}
+
+/// Parse a timestamp as pages give them: RFC 3339, the same without the
+/// colon in the offset, or a bare date (taken as midnight UTC).
+pub fn parse(value: &str) -> Option<DateTime<FixedOffset>> {
+    let value = value.trim();
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%z"))
+        .or_else(|_| DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .ok()
+        .or_else(|| {
+            let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+            Some(date.and_hms_opt(0, 0, 0)?.and_utc().fixed_offset())
+        })
+}
+
+/// Describe how long before (or after) `now` `time` is, e.g. "3 hours ago".
+fn relative(time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let delta = now.signed_duration_since(time);
+    let seconds = delta.num_seconds().unsigned_abs();
+    if seconds < 60 {
+        return "just now".to_string();
+    }
+
+    const UNITS: &[(&str, u64)] = &[
+        ("year", 365 * 24 * 3600),
+        ("month", 30 * 24 * 3600),
+        ("week", 7 * 24 * 3600),
+        ("day", 24 * 3600),
+        ("hour", 3600),
+        ("minute", 60),
+    ];
+    let (unit, size) = UNITS
+        .iter()
+        .find(|(_, size)| seconds >= *size)
+        .copied()
+        .unwrap_or(("minute", 60));
+    let count = seconds / size;
+    let plural = if count == 1 { "" } else { "s" };
+    if delta < TimeDelta::zero() {
+        format!("in {} {}{}", count, unit, plural)
+    } else {
+        format!("{} {}{} ago", count, unit, plural)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let expected = DateTime::parse_from_rfc3339("2025-01-15T12:00:00+01:00").unwrap();
+        assert_eq!(parse("2025-01-15T12:00:00+01:00"), Some(expected));
+        assert_eq!(parse(" 2025-01-15T12:00:00+0100 "), Some(expected));
+        assert_eq!(
+            parse("2025-01-15"),
+            Some(DateTime::parse_from_rfc3339("2025-01-15T00:00:00Z").unwrap())
+        );
+        assert_eq!(parse("last Tuesday"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let time = DateTime::parse_from_rfc3339("2025-01-15T12:00:00Z").unwrap();
+        let now = DateTime::parse_from_rfc3339("2025-01-15T15:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let mut format = TimeFormat {
+            timezone: chrono_tz::Europe::Berlin,
+            style: TimeStyle::Absolute,
+            date_format: "%a %b %-d %Y, %H:%M %Z".to_string(),
+        };
+        assert_eq!(format.render(&time, now), "Wed Jan 15 2025, 13:00 CET");
+
+        format.style = TimeStyle::Relative;
+        assert_eq!(format.render(&time, now), "3 hours ago");
+        assert_eq!(format.render(&now.fixed_offset(), now), "just now");
+        let later = DateTime::parse_from_rfc3339("2025-01-16T15:30:00Z").unwrap();
+        assert_eq!(format.render(&later, now), "in 1 day");
+        let earlier = DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z").unwrap();
+        assert_eq!(format.render(&earlier, now), "2 years ago");
+    }
+}
@@ -2,11 +2,13 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedTransactionId, RoomId};
 use tokio::sync::Mutex;
 use tracing::debug;
 use url::Url;
 
+use crate::cas;
+
 /// Maximum age of tracked events before they are eligible for cleanup.
 const MAX_EVENT_AGE: Duration = Duration::from_secs(15 * 60);
 
@@ -15,23 +17,91 @@ const CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Clone)]
 pub struct TrackedEntry {
-    /// The URL that was extracted from the message (if any).
-    pub extracted_url: Option<Url>,
-    /// Reply event ID (if any).
-    pub reply_event_id: Option<OwnedEventId>,
+    /// The URLs that were extracted from the message to embed.
+    pub extracted_urls: Vec<Url>,
+    /// The events of our replies, each embed's main one first. Caption
+    /// layouts that put the text and media in separate events post two.
+    pub reply_event_ids: Vec<OwnedEventId>,
     /// When this entry was created.
     created_at: Instant,
 }
 
+/// The most recent embed of a canonical URL in a room.
+struct RecentEmbed {
+    event_id: OwnedEventId,
+    embedded_at: Instant,
+}
+
+/// Transaction IDs for the events of one embed, derived from the message and
+/// URL it embeds. Sending an event again after a crash or restart reuses its
+/// transaction ID, so the homeserver returns the event it already has
+/// instead of posting a duplicate.
+///
+/// Once an embed has been redacted, posting it again needs new transaction
+/// IDs, or the homeserver would hand back the redacted events. So each
+/// redaction moves the message and URL on to a new `generation`.
+pub struct EmbedTxns {
+    source: OwnedEventId,
+    url: Url,
+    generation: u32,
+}
+
+impl EmbedTxns {
+    pub fn new(source: &EventId, url: &Url, generation: u32) -> Self {
+        Self {
+            source: source.to_owned(),
+            url: url.clone(),
+            generation,
+        }
+    }
+
+    /// The message whose link is embedded.
+    pub fn source(&self) -> &EventId {
+        &self.source
+    }
+
+    /// The embedded link.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The transaction ID for the embed's `part`, e.g. `"embed"` for the
+    /// main event. Alternatives for the same event (like the text fallback
+    /// when media fails) share a part, since only one of them is posted.
+    pub fn txn_id(&self, part: &str) -> OwnedTransactionId {
+        let mut key = format!("{}\n{}\n{}", self.source, self.url, part);
+        // The first generation keeps the IDs from before there were any.
+        if self.generation > 0 {
+            key.push_str(&format!("\n{}", self.generation));
+        }
+        format!("embed-{}", &cas::content_hash(key.as_bytes())[..32]).into()
+    }
+}
+
+/// How many times the embed of a URL from a message has been redacted.
+struct Generation {
+    generation: u32,
+    bumped_at: Instant,
+}
+
 /// Tracks embed tasks keyed by the original message's event ID.
 pub struct EventTracker {
     entries: Mutex<HashMap<OwnedEventId, TrackedEntry>>,
+    /// Canonical URLs embedded recently, for deduplication.
+    recent_urls: Mutex<HashMap<(OwnedRoomId, String), RecentEmbed>>,
+    /// How long an embedded URL suppresses further embeds of it in the room.
+    dedup_window: Duration,
+    /// Generations of [`EmbedTxns`] past the first, by message and URL.
+    generations: Mutex<HashMap<(OwnedEventId, String), Generation>>,
 }
 
 impl EventTracker {
-    pub fn new() -> Self {
+    pub fn new(dedup_window: Duration) -> Self {
         Self {
             entries: Mutex::new(HashMap::new()),
+            recent_urls: Mutex::new(HashMap::new()),
+            dedup_window,
+            generations: Mutex::new(HashMap::new()),
         }
     }
 
@@ -39,27 +109,118 @@ impl EventTracker {
     pub async fn register(
         &self,
         original_event_id: OwnedEventId,
-        url: Option<Url>,
-        reply_event_id: Option<OwnedEventId>,
+        urls: Vec<Url>,
+        reply_event_ids: Vec<OwnedEventId>,
     ) {
         let mut entries = self.entries.lock().await;
 
         entries.insert(
             original_event_id,
             TrackedEntry {
-                extracted_url: url,
-                reply_event_id,
+                extracted_urls: urls,
+                reply_event_ids,
                 created_at: Instant::now(),
             },
         );
     }
 
+    /// Add the embed of `url` to the task for `original_event_id`,
+    /// registering one if there isn't one yet.
+    pub async fn add(
+        &self,
+        original_event_id: OwnedEventId,
+        url: Url,
+        reply_event_ids: Vec<OwnedEventId>,
+    ) {
+        let mut entries = self.entries.lock().await;
+
+        let entry = entries
+            .entry(original_event_id)
+            .or_insert_with(|| TrackedEntry {
+                extracted_urls: Vec::new(),
+                reply_event_ids: Vec::new(),
+                created_at: Instant::now(),
+            });
+        if !entry.extracted_urls.contains(&url) {
+            entry.extracted_urls.push(url);
+        }
+        entry.reply_event_ids.extend(reply_event_ids);
+    }
+
     pub async fn get_event_entry(&self, original_event_id: &OwnedEventId) -> Option<TrackedEntry> {
         let entries = self.entries.lock().await;
         entries.get(original_event_id).cloned()
     }
 
-    /// Remove entries older than [`MAX_EVENT_AGE`].
+    /// Transaction IDs for embedding `url` from `source`, in its current
+    /// generation.
+    pub async fn embed_txns(&self, source: &EventId, url: &Url) -> EmbedTxns {
+        let generations = self.generations.lock().await;
+        let generation = generations
+            .get(&(source.to_owned(), url.as_str().to_owned()))
+            .map_or(0, |generation| generation.generation);
+        EmbedTxns::new(source, url, generation)
+    }
+
+    /// Move the embed of `url` from `source` on to a new generation of
+    /// transaction IDs, once it's been redacted.
+    pub async fn bump_generation(&self, source: &EventId, url: &Url) {
+        let mut generations = self.generations.lock().await;
+        let generation = generations
+            .entry((source.to_owned(), url.as_str().to_owned()))
+            .or_insert(Generation {
+                generation: 0,
+                bumped_at: Instant::now(),
+            });
+        generation.generation += 1;
+        generation.bumped_at = Instant::now();
+    }
+
+    /// Claim `url` for an embed of `event_id` in `room_id`.
+    ///
+    /// Returns `false` if a different event already embedded the same URL in
+    /// the room within the dedup window. Re-embeds for the same event (after
+    /// an edit) are always allowed.
+    pub async fn claim_url(&self, room_id: &RoomId, url: &Url, event_id: &EventId) -> bool {
+        if self.dedup_window.is_zero() {
+            return true;
+        }
+
+        let mut recent = self.recent_urls.lock().await;
+        let key = (room_id.to_owned(), url.as_str().to_owned());
+        if let Some(embed) = recent.get(&key)
+            && *embed.event_id != *event_id
+            && embed.embedded_at.elapsed() < self.dedup_window
+        {
+            return false;
+        }
+
+        recent.insert(
+            key,
+            RecentEmbed {
+                event_id: event_id.to_owned(),
+                embedded_at: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Give up the claim on `url` that `event_id` made with
+    /// [`claim_url`](Self::claim_url), when its embed wasn't posted after
+    /// all. A claim since made by another event is left alone.
+    pub async fn release_url(&self, room_id: &RoomId, url: &Url, event_id: &EventId) {
+        let mut recent = self.recent_urls.lock().await;
+        let key = (room_id.to_owned(), url.as_str().to_owned());
+        if recent
+            .get(&key)
+            .is_some_and(|embed| *embed.event_id == *event_id)
+        {
+            recent.remove(&key);
+        }
+    }
+
+    /// Remove entries and generations older than [`MAX_EVENT_AGE`], and
+    /// recent URLs older than the dedup window.
     pub async fn cleanup(&self) {
         let mut entries = self.entries.lock().await;
         let before = entries.len();
@@ -72,6 +233,15 @@ impl EventTracker {
                 entries.len()
             );
         }
+        drop(entries);
+
+        let mut recent = self.recent_urls.lock().await;
+        recent.retain(|_, embed| embed.embedded_at.elapsed() < self.dedup_window);
+        drop(recent);
+
+        // Edits and redactions of messages this old aren't handled anymore.
+        let mut generations = self.generations.lock().await;
+        generations.retain(|_, generation| generation.bumped_at.elapsed() < MAX_EVENT_AGE);
     }
 
     /// Spawn a background tokio task that calls [`cleanup`](Self::cleanup)
@@ -86,3 +256,153 @@ impl EventTracker {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{event_id, room_id};
+
+    #[tokio::test]
+    async fn test_claim_url() {
+        let tracker = EventTracker::new(Duration::from_secs(60));
+        let room = room_id!("!room:example.com");
+        let other_room = room_id!("!other:example.com");
+        let url = Url::parse("https://example.com/post").unwrap();
+
+        assert!(tracker.claim_url(room, &url, event_id!("$a")).await);
+        // Same event (e.g. re-embedding after an edit) is fine.
+        assert!(tracker.claim_url(room, &url, event_id!("$a")).await);
+        // Another message with the same URL is a duplicate...
+        assert!(!tracker.claim_url(room, &url, event_id!("$b")).await);
+        // ...but only within the same room.
+        assert!(tracker.claim_url(other_room, &url, event_id!("$b")).await);
+    }
+
+    #[tokio::test]
+    async fn test_release_url() {
+        let tracker = EventTracker::new(Duration::from_secs(60));
+        let room = room_id!("!room:example.com");
+        let url = Url::parse("https://example.com/post").unwrap();
+
+        assert!(tracker.claim_url(room, &url, event_id!("$a")).await);
+        // Only the event holding the claim can release it.
+        tracker.release_url(room, &url, event_id!("$b")).await;
+        assert!(!tracker.claim_url(room, &url, event_id!("$b")).await);
+        tracker.release_url(room, &url, event_id!("$a")).await;
+        assert!(tracker.claim_url(room, &url, event_id!("$b")).await);
+        assert!(!tracker.claim_url(room, &url, event_id!("$a")).await);
+    }
+
+    #[test]
+    fn test_embed_txn_ids() {
+        let url = Url::parse("https://example.com/post").unwrap();
+        let txns = EmbedTxns::new(event_id!("$a"), &url, 0);
+
+        assert_eq!(txns.txn_id("embed"), txns.txn_id("embed"));
+        assert_eq!(
+            txns.txn_id("embed"),
+            EmbedTxns::new(event_id!("$a"), &url, 0).txn_id("embed")
+        );
+        assert_ne!(txns.txn_id("embed"), txns.txn_id("gallery-0"));
+        assert_ne!(
+            txns.txn_id("embed"),
+            EmbedTxns::new(event_id!("$b"), &url, 0).txn_id("embed")
+        );
+        let other_url = Url::parse("https://example.com/other").unwrap();
+        assert_ne!(
+            txns.txn_id("embed"),
+            EmbedTxns::new(event_id!("$a"), &other_url, 0).txn_id("embed")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embed_txn_generations() {
+        let tracker = EventTracker::new(Duration::ZERO);
+        let url = Url::parse("https://example.com/post").unwrap();
+        let other_url = Url::parse("https://example.com/other").unwrap();
+        let first = tracker.embed_txns(event_id!("$a"), &url).await;
+        assert_eq!(
+            first.txn_id("embed"),
+            EmbedTxns::new(event_id!("$a"), &url, 0).txn_id("embed")
+        );
+
+        tracker.bump_generation(event_id!("$a"), &url).await;
+        let second = tracker.embed_txns(event_id!("$a"), &url).await;
+        assert_ne!(first.txn_id("embed"), second.txn_id("embed"));
+        assert_eq!(
+            second.txn_id("embed"),
+            tracker
+                .embed_txns(event_id!("$a"), &url)
+                .await
+                .txn_id("embed")
+        );
+        // Other embeds are left as they were.
+        assert_eq!(
+            tracker
+                .embed_txns(event_id!("$a"), &other_url)
+                .await
+                .txn_id("embed"),
+            EmbedTxns::new(event_id!("$a"), &other_url, 0).txn_id("embed")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add() {
+        let tracker = EventTracker::new(Duration::ZERO);
+        let first = Url::parse("https://example.com/first").unwrap();
+        let second = Url::parse("https://example.com/second").unwrap();
+
+        tracker
+            .add(event_id!("$a").to_owned(), first.clone(), Vec::new())
+            .await;
+        tracker
+            .register(
+                event_id!("$b").to_owned(),
+                vec![first.clone(), second.clone()],
+                Vec::new(),
+            )
+            .await;
+        tracker
+            .add(
+                event_id!("$b").to_owned(),
+                first.clone(),
+                vec![event_id!("$reply1").to_owned()],
+            )
+            .await;
+        tracker
+            .add(
+                event_id!("$b").to_owned(),
+                second.clone(),
+                vec![event_id!("$reply2").to_owned()],
+            )
+            .await;
+
+        let entry = tracker
+            .get_event_entry(&event_id!("$a").to_owned())
+            .await
+            .unwrap();
+        assert_eq!(entry.extracted_urls, vec![first.clone()]);
+        assert!(entry.reply_event_ids.is_empty());
+        let entry = tracker
+            .get_event_entry(&event_id!("$b").to_owned())
+            .await
+            .unwrap();
+        assert_eq!(entry.extracted_urls, vec![first, second]);
+        assert_eq!(
+            entry.reply_event_ids,
+            vec![
+                event_id!("$reply1").to_owned(),
+                event_id!("$reply2").to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_claim_url_disabled() {
+        let tracker = EventTracker::new(Duration::ZERO);
+        let room = room_id!("!room:example.com");
+        let url = Url::parse("https://example.com/post").unwrap();
+        assert!(tracker.claim_url(room, &url, event_id!("$a")).await);
+        assert!(tracker.claim_url(room, &url, event_id!("$b")).await);
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{Context, Result, bail};
+use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
+use serde::Deserialize;
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::{debug, info};
+use url::Url;
+
+use crate::config::Config;
+use crate::maintenance::TEMP_PREFIX;
+use crate::media::{extract_audio_wav, probe_audio_duration};
+use crate::process;
+use crate::processing::truncate_text;
+
+const WHISPER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Placeholder tokens whisper emits for non-speech segments.
+const NON_SPEECH_TOKENS: &[&str] = &["[BLANK_AUDIO]", "[MUSIC]", "[NO_SPEECH]", "(silence)"];
+
+#[derive(Debug, Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+/// Returns `true` if either a local whisper.cpp binary or a transcription API
+/// is configured, and this build can extract audio with ffmpeg.
+pub fn is_enabled(config: &Config) -> bool {
+    cfg!(feature = "media")
+        && (config.transcription_command.is_some() || config.transcription_api_url.is_some())
+}
+
+/// Transcribe the audio track of the media file at `path`.
+///
+/// Returns `Ok(None)` if transcription is disabled, the media has no audio, it
+/// is longer than the configured maximum, or nothing was said. The result is
+/// truncated to `transcription_max_chars`.
+pub async fn transcribe(
+    client: &reqwest::Client,
+    config: &Config,
+    path: &Path,
+) -> Result<Option<String>> {
+    if !is_enabled(config) {
+        return Ok(None);
+    }
+
+    let Some(duration) = probe_audio_duration(path).await? else {
+        debug!("Media has no audio stream, skipping transcription");
+        return Ok(None);
+    };
+    if duration > config.transcription_max_duration {
+        debug!(
+            "Media too long to transcribe ({:.1}s > {}s)",
+            duration.as_secs_f64(),
+            config.transcription_max_duration.as_secs()
+        );
+        return Ok(None);
+    }
+
+    let wav = extract_audio_wav(path).await?;
+
+    let text = if let Some(command) = &config.transcription_command {
+        transcribe_local(command, config, &wav).await?
+    } else if let Some(api_url) = &config.transcription_api_url {
+        transcribe_api(client, api_url, config, wav).await?
+    } else {
+        return Ok(None);
+    };
+
+    let text = clean_transcript(&text);
+    if text.is_empty() {
+        return Ok(None);
+    }
+
+    info!("Transcribed {:.1}s of audio", duration.as_secs_f64());
+    Ok(Some(truncate_text(
+        &text,
+        config.transcription_max_chars,
+        usize::MAX,
+    )))
+}
+
+/// Append a transcript to an attachment caption, creating one if needed.
+pub fn append_transcript(
+    caption: Option<TextMessageEventContent>,
+    transcript: &str,
+) -> TextMessageEventContent {
+    let body = format!("Transcript: {}", transcript);
+    let html = format!(
+        "<p><em>Transcript:</em> {}</p>",
+        html_escape::encode_text(transcript)
+    );
+
+    match caption {
+        Some(mut caption) => {
+            caption.body = format!("{}\n\n{}", caption.body, body);
+            if let Some(formatted) = &mut caption.formatted {
+                formatted.body.push_str(&html);
+            }
+            caption
+        }
+        None => TextMessageEventContent::html(body, html),
+    }
+}
+
+/// Run a local whisper.cpp binary (e.g. `whisper-cli`) on a WAV file.
+/// Runs: <command> -m <model> -f <file> -nt -np -l auto
+async fn transcribe_local(command: &Path, config: &Config, wav: &[u8]) -> Result<String> {
+    let mut wav_file = tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .suffix(".wav")
+        .tempfile()
+        .context("Failed to create temp WAV file")?;
+    wav_file
+        .write_all(wav)
+        .context("Failed to write temp WAV file")?;
+    wav_file.flush().context("Failed to flush temp WAV file")?;
+    let wav_str = wav_file.path().to_str().context("Non-UTF8 temp WAV path")?;
+
+    let mut cmd = Command::new(command);
+    if let Some(model) = &config.transcription_model_path {
+        cmd.arg("-m").arg(model);
+    }
+    cmd.args(["-f", wav_str, "-nt", "-np", "-l", "auto"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let output = process::output(&mut cmd, WHISPER_TIMEOUT, &[]).await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("whisper failed: {}", stderr.trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Use an OpenAI-compatible `audio/transcriptions` endpoint.
+async fn transcribe_api(
+    client: &reqwest::Client,
+    api_url: &Url,
+    config: &Config,
+    wav: Vec<u8>,
+) -> Result<String> {
+    let mut base = api_url.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    let endpoint = base
+        .join("audio/transcriptions")
+        .context("Invalid transcription API URL")?;
+
+    let file = reqwest::multipart::Part::bytes(wav)
+        .file_name("audio.wav")
+        .mime_str("audio/wav")?;
+    let form = reqwest::multipart::Form::new()
+        .text("model", config.transcription_api_model.clone())
+        .part("file", file);
+
+    let mut request = client
+        .post(endpoint)
+        .timeout(WHISPER_TIMEOUT)
+        .multipart(form);
+    if let Some(key) = &config.transcription_api_key {
+        request = request.bearer_auth(key);
+    }
+
+    let response: TranscriptionResponse = request
+        .send()
+        .await
+        .context("Failed to send transcription request")?
+        .error_for_status()
+        .context("Transcription endpoint returned error status")?
+        .json()
+        .await
+        .context("Failed to parse transcription response")?;
+
+    Ok(response.text)
+}
+
+/// Collapse whitespace and drop whisper's non-speech markers.
+fn clean_transcript(text: &str) -> String {
+    let mut text = text.to_string();
+    for token in NON_SPEECH_TOKENS {
+        text = text.replace(token, " ");
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_transcript() {
+        assert_eq!(
+            clean_transcript(" Hello there.\n [BLANK_AUDIO]\n General Kenobi.\n"),
+            "Hello there. General Kenobi."
+        );
+        assert_eq!(clean_transcript("[BLANK_AUDIO]\n"), "");
+    }
+
+    #[test]
+    fn test_append_transcript() {
+        let caption = TextMessageEventContent::html("Title", "<strong>Title</strong>");
+        let caption = append_transcript(Some(caption), "Hi & bye");
+        assert_eq!(caption.body, "Title\n\nTranscript: Hi & bye");
+        assert_eq!(
+            caption.formatted.unwrap().body,
+            "<strong>Title</strong><p><em>Transcript:</em> Hi &amp; bye</p>"
+        );
+
+        let caption = append_transcript(None, "Hello");
+        assert_eq!(caption.body, "Transcript: Hello");
+    }
+}
@@ -0,0 +1,390 @@
+use anyhow::{Context, Result, bail};
+use matrix_sdk::{
+    Client,
+    attachment::{AttachmentInfo, Thumbnail},
+    room::Room,
+    ruma::{
+        OwnedMxcUri,
+        api::client::error::ErrorKind,
+        events::room::{
+            ImageInfo, MediaSource, ThumbnailInfo,
+            message::{
+                AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+                FormattedBody, ImageMessageEventContent, MessageType, TextMessageEventContent,
+                VideoInfo, VideoMessageEventContent,
+            },
+        },
+    },
+};
+use mime_guess::Mime;
+use tracing::{debug, warn};
+
+use crate::cas;
+use crate::config::Config;
+use crate::db::{Database, UploadedMedia};
+use crate::error::EmbedError;
+use crate::processing::{AttachmentData, inline_image_html};
+
+/// Body, formatted body and filename of an attachment message.
+struct MessageText {
+    body: String,
+    formatted: Option<FormattedBody>,
+    filename: Option<String>,
+}
+
+/// Upload `attachment` and build the message for it. `body` is used as the
+/// message body when the attachment has no caption.
+///
+/// If the same content was uploaded before (in any room with the same
+/// encryption), that upload is reused instead of uploading it again.
+pub async fn attachment_message(
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    attachment: AttachmentData,
+    body: &str,
+) -> Result<MessageType> {
+    let AttachmentData {
+        filename,
+        mime_type,
+        data,
+        info,
+        thumbnail,
+        caption,
+    } = attachment;
+
+    let encrypted = room.latest_encryption_state().await?.is_encrypted();
+    let size = data.len();
+    let (source, thumbnail) = upload_or_reuse(
+        &room.client(),
+        config,
+        database,
+        encrypted,
+        &mime_type,
+        data,
+        thumbnail,
+    )
+    .await?;
+
+    let text = match caption {
+        Some(caption) => MessageText {
+            body: caption.body,
+            formatted: caption.formatted,
+            filename: Some(filename),
+        },
+        None => MessageText {
+            body: body.to_owned(),
+            formatted: None,
+            filename: None,
+        },
+    };
+
+    Ok(message_type(
+        &mime_type, info, size, source, thumbnail, text,
+    ))
+}
+
+/// Upload image `attachment` and build a text message that shows it inline,
+/// above the caption. `alt` is the image's alt text, and the message body
+/// when there's no caption.
+///
+/// Inline images can't be encrypted, so this is only for unencrypted rooms.
+pub async fn inline_image_message(
+    room: &Room,
+    config: &Config,
+    database: &Database,
+    attachment: AttachmentData,
+    alt: &str,
+) -> Result<MessageType> {
+    let AttachmentData {
+        mime_type,
+        data,
+        caption,
+        ..
+    } = attachment;
+
+    let uri = upload_inline_image(&room.client(), config, database, &mime_type, data).await?;
+
+    let caption_html = caption
+        .as_ref()
+        .and_then(|caption| caption.formatted.as_ref())
+        .map(|formatted| formatted.body.as_str());
+    let html_body = inline_image_html(uri.as_str(), alt, caption_html);
+    let body = caption.map_or_else(|| alt.to_owned(), |caption| caption.body);
+    Ok(MessageType::Text(TextMessageEventContent::html(
+        body, html_body,
+    )))
+}
+
+/// Upload image `data` unencrypted, for showing inline in a formatted body,
+/// or reuse an earlier upload of the same content. Returns its `mxc://` URI.
+pub async fn upload_inline_image(
+    client: &Client,
+    config: &Config,
+    database: &Database,
+    mime_type: &Mime,
+    data: Vec<u8>,
+) -> Result<OwnedMxcUri> {
+    let (source, _) =
+        upload_or_reuse(client, config, database, false, mime_type, data, None).await?;
+    let MediaSource::Plain(uri) = source else {
+        bail!("Got an encrypted upload for an inline image");
+    };
+    Ok(uri)
+}
+
+/// Upload `data` and its thumbnail, or reuse an earlier upload of the same
+/// content (in a room with the same encryption) if there is one.
+async fn upload_or_reuse(
+    client: &Client,
+    config: &Config,
+    database: &Database,
+    encrypted: bool,
+    mime_type: &Mime,
+    data: Vec<u8>,
+    thumbnail: Option<Thumbnail>,
+) -> Result<(MediaSource, Option<(ThumbnailInfo, MediaSource)>)> {
+    let content_hash = (config.upload_cache_max_entries > 0).then(|| cas::content_hash(&data));
+
+    let cached = match &content_hash {
+        Some(hash) => cached_upload(database, hash, encrypted).await,
+        None => None,
+    };
+
+    if let Some((source, thumbnail_source)) = cached {
+        debug!("Reusing earlier upload of identical {} media", mime_type);
+        for source in std::iter::once(&source).chain(&thumbnail_source) {
+            track_upload(database, source, content_hash.as_deref()).await;
+        }
+        let thumbnail = thumbnail.map(|t| thumbnail_info(&t)).zip(thumbnail_source);
+        return Ok((source, thumbnail));
+    }
+
+    let source = upload_media(client, encrypted, mime_type, data)
+        .await
+        .context("Failed to upload media")?;
+    let thumbnail = match thumbnail {
+        Some(thumbnail) => {
+            let info = thumbnail_info(&thumbnail);
+            let source = upload_media(client, encrypted, &thumbnail.content_type, thumbnail.data)
+                .await
+                .context("Failed to upload thumbnail")?;
+            Some((info, source))
+        }
+        None => None,
+    };
+
+    for source in std::iter::once(&source).chain(thumbnail.as_ref().map(|(_, source)| source)) {
+        track_upload(database, source, content_hash.as_deref()).await;
+    }
+    if let Some(hash) = &content_hash {
+        record_upload(
+            database,
+            config,
+            hash,
+            encrypted,
+            &source,
+            thumbnail.as_ref().map(|(_, source)| source),
+        )
+        .await;
+    }
+    Ok((source, thumbnail))
+}
+
+/// Upload `data` to the media repository, encrypting it first if `encrypted`.
+pub async fn upload_media(
+    client: &Client,
+    encrypted: bool,
+    mime_type: &Mime,
+    data: Vec<u8>,
+) -> Result<MediaSource> {
+    if encrypted {
+        let file = match client.upload_encrypted_file(&mut data.as_slice()).await {
+            Ok(file) => file,
+            Err(e) => {
+                let kind = upload_error_kind(e.client_api_error_kind());
+                return Err(e)
+                    .context("Failed to upload encrypted file")
+                    .context(kind);
+            }
+        };
+        Ok(MediaSource::Encrypted(Box::new(file)))
+    } else {
+        let response = match client.media().upload(mime_type, data, None).await {
+            Ok(response) => response,
+            Err(e) => {
+                let kind = upload_error_kind(e.client_api_error_kind());
+                return Err(e).context("Failed to upload file").context(kind);
+            }
+        };
+        Ok(MediaSource::Plain(response.content_uri))
+    }
+}
+
+/// Note that the media at `source` was just uploaded or reused, so
+/// `media-gc` only deletes it once it goes unused. `content_hash` is what the
+/// upload cache has it under, if anything. Errors are logged.
+pub async fn track_upload(database: &Database, source: &MediaSource, content_hash: Option<&str>) {
+    let (uri, encrypted) = match source {
+        MediaSource::Plain(uri) => (uri, false),
+        MediaSource::Encrypted(file) => (&file.url, true),
+    };
+    if let Err(e) = database
+        .record_media_upload(uri.as_str(), content_hash, encrypted)
+        .await
+    {
+        warn!("Failed to record media upload: {:?}", e);
+    }
+}
+
+/// What kind of failure an upload the homeserver answered with `kind` is.
+fn upload_error_kind(kind: Option<&ErrorKind>) -> EmbedError {
+    match kind {
+        Some(ErrorKind::LimitExceeded { .. }) => EmbedError::RateLimited,
+        // The file is over the homeserver's size limit, or the account is
+        // over its quota.
+        Some(ErrorKind::TooLarge | ErrorKind::ResourceLimitExceeded { .. }) => EmbedError::TooLarge,
+        _ => EmbedError::UploadRejected,
+    }
+}
+
+/// Look up an earlier upload of the content with `content_hash`. Errors are
+/// logged and treated as a miss.
+async fn cached_upload(
+    database: &Database,
+    content_hash: &str,
+    encrypted: bool,
+) -> Option<(MediaSource, Option<MediaSource>)> {
+    let media = match database.get_uploaded_media(content_hash, encrypted).await {
+        Ok(media) => media?,
+        Err(e) => {
+            warn!("Failed to look up uploaded media: {:?}", e);
+            return None;
+        }
+    };
+
+    let source = serde_json::from_str(&media.source);
+    let thumbnail_source = media
+        .thumbnail_source
+        .as_deref()
+        .map(serde_json::from_str)
+        .transpose();
+    match (source, thumbnail_source) {
+        (Ok(source), Ok(thumbnail_source)) => Some((source, thumbnail_source)),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!(
+                "Ignoring unreadable uploaded media {}: {:?}",
+                content_hash, e
+            );
+            None
+        }
+    }
+}
+
+/// Remember an upload so identical content can reuse it. Errors are logged
+/// and otherwise ignored.
+async fn record_upload(
+    database: &Database,
+    config: &Config,
+    content_hash: &str,
+    encrypted: bool,
+    source: &MediaSource,
+    thumbnail_source: Option<&MediaSource>,
+) {
+    let media = serde_json::to_string(source).and_then(|source| {
+        Ok(UploadedMedia {
+            source,
+            thumbnail_source: thumbnail_source.map(serde_json::to_string).transpose()?,
+        })
+    });
+    let result = match media {
+        Ok(media) => {
+            database
+                .record_uploaded_media(
+                    content_hash,
+                    encrypted,
+                    &media,
+                    config.upload_cache_max_entries,
+                )
+                .await
+        }
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        warn!("Failed to record uploaded media: {:?}", e);
+    }
+}
+
+fn thumbnail_info(thumbnail: &Thumbnail) -> ThumbnailInfo {
+    let mut info = ThumbnailInfo::new();
+    info.mimetype = Some(thumbnail.content_type.to_string());
+    info.width = Some(thumbnail.width);
+    info.height = Some(thumbnail.height);
+    info.size = Some(thumbnail.size);
+    info
+}
+
+/// Build the image, video, audio or file message for uploaded media.
+fn message_type(
+    mime_type: &Mime,
+    info: Option<AttachmentInfo>,
+    size: usize,
+    source: MediaSource,
+    thumbnail: Option<(ThumbnailInfo, MediaSource)>,
+    text: MessageText,
+) -> MessageType {
+    let size = Some((size as u32).into());
+    let mimetype = Some(mime_type.to_string());
+    let (thumbnail_info, thumbnail_source) = match thumbnail {
+        Some((info, source)) => (Some(Box::new(info)), Some(source)),
+        None => (None, None),
+    };
+
+    if mime_type.type_() == mime_guess::mime::IMAGE {
+        let mut info = info.map_or_else(ImageInfo::new, ImageInfo::from);
+        info.mimetype = mimetype;
+        info.size = size;
+        info.thumbnail_info = thumbnail_info;
+        info.thumbnail_source = thumbnail_source;
+
+        let mut content = ImageMessageEventContent::new(text.body, source);
+        content.formatted = text.formatted;
+        content.filename = text.filename;
+        content.info = Some(Box::new(info));
+        MessageType::Image(content)
+    } else if mime_type.type_() == mime_guess::mime::VIDEO {
+        let mut info = info.map_or_else(VideoInfo::new, VideoInfo::from);
+        info.mimetype = mimetype;
+        info.size = size;
+        info.thumbnail_info = thumbnail_info;
+        info.thumbnail_source = thumbnail_source;
+
+        let mut content = VideoMessageEventContent::new(text.body, source);
+        content.formatted = text.formatted;
+        content.filename = text.filename;
+        content.info = Some(Box::new(info));
+        MessageType::Video(content)
+    } else if mime_type.type_() == mime_guess::mime::AUDIO {
+        let mut info = info.map_or_else(AudioInfo::new, AudioInfo::from);
+        info.mimetype = mimetype;
+        info.size = size;
+
+        let mut content = AudioMessageEventContent::new(text.body, source);
+        content.formatted = text.formatted;
+        content.filename = text.filename;
+        content.info = Some(Box::new(info));
+        MessageType::Audio(content)
+    } else {
+        let mut info = info.map_or_else(FileInfo::new, FileInfo::from);
+        info.mimetype = mimetype;
+        info.size = size;
+        info.thumbnail_info = thumbnail_info;
+        info.thumbnail_source = thumbnail_source;
+
+        let mut content = FileMessageEventContent::new(text.body, source);
+        content.formatted = text.formatted;
+        content.filename = text.filename;
+        content.info = Some(Box::new(info));
+        MessageType::File(content)
+    }
+}
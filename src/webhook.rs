@@ -0,0 +1,254 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::cas::hex_encode;
+use crate::config::Config;
+use crate::error::EmbedError;
+use crate::processing::FileTooLarge;
+
+/// Header carrying the signature of a webhook's body, when there's a secret
+/// to sign it with.
+pub const SIGNATURE_HEADER: &str = "X-Embed-Signature";
+
+/// How long the webhook endpoint has to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+/// Something that happened to an embed, as sent to the webhook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    EmbedPosted {
+        /// See [`crate::metrics::media_label`].
+        media_type: &'static str,
+        /// Size of all the media posted with the embed.
+        media_bytes: u64,
+    },
+    EmbedFailed {
+        stage: Option<&'static str>,
+        /// The [`EmbedError`] label of the failure.
+        kind: &'static str,
+    },
+    /// Media was too large for us or the homeserver to post.
+    QuotaExceeded {
+        kind: &'static str,
+        /// The size of the media, or at least how much of it was read, if
+        /// it's known.
+        size: Option<u64>,
+        /// The limit it exceeded, if it was ours.
+        max_size: Option<u64>,
+    },
+}
+
+impl Event {
+    /// The event for `error`, if it's media going over a size limit.
+    pub fn quota_exceeded(error: &anyhow::Error, config: &Config) -> Option<Event> {
+        let kind = EmbedError::of(error);
+        if !matches!(
+            kind,
+            EmbedError::TooLarge | EmbedError::ExcessiveCompression
+        ) {
+            return None;
+        }
+        let too_large = error.downcast_ref::<FileTooLarge>();
+        Some(Event::QuotaExceeded {
+            kind: kind.label(),
+            size: too_large.map(|too_large| too_large.size),
+            max_size: too_large.map(|_| config.max_file_size),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    timestamp: String,
+    room_id: &'a str,
+    /// Only the domain of the link, which is enough to tell sites apart
+    /// without passing on what people linked to.
+    domain: Option<&'a str>,
+}
+
+/// Send `event`, about an embed of `url` in `room_id`, to the webhook if one
+/// is configured. It's sent in the background; failures are logged and
+/// otherwise ignored.
+pub fn notify(config: &Config, room_id: &str, url: &Url, event: Event) {
+    let Some(webhook_url) = config.webhook_url.clone() else {
+        return;
+    };
+    let payload = Payload {
+        event: &event,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        room_id,
+        domain: url.host_str(),
+    };
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialize webhook event {:?}: {:?}", event, e);
+            return;
+        }
+    };
+    let secret = config.webhook_secret.clone();
+    tokio::spawn(async move {
+        if let Err(e) = send(&CLIENT, &webhook_url, secret.as_deref(), body).await {
+            warn!("Failed to send webhook to {}: {:?}", webhook_url, e);
+        }
+    });
+}
+
+async fn send(
+    client: &reqwest::Client,
+    url: &Url,
+    secret: Option<&str>,
+    body: Vec<u8>,
+) -> Result<()> {
+    let mut request = client
+        .post(url.clone())
+        .timeout(TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let Some(secret) = secret {
+        request = request.header(SIGNATURE_HEADER, signature(secret.as_bytes(), &body));
+    }
+    request
+        .body(body)
+        .send()
+        .await
+        .context("Failed to send webhook")?
+        .error_for_status()
+        .context("Webhook endpoint returned error status")?;
+    debug!("Sent webhook to {}", url);
+    Ok(())
+}
+
+/// The signature header for `body`: its HMAC-SHA256 under `secret` in hex,
+/// like `sha256=...`.
+fn signature(secret: &[u8], body: &[u8]) -> String {
+    format!(
+        "sha256={}",
+        hex_encode(&hmac_sha256(secret, body).finalize().into_bytes())
+    )
+}
+
+/// HMAC-SHA256 (RFC 2104) of `message` under `key`.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231, test cases 2 and 6.
+        assert_eq!(
+            signature(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            signature(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "sha256=60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+
+        // What a receiver does with the header: check it in constant time.
+        let header = signature(b"secret", b"body");
+        let digest = header.strip_prefix("sha256=").unwrap();
+        let digest: Vec<u8> = (0..digest.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digest[i..i + 2], 16).unwrap())
+            .collect();
+        assert!(
+            hmac_sha256(b"secret", b"body")
+                .verify_slice(&digest)
+                .is_ok()
+        );
+        assert!(
+            hmac_sha256(b"secret", b"other")
+                .verify_slice(&digest)
+                .is_err()
+        );
+        assert!(
+            hmac_sha256(b"other", b"body")
+                .verify_slice(&digest)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_quota_exceeded() {
+        let config = Config {
+            max_file_size: 1000,
+            ..Default::default()
+        };
+        let err = anyhow::Error::new(FileTooLarge {
+            size: 2000,
+            streamed: false,
+        })
+        .context("Failed to download");
+        assert_eq!(
+            Event::quota_exceeded(&err, &config),
+            Some(Event::QuotaExceeded {
+                kind: "too_large",
+                size: Some(2000),
+                max_size: Some(1000),
+            })
+        );
+
+        let err = anyhow::anyhow!("boom");
+        assert_eq!(Event::quota_exceeded(&err, &config), None);
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let server = MockServer::start().await;
+        let event = Event::EmbedFailed {
+            stage: Some("metadata"),
+            kind: "network_timeout",
+        };
+        let payload = Payload {
+            event: &event,
+            timestamp: "2025-01-15T12:00:00+00:00".to_string(),
+            room_id: "!room:example.com",
+            domain: Some("example.com"),
+        };
+        let body = serde_json::to_vec(&payload).unwrap();
+        Mock::given(method("POST"))
+            .and(header(
+                SIGNATURE_HEADER,
+                signature(b"secret", &body).as_str(),
+            ))
+            .and(body_partial_json(serde_json::json!({
+                "event": "embed_failed",
+                "stage": "metadata",
+                "kind": "network_timeout",
+                "room_id": "!room:example.com",
+                "domain": "example.com",
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let url = Url::parse(&server.uri()).unwrap();
+        send(&reqwest::Client::new(), &url, Some("secret"), body)
+            .await
+            .unwrap();
+    }
+}